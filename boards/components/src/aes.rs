@@ -0,0 +1,175 @@
+//! Components for sharing an AES engine and for the userspace AES driver.
+//!
+//! `AesMuxComponent` creates the mux that virtualizes the engine. Each kernel
+//! user of the engine then needs its own `VirtualAES128`, and
+//! `AesComponent` creates one for the userspace driver, along with the CCM
+//! and GCM capsules it uses.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let mux_aes = components::aes::AesMuxComponent::new(&sam4l::aes::AES, dynamic_deferred_caller)
+//!     .finalize(components::aes_mux_component_helper!(sam4l::aes::Aes));
+//!
+//! let aes = components::aes::AesComponent::new(board_kernel, mux_aes)
+//!     .finalize(components::aes_component_helper!(sam4l::aes::Aes));
+//! ```
+
+use capsules::aes::AesDriver;
+use capsules::aes_ccm;
+use capsules::aes_gcm;
+use capsules::virtual_aes::{MuxAES, VirtualAES128};
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::symmetric_encryption::{
+    self, AES128Ctr, AES128, AES128CBC, AES128CCM, AES128ECB, AES128GCM,
+};
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! aes_mux_component_helper {
+    ($A:ty) => {{
+        use capsules::virtual_aes::MuxAES;
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<MuxAES<'static, $A>> = MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct AesMuxComponent<A: 'static + AES128<'static>> {
+    aes: &'static A,
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl<A: 'static + AES128<'static>> AesMuxComponent<A> {
+    pub fn new(
+        aes: &'static A,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> AesMuxComponent<A> {
+        AesMuxComponent {
+            aes,
+            deferred_caller,
+        }
+    }
+}
+
+impl<A: 'static + AES128<'static>> Component for AesMuxComponent<A> {
+    type StaticInput = &'static mut MaybeUninit<MuxAES<'static, A>>;
+    type Output = &'static MuxAES<'static, A>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let mux_aes = static_init_half!(
+            s,
+            MuxAES<'static, A>,
+            MuxAES::new(self.aes, self.deferred_caller)
+        );
+        mux_aes.initialize_callback_handle(
+            self.deferred_caller
+                .register(mux_aes)
+                .expect("no deferred call slot available for AES mux"),
+        );
+        self.aes.set_client(mux_aes);
+
+        mux_aes
+    }
+}
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! aes_component_helper {
+    ($A:ty) => {{
+        use capsules::aes::AesDriver;
+        use capsules::aes_ccm::AES128CCM;
+        use capsules::aes_gcm::AES128GCM;
+        use capsules::virtual_aes::VirtualAES128;
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<VirtualAES128<'static, $A>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<AES128CCM<'static, VirtualAES128<'static, $A>>> =
+            MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<AES128GCM<'static, VirtualAES128<'static, $A>>> =
+            MaybeUninit::uninit();
+        static mut BUF4: MaybeUninit<AesDriver<'static, VirtualAES128<'static, $A>>> =
+            MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2, &mut BUF3, &mut BUF4)
+    };};
+}
+
+// Additional data, message and tag of a CCM or GCM request must fit in the
+// driver's buffer.
+const AES_BUF_SIZE: usize = 128;
+static mut AES_BUF: [u8; AES_BUF_SIZE] = [0x00; AES_BUF_SIZE];
+
+// CCM adds up to three blocks of header and padding, GCM two blocks.
+const CCM_CRYPT_SIZE: usize = 3 * symmetric_encryption::AES128_BLOCK_SIZE + AES_BUF_SIZE;
+static mut CCM_CRYPT_BUF: [u8; CCM_CRYPT_SIZE] = [0x00; CCM_CRYPT_SIZE];
+const GCM_CRYPT_SIZE: usize = 2 * symmetric_encryption::AES128_BLOCK_SIZE + AES_BUF_SIZE;
+static mut GCM_CRYPT_BUF: [u8; GCM_CRYPT_SIZE] = [0x00; GCM_CRYPT_SIZE];
+
+pub struct AesComponent<A: 'static + AES128<'static> + AES128Ctr + AES128CBC + AES128ECB> {
+    board_kernel: &'static kernel::Kernel,
+    mux_aes: &'static MuxAES<'static, A>,
+}
+
+impl<A: 'static + AES128<'static> + AES128Ctr + AES128CBC + AES128ECB> AesComponent<A> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        mux_aes: &'static MuxAES<'static, A>,
+    ) -> AesComponent<A> {
+        AesComponent {
+            board_kernel,
+            mux_aes,
+        }
+    }
+}
+
+impl<A: 'static + AES128<'static> + AES128Ctr + AES128CBC + AES128ECB> Component
+    for AesComponent<A>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<VirtualAES128<'static, A>>,
+        &'static mut MaybeUninit<aes_ccm::AES128CCM<'static, VirtualAES128<'static, A>>>,
+        &'static mut MaybeUninit<aes_gcm::AES128GCM<'static, VirtualAES128<'static, A>>>,
+        &'static mut MaybeUninit<AesDriver<'static, VirtualAES128<'static, A>>>,
+    );
+    type Output = &'static AesDriver<'static, VirtualAES128<'static, A>>;
+
+    unsafe fn finalize(self, s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let virtual_aes = static_init_half!(
+            s.0,
+            VirtualAES128<'static, A>,
+            VirtualAES128::new(self.mux_aes)
+        );
+        let ccm = static_init_half!(
+            s.1,
+            aes_ccm::AES128CCM<'static, VirtualAES128<'static, A>>,
+            aes_ccm::AES128CCM::new(virtual_aes, &mut CCM_CRYPT_BUF)
+        );
+        let gcm = static_init_half!(
+            s.2,
+            aes_gcm::AES128GCM<'static, VirtualAES128<'static, A>>,
+            aes_gcm::AES128GCM::new(virtual_aes, &mut GCM_CRYPT_BUF)
+        );
+        let aes = static_init_half!(
+            s.3,
+            AesDriver<'static, VirtualAES128<'static, A>>,
+            AesDriver::new(
+                virtual_aes,
+                ccm,
+                gcm,
+                &mut AES_BUF,
+                self.board_kernel.create_grant(&grant_cap)
+            )
+        );
+        virtual_aes.set_client(aes);
+        ccm.set_client(aes);
+        gcm.set_client(aes);
+
+        aes
+    }
+}
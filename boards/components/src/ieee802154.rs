@@ -1,8 +1,12 @@
 //! Component for IEEE 802.15.4 radio syscall interface.
 //!
-//! This provides one Component, `Ieee802154Component`, which implements a
-//! userspace syscall interface to a full 802.15.4 stack with a
-//! always-on MAC implementation, as well as multiplexed access to that MAC implementation.
+//! This provides two Components, `Ieee802154Component` and
+//! `Ieee802154CsmaComponent`, which implement a userspace syscall interface to
+//! a full 802.15.4 stack, as well as multiplexed access to its MAC
+//! implementation. `Ieee802154Component` uses the always-on `AwakeMac`, which
+//! leaves channel access and acknowledgements to the radio.
+//! `Ieee802154CsmaComponent` uses `CsmaMac`, which implements CSMA-CA and
+//! acknowledgements in software and needs an alarm.
 //!
 //! Usage
 //! -----
@@ -18,16 +22,34 @@
 //!     nrf52::ieee802154_radio::Radio,
 //!     nrf52::aes::AesECB<'static>
 //! ));
+//!
+//! let (radio, mux_mac) = components::ieee802154::Ieee802154CsmaComponent::new(
+//!     board_kernel,
+//!     &nrf52::ieee802154_radio::RADIO,
+//!     &nrf52::aes::AESECB,
+//!     mux_alarm,
+//!     PAN_ID,
+//!     SRC_MAC,
+//!     true,
+//! )
+//! .finalize(components::ieee802154_csma_component_helper!(
+//!     nrf52::ieee802154_radio::Radio,
+//!     nrf52::aes::AesECB<'static>,
+//!     nrf52::rtc::Rtc<'static>
+//! ));
 //! ```
 
 use capsules;
+use capsules::ieee802154::csma_mac::CsmaMac;
 use capsules::ieee802154::device::MacDevice;
 use capsules::ieee802154::mac::{AwakeMac, Mac};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use core::mem::MaybeUninit;
 use kernel::capabilities;
 use kernel::component::Component;
 use kernel::hil::radio;
 use kernel::hil::symmetric_encryption::{self, AES128Ctr, AES128, AES128CBC, AES128CCM};
+use kernel::hil::time::{self, Alarm};
 use kernel::{create_capability, static_init, static_init_half};
 
 // Setup static space for the objects.
@@ -52,6 +74,30 @@ macro_rules! ieee802154_component_helper {
     };};
 }
 
+#[macro_export]
+macro_rules! ieee802154_csma_component_helper {
+    ($R:ty, $A:ty, $Al:ty) => {{
+        use capsules::ieee802154::csma_mac::CsmaMac;
+        use capsules::virtual_alarm::VirtualMuxAlarm;
+        use core::mem::MaybeUninit;
+        use kernel::hil::symmetric_encryption::{AES128Ctr, AES128, AES128CBC, AES128CCM};
+
+        static mut BUF1: MaybeUninit<capsules::aes_ccm::AES128CCM<'static, $A>> =
+            MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<VirtualMuxAlarm<'static, $Al>> = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<CsmaMac<'static, $R, VirtualMuxAlarm<'static, $Al>>> =
+            MaybeUninit::uninit();
+        static mut BUF4: MaybeUninit<
+            capsules::ieee802154::framer::Framer<
+                'static,
+                CsmaMac<'static, $R, VirtualMuxAlarm<'static, $Al>>,
+                capsules::aes_ccm::AES128CCM<'static, $A>,
+            >,
+        > = MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2, &mut BUF3, &mut BUF4)
+    };};
+}
+
 pub struct Ieee802154Component<
     R: 'static + kernel::hil::radio::Radio,
     A: 'static + AES128<'static> + AES128Ctr + AES128CBC,
@@ -95,6 +141,9 @@ static mut RADIO_RX_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE]
 const CRYPT_SIZE: usize = 3 * symmetric_encryption::AES128_BLOCK_SIZE + radio::MAX_BUF_SIZE;
 static mut CRYPT_BUF: [u8; CRYPT_SIZE] = [0x00; CRYPT_SIZE];
 
+// The buffer CsmaMac builds its own frames (ACKs and beacons) in.
+static mut CSMA_MAC_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+
 impl<
         R: 'static + kernel::hil::radio::Radio,
         A: 'static + AES128<'static> + AES128Ctr + AES128CBC,
@@ -117,8 +166,6 @@ impl<
     );
 
     unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
-        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
-
         let aes_ccm = static_init_half!(
             static_buffer.0,
             capsules::aes_ccm::AES128CCM<'static, A>,
@@ -151,35 +198,169 @@ impl<
         awake_mac.set_receive_client(mac_device);
         awake_mac.set_config_client(mac_device);
 
-        let mux_mac = static_init!(
-            capsules::ieee802154::virtual_mac::MuxMac<'static>,
-            capsules::ieee802154::virtual_mac::MuxMac::new(mac_device)
+        finalize_driver(self.board_kernel, mac_device, self.pan_id, self.short_addr)
+    }
+}
+
+pub struct Ieee802154CsmaComponent<
+    R: 'static + kernel::hil::radio::Radio,
+    A: 'static + AES128<'static> + AES128Ctr + AES128CBC,
+    Al: 'static + time::Alarm<'static>,
+> {
+    board_kernel: &'static kernel::Kernel,
+    radio: &'static R,
+    aes: &'static A,
+    alarm_mux: &'static MuxAlarm<'static, Al>,
+    pan_id: capsules::net::ieee802154::PanID,
+    short_addr: u16,
+    software_ack: bool,
+}
+
+impl<
+        R: 'static + kernel::hil::radio::Radio,
+        A: 'static + AES128<'static> + AES128Ctr + AES128CBC,
+        Al: 'static + time::Alarm<'static>,
+    > Ieee802154CsmaComponent<R, A, Al>
+{
+    /// `software_ack` should be false for radios that acknowledge received
+    /// frames in hardware.
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        radio: &'static R,
+        aes: &'static A,
+        alarm_mux: &'static MuxAlarm<'static, Al>,
+        pan_id: capsules::net::ieee802154::PanID,
+        short_addr: u16,
+        software_ack: bool,
+    ) -> Self {
+        Self {
+            board_kernel,
+            radio,
+            aes,
+            alarm_mux,
+            pan_id,
+            short_addr,
+            software_ack,
+        }
+    }
+}
+
+impl<
+        R: 'static + kernel::hil::radio::Radio,
+        A: 'static + AES128<'static> + AES128Ctr + AES128CBC,
+        Al: 'static + time::Alarm<'static>,
+    > Component for Ieee802154CsmaComponent<R, A, Al>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<capsules::aes_ccm::AES128CCM<'static, A>>,
+        &'static mut MaybeUninit<VirtualMuxAlarm<'static, Al>>,
+        &'static mut MaybeUninit<CsmaMac<'static, R, VirtualMuxAlarm<'static, Al>>>,
+        &'static mut MaybeUninit<
+            capsules::ieee802154::framer::Framer<
+                'static,
+                CsmaMac<'static, R, VirtualMuxAlarm<'static, Al>>,
+                capsules::aes_ccm::AES128CCM<'static, A>,
+            >,
+        >,
+    );
+    type Output = (
+        &'static capsules::ieee802154::RadioDriver<'static>,
+        &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let aes_ccm = static_init_half!(
+            static_buffer.0,
+            capsules::aes_ccm::AES128CCM<'static, A>,
+            capsules::aes_ccm::AES128CCM::new(self.aes, &mut CRYPT_BUF)
         );
-        mac_device.set_transmit_client(mux_mac);
-        mac_device.set_receive_client(mux_mac);
 
-        let userspace_mac = static_init!(
-            capsules::ieee802154::virtual_mac::MacUser<'static>,
-            capsules::ieee802154::virtual_mac::MacUser::new(mux_mac)
+        self.aes.set_client(aes_ccm);
+        self.aes.enable();
+
+        let mac_alarm = static_init_half!(
+            static_buffer.1,
+            VirtualMuxAlarm<'static, Al>,
+            VirtualMuxAlarm::new(self.alarm_mux)
         );
-        mux_mac.add_user(userspace_mac);
-
-        let radio_driver = static_init!(
-            capsules::ieee802154::RadioDriver<'static>,
-            capsules::ieee802154::RadioDriver::new(
-                userspace_mac,
-                self.board_kernel.create_grant(&grant_cap),
-                &mut RADIO_BUF
-            )
+        let csma_mac = static_init_half!(
+            static_buffer.2,
+            CsmaMac<'static, R, VirtualMuxAlarm<'static, Al>>,
+            CsmaMac::new(self.radio, mac_alarm)
         );
+        mac_alarm.set_client(csma_mac);
+        self.radio.set_transmit_client(csma_mac);
+        self.radio.set_receive_client(csma_mac, &mut RADIO_RX_BUF);
+        self.radio.set_config_client(csma_mac);
+        csma_mac.initialize(&mut CSMA_MAC_BUF);
+        csma_mac.set_software_ack(self.software_ack);
 
-        mac_device.set_key_procedure(radio_driver);
-        mac_device.set_device_procedure(radio_driver);
-        userspace_mac.set_transmit_client(radio_driver);
-        userspace_mac.set_receive_client(radio_driver);
-        userspace_mac.set_pan(self.pan_id);
-        userspace_mac.set_address(self.short_addr);
+        let mac_device = static_init_half!(
+            static_buffer.3,
+            capsules::ieee802154::framer::Framer<
+                'static,
+                CsmaMac<'static, R, VirtualMuxAlarm<'static, Al>>,
+                capsules::aes_ccm::AES128CCM<'static, A>,
+            >,
+            capsules::ieee802154::framer::Framer::new(csma_mac, aes_ccm)
+        );
+        aes_ccm.set_client(mac_device);
+        csma_mac.set_transmit_client(mac_device);
+        csma_mac.set_receive_client(mac_device);
+        csma_mac.set_config_client(mac_device);
 
-        (radio_driver, mux_mac)
+        finalize_driver(self.board_kernel, mac_device, self.pan_id, self.short_addr)
     }
 }
+
+// Builds the MAC multiplexer and the userspace driver on top of the MAC
+// device, which is shared by both components.
+unsafe fn finalize_driver<
+    M: 'static + Mac,
+    A: 'static + AES128<'static> + AES128Ctr + AES128CBC,
+>(
+    board_kernel: &'static kernel::Kernel,
+    mac_device: &'static capsules::ieee802154::framer::Framer<
+        'static,
+        M,
+        capsules::aes_ccm::AES128CCM<'static, A>,
+    >,
+    pan_id: capsules::net::ieee802154::PanID,
+    short_addr: u16,
+) -> (
+    &'static capsules::ieee802154::RadioDriver<'static>,
+    &'static capsules::ieee802154::virtual_mac::MuxMac<'static>,
+) {
+    let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+    let mux_mac = static_init!(
+        capsules::ieee802154::virtual_mac::MuxMac<'static>,
+        capsules::ieee802154::virtual_mac::MuxMac::new(mac_device)
+    );
+    mac_device.set_transmit_client(mux_mac);
+    mac_device.set_receive_client(mux_mac);
+
+    let userspace_mac = static_init!(
+        capsules::ieee802154::virtual_mac::MacUser<'static>,
+        capsules::ieee802154::virtual_mac::MacUser::new(mux_mac)
+    );
+    mux_mac.add_user(userspace_mac);
+
+    let radio_driver = static_init!(
+        capsules::ieee802154::RadioDriver<'static>,
+        capsules::ieee802154::RadioDriver::new(
+            userspace_mac,
+            board_kernel.create_grant(&grant_cap),
+            &mut RADIO_BUF
+        )
+    );
+
+    mac_device.set_key_procedure(radio_driver);
+    mac_device.set_device_procedure(radio_driver);
+    userspace_mac.set_transmit_client(radio_driver);
+    userspace_mac.set_receive_client(radio_driver);
+    userspace_mac.set_pan(pan_id);
+    userspace_mac.set_address(short_addr);
+
+    (radio_driver, mux_mac)
+}
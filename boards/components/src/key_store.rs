@@ -0,0 +1,74 @@
+//! Component for the key store.
+//!
+//! The key store needs nonvolatile storage of its own, for example a
+//! `NonvolatileToPages` over a `virtual_flash::FlashUser`, with a region of
+//! `capsules::key_store::KEY_STORE_SIZE` bytes that processes can't access.
+//! The component starts loading the keys. To let processes use their keys
+//! by handle, pass the key store to the crypto drivers.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let key_store = components::key_store::KeyStoreComponent::new(
+//!     board_kernel,
+//!     nv_to_page,
+//!     0x3f000,
+//! )
+//! .finalize(());
+//! aes.set_key_store(key_store);
+//! ```
+
+use capsules::key_store::KeyStore;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::static_init;
+
+pub struct Capability;
+unsafe impl capabilities::ProcessManagementCapability for Capability {}
+
+pub struct KeyStoreComponent {
+    board_kernel: &'static kernel::Kernel,
+    storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    start_address: usize,
+}
+
+impl KeyStoreComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        storage: &'static dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        start_address: usize,
+    ) -> KeyStoreComponent {
+        KeyStoreComponent {
+            board_kernel,
+            storage,
+            start_address,
+        }
+    }
+}
+
+impl Component for KeyStoreComponent {
+    type StaticInput = ();
+    type Output = &'static KeyStore<'static, Capability>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let key_store = static_init!(
+            KeyStore<'static, Capability>,
+            KeyStore::new(
+                self.storage,
+                self.start_address,
+                self.board_kernel,
+                Capability,
+                self.board_kernel.create_grant(&grant_cap),
+                &mut capsules::key_store::BUFFER
+            )
+        );
+        self.storage.set_client(key_store);
+        key_store.load();
+
+        key_store
+    }
+}
@@ -0,0 +1,140 @@
+//! Component for the key-value store.
+//!
+//! This provides one component, KvStoreComponent, which sets up a
+//! `capsules::kv_store::KvStore` on a region of flash and a system call
+//! interface to it. The region must only be used by the store.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let (kv_store, kv_store_driver) = components::kv_store::KvStoreComponent::new(
+//!     board_kernel,
+//!     &sam4l::flashcalw::FLASH_CONTROLLER,
+//!     0x70000,
+//!     0x4000,
+//!     dynamic_deferred_caller,
+//! )
+//! .finalize(components::kv_store_component_helper!(
+//!     sam4l::flashcalw::FLASHCALW
+//! ));
+//! ```
+
+use capsules::kv_store::KvStore;
+use capsules::kv_store_driver::KvStoreDriver;
+use core::mem::MaybeUninit;
+use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::{static_init, static_init_half};
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! kv_store_component_helper {
+    ($F:ty) => {{
+        use capsules::kv_store::KvStore;
+        use core::mem::MaybeUninit;
+        use kernel::hil;
+        static mut BUF1: MaybeUninit<<$F as hil::flash::Flash>::Page> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<<$F as hil::flash::Flash>::Page> = MaybeUninit::uninit();
+        static mut BUF3: MaybeUninit<KvStore<'static, $F>> = MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2, &mut BUF3)
+    };};
+}
+
+pub struct Capability;
+unsafe impl capabilities::ProcessManagementCapability for Capability {}
+
+pub struct KvStoreComponent<
+    F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, KvStore<'static, F>>,
+> {
+    board_kernel: &'static kernel::Kernel,
+    flash: &'static F,
+    start_address: usize,
+    length: usize,
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl<F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, KvStore<'static, F>>>
+    KvStoreComponent<F>
+{
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        flash: &'static F,
+        start_address: usize,
+        length: usize,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> Self {
+        Self {
+            board_kernel,
+            flash,
+            start_address,
+            length,
+            deferred_caller,
+        }
+    }
+}
+
+impl<F: 'static + hil::flash::Flash + hil::flash::HasClient<'static, KvStore<'static, F>>> Component
+    for KvStoreComponent<F>
+{
+    type StaticInput = (
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
+        &'static mut MaybeUninit<KvStore<'static, F>>,
+    );
+    type Output = (
+        &'static KvStore<'static, F>,
+        &'static KvStoreDriver<'static, Capability>,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let pagebuffer = static_init_half!(
+            static_buffer.0,
+            <F as hil::flash::Flash>::Page,
+            <F as hil::flash::Flash>::Page::default()
+        );
+        let gc_pagebuffer = static_init_half!(
+            static_buffer.1,
+            <F as hil::flash::Flash>::Page,
+            <F as hil::flash::Flash>::Page::default()
+        );
+
+        let kv_store = static_init_half!(
+            static_buffer.2,
+            KvStore<'static, F>,
+            KvStore::new(
+                self.flash,
+                self.start_address,
+                self.length,
+                pagebuffer,
+                gc_pagebuffer,
+                self.deferred_caller,
+            )
+        );
+        hil::flash::HasClient::set_client(self.flash, kv_store);
+        kv_store.initialize_callback_handle(
+            self.deferred_caller
+                .register(kv_store)
+                .expect("no deferred call slot available for kv store"),
+        );
+
+        let kv_store_driver = static_init!(
+            KvStoreDriver<'static, Capability>,
+            KvStoreDriver::new(
+                kv_store,
+                self.board_kernel,
+                Capability,
+                self.board_kernel.create_grant(&grant_cap),
+                &mut capsules::kv_store_driver::BUFFER
+            )
+        );
+        hil::kv_store::KVStore::set_client(kv_store, kv_store_driver);
+        kv_store.mount();
+
+        (kv_store, kv_store_driver)
+    }
+}
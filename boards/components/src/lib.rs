@@ -1,6 +1,7 @@
 #![no_std]
 #![feature(const_in_array_repeat_expressions)]
 
+pub mod aes;
 pub mod alarm;
 pub mod analog_comparator;
 pub mod button;
@@ -16,6 +17,8 @@ pub mod hmac;
 pub mod i2c;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod key_store;
+pub mod kv_store;
 pub mod l3gd20;
 pub mod led;
 pub mod lldb;
@@ -31,8 +34,11 @@ pub mod rng;
 pub mod sched;
 pub mod screen;
 pub mod segger_rtt;
+pub mod sha;
 pub mod si7021;
 pub mod spi;
 pub mod st7735;
 pub mod temperature;
 pub mod touch;
+pub mod usb_hid;
+pub mod usb_msc;
@@ -1,7 +1,10 @@
 //! Component for non-volatile storage Drivers.
 //!
 //! This provides one component, NonvolatileStorageComponent, which provides
-//! a system call inteface to non-volatile storage.
+//! a system call inteface to non-volatile storage. Each app gets a region of
+//! the userspace memory space; apps that don't ask for a size in their TBF
+//! header get a region of the default size, and no app gets a region larger
+//! than the maximum size.
 //!
 //! Usage
 //! -----
@@ -11,6 +14,8 @@
 //!     &sam4l::flashcalw::FLASH_CONTROLLER,
 //!     0x60000,
 //!     0x20000,
+//!     0x1000,
+//!     0x4000,
 //!     &_sstorage as *const u8 as usize,
 //!     &_estorage as *const u8 as usize,
 //! )
@@ -28,6 +33,9 @@ use kernel::create_capability;
 use kernel::hil;
 use kernel::{static_init, static_init_half};
 
+pub struct Capability;
+unsafe impl capabilities::ProcessManagementCapability for Capability {}
+
 // Setup static space for the objects.
 #[macro_export]
 macro_rules! nv_storage_component_helper {
@@ -48,6 +56,8 @@ pub struct NonvolatileStorageComponent<
     flash: &'static F,
     userspace_start: usize,
     userspace_length: usize,
+    app_region_size: usize,
+    max_app_region_size: usize,
     kernel_start: usize,
     kernel_length: usize,
 }
@@ -63,6 +73,8 @@ impl<
         flash: &'static F,
         userspace_start: usize,
         userspace_length: usize,
+        app_region_size: usize,
+        max_app_region_size: usize,
         kernel_start: usize,
         kernel_length: usize,
     ) -> Self {
@@ -71,6 +83,8 @@ impl<
             flash,
             userspace_start,
             userspace_length,
+            app_region_size,
+            max_app_region_size,
             kernel_start,
             kernel_length,
         }
@@ -87,7 +101,7 @@ impl<
         &'static mut MaybeUninit<<F as hil::flash::Flash>::Page>,
         &'static mut MaybeUninit<NonvolatileToPages<'static, F>>,
     );
-    type Output = &'static NonvolatileStorage<'static>;
+    type Output = &'static NonvolatileStorage<'static, Capability>;
 
     unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
         let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
@@ -106,12 +120,16 @@ impl<
         hil::flash::HasClient::set_client(self.flash, nv_to_page);
 
         let nonvolatile_storage = static_init!(
-            NonvolatileStorage<'static>,
+            NonvolatileStorage<'static, Capability>,
             NonvolatileStorage::new(
                 nv_to_page,
                 self.board_kernel.create_grant(&grant_cap),
+                self.board_kernel,
+                Capability,
                 self.userspace_start, // Start address for userspace accessible region
                 self.userspace_length, // Length of userspace accessible region
+                self.app_region_size, // Size of app regions by default
+                self.max_app_region_size, // Largest size of an app region
                 self.kernel_start,    // Start address of kernel region
                 self.kernel_length,   // Length of kernel region
                 &mut capsules::nonvolatile_storage_driver::BUFFER
@@ -1,19 +1,30 @@
-//! Component for random number generator using `Entropy32ToRandom`.
+//! Components for random number generators.
 //!
-//! This provides one Component, RngComponent, which implements a
-//! userspace syscall interface to the RNG peripheral (TRNG).
+//! This provides two Components, which implement a userspace syscall
+//! interface to random numbers. RngComponent gives the output of the RNG
+//! peripheral (TRNG) directly, using `Entropy32ToRandom`. DrbgRngComponent
+//! gives the output of an HMAC_DRBG seeded from the TRNG.
 //!
 //! Usage
 //! -----
 //! ```rust
 //! let rng = components::rng::RngComponent::new(board_kernel, &sam4l::trng::TRNG).finalize(());
+//!
+//! let rng = components::rng::DrbgRngComponent::new(
+//!     board_kernel,
+//!     &sam4l::trng::TRNG,
+//!     dynamic_deferred_caller,
+//! )
+//! .finalize(());
 //! ```
 
 // Author: Hudson Ayers <hayers@cs.stanford.edu>
 // Last modified: 07/12/2019
 
+use capsules::drbg;
 use capsules::rng;
 use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
 use kernel::component::Component;
 use kernel::create_capability;
 use kernel::hil::entropy::Entropy32;
@@ -61,3 +72,49 @@ impl Component for RngComponent {
         rng
     }
 }
+
+pub struct DrbgRngComponent {
+    board_kernel: &'static kernel::Kernel,
+    trng: &'static dyn Entropy32<'static>,
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl DrbgRngComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        trng: &'static dyn Entropy32<'static>,
+        deferred_caller: &'static DynamicDeferredCall,
+    ) -> DrbgRngComponent {
+        DrbgRngComponent {
+            board_kernel: board_kernel,
+            trng: trng,
+            deferred_caller: deferred_caller,
+        }
+    }
+}
+
+impl Component for DrbgRngComponent {
+    type StaticInput = ();
+    type Output = &'static rng::RngDriver<'static>;
+
+    unsafe fn finalize(self, _static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let drbg = static_init!(
+            drbg::HmacDrbg<'static>,
+            drbg::HmacDrbg::new(self.trng, self.deferred_caller)
+        );
+        drbg.initialize_callback_handle(
+            self.deferred_caller
+                .register(drbg)
+                .expect("no deferred call slot available for DRBG"),
+        );
+        let rng = static_init!(
+            rng::RngDriver<'static>,
+            rng::RngDriver::new(drbg, self.board_kernel.create_grant(&grant_cap))
+        );
+        drbg.set_client(rng);
+
+        rng
+    }
+}
@@ -0,0 +1,55 @@
+//! Component for the software SHA-2 digest engine.
+//!
+//! This provides a `hil::digest::Digest` on boards without a hash engine. It
+//! can be multiplexed with `HmacMuxComponent` like a hardware engine.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! let sha = components::sha::ShaSoftwareComponent::new(dynamic_deferred_caller)
+//!     .finalize(components::sha_software_component_helper!());
+//! ```
+
+use capsules::sha::Sha;
+use core::mem::MaybeUninit;
+use kernel::common::dynamic_deferred_call::DynamicDeferredCall;
+use kernel::component::Component;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! sha_software_component_helper {
+    () => {{
+        use capsules::sha::Sha;
+        use core::mem::MaybeUninit;
+        static mut BUF: MaybeUninit<Sha<'static>> = MaybeUninit::uninit();
+        &mut BUF
+    };};
+}
+
+pub struct ShaSoftwareComponent {
+    deferred_caller: &'static DynamicDeferredCall,
+}
+
+impl ShaSoftwareComponent {
+    pub fn new(deferred_caller: &'static DynamicDeferredCall) -> ShaSoftwareComponent {
+        ShaSoftwareComponent { deferred_caller }
+    }
+}
+
+impl Component for ShaSoftwareComponent {
+    type StaticInput = &'static mut MaybeUninit<Sha<'static>>;
+    type Output = &'static Sha<'static>;
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let sha = static_init_half!(static_buffer, Sha<'static>, Sha::new(self.deferred_caller));
+
+        sha.initialize_callback_handle(
+            self.deferred_caller
+                .register(sha)
+                .expect("no deferred call slot available for SHA"),
+        );
+
+        sha
+    }
+}
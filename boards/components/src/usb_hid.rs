@@ -0,0 +1,118 @@
+//! Component for USB HID support.
+//!
+//! This provides a component for using the USB HID device and its system
+//! call driver, so a process can send reports. The board can set a report
+//! descriptor with `UsbHid::set_report_descriptor` before a process attaches
+//! the device.
+//!
+//! Usage
+//! -----
+//! ```rust
+//! static STRINGS: &'static [&str; 3] = &[
+//!     "XYZ Corp.",      // Manufacturer
+//!     "The Zorpinator", // Product
+//!     "Serial No. 5",   // Serial number
+//! ];
+//! let (hid, hid_driver) = components::usb_hid::UsbHidComponent::new(
+//!     board_kernel,
+//!     &nrf52::usbd::USBD,
+//!     capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_NRF52840,
+//!     0x2341,
+//!     0x005c,
+//!     STRINGS)
+//! .finalize(components::usb_hid_component_helper!(nrf52::usbd::Usbd));
+//! ```
+
+use core::mem::MaybeUninit;
+
+use capsules::usb::hid::UsbHid;
+use capsules::usb::hid_user::UsbHidDriver;
+use kernel::capabilities;
+use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil;
+use kernel::static_init_half;
+
+// Setup static space for the objects.
+#[macro_export]
+macro_rules! usb_hid_component_helper {
+    ($U:ty) => {{
+        use capsules::usb::hid::UsbHid;
+        use capsules::usb::hid_user::UsbHidDriver;
+        use core::mem::MaybeUninit;
+        static mut BUF1: MaybeUninit<UsbHid<'static, $U>> = MaybeUninit::uninit();
+        static mut BUF2: MaybeUninit<UsbHidDriver<'static, $U>> = MaybeUninit::uninit();
+        (&mut BUF1, &mut BUF2)
+    };};
+}
+
+pub struct UsbHidComponent<U: 'static + hil::usb::UsbController<'static>> {
+    board_kernel: &'static kernel::Kernel,
+    usb: &'static U,
+    max_ctrl_packet_size: u8,
+    vendor_id: u16,
+    product_id: u16,
+    strings: &'static [&'static str; 3],
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> UsbHidComponent<U> {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        usb: &'static U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+    ) -> UsbHidComponent<U> {
+        UsbHidComponent {
+            board_kernel,
+            usb,
+            max_ctrl_packet_size,
+            vendor_id,
+            product_id,
+            strings,
+        }
+    }
+}
+
+impl<U: 'static + hil::usb::UsbController<'static>> Component for UsbHidComponent<U> {
+    type StaticInput = (
+        &'static mut MaybeUninit<UsbHid<'static, U>>,
+        &'static mut MaybeUninit<UsbHidDriver<'static, U>>,
+    );
+    type Output = (
+        &'static UsbHid<'static, U>,
+        &'static UsbHidDriver<'static, U>,
+    );
+
+    unsafe fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let hid = static_init_half!(
+            static_buffer.0,
+            UsbHid<'static, U>,
+            UsbHid::new(
+                self.usb,
+                self.max_ctrl_packet_size,
+                self.vendor_id,
+                self.product_id,
+                self.strings,
+                &mut capsules::usb::hid::REPORT_DESCRIPTOR_BUFFER
+            )
+        );
+        self.usb.set_client(hid);
+
+        let hid_driver = static_init_half!(
+            static_buffer.1,
+            UsbHidDriver<'static, U>,
+            UsbHidDriver::new(
+                hid,
+                &mut capsules::usb::hid_user::BUFFER,
+                self.board_kernel.create_grant(&grant_cap)
+            )
+        );
+        hid.set_client(hid_driver);
+
+        (hid, hid_driver)
+    }
+}
@@ -0,0 +1,141 @@
+//! Component to initialize IPv6 address autoconfiguration.
+//!
+//! This provides one Component, AutoconfComponent, which assigns the
+//! addresses of the address table with stateless address autoconfiguration
+//! and stateless DHCPv6. The fallback addresses are assigned as static
+//! addresses if no router answers.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let autoconf = AutoconfComponent::new(
+//!        udp_send_mux,
+//!        udp_recv_mux,
+//!        ip_receive,
+//!        udp_port_table,
+//!        addr_table,
+//!        mux_alarm,
+//!        ext_addr,
+//!        &FALLBACK_ADDRS,
+//!    )
+//!    .finalize(());
+//! autoconf.start();
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules;
+use capsules::net::ipv6::addr_table::IPAddrTable;
+use capsules::net::ipv6::autoconf::Autoconf;
+use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::ipv6::ipv6_recv::{IP6Receiver, IP6RecvStruct};
+use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules::net::network_capabilities::{
+    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::udp::udp_port_table::UdpPortManager;
+use capsules::net::udp::udp_recv::{MuxUdpReceiver, UDPReceiver};
+use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::capabilities;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::component::Component;
+use kernel::hil::time::Alarm;
+use kernel::{create_capability, static_init};
+
+// Large enough for a Neighbor Advertisement or a DHCPv6 Information-Request.
+static mut AUTOCONF_BUF: [u8; 48] = [0; 48];
+
+type Ip6Sender = IP6SendStruct<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>;
+
+pub struct AutoconfComponent {
+    udp_send_mux: &'static MuxUdpSender<'static, Ip6Sender>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    ip_receive: &'static IP6RecvStruct<'static>,
+    port_table: &'static UdpPortManager,
+    addr_table: &'static IPAddrTable<'static>,
+    alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    ext_addr: [u8; 8],
+    fallback_addrs: &'static [IPAddr],
+}
+
+impl AutoconfComponent {
+    pub fn new(
+        udp_send_mux: &'static MuxUdpSender<'static, Ip6Sender>,
+        udp_recv_mux: &'static MuxUdpReceiver<'static>,
+        ip_receive: &'static IP6RecvStruct<'static>,
+        port_table: &'static UdpPortManager,
+        addr_table: &'static IPAddrTable<'static>,
+        alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+        ext_addr: [u8; 8],
+        fallback_addrs: &'static [IPAddr],
+    ) -> AutoconfComponent {
+        AutoconfComponent {
+            udp_send_mux: udp_send_mux,
+            udp_recv_mux: udp_recv_mux,
+            ip_receive: ip_receive,
+            port_table: port_table,
+            addr_table: addr_table,
+            alarm_mux: alarm_mux,
+            ext_addr: ext_addr,
+            fallback_addrs: fallback_addrs,
+        }
+    }
+}
+
+impl Component for AutoconfComponent {
+    type StaticInput = ();
+    type Output =
+        &'static Autoconf<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>, Ip6Sender>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        // Autoconfiguration only talks to link-local neighbors, routers and
+        // DHCPv6 servers.
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(
+                AddrRange::OnLink,
+                PortRange::Any,
+                PortRange::Any,
+                &create_cap
+            )
+        );
+
+        let udp_send = static_init!(
+            UDPSendStruct<'static, Ip6Sender>,
+            UDPSendStruct::new(self.udp_send_mux, udp_vis)
+        );
+        let udp_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new());
+        self.udp_recv_mux.add_client(udp_recv);
+
+        let alarm = static_init!(
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let autoconf = static_init!(
+            Autoconf<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>, Ip6Sender>,
+            Autoconf::new(
+                self.addr_table,
+                udp_send,
+                udp_recv,
+                self.port_table,
+                alarm,
+                LeasableBuffer::new(&mut AUTOCONF_BUF),
+                self.ext_addr,
+                net_cap,
+            )
+        );
+        udp_send.set_client(autoconf);
+        udp_recv.set_client(autoconf);
+        self.ip_receive.set_icmp_client(autoconf);
+        alarm.set_client(autoconf);
+        autoconf.set_fallback_addrs(self.fallback_addrs);
+        autoconf.enable_dhcpv6();
+        autoconf
+    }
+}
@@ -0,0 +1,140 @@
+//! Component to initialize the CoAP layer and its userspace driver.
+//!
+//! This provides one Component, CoapComponent, which binds a CoAP layer to
+//! the CoAP port and returns the driver through which processes serve
+//! resources and send requests.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let coap_driver = CoapComponent::new(
+//!        board_kernel,
+//!        udp_send_mux,
+//!        udp_recv_mux,
+//!        udp_port_table,
+//!        mux_alarm,
+//!        seed,
+//!    )
+//!    .finalize(());
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules;
+use capsules::net::coap::coap::COAP_PORT;
+use capsules::net::coap::coap_layer::CoapLayer;
+use capsules::net::coap::CoapDriver;
+use capsules::net::ipv6::ipv6_send::IP6SendStruct;
+use capsules::net::network_capabilities::{
+    AddrRange, NetworkCapability, PortRange, UdpVisibilityCapability,
+};
+use capsules::net::udp::udp_port_table::UdpPortManager;
+use capsules::net::udp::udp_recv::{MuxUdpReceiver, UDPReceiver};
+use capsules::net::udp::udp_send::{MuxUdpSender, UDPSendStruct, UDPSender};
+use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
+use kernel::capabilities;
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::component::Component;
+use kernel::hil::time::Alarm;
+use kernel::{create_capability, static_init};
+
+const UDP_HDR_SIZE: usize = 8;
+const PAYLOAD_LEN: usize = super::udp_mux::PAYLOAD_LEN;
+
+static mut COAP_SEND_BUF: [u8; PAYLOAD_LEN - UDP_HDR_SIZE] = [0; PAYLOAD_LEN - UDP_HDR_SIZE];
+static mut COAP_REQUEST_BUF: [u8; PAYLOAD_LEN - UDP_HDR_SIZE] = [0; PAYLOAD_LEN - UDP_HDR_SIZE];
+
+type Ip6Sender = IP6SendStruct<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>;
+type Coap = CoapLayer<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>;
+
+pub struct CoapComponent {
+    board_kernel: &'static kernel::Kernel,
+    udp_send_mux: &'static MuxUdpSender<'static, Ip6Sender>,
+    udp_recv_mux: &'static MuxUdpReceiver<'static>,
+    port_table: &'static UdpPortManager,
+    alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    seed: u32,
+}
+
+impl CoapComponent {
+    /// `seed` starts the message IDs and tokens, and should differ between
+    /// devices and boots.
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        udp_send_mux: &'static MuxUdpSender<'static, Ip6Sender>,
+        udp_recv_mux: &'static MuxUdpReceiver<'static>,
+        port_table: &'static UdpPortManager,
+        alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+        seed: u32,
+    ) -> CoapComponent {
+        CoapComponent {
+            board_kernel: board_kernel,
+            udp_send_mux: udp_send_mux,
+            udp_recv_mux: udp_recv_mux,
+            port_table: port_table,
+            alarm_mux: alarm_mux,
+            seed: seed,
+        }
+    }
+}
+
+impl Component for CoapComponent {
+    type StaticInput = ();
+    type Output = &'static CoapDriver<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+        let create_cap = create_capability!(capabilities::NetworkCapabilityCreationCapability);
+        let udp_vis = static_init!(
+            UdpVisibilityCapability,
+            UdpVisibilityCapability::new(&create_cap)
+        );
+        // CoAP talks to any address, but only from its own port.
+        let net_cap = static_init!(
+            NetworkCapability,
+            NetworkCapability::new(
+                AddrRange::Any,
+                PortRange::Any,
+                PortRange::Port(COAP_PORT),
+                &create_cap
+            )
+        );
+
+        let udp_send = static_init!(
+            UDPSendStruct<'static, Ip6Sender>,
+            UDPSendStruct::new(self.udp_send_mux, udp_vis)
+        );
+        let udp_recv = static_init!(UDPReceiver<'static>, UDPReceiver::new());
+        self.udp_recv_mux.add_client(udp_recv);
+
+        let alarm = static_init!(
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
+        let coap = static_init!(
+            Coap,
+            CoapLayer::new(
+                udp_send,
+                udp_recv,
+                self.port_table,
+                alarm,
+                LeasableBuffer::new(&mut COAP_SEND_BUF),
+                &mut COAP_REQUEST_BUF,
+                net_cap,
+            )
+        );
+        udp_send.set_client(coap);
+        udp_recv.set_client(coap);
+        alarm.set_client(coap);
+        coap.set_seed(self.seed);
+
+        let coap_driver = static_init!(
+            CoapDriver<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
+            CoapDriver::new(coap, self.board_kernel.create_grant(&grant_cap))
+        );
+        coap.set_client(coap_driver);
+        coap.set_server(coap_driver);
+        coap.bind(COAP_PORT);
+        coap_driver
+    }
+}
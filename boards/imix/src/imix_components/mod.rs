@@ -1,5 +1,8 @@
 pub mod adc;
+pub mod autoconf;
+pub mod coap;
 pub mod fxos8700;
+pub mod pcap;
 pub mod rf233;
 pub mod test;
 pub mod udp_driver;
@@ -7,7 +10,10 @@ pub mod udp_mux;
 pub mod usb;
 
 pub use self::adc::AdcComponent;
+pub use self::autoconf::AutoconfComponent;
+pub use self::coap::CoapComponent;
 pub use self::fxos8700::NineDofComponent;
+pub use self::pcap::PcapComponent;
 pub use self::rf233::RF233Component;
 pub use self::udp_driver::UDPDriverComponent;
 pub use self::udp_mux::UDPMuxComponent;
@@ -0,0 +1,61 @@
+//! Component to stream a packet capture of the 802.15.4 and IPv6 traffic.
+//!
+//! This provides one Component, PcapComponent, which creates a
+//! `PcapCapture` on a virtual UART device and attaches it to the MAC mux.
+//! The returned capture can also be passed to `UDPMuxComponent` to capture
+//! IPv6 packets. The stream can be converted to a `.pcap` file with
+//! `tools/pcap`.
+//!
+//! Usage
+//! -----
+//! ```rust
+//!    let pcap = PcapComponent::new(uart_mux, mux_mac).finalize(());
+//! ```
+
+#![allow(dead_code)] // Components are intended to be conditionally included
+
+use capsules::ieee802154::virtual_mac::MuxMac;
+use capsules::net::pcap::PcapCapture;
+use capsules::virtual_uart::{MuxUart, UartDevice};
+use kernel::component::Component;
+use kernel::hil::uart::Transmit;
+use kernel::static_init;
+
+// Each buffer holds at least one full IPv6 packet and its record framing.
+const PCAP_BUF_LEN: usize = 1400;
+static mut PCAP_BUF1: [u8; PCAP_BUF_LEN] = [0; PCAP_BUF_LEN];
+static mut PCAP_BUF2: [u8; PCAP_BUF_LEN] = [0; PCAP_BUF_LEN];
+
+pub struct PcapComponent {
+    uart_mux: &'static MuxUart<'static>,
+    mux_mac: &'static MuxMac<'static>,
+}
+
+impl PcapComponent {
+    pub fn new(
+        uart_mux: &'static MuxUart<'static>,
+        mux_mac: &'static MuxMac<'static>,
+    ) -> PcapComponent {
+        PcapComponent {
+            uart_mux: uart_mux,
+            mux_mac: mux_mac,
+        }
+    }
+}
+
+impl Component for PcapComponent {
+    type StaticInput = ();
+    type Output = &'static PcapCapture<'static, UartDevice<'static>, sam4l::ast::Ast<'static>>;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let pcap_uart = static_init!(UartDevice, UartDevice::new(self.uart_mux, false));
+        pcap_uart.setup();
+        let pcap = static_init!(
+            PcapCapture<'static, UartDevice<'static>, sam4l::ast::Ast<'static>>,
+            PcapCapture::new(pcap_uart, &sam4l::ast::AST, &mut PCAP_BUF1, &mut PCAP_BUF2)
+        );
+        pcap_uart.set_transmit_client(pcap);
+        self.mux_mac.set_capture_tap(pcap);
+        pcap
+    }
+}
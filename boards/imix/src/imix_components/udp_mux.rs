@@ -2,20 +2,21 @@
 //!
 //! This provides one Component, UDPMuxComponent. This component
 //! exposes a MuxUdpSender that other components can implement
-//! UDPSenders on top of to use the UDP/6Lowpan stack.
+//! UDPSenders on top of to use the UDP/6Lowpan stack, and the IPv6
+//! receiver that passes ICMPv6 messages to its ICMP client.
 //!
 //! Usage
 //! -----
 //! ```rust
-//!    let (udp_mux, udp_recv) = UDPMuxComponent::new(
+//!    let (udp_mux, udp_recv, udp_port_table, lowpan_stats, ip_receive) = UDPMuxComponent::new(
 //!        mux_mac,
 //!        DEFAULT_CTX_PREFIX_LEN,
 //!        DEFAULT_CTX_PREFIX,
 //!        DST_MAC_ADDR,
 //!        src_mac_from_serial_num,
-//!        local_ip_ifaces,
+//!        addr_table,
 //!        mux_alarm,
-//!        PAYLOAD_LEN,
+//!        None,
 //!    )
 //!    .finalize();
 //! ```
@@ -28,12 +29,13 @@
 use capsules;
 use capsules::ieee802154::device::MacDevice;
 use capsules::net::ieee802154::MacAddress;
-use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::ipv6::addr_table::IPAddrTable;
 use capsules::net::ipv6::ipv6::{IP6Packet, IPPayload, TransportHeader};
 use capsules::net::ipv6::ipv6_recv::IP6Receiver;
 use capsules::net::ipv6::ipv6_send::IP6SendStruct;
 use capsules::net::ipv6::ipv6_send::IP6Sender;
 use capsules::net::network_capabilities::{IpVisibilityCapability, UdpVisibilityCapability};
+use capsules::net::pcap::CaptureTap;
 use capsules::net::sixlowpan::{sixlowpan_compression, sixlowpan_state};
 use capsules::net::udp::udp::UDPHeader;
 use capsules::net::udp::udp_port_table::{SocketBindingEntry, UdpPortManager, MAX_NUM_BOUND_PORTS};
@@ -53,14 +55,18 @@ use sam4l;
 // The UDP stack requires exactly one of several packet buffers:
 //
 //   1. RF233_BUF: buffer the IP6_Sender uses to pass frames to the radio after fragmentation
-//   2. SIXLOWPAN_RX_BUF: Buffer to hold full IP packets after they are decompressed by 6LoWPAN
+//   2. SIXLOWPAN_RX_BUFS: Buffers to hold full IP packets after they are decompressed by 6LoWPAN,
+//      one for each packet that can be reassembled concurrently
 //   3. udp_dgram: The payload of the IP6_Packet, which holds full IP Packets before they are tx'd.
 //
 //   Additionally, every capsule using the stack needs an additional buffer to craft packets for
 //   tx which can then be passed to the MuxUdpSender for tx.
 
 static mut RF233_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
-static mut SIXLOWPAN_RX_BUF: [u8; 1280] = [0x00; 1280];
+// The number of fragmented packets that can be reassembled at the same time.
+const SIXLOWPAN_RX_STATES: usize = 2;
+static mut SIXLOWPAN_RX_BUFS: [[u8; 1280]; SIXLOWPAN_RX_STATES] =
+    [[0x00; 1280]; SIXLOWPAN_RX_STATES];
 
 pub const PAYLOAD_LEN: usize = 200; //The max size UDP message that can be sent by userspace apps or capsules
 const UDP_HDR_SIZE: usize = 8;
@@ -84,8 +90,9 @@ pub struct UDPMuxComponent {
     ctx_pfix: [u8; 16],
     dst_mac_addr: MacAddress,
     src_mac_addr: MacAddress,
-    interface_list: &'static [IPAddr],
+    addr_table: &'static IPAddrTable<'static>,
     alarm_mux: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    capture_tap: Option<&'static dyn CaptureTap>,
 }
 
 impl UDPMuxComponent {
@@ -95,8 +102,9 @@ impl UDPMuxComponent {
         ctx_pfix: [u8; 16],
         dst_mac_addr: MacAddress,
         src_mac_addr: MacAddress,
-        interface_list: &'static [IPAddr],
+        addr_table: &'static IPAddrTable<'static>,
         alarm: &'static MuxAlarm<'static, sam4l::ast::Ast<'static>>,
+        capture_tap: Option<&'static dyn CaptureTap>,
     ) -> UDPMuxComponent {
         UDPMuxComponent {
             mux_mac: mux_mac,
@@ -104,8 +112,9 @@ impl UDPMuxComponent {
             ctx_pfix: ctx_pfix,
             dst_mac_addr: dst_mac_addr,
             src_mac_addr: src_mac_addr,
-            interface_list: interface_list,
+            addr_table: addr_table,
             alarm_mux: alarm,
+            capture_tap: capture_tap,
         }
     }
 }
@@ -119,6 +128,8 @@ impl Component for UDPMuxComponent {
         >,
         &'static MuxUdpReceiver<'static>,
         &'static UdpPortManager,
+        &'static dyn sixlowpan_state::SixlowpanStatistics,
+        &'static capsules::net::ipv6::ipv6_recv::IP6RecvStruct<'static>,
     );
 
     unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
@@ -142,10 +153,14 @@ impl Component for UDPMuxComponent {
             IpVisibilityCapability::new(&create_cap)
         );
 
+        let sixlowpan_alarm = static_init!(
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+            VirtualMuxAlarm::new(self.alarm_mux)
+        );
         let sixlowpan = static_init!(
             sixlowpan_state::Sixlowpan<
                 'static,
-                sam4l::ast::Ast<'static>,
+                VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
                 sixlowpan_compression::Context,
             >,
             sixlowpan_state::Sixlowpan::new(
@@ -155,17 +170,23 @@ impl Component for UDPMuxComponent {
                     id: 0,
                     compress: false,
                 },
-                &sam4l::ast::AST
+                sixlowpan_alarm
             )
         );
+        sixlowpan_alarm.set_client(sixlowpan);
 
         let sixlowpan_state = sixlowpan as &dyn sixlowpan_state::SixlowpanState;
         let sixlowpan_tx = sixlowpan_state::TxState::new(sixlowpan_state);
-        let default_rx_state = static_init!(
-            sixlowpan_state::RxState<'static>,
-            sixlowpan_state::RxState::new(&mut SIXLOWPAN_RX_BUF)
+        let rx_states = static_init!(
+            [sixlowpan_state::RxState<'static>; SIXLOWPAN_RX_STATES],
+            [
+                sixlowpan_state::RxState::new(&mut SIXLOWPAN_RX_BUFS[0]),
+                sixlowpan_state::RxState::new(&mut SIXLOWPAN_RX_BUFS[1]),
+            ]
         );
-        sixlowpan_state.add_rx_state(default_rx_state);
+        for rx_state in rx_states.iter() {
+            sixlowpan_state.add_rx_state(rx_state);
+        }
         udp_mac.set_receive_client(sixlowpan);
 
         let tr_hdr = TransportHeader::UDP(UDPHeader::new());
@@ -199,17 +220,23 @@ impl Component for UDPMuxComponent {
         );
         ipsender_virtual_alarm.set_client(ip_send);
 
-        // Initially, set src IP of the sender to be the first IP in the Interface
-        // list. Userland apps can change this if they so choose.
-        // Notably, the src addr is the same regardless of if messages are sent from
-        // userland or capsules.
-        ip_send.set_addr(self.interface_list[0]);
+        // The src IP of each packet is selected from the address table based on
+        // its destination. Notably, the src addr is the same regardless of if
+        // messages are sent from userland or capsules. The first address in the
+        // table is only used while no address is assigned.
+        self.addr_table.get(0).map(|addr| ip_send.set_addr(addr));
+        ip_send.set_addr_table(self.addr_table);
         udp_mac.set_transmit_client(ip_send);
 
         let ip_receive = static_init!(
             capsules::net::ipv6::ipv6_recv::IP6RecvStruct<'static>,
             capsules::net::ipv6::ipv6_recv::IP6RecvStruct::new()
         );
+        ip_receive.set_addr_table(self.addr_table);
+        self.capture_tap.map(|tap| {
+            ip_send.set_capture_tap(tap);
+            ip_receive.set_capture_tap(tap);
+        });
         sixlowpan_state.set_rx_client(ip_receive);
         let udp_recv_mux = static_init!(MuxUdpReceiver<'static>, MuxUdpReceiver::new());
         ip_receive.set_client(udp_recv_mux);
@@ -232,6 +259,12 @@ impl Component for UDPMuxComponent {
             UdpPortManager::new(&create_table_cap, &mut USED_KERNEL_PORTS, udp_vis)
         );
 
-        (udp_send_mux, udp_recv_mux, udp_port_table)
+        (
+            udp_send_mux,
+            udp_recv_mux,
+            udp_port_table,
+            sixlowpan,
+            ip_receive,
+        )
     }
 }
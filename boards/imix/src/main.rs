@@ -13,7 +13,10 @@
 mod imix_components;
 use capsules::alarm::AlarmDriver;
 use capsules::net::ieee802154::MacAddress;
+use capsules::net::ipv6::addr_table::{AddrEntry, IPAddrTable};
 use capsules::net::ipv6::ip_utils::IPAddr;
+use capsules::net::sixlowpan::sixlowpan_state::SixlowpanStatistics;
+use capsules::virtual_aes::VirtualAES128;
 use capsules::virtual_alarm::VirtualMuxAlarm;
 use capsules::virtual_i2c::MuxI2C;
 use capsules::virtual_spi::VirtualSpiMasterDevice;
@@ -29,6 +32,7 @@ use kernel::hil::Controller;
 use kernel::{create_capability, debug, debug_gpio, static_init};
 
 use components;
+use components::aes::{AesComponent, AesMuxComponent};
 use components::alarm::{AlarmDriverComponent, AlarmMuxComponent};
 use components::console::{ConsoleComponent, UartMuxComponent};
 use components::crc::CrcComponent;
@@ -38,10 +42,12 @@ use components::isl29035::AmbientLightComponent;
 use components::led::LedsComponent;
 use components::nrf51822::Nrf51822Component;
 use components::process_console::ProcessConsoleComponent;
-use components::rng::RngComponent;
+use components::rng::DrbgRngComponent;
 use components::si7021::{HumidityComponent, SI7021Component};
 use components::spi::{SpiComponent, SpiSyscallComponent};
 use imix_components::adc::AdcComponent;
+use imix_components::autoconf::AutoconfComponent;
+use imix_components::coap::CoapComponent;
 use imix_components::fxos8700::NineDofComponent;
 use imix_components::rf233::RF233Component;
 use imix_components::udp_driver::UDPDriverComponent;
@@ -77,6 +83,19 @@ const DST_MAC_ADDR: MacAddress = MacAddress::Short(49138);
 const DEFAULT_CTX_PREFIX_LEN: u8 = 8; //Length of context for 6LoWPAN compression
 const DEFAULT_CTX_PREFIX: [u8; 16] = [0x0 as u8; 16]; //Context for 6LoWPAN Compression
 const PAN_ID: u16 = 0xABCD;
+// Number of local IPv6 addresses, including ones assigned at runtime
+const LOCAL_IP_TABLE_SIZE: usize = 6;
+// Addresses assigned if no router answers autoconfiguration
+static FALLBACK_IP_ADDRS: [IPAddr; 2] = [
+    IPAddr([
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+        0x0f,
+    ]),
+    IPAddr([
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e,
+        0x1f,
+    ]),
+];
 
 // how should the kernel respond when a process faults
 const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
@@ -117,13 +136,25 @@ struct Imix {
     ninedof: &'static capsules::ninedof::NineDof<'static>,
     radio_driver: &'static capsules::ieee802154::RadioDriver<'static>,
     udp_driver: &'static capsules::net::udp::UDPDriver<'static>,
+    coap_driver: &'static capsules::net::coap::CoapDriver<
+        'static,
+        VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+    >,
+    lowpan_stats: &'static capsules::net::sixlowpan::SixlowpanStatsDriver<'static>,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
     usb_driver: &'static capsules::usb::usb_user::UsbSyscallDriver<
         'static,
         capsules::usb::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
     >,
     nrf51822: &'static capsules::nrf51822_serialization::Nrf51822Serialization<'static>,
-    nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+    nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<
+        'static,
+        components::nonvolatile_storage::Capability,
+    >,
+    aes: &'static capsules::aes::AesDriver<
+        'static,
+        VirtualAES128<'static, sam4l::aes::Aes<'static>>,
+    >,
 }
 
 // The RF233 radio stack requires our buffers for its SPI operations:
@@ -161,9 +192,12 @@ impl kernel::Platform for Imix {
             capsules::usb::usb_user::DRIVER_NUM => f(Some(self.usb_driver)),
             capsules::ieee802154::DRIVER_NUM => f(Some(self.radio_driver)),
             capsules::net::udp::DRIVER_NUM => f(Some(self.udp_driver)),
+            capsules::net::coap::DRIVER_NUM => f(Some(self.coap_driver)),
+            capsules::net::sixlowpan::DRIVER_NUM => f(Some(self.lowpan_stats)),
             capsules::nrf51822_serialization::DRIVER_NUM => f(Some(self.nrf51822)),
             capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
+            capsules::aes::DRIVER_NUM => f(Some(self.aes)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
@@ -277,7 +311,7 @@ pub unsafe fn reset_handler() {
     let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 2], Default::default());
+        static_init!([DynamicDeferredCallClientState; 4], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -391,7 +425,8 @@ pub unsafe fn reset_handler() {
         ),
     )
     .finalize(components::acomp_component_buf!(sam4l::acifc::Acifc));
-    let rng = RngComponent::new(board_kernel, &sam4l::trng::TRNG).finalize(());
+    let rng = DrbgRngComponent::new(board_kernel, &sam4l::trng::TRNG, dynamic_deferred_caller)
+        .finalize(());
 
     // For now, assign the 802.15.4 MAC address on the device as
     // simply a 16-bit short address which represents the last 16 bits
@@ -402,18 +437,32 @@ pub unsafe fn reset_handler() {
     let serial_num_bottom_16 = (serial_num.get_lower_64() & 0x0000_0000_0000_ffff) as u16;
     let src_mac_from_serial_num: MacAddress = MacAddress::Short(serial_num_bottom_16);
 
+    // The 802.15.4 framer and the userspace AES driver share the AES engine
+    let mux_aes = AesMuxComponent::new(&sam4l::aes::AES, dynamic_deferred_caller)
+        .finalize(components::aes_mux_component_helper!(sam4l::aes::Aes));
+    let aes_802154 = static_init!(
+        VirtualAES128<'static, sam4l::aes::Aes<'static>>,
+        VirtualAES128::new(mux_aes)
+    );
+    let aes = AesComponent::new(board_kernel, mux_aes)
+        .finalize(components::aes_component_helper!(sam4l::aes::Aes));
+
     // Can this initialize be pushed earlier, or into component? -pal
     rf233.initialize(&mut RF233_BUF, &mut RF233_REG_WRITE, &mut RF233_REG_READ);
-    let (radio_driver, mux_mac) = components::ieee802154::Ieee802154Component::new(
+    // The RF233 acknowledges received frames in hardware
+    let (radio_driver, mux_mac) = components::ieee802154::Ieee802154CsmaComponent::new(
         board_kernel,
         rf233,
-        &sam4l::aes::AES,
+        aes_802154,
+        mux_alarm,
         PAN_ID,
         serial_num_bottom_16,
+        false,
     )
-    .finalize(components::ieee802154_component_helper!(
+    .finalize(components::ieee802154_csma_component_helper!(
         capsules::rf233::RF233<'static, VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>>,
-        sam4l::aes::Aes<'static>
+        VirtualAES128<'static, sam4l::aes::Aes<'static>>,
+        sam4l::ast::Ast<'static>
     ));
 
     let usb_driver = UsbComponent::new(board_kernel).finalize(());
@@ -431,6 +480,8 @@ pub unsafe fn reset_handler() {
         &sam4l::flashcalw::FLASH_CONTROLLER,
         0x60000,                          // Start address for userspace accessible region
         0x20000,                          // Length of userspace accessible region
+        0x1000,                           // Default size of app regions
+        0x4000,                           // Largest size of an app region
         &_sstorage as *const u8 as usize, //start address of kernel region
         &_estorage as *const u8 as usize - &_sstorage as *const u8 as usize, // length of kernel region
     )
@@ -438,43 +489,72 @@ pub unsafe fn reset_handler() {
         sam4l::flashcalw::FLASHCALW
     ));
 
-    let local_ip_ifaces = static_init!(
-        [IPAddr; 3],
-        [
-            IPAddr([
-                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
-                0x0e, 0x0f,
-            ]),
-            IPAddr([
-                0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d,
-                0x1e, 0x1f,
-            ]),
-            IPAddr::generate_from_mac(src_mac_from_serial_num),
-        ]
+    let local_ip_entries = static_init!(
+        [Option<AddrEntry>; LOCAL_IP_TABLE_SIZE],
+        [None; LOCAL_IP_TABLE_SIZE]
     );
+    let local_ip_ifaces = static_init!(IPAddrTable<'static>, IPAddrTable::new(local_ip_entries));
+
+    // Uncomment to stream a capture of all 802.15.4 frames and IPv6 packets
+    // over the console UART. Use tools/pcap to convert it to a pcap file.
+    //let pcap = Some(imix_components::PcapComponent::new(uart_mux, mux_mac).finalize(())
+    //    as &dyn capsules::net::pcap::CaptureTap);
+    let pcap = None;
+
+    let (udp_send_mux, udp_recv_mux, udp_port_table, lowpan_stats, ip_receive) =
+        UDPMuxComponent::new(
+            mux_mac,
+            DEFAULT_CTX_PREFIX_LEN,
+            DEFAULT_CTX_PREFIX,
+            DST_MAC_ADDR,
+            src_mac_from_serial_num, //comment out for dual rx test only
+            //MacAddress::Short(49138), //comment in for dual rx test only
+            local_ip_ifaces,
+            mux_alarm,
+            pcap,
+        )
+        .finalize(());
 
-    let (udp_send_mux, udp_recv_mux, udp_port_table) = UDPMuxComponent::new(
-        mux_mac,
-        DEFAULT_CTX_PREFIX_LEN,
-        DEFAULT_CTX_PREFIX,
-        DST_MAC_ADDR,
-        src_mac_from_serial_num, //comment out for dual rx test only
-        //MacAddress::Short(49138), //comment in for dual rx test only
+    // UDP driver initialization happens here
+    let udp_driver = UDPDriverComponent::new(
+        board_kernel,
+        udp_send_mux,
+        udp_recv_mux,
+        udp_port_table,
         local_ip_ifaces,
-        mux_alarm,
     )
     .finalize(());
 
-    // UDP driver initialization happens here
-    let udp_driver = UDPDriverComponent::new(
+    let coap_driver = CoapComponent::new(
         board_kernel,
         udp_send_mux,
         udp_recv_mux,
         udp_port_table,
+        mux_alarm,
+        serial_num.get_lower_64() as u32,
+    )
+    .finalize(());
+
+    // Addresses are assigned by autoconfiguration, or are the fallback
+    // addresses if no router answers.
+    let autoconf = AutoconfComponent::new(
+        udp_send_mux,
+        udp_recv_mux,
+        ip_receive,
+        udp_port_table,
         local_ip_ifaces,
+        mux_alarm,
+        serial_num.get_lower_64().to_be_bytes(),
+        &FALLBACK_IP_ADDRS,
     )
     .finalize(());
 
+    let lowpan_interfaces = static_init!([&'static dyn SixlowpanStatistics; 1], [lowpan_stats]);
+    let lowpan_stats_driver = static_init!(
+        capsules::net::sixlowpan::SixlowpanStatsDriver<'static>,
+        capsules::net::sixlowpan::SixlowpanStatsDriver::new(lowpan_interfaces)
+    );
+
     let imix = Imix {
         pconsole,
         console,
@@ -494,9 +574,12 @@ pub unsafe fn reset_handler() {
         ninedof,
         radio_driver,
         udp_driver,
+        coap_driver,
+        lowpan_stats: lowpan_stats_driver,
         usb_driver,
         nrf51822: nrf_serialization,
         nonvolatile_storage: nonvolatile_storage,
+        aes,
     };
 
     let chip = static_init!(sam4l::chip::Sam4l, sam4l::chip::Sam4l::new());
@@ -509,6 +592,7 @@ pub unsafe fn reset_handler() {
     // initialization to work.
     rf233.reset();
     rf233.start();
+    autoconf.start();
 
     imix.pconsole.start();
 
@@ -521,6 +605,7 @@ pub unsafe fn reset_handler() {
     //test::virtual_uart_rx_test::run_virtual_uart_receive(uart_mux);
     //test::rng_test::run_entropy32();
     //test::aes_ccm_test::run();
+    //test::aes_gcm_test::run();
     //test::aes_test::run_aes128_ctr();
     //test::aes_test::run_aes128_cbc();
     //test::log_test::run(mux_alarm, dynamic_deferred_caller);
@@ -0,0 +1,50 @@
+//! To run this test, include the code
+//! ```
+//!    test::aes_gcm_test::run();
+//! ```
+//! In the boot sequence. If it runs correctly, you should see the following
+//! output:
+//!
+//! aes_gcm_test passed: (current_test=0, encrypting=true, tag_is_valid=true)
+//! aes_gcm_test passed: (current_test=0, encrypting=false, tag_is_valid=true)
+//! aes_gcm_test passed: (current_test=1, encrypting=true, tag_is_valid=true)
+//! aes_gcm_test passed: (current_test=1, encrypting=false, tag_is_valid=true)
+//! aes_gcm_test passed: (current_test=2, encrypting=true, tag_is_valid=true)
+//! aes_gcm_test passed: (current_test=2, encrypting=false, tag_is_valid=true)
+//! aes_gcm_test passed: (current_test=3, encrypting=true, tag_is_valid=true)
+//! aes_gcm_test passed: (current_test=3, encrypting=false, tag_is_valid=true)
+
+use capsules::aes_gcm;
+use capsules::test::aes_gcm::Test;
+use kernel::hil::symmetric_encryption::{AES128, AES128GCM, AES128_BLOCK_SIZE};
+use kernel::static_init;
+use sam4l::aes::{Aes, AES};
+
+pub unsafe fn run() {
+    let gcm = static_init_gcm();
+    AES.set_client(gcm);
+    AES.enable();
+
+    let t = static_init_test(gcm);
+    gcm.set_client(t);
+
+    t.run();
+}
+
+unsafe fn static_init_gcm() -> &'static mut aes_gcm::AES128GCM<'static, Aes<'static>> {
+    const CRYPT_SIZE: usize = 6 * AES128_BLOCK_SIZE;
+    let crypt_buf = static_init!([u8; CRYPT_SIZE], [0x00; CRYPT_SIZE]);
+    static_init!(
+        aes_gcm::AES128GCM<'static, Aes<'static>>,
+        aes_gcm::AES128GCM::new(&AES, crypt_buf)
+    )
+}
+
+type AESGCM = aes_gcm::AES128GCM<'static, Aes<'static>>;
+
+#[allow(clippy::mut_from_ref)]
+// Static init returns a singly owned mutable reference
+unsafe fn static_init_test(aes_gcm: &'static AESGCM) -> &'static mut Test<'static, AESGCM> {
+    let data = static_init!([u8; 7 * AES128_BLOCK_SIZE], [0x00; 7 * AES128_BLOCK_SIZE]);
+    static_init!(Test<'static, AESGCM>, Test::new(aes_gcm, data))
+}
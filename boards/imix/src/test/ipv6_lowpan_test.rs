@@ -137,8 +137,16 @@ pub unsafe fn initialize_all(
     mux_mac.add_user(radio_mac);
     let default_rx_state = static_init!(RxState<'static>, RxState::new(&mut RX_STATE_BUF));
 
+    let sixlowpan_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
     let sixlowpan = static_init!(
-        Sixlowpan<'static, sam4l::ast::Ast<'static>, sixlowpan_compression::Context>,
+        Sixlowpan<
+            'static,
+            VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
+            sixlowpan_compression::Context,
+        >,
         Sixlowpan::new(
             sixlowpan_compression::Context {
                 prefix: DEFAULT_CTX_PREFIX,
@@ -146,9 +154,10 @@ pub unsafe fn initialize_all(
                 id: 0,
                 compress: false,
             },
-            &sam4l::ast::AST
+            sixlowpan_alarm
         )
     );
+    sixlowpan_alarm.set_client(sixlowpan);
 
     let sixlowpan_state = sixlowpan as &dyn SixlowpanState;
     let sixlowpan_tx = TxState::new(sixlowpan_state);
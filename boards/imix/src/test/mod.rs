@@ -1,4 +1,5 @@
 pub(crate) mod aes_ccm_test;
+pub(crate) mod aes_gcm_test;
 pub(crate) mod aes_test;
 pub(crate) mod i2c_dummy;
 pub(crate) mod icmp_lowpan_test;
@@ -81,6 +81,8 @@
 //! `examples/tests/udp/udp_virt_app_tests/` flashed on this second board. Press reset on the
 //! second board at least 2 seconds after running `tockloader listen` on the receiving board to run
 //! this test.
+//! Without a router on the link, the boards only use the addresses below once autoconfiguration
+//! falls back to them, about 20 seconds after reset.
 //!
 //! start_rx() expected output:
 //!
@@ -70,6 +70,7 @@
 use capsules::virtual_alarm::VirtualMuxAlarm;
 use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::component::Component;
+use kernel::hil;
 #[allow(unused_imports)]
 use kernel::{capabilities, create_capability, debug, debug_gpio, debug_verbose, static_init};
 use nrf52840::gpio::Pin;
@@ -101,6 +102,23 @@ const SPI_MX25R6435F_CHIP_SELECT: Pin = Pin::P0_17;
 const SPI_MX25R6435F_WRITE_PROTECT_PIN: Pin = Pin::P0_22;
 const SPI_MX25R6435F_HOLD_PIN: Pin = Pin::P0_23;
 
+// The process that gets a log in the internal flash, and the size of the log
+const LOG_PROCESS_NAME: &str = "datalogger";
+mod log_volume {
+    kernel::storage_volume!(APP_LOG, 16);
+}
+
+// Internal flash page size
+const FLASH_PAGE_SIZE: usize = 4096;
+// Buffer for processes writing their own flash
+static mut APP_FLASH_BUFFER: [u8; 512] = [0; 512];
+
+type FlashUser = capsules::virtual_flash::FlashUser<'static, nrf52840::nvmc::Nvmc>;
+type Log = capsules::log::Log<'static, FlashUser>;
+
+struct ProcessMgmtCap;
+unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+
 // Constants related to the configuration of the 15.4 network stack
 const SRC_MAC: u16 = 0xf00f;
 const PAN_ID: u16 = 0xABCD;
@@ -157,7 +175,12 @@ pub struct Platform {
         'static,
         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52840::rtc::Rtc<'static>>,
     >,
-    nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+    nonvolatile_storage: &'static capsules::nonvolatile_storage_driver::NonvolatileStorage<
+        'static,
+        components::nonvolatile_storage::Capability,
+    >,
+    log: &'static capsules::log_driver::LogDriver<'static, Log, ProcessMgmtCap>,
+    app_flash: &'static capsules::app_flash_driver::AppFlash<'static>,
 }
 
 impl kernel::Platform for Platform {
@@ -177,6 +200,8 @@ impl kernel::Platform for Platform {
             capsules::temperature::DRIVER_NUM => f(Some(self.temp)),
             capsules::analog_comparator::DRIVER_NUM => f(Some(self.analog_comparator)),
             capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
+            capsules::log_driver::DRIVER_NUM => f(Some(self.log)),
+            capsules::app_flash_driver::DRIVER_NUM => f(Some(self.app_flash)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
@@ -313,7 +338,7 @@ pub unsafe fn reset_handler() {
     let channel = nrf52_components::UartChannelComponent::new(uart_channel, mux_alarm).finalize(());
 
     let dynamic_deferred_call_clients =
-        static_init!([DynamicDeferredCallClientState; 2], Default::default());
+        static_init!([DynamicDeferredCallClientState; 3], Default::default());
     let dynamic_deferred_caller = static_init!(
         DynamicDeferredCall,
         DynamicDeferredCall::new(dynamic_deferred_call_clients)
@@ -338,16 +363,19 @@ pub unsafe fn reset_handler() {
         nrf52_components::BLEComponent::new(board_kernel, &nrf52840::ble_radio::RADIO, mux_alarm)
             .finalize(());
 
-    let (ieee802154_radio, _mux_mac) = components::ieee802154::Ieee802154Component::new(
+    let (ieee802154_radio, _mux_mac) = components::ieee802154::Ieee802154CsmaComponent::new(
         board_kernel,
         &nrf52840::ieee802154_radio::RADIO,
         &nrf52840::aes::AESECB,
+        mux_alarm,
         PAN_ID,
         SRC_MAC,
+        true,
     )
-    .finalize(components::ieee802154_component_helper!(
+    .finalize(components::ieee802154_csma_component_helper!(
         nrf52840::ieee802154_radio::Radio,
-        nrf52840::aes::AesECB<'static>
+        nrf52840::aes::AesECB<'static>,
+        nrf52840::rtc::Rtc<'static>
     ));
 
     let temp = components::temperature::TemperatureComponent::new(
@@ -386,6 +414,8 @@ pub unsafe fn reset_handler() {
         mx25r6435f,
         0x60000, // Start address for userspace accessible region
         0x20000, // Length of userspace accessible region
+        0x1000,  // Default size of app regions
+        0x4000,  // Largest size of an app region
         0,       // Start address of kernel region
         0x60000, // Length of kernel region
     )
@@ -398,6 +428,88 @@ pub unsafe fn reset_handler() {
         >
     ));
 
+    // The log and the processes writing their own flash share the internal
+    // flash, each restricted to its own pages
+    let mux_flash = static_init!(
+        capsules::virtual_flash::MuxFlash<'static, nrf52840::nvmc::Nvmc>,
+        capsules::virtual_flash::MuxFlash::new(&nrf52840::nvmc::NVMC, dynamic_deferred_caller)
+    );
+    mux_flash.initialize_callback_handle(
+        dynamic_deferred_caller
+            .register(mux_flash)
+            .expect("no deferred call slot available for flash mux"),
+    );
+    hil::flash::HasClient::set_client(&nrf52840::nvmc::NVMC, mux_flash);
+
+    let log_flash = static_init!(
+        FlashUser,
+        capsules::virtual_flash::FlashUser::new(
+            mux_flash,
+            log_volume::APP_LOG.as_ptr() as usize / FLASH_PAGE_SIZE,
+            log_volume::APP_LOG.len() / FLASH_PAGE_SIZE,
+        )
+    );
+    let log_pagebuffer = static_init!(nrf52840::nvmc::NrfPage, nrf52840::nvmc::NrfPage::default());
+    let app_log = static_init!(
+        Log,
+        capsules::log::Log::new(
+            &log_volume::APP_LOG,
+            log_flash,
+            log_pagebuffer,
+            dynamic_deferred_caller,
+            true
+        )
+    );
+    hil::flash::HasClient::set_client(log_flash, app_log);
+    app_log.initialize_callback_handle(
+        dynamic_deferred_caller
+            .register(app_log)
+            .expect("no deferred call slot available for log storage"),
+    );
+    let app_logs = static_init!(
+        [capsules::log_driver::AppLog<'static, Log>; 1],
+        [capsules::log_driver::AppLog::new(LOG_PROCESS_NAME, app_log)]
+    );
+    let log = static_init!(
+        capsules::log_driver::LogDriver<'static, Log, ProcessMgmtCap>,
+        capsules::log_driver::LogDriver::new(
+            app_logs,
+            board_kernel,
+            ProcessMgmtCap,
+            board_kernel.create_grant(&memory_allocation_capability),
+            &mut capsules::log_driver::BUFFER
+        )
+    );
+    log.set_clients();
+
+    let app_flash_user = static_init!(
+        FlashUser,
+        capsules::virtual_flash::FlashUser::new(
+            mux_flash,
+            &_sapps as *const u8 as usize / FLASH_PAGE_SIZE,
+            (&_eapps as *const u8 as usize - &_sapps as *const u8 as usize) / FLASH_PAGE_SIZE,
+        )
+    );
+    let app_flash_pagebuffer =
+        static_init!(nrf52840::nvmc::NrfPage, nrf52840::nvmc::NrfPage::default());
+    let app_flash_to_pages = static_init!(
+        capsules::nonvolatile_to_pages::NonvolatileToPages<'static, FlashUser>,
+        capsules::nonvolatile_to_pages::NonvolatileToPages::new(
+            app_flash_user,
+            app_flash_pagebuffer
+        )
+    );
+    hil::flash::HasClient::set_client(app_flash_user, app_flash_to_pages);
+    let app_flash = static_init!(
+        capsules::app_flash_driver::AppFlash<'static>,
+        capsules::app_flash_driver::AppFlash::new(
+            app_flash_to_pages,
+            board_kernel.create_grant(&memory_allocation_capability),
+            &mut APP_FLASH_BUFFER
+        )
+    );
+    hil::nonvolatile_storage::NonvolatileStorage::set_client(app_flash_to_pages, app_flash);
+
     // Initialize AC using AIN5 (P0.29) as VIN+ and VIN- as AIN0 (P0.02)
     // These are hardcoded pin assignments specified in the driver
     let analog_comparator = components::analog_comparator::AcComponent::new(
@@ -426,6 +538,8 @@ pub unsafe fn reset_handler() {
         alarm,
         analog_comparator,
         nonvolatile_storage,
+        log,
+        app_flash,
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
     };
 
@@ -5,6 +5,19 @@
 //! ```rust
 //! let ble_radio = BLEComponent::new(board_kernel, &nrf52::ble_radio::RADIO, mux_alarm).finalize();
 //! ```
+//!
+//! The connectable peripheral with a GATT server uses the same radio, so a
+//! board provides either of the two drivers:
+//!
+//! ```rust
+//! let ble_peripheral = BlePeripheralComponent::new(
+//!     board_kernel,
+//!     &nrf52::ble_radio::RADIO,
+//!     mux_alarm,
+//!     b"Tock",
+//! )
+//! .finalize(());
+//! ```
 
 use capsules;
 use capsules::virtual_alarm::VirtualMuxAlarm;
@@ -73,8 +86,94 @@ impl Component for BLEComponent {
         kernel::hil::ble_advertising::BleAdvertisementDriver::set_transmit_client(
             self.radio, ble_radio,
         );
+        kernel::hil::ble_advertising::BleLinkLayerDriver::set_exchange_client(
+            self.radio, ble_radio,
+        );
+        ble_radio.set_link_layer(self.radio);
         hil::time::Alarm::set_client(ble_radio_virtual_alarm, ble_radio);
 
         ble_radio
     }
 }
+
+pub struct BlePeripheralComponent {
+    board_kernel: &'static kernel::Kernel,
+    radio: &'static nrf52::ble_radio::Radio<'static>,
+    mux_alarm: &'static capsules::virtual_alarm::MuxAlarm<'static, nrf52::rtc::Rtc<'static>>,
+    device_name: &'static [u8],
+}
+
+impl BlePeripheralComponent {
+    pub fn new(
+        board_kernel: &'static kernel::Kernel,
+        radio: &'static nrf52::ble_radio::Radio,
+        mux_alarm: &'static capsules::virtual_alarm::MuxAlarm<'static, nrf52::rtc::Rtc>,
+        device_name: &'static [u8],
+    ) -> BlePeripheralComponent {
+        BlePeripheralComponent {
+            board_kernel: board_kernel,
+            radio: radio,
+            mux_alarm: mux_alarm,
+            device_name: device_name,
+        }
+    }
+}
+
+impl Component for BlePeripheralComponent {
+    type StaticInput = ();
+    type Output = &'static capsules::ble::peripheral::BlePeripheral<
+        'static,
+        nrf52::ble_radio::Radio<'static>,
+        VirtualMuxAlarm<'static, Rtc<'static>>,
+    >;
+
+    unsafe fn finalize(self, _s: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        let ble_virtual_alarm = static_init!(
+            capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf52::rtc::Rtc>,
+            capsules::virtual_alarm::VirtualMuxAlarm::new(self.mux_alarm)
+        );
+
+        // The factory programmed address is used as a static random address,
+        // which requires the two most significant bits to be set
+        let mut address = nrf52::ficr::FICR_INSTANCE.address();
+        address[5] |= 0xc0;
+
+        let peripheral = static_init!(
+            capsules::ble::peripheral::Peripheral<
+                'static,
+                nrf52::ble_radio::Radio,
+                VirtualMuxAlarm<'static, Rtc>,
+            >,
+            capsules::ble::peripheral::Peripheral::new(
+                self.radio,
+                ble_virtual_alarm,
+                address,
+                self.device_name,
+                &mut capsules::ble::peripheral::DATABASE,
+                &mut capsules::ble::peripheral::RX_BUF,
+                &mut capsules::ble::peripheral::TX_BUF,
+            )
+        );
+        kernel::hil::ble_advertising::BleLinkLayerDriver::set_exchange_client(
+            self.radio, peripheral,
+        );
+        hil::time::Alarm::set_client(ble_virtual_alarm, peripheral);
+
+        let ble_peripheral = static_init!(
+            capsules::ble::peripheral::BlePeripheral<
+                'static,
+                nrf52::ble_radio::Radio,
+                VirtualMuxAlarm<'static, Rtc>,
+            >,
+            capsules::ble::peripheral::BlePeripheral::new(
+                peripheral,
+                self.board_kernel.create_grant(&grant_cap)
+            )
+        );
+        peripheral.set_client(ble_peripheral);
+
+        ble_peripheral
+    }
+}
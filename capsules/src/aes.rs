@@ -0,0 +1,688 @@
+//! Provides userspace with access to AES encryption.
+//!
+//! Processes give the key, the IV or nonce and the data with allow calls and
+//! pick one of the ECB, CBC, CTR, CCM and GCM modes. Requests from
+//! different processes are queued and run one at a time on the same AES
+//! engine.
+//!
+//! The driver is the client of the AES engine, and hands its callbacks on
+//! to the CCM or GCM capsule while one of those modes is running. The CCM
+//! and GCM capsules must therefore be built on the same engine, and must not
+//! be set as its client themselves. To share the hardware with other kernel
+//! users, give the driver a `virtual_aes::VirtualAES128` as its engine
+//! (`components::aes::AesComponent` does this).
+//!
+//! Instead of giving the key itself, a process can use a key of its own in
+//! a `key_store::KeyStore` by its handle, if the board has set a key store
+//! with `set_key_store()`.
+//!
+//! Data is copied through a kernel buffer. ECB, CBC and CTR requests are
+//! processed in chunks of the buffer size, so they can be of any length.
+//! For CCM and GCM, the additional data, the message and the tag must fit
+//! in the buffer together.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use kernel::hil::symmetric_encryption::{AES128, AES128CCM, AES128GCM};
+//!
+//! let ccm_buf = static_init!([u8; 160], [0; 160]);
+//! let ccm = static_init!(
+//!     capsules::aes_ccm::AES128CCM<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::aes_ccm::AES128CCM::new(&sam4l::aes::AES, ccm_buf)
+//! );
+//! let gcm_buf = static_init!([u8; 160], [0; 160]);
+//! let gcm = static_init!(
+//!     capsules::aes_gcm::AES128GCM<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::aes_gcm::AES128GCM::new(&sam4l::aes::AES, gcm_buf)
+//! );
+//! let aes_buf = static_init!([u8; 128], [0; 128]);
+//! let aes = static_init!(
+//!     capsules::aes::AesDriver<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::aes::AesDriver::new(
+//!         &sam4l::aes::AES,
+//!         ccm,
+//!         gcm,
+//!         aes_buf,
+//!         board_kernel.create_grant(&memory_allocation_cap)
+//!     )
+//! );
+//! sam4l::aes::AES.set_client(aes);
+//! ccm.set_client(aes);
+//! gcm.set_client(aes);
+//! ```
+
+use crate::aes_ccm;
+use crate::aes_gcm;
+use crate::driver;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::key_store::{KeyHandle, KeyStore};
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, AES128, AES128CBC, AES128CCM, AES128ECB, AES128GCM, AES128_BLOCK_SIZE,
+    AES128_KEY_SIZE, CCM_NONCE_LENGTH, GCM_NONCE_LENGTH, GCM_TAG_LENGTH,
+};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::Aes as usize;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Mode {
+    Ecb,
+    Cbc,
+    Ctr,
+    Ccm,
+    Gcm,
+}
+
+impl Mode {
+    fn from_usize(mode: usize) -> Option<Mode> {
+        match mode {
+            0 => Some(Mode::Ecb),
+            1 => Some(Mode::Cbc),
+            2 => Some(Mode::Ctr),
+            3 => Some(Mode::Ccm),
+            4 => Some(Mode::Gcm),
+            _ => None,
+        }
+    }
+}
+
+pub struct App {
+    callback: OptionalCell<Callback>,
+    pending_run: bool,
+    mode: Mode,
+    encrypting: bool,
+    length: usize,
+    tag_length: usize,
+    key: Option<AppSlice<Shared, u8>>,
+    key_handle: Option<KeyHandle>,
+    iv: Option<AppSlice<Shared, u8>>,
+    source: Option<AppSlice<Shared, u8>>,
+    dest: Option<AppSlice<Shared, u8>>,
+    aad: Option<AppSlice<Shared, u8>>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: OptionalCell::empty(),
+            pending_run: false,
+            mode: Mode::Ecb,
+            encrypting: true,
+            length: 0,
+            tag_length: 0,
+            key: None,
+            key_handle: None,
+            iv: None,
+            source: None,
+            dest: None,
+            aad: None,
+        }
+    }
+}
+
+/// Returns the subslice `data[start..start + len]`, or `None` if `data` is
+/// too short.
+fn window(data: &[u8], start: usize, len: usize) -> Option<&[u8]> {
+    data.get(start..start.checked_add(len)?)
+}
+
+/// Copies as much of `data` as fits into `dest` at `offset`.
+fn copy_out(dest: &mut [u8], offset: usize, data: &[u8]) {
+    if offset < dest.len() {
+        let len = cmp::min(data.len(), dest.len() - offset);
+        dest[offset..offset + len].copy_from_slice(&data[..len]);
+    }
+}
+
+/// Reads the key `handle` of `appid`, which must be an AES-128 key.
+fn read_stored_key(
+    key_store: &dyn KeyStore,
+    handle: KeyHandle,
+    appid: AppId,
+    key: &mut [u8; AES128_KEY_SIZE],
+) -> ReturnCode {
+    match key_store.read_key(handle, Some(appid), key) {
+        Ok(AES128_KEY_SIZE) => ReturnCode::SUCCESS,
+        Ok(_) => ReturnCode::EINVAL,
+        Err(res) => res,
+    }
+}
+
+pub struct AesDriver<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> {
+    aes: &'a A,
+    ccm: &'a aes_ccm::AES128CCM<'a, A>,
+    gcm: &'a aes_gcm::AES128GCM<'a, A>,
+    key_store: OptionalCell<&'a dyn KeyStore>,
+
+    apps: Grant<App>,
+    appid: OptionalCell<AppId>,
+    mode: Cell<Mode>,
+
+    buffer: TakeCell<'static, [u8]>,
+    // For ECB, CBC and CTR: how much of the request is done, and the
+    // length of the chunk in the buffer
+    position: Cell<usize>,
+    chunk_length: Cell<usize>,
+    // For CCM and GCM: the length of the additional data in the buffer
+    aad_length: Cell<usize>,
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC + AES128ECB> AesDriver<'a, A> {
+    pub fn new(
+        aes: &'a A,
+        ccm: &'a aes_ccm::AES128CCM<'a, A>,
+        gcm: &'a aes_gcm::AES128GCM<'a, A>,
+        buffer: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> AesDriver<'a, A> {
+        AesDriver {
+            aes: aes,
+            ccm: ccm,
+            gcm: gcm,
+            key_store: OptionalCell::empty(),
+            apps: grant,
+            appid: OptionalCell::empty(),
+            mode: Cell::new(Mode::Ecb),
+            buffer: TakeCell::new(buffer),
+            position: Cell::new(0),
+            chunk_length: Cell::new(0),
+            aad_length: Cell::new(0),
+        }
+    }
+
+    /// Lets processes use their keys in `key_store` by handle.
+    pub fn set_key_store(&self, key_store: &'a dyn KeyStore) {
+        self.key_store.set(key_store);
+    }
+
+    /// Copies the key for a request of `appid` into `key`: the key with the
+    /// handle the process picked, or else the key buffer.
+    fn app_key(&self, appid: AppId, app: &App, key: &mut [u8; AES128_KEY_SIZE]) -> ReturnCode {
+        match (app.key_handle, &app.key) {
+            (Some(handle), _) => self.key_store.map_or(ReturnCode::ENOSUPPORT, |key_store| {
+                read_stored_key(*key_store, handle, appid, key)
+            }),
+            (None, Some(slice)) if slice.len() == AES128_KEY_SIZE => {
+                key.copy_from_slice(slice.as_ref());
+                ReturnCode::SUCCESS
+            }
+            (None, Some(_)) => ReturnCode::EINVAL,
+            (None, None) => ReturnCode::ERESERVE,
+        }
+    }
+}
+
+impl<A: AES128<'static> + AES128Ctr + AES128CBC + AES128ECB> AesDriver<'static, A> {
+    /// Starts the request of `appid`, which becomes the owner of the engine
+    /// until it completes.
+    fn start(&self, appid: AppId) -> ReturnCode {
+        self.appid.set(appid);
+        let res = self
+            .apps
+            .enter(appid, |app, _| {
+                self.mode.set(app.mode);
+                let mut key = [0; AES128_KEY_SIZE];
+                let res = self.app_key(appid, app, &mut key);
+                let res = if res != ReturnCode::SUCCESS {
+                    res
+                } else {
+                    match app.mode {
+                        Mode::Ecb | Mode::Cbc | Mode::Ctr => self.start_block_mode(app, &key),
+                        Mode::Ccm | Mode::Gcm => self.start_aead(app, &key),
+                    }
+                };
+                key.iter_mut().for_each(|b| *b = 0);
+                res
+            })
+            .unwrap_or_else(|err| err.into());
+        if res != ReturnCode::SUCCESS {
+            self.appid.clear();
+        }
+        res
+    }
+
+    fn start_block_mode(&self, app: &mut App, key: &[u8]) -> ReturnCode {
+        let length = app.length;
+        if length == 0 || (app.mode != Mode::Ctr && length % AES128_BLOCK_SIZE != 0) {
+            return ReturnCode::EINVAL;
+        }
+        match (&app.source, &app.dest) {
+            (Some(source), Some(dest)) => {
+                if source.len() < length || dest.len() < length {
+                    return ReturnCode::EINVAL;
+                }
+                let res = self.aes.set_key(key);
+                if res != ReturnCode::SUCCESS {
+                    return res;
+                }
+            }
+            _ => return ReturnCode::ERESERVE,
+        }
+        if app.mode != Mode::Ecb {
+            match &app.iv {
+                Some(iv) if iv.len() >= AES128_BLOCK_SIZE => {
+                    let res = self.aes.set_iv(&iv.as_ref()[..AES128_BLOCK_SIZE]);
+                    if res != ReturnCode::SUCCESS {
+                        return res;
+                    }
+                }
+                Some(_) => return ReturnCode::EINVAL,
+                None => return ReturnCode::ERESERVE,
+            }
+        }
+
+        self.aes.enable();
+        match app.mode {
+            Mode::Ecb => self.aes.set_mode_aes128ecb(app.encrypting),
+            Mode::Cbc => self.aes.set_mode_aes128cbc(app.encrypting),
+            _ => self.aes.set_mode_aes128ctr(app.encrypting),
+        }
+        self.aes.start_message();
+        self.position.set(0);
+        self.crypt_next_chunk(app)
+    }
+
+    /// Copies the next part of the source buffer into the kernel buffer and
+    /// starts processing it. A partial last block (CTR only) is padded.
+    fn crypt_next_chunk(&self, app: &App) -> ReturnCode {
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return ReturnCode::ENOMEM,
+        };
+        let position = self.position.get();
+        let max_chunk = buffer.len() - buffer.len() % AES128_BLOCK_SIZE;
+        let chunk = cmp::min(app.length - position, max_chunk);
+        let padded = ((chunk + AES128_BLOCK_SIZE - 1) / AES128_BLOCK_SIZE) * AES128_BLOCK_SIZE;
+
+        let copied = app
+            .source
+            .as_ref()
+            .and_then(|source| window(source.as_ref(), position, chunk))
+            .map(|data| buffer[..chunk].copy_from_slice(data))
+            .is_some();
+        if !copied {
+            self.buffer.replace(buffer);
+            return ReturnCode::EINVAL;
+        }
+        buffer[chunk..padded].iter_mut().for_each(|b| *b = 0);
+        self.chunk_length.set(chunk);
+
+        match self.aes.crypt(None, buffer, 0, padded) {
+            None => ReturnCode::SUCCESS,
+            Some((res, _, buffer)) => {
+                self.buffer.replace(buffer);
+                res
+            }
+        }
+    }
+
+    fn start_aead(&self, app: &mut App, key: &[u8]) -> ReturnCode {
+        let (nonce_length, tag_length) = match app.mode {
+            Mode::Ccm => (CCM_NONCE_LENGTH, app.tag_length),
+            _ => (GCM_NONCE_LENGTH, GCM_TAG_LENGTH),
+        };
+        // CCM* allows no tag, or an even length from 4 to 16 bytes
+        if app.mode == Mode::Ccm
+            && tag_length != 0
+            && (tag_length < 4 || tag_length > 16 || tag_length % 2 != 0)
+        {
+            return ReturnCode::EINVAL;
+        }
+
+        let length = app.length;
+        let encrypting = app.encrypting;
+        // The tag follows the message in the destination when encrypting,
+        // and in the source when decrypting
+        let (source_length, dest_length) = if encrypting {
+            (length, length + tag_length)
+        } else {
+            (length + tag_length, length)
+        };
+        let (iv, source) = match (&app.iv, &app.source, &app.dest) {
+            (Some(iv), Some(source), Some(dest)) => {
+                if iv.len() != nonce_length
+                    || source.len() < source_length
+                    || dest.len() < dest_length
+                {
+                    return ReturnCode::EINVAL;
+                }
+                (iv.as_ref(), &source.as_ref()[..source_length])
+            }
+            _ => return ReturnCode::ERESERVE,
+        };
+        let aad = app.aad.as_ref().map_or(&[][..], |aad| aad.as_ref());
+
+        let buffer = match self.buffer.take() {
+            Some(buffer) => buffer,
+            None => return ReturnCode::ENOMEM,
+        };
+        if aad.len() + length + tag_length > buffer.len() {
+            self.buffer.replace(buffer);
+            return ReturnCode::ESIZE;
+        }
+        buffer[..aad.len()].copy_from_slice(aad);
+        buffer[aad.len()..aad.len() + source_length].copy_from_slice(source);
+        self.aad_length.set(aad.len());
+
+        self.aes.enable();
+        let (res, buffer) = if app.mode == Mode::Ccm {
+            if self.ccm.set_key(key) != ReturnCode::SUCCESS
+                || self.ccm.set_nonce(iv) != ReturnCode::SUCCESS
+            {
+                (ReturnCode::EINVAL, Some(buffer))
+            } else {
+                self.ccm
+                    .crypt(buffer, 0, aad.len(), length, tag_length, true, encrypting)
+            }
+        } else {
+            if self.gcm.set_key(key) != ReturnCode::SUCCESS
+                || self.gcm.set_iv(iv) != ReturnCode::SUCCESS
+            {
+                (ReturnCode::EINVAL, Some(buffer))
+            } else {
+                self.gcm.crypt(buffer, 0, aad.len(), length, encrypting)
+            }
+        };
+        if let Some(buffer) = buffer {
+            self.buffer.replace(buffer);
+        }
+        res
+    }
+
+    /// Clears the current request and starts the next queued one.
+    fn finish(&self) {
+        self.appid.clear();
+        self.check_queue();
+    }
+
+    fn check_queue(&self) {
+        for appiter in self.apps.iter() {
+            let pending = appiter.enter(|app, _| {
+                if app.pending_run {
+                    app.pending_run = false;
+                    Some(app.appid())
+                } else {
+                    None
+                }
+            });
+            if let Some(appid) = pending {
+                let res = self.start(appid);
+                if res == ReturnCode::SUCCESS {
+                    break;
+                }
+                // The request could not start, report it and move on
+                let _ = self.apps.enter(appid, |app, _| {
+                    app.callback.map(|cb| cb.schedule(usize::from(res), 0, 0));
+                });
+            }
+        }
+    }
+
+    fn block_crypt_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.buffer.replace(buffer);
+        let done = self.appid.map_or(true, |appid| {
+            self.apps
+                .enter(*appid, |app, _| {
+                    if result != ReturnCode::SUCCESS {
+                        app.callback
+                            .map(|cb| cb.schedule(usize::from(result), 0, 0));
+                        return true;
+                    }
+                    let position = self.position.get();
+                    let chunk = self.chunk_length.get();
+                    if let Some(dest) = app.dest.as_mut() {
+                        self.buffer
+                            .map(|buffer| copy_out(dest.as_mut(), position, &buffer[..chunk]));
+                    }
+                    self.position.set(position + chunk);
+
+                    let res = if position + chunk < app.length {
+                        match self.crypt_next_chunk(app) {
+                            ReturnCode::SUCCESS => return false,
+                            res => res,
+                        }
+                    } else {
+                        ReturnCode::SUCCESS
+                    };
+                    let length = if res == ReturnCode::SUCCESS {
+                        app.length
+                    } else {
+                        0
+                    };
+                    app.callback
+                        .map(|cb| cb.schedule(usize::from(res), length, 0));
+                    true
+                })
+                .unwrap_or(true)
+        });
+        if done {
+            self.finish();
+        }
+    }
+
+    fn aead_crypt_done(&self, buffer: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+        self.appid.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                let aad_length = self.aad_length.get();
+                let tag_length = match self.mode.get() {
+                    Mode::Gcm => GCM_TAG_LENGTH,
+                    _ => app.tag_length,
+                };
+                // Only hand out decrypted data if it is authentic
+                let length = if res != ReturnCode::SUCCESS || !tag_is_valid {
+                    0
+                } else if app.encrypting {
+                    app.length + tag_length
+                } else {
+                    app.length
+                };
+                if let Some(dest) = app.dest.as_mut() {
+                    copy_out(dest.as_mut(), 0, &buffer[aad_length..aad_length + length]);
+                }
+                app.callback
+                    .map(|cb| cb.schedule(usize::from(res), length, tag_is_valid as usize));
+            });
+        });
+        // Don't leave plaintext behind in the kernel buffer
+        buffer.iter_mut().for_each(|b| *b = 0);
+        self.buffer.replace(buffer);
+        self.finish();
+    }
+}
+
+impl<A: AES128<'static> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption::Client<'static>
+    for AesDriver<'static, A>
+{
+    fn crypt_done(
+        &'static self,
+        source: Option<&'static mut [u8]>,
+        dest: &'static mut [u8],
+        result: ReturnCode,
+    ) {
+        match self.mode.get() {
+            Mode::Ccm => symmetric_encryption::Client::crypt_done(self.ccm, source, dest, result),
+            Mode::Gcm => symmetric_encryption::Client::crypt_done(self.gcm, source, dest, result),
+            Mode::Ecb | Mode::Cbc | Mode::Ctr => self.block_crypt_done(dest, result),
+        }
+    }
+}
+
+impl<A: AES128<'static> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption::CCMClient
+    for AesDriver<'static, A>
+{
+    fn crypt_done(&self, buf: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+        self.aead_crypt_done(buf, res, tag_is_valid);
+    }
+}
+
+impl<A: AES128<'static> + AES128Ctr + AES128CBC + AES128ECB> symmetric_encryption::GCMClient
+    for AesDriver<'static, A>
+{
+    fn crypt_done(&self, buf: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+        self.aead_crypt_done(buf, res, tag_is_valid);
+    }
+}
+
+/// Specify memory regions to be used.
+///
+/// ### `allow_num`
+///
+/// - `0`: The key, `AES128_KEY_SIZE` bytes. Allowing a key buffer stops
+///        the use of a key handle.
+/// - `1`: The IV (ECB: unused, CBC: `AES128_BLOCK_SIZE` bytes), initial
+///        counter (CTR: `AES128_BLOCK_SIZE` bytes) or nonce (CCM:
+///        `CCM_NONCE_LENGTH` bytes, GCM: `GCM_NONCE_LENGTH` bytes).
+/// - `2`: The source data. When decrypting with CCM or GCM, the tag follows
+///        the message.
+/// - `3`: The destination. When encrypting with CCM or GCM, the tag follows
+///        the message.
+/// - `4`: The additional authenticated data for CCM and GCM. The whole
+///        buffer is used.
+///
+/// The buffers are read when the request starts and should not be changed
+/// until it completes.
+impl<A: AES128<'static> + AES128Ctr + AES128CBC + AES128ECB> Driver for AesDriver<'static, A> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => {
+                        app.key = slice;
+                        app.key_handle = None;
+                    }
+                    1 => app.iv = slice,
+                    2 => app.source = slice,
+                    3 => app.dest = slice,
+                    4 => app.aad = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or(ReturnCode::FAIL)
+    }
+
+    /// Subscribe to AES events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Subscribe to request completion.
+    ///        The callback signature is
+    ///        `fn(result: u32, length: usize, tag_is_valid: bool)`, where
+    ///        `length` is the number of bytes written to the destination.
+    ///        `tag_is_valid` is always false for ECB, CBC and CTR.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback.insert(callback);
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or(ReturnCode::FAIL),
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Configure and run AES requests.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Set the mode for the following requests. `data1` is 0 for
+    ///        ECB, 1 for CBC, 2 for CTR, 3 for CCM and 4 for GCM. `data2` is
+    ///        1 to encrypt and 0 to decrypt.
+    /// - `2`: Run a request over `data1` bytes of the source. For CCM,
+    ///        `data2` is the tag length: 0 or an even number from 4 to 16.
+    ///        The request is queued if another process is using the engine.
+    /// - `3`: Use the `AES128_KEY_SIZE` byte key with handle `data1` in the
+    ///        key store for the following requests, instead of the key
+    ///        buffer.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            // set mode
+            1 => match Mode::from_usize(data1) {
+                Some(mode) => self
+                    .apps
+                    .enter(appid, |app, _| {
+                        if app.pending_run || self.appid.map_or(false, |id| *id == appid) {
+                            return ReturnCode::EBUSY;
+                        }
+                        app.mode = mode;
+                        app.encrypting = data2 != 0;
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into()),
+                None => ReturnCode::ENOSUPPORT,
+            },
+
+            // run
+            2 => {
+                let res = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        if app.pending_run || self.appid.map_or(false, |id| *id == appid) {
+                            return ReturnCode::EBUSY;
+                        }
+                        app.length = data1;
+                        app.tag_length = data2;
+                        if self.appid.is_some() {
+                            // Some app is using the engine, we must wait.
+                            app.pending_run = true;
+                        }
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into());
+                if res == ReturnCode::SUCCESS && self.appid.is_none() {
+                    self.start(appid)
+                } else {
+                    res
+                }
+            }
+
+            // use key handle
+            3 => self.key_store.map_or(ReturnCode::ENOSUPPORT, |key_store| {
+                let handle = KeyHandle::new(data1);
+                // Check that the process may use the key
+                let mut key = [0; AES128_KEY_SIZE];
+                let res = read_stored_key(*key_store, handle, appid, &mut key);
+                key.iter_mut().for_each(|b| *b = 0);
+                if res != ReturnCode::SUCCESS {
+                    return res;
+                }
+                self.apps
+                    .enter(appid, |app, _| {
+                        if app.pending_run || self.appid.map_or(false, |id| *id == appid) {
+                            return ReturnCode::EBUSY;
+                        }
+                        app.key_handle = Some(handle);
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }),
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -498,8 +498,18 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::AES128CCM<
 impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::Client<'a>
     for AES128CCM<'a, A>
 {
-    fn crypt_done(&self, _: Option<&'a mut [u8]>, crypt_buf: &'a mut [u8]) {
+    fn crypt_done(&self, _: Option<&'a mut [u8]>, crypt_buf: &'a mut [u8], result: ReturnCode) {
         self.crypt_buf.replace(crypt_buf);
+        if result != ReturnCode::SUCCESS && self.state.get() != CCMState::Idle {
+            // Return client buffer to client
+            self.state.set(CCMState::Idle);
+            self.buf.take().map(|buf| {
+                self.crypt_client.map(move |client| {
+                    client.crypt_done(buf, result, false);
+                });
+            });
+            return;
+        }
         match self.state.get() {
             CCMState::Idle => {}
             CCMState::Auth => {
@@ -577,3 +587,101 @@ impl<'a, A: AES128<'a> + AES128Ctr + AES128CBC> symmetric_encryption::Client<'a>
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{aes128_encrypt, leak, SimAes};
+    use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM as _};
+
+    struct TestClient {
+        result: Cell<Option<(ReturnCode, bool)>>,
+        buf: TakeCell<'static, [u8]>,
+    }
+
+    impl CCMClient for TestClient {
+        fn crypt_done(&self, buf: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+            self.buf.replace(buf);
+            self.result.set(Some((res, tag_is_valid)));
+        }
+    }
+
+    #[test]
+    fn aes_matches_fips_197() {
+        let key = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f,
+        ];
+        let mut block = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+        aes128_encrypt(&key, &mut block);
+        assert_eq!(
+            block,
+            [
+                0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4,
+                0xc5, 0x5a
+            ]
+        );
+    }
+
+    /// Packet vector #1 of RFC 3610.
+    #[test]
+    fn ccm_matches_rfc_3610() {
+        let aes: &'static SimAes<'static> = leak(SimAes::new());
+        let ccm = leak(AES128CCM::new(aes, leak([0; 128])));
+        let client: &'static TestClient = leak(TestClient {
+            result: Cell::new(None),
+            buf: TakeCell::empty(),
+        });
+        aes.set_client(ccm);
+        ccm.set_client(client);
+
+        let key: std::vec::Vec<u8> = (0xc0..0xd0).collect();
+        let nonce = [
+            0x00, 0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5,
+        ];
+        let packet: std::vec::Vec<u8> = (0..31).collect();
+        let expected = [
+            0x58, 0x8c, 0x97, 0x9a, 0x61, 0xc6, 0x63, 0xd2, 0xf0, 0x66, 0xd0, 0xc2, 0xc0, 0xf9,
+            0x89, 0x80, 0x6d, 0x5f, 0x6b, 0x61, 0xda, 0xc3, 0x84, 0x17, 0xe8, 0xd1, 0x2c, 0xfd,
+            0xf9, 0x26, 0xe0,
+        ];
+        assert_eq!(ccm.set_key(&key), ReturnCode::SUCCESS);
+        assert_eq!(ccm.set_nonce(&nonce), ReturnCode::SUCCESS);
+
+        let buf = leak([0; 39]);
+        buf[..31].copy_from_slice(&packet);
+        assert_eq!(
+            ccm.crypt(buf, 0, 8, 23, 8, true, true).0,
+            ReturnCode::SUCCESS
+        );
+        while aes.step() {}
+        assert_eq!(client.result.take(), Some((ReturnCode::SUCCESS, true)));
+        let buf = client.buf.take().unwrap();
+        assert_eq!(&buf[..8], &packet[..8]);
+        assert_eq!(&buf[8..], &expected[..]);
+
+        // Decrypting gives the packet back, unless the tag was changed.
+        assert_eq!(
+            ccm.crypt(buf, 0, 8, 23, 8, true, false).0,
+            ReturnCode::SUCCESS
+        );
+        while aes.step() {}
+        assert_eq!(client.result.take(), Some((ReturnCode::SUCCESS, true)));
+        let buf = client.buf.take().unwrap();
+        assert_eq!(&buf[..31], &packet[..]);
+
+        buf[8..].copy_from_slice(&expected);
+        buf[38] ^= 1;
+        assert_eq!(
+            ccm.crypt(buf, 0, 8, 23, 8, true, false).0,
+            ReturnCode::SUCCESS
+        );
+        while aes.step() {}
+        assert_eq!(client.result.take(), Some((ReturnCode::SUCCESS, false)));
+    }
+}
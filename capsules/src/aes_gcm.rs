@@ -0,0 +1,377 @@
+//! Implements AES-GCM encryption/decryption/authentication using an underlying
+//! AES-CTR implementation.
+//!
+//! NIST SP 800-38D. GCM encrypts with AES-CTR and authenticates the
+//! additional data and the ciphertext with GHASH, a polynomial hash over
+//! GF(2^128) keyed by H = Enc(Key, 0^128). The tag is the GHASH output
+//! encrypted with the first counter block J0.
+//!
+//! All three block cipher outputs come from two CTR passes over `crypt_buf`:
+//!
+//! ```text
+//! crypt_buf: [ 0^128 | 0^128 | -------- PData/CData -------- ]
+//! pass 1:     \_____/                                           IV = 0
+//! pass 2:             \_____ | _____________________________/   IV = J0
+//! ```
+//!
+//! The first pass leaves H in the first block. The second pass leaves
+//! Enc(Key, J0) in the second block and encrypts or decrypts the message,
+//! whose first block uses counter J0 + 1 as the standard requires. GHASH
+//! is computed in software once the ciphertext is known.
+//!
+//! Only 96 bit IVs are supported, which is what SP 800-38D recommends, and
+//! tags are always `GCM_TAG_LENGTH` bytes long.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use kernel::hil::symmetric_encryption;
+//!
+//! const CRYPT_SIZE: usize = 2 * symmetric_encryption::AES128_BLOCK_SIZE + 128;
+//! static mut CRYPT_BUF: [u8; CRYPT_SIZE] = [0x00; CRYPT_SIZE];
+//!
+//! let aes_gcm = static_init!(
+//!     capsules::aes_gcm::AES128GCM<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::aes_gcm::AES128GCM::new(&sam4l::aes::AES, &mut CRYPT_BUF)
+//! );
+//! sam4l::aes::AES.set_client(aes_gcm);
+//! sam4l::aes::AES.enable();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, AES128, AES128_BLOCK_SIZE, AES128_KEY_SIZE, GCM_NONCE_LENGTH, GCM_TAG_LENGTH,
+};
+use kernel::ReturnCode;
+
+/// Offset of the message in `crypt_buf`, after H and Enc(Key, J0)
+const MESSAGE_OFFSET: usize = 2 * AES128_BLOCK_SIZE;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum GCMState {
+    Idle,
+    Hash,
+    Encrypt,
+}
+
+pub struct AES128GCM<'a, A: AES128<'a> + AES128Ctr> {
+    aes: &'a A,
+    crypt_buf: TakeCell<'a, [u8]>,
+    crypt_enc_len: Cell<usize>,
+    crypt_client: OptionalCell<&'a dyn symmetric_encryption::GCMClient>,
+
+    state: Cell<GCMState>,
+    encrypting: Cell<bool>,
+
+    buf: TakeCell<'static, [u8]>,
+    pos: Cell<(usize, usize, usize)>,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    iv: Cell<[u8; GCM_NONCE_LENGTH]>,
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> AES128GCM<'a, A> {
+    pub fn new(aes: &'a A, crypt_buf: &'static mut [u8]) -> AES128GCM<'a, A> {
+        AES128GCM {
+            aes: aes,
+            crypt_buf: TakeCell::new(crypt_buf),
+            crypt_enc_len: Cell::new(0),
+            crypt_client: OptionalCell::empty(),
+            state: Cell::new(GCMState::Idle),
+            encrypting: Cell::new(false),
+            buf: TakeCell::empty(),
+            pos: Cell::new((0, 0, 0)),
+            key: Cell::new(Default::default()),
+            iv: Cell::new(Default::default()),
+        }
+    }
+
+    /// Copies the message into crypt_buf after two zero blocks and pads it
+    /// to a multiple of the block size. Returns ENOMEM if crypt_buf is not
+    /// present or if it is not long enough.
+    fn prepare_gcm_buffer(&self, m_data: &[u8]) -> ReturnCode {
+        self.crypt_buf.map_or(ReturnCode::ENOMEM, |cbuf| {
+            let enc_len = MESSAGE_OFFSET
+                + ((m_data.len() + AES128_BLOCK_SIZE - 1) / AES128_BLOCK_SIZE) * AES128_BLOCK_SIZE;
+            if cbuf.len() < enc_len {
+                return ReturnCode::ENOMEM;
+            }
+            cbuf[..MESSAGE_OFFSET].iter_mut().for_each(|b| *b = 0);
+            let m_end = MESSAGE_OFFSET + m_data.len();
+            cbuf[MESSAGE_OFFSET..m_end].copy_from_slice(m_data);
+            cbuf[m_end..enc_len].iter_mut().for_each(|b| *b = 0);
+            self.crypt_enc_len.set(enc_len);
+            ReturnCode::SUCCESS
+        })
+    }
+
+    /// Runs `crypt_buf[start..stop]` through AES-CTR with the given initial
+    /// counter, moving to `state` if the request was accepted. Returns EBUSY
+    /// if the engine still holds `crypt_buf`.
+    fn start_ctr(
+        &self,
+        iv: &[u8; AES128_BLOCK_SIZE],
+        start: usize,
+        stop: usize,
+        state: GCMState,
+    ) -> ReturnCode {
+        let res = self.aes.set_iv(iv);
+        if res != ReturnCode::SUCCESS {
+            return res;
+        }
+        let res = self.aes.set_key(&self.key.get());
+        if res != ReturnCode::SUCCESS {
+            return res;
+        }
+
+        let crypt_buf = match self.crypt_buf.take() {
+            None => return ReturnCode::EBUSY,
+            Some(buf) => buf,
+        };
+
+        // CTR is its own inverse, so always encrypt.
+        self.aes.set_mode_aes128ctr(true);
+        self.aes.start_message();
+        match self.aes.crypt(None, crypt_buf, start, stop) {
+            None => {
+                self.state.set(state);
+                ReturnCode::SUCCESS
+            }
+            Some((res, _, crypt_buf)) => {
+                self.crypt_buf.replace(crypt_buf);
+                res
+            }
+        }
+    }
+
+    fn start_gcm_encrypt(&self) -> ReturnCode {
+        // J0 = IV | 0^31 | 1
+        let mut j0 = [0u8; AES128_BLOCK_SIZE];
+        j0[..GCM_NONCE_LENGTH].copy_from_slice(&self.iv.get());
+        j0[AES128_BLOCK_SIZE - 1] = 1;
+        self.start_ctr(
+            &j0,
+            AES128_BLOCK_SIZE,
+            self.crypt_enc_len.get(),
+            GCMState::Encrypt,
+        )
+    }
+
+    /// Computes the tag and returns the message to the client, or FAIL if
+    /// `crypt_buf` was not returned by the engine.
+    fn end_gcm(&self) {
+        let result = self.buf.map_or(Ok(false), |buf| {
+            self.crypt_buf.map_or(Err(ReturnCode::FAIL), |cbuf| {
+                let (a_off, m_off, m_len) = self.pos.get();
+                let m_end = m_off + m_len;
+                let mut hash_key = [0u8; AES128_BLOCK_SIZE];
+                hash_key.copy_from_slice(&cbuf[..AES128_BLOCK_SIZE]);
+                let c_data = if self.encrypting.get() {
+                    &cbuf[MESSAGE_OFFSET..MESSAGE_OFFSET + m_len]
+                } else {
+                    &buf[m_off..m_end]
+                };
+                let mut tag =
+                    ghash(u128::from_be_bytes(hash_key), &buf[a_off..m_off], c_data).to_be_bytes();
+                tag.iter_mut()
+                    .zip(cbuf[AES128_BLOCK_SIZE..MESSAGE_OFFSET].iter())
+                    .for_each(|(a, b)| *a ^= *b);
+
+                // Copy the encrypted/decrypted message data
+                buf[m_off..m_end].copy_from_slice(&cbuf[MESSAGE_OFFSET..MESSAGE_OFFSET + m_len]);
+                // H and the message are no longer needed
+                cbuf.iter_mut().for_each(|b| *b = 0);
+
+                if self.encrypting.get() {
+                    // Append the tag to the message
+                    buf[m_end..m_end + GCM_TAG_LENGTH].copy_from_slice(&tag);
+                    Ok(true)
+                } else {
+                    // Compare the computed tag to the received tag
+                    Ok(buf[m_end..m_end + GCM_TAG_LENGTH]
+                        .iter()
+                        .zip(tag.iter())
+                        .fold(0, |acc, (a, b)| acc | (*a ^ *b))
+                        == 0)
+                }
+            })
+        });
+        let (res, tag_valid) = match result {
+            Ok(tag_valid) => (ReturnCode::SUCCESS, tag_valid),
+            Err(res) => (res, false),
+        };
+
+        self.state.set(GCMState::Idle);
+        self.crypt_client.map(|client| {
+            self.buf.take().map(|buf| {
+                client.crypt_done(buf, res, tag_valid);
+            });
+        });
+    }
+}
+
+/// Multiplies two elements of GF(2^128) in the bit order used by GCM, where
+/// the most significant bit of the integer is the coefficient of x^0.
+fn gf_mul(x: u128, y: u128) -> u128 {
+    const R: u128 = 0xe1 << 120;
+    let mut z = 0;
+    let mut v = y;
+    for i in (0..128).rev() {
+        if (x >> i) & 1 == 1 {
+            z ^= v;
+        }
+        v = if v & 1 == 1 { (v >> 1) ^ R } else { v >> 1 };
+    }
+    z
+}
+
+/// Hashes the zero-padded blocks of `data` into `y`.
+fn ghash_update(h: u128, mut y: u128, data: &[u8]) -> u128 {
+    for chunk in data.chunks(AES128_BLOCK_SIZE) {
+        let mut block = [0u8; AES128_BLOCK_SIZE];
+        block[..chunk.len()].copy_from_slice(chunk);
+        y = gf_mul(y ^ u128::from_be_bytes(block), h);
+    }
+    y
+}
+
+/// GHASH of the additional data `a_data` and the ciphertext `c_data`
+/// followed by their lengths in bits, with hash key `h`.
+fn ghash(h: u128, a_data: &[u8], c_data: &[u8]) -> u128 {
+    let y = ghash_update(h, 0, a_data);
+    let y = ghash_update(h, y, c_data);
+    let lengths = ((a_data.len() as u128 * 8) << 64) | (c_data.len() as u128 * 8);
+    gf_mul(y ^ lengths, h)
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> symmetric_encryption::AES128GCM<'a> for AES128GCM<'a, A> {
+    fn set_client(&self, client: &'a dyn symmetric_encryption::GCMClient) {
+        self.crypt_client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() < AES128_KEY_SIZE {
+            ReturnCode::EINVAL
+        } else {
+            let mut new_key = [0u8; AES128_KEY_SIZE];
+            new_key.copy_from_slice(&key[..AES128_KEY_SIZE]);
+            self.key.set(new_key);
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> ReturnCode {
+        if iv.len() < GCM_NONCE_LENGTH {
+            ReturnCode::EINVAL
+        } else {
+            let mut new_iv = [0u8; GCM_NONCE_LENGTH];
+            new_iv.copy_from_slice(&iv[..GCM_NONCE_LENGTH]);
+            self.iv.set(new_iv);
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        encrypting: bool,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.state.get() != GCMState::Idle {
+            return (ReturnCode::EBUSY, Some(buf));
+        }
+        if !(a_off <= m_off && m_off + m_len + GCM_TAG_LENGTH <= buf.len()) {
+            return (ReturnCode::EINVAL, Some(buf));
+        }
+
+        self.encrypting.set(encrypting);
+
+        let res = self.prepare_gcm_buffer(&buf[m_off..m_off + m_len]);
+        if res != ReturnCode::SUCCESS {
+            return (res, Some(buf));
+        }
+
+        // H = Enc(Key, 0^128)
+        let res = self.start_ctr(
+            &[0; AES128_BLOCK_SIZE],
+            0,
+            AES128_BLOCK_SIZE,
+            GCMState::Hash,
+        );
+        if res != ReturnCode::SUCCESS {
+            (res, Some(buf))
+        } else {
+            self.buf.replace(buf);
+            self.pos.set((a_off, m_off, m_len));
+            (ReturnCode::SUCCESS, None)
+        }
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> symmetric_encryption::Client<'a> for AES128GCM<'a, A> {
+    fn crypt_done(&self, _: Option<&'a mut [u8]>, crypt_buf: &'a mut [u8], result: ReturnCode) {
+        self.crypt_buf.replace(crypt_buf);
+        if result != ReturnCode::SUCCESS && self.state.get() != GCMState::Idle {
+            // Return client buffer to client
+            self.state.set(GCMState::Idle);
+            self.buf.take().map(|buf| {
+                self.crypt_client.map(move |client| {
+                    client.crypt_done(buf, result, false);
+                });
+            });
+            return;
+        }
+        match self.state.get() {
+            GCMState::Idle => {}
+            GCMState::Hash => {
+                let res = self.start_gcm_encrypt();
+                if res != ReturnCode::SUCCESS {
+                    // Return client buffer to client
+                    self.state.set(GCMState::Idle);
+                    self.buf.take().map(|buf| {
+                        self.crypt_client.map(move |client| {
+                            client.crypt_done(buf, res, false);
+                        });
+                    });
+                }
+            }
+            GCMState::Encrypt => {
+                self.end_gcm();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The McGrew and Viega GCM specification, test case 2: H and C for a
+    // zero key and IV and a single block of zero plaintext
+    const H: u128 = 0x66e94bd4ef8a2c3b884cfa59ca342b2e;
+    const C: [u8; 16] = [
+        0x03, 0x88, 0xda, 0xce, 0x60, 0xb6, 0xa3, 0x92, 0xf3, 0x28, 0xc2, 0xb9, 0x71, 0xb2, 0xfe,
+        0x78,
+    ];
+
+    #[test]
+    fn ghash_vector() {
+        assert_eq!(ghash(H, &[], &C), 0xf38cbb1ad69223dcc3457ae5b6b0f885);
+        // The hash of no data is just the (zero) length block times H
+        assert_eq!(ghash(H, &[], &[]), 0);
+    }
+
+    #[test]
+    fn gf_mul_identity() {
+        // x^0 is the most significant bit
+        let one = 1 << 127;
+        assert_eq!(gf_mul(H, one), H);
+        assert_eq!(gf_mul(one, H), H);
+        assert_eq!(gf_mul(H, 0), 0);
+    }
+}
@@ -0,0 +1,208 @@
+//! Advertising and scan response data.
+//!
+//! Advertising data is a sequence of AD structures, each made of a length
+//! byte, an AD type and `length - 1` bytes of data. A length of zero ends
+//! the significant part of the data; the rest is padding (Bluetooth Core
+//! Specification v5.0, Vol 3, Part C, section 11).
+//!
+//! `AdStructures` parses advertising data and `AdvertisingDataBuilder` builds
+//! it in a buffer.
+
+use crate::ble::link_layer::MAX_ADV_DATA_LEN;
+use core::cmp;
+use kernel::ReturnCode;
+
+/// AD types (Bluetooth Assigned Numbers, Generic Access Profile).
+pub mod ad_type {
+    pub const FLAGS: u8 = 0x01;
+    pub const INCOMPLETE_16BIT_UUIDS: u8 = 0x02;
+    pub const COMPLETE_16BIT_UUIDS: u8 = 0x03;
+    pub const INCOMPLETE_128BIT_UUIDS: u8 = 0x06;
+    pub const COMPLETE_128BIT_UUIDS: u8 = 0x07;
+    pub const SHORTENED_LOCAL_NAME: u8 = 0x08;
+    pub const COMPLETE_LOCAL_NAME: u8 = 0x09;
+    pub const TX_POWER_LEVEL: u8 = 0x0a;
+    pub const SERVICE_DATA_16BIT: u8 = 0x16;
+    pub const APPEARANCE: u8 = 0x19;
+    pub const MANUFACTURER_SPECIFIC_DATA: u8 = 0xff;
+}
+
+/// Bits of the flags AD structure.
+pub mod flags {
+    pub const LE_LIMITED_DISCOVERABLE: u8 = 0x01;
+    pub const LE_GENERAL_DISCOVERABLE: u8 = 0x02;
+    pub const BR_EDR_NOT_SUPPORTED: u8 = 0x04;
+}
+
+/// Iterator over the AD structures of advertising data, as (AD type, data)
+/// pairs. Iteration stops at the end of the significant part or at a
+/// structure that runs past the end of the data.
+pub struct AdStructures<'d> {
+    data: &'d [u8],
+}
+
+impl<'d> AdStructures<'d> {
+    pub fn new(data: &'d [u8]) -> AdStructures<'d> {
+        AdStructures { data: data }
+    }
+}
+
+impl<'d> Iterator for AdStructures<'d> {
+    type Item = (u8, &'d [u8]);
+
+    fn next(&mut self) -> Option<(u8, &'d [u8])> {
+        let len = *self.data.first()? as usize;
+        if len == 0 || len >= self.data.len() {
+            self.data = &[];
+            return None;
+        }
+        let structure = (self.data[1], &self.data[2..1 + len]);
+        self.data = &self.data[1 + len..];
+        Some(structure)
+    }
+}
+
+// Returns the end of the significant part of `data`, which is past the end
+// of `data` if the last AD structure is truncated.
+fn significant_end(data: &[u8]) -> usize {
+    let mut len = 0;
+    while len < data.len() && data[len] != 0 {
+        len += 1 + data[len] as usize;
+    }
+    len
+}
+
+/// Returns the length of the significant part of `data`.
+pub fn significant_len(data: &[u8]) -> usize {
+    cmp::min(significant_end(data), data.len())
+}
+
+/// Returns whether every AD structure in the significant part of `data` is
+/// complete.
+pub fn is_valid(data: &[u8]) -> bool {
+    significant_end(data) <= data.len()
+}
+
+/// Returns the data of the first AD structure of type `ad_type`.
+pub fn find(data: &[u8], ad_type: u8) -> Option<&[u8]> {
+    AdStructures::new(data)
+        .find(|&(structure_type, _)| structure_type == ad_type)
+        .map(|(_, structure_data)| structure_data)
+}
+
+/// Builds advertising data in a buffer of at most `MAX_ADV_DATA_LEN` bytes.
+pub struct AdvertisingDataBuilder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> AdvertisingDataBuilder<'b> {
+    pub fn new(buf: &'b mut [u8]) -> AdvertisingDataBuilder<'b> {
+        let capacity = cmp::min(buf.len(), MAX_ADV_DATA_LEN);
+        AdvertisingDataBuilder {
+            buf: &mut buf[..capacity],
+            len: 0,
+        }
+    }
+
+    /// Length of the data built so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Number of bytes that can still be added.
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.len
+    }
+
+    /// Adds an AD structure. Returns `ReturnCode::ESIZE` if it does not fit.
+    pub fn add(&mut self, ad_type: u8, data: &[u8]) -> ReturnCode {
+        if 2 + data.len() > self.remaining() {
+            return ReturnCode::ESIZE;
+        }
+        self.buf[self.len] = 1 + data.len() as u8;
+        self.buf[self.len + 1] = ad_type;
+        self.buf[self.len + 2..self.len + 2 + data.len()].copy_from_slice(data);
+        self.len += 2 + data.len();
+        ReturnCode::SUCCESS
+    }
+
+    pub fn add_flags(&mut self, flags: u8) -> ReturnCode {
+        self.add(ad_type::FLAGS, &[flags])
+    }
+
+    /// Adds the local name, shortened to the space left if needed.
+    pub fn add_local_name(&mut self, name: &[u8]) -> ReturnCode {
+        if 2 + name.len() <= self.remaining() {
+            self.add(ad_type::COMPLETE_LOCAL_NAME, name)
+        } else if self.remaining() > 2 {
+            let len = self.remaining() - 2;
+            self.add(ad_type::SHORTENED_LOCAL_NAME, &name[..len])
+        } else {
+            ReturnCode::ESIZE
+        }
+    }
+
+    /// Appends already encoded AD structures.
+    pub fn append(&mut self, data: &[u8]) -> ReturnCode {
+        if data.len() > self.remaining() {
+            return ReturnCode::ESIZE;
+        }
+        self.buf[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+        ReturnCode::SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn build_and_parse() {
+        let mut buf = [0u8; 40];
+        let mut builder = AdvertisingDataBuilder::new(&mut buf);
+        assert_eq!(
+            builder.add_flags(flags::LE_GENERAL_DISCOVERABLE | flags::BR_EDR_NOT_SUPPORTED),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            builder.add(ad_type::COMPLETE_16BIT_UUIDS, &[0x0f, 0x18]),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(builder.remaining(), 24);
+        assert_eq!(
+            builder.add_local_name(b"a rather long device name"),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(builder.len(), MAX_ADV_DATA_LEN);
+        assert_eq!(builder.add_flags(0), ReturnCode::ESIZE);
+
+        let data = &buf[..MAX_ADV_DATA_LEN];
+        assert!(is_valid(data));
+        assert_eq!(significant_len(data), MAX_ADV_DATA_LEN);
+        assert_eq!(find(data, ad_type::FLAGS), Some(&[0x06][..]));
+        assert_eq!(
+            find(data, ad_type::SHORTENED_LOCAL_NAME),
+            Some(&b"a rather long device n"[..])
+        );
+        assert_eq!(find(data, ad_type::COMPLETE_LOCAL_NAME), None);
+        assert_eq!(AdStructures::new(data).count(), 3);
+
+        // Padding after the significant part
+        let padded = [2, ad_type::TX_POWER_LEVEL, 0, 0, 0xff, 0xff];
+        assert!(is_valid(&padded));
+        assert_eq!(significant_len(&padded), 3);
+        // Truncated structure
+        let truncated = [
+            2,
+            ad_type::FLAGS,
+            0x06,
+            5,
+            ad_type::COMPLETE_LOCAL_NAME,
+            b'x',
+        ];
+        assert!(!is_valid(&truncated));
+        assert_eq!(AdStructures::new(&truncated).count(), 1);
+    }
+}
@@ -0,0 +1,75 @@
+//! Attribute protocol (ATT) definitions (Vol 3, Part F).
+
+/// Default and minimum ATT MTU on an LE connection.
+pub const DEFAULT_MTU: usize = 23;
+
+/// ATT PDU opcodes.
+pub mod opcode {
+    pub const ERROR_RSP: u8 = 0x01;
+    pub const EXCHANGE_MTU_REQ: u8 = 0x02;
+    pub const EXCHANGE_MTU_RSP: u8 = 0x03;
+    pub const FIND_INFORMATION_REQ: u8 = 0x04;
+    pub const FIND_INFORMATION_RSP: u8 = 0x05;
+    pub const FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+    pub const FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+    pub const READ_BY_TYPE_REQ: u8 = 0x08;
+    pub const READ_BY_TYPE_RSP: u8 = 0x09;
+    pub const READ_REQ: u8 = 0x0a;
+    pub const READ_RSP: u8 = 0x0b;
+    pub const READ_BLOB_REQ: u8 = 0x0c;
+    pub const READ_BLOB_RSP: u8 = 0x0d;
+    pub const READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+    pub const READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+    pub const WRITE_REQ: u8 = 0x12;
+    pub const WRITE_RSP: u8 = 0x13;
+    pub const HANDLE_VALUE_NTF: u8 = 0x1b;
+    pub const HANDLE_VALUE_IND: u8 = 0x1d;
+    pub const HANDLE_VALUE_CFM: u8 = 0x1e;
+    pub const WRITE_CMD: u8 = 0x52;
+
+    /// Set in the opcodes of commands, which have no response.
+    pub const COMMAND_FLAG: u8 = 0x40;
+}
+
+/// ATT error codes.
+pub mod error {
+    pub const INVALID_HANDLE: u8 = 0x01;
+    pub const READ_NOT_PERMITTED: u8 = 0x02;
+    pub const WRITE_NOT_PERMITTED: u8 = 0x03;
+    pub const INVALID_PDU: u8 = 0x04;
+    pub const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+    pub const INVALID_OFFSET: u8 = 0x07;
+    pub const ATTRIBUTE_NOT_FOUND: u8 = 0x0a;
+    pub const INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0d;
+    pub const UNSUPPORTED_GROUP_TYPE: u8 = 0x10;
+}
+
+/// Writes an error response to `request_opcode` for `handle` to `buf` and
+/// returns its length.
+pub fn encode_error(buf: &mut [u8], request_opcode: u8, handle: u16, error: u8) -> usize {
+    buf[0] = opcode::ERROR_RSP;
+    buf[1] = request_opcode;
+    buf[2..4].copy_from_slice(&handle.to_le_bytes());
+    buf[4] = error;
+    5
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_response() {
+        let mut buf = [0u8; DEFAULT_MTU];
+        let len = encode_error(
+            &mut buf,
+            opcode::READ_REQ,
+            0x1234,
+            error::READ_NOT_PERMITTED,
+        );
+        assert_eq!(&buf[..len], &[0x01, 0x0a, 0x34, 0x12, 0x02]);
+
+        // Commands have the command flag set in their opcode
+        assert_eq!(opcode::WRITE_CMD, opcode::WRITE_REQ | opcode::COMMAND_FLAG);
+    }
+}
@@ -0,0 +1,861 @@
+//! Generic attribute profile (GATT) server.
+//!
+//! `Database` stores the attributes of the server in a byte buffer, in
+//! handle order starting at handle 1, and is built with `add_service` and
+//! `add_characteristic`. `handle_pdu` answers the ATT requests of a client
+//! from the database (Vol 3, Part F, section 3.4 and Part G, section 4).
+//!
+//! Each attribute is stored as
+//!
+//! ```txt
+//! +----------+------+-------------+---------+-----+-------+
+//! | UUID len | UUID | permissions | max len | len | value |
+//! +----------+------+-------------+---------+-----+-------+
+//! ```
+//!
+//! where the value field is `max len` bytes long.
+
+use crate::ble::att::{self, error, opcode};
+use core::cmp;
+use kernel::ReturnCode;
+
+/// Attribute types and GATT UUIDs.
+pub mod uuid {
+    pub const GAP_SERVICE: u16 = 0x1800;
+    pub const GATT_SERVICE: u16 = 0x1801;
+    pub const PRIMARY_SERVICE: u16 = 0x2800;
+    pub const SECONDARY_SERVICE: u16 = 0x2801;
+    pub const CHARACTERISTIC: u16 = 0x2803;
+    pub const CLIENT_CHARACTERISTIC_CONFIGURATION: u16 = 0x2902;
+    pub const DEVICE_NAME: u16 = 0x2a00;
+    pub const APPEARANCE: u16 = 0x2a01;
+}
+
+/// Characteristic properties.
+pub mod properties {
+    pub const READ: u8 = 0x02;
+    pub const WRITE_WITHOUT_RESPONSE: u8 = 0x04;
+    pub const WRITE: u8 = 0x08;
+    pub const NOTIFY: u8 = 0x10;
+    pub const INDICATE: u8 = 0x20;
+}
+
+/// Bits of a client characteristic configuration descriptor.
+pub const CCCD_NOTIFY: u16 = 0x0001;
+pub const CCCD_INDICATE: u16 = 0x0002;
+
+const PERMISSION_READ: u8 = 0x01;
+const PERMISSION_WRITE: u8 = 0x02;
+
+// Bluetooth base UUID 00000000-0000-1000-8000-00805F9B34FB, little endian
+const BASE_UUID: [u8; 16] = [
+    0xfb, 0x34, 0x9b, 0x5f, 0x80, 0x00, 0x00, 0x80, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Uuid {
+    Uuid16(u16),
+    /// A 128-bit UUID, little endian
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    /// Decodes a little endian 16-bit or 128-bit UUID.
+    pub fn decode(buf: &[u8]) -> Option<Uuid> {
+        match buf.len() {
+            2 => Some(Uuid::Uuid16(u16::from_le_bytes([buf[0], buf[1]]))),
+            16 => {
+                let mut uuid = [0; 16];
+                uuid.copy_from_slice(buf);
+                Some(Uuid::Uuid128(uuid))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            Uuid::Uuid16(_) => 2,
+            Uuid::Uuid128(_) => 16,
+        }
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        match *self {
+            Uuid::Uuid16(uuid) => buf[..2].copy_from_slice(&uuid.to_le_bytes()),
+            Uuid::Uuid128(uuid) => buf[..16].copy_from_slice(&uuid),
+        }
+    }
+
+    fn to_128(&self) -> [u8; 16] {
+        match *self {
+            Uuid::Uuid16(uuid) => {
+                let mut full = BASE_UUID;
+                full[12..14].copy_from_slice(&uuid.to_le_bytes());
+                full
+            }
+            Uuid::Uuid128(uuid) => uuid,
+        }
+    }
+
+    /// Compares UUIDs, including a 16-bit UUID with its 128-bit form.
+    pub fn matches(&self, other: &Uuid) -> bool {
+        self.to_128() == other.to_128()
+    }
+}
+
+/// An attribute of the database.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Attribute<'d> {
+    pub handle: u16,
+    pub uuid: Uuid,
+    permissions: u8,
+    pub value: &'d [u8],
+}
+
+impl Attribute<'_> {
+    pub fn readable(&self) -> bool {
+        self.permissions & PERMISSION_READ != 0
+    }
+
+    pub fn writable(&self) -> bool {
+        self.permissions & PERMISSION_WRITE != 0
+    }
+
+    fn is_service(&self) -> bool {
+        self.uuid == Uuid::Uuid16(uuid::PRIMARY_SERVICE)
+            || self.uuid == Uuid::Uuid16(uuid::SECONDARY_SERVICE)
+    }
+}
+
+// Decodes the attribute stored at the start of `buf`, returning it and the
+// length of its record.
+fn decode_record(buf: &[u8], handle: u16) -> (Attribute, usize) {
+    let uuid_len = buf[0] as usize;
+    let uuid = Uuid::decode(&buf[1..1 + uuid_len]).unwrap_or(Uuid::Uuid16(0));
+    let fields = 1 + uuid_len;
+    let max_len = buf[fields + 1] as usize;
+    let len = buf[fields + 2] as usize;
+    let value_start = fields + 3;
+    let attribute = Attribute {
+        handle: handle,
+        uuid: uuid,
+        permissions: buf[fields],
+        value: &buf[value_start..value_start + len],
+    };
+    (attribute, value_start + max_len)
+}
+
+pub struct Attributes<'d> {
+    buf: &'d [u8],
+    handle: u16,
+}
+
+impl<'d> Iterator for Attributes<'d> {
+    type Item = Attribute<'d>;
+
+    fn next(&mut self) -> Option<Attribute<'d>> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        self.handle += 1;
+        let (attribute, len) = decode_record(self.buf, self.handle);
+        self.buf = &self.buf[len..];
+        Some(attribute)
+    }
+}
+
+/// The attributes of a GATT server.
+pub struct Database<'b> {
+    buf: &'b mut [u8],
+    used: usize,
+    count: u16,
+}
+
+impl<'b> Database<'b> {
+    pub fn new(buf: &'b mut [u8]) -> Database<'b> {
+        Database {
+            buf: buf,
+            used: 0,
+            count: 0,
+        }
+    }
+
+    /// Removes all attributes.
+    pub fn clear(&mut self) {
+        self.used = 0;
+        self.count = 0;
+    }
+
+    /// Returns the handle of the last attribute, which is the number of
+    /// attributes.
+    pub fn last_handle(&self) -> u16 {
+        self.count
+    }
+
+    pub fn iter(&self) -> Attributes {
+        Attributes {
+            buf: &self.buf[..self.used],
+            handle: 0,
+        }
+    }
+
+    pub fn get(&self, handle: u16) -> Option<Attribute> {
+        if handle == 0 {
+            return None;
+        }
+        self.iter().nth(handle as usize - 1)
+    }
+
+    // Returns the offset of the record of `handle` in the buffer.
+    fn record_offset(&self, handle: u16) -> Option<usize> {
+        if handle == 0 || handle > self.count {
+            return None;
+        }
+        let mut offset = 0;
+        for h in 1..handle {
+            let (_, len) = decode_record(&self.buf[offset..self.used], h);
+            offset += len;
+        }
+        Some(offset)
+    }
+
+    fn add(
+        &mut self,
+        uuid: Uuid,
+        permissions: u8,
+        max_len: usize,
+        value: &[u8],
+    ) -> Result<u16, ReturnCode> {
+        if value.len() > max_len || max_len > u8::max_value() as usize {
+            return Err(ReturnCode::ESIZE);
+        }
+        let fields = 1 + uuid.len();
+        let record_len = fields + 3 + max_len;
+        if self.used + record_len > self.buf.len() || self.count == u16::max_value() {
+            return Err(ReturnCode::ENOMEM);
+        }
+        let record = &mut self.buf[self.used..self.used + record_len];
+        record[0] = uuid.len() as u8;
+        uuid.encode(&mut record[1..fields]);
+        record[fields] = permissions;
+        record[fields + 1] = max_len as u8;
+        record[fields + 2] = value.len() as u8;
+        record[fields + 3..fields + 3 + value.len()].copy_from_slice(value);
+        self.used += record_len;
+        self.count += 1;
+        Ok(self.count)
+    }
+
+    /// Adds a primary service declaration. The characteristics added after
+    /// it belong to the service. Returns the handle of the declaration.
+    pub fn add_service(&mut self, service: Uuid) -> Result<u16, ReturnCode> {
+        let mut value = [0; 16];
+        service.encode(&mut value);
+        self.add(
+            Uuid::Uuid16(uuid::PRIMARY_SERVICE),
+            PERMISSION_READ,
+            service.len(),
+            &value[..service.len()],
+        )
+    }
+
+    /// Adds a characteristic with the given properties and initial value,
+    /// whose value can be up to `max_len` bytes long. A client characteristic
+    /// configuration descriptor follows the value if the characteristic
+    /// supports notifications or indications. Returns the handle of the
+    /// value.
+    pub fn add_characteristic(
+        &mut self,
+        characteristic: Uuid,
+        props: u8,
+        max_len: usize,
+        value: &[u8],
+    ) -> Result<u16, ReturnCode> {
+        let has_cccd = props & (properties::NOTIFY | properties::INDICATE) != 0;
+        let declaration_len = 3 + characteristic.len();
+        let needed = (2 + declaration_len + 3)
+            + (1 + characteristic.len() + 3 + max_len)
+            + if has_cccd { 1 + 2 + 3 + 2 } else { 0 };
+        if self.used + needed > self.buf.len() || value.len() > max_len {
+            return Err(ReturnCode::ENOMEM);
+        }
+
+        let value_handle = self.count + 2;
+        let mut declaration = [0; 19];
+        declaration[0] = props;
+        declaration[1..3].copy_from_slice(&value_handle.to_le_bytes());
+        characteristic.encode(&mut declaration[3..]);
+        self.add(
+            Uuid::Uuid16(uuid::CHARACTERISTIC),
+            PERMISSION_READ,
+            declaration_len,
+            &declaration[..declaration_len],
+        )?;
+
+        let mut permissions = 0;
+        if props & properties::READ != 0 {
+            permissions |= PERMISSION_READ;
+        }
+        if props & (properties::WRITE | properties::WRITE_WITHOUT_RESPONSE) != 0 {
+            permissions |= PERMISSION_WRITE;
+        }
+        self.add(characteristic, permissions, max_len, value)?;
+
+        if has_cccd {
+            self.add(
+                Uuid::Uuid16(uuid::CLIENT_CHARACTERISTIC_CONFIGURATION),
+                PERMISSION_READ | PERMISSION_WRITE,
+                2,
+                &[0, 0],
+            )?;
+        }
+        Ok(value_handle)
+    }
+
+    /// Sets the value of an attribute.
+    pub fn set_value(&mut self, handle: u16, value: &[u8]) -> ReturnCode {
+        let offset = match self.record_offset(handle) {
+            Some(offset) => offset,
+            None => return ReturnCode::EINVAL,
+        };
+        let record = &mut self.buf[offset..];
+        let fields = 1 + record[0] as usize;
+        if value.len() > record[fields + 1] as usize {
+            return ReturnCode::ESIZE;
+        }
+        record[fields + 2] = value.len() as u8;
+        record[fields + 3..fields + 3 + value.len()].copy_from_slice(value);
+        ReturnCode::SUCCESS
+    }
+
+    /// Returns the client characteristic configuration of the characteristic
+    /// with the value `value_handle`, or 0 if it has none.
+    pub fn client_configuration(&self, value_handle: u16) -> u16 {
+        match self.get(value_handle + 1) {
+            Some(attribute)
+                if attribute.uuid == Uuid::Uuid16(uuid::CLIENT_CHARACTERISTIC_CONFIGURATION)
+                    && attribute.value.len() == 2 =>
+            {
+                u16::from_le_bytes([attribute.value[0], attribute.value[1]])
+            }
+            _ => 0,
+        }
+    }
+
+    /// Resets the client characteristic configurations, as when a client
+    /// disconnects.
+    pub fn reset_client_configurations(&mut self) {
+        let cccds = self
+            .iter()
+            .filter(|attribute| {
+                attribute.uuid == Uuid::Uuid16(uuid::CLIENT_CHARACTERISTIC_CONFIGURATION)
+            })
+            .fold(0u64, |cccds, attribute| {
+                // Only the first 64 handles are tracked in the bitmap
+                cccds | 1u64.checked_shl(attribute.handle as u32).unwrap_or(0)
+            });
+        for handle in 1..cmp::min(64, self.count as u32 + 1) {
+            if cccds & (1 << handle) != 0 {
+                self.set_value(handle as u16, &[0, 0]);
+            }
+        }
+    }
+
+    // Returns the last handle of the service declared at `handle`.
+    fn group_end(&self, handle: u16) -> u16 {
+        self.iter()
+            .skip(handle as usize)
+            .find(|attribute| attribute.is_service())
+            .map_or(self.count, |next_service| next_service.handle - 1)
+    }
+}
+
+/// Events caused by a client that the owner of the server is notified of.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ServerEvent {
+    /// The client wrote `len` bytes to an attribute.
+    Written { handle: u16, len: usize },
+    /// The ATT MTU of the connection was changed.
+    MtuChanged(usize),
+    /// The client confirmed an indication.
+    Confirmed,
+}
+
+fn decode_range(pdu: &[u8]) -> (u16, u16) {
+    (
+        u16::from_le_bytes([pdu[1], pdu[2]]),
+        u16::from_le_bytes([pdu[3], pdu[4]]),
+    )
+}
+
+fn valid_range(start: u16, end: u16) -> bool {
+    start != 0 && start <= end
+}
+
+/// Handles an ATT PDU from a client. `mtu` is the current ATT MTU and
+/// `server_mtu` the largest MTU the server supports; `rsp` must hold `mtu`
+/// bytes. Returns the length of the response written to `rsp` (0 if there
+/// is none) and the event caused by the PDU, if any.
+pub fn handle_pdu(
+    db: &mut Database,
+    mtu: usize,
+    server_mtu: usize,
+    pdu: &[u8],
+    rsp: &mut [u8],
+) -> (usize, Option<ServerEvent>) {
+    let request = match pdu.first() {
+        Some(&request) => request,
+        None => return (0, None),
+    };
+    let rsp = &mut rsp[..mtu];
+    match request {
+        opcode::EXCHANGE_MTU_REQ if pdu.len() == 3 => {
+            let client_mtu = u16::from_le_bytes([pdu[1], pdu[2]]) as usize;
+            rsp[0] = opcode::EXCHANGE_MTU_RSP;
+            rsp[1..3].copy_from_slice(&(server_mtu as u16).to_le_bytes());
+            let mtu = cmp::max(att::DEFAULT_MTU, cmp::min(client_mtu, server_mtu));
+            (3, Some(ServerEvent::MtuChanged(mtu)))
+        }
+        opcode::FIND_INFORMATION_REQ if pdu.len() == 5 => (find_information(db, pdu, rsp), None),
+        opcode::FIND_BY_TYPE_VALUE_REQ if pdu.len() >= 7 => {
+            (find_by_type_value(db, pdu, rsp), None)
+        }
+        opcode::READ_BY_TYPE_REQ if pdu.len() == 7 || pdu.len() == 21 => {
+            (read_by_type(db, pdu, rsp), None)
+        }
+        opcode::READ_REQ if pdu.len() == 3 => {
+            let handle = u16::from_le_bytes([pdu[1], pdu[2]]);
+            (read(db, request, handle, 0, rsp), None)
+        }
+        opcode::READ_BLOB_REQ if pdu.len() == 5 => {
+            let handle = u16::from_le_bytes([pdu[1], pdu[2]]);
+            let offset = u16::from_le_bytes([pdu[3], pdu[4]]) as usize;
+            (read(db, request, handle, offset, rsp), None)
+        }
+        opcode::READ_BY_GROUP_TYPE_REQ if pdu.len() == 7 || pdu.len() == 21 => {
+            (read_by_group_type(db, pdu, rsp), None)
+        }
+        opcode::WRITE_REQ | opcode::WRITE_CMD if pdu.len() >= 3 => {
+            let handle = u16::from_le_bytes([pdu[1], pdu[2]]);
+            let value = &pdu[3..];
+            let result = match db.get(handle) {
+                None => Err(error::INVALID_HANDLE),
+                Some(attribute) if !attribute.writable() => Err(error::WRITE_NOT_PERMITTED),
+                Some(_) => match db.set_value(handle, value) {
+                    ReturnCode::SUCCESS => Ok(()),
+                    _ => Err(error::INVALID_ATTRIBUTE_VALUE_LENGTH),
+                },
+            };
+            let event = ServerEvent::Written {
+                handle: handle,
+                len: value.len(),
+            };
+            match (request, result) {
+                (opcode::WRITE_REQ, Ok(())) => {
+                    rsp[0] = opcode::WRITE_RSP;
+                    (1, Some(event))
+                }
+                (opcode::WRITE_REQ, Err(e)) => (att::encode_error(rsp, request, handle, e), None),
+                (_, Ok(())) => (0, Some(event)),
+                (_, Err(_)) => (0, None),
+            }
+        }
+        opcode::HANDLE_VALUE_CFM if pdu.len() == 1 => (0, Some(ServerEvent::Confirmed)),
+        opcode::EXCHANGE_MTU_REQ
+        | opcode::FIND_INFORMATION_REQ
+        | opcode::FIND_BY_TYPE_VALUE_REQ
+        | opcode::READ_BY_TYPE_REQ
+        | opcode::READ_REQ
+        | opcode::READ_BLOB_REQ
+        | opcode::READ_BY_GROUP_TYPE_REQ
+        | opcode::WRITE_REQ => (att::encode_error(rsp, request, 0, error::INVALID_PDU), None),
+        // Unsupported commands are ignored
+        _ if request & opcode::COMMAND_FLAG != 0 => (0, None),
+        opcode::HANDLE_VALUE_CFM => (0, None),
+        _ => (
+            att::encode_error(rsp, request, 0, error::REQUEST_NOT_SUPPORTED),
+            None,
+        ),
+    }
+}
+
+fn find_information(db: &Database, pdu: &[u8], rsp: &mut [u8]) -> usize {
+    let (start, end) = decode_range(pdu);
+    if !valid_range(start, end) {
+        return att::encode_error(rsp, pdu[0], start, error::INVALID_HANDLE);
+    }
+    let mut uuid_len = 0;
+    let mut len = 2;
+    for attribute in db
+        .iter()
+        .skip(start as usize - 1)
+        .take_while(|attribute| attribute.handle <= end)
+    {
+        if uuid_len == 0 {
+            uuid_len = attribute.uuid.len();
+        }
+        // All entries have the same format, and must fit in the response
+        if attribute.uuid.len() != uuid_len || len + 2 + uuid_len > rsp.len() {
+            break;
+        }
+        rsp[len..len + 2].copy_from_slice(&attribute.handle.to_le_bytes());
+        attribute.uuid.encode(&mut rsp[len + 2..]);
+        len += 2 + uuid_len;
+    }
+    if uuid_len == 0 {
+        return att::encode_error(rsp, pdu[0], start, error::ATTRIBUTE_NOT_FOUND);
+    }
+    rsp[0] = opcode::FIND_INFORMATION_RSP;
+    rsp[1] = if uuid_len == 2 { 1 } else { 2 };
+    len
+}
+
+fn find_by_type_value(db: &Database, pdu: &[u8], rsp: &mut [u8]) -> usize {
+    let (start, end) = decode_range(pdu);
+    if !valid_range(start, end) {
+        return att::encode_error(rsp, pdu[0], start, error::INVALID_HANDLE);
+    }
+    let attribute_type = Uuid::Uuid16(u16::from_le_bytes([pdu[5], pdu[6]]));
+    let value = &pdu[7..];
+    let mut len = 1;
+    for attribute in db
+        .iter()
+        .skip(start as usize - 1)
+        .take_while(|attribute| attribute.handle <= end)
+        .filter(|attribute| attribute.uuid == attribute_type && attribute.value == value)
+    {
+        if len + 4 > rsp.len() {
+            break;
+        }
+        let group_end = if attribute.is_service() {
+            db.group_end(attribute.handle)
+        } else {
+            attribute.handle
+        };
+        rsp[len..len + 2].copy_from_slice(&attribute.handle.to_le_bytes());
+        rsp[len + 2..len + 4].copy_from_slice(&group_end.to_le_bytes());
+        len += 4;
+    }
+    if len == 1 {
+        return att::encode_error(rsp, pdu[0], start, error::ATTRIBUTE_NOT_FOUND);
+    }
+    rsp[0] = opcode::FIND_BY_TYPE_VALUE_RSP;
+    len
+}
+
+fn read_by_type(db: &Database, pdu: &[u8], rsp: &mut [u8]) -> usize {
+    let (start, end) = decode_range(pdu);
+    if !valid_range(start, end) {
+        return att::encode_error(rsp, pdu[0], start, error::INVALID_HANDLE);
+    }
+    let attribute_type = Uuid::decode(&pdu[5..]).unwrap_or(Uuid::Uuid16(0));
+    // Values are truncated to fit in one entry of the response
+    let max_value_len = cmp::min(rsp.len() - 4, 253);
+    let mut value_len = None;
+    let mut len = 2;
+    for attribute in db
+        .iter()
+        .skip(start as usize - 1)
+        .take_while(|attribute| attribute.handle <= end)
+        .filter(|attribute| attribute.uuid.matches(&attribute_type))
+    {
+        if !attribute.readable() {
+            if value_len.is_none() {
+                return att::encode_error(rsp, pdu[0], attribute.handle, error::READ_NOT_PERMITTED);
+            }
+            break;
+        }
+        let attribute_len = cmp::min(attribute.value.len(), max_value_len);
+        match value_len {
+            None => value_len = Some(attribute_len),
+            Some(value_len) if value_len != attribute_len => break,
+            Some(_) => {}
+        }
+        if len + 2 + attribute_len > rsp.len() {
+            break;
+        }
+        rsp[len..len + 2].copy_from_slice(&attribute.handle.to_le_bytes());
+        rsp[len + 2..len + 2 + attribute_len].copy_from_slice(&attribute.value[..attribute_len]);
+        len += 2 + attribute_len;
+    }
+    match value_len {
+        Some(value_len) => {
+            rsp[0] = opcode::READ_BY_TYPE_RSP;
+            rsp[1] = (2 + value_len) as u8;
+            len
+        }
+        None => att::encode_error(rsp, pdu[0], start, error::ATTRIBUTE_NOT_FOUND),
+    }
+}
+
+fn read(db: &Database, request: u8, handle: u16, offset: usize, rsp: &mut [u8]) -> usize {
+    let attribute = match db.get(handle) {
+        Some(attribute) => attribute,
+        None => return att::encode_error(rsp, request, handle, error::INVALID_HANDLE),
+    };
+    if !attribute.readable() {
+        return att::encode_error(rsp, request, handle, error::READ_NOT_PERMITTED);
+    }
+    if offset > attribute.value.len() {
+        return att::encode_error(rsp, request, handle, error::INVALID_OFFSET);
+    }
+    let value = &attribute.value[offset..];
+    let len = cmp::min(value.len(), rsp.len() - 1);
+    rsp[0] = if request == opcode::READ_REQ {
+        opcode::READ_RSP
+    } else {
+        opcode::READ_BLOB_RSP
+    };
+    rsp[1..1 + len].copy_from_slice(&value[..len]);
+    1 + len
+}
+
+fn read_by_group_type(db: &Database, pdu: &[u8], rsp: &mut [u8]) -> usize {
+    let (start, end) = decode_range(pdu);
+    if !valid_range(start, end) {
+        return att::encode_error(rsp, pdu[0], start, error::INVALID_HANDLE);
+    }
+    let group_type = Uuid::decode(&pdu[5..]).unwrap_or(Uuid::Uuid16(0));
+    if !group_type.matches(&Uuid::Uuid16(uuid::PRIMARY_SERVICE))
+        && !group_type.matches(&Uuid::Uuid16(uuid::SECONDARY_SERVICE))
+    {
+        return att::encode_error(rsp, pdu[0], start, error::UNSUPPORTED_GROUP_TYPE);
+    }
+    let max_value_len = cmp::min(rsp.len() - 6, 251);
+    let mut value_len = None;
+    let mut len = 2;
+    for attribute in db
+        .iter()
+        .skip(start as usize - 1)
+        .take_while(|attribute| attribute.handle <= end)
+        .filter(|attribute| attribute.uuid.matches(&group_type))
+    {
+        let attribute_len = cmp::min(attribute.value.len(), max_value_len);
+        match value_len {
+            None => value_len = Some(attribute_len),
+            Some(value_len) if value_len != attribute_len => break,
+            Some(_) => {}
+        }
+        if len + 4 + attribute_len > rsp.len() {
+            break;
+        }
+        let group_end = db.group_end(attribute.handle);
+        rsp[len..len + 2].copy_from_slice(&attribute.handle.to_le_bytes());
+        rsp[len + 2..len + 4].copy_from_slice(&group_end.to_le_bytes());
+        rsp[len + 4..len + 4 + attribute_len].copy_from_slice(&attribute.value[..attribute_len]);
+        len += 4 + attribute_len;
+    }
+    match value_len {
+        Some(value_len) => {
+            rsp[0] = opcode::READ_BY_GROUP_TYPE_RSP;
+            rsp[1] = (4 + value_len) as u8;
+            len
+        }
+        None => att::encode_error(rsp, pdu[0], start, error::ATTRIBUTE_NOT_FOUND),
+    }
+}
+
+/// Writes a notification (or an indication if `indicate`) of the value of
+/// the attribute `handle` to `buf`, truncated to the ATT MTU `mtu`. Returns
+/// the length of the PDU, or `None` if there is no such attribute.
+pub fn encode_notification(
+    db: &Database,
+    handle: u16,
+    indicate: bool,
+    mtu: usize,
+    buf: &mut [u8],
+) -> Option<usize> {
+    let attribute = db.get(handle)?;
+    let len = cmp::min(attribute.value.len(), cmp::min(mtu, buf.len()) - 3);
+    buf[0] = if indicate {
+        opcode::HANDLE_VALUE_IND
+    } else {
+        opcode::HANDLE_VALUE_NTF
+    };
+    buf[1..3].copy_from_slice(&handle.to_le_bytes());
+    buf[3..3 + len].copy_from_slice(&attribute.value[..len]);
+    Some(3 + len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SERVICE: u16 = 0x180f;
+    const LEVEL: u16 = 0x2a19;
+
+    // Handles: 1 GAP service, 2-3 device name, 4 battery service, 5-7 battery
+    // level with CCCD
+    fn build(buf: &mut [u8]) -> Database {
+        let mut db = Database::new(buf);
+        assert_eq!(db.add_service(Uuid::Uuid16(uuid::GAP_SERVICE)), Ok(1));
+        assert_eq!(
+            db.add_characteristic(
+                Uuid::Uuid16(uuid::DEVICE_NAME),
+                properties::READ,
+                8,
+                b"tock"
+            ),
+            Ok(3)
+        );
+        assert_eq!(db.add_service(Uuid::Uuid16(SERVICE)), Ok(4));
+        assert_eq!(
+            db.add_characteristic(
+                Uuid::Uuid16(LEVEL),
+                properties::READ | properties::WRITE | properties::NOTIFY,
+                1,
+                &[100]
+            ),
+            Ok(6)
+        );
+        db
+    }
+
+    #[test]
+    fn discovery() {
+        let mut buf = [0u8; 128];
+        let mut db = build(&mut buf);
+        assert_eq!(db.last_handle(), 7);
+        let mut rsp = [0u8; 23];
+
+        // Primary services
+        let req = [opcode::READ_BY_GROUP_TYPE_REQ, 1, 0, 0xff, 0xff, 0x00, 0x28];
+        assert_eq!(handle_pdu(&mut db, 23, 64, &req, &mut rsp), (14, None));
+        assert_eq!(
+            &rsp[..14],
+            &[
+                opcode::READ_BY_GROUP_TYPE_RSP,
+                6,
+                1,
+                0,
+                3,
+                0,
+                0x00,
+                0x18,
+                4,
+                0,
+                7,
+                0,
+                0x0f,
+                0x18
+            ]
+        );
+
+        // Characteristics of the second service
+        let req = [opcode::READ_BY_TYPE_REQ, 4, 0, 7, 0, 0x03, 0x28];
+        assert_eq!(handle_pdu(&mut db, 23, 64, &req, &mut rsp), (9, None));
+        assert_eq!(
+            &rsp[..9],
+            &[opcode::READ_BY_TYPE_RSP, 7, 5, 0, 0x1a, 6, 0, 0x19, 0x2a]
+        );
+
+        // Descriptors
+        let req = [opcode::FIND_INFORMATION_REQ, 7, 0, 7, 0];
+        assert_eq!(handle_pdu(&mut db, 23, 64, &req, &mut rsp), (6, None));
+        assert_eq!(
+            &rsp[..6],
+            &[opcode::FIND_INFORMATION_RSP, 1, 7, 0, 0x02, 0x29]
+        );
+
+        // Service by UUID
+        let req = [
+            opcode::FIND_BY_TYPE_VALUE_REQ,
+            1,
+            0,
+            0xff,
+            0xff,
+            0x00,
+            0x28,
+            0x0f,
+            0x18,
+        ];
+        assert_eq!(handle_pdu(&mut db, 23, 64, &req, &mut rsp), (5, None));
+        assert_eq!(&rsp[..5], &[opcode::FIND_BY_TYPE_VALUE_RSP, 4, 0, 7, 0]);
+
+        // Past the last attribute
+        let req = [opcode::FIND_INFORMATION_REQ, 8, 0, 0xff, 0xff];
+        assert_eq!(handle_pdu(&mut db, 23, 64, &req, &mut rsp), (5, None));
+        assert_eq!(
+            &rsp[..5],
+            &[
+                opcode::ERROR_RSP,
+                opcode::FIND_INFORMATION_REQ,
+                8,
+                0,
+                error::ATTRIBUTE_NOT_FOUND
+            ]
+        );
+    }
+
+    #[test]
+    fn read_and_write() {
+        let mut buf = [0u8; 128];
+        let mut db = build(&mut buf);
+        let mut rsp = [0u8; 23];
+
+        assert_eq!(
+            handle_pdu(&mut db, 23, 64, &[opcode::READ_REQ, 3, 0], &mut rsp),
+            (5, None)
+        );
+        assert_eq!(&rsp[..5], b"\x0btock");
+        assert_eq!(
+            handle_pdu(
+                &mut db,
+                23,
+                64,
+                &[opcode::READ_BLOB_REQ, 3, 0, 2, 0],
+                &mut rsp
+            ),
+            (3, None)
+        );
+        assert_eq!(&rsp[..3], b"\x0dck");
+
+        // The device name is read only
+        let req = [opcode::WRITE_REQ, 3, 0, b'x'];
+        assert_eq!(handle_pdu(&mut db, 23, 64, &req, &mut rsp), (5, None));
+        assert_eq!(rsp[4], error::WRITE_NOT_PERMITTED);
+
+        let req = [opcode::WRITE_REQ, 6, 0, 42];
+        assert_eq!(
+            handle_pdu(&mut db, 23, 64, &req, &mut rsp),
+            (1, Some(ServerEvent::Written { handle: 6, len: 1 }))
+        );
+        assert_eq!(db.get(6).unwrap().value, &[42]);
+
+        // Value longer than the characteristic
+        let req = [opcode::WRITE_REQ, 6, 0, 1, 2];
+        assert_eq!(handle_pdu(&mut db, 23, 64, &req, &mut rsp), (5, None));
+        assert_eq!(rsp[4], error::INVALID_ATTRIBUTE_VALUE_LENGTH);
+
+        // Enable notifications
+        assert_eq!(db.client_configuration(6), 0);
+        let req = [opcode::WRITE_CMD, 7, 0, 1, 0];
+        assert_eq!(
+            handle_pdu(&mut db, 23, 64, &req, &mut rsp),
+            (0, Some(ServerEvent::Written { handle: 7, len: 2 }))
+        );
+        assert_eq!(db.client_configuration(6), CCCD_NOTIFY);
+        let mut ntf = [0u8; 23];
+        assert_eq!(encode_notification(&db, 6, false, 23, &mut ntf), Some(4));
+        assert_eq!(&ntf[..4], &[opcode::HANDLE_VALUE_NTF, 6, 0, 42]);
+        db.reset_client_configurations();
+        assert_eq!(db.client_configuration(6), 0);
+
+        assert_eq!(
+            handle_pdu(
+                &mut db,
+                23,
+                64,
+                &[opcode::EXCHANGE_MTU_REQ, 100, 0],
+                &mut rsp
+            ),
+            (3, Some(ServerEvent::MtuChanged(64)))
+        );
+        assert_eq!(
+            handle_pdu(&mut db, 23, 64, &[0x16, 1, 0, 0, 0], &mut rsp),
+            (5, None)
+        );
+        assert_eq!(rsp[4], error::REQUEST_NOT_SUPPORTED);
+    }
+}
@@ -0,0 +1,186 @@
+//! L2CAP basic mode over a Bluetooth Low Energy connection.
+//!
+//! L2CAP PDUs (Vol 3, Part A, section 3.1) are carried in one or more link
+//! layer data PDUs: the first fragment is sent with `llid::START` and the
+//! following ones with `llid::CONTINUATION`. `Reassembler` rebuilds received
+//! PDUs and `Fragmenter` splits PDUs to send into link layer payloads.
+
+use crate::ble::link_layer::llid;
+use kernel::ReturnCode;
+
+pub const HEADER_LEN: usize = 4;
+
+/// Fixed channel identifiers of an LE connection.
+pub mod cid {
+    pub const ATT: u16 = 0x0004;
+    pub const LE_SIGNALING: u16 = 0x0005;
+    pub const SMP: u16 = 0x0006;
+}
+
+/// LE signaling channel command codes.
+pub mod signaling {
+    pub const COMMAND_REJECT: u8 = 0x01;
+    /// Command reject reason: command not understood
+    pub const REASON_NOT_UNDERSTOOD: u16 = 0x0000;
+}
+
+/// Security manager protocol codes.
+pub mod smp {
+    pub const PAIRING_REQUEST: u8 = 0x01;
+    pub const PAIRING_FAILED: u8 = 0x05;
+    /// Pairing failed reason: pairing not supported
+    pub const REASON_PAIRING_NOT_SUPPORTED: u8 = 0x05;
+}
+
+/// Writes the basic L2CAP header for a payload of `len` bytes on channel
+/// `cid` to `buf`.
+pub fn encode_header(buf: &mut [u8], len: usize, cid: u16) {
+    buf[0..2].copy_from_slice(&(len as u16).to_le_bytes());
+    buf[2..4].copy_from_slice(&cid.to_le_bytes());
+}
+
+/// Decodes the basic L2CAP header, returning the payload length and channel.
+pub fn decode_header(buf: &[u8]) -> Option<(usize, u16)> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+    Some((
+        u16::from_le_bytes([buf[0], buf[1]]) as usize,
+        u16::from_le_bytes([buf[2], buf[3]]),
+    ))
+}
+
+/// Reassembles L2CAP PDUs from link layer data PDUs.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Reassembler {
+    received: usize,
+}
+
+impl Reassembler {
+    /// Adds the payload of a data PDU with the given LLID to the PDU being
+    /// reassembled in `buf`. Returns the length of the PDU (header and
+    /// payload) once it is complete.
+    ///
+    /// Returns `ReturnCode::ESIZE` if the PDU does not fit in `buf` and
+    /// `ReturnCode::EINVAL` if the fragment is unexpected or longer than the
+    /// PDU; the partial PDU is discarded in both cases.
+    pub fn push(
+        &mut self,
+        buf: &mut [u8],
+        llid: u8,
+        fragment: &[u8],
+    ) -> Result<Option<usize>, ReturnCode> {
+        match llid {
+            llid::START => self.received = 0,
+            llid::CONTINUATION if self.received > 0 => {}
+            _ => {
+                self.received = 0;
+                return Err(ReturnCode::EINVAL);
+            }
+        }
+        let end = self.received + fragment.len();
+        if end > buf.len() {
+            self.received = 0;
+            return Err(ReturnCode::ESIZE);
+        }
+        buf[self.received..end].copy_from_slice(fragment);
+        self.received = end;
+
+        match decode_header(&buf[..end]) {
+            Some((len, _)) if end == HEADER_LEN + len => {
+                self.received = 0;
+                Ok(Some(end))
+            }
+            Some((len, _)) if end > HEADER_LEN + len => {
+                self.received = 0;
+                Err(ReturnCode::EINVAL)
+            }
+            Some((len, _)) if HEADER_LEN + len > buf.len() => {
+                self.received = 0;
+                Err(ReturnCode::ESIZE)
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Splits an L2CAP PDU into link layer data PDU payloads.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Fragmenter {
+    len: usize,
+    sent: usize,
+}
+
+impl Fragmenter {
+    /// Starts sending a PDU of `len` bytes (header and payload).
+    pub fn new(len: usize) -> Fragmenter {
+        Fragmenter { len: len, sent: 0 }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.sent >= self.len
+    }
+
+    /// Copies the next fragment of at most `max_len` bytes of `pdu` to
+    /// `buf`. Returns the LLID and length of the fragment, or `None` when
+    /// the whole PDU has been sent.
+    pub fn next(&mut self, pdu: &[u8], buf: &mut [u8], max_len: usize) -> Option<(u8, usize)> {
+        if self.is_done() {
+            return None;
+        }
+        let llid = if self.sent == 0 {
+            llid::START
+        } else {
+            llid::CONTINUATION
+        };
+        let len = core::cmp::min(max_len, self.len - self.sent);
+        buf[..len].copy_from_slice(&pdu[self.sent..self.sent + len]);
+        self.sent += len;
+        Some((llid, len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fragment_and_reassemble() {
+        let mut pdu = [0u8; 40];
+        encode_header(&mut pdu, 36, cid::ATT);
+        for (i, b) in pdu[HEADER_LEN..].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        let mut fragmenter = Fragmenter::new(pdu.len());
+        let mut reassembler = Reassembler::default();
+        let mut fragment = [0u8; 27];
+        let mut buf = [0u8; 64];
+
+        let (llid, len) = fragmenter.next(&pdu, &mut fragment, 27).unwrap();
+        assert_eq!((llid, len), (llid::START, 27));
+        assert_eq!(reassembler.push(&mut buf, llid, &fragment[..len]), Ok(None));
+
+        let (llid, len) = fragmenter.next(&pdu, &mut fragment, 27).unwrap();
+        assert_eq!((llid, len), (llid::CONTINUATION, 13));
+        assert_eq!(
+            reassembler.push(&mut buf, llid, &fragment[..len]),
+            Ok(Some(40))
+        );
+        assert_eq!(&buf[..40], &pdu[..]);
+        assert_eq!(decode_header(&buf), Some((36, cid::ATT)));
+        assert_eq!(fragmenter.next(&pdu, &mut fragment, 27), None);
+
+        // A continuation without a start is rejected
+        assert_eq!(
+            reassembler.push(&mut buf, llid::CONTINUATION, &[1, 2]),
+            Err(ReturnCode::EINVAL)
+        );
+        // A PDU longer than the buffer is rejected
+        let mut small = [0u8; 16];
+        assert_eq!(
+            reassembler.push(&mut small, llid::START, &pdu[..8]),
+            Err(ReturnCode::ESIZE)
+        );
+    }
+}
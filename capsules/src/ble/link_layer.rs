@@ -0,0 +1,611 @@
+//! Bluetooth Low Energy link layer PDU formats.
+//!
+//! Encoding and decoding of the advertising channel PDUs, data channel PDU
+//! headers and link layer control PDUs used by a peripheral, together with
+//! the acknowledgement and flow control state and channel selection
+//! algorithm #1 of a connection (Bluetooth Core Specification v5.0, Vol 6,
+//! Part B, sections 2.3, 2.4, 4.5 and 4.5.8.2).
+
+/// Maximum length of an advertising channel PDU (header and payload).
+pub const MAX_ADV_PDU_LEN: usize = 39;
+/// Maximum advertising data or scan response data length.
+pub const MAX_ADV_DATA_LEN: usize = 31;
+/// Maximum payload of a data channel PDU without the data length extension.
+pub const MAX_DATA_PAYLOAD: usize = 27;
+/// Maximum length of a data channel PDU (header and payload).
+pub const MAX_DATA_PDU_LEN: usize = PDU_HEADER_LEN + MAX_DATA_PAYLOAD;
+pub const PDU_HEADER_LEN: usize = 2;
+pub const ADDRESS_LEN: usize = 6;
+
+/// Advertising channel PDU types.
+pub mod adv_pdu_type {
+    pub const ADV_IND: u8 = 0x0;
+    pub const ADV_DIRECT_IND: u8 = 0x1;
+    pub const ADV_NONCONN_IND: u8 = 0x2;
+    pub const SCAN_REQ: u8 = 0x3;
+    pub const SCAN_RSP: u8 = 0x4;
+    pub const CONNECT_IND: u8 = 0x5;
+    pub const ADV_SCAN_IND: u8 = 0x6;
+}
+
+const ADV_PDU_TYPE_MASK: u8 = 0x0f;
+const ADV_TX_ADD: u8 = 1 << 6;
+const ADV_RX_ADD: u8 = 1 << 7;
+
+/// Header of an advertising channel PDU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AdvHeader {
+    pub pdu_type: u8,
+    /// The address in the first address field is random.
+    pub tx_add: bool,
+    /// The address in the second address field is random.
+    pub rx_add: bool,
+    pub length: u8,
+}
+
+impl AdvHeader {
+    /// Decodes the header of `pdu`, checking that the payload is present.
+    pub fn decode(pdu: &[u8]) -> Option<AdvHeader> {
+        if pdu.len() < PDU_HEADER_LEN || pdu.len() < PDU_HEADER_LEN + pdu[1] as usize {
+            return None;
+        }
+        Some(AdvHeader {
+            pdu_type: pdu[0] & ADV_PDU_TYPE_MASK,
+            tx_add: pdu[0] & ADV_TX_ADD != 0,
+            rx_add: pdu[0] & ADV_RX_ADD != 0,
+            length: pdu[1],
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.pdu_type & ADV_PDU_TYPE_MASK;
+        if self.tx_add {
+            buf[0] |= ADV_TX_ADD;
+        }
+        if self.rx_add {
+            buf[0] |= ADV_RX_ADD;
+        }
+        buf[1] = self.length;
+    }
+}
+
+/// Writes an advertising PDU of type `pdu_type` with the advertiser address
+/// `adv_a` followed by `data` to `buf`. Returns the length of the PDU, or
+/// `None` if `data` or `buf` is too long or too short.
+pub fn encode_adv_pdu(
+    buf: &mut [u8],
+    pdu_type: u8,
+    adv_a: &[u8; ADDRESS_LEN],
+    random_address: bool,
+    data: &[u8],
+) -> Option<usize> {
+    let len = PDU_HEADER_LEN + ADDRESS_LEN + data.len();
+    if data.len() > MAX_ADV_DATA_LEN || buf.len() < len {
+        return None;
+    }
+    AdvHeader {
+        pdu_type: pdu_type,
+        tx_add: random_address,
+        rx_add: false,
+        length: (ADDRESS_LEN + data.len()) as u8,
+    }
+    .encode(buf);
+    buf[PDU_HEADER_LEN..PDU_HEADER_LEN + ADDRESS_LEN].copy_from_slice(adv_a);
+    buf[PDU_HEADER_LEN + ADDRESS_LEN..len].copy_from_slice(data);
+    Some(len)
+}
+
+/// The data channels used by a connection.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ChannelMap([u8; 5]);
+
+impl ChannelMap {
+    /// Creates a channel map from its 5-byte encoding, in which bit `n` is
+    /// set if data channel `n` is used. Returns `None` if fewer than two
+    /// channels are used.
+    pub fn new(map: [u8; 5]) -> Option<ChannelMap> {
+        // Channels 37 to 39 are reserved
+        let channel_map = ChannelMap([map[0], map[1], map[2], map[3], map[4] & 0x1f]);
+        if channel_map.used_count() < 2 {
+            None
+        } else {
+            Some(channel_map)
+        }
+    }
+
+    pub fn is_used(&self, channel: u8) -> bool {
+        channel < 37 && self.0[channel as usize / 8] & (1 << (channel % 8)) != 0
+    }
+
+    pub fn used_count(&self) -> u8 {
+        self.0.iter().map(|b| b.count_ones() as u8).sum()
+    }
+
+    // Returns the used channel with the given index in ascending order.
+    fn nth_used(&self, n: u8) -> u8 {
+        (0..37)
+            .filter(|&channel| self.is_used(channel))
+            .nth(n as usize)
+            .unwrap_or(0)
+    }
+
+    /// Channel selection algorithm #1. Returns the unmapped channel and the
+    /// data channel of the connection event following the one that used
+    /// the unmapped channel `last_unmapped`.
+    pub fn select(&self, last_unmapped: u8, hop: u8) -> (u8, u8) {
+        let unmapped = (last_unmapped + hop) % 37;
+        if self.is_used(unmapped) {
+            (unmapped, unmapped)
+        } else {
+            (unmapped, self.nth_used(unmapped % self.used_count()))
+        }
+    }
+}
+
+/// Connection parameters sent by the central in a CONNECT_IND PDU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ConnectInd {
+    pub init_a: [u8; ADDRESS_LEN],
+    pub init_random: bool,
+    pub adv_a: [u8; ADDRESS_LEN],
+    pub adv_random: bool,
+    pub access_address: u32,
+    pub crc_init: u32,
+    /// Transmit window size, in units of 1.25 ms
+    pub win_size: u8,
+    /// Transmit window offset, in units of 1.25 ms
+    pub win_offset: u16,
+    /// Connection interval, in units of 1.25 ms
+    pub interval: u16,
+    pub latency: u16,
+    /// Supervision timeout, in units of 10 ms
+    pub timeout: u16,
+    pub channel_map: ChannelMap,
+    pub hop: u8,
+    /// Sleep clock accuracy of the central (0: 251-500 ppm, ..., 7: 0-20 ppm)
+    pub sca: u8,
+}
+
+impl ConnectInd {
+    const PAYLOAD_LEN: usize = 34;
+
+    /// Decodes a CONNECT_IND PDU, including the PDU header. Returns `None`
+    /// if the PDU is not a valid CONNECT_IND.
+    pub fn decode(pdu: &[u8]) -> Option<ConnectInd> {
+        let header = AdvHeader::decode(pdu)?;
+        if header.pdu_type != adv_pdu_type::CONNECT_IND
+            || header.length as usize != ConnectInd::PAYLOAD_LEN
+        {
+            return None;
+        }
+        let p = &pdu[PDU_HEADER_LEN..];
+        let u16_at = |i: usize| u16::from_le_bytes([p[i], p[i + 1]]);
+        let mut init_a = [0; ADDRESS_LEN];
+        init_a.copy_from_slice(&p[0..6]);
+        let mut adv_a = [0; ADDRESS_LEN];
+        adv_a.copy_from_slice(&p[6..12]);
+        let mut map = [0; 5];
+        map.copy_from_slice(&p[28..33]);
+
+        let connect_ind = ConnectInd {
+            init_a: init_a,
+            init_random: header.tx_add,
+            adv_a: adv_a,
+            adv_random: header.rx_add,
+            access_address: u32::from_le_bytes([p[12], p[13], p[14], p[15]]),
+            crc_init: u32::from_le_bytes([p[16], p[17], p[18], 0]),
+            win_size: p[19],
+            win_offset: u16_at(20),
+            interval: u16_at(22),
+            latency: u16_at(24),
+            timeout: u16_at(26),
+            channel_map: ChannelMap::new(map)?,
+            hop: p[33] & 0x1f,
+            sca: p[33] >> 5,
+        };
+        // Vol 6, Part B, section 2.3.3.1: valid ranges of the parameters
+        let valid = (5..=16).contains(&connect_ind.hop)
+            && (1..=8).contains(&connect_ind.win_size)
+            && connect_ind.win_offset <= connect_ind.interval
+            && (6..=3200).contains(&connect_ind.interval)
+            && (10..=3200).contains(&connect_ind.timeout);
+        if valid {
+            Some(connect_ind)
+        } else {
+            None
+        }
+    }
+}
+
+/// Logical link identifiers of data channel PDUs.
+pub mod llid {
+    /// Continuation fragment of an L2CAP message, or an empty PDU
+    pub const CONTINUATION: u8 = 0x1;
+    /// Start of an L2CAP message, or a complete message
+    pub const START: u8 = 0x2;
+    /// Link layer control PDU
+    pub const CONTROL: u8 = 0x3;
+}
+
+const DATA_LLID_MASK: u8 = 0x03;
+const DATA_NESN: u8 = 1 << 2;
+const DATA_SN: u8 = 1 << 3;
+const DATA_MD: u8 = 1 << 4;
+
+/// Header of a data channel PDU.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DataHeader {
+    pub llid: u8,
+    /// Next expected sequence number
+    pub nesn: bool,
+    /// Sequence number
+    pub sn: bool,
+    /// More data
+    pub md: bool,
+    pub length: u8,
+}
+
+impl DataHeader {
+    /// Decodes the header of `pdu`, checking that the payload is present.
+    pub fn decode(pdu: &[u8]) -> Option<DataHeader> {
+        if pdu.len() < PDU_HEADER_LEN || pdu.len() < PDU_HEADER_LEN + pdu[1] as usize {
+            return None;
+        }
+        let llid = pdu[0] & DATA_LLID_MASK;
+        if llid == 0 {
+            // Reserved
+            return None;
+        }
+        Some(DataHeader {
+            llid: llid,
+            nesn: pdu[0] & DATA_NESN != 0,
+            sn: pdu[0] & DATA_SN != 0,
+            md: pdu[0] & DATA_MD != 0,
+            length: pdu[1],
+        })
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) {
+        buf[0] = self.llid & DATA_LLID_MASK;
+        if self.nesn {
+            buf[0] |= DATA_NESN;
+        }
+        if self.sn {
+            buf[0] |= DATA_SN;
+        }
+        if self.md {
+            buf[0] |= DATA_MD;
+        }
+        buf[1] = self.length;
+    }
+}
+
+/// Acknowledgement and flow control state of a connection (section 4.5.9).
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct AckState {
+    /// Sequence number of the PDU being sent
+    pub sn: bool,
+    /// Sequence number of the next PDU expected from the peer
+    pub nesn: bool,
+}
+
+impl AckState {
+    /// Processes the header of a PDU received with a valid CRC. `accept` is
+    /// whether a new PDU can be stored; if not, the PDU is not acknowledged so
+    /// the peer sends it again. Returns whether the PDU is new and accepted,
+    /// and whether it acknowledges the PDU sent last.
+    pub fn receive(&mut self, header: &DataHeader, accept: bool) -> (bool, bool) {
+        let new = header.sn == self.nesn && accept;
+        if new {
+            self.nesn = !self.nesn;
+        }
+        let acked = header.nesn != self.sn;
+        if acked {
+            self.sn = !self.sn;
+        }
+        (new, acked)
+    }
+}
+
+/// Link layer control PDU opcodes.
+pub mod control_opcode {
+    pub const CONNECTION_UPDATE_IND: u8 = 0x00;
+    pub const CHANNEL_MAP_IND: u8 = 0x01;
+    pub const TERMINATE_IND: u8 = 0x02;
+    pub const UNKNOWN_RSP: u8 = 0x07;
+    pub const FEATURE_REQ: u8 = 0x08;
+    pub const FEATURE_RSP: u8 = 0x09;
+    pub const VERSION_IND: u8 = 0x0c;
+    pub const PERIPHERAL_FEATURE_REQ: u8 = 0x0e;
+    pub const PING_REQ: u8 = 0x12;
+    pub const PING_RSP: u8 = 0x13;
+}
+
+/// Link layer control PDUs handled by the peripheral.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ControlPdu {
+    ConnectionUpdate {
+        win_size: u8,
+        win_offset: u16,
+        interval: u16,
+        latency: u16,
+        timeout: u16,
+        instant: u16,
+    },
+    ChannelMap {
+        map: ChannelMap,
+        instant: u16,
+    },
+    Terminate {
+        reason: u8,
+    },
+    FeatureReq {
+        features: u64,
+    },
+    FeatureRsp {
+        features: u64,
+    },
+    VersionInd {
+        version: u8,
+        company: u16,
+        subversion: u16,
+    },
+    PingReq,
+    PingRsp,
+    UnknownRsp {
+        opcode: u8,
+    },
+    /// A control PDU that is not supported, by its opcode.
+    Unsupported(u8),
+}
+
+impl ControlPdu {
+    /// Decodes the payload of a control PDU. Returns `None` if the payload
+    /// is too short for its opcode.
+    pub fn decode(payload: &[u8]) -> Option<ControlPdu> {
+        let (&opcode, p) = payload.split_first()?;
+        let u16_at = |i: usize| u16::from_le_bytes([p[i], p[i + 1]]);
+        let u64_at = |i: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&p[i..i + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let min_len = match opcode {
+            control_opcode::CONNECTION_UPDATE_IND => 11,
+            control_opcode::CHANNEL_MAP_IND => 7,
+            control_opcode::TERMINATE_IND | control_opcode::UNKNOWN_RSP => 1,
+            control_opcode::FEATURE_REQ
+            | control_opcode::FEATURE_RSP
+            | control_opcode::PERIPHERAL_FEATURE_REQ => 8,
+            control_opcode::VERSION_IND => 5,
+            _ => 0,
+        };
+        if p.len() < min_len {
+            return None;
+        }
+        let pdu = match opcode {
+            control_opcode::CONNECTION_UPDATE_IND => ControlPdu::ConnectionUpdate {
+                win_size: p[0],
+                win_offset: u16_at(1),
+                interval: u16_at(3),
+                latency: u16_at(5),
+                timeout: u16_at(7),
+                instant: u16_at(9),
+            },
+            control_opcode::CHANNEL_MAP_IND => {
+                let mut map = [0; 5];
+                map.copy_from_slice(&p[0..5]);
+                ControlPdu::ChannelMap {
+                    map: ChannelMap::new(map)?,
+                    instant: u16_at(5),
+                }
+            }
+            control_opcode::TERMINATE_IND => ControlPdu::Terminate { reason: p[0] },
+            control_opcode::FEATURE_REQ | control_opcode::PERIPHERAL_FEATURE_REQ => {
+                ControlPdu::FeatureReq {
+                    features: u64_at(0),
+                }
+            }
+            control_opcode::FEATURE_RSP => ControlPdu::FeatureRsp {
+                features: u64_at(0),
+            },
+            control_opcode::VERSION_IND => ControlPdu::VersionInd {
+                version: p[0],
+                company: u16_at(1),
+                subversion: u16_at(3),
+            },
+            control_opcode::PING_REQ => ControlPdu::PingReq,
+            control_opcode::PING_RSP => ControlPdu::PingRsp,
+            control_opcode::UNKNOWN_RSP => ControlPdu::UnknownRsp { opcode: p[0] },
+            _ => ControlPdu::Unsupported(opcode),
+        };
+        Some(pdu)
+    }
+
+    /// Encodes the PDUs sent by a peripheral into `buf`, which must hold
+    /// `MAX_DATA_PAYLOAD` bytes. Returns the payload length, or `None` for
+    /// PDUs that are only sent by a central.
+    pub fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        match *self {
+            ControlPdu::Terminate { reason } => {
+                buf[0] = control_opcode::TERMINATE_IND;
+                buf[1] = reason;
+                Some(2)
+            }
+            ControlPdu::FeatureRsp { features } => {
+                buf[0] = control_opcode::FEATURE_RSP;
+                buf[1..9].copy_from_slice(&features.to_le_bytes());
+                Some(9)
+            }
+            ControlPdu::VersionInd {
+                version,
+                company,
+                subversion,
+            } => {
+                buf[0] = control_opcode::VERSION_IND;
+                buf[1] = version;
+                buf[2..4].copy_from_slice(&company.to_le_bytes());
+                buf[4..6].copy_from_slice(&subversion.to_le_bytes());
+                Some(6)
+            }
+            ControlPdu::PingReq => {
+                buf[0] = control_opcode::PING_REQ;
+                Some(1)
+            }
+            ControlPdu::PingRsp => {
+                buf[0] = control_opcode::PING_RSP;
+                Some(1)
+            }
+            ControlPdu::UnknownRsp { opcode } => {
+                buf[0] = control_opcode::UNKNOWN_RSP;
+                buf[1] = opcode;
+                Some(2)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connect_ind() {
+        let mut pdu = [0u8; 36];
+        pdu[0] = adv_pdu_type::CONNECT_IND | ADV_TX_ADD;
+        pdu[1] = 34;
+        pdu[2..8].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+        pdu[8..14].copy_from_slice(&[0xf0, 1, 2, 3, 4, 0xf0]);
+        pdu[14..18].copy_from_slice(&0x5065_1234u32.to_le_bytes());
+        pdu[18..21].copy_from_slice(&[0x11, 0x22, 0x33]);
+        pdu[21] = 2;
+        pdu[22..24].copy_from_slice(&3u16.to_le_bytes());
+        pdu[24..26].copy_from_slice(&24u16.to_le_bytes());
+        pdu[26..28].copy_from_slice(&0u16.to_le_bytes());
+        pdu[28..30].copy_from_slice(&72u16.to_le_bytes());
+        pdu[30..35].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x1f]);
+        pdu[35] = 7 | (5 << 5);
+
+        let connect_ind = ConnectInd::decode(&pdu).unwrap();
+        assert_eq!(connect_ind.init_a, [1, 2, 3, 4, 5, 6]);
+        assert!(connect_ind.init_random);
+        assert!(!connect_ind.adv_random);
+        assert_eq!(connect_ind.access_address, 0x5065_1234);
+        assert_eq!(connect_ind.crc_init, 0x33_2211);
+        assert_eq!(connect_ind.win_size, 2);
+        assert_eq!(connect_ind.win_offset, 3);
+        assert_eq!(connect_ind.interval, 24);
+        assert_eq!(connect_ind.timeout, 72);
+        assert_eq!(connect_ind.channel_map.used_count(), 37);
+        assert_eq!(connect_ind.hop, 7);
+        assert_eq!(connect_ind.sca, 5);
+
+        // Hop increment out of range
+        pdu[35] = 4;
+        assert_eq!(ConnectInd::decode(&pdu), None);
+        // Truncated
+        assert_eq!(ConnectInd::decode(&pdu[..30]), None);
+    }
+
+    #[test]
+    fn channel_selection() {
+        let all = ChannelMap::new([0xff, 0xff, 0xff, 0xff, 0x1f]).unwrap();
+        assert_eq!(all.select(0, 5), (5, 5));
+        assert_eq!(all.select(35, 5), (3, 3));
+
+        // Only channels 1, 9 and 17 are used
+        let map = ChannelMap::new([0x02, 0x02, 0x02, 0x00, 0x00]).unwrap();
+        assert_eq!(map.used_count(), 3);
+        assert_eq!(map.select(0, 9), (9, 9));
+        // Unmapped channel 18 is not used: 18 % 3 = 0 remaps to channel 1
+        assert_eq!(map.select(9, 9), (18, 1));
+        // 27 % 3 = 0 -> 1, 36 % 3 = 0 -> 1, 8 % 3 = 2 -> 17
+        assert_eq!(map.select(27, 18), (8, 17));
+
+        assert_eq!(ChannelMap::new([0x01, 0, 0, 0, 0xe0]), None);
+    }
+
+    #[test]
+    fn acknowledgement() {
+        let mut ack = AckState::default();
+        let header = |sn, nesn| DataHeader {
+            llid: llid::START,
+            nesn: nesn,
+            sn: sn,
+            md: false,
+            length: 1,
+        };
+
+        // New PDU that does not acknowledge ours
+        assert_eq!(ack.receive(&header(false, false), true), (true, false));
+        assert_eq!(
+            ack,
+            AckState {
+                sn: false,
+                nesn: true
+            }
+        );
+        // Retransmission of the same PDU, now acknowledging ours
+        assert_eq!(ack.receive(&header(false, true), true), (false, true));
+        assert_eq!(
+            ack,
+            AckState {
+                sn: true,
+                nesn: true
+            }
+        );
+        // No room: the PDU is not acknowledged
+        assert_eq!(ack.receive(&header(true, true), false), (false, false));
+        assert_eq!(
+            ack,
+            AckState {
+                sn: true,
+                nesn: true
+            }
+        );
+    }
+
+    #[test]
+    fn control_pdus() {
+        let update = [
+            control_opcode::CONNECTION_UPDATE_IND,
+            1,
+            2,
+            0,
+            40,
+            0,
+            0,
+            0,
+            100,
+            0,
+            0x10,
+            0x00,
+        ];
+        assert_eq!(
+            ControlPdu::decode(&update),
+            Some(ControlPdu::ConnectionUpdate {
+                win_size: 1,
+                win_offset: 2,
+                interval: 40,
+                latency: 0,
+                timeout: 100,
+                instant: 16,
+            })
+        );
+        assert_eq!(ControlPdu::decode(&update[..6]), None);
+        assert_eq!(
+            ControlPdu::decode(&[0x20]),
+            Some(ControlPdu::Unsupported(0x20))
+        );
+
+        let mut buf = [0; MAX_DATA_PAYLOAD];
+        let version = ControlPdu::VersionInd {
+            version: 9,
+            company: 0xffff,
+            subversion: 1,
+        };
+        let len = version.encode(&mut buf).unwrap();
+        assert_eq!(ControlPdu::decode(&buf[..len]), Some(version));
+    }
+}
@@ -0,0 +1,6 @@
+pub mod advertising_data;
+pub mod att;
+pub mod gatt;
+pub mod l2cap;
+pub mod link_layer;
+pub mod peripheral;
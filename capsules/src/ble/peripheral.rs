@@ -0,0 +1,1746 @@
+//! Bluetooth Low Energy peripheral with a GATT server.
+//!
+//! A system call driver that lets one process act as a connectable BLE
+//! peripheral. The process declares the services and characteristics of a
+//! GATT server, starts connectable advertising, and once a central connects
+//! the driver takes part in the connection events, answers ATT requests from
+//! the attribute database and notifies the process when the central writes a
+//! characteristic. The process can update characteristic values at any time
+//! and send notifications or indications to the central.
+//!
+//! `Peripheral` implements the link layer and the GATT server, and
+//! `BlePeripheral` is the system call driver giving a process the use of it.
+//!
+//! The link layer follows the Bluetooth Core Specification v5.0, Vol 6,
+//! Part B for a peripheral on the LE 1M PHY: the connection parameters of
+//! the CONNECT_IND PDU, channel selection algorithm #1, acknowledgement and
+//! flow control with SN/NESN, window widening, the supervision timeout and
+//! connection update and channel map procedures at their instant. Only one
+//! data PDU is received per connection event; further PDUs are not
+//! acknowledged until it has been processed, so the central sends them
+//! again. Encryption, pairing and the data length extension are not
+//! supported. Connection events are timed with an `Alarm`, so the alarm
+//! should have a resolution of a few tens of microseconds or better.
+//!
+//! The radio must implement `BleLinkLayerDriver`, which turns the radio
+//! around at T_IFS to answer the central within a connection event. Only
+//! `nrf52::ble_radio` implements it. The apollo3 BLE controller runs its own
+//! link layer behind an HCI interface over SPI and gives no access to the
+//! radio timing, so it cannot implement `BleLinkLayerDriver`; a peripheral
+//! on the apollo3 would need an HCI host instead of this link layer.
+//!
+//! ### Attribute database
+//!
+//! The process describes its attributes in the buffer allowed with allow
+//! number 0 as a sequence of items:
+//!
+//! ```txt
+//! service:        1 | UUID length | UUID
+//! characteristic: 2 | UUID length | UUID | properties | max length | length | value
+//! ```
+//!
+//! UUIDs are 2 or 16 bytes long and little endian. Characteristics belong to
+//! the service declared before them. Handles are assigned in order starting
+//! at `FIRST_APP_HANDLE`: a service declaration uses one handle, a
+//! characteristic uses two (the declaration, then the value) and a third one
+//! for its client characteristic configuration descriptor if it supports
+//! notifications or indications. The GAP and GATT services of the device use
+//! the handles before `FIRST_APP_HANDLE`.
+//!
+//! ### Allow system call
+//!
+//! * 0: Attribute database description
+//! * 1: Advertising data
+//! * 2: Write buffer, which receives the value of characteristics written by
+//!      the central
+//! * 3: Value buffer, from which characteristic values are set
+//!
+//! ### Subscribe system call
+//!
+//! * 0: Connection events. The first callback argument is the event:
+//!      0 connected (the connection interval in units of 1.25 ms),
+//!      1 disconnected (the reason), 2 attribute written (handle, length)
+//!      and 3 indication confirmed.
+//!
+//! ### Command system call
+//!
+//! * 0: Check whether the driver is present
+//! * 1: Build the attribute database, returning the first process handle
+//! * 2: Start advertising with the interval in milliseconds given as argument
+//! * 3: Stop advertising
+//! * 4: Disconnect
+//! * 5: Set the value of attribute `arg1` to the first `arg2` bytes of the
+//!      value buffer
+//! * 6: Notify or indicate the value of characteristic `arg1`, depending on
+//!      the client characteristic configuration set by the central
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::virtual_alarm::VirtualMuxAlarm;
+//!
+//! let ble_alarm = static_init!(VirtualMuxAlarm<'static, Rtc>, VirtualMuxAlarm::new(mux_alarm));
+//! let peripheral = static_init!(
+//!     capsules::ble::peripheral::Peripheral<'static, nrf52::ble_radio::Radio,
+//!         VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::ble::peripheral::Peripheral::new(
+//!         &nrf52::ble_radio::RADIO,
+//!         ble_alarm,
+//!         address,
+//!         b"Tock",
+//!         &mut capsules::ble::peripheral::DATABASE,
+//!         &mut capsules::ble::peripheral::RX_BUF,
+//!         &mut capsules::ble::peripheral::TX_BUF));
+//! kernel::hil::ble_advertising::BleLinkLayerDriver::set_exchange_client(
+//!     &nrf52::ble_radio::RADIO, peripheral);
+//! ble_alarm.set_client(peripheral);
+//! let ble = static_init!(
+//!     capsules::ble::peripheral::BlePeripheral<'static, nrf52::ble_radio::Radio,
+//!         VirtualMuxAlarm<'static, Rtc>>,
+//!     capsules::ble::peripheral::BlePeripheral::new(
+//!         peripheral,
+//!         board_kernel.create_grant(&grant_cap)));
+//! peripheral.set_client(ble);
+//! ```
+
+use crate::ble::advertising_data::{self, ad_type, flags, AdvertisingDataBuilder};
+use crate::ble::gatt::{self, Database, ServerEvent, Uuid};
+use crate::ble::l2cap::{self, cid, Fragmenter, Reassembler};
+use crate::ble::link_layer::{
+    adv_pdu_type, encode_adv_pdu, llid, AckState, AdvHeader, ConnectInd, ControlPdu, DataHeader,
+    ADDRESS_LEN, MAX_ADV_DATA_LEN, MAX_ADV_PDU_LEN, MAX_DATA_PAYLOAD, MAX_DATA_PDU_LEN,
+    PDU_HEADER_LEN,
+};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::hil::ble_advertising::{BleConfig, BleLinkLayerDriver, ExchangeClient, RadioChannel};
+use kernel::hil::time::{Alarm, AlarmClient, Frequency};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::BlePeripheral as usize;
+
+/// ATT MTU supported by the GATT server.
+pub const ATT_MTU: usize = 64;
+pub const DATABASE_LEN: usize = 512;
+pub const L2CAP_BUF_LEN: usize = l2cap::HEADER_LEN + ATT_MTU;
+
+pub static mut DATABASE: [u8; DATABASE_LEN] = [0; DATABASE_LEN];
+pub static mut RX_BUF: [u8; L2CAP_BUF_LEN] = [0; L2CAP_BUF_LEN];
+pub static mut TX_BUF: [u8; L2CAP_BUF_LEN] = [0; L2CAP_BUF_LEN];
+
+/// Handle of the first attribute declared by the process. Handles 1 to 5
+/// are the GAP service with the device name and appearance
+/// characteristics, and handle 6 the GATT service.
+pub const FIRST_APP_HANDLE: u16 = 7;
+
+mod event {
+    pub const CONNECTED: usize = 0;
+    pub const DISCONNECTED: usize = 1;
+    pub const WRITTEN: usize = 2;
+    pub const INDICATION_CONFIRMED: usize = 3;
+}
+
+// Disconnection reasons (Vol 2, Part D, section 1.3)
+mod reason {
+    pub const CONNECTION_TIMEOUT: u8 = 0x08;
+    pub const REMOTE_USER_TERMINATED: u8 = 0x13;
+    pub const LOCAL_HOST_TERMINATED: u8 = 0x16;
+    pub const FAILED_TO_ESTABLISH: u8 = 0x3e;
+}
+
+// Items of the attribute database description
+const ITEM_SERVICE: u8 = 1;
+const ITEM_CHARACTERISTIC: u8 = 2;
+
+// LE Ping is the only supported feature
+const FEATURES: u64 = 1 << 4;
+// Bluetooth 5.0, no company identifier assigned
+const VERSION: u8 = 9;
+const COMPANY_ID: u16 = 0xffff;
+
+// Connection timing unit (Vol 6, Part B, section 4.5.1)
+const UNIT_US: u32 = 1250;
+const T_IFS_US: u32 = 150;
+// How long to listen for a request after each advertising PDU
+const ADV_LISTEN_US: u32 = 1500;
+// How early to start listening before the expected packet, to cover the
+// radio ramp up and the resolution of the alarm
+const RX_MARGIN_US: u32 = 500;
+// Sleep clock accuracy of this device
+const SCA_PPM: u32 = 50;
+// Sleep clock accuracies of the central, by SCA field value
+const CENTRAL_SCA_PPM: [u32; 8] = [500, 250, 150, 100, 75, 50, 30, 20];
+// A connection must be established within 6 connection events
+const ESTABLISH_EVENTS: u16 = 6;
+
+// Air time of a PDU of `len` bytes: preamble, access address, PDU and CRC
+fn airtime_us(len: usize) -> u32 {
+    (1 + 4 + len as u32 + 3) * 8
+}
+
+// Converts microseconds to alarm ticks
+fn ticks<F: Frequency>(us: u32) -> u32 {
+    (us as u64 * F::frequency() as u64 / 1_000_000) as u32
+}
+
+// Window widening for the next event (Vol 6, Part B, section 4.5.7)
+fn widening<F: Frequency>(connection: &Connection) -> u32 {
+    let ppm = CENTRAL_SCA_PPM[connection.params.sca as usize & 0x7] + SCA_PPM;
+    let elapsed = connection.next_anchor.wrapping_sub(connection.last_anchor);
+    (elapsed as u64 * ppm as u64 / 1_000_000) as u32 + ticks::<F>(16)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Waiting for the next advertising event
+    AdvertisingIdle,
+    /// Advertising on a channel and listening for requests
+    Advertising(RadioChannel),
+    /// Connected, waiting for the next connection event
+    ConnectionIdle,
+    /// In a connection event
+    ConnectionEvent,
+}
+
+#[derive(Copy, Clone)]
+struct Connection {
+    params: ConnectInd,
+    event_counter: u16,
+    unmapped_channel: u8,
+    channel: u8,
+    /// Expected anchor point of the next event, or the start of the
+    /// transmit window after a CONNECT_IND or connection update, in ticks
+    next_anchor: u32,
+    /// Length of the transmit window in ticks, 0 once the anchor is known
+    window: u32,
+    /// Last anchor point observed, from which the window widening grows
+    last_anchor: u32,
+    /// Time of the last packet received, for the supervision timeout
+    last_rx: u32,
+    /// Whether a packet was received in the current event
+    anchor_found: bool,
+    established: bool,
+    /// Connection update or channel map update waiting for its instant
+    update: Option<ControlPdu>,
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    database: Option<AppSlice<Shared, u8>>,
+    adv_data: Option<AppSlice<Shared, u8>>,
+    write_buffer: Option<AppSlice<Shared, u8>>,
+    value_buffer: Option<AppSlice<Shared, u8>>,
+}
+
+/// Events of the peripheral reported to its client.
+pub trait PeripheralClient {
+    /// A central has connected with the connection interval in units of
+    /// 1.25 ms.
+    fn connected(&self, interval: u16);
+    /// The connection has ended for `reason`.
+    fn disconnected(&self, reason: u8);
+    /// The central has written `len` bytes to attribute `handle`, whose
+    /// value is now `value`.
+    fn written(&self, handle: u16, value: &[u8], len: usize);
+    /// The central has confirmed the last indication.
+    fn indication_confirmed(&self);
+}
+
+/// Link layer and GATT server of a connectable peripheral.
+pub struct Peripheral<'a, B, A>
+where
+    B: BleLinkLayerDriver<'a> + BleConfig,
+    A: Alarm<'a>,
+{
+    radio: &'a B,
+    alarm: &'a A,
+    client: OptionalCell<&'a dyn PeripheralClient>,
+    address: [u8; ADDRESS_LEN],
+    random_address: bool,
+    device_name: &'static [u8],
+    state: Cell<State>,
+    advertising: Cell<bool>,
+    adv_interval_ms: Cell<u32>,
+    adv_pdu: Cell<[u8; MAX_ADV_PDU_LEN]>,
+    adv_len: Cell<usize>,
+    random_nonce: Cell<u32>,
+    /// CONNECT_IND received while advertising, with its time
+    connect_ind: Cell<Option<(ConnectInd, u32)>>,
+    connection: Cell<Option<Connection>>,
+    disconnect_reason: Cell<Option<u8>>,
+    ack: Cell<AckState>,
+    /// Data PDU being sent until it is acknowledged
+    tx_pdu: Cell<[u8; MAX_DATA_PDU_LEN]>,
+    tx_pending: Cell<bool>,
+    tx_terminate: Cell<bool>,
+    /// Data PDU received and not processed yet
+    rx_pdu: Cell<[u8; MAX_DATA_PDU_LEN]>,
+    rx_pending: Cell<bool>,
+    control: Cell<Option<ControlPdu>>,
+    version_sent: Cell<bool>,
+    reassembler: Cell<Reassembler>,
+    /// Length of the complete L2CAP PDU in `rx_buf`, 0 if none
+    rx_sdu_len: Cell<usize>,
+    fragmenter: Cell<Fragmenter>,
+    mtu: Cell<usize>,
+    indication_pending: Cell<bool>,
+    database: MapCell<Database<'static>>,
+    rx_buf: TakeCell<'static, [u8]>,
+    tx_buf: TakeCell<'static, [u8]>,
+}
+
+impl<'a, B, A> Peripheral<'a, B, A>
+where
+    B: BleLinkLayerDriver<'a> + BleConfig,
+    A: Alarm<'a>,
+{
+    /// `address` is the static random device address used to advertise,
+    /// in the byte order sent over the air.
+    pub fn new(
+        radio: &'a B,
+        alarm: &'a A,
+        address: [u8; ADDRESS_LEN],
+        device_name: &'static [u8],
+        database: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        tx_buf: &'static mut [u8],
+    ) -> Peripheral<'a, B, A> {
+        Peripheral {
+            radio: radio,
+            alarm: alarm,
+            client: OptionalCell::empty(),
+            address: address,
+            random_address: true,
+            device_name: device_name,
+            state: Cell::new(State::Idle),
+            advertising: Cell::new(false),
+            adv_interval_ms: Cell::new(100),
+            adv_pdu: Cell::new([0; MAX_ADV_PDU_LEN]),
+            adv_len: Cell::new(0),
+            random_nonce: Cell::new(0xdeadbeef),
+            connect_ind: Cell::new(None),
+            connection: Cell::new(None),
+            disconnect_reason: Cell::new(None),
+            ack: Cell::new(AckState::default()),
+            tx_pdu: Cell::new([0; MAX_DATA_PDU_LEN]),
+            tx_pending: Cell::new(false),
+            tx_terminate: Cell::new(false),
+            rx_pdu: Cell::new([0; MAX_DATA_PDU_LEN]),
+            rx_pending: Cell::new(false),
+            control: Cell::new(None),
+            version_sent: Cell::new(false),
+            reassembler: Cell::new(Reassembler::default()),
+            rx_sdu_len: Cell::new(0),
+            fragmenter: Cell::new(Fragmenter::default()),
+            mtu: Cell::new(crate::ble::att::DEFAULT_MTU),
+            indication_pending: Cell::new(false),
+            database: MapCell::new(Database::new(database)),
+            rx_buf: TakeCell::new(rx_buf),
+            tx_buf: TakeCell::new(tx_buf),
+        }
+    }
+
+    // Xorshift, as in the advertising driver, for the advDelay
+    fn random_nonce(&self) -> u32 {
+        let mut nonce = self.random_nonce.get();
+        nonce ^= nonce << 13;
+        nonce ^= nonce >> 17;
+        nonce ^= nonce << 5;
+        self.random_nonce.set(nonce);
+        nonce
+    }
+
+    pub fn set_client(&self, client: &'a dyn PeripheralClient) {
+        self.client.set(client);
+    }
+
+    /// Returns whether the peripheral is neither advertising nor connected.
+    pub fn is_idle(&self) -> bool {
+        self.state.get() == State::Idle
+    }
+
+    /// Builds the attribute database from its description, returning the
+    /// handle of the first attribute of the description.
+    pub fn build_database(&self, description: &[u8]) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let name = self.device_name;
+        self.database.map_or(ReturnCode::FAIL, |db| {
+            db.clear();
+            let result = db
+                .add_service(Uuid::Uuid16(gatt::uuid::GAP_SERVICE))
+                .and_then(|_| {
+                    db.add_characteristic(
+                        Uuid::Uuid16(gatt::uuid::DEVICE_NAME),
+                        gatt::properties::READ,
+                        name.len(),
+                        name,
+                    )
+                })
+                .and_then(|_| {
+                    db.add_characteristic(
+                        Uuid::Uuid16(gatt::uuid::APPEARANCE),
+                        gatt::properties::READ,
+                        2,
+                        &[0, 0],
+                    )
+                })
+                .and_then(|_| db.add_service(Uuid::Uuid16(gatt::uuid::GATT_SERVICE)))
+                .and_then(|_| add_declarations(db, description));
+            match result {
+                Ok(()) => ReturnCode::SuccessWithValue {
+                    value: FIRST_APP_HANDLE as usize,
+                },
+                Err(err) => {
+                    db.clear();
+                    err
+                }
+            }
+        })
+    }
+
+    /// Starts connectable advertising of `data` every `interval_ms`.
+    pub fn start_advertising(&self, data: &[u8], interval_ms: usize) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        // Connectable advertisements are discoverable, so add the flags if the
+        // process did not
+        let mut adv_data = [0; MAX_ADV_DATA_LEN];
+        let mut builder = AdvertisingDataBuilder::new(&mut adv_data);
+        if advertising_data::find(data, ad_type::FLAGS).is_none() {
+            builder.add_flags(flags::LE_GENERAL_DISCOVERABLE | flags::BR_EDR_NOT_SUPPORTED);
+        }
+        if builder.append(data) != ReturnCode::SUCCESS {
+            return ReturnCode::ESIZE;
+        }
+        let adv_data_len = builder.len();
+        let mut pdu = [0; MAX_ADV_PDU_LEN];
+        match encode_adv_pdu(
+            &mut pdu,
+            adv_pdu_type::ADV_IND,
+            &self.address,
+            self.random_address,
+            &adv_data[..adv_data_len],
+        ) {
+            Some(len) => self.adv_len.set(len),
+            None => return ReturnCode::ESIZE,
+        }
+        self.adv_pdu.set(pdu);
+        self.adv_interval_ms.set(cmp::max(20, interval_ms as u32));
+        self.random_nonce.set(self.alarm.now() | 1);
+        self.advertising.set(true);
+        self.schedule_advertising(0);
+        ReturnCode::SUCCESS
+    }
+
+    // Schedules the next advertising event `interval_ms` from now, plus the
+    // pseudo random advDelay of 0 to 10 ms (Vol 6, Part B, section 4.4.2.2).
+    fn schedule_advertising(&self, interval_ms: u32) {
+        self.state.set(State::AdvertisingIdle);
+        let delay_us = interval_ms * 1000 + self.random_nonce() % 10_000;
+        self.alarm.set_alarm(
+            self.alarm
+                .now()
+                .wrapping_add(ticks::<A::Frequency>(delay_us)),
+        );
+    }
+
+    fn advertise(&self, channel: RadioChannel) {
+        self.state.set(State::Advertising(channel));
+        let pdu = self.adv_pdu.get();
+        let now = self.alarm.now();
+        if self
+            .radio
+            .transmit_and_listen(&pdu[..self.adv_len.get()], channel)
+            == ReturnCode::SUCCESS
+        {
+            self.alarm
+                .set_alarm(now.wrapping_add(ticks::<A::Frequency>(ADV_LISTEN_US)));
+        } else {
+            // The radio is in use, try again at the next advertising event
+            self.schedule_advertising(self.adv_interval_ms.get());
+        }
+    }
+
+    fn advertising_done(&self, channel: RadioChannel) {
+        if !self.advertising.get() {
+            self.state.set(State::Idle);
+            return;
+        }
+        if let Some((connect_ind, time)) = self.connect_ind.take() {
+            self.advertising.set(false);
+            self.connect(connect_ind, time);
+            return;
+        }
+        match channel {
+            RadioChannel::AdvertisingChannel37 => {
+                self.advertise(RadioChannel::AdvertisingChannel38)
+            }
+            RadioChannel::AdvertisingChannel38 => {
+                self.advertise(RadioChannel::AdvertisingChannel39)
+            }
+            _ => self.schedule_advertising(self.adv_interval_ms.get()),
+        }
+    }
+
+    // Handles a packet received after an advertising PDU: answers scan
+    // requests and accepts connection requests addressed to this device.
+    fn advertising_packet(&self, pdu: &[u8], crc_ok: bool, response: &mut [u8]) -> usize {
+        let header = match AdvHeader::decode(pdu) {
+            Some(header) if crc_ok => header,
+            _ => return 0,
+        };
+        let addressed = |adv_a: &[u8], random: bool| {
+            adv_a == &self.address[..] && random == self.random_address
+        };
+        match header.pdu_type {
+            adv_pdu_type::SCAN_REQ
+                if header.length as usize == 2 * ADDRESS_LEN
+                    && addressed(&pdu[8..14], header.rx_add) =>
+            {
+                encode_adv_pdu(
+                    response,
+                    adv_pdu_type::SCAN_RSP,
+                    &self.address,
+                    self.random_address,
+                    &[],
+                )
+                .unwrap_or(0)
+            }
+            adv_pdu_type::CONNECT_IND => {
+                if let Some(connect_ind) = ConnectInd::decode(pdu) {
+                    if addressed(&connect_ind.adv_a, connect_ind.adv_random) {
+                        self.connect_ind.set(Some((connect_ind, self.alarm.now())));
+                    }
+                }
+                0
+            }
+            _ => 0,
+        }
+    }
+
+    // Enters the connection state after a CONNECT_IND that ended at `time`
+    // (Vol 6, Part B, section 4.5.3).
+    fn connect(&self, params: ConnectInd, time: u32) {
+        self.radio
+            .set_access_address(params.access_address, params.crc_init);
+        self.ack.set(AckState::default());
+        self.tx_pending.set(false);
+        self.tx_terminate.set(false);
+        self.rx_pending.set(false);
+        self.control.set(None);
+        self.version_sent.set(false);
+        self.reassembler.set(Reassembler::default());
+        self.rx_sdu_len.set(0);
+        self.fragmenter.set(Fragmenter::default());
+        self.mtu.set(crate::ble::att::DEFAULT_MTU);
+        self.indication_pending.set(false);
+        self.disconnect_reason.set(None);
+
+        let (unmapped, channel) = params.channel_map.select(0, params.hop);
+        let window_start = time.wrapping_add(ticks::<A::Frequency>(
+            UNIT_US + params.win_offset as u32 * UNIT_US,
+        ));
+        self.connection.set(Some(Connection {
+            params: params,
+            event_counter: 0,
+            unmapped_channel: unmapped,
+            channel: channel,
+            next_anchor: window_start,
+            window: ticks::<A::Frequency>(params.win_size as u32 * UNIT_US),
+            last_anchor: time,
+            last_rx: time,
+            anchor_found: false,
+            established: false,
+            update: None,
+        }));
+        self.schedule_event();
+    }
+
+    fn schedule_event(&self) {
+        if let Some(connection) = self.connection.get() {
+            self.state.set(State::ConnectionIdle);
+            let early = widening::<A::Frequency>(&connection) + ticks::<A::Frequency>(RX_MARGIN_US);
+            self.alarm
+                .set_alarm(connection.next_anchor.wrapping_sub(early));
+        }
+    }
+
+    fn start_event(&self) {
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return,
+        };
+        connection.anchor_found = false;
+        self.connection.set(Some(connection));
+        self.state.set(State::ConnectionEvent);
+
+        let listening = RadioChannel::from_channel_index(connection.channel as u32)
+            .map_or(ReturnCode::FAIL, |channel| self.radio.listen(channel));
+        if listening == ReturnCode::SUCCESS {
+            let late = connection.window
+                + widening::<A::Frequency>(&connection)
+                + ticks::<A::Frequency>(RX_MARGIN_US);
+            self.alarm
+                .set_alarm(connection.next_anchor.wrapping_add(late));
+        } else {
+            self.connection_event_done();
+        }
+    }
+
+    // Handles a data channel PDU received in a connection event and writes
+    // the PDU to send in response.
+    fn connection_packet(&self, pdu: &[u8], crc_ok: bool, response: &mut [u8]) -> usize {
+        let now = self.alarm.now();
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return 0,
+        };
+        // After a CRC error the central sends the packet again in the next
+        // connection event
+        let header = match DataHeader::decode(pdu) {
+            Some(header) if crc_ok && header.length as usize <= MAX_DATA_PAYLOAD => header,
+            _ => return 0,
+        };
+        if !connection.anchor_found {
+            connection.anchor_found = true;
+            connection.last_anchor = now.wrapping_sub(ticks::<A::Frequency>(airtime_us(pdu.len())));
+        }
+        connection.last_rx = now;
+        self.connection.set(Some(connection));
+
+        // Empty PDUs are always accepted, other PDUs only if the previous
+        // one has been processed
+        let accept = header.length == 0 || !self.rx_pending.get();
+        let mut ack = self.ack.get();
+        let (new, acked) = ack.receive(&header, accept);
+        self.ack.set(ack);
+        if new && header.length > 0 {
+            let mut rx = [0; MAX_DATA_PDU_LEN];
+            rx[..pdu.len()].copy_from_slice(pdu);
+            self.rx_pdu.set(rx);
+            self.rx_pending.set(true);
+        }
+        if acked && self.tx_pending.get() {
+            self.tx_pending.set(false);
+            if self.tx_terminate.get() {
+                self.disconnect_reason
+                    .set(Some(reason::LOCAL_HOST_TERMINATED));
+                return 0;
+            }
+        }
+
+        // Stop answering before the next connection event
+        let interval = ticks::<A::Frequency>(connection.params.interval as u32 * UNIT_US);
+        if now.wrapping_sub(connection.last_anchor) + ticks::<A::Frequency>(2 * RX_MARGIN_US)
+            > interval
+        {
+            return 0;
+        }
+
+        if !self.tx_pending.get() {
+            self.next_tx_pdu();
+        }
+        let mut tx = self.tx_pdu.get();
+        let len = PDU_HEADER_LEN + tx[1] as usize;
+        DataHeader {
+            llid: tx[0] & 0x3,
+            nesn: ack.nesn,
+            sn: ack.sn,
+            md: self.control.get().is_some() || !self.fragmenter.get().is_done(),
+            length: tx[1],
+        }
+        .encode(&mut tx);
+        response[..len].copy_from_slice(&tx[..len]);
+
+        // Close the connection event if the central has nothing more to send
+        // after the response
+        let close_us = T_IFS_US + airtime_us(len) + T_IFS_US + RX_MARGIN_US;
+        self.alarm
+            .set_alarm(now.wrapping_add(ticks::<A::Frequency>(close_us)));
+        len
+    }
+
+    // Chooses the next data PDU to send: a control PDU, the next fragment of
+    // an L2CAP PDU or an empty PDU.
+    fn next_tx_pdu(&self) {
+        let mut tx = [0; MAX_DATA_PDU_LEN];
+        let mut pdu_llid = llid::CONTINUATION;
+        let mut len = 0;
+        self.tx_terminate.set(false);
+        if let Some(control) = self.control.take() {
+            pdu_llid = llid::CONTROL;
+            len = control.encode(&mut tx[PDU_HEADER_LEN..]).unwrap_or(0);
+            if let ControlPdu::Terminate { .. } = control {
+                self.tx_terminate.set(true);
+            }
+        } else {
+            let mut fragmenter = self.fragmenter.get();
+            let fragment = self.tx_buf.map_or(None, |buf| {
+                fragmenter.next(buf, &mut tx[PDU_HEADER_LEN..], MAX_DATA_PAYLOAD)
+            });
+            self.fragmenter.set(fragmenter);
+            if let Some((fragment_llid, fragment_len)) = fragment {
+                pdu_llid = fragment_llid;
+                len = fragment_len;
+            }
+        }
+        DataHeader {
+            llid: pdu_llid,
+            nesn: false,
+            sn: false,
+            md: false,
+            length: len as u8,
+        }
+        .encode(&mut tx);
+        self.tx_pdu.set(tx);
+        self.tx_pending.set(true);
+    }
+
+    fn connection_event_done(&self) {
+        self.process_sdu();
+        self.process_rx();
+
+        let mut connection = match self.connection.get() {
+            Some(connection) => connection,
+            None => return,
+        };
+        if let Some(reason) = self.disconnect_reason.take() {
+            self.disconnect(reason);
+            return;
+        }
+        let now = self.alarm.now();
+        let params = connection.params;
+        if connection.anchor_found {
+            connection.next_anchor = connection.last_anchor;
+            connection.window = 0;
+            if !connection.established {
+                connection.established = true;
+                self.client.map(|client| client.connected(params.interval));
+            }
+        } else if connection.established {
+            let timeout = ticks::<A::Frequency>(params.timeout as u32 * 10_000);
+            if now.wrapping_sub(connection.last_rx) > timeout {
+                self.disconnect(reason::CONNECTION_TIMEOUT);
+                return;
+            }
+        } else if connection.event_counter + 1 >= ESTABLISH_EVENTS {
+            self.disconnect(reason::FAILED_TO_ESTABLISH);
+            return;
+        }
+
+        connection.event_counter = connection.event_counter.wrapping_add(1);
+        connection.next_anchor = connection
+            .next_anchor
+            .wrapping_add(ticks::<A::Frequency>(params.interval as u32 * UNIT_US));
+        if let Some(update) = connection.update {
+            match update {
+                ControlPdu::ChannelMap { map, instant } if instant == connection.event_counter => {
+                    connection.params.channel_map = map;
+                    connection.update = None;
+                }
+                ControlPdu::ConnectionUpdate {
+                    win_size,
+                    win_offset,
+                    interval,
+                    latency,
+                    timeout,
+                    instant,
+                } if instant == connection.event_counter => {
+                    // The transmit window starts win_offset after the anchor
+                    // the instant would have had with the old interval
+                    connection.next_anchor =
+                        connection
+                            .next_anchor
+                            .wrapping_add(ticks::<A::Frequency>(win_offset as u32 * UNIT_US));
+                    connection.window = ticks::<A::Frequency>(win_size as u32 * UNIT_US);
+                    connection.params.interval = interval;
+                    connection.params.latency = latency;
+                    connection.params.timeout = timeout;
+                    connection.update = None;
+                }
+                _ => {}
+            }
+        }
+        let (unmapped, channel) = connection
+            .params
+            .channel_map
+            .select(connection.unmapped_channel, connection.params.hop);
+        connection.unmapped_channel = unmapped;
+        connection.channel = channel;
+        self.connection.set(Some(connection));
+        self.schedule_event();
+    }
+
+    // Processes the data PDU received in the last connection event, unless
+    // it has to wait for a previous response to be sent.
+    fn process_rx(&self) {
+        if !self.rx_pending.get() {
+            return;
+        }
+        let pdu = self.rx_pdu.get();
+        let header = match DataHeader::decode(&pdu) {
+            Some(header) => header,
+            None => {
+                self.rx_pending.set(false);
+                return;
+            }
+        };
+        let payload = &pdu[PDU_HEADER_LEN..PDU_HEADER_LEN + header.length as usize];
+        if header.llid == llid::CONTROL {
+            if self.control.get().is_some() {
+                return;
+            }
+            self.handle_control(payload);
+        } else {
+            if self.rx_sdu_len.get() > 0 {
+                return;
+            }
+            let mut reassembler = self.reassembler.get();
+            let result = self
+                .rx_buf
+                .map(|buf| reassembler.push(buf, header.llid, payload));
+            self.reassembler.set(reassembler);
+            if let Some(Ok(Some(len))) = result {
+                self.rx_sdu_len.set(len);
+                self.process_sdu();
+            }
+        }
+        self.rx_pending.set(false);
+    }
+
+    fn handle_control(&self, payload: &[u8]) {
+        let response = match ControlPdu::decode(payload) {
+            Some(update @ ControlPdu::ConnectionUpdate { .. })
+            | Some(update @ ControlPdu::ChannelMap { .. }) => {
+                self.connection
+                    .set(self.connection.get().map(|mut connection| {
+                        connection.update = Some(update);
+                        connection
+                    }));
+                None
+            }
+            Some(ControlPdu::Terminate { reason }) => {
+                self.disconnect_reason.set(Some(reason));
+                None
+            }
+            Some(ControlPdu::FeatureReq { .. }) => {
+                Some(ControlPdu::FeatureRsp { features: FEATURES })
+            }
+            Some(ControlPdu::VersionInd { .. }) if !self.version_sent.get() => {
+                self.version_sent.set(true);
+                Some(ControlPdu::VersionInd {
+                    version: VERSION,
+                    company: COMPANY_ID,
+                    subversion: 0,
+                })
+            }
+            Some(ControlPdu::PingReq) => Some(ControlPdu::PingRsp),
+            Some(ControlPdu::Unsupported(opcode)) => {
+                Some(ControlPdu::UnknownRsp { opcode: opcode })
+            }
+            _ => None,
+        };
+        if response.is_some() {
+            self.control.set(response);
+        }
+    }
+
+    // Answers the L2CAP PDU received, once the previous response has been
+    // sent.
+    fn process_sdu(&self) {
+        let len = self.rx_sdu_len.get();
+        if len == 0 || !self.fragmenter.get().is_done() {
+            return;
+        }
+        self.rx_sdu_len.set(0);
+
+        let mut server_event = None;
+        self.rx_buf.map(|rx| {
+            self.tx_buf.map(|tx| {
+                let channel = match l2cap::decode_header(&rx[..len]) {
+                    Some((_, channel)) => channel,
+                    None => return,
+                };
+                let payload = &rx[l2cap::HEADER_LEN..len];
+                let rsp = &mut tx[l2cap::HEADER_LEN..];
+                let rsp_len = match channel {
+                    cid::ATT => self.database.map_or(0, |db| {
+                        let (rsp_len, event) =
+                            gatt::handle_pdu(db, self.mtu.get(), ATT_MTU, payload, rsp);
+                        server_event = event;
+                        rsp_len
+                    }),
+                    // No signaling commands are supported
+                    cid::LE_SIGNALING
+                        if payload.len() >= 2 && payload[0] != l2cap::signaling::COMMAND_REJECT =>
+                    {
+                        rsp[0] = l2cap::signaling::COMMAND_REJECT;
+                        rsp[1] = payload[1];
+                        rsp[2..4].copy_from_slice(&2u16.to_le_bytes());
+                        rsp[4..6].copy_from_slice(
+                            &l2cap::signaling::REASON_NOT_UNDERSTOOD.to_le_bytes(),
+                        );
+                        6
+                    }
+                    cid::SMP if payload.first() == Some(&l2cap::smp::PAIRING_REQUEST) => {
+                        rsp[0] = l2cap::smp::PAIRING_FAILED;
+                        rsp[1] = l2cap::smp::REASON_PAIRING_NOT_SUPPORTED;
+                        2
+                    }
+                    _ => 0,
+                };
+                if rsp_len > 0 {
+                    l2cap::encode_header(tx, rsp_len, channel);
+                    self.fragmenter
+                        .set(Fragmenter::new(l2cap::HEADER_LEN + rsp_len));
+                }
+            });
+        });
+
+        match server_event {
+            Some(ServerEvent::MtuChanged(mtu)) => self.mtu.set(mtu),
+            Some(ServerEvent::Written { handle, len }) => self.written(handle, len),
+            Some(ServerEvent::Confirmed) => {
+                if self.indication_pending.get() {
+                    self.indication_pending.set(false);
+                    self.client.map(|client| client.indication_confirmed());
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn written(&self, handle: u16, len: usize) {
+        self.database.map(|db| {
+            if let Some(attribute) = db.get(handle) {
+                self.client
+                    .map(|client| client.written(handle, attribute.value, len));
+            }
+        });
+    }
+
+    fn disconnect(&self, reason: u8) {
+        self.connection.set(None);
+        self.state.set(State::Idle);
+        self.alarm.disable();
+        self.database.map(|db| db.reset_client_configurations());
+        self.client.map(|client| client.disconnected(reason));
+    }
+
+    /// Stops advertising, at the end of the advertising event if one is in
+    /// progress.
+    pub fn stop_advertising(&self) -> ReturnCode {
+        match self.state.get() {
+            State::AdvertisingIdle => {
+                self.advertising.set(false);
+                self.state.set(State::Idle);
+                self.alarm.disable();
+                ReturnCode::SUCCESS
+            }
+            State::Advertising(_) => {
+                self.advertising.set(false);
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::EALREADY,
+        }
+    }
+
+    /// Terminates the connection.
+    pub fn terminate(&self) -> ReturnCode {
+        let established = self
+            .connection
+            .get()
+            .map_or(false, |connection| connection.established);
+        if !established {
+            ReturnCode::EOFF
+        } else if self.control.get().is_some() {
+            ReturnCode::EBUSY
+        } else {
+            self.control.set(Some(ControlPdu::Terminate {
+                reason: reason::REMOTE_USER_TERMINATED,
+            }));
+            ReturnCode::SUCCESS
+        }
+    }
+
+    pub fn set_value(&self, handle: u16, value: &[u8]) -> ReturnCode {
+        self.database
+            .map_or(ReturnCode::FAIL, |db| db.set_value(handle, value))
+    }
+
+    /// Notifies or indicates the value of characteristic `handle`,
+    /// depending on its client characteristic configuration.
+    pub fn notify(&self, handle: u16) -> ReturnCode {
+        if self.state.get() != State::ConnectionIdle && self.state.get() != State::ConnectionEvent {
+            return ReturnCode::EOFF;
+        }
+        if !self.fragmenter.get().is_done() {
+            return ReturnCode::EBUSY;
+        }
+        let configuration = self
+            .database
+            .map_or(0, |db| db.client_configuration(handle));
+        let indicate = if configuration & gatt::CCCD_NOTIFY != 0 {
+            false
+        } else if configuration & gatt::CCCD_INDICATE != 0 {
+            if self.indication_pending.get() {
+                return ReturnCode::EBUSY;
+            }
+            true
+        } else {
+            // The central has not enabled notifications or indications
+            return ReturnCode::EINVAL;
+        };
+        let mtu = self.mtu.get();
+        let len = self.tx_buf.map_or(None, |tx| {
+            let len = self.database.map_or(None, |db| {
+                gatt::encode_notification(db, handle, indicate, mtu, &mut tx[l2cap::HEADER_LEN..])
+            })?;
+            l2cap::encode_header(tx, len, cid::ATT);
+            Some(len)
+        });
+        match len {
+            Some(len) => {
+                self.fragmenter
+                    .set(Fragmenter::new(l2cap::HEADER_LEN + len));
+                self.indication_pending.set(indicate);
+                ReturnCode::SUCCESS
+            }
+            None => ReturnCode::EINVAL,
+        }
+    }
+}
+
+// Adds the services and characteristics of an attribute database
+// description to `db`.
+fn add_declarations(db: &mut Database, description: &[u8]) -> Result<(), ReturnCode> {
+    let mut rest = description;
+    while let Some((&kind, items)) = rest.split_first() {
+        let uuid_len = *items.first().ok_or(ReturnCode::EINVAL)? as usize;
+        let uuid = items
+            .get(1..1 + uuid_len)
+            .and_then(Uuid::decode)
+            .ok_or(ReturnCode::EINVAL)?;
+        let fields = &items[1 + uuid_len..];
+        rest = match kind {
+            ITEM_SERVICE => {
+                db.add_service(uuid)?;
+                fields
+            }
+            ITEM_CHARACTERISTIC => {
+                if fields.len() < 3 || fields.len() < 3 + fields[2] as usize {
+                    return Err(ReturnCode::EINVAL);
+                }
+                let value_len = fields[2] as usize;
+                db.add_characteristic(
+                    uuid,
+                    fields[0],
+                    fields[1] as usize,
+                    &fields[3..3 + value_len],
+                )?;
+                &fields[3 + value_len..]
+            }
+            _ => return Err(ReturnCode::EINVAL),
+        };
+    }
+    Ok(())
+}
+
+impl<'a, B, A> AlarmClient for Peripheral<'a, B, A>
+where
+    B: BleLinkLayerDriver<'a> + BleConfig,
+    A: Alarm<'a>,
+{
+    fn fired(&self) {
+        match self.state.get() {
+            State::AdvertisingIdle => self.advertise(RadioChannel::AdvertisingChannel37),
+            // The listen window or the connection event is over
+            State::Advertising(_) | State::ConnectionEvent => self.radio.stop_exchange(),
+            State::ConnectionIdle => self.start_event(),
+            State::Idle => {}
+        }
+    }
+}
+
+impl<'a, B, A> ExchangeClient for Peripheral<'a, B, A>
+where
+    B: BleLinkLayerDriver<'a> + BleConfig,
+    A: Alarm<'a>,
+{
+    fn packet_received(&self, pdu: &[u8], crc_ok: bool, response: &mut [u8]) -> usize {
+        match self.state.get() {
+            State::Advertising(_) => self.advertising_packet(pdu, crc_ok, response),
+            State::ConnectionEvent => self.connection_packet(pdu, crc_ok, response),
+            _ => 0,
+        }
+    }
+
+    fn exchange_done(&self, _result: ReturnCode) {
+        match self.state.get() {
+            State::Advertising(channel) => self.advertising_done(channel),
+            State::ConnectionEvent => self.connection_event_done(),
+            _ => {}
+        }
+    }
+}
+
+/// System call driver giving one process the use of a `Peripheral`.
+pub struct BlePeripheral<'a, B, A>
+where
+    B: BleLinkLayerDriver<'a> + BleConfig,
+    A: Alarm<'a>,
+{
+    peripheral: &'a Peripheral<'a, B, A>,
+    apps: Grant<App>,
+    owner: OptionalCell<AppId>,
+}
+
+impl<'a, B, A> BlePeripheral<'a, B, A>
+where
+    B: BleLinkLayerDriver<'a> + BleConfig,
+    A: Alarm<'a>,
+{
+    pub fn new(peripheral: &'a Peripheral<'a, B, A>, grant: Grant<App>) -> BlePeripheral<'a, B, A> {
+        BlePeripheral {
+            peripheral: peripheral,
+            apps: grant,
+            owner: OptionalCell::empty(),
+        }
+    }
+
+    fn upcall(&self, event: usize, arg1: usize, arg2: usize) {
+        self.owner.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.callback.map(|mut cb| cb.schedule(event, arg1, arg2));
+            });
+        });
+    }
+
+    // Returns whether `appid` may use the driver, making it the owner if no
+    // process owns the driver or the owner no longer exists.
+    fn claim(&self, appid: AppId) -> bool {
+        let available = self.owner.map_or(true, |owner| {
+            if !self.peripheral.is_idle() {
+                *owner == appid
+            } else {
+                self.apps
+                    .enter(*owner, |_, _| *owner == appid)
+                    .unwrap_or(true)
+            }
+        });
+        if available {
+            self.owner.set(appid);
+        }
+        available
+    }
+}
+
+impl<'a, B, A> PeripheralClient for BlePeripheral<'a, B, A>
+where
+    B: BleLinkLayerDriver<'a> + BleConfig,
+    A: Alarm<'a>,
+{
+    fn connected(&self, interval: u16) {
+        self.upcall(event::CONNECTED, interval as usize, 0);
+    }
+
+    fn disconnected(&self, reason: u8) {
+        self.upcall(event::DISCONNECTED, reason as usize, 0);
+    }
+
+    // Copies the value written by the central to the write buffer of the
+    // owner and notifies it.
+    fn written(&self, handle: u16, value: &[u8], len: usize) {
+        self.owner.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                if let Some(buffer) = app.write_buffer.as_mut() {
+                    let copy_len = cmp::min(buffer.len(), value.len());
+                    buffer.as_mut()[..copy_len].copy_from_slice(&value[..copy_len]);
+                }
+                app.callback
+                    .map(|mut cb| cb.schedule(event::WRITTEN, handle as usize, len));
+            });
+        });
+    }
+
+    fn indication_confirmed(&self) {
+        self.upcall(event::INDICATION_CONFIRMED, 0, 0);
+    }
+}
+
+impl<'a, B, A> Driver for BlePeripheral<'a, B, A>
+where
+    B: BleLinkLayerDriver<'a> + BleConfig,
+    A: Alarm<'a>,
+{
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.database = slice,
+                    1 => app.adv_data = slice,
+                    2 => app.write_buffer = slice,
+                    3 => app.value_buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if command_num > 6 {
+            return ReturnCode::ENOSUPPORT;
+        }
+        if !self.claim(appid) {
+            return ReturnCode::EBUSY;
+        }
+        match command_num {
+            1 => self
+                .apps
+                .enter(appid, |app, _| match app.database {
+                    Some(ref description) => self.peripheral.build_database(description.as_ref()),
+                    None => self.peripheral.build_database(&[]),
+                })
+                .unwrap_or_else(|err| err.into()),
+            2 => self
+                .apps
+                .enter(appid, |app, _| {
+                    let data = app.adv_data.as_ref().map_or(&[][..], |data| {
+                        &data.as_ref()[..advertising_data::significant_len(data.as_ref())]
+                    });
+                    self.peripheral.start_advertising(data, arg1)
+                })
+                .unwrap_or_else(|err| err.into()),
+            3 => self.peripheral.stop_advertising(),
+            4 => self.peripheral.terminate(),
+            5 => self
+                .apps
+                .enter(appid, |app, _| {
+                    let value = match app.value_buffer {
+                        Some(ref buffer) if arg2 <= buffer.len() => &buffer.as_ref()[..arg2],
+                        Some(_) => return ReturnCode::ESIZE,
+                        None => return ReturnCode::ERESERVE,
+                    };
+                    self.peripheral.set_value(arg1 as u16, value)
+                })
+                .unwrap_or_else(|err| err.into()),
+            6 => self.peripheral.notify(arg1 as u16),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::ble::link_layer::{control_opcode, AdvHeader};
+    use crate::test_util::{leak, SimAlarm};
+    use core::cell::RefCell;
+    use kernel::hil::time::{Freq32KHz, Time};
+    use std::vec::Vec;
+
+    const ADDRESS: [u8; ADDRESS_LEN] = [1, 2, 3, 4, 5, 0xc6];
+    const ACCESS_ADDRESS: u32 = 0x5065_1234;
+    // Connection interval of 30 ms and supervision timeout of 100 ms
+    const INTERVAL: u16 = 24;
+    const TIMEOUT: u16 = 10;
+    const HOP: u8 = 7;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    enum RadioOp {
+        TransmitAndListen(RadioChannel),
+        Listen(RadioChannel),
+    }
+
+    struct TestRadio<'a> {
+        ops: RefCell<Vec<RadioOp>>,
+        access_address: Cell<u32>,
+        exchange: Cell<bool>,
+        client: OptionalCell<&'a dyn ExchangeClient>,
+    }
+
+    impl TestRadio<'_> {
+        fn start(&self, op: RadioOp) -> ReturnCode {
+            if self.exchange.get() {
+                return ReturnCode::EBUSY;
+            }
+            self.exchange.set(true);
+            self.ops.borrow_mut().push(op);
+            ReturnCode::SUCCESS
+        }
+
+        fn end(&self) {
+            self.exchange.set(false);
+            self.client
+                .map(|client| client.exchange_done(ReturnCode::SUCCESS));
+        }
+
+        // Passes a packet from the central to the client and returns the
+        // response, ending the exchange if there is none.
+        fn receive(&self, pdu: &[u8]) -> Vec<u8> {
+            assert!(self.exchange.get(), "the radio is not listening");
+            let mut response = [0; MAX_ADV_PDU_LEN];
+            let len = self
+                .client
+                .map_or(0, |client| client.packet_received(pdu, true, &mut response));
+            if len == 0 {
+                self.end();
+            }
+            response[..len].to_vec()
+        }
+
+        fn last_op(&self) -> Option<RadioOp> {
+            self.ops.borrow().last().cloned()
+        }
+    }
+
+    impl<'a> BleLinkLayerDriver<'a> for TestRadio<'a> {
+        fn set_access_address(&self, access_address: u32, _crc_init: u32) {
+            self.access_address.set(access_address);
+        }
+
+        fn transmit_and_listen(&self, _pdu: &[u8], channel: RadioChannel) -> ReturnCode {
+            self.start(RadioOp::TransmitAndListen(channel))
+        }
+
+        fn listen(&self, channel: RadioChannel) -> ReturnCode {
+            self.start(RadioOp::Listen(channel))
+        }
+
+        fn stop_exchange(&self) {
+            if self.exchange.get() {
+                self.end();
+            }
+        }
+
+        fn set_exchange_client(&self, client: &'a dyn ExchangeClient) {
+            self.client.set(client);
+        }
+    }
+
+    impl BleConfig for TestRadio<'_> {
+        fn set_tx_power(&self, _power: u8) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Connected(u16),
+        Disconnected(u8),
+    }
+
+    struct TestClient {
+        events: RefCell<Vec<Event>>,
+    }
+
+    impl PeripheralClient for TestClient {
+        fn connected(&self, interval: u16) {
+            self.events.borrow_mut().push(Event::Connected(interval));
+        }
+
+        fn disconnected(&self, reason: u8) {
+            self.events.borrow_mut().push(Event::Disconnected(reason));
+        }
+
+        fn written(&self, _handle: u16, _value: &[u8], _len: usize) {}
+
+        fn indication_confirmed(&self) {}
+    }
+
+    fn us(us: u32) -> u32 {
+        ticks::<Freq32KHz>(us)
+    }
+
+    fn connect_ind() -> [u8; 36] {
+        let mut pdu = [0; 36];
+        AdvHeader {
+            pdu_type: adv_pdu_type::CONNECT_IND,
+            tx_add: false,
+            rx_add: true,
+            length: 34,
+        }
+        .encode(&mut pdu);
+        pdu[2..8].copy_from_slice(&[6, 5, 4, 3, 2, 1]);
+        pdu[8..14].copy_from_slice(&ADDRESS);
+        pdu[14..18].copy_from_slice(&ACCESS_ADDRESS.to_le_bytes());
+        pdu[18..21].copy_from_slice(&[0x11, 0x22, 0x33]);
+        // Transmit window of 2.5 ms starting 3.75 ms after the transmit
+        // window delay
+        pdu[21] = 2;
+        pdu[22..24].copy_from_slice(&3u16.to_le_bytes());
+        pdu[24..26].copy_from_slice(&INTERVAL.to_le_bytes());
+        pdu[26..28].copy_from_slice(&0u16.to_le_bytes());
+        pdu[28..30].copy_from_slice(&TIMEOUT.to_le_bytes());
+        pdu[30..35].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0x1f]);
+        pdu[35] = HOP | (5 << 5);
+        pdu
+    }
+
+    fn data_pdu(pdu_llid: u8, sn: bool, nesn: bool, payload: &[u8]) -> Vec<u8> {
+        let mut pdu = std::vec![0; PDU_HEADER_LEN + payload.len()];
+        DataHeader {
+            llid: pdu_llid,
+            nesn: nesn,
+            sn: sn,
+            md: false,
+            length: payload.len() as u8,
+        }
+        .encode(&mut pdu);
+        pdu[PDU_HEADER_LEN..].copy_from_slice(payload);
+        pdu
+    }
+
+    fn header(pdu: &[u8]) -> (u8, bool, bool, &[u8]) {
+        let header = DataHeader::decode(pdu).unwrap();
+        (header.llid, header.sn, header.nesn, &pdu[PDU_HEADER_LEN..])
+    }
+
+    struct Harness {
+        radio: &'static TestRadio<'static>,
+        alarm: &'static SimAlarm<'static, Freq32KHz>,
+        peripheral: &'static Peripheral<'static, TestRadio<'static>, SimAlarm<'static, Freq32KHz>>,
+        client: &'static TestClient,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let radio = leak(TestRadio {
+                ops: RefCell::new(Vec::new()),
+                access_address: Cell::new(0),
+                exchange: Cell::new(false),
+                client: OptionalCell::empty(),
+            });
+            let alarm = leak(SimAlarm::with_frequency(1000));
+            let peripheral = leak(Peripheral::new(
+                radio,
+                alarm,
+                ADDRESS,
+                b"Test",
+                leak([0; DATABASE_LEN]),
+                leak([0; L2CAP_BUF_LEN]),
+                leak([0; L2CAP_BUF_LEN]),
+            ));
+            let client = leak(TestClient {
+                events: RefCell::new(Vec::new()),
+            });
+            radio.set_exchange_client(peripheral);
+            alarm.set_client(peripheral);
+            peripheral.set_client(client);
+            Harness {
+                radio: radio,
+                alarm: alarm,
+                peripheral: peripheral,
+                client: client,
+            }
+        }
+
+        // Advances the clock one tick at a time until `done`.
+        fn run_until(&self, done: impl Fn() -> bool) {
+            for _ in 0..100_000 {
+                if done() {
+                    return;
+                }
+                self.alarm.advance(1);
+            }
+            panic!("timed out");
+        }
+
+        fn run_to(&self, time: u32) {
+            self.run_until(|| self.alarm.now() == time);
+        }
+
+        fn listening(&self) -> bool {
+            self.radio.exchange.get()
+        }
+
+        // Advertises until the central connects, returning the time of the
+        // CONNECT_IND.
+        fn connect(&self) -> u32 {
+            assert_eq!(
+                self.peripheral.start_advertising(&[], 20),
+                ReturnCode::SUCCESS
+            );
+            self.run_until(|| self.listening());
+            assert_eq!(
+                self.radio.last_op(),
+                Some(RadioOp::TransmitAndListen(
+                    RadioChannel::AdvertisingChannel37
+                ))
+            );
+            let time = self.alarm.now();
+            assert!(self.radio.receive(&connect_ind()).is_empty());
+            assert_eq!(self.radio.access_address.get(), ACCESS_ADDRESS);
+            time
+        }
+
+        // Connects and exchanges empty PDUs in the first connection event,
+        // returning its anchor point.
+        fn establish(&self) -> u32 {
+            let time = self.connect();
+            self.run_until(|| self.listening());
+            let anchor = time + us(5000) + 10;
+            self.run_to(anchor + us(airtime_us(2)));
+            let response = self
+                .radio
+                .receive(&data_pdu(llid::CONTINUATION, false, false, &[]));
+            assert_eq!(
+                header(&response),
+                (llid::CONTINUATION, false, true, &[][..])
+            );
+            self.run_until(|| !self.listening());
+            assert_eq!(*self.client.events.borrow(), [Event::Connected(INTERVAL)]);
+            anchor
+        }
+
+        // Runs until the next connection event and returns its channel.
+        fn next_event(&self) -> RadioChannel {
+            self.run_until(|| self.listening());
+            match self.radio.last_op() {
+                Some(RadioOp::Listen(channel)) => channel,
+                op => panic!("unexpected radio operation {:?}", op),
+            }
+        }
+    }
+
+    #[test]
+    fn database_description() {
+        let mut buf = [0u8; 128];
+        let mut db = Database::new(&mut buf);
+        let description = [
+            ITEM_SERVICE,
+            2,
+            0x0f,
+            0x18,
+            ITEM_CHARACTERISTIC,
+            2,
+            0x19,
+            0x2a,
+            gatt::properties::READ | gatt::properties::NOTIFY,
+            1,
+            1,
+            100,
+            ITEM_CHARACTERISTIC,
+            2,
+            0x00,
+            0x2b,
+            gatt::properties::WRITE,
+            4,
+            0,
+        ];
+        assert_eq!(add_declarations(&mut db, &description), Ok(()));
+        // Service, characteristic with a descriptor, characteristic
+        assert_eq!(db.last_handle(), 6);
+        assert_eq!(db.get(3).unwrap().value, &[100]);
+        assert_eq!(db.get(6).unwrap().uuid, Uuid::Uuid16(0x2b00));
+
+        // Truncated value
+        assert_eq!(
+            add_declarations(&mut db, &description[..11]),
+            Err(ReturnCode::EINVAL)
+        );
+        // Unknown item
+        assert_eq!(
+            add_declarations(&mut db, &[3, 2, 0, 0]),
+            Err(ReturnCode::EINVAL)
+        );
+    }
+
+    #[test]
+    fn connection_events() {
+        let h = Harness::new();
+        let time = h.connect();
+
+        // The first event listens from before the transmit window, which
+        // starts 1.25 ms plus the window offset after the CONNECT_IND
+        assert_eq!(h.next_event(), RadioChannel::DataChannel7);
+        let window_start = time + us(5000);
+        assert!(h.alarm.now() <= window_start);
+        assert!(window_start - h.alarm.now() <= us(RX_MARGIN_US) + 1);
+
+        // The central sends its first packet inside the window, which sets
+        // the anchor point
+        let mut anchor = window_start + 10;
+        h.run_to(anchor + us(airtime_us(2)));
+        let response = h
+            .radio
+            .receive(&data_pdu(llid::CONTINUATION, false, false, &[]));
+        assert_eq!(
+            header(&response),
+            (llid::CONTINUATION, false, true, &[][..])
+        );
+        h.run_until(|| !h.listening());
+        assert_eq!(*h.client.events.borrow(), [Event::Connected(INTERVAL)]);
+
+        // Following events are an interval apart and hop by HOP channels,
+        // and each acknowledges the central's last packet
+        let interval = us(INTERVAL as u32 * UNIT_US);
+        for (event, &channel) in [14, 21, 28, 35, 5].iter().enumerate() {
+            assert_eq!(
+                h.next_event(),
+                RadioChannel::from_channel_index(channel).unwrap()
+            );
+            anchor += interval;
+            assert!(h.alarm.now() <= anchor);
+            assert!(anchor - h.alarm.now() <= us(RX_MARGIN_US) + 2);
+
+            let sn = event % 2 == 0;
+            h.run_to(anchor + us(airtime_us(2)));
+            let response = h.radio.receive(&data_pdu(llid::CONTINUATION, sn, sn, &[]));
+            assert_eq!(header(&response), (llid::CONTINUATION, sn, !sn, &[][..]));
+            h.run_until(|| !h.listening());
+        }
+        assert_eq!(*h.client.events.borrow(), [Event::Connected(INTERVAL)]);
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        let h = Harness::new();
+        h.establish();
+        let ping_req = [control_opcode::PING_REQ];
+        let ping_rsp = [control_opcode::PING_RSP];
+
+        // A new PDU acknowledging ours: the response acknowledges it
+        h.next_event();
+        let response = h
+            .radio
+            .receive(&data_pdu(llid::CONTROL, true, true, &ping_req));
+        assert_eq!(
+            header(&response),
+            (llid::CONTINUATION, true, false, &[][..])
+        );
+        // The next one cannot be stored before the first is processed, so it
+        // is not acknowledged, and neither is our PDU
+        let response = h
+            .radio
+            .receive(&data_pdu(llid::CONTROL, false, true, &ping_req));
+        assert_eq!(
+            header(&response),
+            (llid::CONTINUATION, true, false, &[][..])
+        );
+        h.run_until(|| !h.listening());
+
+        // The central sends the PDU again and acknowledges ours, and the
+        // response to the first request is sent
+        h.next_event();
+        let response = h
+            .radio
+            .receive(&data_pdu(llid::CONTROL, false, false, &ping_req));
+        assert_eq!(
+            header(&response),
+            (llid::CONTROL, false, true, &ping_rsp[..])
+        );
+        // Until the central acknowledges it, the same PDU is sent again
+        let response = h
+            .radio
+            .receive(&data_pdu(llid::CONTINUATION, true, false, &[]));
+        assert_eq!(
+            header(&response),
+            (llid::CONTROL, false, false, &ping_rsp[..])
+        );
+        let response = h
+            .radio
+            .receive(&data_pdu(llid::CONTINUATION, false, true, &[]));
+        assert_eq!(header(&response), (llid::CONTINUATION, true, true, &[][..]));
+        h.run_until(|| !h.listening());
+
+        // The second request is answered in the next event
+        h.next_event();
+        let response = h
+            .radio
+            .receive(&data_pdu(llid::CONTINUATION, true, false, &[]));
+        assert_eq!(
+            header(&response),
+            (llid::CONTROL, false, false, &ping_rsp[..])
+        );
+    }
+
+    #[test]
+    fn supervision_timeout() {
+        let h = Harness::new();
+        let anchor = h.establish();
+        let last_rx = anchor + us(airtime_us(2));
+
+        // The central goes silent: events keep being scheduled until the
+        // supervision timeout has elapsed since the last packet
+        let events = h.radio.ops.borrow().len();
+        h.run_until(|| h.client.events.borrow().len() == 2);
+        assert_eq!(
+            h.client.events.borrow()[1],
+            Event::Disconnected(reason::CONNECTION_TIMEOUT)
+        );
+        let elapsed = h.alarm.now() - last_rx;
+        let timeout = us(TIMEOUT as u32 * 10_000);
+        let interval = us(INTERVAL as u32 * UNIT_US);
+        assert!(elapsed > timeout && elapsed <= timeout + interval);
+        assert_eq!(h.radio.ops.borrow().len() - events, 4);
+
+        assert!(h.peripheral.is_idle());
+        assert!(!h.listening());
+        assert!(!h.alarm.is_enabled());
+    }
+
+    #[test]
+    fn connection_not_established() {
+        let h = Harness::new();
+        h.connect();
+
+        // The central is never heard, so the connection fails after six
+        // events
+        h.run_until(|| !h.client.events.borrow().is_empty());
+        assert_eq!(
+            *h.client.events.borrow(),
+            [Event::Disconnected(reason::FAILED_TO_ESTABLISH)]
+        );
+        let listens = h
+            .radio
+            .ops
+            .borrow()
+            .iter()
+            .filter(|op| match op {
+                RadioOp::Listen(_) => true,
+                _ => false,
+            })
+            .count();
+        assert_eq!(listens, ESTABLISH_EVENTS as usize);
+        assert!(h.peripheral.is_idle());
+    }
+}
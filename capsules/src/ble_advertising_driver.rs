@@ -8,15 +8,28 @@
 //! Processes can also control the TX power used for their advertisements.
 //!
 //! Data payloads are limited to 31 bytes since the maximum advertising channel
-//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header.
+//! protocol data unit (PDU) is 37 bytes and includes a 6-byte header. Payloads
+//! are sequences of AD structures; connectable and scannable advertisements
+//! get a flags AD structure (LE General Discoverable, BR/EDR not supported)
+//! added by the driver if the process did not include one.
+//!
+//! If the radio also provides the `BleLinkLayerDriver` interface (see
+//! `set_link_layer`), scannable advertisements answer scan requests with the
+//! scan response data of the process, and processes can scan actively: a
+//! scan request is sent to each scannable advertiser that passes the filter
+//! of the process and its scan response is reported together with the
+//! advertisement.
 //!
 //! ### Allow system call
 //!
 //! The allow systems calls are used for buffers from allocated by userland
 //!
-//! There are two different buffers:
+//! There are four different buffers:
 //! * 0: Advertising data
-//! * 1: Passive scanning buffer
+//! * 1: Scanning buffer. Receives the advertising PDU, followed by the scan
+//!      response PDU when scanning actively
+//! * 2: Scan response data
+//! * 3: Address filter, the 6 byte advertiser address in over-the-air order
 //!
 //! The possible return codes from the 'allow' system call indicate the following:
 //!
@@ -33,7 +46,9 @@
 //!  The `subscribe` is used to specify the specific operation, currently:
 //!
 //! * 0: provides a callback user-space when a device scanning for advertisements
-//!      and the callback is used to invoke user-space processes.
+//!      and the callback is used to invoke user-space processes. The callback
+//!      arguments are the result, the length of the advertising PDU and the
+//!      length of the scan response PDU following it (0 if none).
 //!
 //! The possible return codes from the `allow` system call indicate the following:
 //!
@@ -48,7 +63,13 @@
 //!
 //! * 0: start advertisement
 //! * 1: stop advertisement or scanning
-//! * 5: start scanning
+//! * 2: configure the transmit power
+//! * 5: start passive scanning
+//! * 6: start active scanning
+//! * 7: only report advertisements containing an AD structure of the type
+//!      given as argument, or all advertisements if it is 0
+//! * 8: only report advertisements from the address in buffer 3 if the
+//!      argument is 1, or from any address if it is 0
 //!
 //! The possible return codes from the `command` system call indicate the following:
 //!
@@ -80,6 +101,10 @@
 //!                                                                   ble_radio);
 //! nrf5x::ble_advertising_hil::BleAdvertisementDriver::set_tx_client(&nrf52::radio::RADIO,
 //!                                                                   ble_radio);
+//! // Optional, for scan responses and active scanning
+//! nrf5x::ble_advertising_hil::BleLinkLayerDriver::set_exchange_client(&nrf52::radio::RADIO,
+//!                                                                    ble_radio);
+//! ble_radio.set_link_layer(&nrf52::radio::RADIO);
 //! ble_radio_virtual_alarm.set_client(ble_radio);
 //! ```
 //!
@@ -99,7 +124,14 @@
 // This means that advertising events can collide. In this case, we just defer one of the
 // advertisements. Because we add a pseudo random pad to the timer interval each time (as required
 // by the Bluetooth specification) multiple collisions of the same processes are highly unlikely.
+//
+// Scannable advertisements and active scanning use exchanges of the link layer interface, in which
+// the radio answers a received packet T_IFS after it. The radio keeps listening after the
+// advertisement or scan request until the exchange is stopped, so the app timer is also used to
+// end the listen window on each channel.
 
+use crate::ble::advertising_data::{self, ad_type, flags, AdvertisingDataBuilder};
+use crate::ble::link_layer::MAX_ADV_DATA_LEN;
 use core::cell::Cell;
 use core::cmp;
 use kernel::common::cells::OptionalCell;
@@ -119,6 +151,13 @@ pub static mut BUF: [u8; PACKET_LENGTH] = [0; PACKET_LENGTH];
 const PACKET_ADDR_LEN: usize = 6;
 const PACKET_LENGTH: usize = 39;
 const ADV_HEADER_TXADD_OFFSET: usize = 6;
+const ADV_HEADER_RXADD_OFFSET: usize = 7;
+const ADV_PDU_TYPE_MASK: u8 = 0x0f;
+
+// How long to listen for scan requests after a scannable advertisement
+const ADV_LISTEN_MS: u32 = 2;
+// How long to listen on each channel when scanning actively
+const ACTIVE_SCAN_WINDOW_MS: u32 = 10;
 
 #[derive(PartialEq, Debug)]
 enum BLEState {
@@ -158,14 +197,98 @@ const ADV_IND: AdvPduType = 0b0000;
 #[allow(dead_code)]
 const ADV_DIRECTED_IND: AdvPduType = 0b0001;
 const ADV_NONCONN_IND: AdvPduType = 0b0010;
-#[allow(dead_code)]
 const SCAN_REQ: AdvPduType = 0b0011;
-#[allow(dead_code)]
 const SCAN_RESP: AdvPduType = 0b0100;
 #[allow(dead_code)]
 const CONNECT_IND: AdvPduType = 0b0101;
 const ADV_SCAN_IND: AdvPduType = 0b0110;
 
+// Operation in progress with the link layer interface of the radio
+#[derive(Copy, Clone, PartialEq)]
+enum Exchange {
+    None,
+    /// Scannable advertisement, answering scan requests
+    Advertising,
+    /// Active scanning, sending scan requests
+    Scanning,
+}
+
+/// Filter on the advertisements reported to a process.
+#[derive(Copy, Clone, Default)]
+struct ScanFilter {
+    address: Option<[u8; PACKET_ADDR_LEN]>,
+    ad_type: Option<u8>,
+}
+
+impl ScanFilter {
+    fn matches(&self, pdu: &[u8]) -> bool {
+        if self.address.is_none() && self.ad_type.is_none() {
+            return true;
+        }
+        let len = 2 + (pdu[1] & 0x3f) as usize;
+        if len < 2 + PACKET_ADDR_LEN || pdu.len() < len {
+            return false;
+        }
+        let adv_a = &pdu[2..2 + PACKET_ADDR_LEN];
+        let data = &pdu[2 + PACKET_ADDR_LEN..len];
+        let has_data = match pdu[0] & ADV_PDU_TYPE_MASK {
+            ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND | SCAN_RESP => true,
+            _ => false,
+        };
+        self.address.map_or(true, |address| address == adv_a)
+            && self.ad_type.map_or(true, |ad_type| {
+                has_data && advertising_data::find(data, ad_type).is_some()
+            })
+    }
+}
+
+// Writes an advertising PDU of type `pdu_type` from `address` with
+// `adv_data` to `buf` and returns its length. Discoverable advertisements
+// get the flags AD structure if `adv_data` has none, and the data is
+// truncated to fit in the PDU.
+fn encode_advertisement(
+    buf: &mut [u8],
+    pdu_type: AdvPduType,
+    address: &[u8; PACKET_ADDR_LEN],
+    adv_data: &[u8],
+) -> usize {
+    let (header, payload) = buf.split_at_mut(2);
+    header[0] = pdu_type;
+    let discoverable = match pdu_type {
+        ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
+            // Set TxAdd because AdvA field is going to be a "random"
+            // address
+            header[0] |= 1 << ADV_HEADER_TXADD_OFFSET;
+            pdu_type != ADV_NONCONN_IND
+        }
+        _ => false,
+    };
+    let (adva, data) = payload.split_at_mut(PACKET_ADDR_LEN);
+    adva.copy_from_slice(address);
+
+    let mut builder = AdvertisingDataBuilder::new(data);
+    if discoverable && advertising_data::find(adv_data, ad_type::FLAGS).is_none() {
+        builder.add_flags(flags::LE_GENERAL_DISCOVERABLE | flags::BR_EDR_NOT_SUPPORTED);
+    }
+    let adv_data_len = cmp::min(adv_data.len(), builder.remaining());
+    builder.append(&adv_data[..adv_data_len]);
+    let payload_len = PACKET_ADDR_LEN + builder.len();
+    // The LENGTH field is 6-bits wide, so make sure to truncate it
+    header[1] = (payload_len & 0x3f) as u8;
+    cmp::min(PACKET_LENGTH, payload_len + 2)
+}
+
+// Writes a scan response PDU from `address` with `data`, truncated to the
+// maximum advertising data length, to `buf` and returns its length.
+fn encode_scan_response(buf: &mut [u8], address: &[u8; PACKET_ADDR_LEN], data: &[u8]) -> usize {
+    let data_len = cmp::min(data.len(), MAX_ADV_DATA_LEN);
+    buf[0] = SCAN_RESP | 1 << ADV_HEADER_TXADD_OFFSET;
+    buf[1] = (PACKET_ADDR_LEN + data_len) as u8;
+    buf[2..2 + PACKET_ADDR_LEN].copy_from_slice(address);
+    buf[2 + PACKET_ADDR_LEN..2 + PACKET_ADDR_LEN + data_len].copy_from_slice(&data[..data_len]);
+    2 + PACKET_ADDR_LEN + data_len
+}
+
 /// Process specific memory
 pub struct App {
     process_status: Option<BLEState>,
@@ -184,9 +307,14 @@ pub struct App {
     /// well.
     random_nonce: u32,
 
+    scan_response_data: Option<kernel::AppSlice<kernel::Shared, u8>>,
+
     // Scanning meta-data
     scan_buffer: Option<kernel::AppSlice<kernel::Shared, u8>>,
     scan_callback: Option<kernel::Callback>,
+    active_scan: bool,
+    scan_filter: ScanFilter,
+    filter_address: Option<kernel::AppSlice<kernel::Shared, u8>>,
 }
 
 impl Default for App {
@@ -194,10 +322,14 @@ impl Default for App {
         App {
             alarm_data: AlarmData::new(),
             adv_data: None,
+            scan_response_data: None,
             scan_buffer: None,
             address: [0; PACKET_ADDR_LEN],
             pdu_type: ADV_NONCONN_IND,
             scan_callback: None,
+            active_scan: false,
+            scan_filter: ScanFilter::default(),
+            filter_address: None,
             process_status: Some(BLEState::NotInitialized),
             tx_power: 0,
             advertisement_interval_ms: 200,
@@ -236,40 +368,86 @@ impl App {
         ReturnCode::SUCCESS
     }
 
-    fn send_advertisement<'a, B, A>(&self, ble: &BLE<'a, B, A>, channel: RadioChannel) -> ReturnCode
+    // Writes the advertising PDU of this app to `buf` and returns its length.
+    fn encode_advertisement(&self, buf: &mut [u8]) -> usize {
+        let adv_data = self.adv_data.as_ref().map_or(&[][..], |adv_data| {
+            &adv_data.as_ref()[..advertising_data::significant_len(adv_data.as_ref())]
+        });
+        encode_advertisement(buf, self.pdu_type, &self.address, adv_data)
+    }
+
+    // Writes the scan response PDU of this app to `buf` and returns its
+    // length.
+    fn encode_scan_response(&self, buf: &mut [u8]) -> usize {
+        let data = self.scan_response_data.as_ref().map_or(&[][..], |data| {
+            &data.as_ref()[..advertising_data::significant_len(data.as_ref())]
+        });
+        encode_scan_response(buf, &self.address, data)
+    }
+
+    fn send_advertisement<'a, B, A>(
+        &mut self,
+        ble: &BLE<'a, B, A>,
+        channel: RadioChannel,
+    ) -> ReturnCode
     where
         B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
         A: kernel::hil::time::Alarm<'a>,
     {
-        self.adv_data.as_ref().map_or(ReturnCode::FAIL, |adv_data| {
-            ble.kernel_tx.take().map_or(ReturnCode::FAIL, |kernel_tx| {
-                let adv_data_len = cmp::min(kernel_tx.len() - PACKET_ADDR_LEN - 2, adv_data.len());
-                let adv_data_corrected = &adv_data.as_ref()[..adv_data_len];
-                let payload_len = adv_data_corrected.len() + PACKET_ADDR_LEN;
-                {
-                    let (header, payload) = kernel_tx.split_at_mut(2);
-                    header[0] = self.pdu_type;
-                    match self.pdu_type {
-                        ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
-                            // Set TxAdd because AdvA field is going to be a "random"
-                            // address
-                            header[0] |= 1 << ADV_HEADER_TXADD_OFFSET;
-                        }
-                        _ => {}
-                    }
-                    // The LENGTH field is 6-bits wide, so make sure to truncate it
-                    header[1] = (payload_len & 0x3f) as u8;
+        if self.adv_data.is_none() {
+            return ReturnCode::FAIL;
+        }
+        let scannable = self.pdu_type == ADV_IND || self.pdu_type == ADV_SCAN_IND;
+        if let (true, Some(link_layer)) = (scannable, ble.link_layer.map(|link_layer| *link_layer))
+        {
+            let mut pdu = [0; PACKET_LENGTH];
+            let len = self.encode_advertisement(&mut pdu);
+            let mut scan_response = [0; PACKET_LENGTH];
+            ble.scan_response_len
+                .set(self.encode_scan_response(&mut scan_response));
+            ble.scan_response.set(scan_response);
+            ble.address.set(self.address);
+            ble.exchange.set(Exchange::Advertising);
+            let result = link_layer.transmit_and_listen(&pdu[..len], channel);
+            if result == ReturnCode::SUCCESS {
+                self.set_listen_alarm::<A::Frequency>(ble.alarm.now(), ADV_LISTEN_MS);
+                return result;
+            }
+            // Advertise without answering scan requests instead
+            ble.exchange.set(Exchange::None);
+        }
+        ble.kernel_tx.take().map_or(ReturnCode::FAIL, |kernel_tx| {
+            let total_len = self.encode_advertisement(kernel_tx);
+            ble.radio
+                .transmit_advertisement(kernel_tx, total_len, channel);
+            ReturnCode::SUCCESS
+        })
+    }
 
-                    let (adva, data) = payload.split_at_mut(6);
-                    adva.copy_from_slice(&self.address);
-                    data[..adv_data_len].copy_from_slice(adv_data_corrected);
+    // Starts scanning on `channel`, actively if this app asked for it and
+    // passively otherwise.
+    fn scan<'a, B, A>(&mut self, ble: &BLE<'a, B, A>, channel: RadioChannel)
+    where
+        B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+        A: kernel::hil::time::Alarm<'a>,
+    {
+        match ble.link_layer.map(|link_layer| *link_layer) {
+            Some(link_layer) if self.active_scan => {
+                ble.scan_filter.set(self.scan_filter);
+                ble.address.set(self.address);
+                ble.report_len.set(0);
+                ble.scan_response_report_len.set(0);
+                ble.exchange.set(Exchange::Scanning);
+                if link_layer.listen(channel) == ReturnCode::SUCCESS {
+                    self.set_listen_alarm::<A::Frequency>(ble.alarm.now(), ACTIVE_SCAN_WINDOW_MS);
+                } else {
+                    // Scan passively on this channel instead
+                    ble.exchange.set(Exchange::None);
+                    ble.radio.receive_advertisement(channel);
                 }
-                let total_len = cmp::min(PACKET_LENGTH, payload_len + 2);
-                ble.radio
-                    .transmit_advertisement(kernel_tx, total_len, channel);
-                ReturnCode::SUCCESS
-            })
-        })
+            }
+            _ => ble.radio.receive_advertisement(channel),
+        }
     }
 
     // Returns a new pseudo-random number and updates the randomness state.
@@ -294,6 +472,13 @@ impl App {
         let period_ms = (self.advertisement_interval_ms + nonce) * F::frequency() / 1000;
         self.alarm_data.expiration = Expiration::Abs(now.wrapping_add(period_ms));
     }
+
+    // Set the alarm for the end of the listen window of an exchange.
+    fn set_listen_alarm<F: Frequency>(&mut self, now: u32, window_ms: u32) {
+        self.alarm_data.t0 = now;
+        let window = cmp::max(1, window_ms * F::frequency() / 1000);
+        self.alarm_data.expiration = Expiration::Abs(now.wrapping_add(window));
+    }
 }
 
 pub struct BLE<'a, B, A>
@@ -308,6 +493,20 @@ where
     alarm: &'a A,
     sending_app: OptionalCell<kernel::AppId>,
     receiving_app: OptionalCell<kernel::AppId>,
+    link_layer: OptionalCell<&'a dyn ble_advertising::BleLinkLayerDriver<'a>>,
+    exchange: Cell<Exchange>,
+    /// Address of the app advertising or scanning in the current exchange
+    address: Cell<[u8; PACKET_ADDR_LEN]>,
+    /// Scan response of the app advertising
+    scan_response: Cell<[u8; PACKET_LENGTH]>,
+    scan_response_len: Cell<usize>,
+    /// Filter of the app scanning actively
+    scan_filter: Cell<ScanFilter>,
+    /// Advertisement received when scanning actively, followed by its scan
+    /// response
+    report: Cell<[u8; 2 * PACKET_LENGTH]>,
+    report_len: Cell<usize>,
+    scan_response_report_len: Cell<usize>,
 }
 
 impl<'a, B, A> BLE<'a, B, A>
@@ -329,6 +528,76 @@ where
             alarm: alarm,
             sending_app: OptionalCell::empty(),
             receiving_app: OptionalCell::empty(),
+            link_layer: OptionalCell::empty(),
+            exchange: Cell::new(Exchange::None),
+            address: Cell::new([0; PACKET_ADDR_LEN]),
+            scan_response: Cell::new([0; PACKET_LENGTH]),
+            scan_response_len: Cell::new(0),
+            scan_filter: Cell::new(ScanFilter::default()),
+            report: Cell::new([0; 2 * PACKET_LENGTH]),
+            report_len: Cell::new(0),
+            scan_response_report_len: Cell::new(0),
+        }
+    }
+
+    /// Enables scan responses and active scanning with the link layer
+    /// interface of the radio. The driver must also be set as the exchange
+    /// client of the radio.
+    pub fn set_link_layer(&self, link_layer: &'a dyn ble_advertising::BleLinkLayerDriver<'a>) {
+        self.link_layer.set(link_layer);
+    }
+
+    // Moves the advertising event of `app` to the next channel, or ends it
+    // after the last one.
+    fn advertising_channel_done(&self, appid: kernel::AppId, app: &mut App) {
+        match app.process_status {
+            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37)) => {
+                app.process_status =
+                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
+                self.sending_app.set(appid);
+                self.radio.set_tx_power(app.tx_power);
+                app.send_advertisement(&self, RadioChannel::AdvertisingChannel38);
+            }
+
+            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38)) => {
+                app.process_status =
+                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
+                self.sending_app.set(appid);
+                app.send_advertisement(&self, RadioChannel::AdvertisingChannel39);
+            }
+
+            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
+                self.busy.set(false);
+                app.process_status = Some(BLEState::AdvertisingIdle);
+                app.set_next_alarm::<A::Frequency>(self.alarm.now());
+            }
+            // Invalid state => don't care
+            _ => (),
+        }
+    }
+
+    // Moves the scanning event of `app` to the next channel, or ends it after
+    // the last one.
+    fn scanning_channel_done(&self, appid: kernel::AppId, app: &mut App) {
+        match app.process_status {
+            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
+                app.process_status = Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38));
+                self.receiving_app.set(appid);
+                self.radio.set_tx_power(app.tx_power);
+                app.scan(&self, RadioChannel::AdvertisingChannel38);
+            }
+            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38)) => {
+                app.process_status = Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39));
+                self.receiving_app.set(appid);
+                app.scan(&self, RadioChannel::AdvertisingChannel39);
+            }
+            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39)) => {
+                self.busy.set(false);
+                app.process_status = Some(BLEState::ScanningIdle);
+                app.set_next_alarm::<A::Frequency>(self.alarm.now());
+            }
+            // Invalid state => don't care
+            _ => (),
         }
     }
 
@@ -381,12 +650,25 @@ where
     // recently performed an operation.
     fn fired(&self) {
         let now = self.alarm.now();
+        let window_over = Cell::new(false);
 
         self.app.each(|app| {
             if let Expiration::Abs(exp) = app.alarm_data.expiration {
                 let expired =
                     now.wrapping_sub(app.alarm_data.t0) >= exp.wrapping_sub(app.alarm_data.t0);
                 if expired {
+                    match app.process_status {
+                        Some(BLEState::Advertising(_)) | Some(BLEState::Scanning(_)) => {
+                            // The listen window of the exchange of this app is
+                            // over. The exchange is stopped once the grant is
+                            // no longer entered.
+                            app.alarm_data.expiration = Expiration::Disabled;
+                            window_over.set(true);
+                            return;
+                        }
+                        _ => {}
+                    }
+
                     if self.busy.get() {
                         // The radio is currently busy, so we won't be able to start the
                         // operation at the appropriate time. Instead, reschedule the
@@ -414,8 +696,7 @@ where
                                 Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
                             self.receiving_app.set(app.appid());
                             self.radio.set_tx_power(app.tx_power);
-                            self.radio
-                                .receive_advertisement(RadioChannel::AdvertisingChannel37);
+                            app.scan(&self, RadioChannel::AdvertisingChannel37);
                         }
                         _ => debug!(
                             "app: {:?} \t invalid state {:?}",
@@ -426,6 +707,9 @@ where
                 }
             }
         });
+        if window_over.get() && self.exchange.get() != Exchange::None {
+            self.link_layer.map(|link_layer| link_layer.stop_exchange());
+        }
         self.reset_active_alarm();
     }
 }
@@ -448,7 +732,10 @@ where
                 // Packets that are bigger than 39 bytes are likely `Channel PDUs` which should
                 // only be sent on the other 37 RadioChannel channels.
 
-                if len <= PACKET_LENGTH as u8 && result == ReturnCode::SUCCESS {
+                if len <= PACKET_LENGTH as u8
+                    && result == ReturnCode::SUCCESS
+                    && app.scan_filter.matches(&buf[..len as usize])
+                {
                     // write to buffer in userland
                     let success = app
                         .scan_buffer
@@ -467,30 +754,7 @@ where
                     }
                 }
 
-                match app.process_status {
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38));
-                        self.receiving_app.set(app.appid());
-                        self.radio.set_tx_power(app.tx_power);
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel38);
-                    }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39));
-                        self.receiving_app.set(app.appid());
-                        self.radio
-                            .receive_advertisement(RadioChannel::AdvertisingChannel39);
-                    }
-                    Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::ScanningIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
-                    }
-                    // Invalid state => don't care
-                    _ => (),
-                }
+                self.scanning_channel_done(*appid, app);
             });
             self.reset_active_alarm();
         });
@@ -508,34 +772,126 @@ where
     fn transmit_event(&self, buf: &'static mut [u8], _crc_ok: ReturnCode) {
         self.kernel_tx.replace(buf);
         self.sending_app.map(|appid| {
-            let _ = self.app.enter(*appid, |app, _| {
-                match app.process_status {
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38));
-                        self.sending_app.set(app.appid());
-                        self.radio.set_tx_power(app.tx_power);
-                        app.send_advertisement(&self, RadioChannel::AdvertisingChannel38);
-                    }
-
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel38)) => {
-                        app.process_status =
-                            Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39));
-                        self.sending_app.set(app.appid());
-                        app.send_advertisement(&self, RadioChannel::AdvertisingChannel39);
-                    }
+            let _ = self
+                .app
+                .enter(*appid, |app, _| self.advertising_channel_done(*appid, app));
+            self.reset_active_alarm();
+        });
+    }
+}
 
-                    Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
-                        app.process_status = Some(BLEState::AdvertisingIdle);
-                        app.set_next_alarm::<A::Frequency>(self.alarm.now());
+// Callbacks from the radio in scan request and scan response exchanges
+impl<'a, B, A> ble_advertising::ExchangeClient for BLE<'a, B, A>
+where
+    B: ble_advertising::BleAdvertisementDriver<'a> + ble_advertising::BleConfig,
+    A: kernel::hil::time::Alarm<'a>,
+{
+    fn packet_received(&self, pdu: &[u8], crc_ok: bool, response: &mut [u8]) -> usize {
+        if !crc_ok || pdu.len() < 2 + PACKET_ADDR_LEN || pdu.len() > PACKET_LENGTH {
+            return 0;
+        }
+        let pdu_type = pdu[0] & ADV_PDU_TYPE_MASK;
+        let address = self.address.get();
+        match self.exchange.get() {
+            Exchange::Advertising => {
+                // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.3.2.1
+                // SCAN_REQ: ScanA followed by AdvA, which is our random address
+                let for_us = pdu_type == SCAN_REQ
+                    && pdu.len() == 2 + 2 * PACKET_ADDR_LEN
+                    && pdu[0] & (1 << ADV_HEADER_RXADD_OFFSET) != 0
+                    && pdu[2 + PACKET_ADDR_LEN..] == address;
+                if for_us {
+                    let len = self.scan_response_len.get();
+                    response[..len].copy_from_slice(&self.scan_response.get()[..len]);
+                    len
+                } else {
+                    0
+                }
+            }
+            Exchange::Scanning => {
+                let mut report = self.report.get();
+                let adv_len = self.report_len.get();
+                if adv_len > 0 {
+                    // Waiting for the scan response of the advertiser
+                    if pdu_type == SCAN_RESP
+                        && pdu[2..2 + PACKET_ADDR_LEN] == report[2..2 + PACKET_ADDR_LEN]
+                    {
+                        report[adv_len..adv_len + pdu.len()].copy_from_slice(pdu);
+                        self.report.set(report);
+                        self.scan_response_report_len.set(pdu.len());
                     }
-                    // Invalid state => don't care
-                    _ => (),
+                    return 0;
                 }
-            });
-            self.reset_active_alarm();
-        });
+                if !self.scan_filter.get().matches(pdu) {
+                    return 0;
+                }
+                report[..pdu.len()].copy_from_slice(pdu);
+                self.report.set(report);
+                self.report_len.set(pdu.len());
+                if pdu_type == ADV_IND || pdu_type == ADV_SCAN_IND {
+                    // SCAN_REQ from our random address, with RxAdd copied
+                    // from the TxAdd of the advertiser
+                    response[0] = SCAN_REQ
+                        | 1 << ADV_HEADER_TXADD_OFFSET
+                        | (pdu[0] & (1 << ADV_HEADER_TXADD_OFFSET)) << 1;
+                    response[1] = (2 * PACKET_ADDR_LEN) as u8;
+                    response[2..2 + PACKET_ADDR_LEN].copy_from_slice(&address);
+                    response[2 + PACKET_ADDR_LEN..2 + 2 * PACKET_ADDR_LEN]
+                        .copy_from_slice(&pdu[2..2 + PACKET_ADDR_LEN]);
+                    2 + 2 * PACKET_ADDR_LEN
+                } else {
+                    0
+                }
+            }
+            Exchange::None => 0,
+        }
+    }
+
+    fn exchange_done(&self, _result: ReturnCode) {
+        match self.exchange.replace(Exchange::None) {
+            Exchange::Advertising => {
+                self.sending_app.map(|appid| {
+                    let _ = self
+                        .app
+                        .enter(*appid, |app, _| self.advertising_channel_done(*appid, app));
+                });
+            }
+            Exchange::Scanning => {
+                self.receiving_app.map(|appid| {
+                    let _ = self.app.enter(*appid, |app, _| {
+                        let adv_len = self.report_len.get();
+                        if adv_len > 0 {
+                            let scan_response_len = self.scan_response_report_len.get();
+                            let report = self.report.get();
+                            let success = app
+                                .scan_buffer
+                                .as_mut()
+                                .map(|userland| {
+                                    for (dst, src) in userland
+                                        .iter_mut()
+                                        .zip(report[..adv_len + scan_response_len].iter())
+                                    {
+                                        *dst = *src;
+                                    }
+                                })
+                                .is_some();
+                            if success {
+                                app.scan_callback.map(|mut cb| {
+                                    cb.schedule(
+                                        usize::from(ReturnCode::SUCCESS),
+                                        adv_len,
+                                        scan_response_len,
+                                    );
+                                });
+                            }
+                        }
+                        self.scanning_channel_done(*appid, app);
+                    });
+                });
+            }
+            Exchange::None => return,
+        }
+        self.reset_active_alarm();
     }
 }
 
@@ -620,11 +976,15 @@ where
                     .unwrap_or_else(|err| err.into())
             }
 
-            // Passive scanning mode
-            5 => self
+            // Passive or active scanning mode
+            5 | 6 => self
                 .app
                 .enter(appid, |app, _| {
+                    if command_num == 6 && self.link_layer.is_none() {
+                        return ReturnCode::ENOSUPPORT;
+                    }
                     if let Some(BLEState::Initialized) = app.process_status {
+                        app.active_scan = command_num == 6;
                         app.process_status = Some(BLEState::ScanningIdle);
                         app.set_next_alarm::<A::Frequency>(self.alarm.now());
                         self.reset_active_alarm();
@@ -635,6 +995,39 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Filter scanning results by AD type
+            7 => self
+                .app
+                .enter(appid, |app, _| {
+                    app.scan_filter.ad_type = match data {
+                        0 => None,
+                        ad_type @ 1..=0xff => Some(ad_type as u8),
+                        _ => return ReturnCode::EINVAL,
+                    };
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Filter scanning results by advertiser address
+            8 => self
+                .app
+                .enter(appid, |app, _| {
+                    app.scan_filter.address = match data {
+                        0 => None,
+                        1 => match app.filter_address {
+                            Some(ref slice) if slice.len() >= PACKET_ADDR_LEN => {
+                                let mut address = [0; PACKET_ADDR_LEN];
+                                address.copy_from_slice(&slice.as_ref()[..PACKET_ADDR_LEN]);
+                                Some(address)
+                            }
+                            _ => return ReturnCode::EINVAL,
+                        },
+                        _ => return ReturnCode::EINVAL,
+                    };
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -673,6 +1066,24 @@ where
                 })
                 .unwrap_or_else(|err| err.into()),
 
+            // Scan response data
+            2 => self
+                .app
+                .enter(appid, |app, _| {
+                    app.scan_response_data = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Address to filter scanning results by
+            3 => self
+                .app
+                .enter(appid, |app, _| {
+                    app.filter_address = slice;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
             // Operation not supported
             _ => ReturnCode::ENOSUPPORT,
         }
@@ -700,3 +1111,131 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDRESS: [u8; PACKET_ADDR_LEN] = [0xf0, 1, 2, 3, 4, 0xf0];
+    const NAME: [u8; 6] = [5, ad_type::COMPLETE_LOCAL_NAME, b'T', b'o', b'c', b'k'];
+    const FLAGS: [u8; 3] = [
+        2,
+        ad_type::FLAGS,
+        flags::LE_GENERAL_DISCOVERABLE | flags::BR_EDR_NOT_SUPPORTED,
+    ];
+
+    // An advertising PDU from `address` with `data`
+    fn pdu(pdu_type: AdvPduType, address: &[u8], data: &[u8]) -> [u8; PACKET_LENGTH] {
+        let mut pdu = [0; PACKET_LENGTH];
+        pdu[0] = pdu_type;
+        pdu[1] = (PACKET_ADDR_LEN + data.len()) as u8;
+        pdu[2..8].copy_from_slice(address);
+        pdu[8..8 + data.len()].copy_from_slice(data);
+        pdu
+    }
+
+    #[test]
+    fn scan_filter() {
+        let other = [0xc0, 9, 9, 9, 9, 0xc0];
+        let with_name = pdu(ADV_IND, &ADDRESS, &NAME);
+        let len = 2 + PACKET_ADDR_LEN + NAME.len();
+
+        assert!(ScanFilter::default().matches(&with_name[..len]));
+
+        let by_address = ScanFilter {
+            address: Some(ADDRESS),
+            ad_type: None,
+        };
+        assert!(by_address.matches(&with_name[..len]));
+        assert!(!by_address.matches(&pdu(ADV_IND, &other, &NAME)));
+        // Also applies to PDUs without advertising data
+        assert!(by_address.matches(&pdu(ADV_DIRECTED_IND, &ADDRESS, &[])));
+
+        let by_type = ScanFilter {
+            address: None,
+            ad_type: Some(ad_type::COMPLETE_LOCAL_NAME),
+        };
+        assert!(by_type.matches(&with_name[..len]));
+        assert!(by_type.matches(&pdu(SCAN_RESP, &other, &NAME)));
+        assert!(!by_type.matches(&pdu(ADV_IND, &ADDRESS, &FLAGS)));
+        // The AD type must be in the advertising data of the PDU, which
+        // directed advertisements have none of
+        assert!(!by_type.matches(&pdu(ADV_DIRECTED_IND, &ADDRESS, &NAME)));
+        // Truncated PDU
+        assert!(!by_type.matches(&with_name[..len - 1]));
+
+        let both = ScanFilter {
+            address: Some(ADDRESS),
+            ad_type: Some(ad_type::COMPLETE_LOCAL_NAME),
+        };
+        assert!(both.matches(&with_name[..len]));
+        assert!(!both.matches(&pdu(ADV_IND, &other, &NAME)));
+        assert!(!both.matches(&pdu(ADV_IND, &ADDRESS, &FLAGS)));
+    }
+
+    #[test]
+    fn advertisement_flags() {
+        let mut buf = [0; PACKET_LENGTH];
+
+        // Discoverable advertisements get the flags first
+        let len = encode_advertisement(&mut buf, ADV_IND, &ADDRESS, &NAME);
+        assert_eq!(len, 2 + PACKET_ADDR_LEN + FLAGS.len() + NAME.len());
+        assert_eq!(buf[0], ADV_IND | 1 << ADV_HEADER_TXADD_OFFSET);
+        assert_eq!(buf[1] as usize, len - 2);
+        assert_eq!(&buf[2..8], &ADDRESS);
+        assert_eq!(&buf[8..11], &FLAGS);
+        assert_eq!(&buf[11..len], &NAME);
+
+        // unless they already have them
+        let mut data = [0; 9];
+        data[..3].copy_from_slice(&[2, ad_type::FLAGS, flags::LE_LIMITED_DISCOVERABLE]);
+        data[3..].copy_from_slice(&NAME);
+        let len = encode_advertisement(&mut buf, ADV_SCAN_IND, &ADDRESS, &data);
+        assert_eq!(&buf[8..len], &data);
+
+        // Non-connectable, non-scannable advertisements are not
+        // discoverable
+        let len = encode_advertisement(&mut buf, ADV_NONCONN_IND, &ADDRESS, &NAME);
+        assert_eq!(&buf[8..len], &NAME);
+    }
+
+    #[test]
+    fn advertisement_truncation() {
+        let mut buf = [0; PACKET_LENGTH];
+        let data = [0x55; 40];
+
+        // The advertising data, flags included, is cut to 31 bytes
+        let len = encode_advertisement(&mut buf, ADV_IND, &ADDRESS, &data);
+        assert_eq!(len, PACKET_LENGTH);
+        assert_eq!(buf[1] as usize, PACKET_ADDR_LEN + MAX_ADV_DATA_LEN);
+        assert_eq!(&buf[8..11], &FLAGS);
+        assert_eq!(&buf[11..], &data[..MAX_ADV_DATA_LEN - FLAGS.len()]);
+
+        let len = encode_advertisement(&mut buf, ADV_NONCONN_IND, &ADDRESS, &data);
+        assert_eq!(len, PACKET_LENGTH);
+        assert_eq!(&buf[8..], &data[..MAX_ADV_DATA_LEN]);
+    }
+
+    #[test]
+    fn scan_response() {
+        let mut buf = [0; PACKET_LENGTH];
+
+        let len = encode_scan_response(&mut buf, &ADDRESS, &NAME);
+        assert_eq!(len, 2 + PACKET_ADDR_LEN + NAME.len());
+        assert_eq!(buf[0], SCAN_RESP | 1 << ADV_HEADER_TXADD_OFFSET);
+        assert_eq!(buf[1] as usize, len - 2);
+        assert_eq!(&buf[2..8], &ADDRESS);
+        assert_eq!(&buf[8..len], &NAME);
+
+        // Scan responses get no flags, and their data is cut to 31 bytes
+        let data = [0x55; 40];
+        let len = encode_scan_response(&mut buf, &ADDRESS, &data);
+        assert_eq!(len, PACKET_LENGTH);
+        assert_eq!(buf[1] as usize, PACKET_ADDR_LEN + MAX_ADV_DATA_LEN);
+        assert_eq!(&buf[8..], &data[..MAX_ADV_DATA_LEN]);
+
+        let len = encode_scan_response(&mut buf, &ADDRESS, &[]);
+        assert_eq!(len, 2 + PACKET_ADDR_LEN);
+        assert_eq!(buf[1] as usize, PACKET_ADDR_LEN);
+    }
+}
@@ -0,0 +1,40 @@
+//! Software CRC-32.
+//!
+//! Computes the same checksum as `hil::crc::CrcAlg::Crc32`. `hil::crc::CRC` is not used because
+//! it can't serve the capsules that use this module:
+//!
+//! - It is asynchronous, returning its result in a callback, while the log, the FTL and the
+//!   key-value store check checksums in the middle of parsing a page, and the log does so while
+//!   it is constructed at boot, before any callback can run.
+//! - It runs one computation at a time and has one client, so these capsules would have to share
+//!   it through a virtualizer, and wait for one another.
+//! - Only the SAM4L implements it, while these capsules work on any `hil::flash` device.
+//!
+//! The loop below is slower than a CRC unit, but these checksums cover at most a flash page.
+
+/// Computes the CRC-32 (as used by Ethernet and zlib) of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff;
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::crc32;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+}
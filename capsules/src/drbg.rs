@@ -0,0 +1,540 @@
+//! HMAC_DRBG random number generator seeded from an entropy source.
+//!
+//! This implements the HMAC_DRBG of NIST SP 800-90A with SHA-256 on top of
+//! `hil::entropy::Entropy32`, and provides it as a `hil::rng::Rng`. Raw
+//! TRNG output is slow and unconditioned; the DRBG turns a seed into as much
+//! output as needed, and reseeds from the entropy source every
+//! `RESEED_INTERVAL` requests.
+//!
+//! The raw entropy is checked with the continuous health tests of NIST SP
+//! 800-90B (repetition count and adaptive proportion), assuming a
+//! min-entropy of `MIN_ENTROPY_PER_BYTE` bits per byte. If a test fails the
+//! entropy source is considered broken: the DRBG drops its state and returns
+//! FAIL from `get()` until the next reboot.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let drbg = static_init!(
+//!     capsules::drbg::HmacDrbg<'static>,
+//!     capsules::drbg::HmacDrbg::new(&sam4l::trng::TRNG, dynamic_deferred_caller)
+//! );
+//! drbg.initialize_callback_handle(
+//!     dynamic_deferred_caller.register(drbg).expect("no deferred call slot available for DRBG"),
+//! );
+//! let rng = static_init!(
+//!     capsules::rng::RngDriver<'static>,
+//!     capsules::rng::RngDriver::new(drbg, board_kernel.create_grant(&grant_cap))
+//! );
+//! drbg.set_client(rng);
+//! ```
+
+use crate::sha::{HmacSha256State, SHA256_DIGEST_LEN};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::entropy;
+use kernel::hil::entropy::Entropy32;
+use kernel::hil::rng;
+use kernel::ReturnCode;
+
+/// Number of generate requests between reseeds. SP 800-90A allows up to
+/// 2^48; reseeding more often limits what a compromised state reveals.
+pub const RESEED_INTERVAL: usize = 1024;
+
+/// Most bytes a single generate request may return.
+pub const MAX_REQUEST_LEN: usize = 1 << 16;
+
+/// Min-entropy assumed for each byte from the entropy source, in bits.
+pub const MIN_ENTROPY_PER_BYTE: usize = 4;
+
+// Bytes of entropy input for 256 bits of entropy, and of nonce for 128 bits
+const ENTROPY_INPUT_LEN: usize = 256 / MIN_ENTROPY_PER_BYTE;
+const NONCE_LEN: usize = 128 / MIN_ENTROPY_PER_BYTE;
+const SEED_LEN: usize = ENTROPY_INPUT_LEN + NONCE_LEN;
+
+// Cutoffs for a false positive rate of 2^-20 (SP 800-90B 4.4.1 and 4.4.2):
+// 1 + ceil(20 / H) repetitions, and 1 + CRITBINOM(512, 2^-H, 1 - 2^-20)
+// matches in a window of 512 samples, with H = 4.
+const REPETITION_CUTOFF: usize = 6;
+const PROPORTION_WINDOW: usize = 512;
+const PROPORTION_CUTOFF: usize = 62;
+
+/// State of an HMAC_DRBG instance with SHA-256.
+#[derive(Copy, Clone)]
+pub struct HmacDrbgState {
+    key: [u8; SHA256_DIGEST_LEN],
+    v: [u8; SHA256_DIGEST_LEN],
+    reseed_counter: usize,
+}
+
+impl HmacDrbgState {
+    /// Instantiates the DRBG from the entropy input, the nonce and an
+    /// optional personalization string.
+    pub fn new(entropy: &[u8], nonce: &[u8], personalization: &[u8]) -> HmacDrbgState {
+        let mut state = HmacDrbgState {
+            key: [0x00; SHA256_DIGEST_LEN],
+            v: [0x01; SHA256_DIGEST_LEN],
+            reseed_counter: 1,
+        };
+        state.update(&[entropy, nonce, personalization]);
+        state
+    }
+
+    /// Mixes fresh entropy and optional additional input into the state.
+    pub fn reseed(&mut self, entropy: &[u8], additional: &[u8]) {
+        self.update(&[entropy, additional]);
+        self.reseed_counter = 1;
+    }
+
+    /// Whether `generate` will refuse to run until the next reseed.
+    pub fn needs_reseed(&self) -> bool {
+        self.reseed_counter > RESEED_INTERVAL
+    }
+
+    /// Fills `output` with random bytes, after mixing in the optional
+    /// additional input.
+    ///
+    /// Returns FAIL if the DRBG must be reseeded first, and ESIZE if
+    /// `output` is longer than `MAX_REQUEST_LEN`.
+    pub fn generate(&mut self, output: &mut [u8], additional: &[u8]) -> ReturnCode {
+        if self.needs_reseed() {
+            return ReturnCode::FAIL;
+        }
+        if output.len() > MAX_REQUEST_LEN {
+            return ReturnCode::ESIZE;
+        }
+        if !additional.is_empty() {
+            self.update(&[additional]);
+        }
+        for chunk in output.chunks_mut(SHA256_DIGEST_LEN) {
+            self.v = self.hmac(&[&self.v]);
+            chunk.copy_from_slice(&self.v[..chunk.len()]);
+        }
+        self.update(&[additional]);
+        self.reseed_counter += 1;
+        ReturnCode::SUCCESS
+    }
+
+    fn hmac(&self, data: &[&[u8]]) -> [u8; SHA256_DIGEST_LEN] {
+        let mut mac = [0; SHA256_DIGEST_LEN];
+        let mut hmac = HmacSha256State::new(&self.key);
+        for d in data {
+            hmac.update(d);
+        }
+        hmac.finish(&mut mac);
+        mac
+    }
+
+    /// HMAC_DRBG_Update with the concatenation of `provided`.
+    fn update(&mut self, provided: &[&[u8]]) {
+        let empty = provided.iter().all(|data| data.is_empty());
+        for round in [0x00u8, 0x01].iter() {
+            let mut hmac = HmacSha256State::new(&self.key);
+            hmac.update(&self.v);
+            hmac.update(&[*round]);
+            for data in provided {
+                hmac.update(data);
+            }
+            hmac.finish(&mut self.key);
+            self.v = self.hmac(&[&self.v]);
+            if empty {
+                break;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.key = [0; SHA256_DIGEST_LEN];
+        self.v = [0; SHA256_DIGEST_LEN];
+    }
+}
+
+/// Continuous health tests of SP 800-90B on bytes of raw entropy.
+#[derive(Copy, Clone, Default)]
+pub struct HealthTests {
+    // Repetition count test
+    last: u8,
+    repetitions: usize,
+    // Adaptive proportion test
+    first: u8,
+    matches: usize,
+    seen: usize,
+}
+
+impl HealthTests {
+    /// Feeds the next sample to the tests. Returns false if a test failed.
+    pub fn check(&mut self, sample: u8) -> bool {
+        if self.repetitions > 0 && sample == self.last {
+            self.repetitions += 1;
+        } else {
+            self.last = sample;
+            self.repetitions = 1;
+        }
+
+        if self.seen == 0 {
+            self.first = sample;
+            self.matches = 1;
+        } else if sample == self.first {
+            self.matches += 1;
+        }
+        self.seen = (self.seen + 1) % PROPORTION_WINDOW;
+
+        self.repetitions < REPETITION_CUTOFF && self.matches < PROPORTION_CUTOFF
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Status {
+    Idle,
+    /// Collecting entropy for instantiation or a reseed
+    Seeding,
+    /// A health test failed
+    Failed,
+}
+
+/// Output of one generate request, handed out a word at a time.
+const OUTPUT_LEN: usize = SHA256_DIGEST_LEN;
+
+pub struct HmacDrbg<'a> {
+    entropy: &'a dyn Entropy32<'a>,
+    client: OptionalCell<&'a dyn rng::Client>,
+    // Empty until the first seed has been collected
+    drbg: MapCell<HmacDrbgState>,
+    status: Cell<Status>,
+    // Whether the client is waiting for randomness
+    requested: Cell<bool>,
+
+    seed: MapCell<[u8; SEED_LEN]>,
+    seed_length: Cell<usize>,
+    health: Cell<HealthTests>,
+
+    output: MapCell<[u8; OUTPUT_LEN]>,
+    output_position: Cell<usize>,
+
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> HmacDrbg<'a> {
+    pub fn new(
+        entropy: &'a dyn Entropy32<'a>,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> HmacDrbg<'a> {
+        HmacDrbg {
+            entropy: entropy,
+            client: OptionalCell::empty(),
+            drbg: MapCell::empty(),
+            status: Cell::new(Status::Idle),
+            requested: Cell::new(false),
+            seed: MapCell::new([0; SEED_LEN]),
+            seed_length: Cell::new(0),
+            health: Cell::new(HealthTests::default()),
+            output: MapCell::new([0; OUTPUT_LEN]),
+            output_position: Cell::new(OUTPUT_LEN),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    /// Initializes a callback handle for deferred callbacks.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn needs_seed(&self) -> bool {
+        self.drbg.map_or(true, |drbg| drbg.needs_reseed())
+    }
+
+    fn start_seeding(&self) -> ReturnCode {
+        self.status.set(Status::Seeding);
+        self.seed_length.set(0);
+        let res = self.entropy.get();
+        if res != ReturnCode::SUCCESS {
+            self.status.set(Status::Idle);
+        }
+        res
+    }
+
+    fn fail_request(&self, error: ReturnCode) {
+        if self.requested.get() {
+            self.requested.set(false);
+            self.client
+                .map(|client| client.randomness_available(&mut core::iter::empty(), error));
+        }
+    }
+
+    /// Gives the client as much output as it wants, until a reseed is due.
+    fn deliver(&self) {
+        if !self.requested.get() {
+            return;
+        }
+        let more = self.client.map_or(rng::Continue::Done, |client| {
+            client.randomness_available(&mut HmacDrbgIter(self), ReturnCode::SUCCESS)
+        });
+        // Don't keep output around for later requests
+        self.output.map(|output| *output = [0; OUTPUT_LEN]);
+        self.output_position.set(OUTPUT_LEN);
+
+        match more {
+            rng::Continue::Done => self.requested.set(false),
+            rng::Continue::More => {
+                if self.needs_seed() {
+                    let res = self.start_seeding();
+                    if res != ReturnCode::SUCCESS {
+                        self.fail_request(res);
+                    }
+                } else {
+                    self.handle.map(|handle| self.deferred_caller.set(*handle));
+                }
+            }
+        }
+    }
+
+    /// Runs the next generate request, returning false if a reseed is due.
+    fn refill(&self) -> bool {
+        let res = self.drbg.map_or(ReturnCode::FAIL, |drbg| {
+            self.output
+                .map_or(ReturnCode::FAIL, |output| drbg.generate(output, &[]))
+        });
+        self.output_position.set(0);
+        res == ReturnCode::SUCCESS
+    }
+}
+
+struct HmacDrbgIter<'a, 'b: 'a>(&'a HmacDrbg<'b>);
+
+impl Iterator for HmacDrbgIter<'_, '_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.0.output_position.get() >= OUTPUT_LEN && !self.0.refill() {
+            self.0.output_position.set(OUTPUT_LEN);
+            return None;
+        }
+        let position = self.0.output_position.get();
+        self.0.output_position.set(position + 4);
+        self.0.output.map(|output| {
+            u32::from_le_bytes([
+                output[position],
+                output[position + 1],
+                output[position + 2],
+                output[position + 3],
+            ])
+        })
+    }
+}
+
+impl<'a> rng::Rng<'a> for HmacDrbg<'a> {
+    fn get(&self) -> ReturnCode {
+        match self.status.get() {
+            Status::Failed => ReturnCode::FAIL,
+            Status::Seeding => {
+                self.requested.set(true);
+                ReturnCode::SUCCESS
+            }
+            Status::Idle => {
+                let res = if self.needs_seed() {
+                    self.start_seeding()
+                } else {
+                    self.handle.map(|handle| self.deferred_caller.set(*handle));
+                    ReturnCode::SUCCESS
+                };
+                if res == ReturnCode::SUCCESS {
+                    self.requested.set(true);
+                }
+                res
+            }
+        }
+    }
+
+    fn cancel(&self) -> ReturnCode {
+        // A seed being collected is still used for the next request
+        self.requested.set(false);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_client(&'a self, client: &'a dyn rng::Client) {
+        self.entropy.set_client(self);
+        self.client.set(client);
+    }
+}
+
+impl entropy::Client32 for HmacDrbg<'_> {
+    fn entropy_available(
+        &self,
+        entropy: &mut dyn Iterator<Item = u32>,
+        error: ReturnCode,
+    ) -> entropy::Continue {
+        if self.status.get() != Status::Seeding {
+            return entropy::Continue::Done;
+        }
+        if error != ReturnCode::SUCCESS {
+            self.status.set(Status::Idle);
+            self.fail_request(error);
+            return entropy::Continue::Done;
+        }
+
+        let needed = if self.drbg.is_some() {
+            ENTROPY_INPUT_LEN
+        } else {
+            SEED_LEN
+        };
+        let mut length = self.seed_length.get();
+        let mut health = self.health.get();
+        let mut healthy = true;
+        self.seed.map(|seed| {
+            for word in entropy {
+                for byte in word.to_le_bytes().iter() {
+                    healthy = health.check(*byte);
+                    if !healthy {
+                        return;
+                    }
+                    if length < needed {
+                        seed[length] = *byte;
+                        length += 1;
+                    }
+                }
+                if length == needed {
+                    return;
+                }
+            }
+        });
+        self.health.set(health);
+        self.seed_length.set(length);
+
+        if !healthy {
+            self.status.set(Status::Failed);
+            self.seed.map(|seed| *seed = [0; SEED_LEN]);
+            self.drbg.take().map(|mut drbg| drbg.clear());
+            self.fail_request(ReturnCode::FAIL);
+            return entropy::Continue::Done;
+        }
+        if length < needed {
+            return entropy::Continue::More;
+        }
+
+        self.seed.map(|seed| {
+            let (input, nonce) = seed.split_at(ENTROPY_INPUT_LEN);
+            if self.drbg.map(|drbg| drbg.reseed(input, &[])).is_none() {
+                self.drbg.put(HmacDrbgState::new(input, nonce, &[]));
+            }
+            *seed = [0; SEED_LEN];
+        });
+        self.status.set(Status::Idle);
+        self.deliver();
+        entropy::Continue::Done
+    }
+}
+
+impl DynamicDeferredCallClient for HmacDrbg<'_> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.status.get() == Status::Idle {
+            self.deliver();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unhex(hex: &str, out: &mut [u8]) {
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+    }
+
+    // NIST CAVP HMAC_DRBG.rsp, SHA-256, no prediction resistance, no
+    // personalization string or additional input, COUNT = 0
+    #[test]
+    fn cavp_no_additional_input() {
+        let mut entropy = [0; 32];
+        let mut nonce = [0; 16];
+        let mut expected = [0; 128];
+        unhex(
+            "ca851911349384bffe89de1cbdc46e6831e44d34a4fb935ee285dd14b71a7488",
+            &mut entropy,
+        );
+        unhex("659ba96c601dc69fc902940805ec0ca8", &mut nonce);
+        unhex(
+            "e528e9abf2dece54d47c7e75e5fe302149f817ea9fb4bee6f4199697d04d5b89\
+             d54fbb978a15b5c443c9ec21036d2460b6f73ebad0dc2aba6e624abf07745bc1\
+             07694bb7547bb0995f70de25d6b29e2d3011bb19d27676c07162c8b5ccde0668\
+             961df86803482cb37ed6d5c0bb8d50cf1f50d476aa0458bdaba806f48be9dcb8",
+            &mut expected,
+        );
+
+        let mut drbg = HmacDrbgState::new(&entropy, &nonce, &[]);
+        let mut output = [0; 128];
+        assert_eq!(drbg.generate(&mut output, &[]), ReturnCode::SUCCESS);
+        assert_eq!(drbg.generate(&mut output, &[]), ReturnCode::SUCCESS);
+        assert_eq!(output[..], expected[..]);
+    }
+
+    // Personalization string, reseed and additional input, against the
+    // reference implementation that passes the vector above
+    #[test]
+    fn reseed_and_additional_input() {
+        let mut entropy = [0; 32];
+        let mut nonce = [0; 16];
+        let mut expected = [0; 40];
+        unhex(
+            "ca851911349384bffe89de1cbdc46e6831e44d34a4fb935ee285dd14b71a7488",
+            &mut entropy,
+        );
+        unhex("659ba96c601dc69fc902940805ec0ca8", &mut nonce);
+        unhex(
+            "db683ea814d6dcf9638a259516b81008ecd45af548cff15f6c32ed2a0ee4d8b6\
+             c01ea5d1b376be9a",
+            &mut expected,
+        );
+
+        let mut drbg = HmacDrbgState::new(&entropy, &nonce, b"personal");
+        let mut reseed_entropy = [0; 32];
+        for (i, b) in reseed_entropy.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        drbg.reseed(&reseed_entropy, b"tock");
+        let mut output = [0; 40];
+        assert_eq!(drbg.generate(&mut output, b"abc"), ReturnCode::SUCCESS);
+        assert_eq!(output[..], expected[..]);
+    }
+
+    #[test]
+    fn reseed_interval() {
+        let mut drbg = HmacDrbgState::new(&[0; 32], &[0; 16], &[]);
+        let mut output = [0; 4];
+        for _ in 0..RESEED_INTERVAL {
+            assert_eq!(drbg.generate(&mut output, &[]), ReturnCode::SUCCESS);
+        }
+        assert!(drbg.needs_reseed());
+        assert_eq!(drbg.generate(&mut output, &[]), ReturnCode::FAIL);
+        drbg.reseed(&[1; 32], &[]);
+        assert_eq!(drbg.generate(&mut output, &[]), ReturnCode::SUCCESS);
+    }
+
+    #[test]
+    fn health_tests() {
+        // A counter has no repetitions and spreads evenly
+        let mut health = HealthTests::default();
+        assert!((0..4096).all(|i| health.check(i as u8)));
+
+        // A stuck source fails the repetition count test
+        let mut health = HealthTests::default();
+        assert!((1..REPETITION_CUTOFF).all(|_| health.check(0x55)));
+        assert!(!health.check(0x55));
+
+        // A biased source fails the adaptive proportion test
+        let mut health = HealthTests::default();
+        let results: usize = (0..PROPORTION_WINDOW)
+            .map(|i| health.check(if i % 2 == 0 { 0xaa } else { i as u8 }) as usize)
+            .sum();
+        assert!(results < PROPORTION_WINDOW);
+    }
+}
@@ -26,21 +26,31 @@ pub enum NUM {
     I2cMaster             = 0x20003,
     UsbUser               = 0x20005,
     I2cMasterSlave        = 0x20006,
+    UsbHid                = 0x20007,
 
     // Radio
     BleAdvertising        = 0x30000,
     Ieee802154            = 0x30001,
     Udp                   = 0x30002,
+    Coap                  = 0x30003,
+    LowpanStats           = 0x30004,
+    BlePeripheral         = 0x30005,
 
     // Cryptography
+    Aes                   = 0x40000,
     Rng                   = 0x40001,
     Crc                   = 0x40002,
     Hmac                  = 0x40003,
+    KeyStore              = 0x40004,
 
     // Storage
     AppFlash              = 0x50000,
     NvmStorage            = 0x50001,
     SdCard                = 0x50002,
+    KvStore               = 0x50003,
+    Log                   = 0x50004,
+    Fat                   = 0x50005,
+    FirmwareUpdate        = 0x50006,
 
     // Sensors
     Temperature           = 0x60000,
@@ -0,0 +1,767 @@
+//! Encrypts and authenticates nonvolatile storage.
+//!
+//! `EncryptedStorage` implements `hil::nonvolatile_storage::NonvolatileStorage`
+//! on top of another instance, so that data written through it, for example
+//! by `nonvolatile_storage_driver`, is stored encrypted with AES-CCM and can't
+//! be read or changed without the key.
+//!
+//! The storage is split into blocks of `BLOCK_SIZE` bytes, each stored in a
+//! physical block of `PHYSICAL_BLOCK_SIZE` bytes with a write counter and an
+//! authentication tag:
+//!
+//! ```text
+//! physical block: counter (8) | ciphertext (BLOCK_SIZE) | tag (TAG_LEN)
+//! nonce:          physical address (4) | counter (8) | 0 (1)
+//! ```
+//!
+//! The counter goes up each time the block is written, so that a nonce is
+//! never used twice for a block, and the nonce holds the address of the
+//! block, so that blocks can't be moved around. Numbers are big endian.
+//!
+//! Only blocks that authenticate are read or written, and a write uses the
+//! counter of the block it replaces plus one, so a block must hold an
+//! authentic record before its first write: `format()` writes every block
+//! with counter 0 and data 0xff. Call it once, when the key is provisioned,
+//! and never again with the same key, since it starts the counters over.
+//! Erased or otherwise damaged blocks, including blocks whose write was cut
+//! off by a power loss, then fail to authenticate and can't be used until
+//! the storage is formatted with a new key; put `ftl` under this layer if
+//! writes must survive power losses. The counters are stored with the data,
+//! so putting back an older authentic copy of a block isn't detected, and
+//! the next write of that block reuses a nonce: keep the storage out of
+//! reach of anyone who could do that.
+//!
+//! Addresses given to this layer start at 0 and go up to `size()`; writing
+//! part of a block reads and decrypts the block first. A read or write stops
+//! at the first block that fails to authenticate, or to be read or written,
+//! and reports the number of bytes done until then.
+//!
+//! Every instance must have its own key, and the key should be unique to the
+//! device, so that copying flash between devices doesn't reveal anything.
+//!
+//! ```text
+//! +--------------------------------------------------------+
+//! |  Nonvolatile storage user (nonvolatile_storage_driver) |
+//! +--------------------------------------------------------+
+//!        hil::nonvolatile_storage::NonvolatileStorage
+//! +--------------------------------------------------------+     +----------+
+//! |     capsules::encrypted_storage::EncryptedStorage      | --> | AES-CCM  |
+//! +--------------------------------------------------------+     +----------+
+//!        hil::nonvolatile_storage::NonvolatileStorage
+//! +--------------------------------------------------------+
+//! |              Storage (NonvolatileToPages)              |
+//! +--------------------------------------------------------+
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let encrypted_storage = static_init!(
+//!     capsules::encrypted_storage::EncryptedStorage<'static, AES128CCM<'static, Aes>>,
+//!     capsules::encrypted_storage::EncryptedStorage::new(
+//!         nv_to_page,
+//!         aes_ccm,
+//!         device_key,  // [u8; 16] unique to the device
+//!         0x60000,     // Start address of the encrypted region
+//!         256,         // Number of blocks
+//!         &mut capsules::encrypted_storage::BUFFER,
+//!     )
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, encrypted_storage);
+//! aes_ccm.set_client(encrypted_storage);
+//! // Only when the device key is provisioned:
+//! encrypted_storage.format(format_buffer);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::symmetric_encryption::{CCMClient, AES128CCM, AES128_KEY_SIZE, CCM_NONCE_LENGTH};
+use kernel::ReturnCode;
+
+/// Bytes of data in a block.
+pub const BLOCK_SIZE: usize = 64;
+/// Length of the authentication tag of a block.
+pub const TAG_LEN: usize = 16;
+const COUNTER_LEN: usize = 8;
+/// Bytes a block takes in the underlying storage.
+pub const PHYSICAL_BLOCK_SIZE: usize = COUNTER_LEN + BLOCK_SIZE + TAG_LEN;
+
+pub static mut BUFFER: [u8; PHYSICAL_BLOCK_SIZE] = [0; PHYSICAL_BLOCK_SIZE];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Read,
+    Write,
+    Format,
+}
+
+/// The read or write in progress.
+#[derive(Clone, Copy)]
+struct Operation {
+    direction: Direction,
+    address: usize,
+    length: usize,
+    /// Bytes read or written so far.
+    done: usize,
+}
+
+impl Operation {
+    fn block(&self) -> usize {
+        (self.address + self.done) / BLOCK_SIZE
+    }
+
+    /// The part of the current block the operation covers.
+    fn block_range(&self) -> core::ops::Range<usize> {
+        let start = (self.address + self.done) % BLOCK_SIZE;
+        start..cmp::min(BLOCK_SIZE, start + self.length - self.done)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+    ReadBlock,
+    Decrypt,
+    Encrypt,
+    WriteBlock,
+}
+
+pub struct EncryptedStorage<'a, A: AES128CCM<'a>> {
+    driver: &'a dyn NonvolatileStorage<'static>,
+    ccm: &'a A,
+    key: [u8; AES128_KEY_SIZE],
+    start_address: usize,
+    blocks: usize,
+    client: OptionalCell<&'static dyn NonvolatileStorageClient<'static>>,
+    client_buffer: TakeCell<'static, [u8]>,
+    /// Holds a physical block.
+    buffer: TakeCell<'static, [u8]>,
+    operation: Cell<Option<Operation>>,
+    step: Cell<Step>,
+    /// The counter of the authentic block being replaced.
+    counter: Cell<u64>,
+}
+
+impl<'a, A: AES128CCM<'a>> EncryptedStorage<'a, A> {
+    /// `blocks` blocks of `PHYSICAL_BLOCK_SIZE` bytes from `start_address`
+    /// in `driver` hold the encrypted data.
+    pub fn new(
+        driver: &'a dyn NonvolatileStorage<'static>,
+        ccm: &'a A,
+        key: [u8; AES128_KEY_SIZE],
+        start_address: usize,
+        blocks: usize,
+        buffer: &'static mut [u8],
+    ) -> EncryptedStorage<'a, A> {
+        EncryptedStorage {
+            driver: driver,
+            ccm: ccm,
+            key: key,
+            start_address: start_address,
+            blocks: blocks,
+            client: OptionalCell::empty(),
+            client_buffer: TakeCell::empty(),
+            buffer: TakeCell::new(buffer),
+            operation: Cell::new(None),
+            step: Cell::new(Step::ReadBlock),
+            counter: Cell::new(0),
+        }
+    }
+
+    /// The number of bytes that can be stored.
+    pub fn size(&self) -> usize {
+        self.blocks * BLOCK_SIZE
+    }
+
+    /// Writes every block with counter 0 and data 0xff, so that blocks can
+    /// be written and read, and reports `size()` bytes written to the
+    /// client, with `buffer`, when done. Must only be done once per key.
+    pub fn format(&self, buffer: &'static mut [u8]) -> ReturnCode {
+        if self.operation.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if self.blocks == 0 {
+            return ReturnCode::EINVAL;
+        }
+        self.client_buffer.replace(buffer);
+        self.operation.set(Some(Operation {
+            direction: Direction::Format,
+            address: 0,
+            length: self.size(),
+            done: 0,
+        }));
+        let result = self.start_block();
+        if result != ReturnCode::SUCCESS {
+            self.operation.set(None);
+        }
+        result
+    }
+
+    fn physical_address(&self, block: usize) -> usize {
+        self.start_address + block * PHYSICAL_BLOCK_SIZE
+    }
+
+    fn start(
+        &self,
+        direction: Direction,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> ReturnCode {
+        if self.operation.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if length == 0 || length > buffer.len() || address + length > self.size() {
+            return ReturnCode::EINVAL;
+        }
+        self.client_buffer.replace(buffer);
+        self.operation.set(Some(Operation {
+            direction: direction,
+            address: address,
+            length: length,
+            done: 0,
+        }));
+        let result = self.start_block();
+        if result != ReturnCode::SUCCESS {
+            self.operation.set(None);
+        }
+        result
+    }
+
+    /// Reads the physical block the operation is at, or goes straight to
+    /// encrypting it when formatting.
+    fn start_block(&self) -> ReturnCode {
+        match self.operation.get() {
+            Some(operation) if operation.direction == Direction::Format => {
+                match self.buffer.take() {
+                    Some(buffer) => self.block_decrypted(buffer),
+                    None => ReturnCode::ERESERVE,
+                }
+            }
+            Some(operation) => match self.driver_call(Step::ReadBlock, operation.block()) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err(result) => result,
+            },
+            None => ReturnCode::FAIL,
+        }
+    }
+
+    /// Reads or writes a physical block.
+    fn driver_call(&self, step: Step, block: usize) -> Result<(), ReturnCode> {
+        let buffer = self.buffer.take().ok_or(ReturnCode::ERESERVE)?;
+        self.step.set(step);
+        let address = self.physical_address(block);
+        let result = match step {
+            Step::WriteBlock => self.driver.write(buffer, address, PHYSICAL_BLOCK_SIZE),
+            _ => self.driver.read(buffer, address, PHYSICAL_BLOCK_SIZE),
+        };
+        match result {
+            ReturnCode::SUCCESS => Ok(()),
+            result => Err(result),
+        }
+    }
+
+    /// Encrypts or decrypts the block in `buffer` with `counter`.
+    fn crypt(&self, buffer: &'static mut [u8], counter: u64, step: Step) -> ReturnCode {
+        let block = self
+            .operation
+            .get()
+            .map_or(0, |operation| operation.block());
+        let mut nonce = [0; CCM_NONCE_LENGTH];
+        nonce[0..4].copy_from_slice(&(self.physical_address(block) as u32).to_be_bytes());
+        nonce[4..12].copy_from_slice(&counter.to_be_bytes());
+        let result = self.ccm.set_key(&self.key);
+        if result != ReturnCode::SUCCESS {
+            self.buffer.replace(buffer);
+            return result;
+        }
+        let result = self.ccm.set_nonce(&nonce);
+        if result != ReturnCode::SUCCESS {
+            self.buffer.replace(buffer);
+            return result;
+        }
+        self.step.set(step);
+        match self.ccm.crypt(
+            buffer,
+            COUNTER_LEN,
+            COUNTER_LEN,
+            BLOCK_SIZE,
+            TAG_LEN,
+            true,
+            step == Step::Encrypt,
+        ) {
+            (ReturnCode::SUCCESS, _) => ReturnCode::SUCCESS,
+            (result, buffer) => {
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                result
+            }
+        }
+    }
+
+    /// Continues the operation once the plaintext of the current block is in
+    /// `buffer`.
+    fn block_decrypted(&self, buffer: &'static mut [u8]) -> ReturnCode {
+        let mut operation = match self.operation.get() {
+            Some(operation) => operation,
+            None => return ReturnCode::FAIL,
+        };
+        let range = operation.block_range();
+        let data = &mut buffer[COUNTER_LEN..COUNTER_LEN + BLOCK_SIZE];
+        let done = operation.done;
+        let len = range.len();
+        match operation.direction {
+            Direction::Read => {
+                self.client_buffer.map(|client_buffer| {
+                    client_buffer[done..done + len].copy_from_slice(&data[range]);
+                });
+                self.buffer.replace(buffer);
+                operation.done += len;
+                self.operation.set(Some(operation));
+                self.next_block()
+            }
+            Direction::Write => {
+                self.client_buffer.map(|client_buffer| {
+                    data[range].copy_from_slice(&client_buffer[done..done + len]);
+                });
+                match self.counter.get().checked_add(1) {
+                    Some(counter) => self.encrypt(buffer, counter),
+                    None => {
+                        self.buffer.replace(buffer);
+                        ReturnCode::FAIL
+                    }
+                }
+            }
+            Direction::Format => {
+                data.iter_mut().for_each(|byte| *byte = 0xff);
+                self.encrypt(buffer, 0)
+            }
+        }
+    }
+
+    fn encrypt(&self, buffer: &'static mut [u8], counter: u64) -> ReturnCode {
+        buffer[..COUNTER_LEN].copy_from_slice(&counter.to_be_bytes());
+        self.crypt(buffer, counter, Step::Encrypt)
+    }
+
+    /// Moves to the next block, or ends the operation.
+    fn next_block(&self) -> ReturnCode {
+        match self.operation.get() {
+            Some(operation) if operation.done < operation.length => self.start_block(),
+            _ => {
+                self.finish();
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    /// Ends the operation, reporting the bytes done so far.
+    fn finish(&self) {
+        self.operation.take().map(|operation| {
+            self.client_buffer.take().map(|buffer| {
+                self.client.map(move |client| match operation.direction {
+                    Direction::Read => client.read_done(buffer, operation.done),
+                    Direction::Write | Direction::Format => {
+                        client.write_done(buffer, operation.done)
+                    }
+                });
+            });
+        });
+    }
+
+    fn check(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.finish();
+        }
+    }
+}
+
+impl<'a, A: AES128CCM<'a>> NonvolatileStorage<'static> for EncryptedStorage<'a, A> {
+    fn set_client(&self, client: &'static dyn NonvolatileStorageClient<'static>) {
+        self.client.set(client);
+    }
+
+    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.start(Direction::Read, buffer, address, length)
+    }
+
+    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+        self.start(Direction::Write, buffer, address, length)
+    }
+}
+
+impl<'a, A: AES128CCM<'a>> NonvolatileStorageClient<'static> for EncryptedStorage<'a, A> {
+    fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+        if self.operation.get().is_none() || length < PHYSICAL_BLOCK_SIZE {
+            self.buffer.replace(buffer);
+            self.finish();
+            return;
+        }
+        // The counter is only used for a write once the block authenticates.
+        let mut counter = [0; COUNTER_LEN];
+        counter.copy_from_slice(&buffer[..COUNTER_LEN]);
+        let counter = u64::from_be_bytes(counter);
+        self.counter.set(counter);
+        let result = self.crypt(buffer, counter, Step::Decrypt);
+        self.check(result);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        let result = match self.operation.get() {
+            Some(mut operation) if length == PHYSICAL_BLOCK_SIZE => {
+                operation.done += operation.block_range().len();
+                self.operation.set(Some(operation));
+                self.next_block()
+            }
+            _ => ReturnCode::FAIL,
+        };
+        self.check(result);
+    }
+}
+
+impl<'a, A: AES128CCM<'a>> CCMClient for EncryptedStorage<'a, A> {
+    fn crypt_done(&self, buffer: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+        let result = match self.step.get() {
+            Step::Decrypt if res == ReturnCode::SUCCESS && tag_is_valid => {
+                self.block_decrypted(buffer)
+            }
+            Step::Encrypt if res == ReturnCode::SUCCESS => {
+                self.buffer.replace(buffer);
+                let block = self
+                    .operation
+                    .get()
+                    .map_or(0, |operation| operation.block());
+                match self.driver_call(Step::WriteBlock, block) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err(result) => result,
+                }
+            }
+            _ => {
+                self.buffer.replace(buffer);
+                ReturnCode::FAIL
+            }
+        };
+        self.check(result);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::aes_ccm;
+    use crate::test_util::SimAes;
+    use core::cell::RefCell;
+    use kernel::hil::symmetric_encryption::AES128;
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    const TEST_BLOCKS: usize = 4;
+
+    type Ccm = aes_ccm::AES128CCM<'static, SimAes<'static>>;
+
+    /// Storage in RAM that completes operations when `step` is called.
+    struct TestStorage {
+        data: RefCell<Vec<u8>>,
+        client: OptionalCell<&'static dyn NonvolatileStorageClient<'static>>,
+        pending: Cell<Option<(bool, usize)>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl TestStorage {
+        fn step(&self) -> bool {
+            match self.pending.take() {
+                Some((write, length)) => {
+                    self.buffer.take().map(|buffer| {
+                        self.client.map(move |client| {
+                            if write {
+                                client.write_done(buffer, length)
+                            } else {
+                                client.read_done(buffer, length)
+                            }
+                        })
+                    });
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    impl NonvolatileStorage<'static> for TestStorage {
+        fn set_client(&self, client: &'static dyn NonvolatileStorageClient<'static>) {
+            self.client.set(client);
+        }
+
+        fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+            buffer[..length].copy_from_slice(&self.data.borrow()[address..address + length]);
+            self.buffer.replace(buffer);
+            self.pending.set(Some((false, length)));
+            ReturnCode::SUCCESS
+        }
+
+        fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+            self.data.borrow_mut()[address..address + length].copy_from_slice(&buffer[..length]);
+            self.buffer.replace(buffer);
+            self.pending.set(Some((true, length)));
+            ReturnCode::SUCCESS
+        }
+    }
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        length: Cell<Option<usize>>,
+    }
+
+    impl NonvolatileStorageClient<'static> for TestClient {
+        fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.length.set(Some(length));
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.length.set(Some(length));
+        }
+    }
+
+    struct Harness {
+        storage: &'static TestStorage,
+        aes: &'static SimAes<'static>,
+        encrypted: &'static EncryptedStorage<'static, Ccm>,
+        client: &'static TestClient,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let storage: &'static TestStorage = Box::leak(Box::new(TestStorage {
+                data: RefCell::new(std::vec![0xff; 16 + TEST_BLOCKS * PHYSICAL_BLOCK_SIZE]),
+                client: OptionalCell::empty(),
+                pending: Cell::new(None),
+                buffer: TakeCell::empty(),
+            }));
+            let aes: &'static SimAes<'static> = Box::leak(Box::new(SimAes::new()));
+            let ccm: &'static Ccm = Box::leak(Box::new(aes_ccm::AES128CCM::new(
+                aes,
+                Box::leak(Box::new([0; 128])),
+            )));
+            let encrypted = Box::leak(Box::new(EncryptedStorage::new(
+                storage,
+                ccm,
+                [0x5a; AES128_KEY_SIZE],
+                16,
+                TEST_BLOCKS,
+                Box::leak(Box::new([0; PHYSICAL_BLOCK_SIZE])),
+            )));
+            let client: &'static TestClient = Box::leak(Box::new(TestClient {
+                buffer: TakeCell::new(Box::leak(Box::new([0; TEST_BLOCKS * BLOCK_SIZE]))),
+                length: Cell::new(None),
+            }));
+            storage.set_client(encrypted);
+            aes.set_client(ccm);
+            ccm.set_client(encrypted);
+            encrypted.set_client(client);
+            Harness {
+                storage: storage,
+                aes: aes,
+                encrypted: encrypted,
+                client: client,
+            }
+        }
+
+        /// A harness over formatted storage.
+        fn formatted() -> Harness {
+            let harness = Harness::new();
+            assert_eq!(harness.format(), Some(TEST_BLOCKS * BLOCK_SIZE));
+            harness
+        }
+
+        fn run(&self, result: ReturnCode) -> Option<usize> {
+            assert_eq!(result, ReturnCode::SUCCESS);
+            while self.storage.step() || self.aes.step() {}
+            self.client.length.take()
+        }
+
+        fn format(&self) -> Option<usize> {
+            let buffer = self.client.buffer.take().unwrap();
+            self.run(self.encrypted.format(buffer))
+        }
+
+        fn write(&self, address: usize, data: &[u8]) -> Option<usize> {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            self.run(self.encrypted.write(buffer, address, data.len()))
+        }
+
+        fn read(&self, address: usize, length: usize) -> (Option<usize>, Vec<u8>) {
+            let buffer = self.client.buffer.take().unwrap();
+            let length = self.run(self.encrypted.read(buffer, address, length));
+            let data = self
+                .client
+                .buffer
+                .map(|buffer| buffer[..length.unwrap_or(0)].to_vec());
+            (length, data.unwrap())
+        }
+
+        fn physical_block(&self, block: usize) -> Vec<u8> {
+            let start = 16 + block * PHYSICAL_BLOCK_SIZE;
+            self.storage.data.borrow()[start..start + PHYSICAL_BLOCK_SIZE].to_vec()
+        }
+
+        fn set_physical_block(&self, block: usize, data: &[u8]) {
+            let start = 16 + block * PHYSICAL_BLOCK_SIZE;
+            self.storage.data.borrow_mut()[start..start + PHYSICAL_BLOCK_SIZE]
+                .copy_from_slice(data);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let harness = Harness::formatted();
+        assert_eq!(harness.encrypted.size(), TEST_BLOCKS * BLOCK_SIZE);
+
+        // Blocks not written since the format read like erased flash.
+        assert_eq!(harness.read(0, 10), (Some(10), std::vec![0xff; 10]));
+
+        // Spans three blocks, starting and ending partway through blocks.
+        let data: Vec<u8> = (0..150).map(|i| i as u8).collect();
+        assert_eq!(harness.write(20, &data), Some(150));
+        assert_eq!(harness.read(20, 150), (Some(150), data.clone()));
+        let (_, around) = harness.read(0, 3 * BLOCK_SIZE);
+        assert_eq!(&around[..20], &[0xff; 20][..]);
+        assert_eq!(&around[20..170], &data[..]);
+        assert_eq!(&around[170..], &[0xff; 3 * BLOCK_SIZE - 170][..]);
+
+        // The data isn't stored in the clear.
+        let stored: Vec<u8> = (0..3)
+            .flat_map(|block| harness.physical_block(block))
+            .collect();
+        assert!(!stored.windows(8).any(|window| window == &data[40..48]));
+
+        assert_eq!(
+            harness
+                .encrypted
+                .read(harness.client.buffer.take().unwrap(), 200, 100),
+            ReturnCode::EINVAL
+        );
+    }
+
+    #[test]
+    fn nonces_change() {
+        let harness = Harness::formatted();
+        let data = [0x42; BLOCK_SIZE];
+        assert_eq!(harness.write(0, &data), Some(BLOCK_SIZE));
+        let first = harness.physical_block(0);
+        assert_eq!(harness.write(0, &data), Some(BLOCK_SIZE));
+        let second = harness.physical_block(0);
+        assert_eq!(&first[..COUNTER_LEN], &1u64.to_be_bytes());
+        assert_eq!(&second[..COUNTER_LEN], &2u64.to_be_bytes());
+        assert_ne!(first[COUNTER_LEN..], second[COUNTER_LEN..]);
+
+        // The same data in another block is encrypted differently too.
+        assert_eq!(harness.write(BLOCK_SIZE, &data), Some(BLOCK_SIZE));
+        assert_ne!(
+            harness.physical_block(1)[COUNTER_LEN..],
+            first[COUNTER_LEN..]
+        );
+    }
+
+    #[test]
+    fn tampering() {
+        let harness = Harness::formatted();
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect();
+        assert_eq!(harness.write(0, &data), Some(2 * BLOCK_SIZE));
+
+        // A changed byte in the second block stops reads there.
+        harness.storage.data.borrow_mut()[16 + PHYSICAL_BLOCK_SIZE + 20] ^= 1;
+        assert_eq!(
+            harness.read(0, 2 * BLOCK_SIZE),
+            (Some(BLOCK_SIZE), data[..BLOCK_SIZE].to_vec())
+        );
+        // Neither part nor all of it can be written.
+        assert_eq!(harness.write(BLOCK_SIZE + 4, &[1; 4]), Some(0));
+        assert_eq!(harness.write(BLOCK_SIZE, &[1; BLOCK_SIZE]), Some(0));
+
+        // Blocks can't be moved.
+        let first = harness.physical_block(0);
+        harness.storage.data.borrow_mut()[16 + PHYSICAL_BLOCK_SIZE..16 + 2 * PHYSICAL_BLOCK_SIZE]
+            .copy_from_slice(&first);
+        assert_eq!(harness.read(BLOCK_SIZE, 8).0, Some(0));
+    }
+
+    #[test]
+    fn unformatted_blocks_are_refused() {
+        let harness = Harness::new();
+        assert_eq!(harness.read(0, 10).0, Some(0));
+        assert_eq!(harness.write(0, &[0x42; BLOCK_SIZE]), Some(0));
+        assert_eq!(
+            harness.physical_block(0),
+            std::vec![0xff; PHYSICAL_BLOCK_SIZE]
+        );
+
+        // Formatting writes an authentic record in every block.
+        assert_eq!(harness.format(), Some(TEST_BLOCKS * BLOCK_SIZE));
+        for block in 0..TEST_BLOCKS {
+            let physical = harness.physical_block(block);
+            assert_eq!(&physical[..COUNTER_LEN], &0u64.to_be_bytes());
+            assert_ne!(
+                physical[COUNTER_LEN..],
+                [0xff; PHYSICAL_BLOCK_SIZE - COUNTER_LEN][..]
+            );
+        }
+        assert_eq!(
+            harness.read(0, TEST_BLOCKS * BLOCK_SIZE),
+            (
+                Some(TEST_BLOCKS * BLOCK_SIZE),
+                std::vec![0xff; TEST_BLOCKS * BLOCK_SIZE]
+            )
+        );
+    }
+
+    #[test]
+    fn erased_blocks_are_refused() {
+        let harness = Harness::formatted();
+        let data = [0x42; BLOCK_SIZE];
+        assert_eq!(harness.write(BLOCK_SIZE, &data), Some(BLOCK_SIZE));
+        assert_eq!(harness.write(BLOCK_SIZE, &data), Some(BLOCK_SIZE));
+        let written = harness.physical_block(1);
+
+        // Erasing the block doesn't bring back counter 0: it can't be read
+        // or written any more.
+        harness.set_physical_block(1, &[0xff; PHYSICAL_BLOCK_SIZE]);
+        assert_eq!(harness.read(BLOCK_SIZE, 10).0, Some(0));
+        assert_eq!(harness.write(BLOCK_SIZE + 4, &[1; 4]), Some(0));
+        assert_eq!(harness.write(BLOCK_SIZE, &[1; BLOCK_SIZE]), Some(0));
+        assert_eq!(
+            harness.physical_block(1),
+            std::vec![0xff; PHYSICAL_BLOCK_SIZE]
+        );
+
+        // Nor does a counter changed to 0.
+        let mut reset = written.clone();
+        reset[..COUNTER_LEN].copy_from_slice(&0u64.to_be_bytes());
+        harness.set_physical_block(1, &reset);
+        assert_eq!(harness.read(BLOCK_SIZE, 10).0, Some(0));
+        assert_eq!(harness.write(BLOCK_SIZE, &[1; BLOCK_SIZE]), Some(0));
+
+        // With the authentic block back, writing goes on from its counter.
+        harness.set_physical_block(1, &written);
+        assert_eq!(
+            harness.read(BLOCK_SIZE, BLOCK_SIZE),
+            (Some(BLOCK_SIZE), data.to_vec())
+        );
+        assert_eq!(
+            harness.write(BLOCK_SIZE, &[1; BLOCK_SIZE]),
+            Some(BLOCK_SIZE)
+        );
+        assert_eq!(
+            &harness.physical_block(1)[..COUNTER_LEN],
+            &3u64.to_be_bytes()
+        );
+    }
+}
@@ -0,0 +1,1960 @@
+//! FAT16 and FAT32 filesystem on a block device, such as an SD card.
+//!
+//! The filesystem runs on any `hil::block_storage::BlockStorage` device with
+//! 512-byte blocks. It reads and writes files and directories by path, one
+//! operation at a time, using a single 512-byte block buffer. Files can be
+//! read from any offset, appended to, and deleted, and directories can be
+//! created, listed and deleted when empty. The volume may start at the first
+//! block of the device or be the first partition of an MBR partition table.
+//!
+//! Only 8.3 names are supported. Long file name entries are skipped, and
+//! deleting a file leaves its long name entries behind as orphans, which
+//! other systems ignore. Directories don't grow, so a directory can
+//! hold as many entries as fit in its first cluster, or in the root
+//! directory region of FAT16. Dates and times are left at zero.
+//!
+//! Appends write the data before updating the directory entry, so if power
+//! is lost the file keeps its old size, and at worst clusters are leaked.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let fat = static_init!(
+//!     capsules::fat::FatFs<'static>,
+//!     capsules::fat::FatFs::new(sdcard, &mut capsules::fat::BUFFER)
+//! );
+//! kernel::hil::block_storage::BlockStorage::set_client(sdcard, fat);
+//! fat.set_client(fat_driver);
+//! sdcard.initialize();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+use kernel::ReturnCode;
+
+/// Size of the blocks of the device.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Block buffer for the filesystem.
+pub static mut BUFFER: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+/// Most directories in a path.
+pub const MAX_DEPTH: usize = 8;
+
+/// Length of a short name in a directory entry: 8 for the name, 3 for the
+/// extension.
+const NAME_LEN: usize = 11;
+const DIR_ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_BLOCK: usize = BLOCK_SIZE / DIR_ENTRY_SIZE;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+const DELETED: u8 = 0xe5;
+
+/// Callbacks for filesystem operations.
+///
+/// Results are ENOSUPPORT if the path doesn't exist, EINVAL if it names a
+/// directory where a file is needed or the other way around, and FAIL if
+/// the device fails or the filesystem is damaged.
+pub trait FatClient {
+    fn mount_done(&self, result: ReturnCode);
+
+    /// `length` bytes were read into `buffer`, fewer than asked for at the
+    /// end of the file.
+    fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize);
+
+    /// `length` bytes of `buffer` were appended. ENOMEM means the disk or
+    /// the directory is full.
+    fn append_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize);
+
+    /// FAIL means the directory isn't empty.
+    fn delete_done(&self, result: ReturnCode);
+
+    /// SUCCESS if the directory already exists.
+    fn create_dir_done(&self, result: ReturnCode);
+
+    fn stat_done(&self, result: ReturnCode, info: FileInfo);
+
+    /// FAIL means there are no more entries.
+    fn read_dir_done(&self, result: ReturnCode, info: FileInfo);
+}
+
+/// A file or directory.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct FileInfo {
+    /// Name, such as `NOTES.TXT`.
+    pub name: [u8; 12],
+    pub name_len: usize,
+    pub size: u32,
+    pub directory: bool,
+}
+
+impl FileInfo {
+    fn from_entry(entry: &DirEntry) -> FileInfo {
+        let mut info = FileInfo {
+            size: entry.size,
+            directory: entry.is_dir(),
+            ..FileInfo::default()
+        };
+        let trimmed = |name: &[u8]| name.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+        let base = trimmed(&entry.name[..8]);
+        let extension = trimmed(&entry.name[8..]);
+        info.name[..base].copy_from_slice(&entry.name[..base]);
+        info.name_len = base;
+        if extension > 0 {
+            info.name[base] = b'.';
+            info.name[base + 1..base + 1 + extension]
+                .copy_from_slice(&entry.name[8..8 + extension]);
+            info.name_len += 1 + extension;
+        }
+        info
+    }
+}
+
+/// A path of short names, from the root directory.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Path {
+    names: [[u8; NAME_LEN]; MAX_DEPTH],
+    len: usize,
+}
+
+impl Path {
+    /// The root directory.
+    pub fn new() -> Path {
+        Path::default()
+    }
+
+    /// Appends the names in `path`, such as `logs/today.txt`, separated by
+    /// `/`. Names are converted to upper case. Returns EINVAL if a name
+    /// isn't a valid 8.3 name, including `.` and `..`, and ESIZE if the
+    /// path is too deep.
+    pub fn push(&mut self, path: &[u8]) -> ReturnCode {
+        for name in path.split(|&c| c == b'/').filter(|name| !name.is_empty()) {
+            if self.len == MAX_DEPTH {
+                return ReturnCode::ESIZE;
+            }
+            match short_name(name) {
+                Some(name) => {
+                    self.names[self.len] = name;
+                    self.len += 1;
+                }
+                None => return ReturnCode::EINVAL,
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// The number of names in the path.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Converts `name` to the form stored in directory entries, padded with
+/// spaces.
+fn short_name(name: &[u8]) -> Option<[u8; NAME_LEN]> {
+    let (base, extension) = match name.iter().position(|&c| c == b'.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, &[][..]),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    let mut short = [b' '; NAME_LEN];
+    for (i, &c) in base.iter().enumerate() {
+        short[i] = short_name_char(c)?;
+    }
+    for (i, &c) in extension.iter().enumerate() {
+        short[8 + i] = short_name_char(c)?;
+    }
+    Some(short)
+}
+
+fn short_name_char(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' | b'0'..=b'9' => Some(c),
+        b'a'..=b'z' => Some(c.to_ascii_uppercase()),
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-' | b'@' | b'^' | b'_'
+        | b'`' | b'{' | b'}' | b'~' => Some(c),
+        _ => None,
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u32 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]]) as u32
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// Layout of a mounted volume. Block numbers are from the start of the
+/// device.
+#[derive(Copy, Clone, Debug)]
+struct Volume {
+    fat_type: FatType,
+    blocks_per_cluster: u32,
+    fat_start: u32,
+    fat_size: u32,
+    num_fats: u32,
+    /// The root directory region of FAT16.
+    root_start: u32,
+    root_blocks: u32,
+    /// The first cluster of the root directory of FAT32.
+    root_cluster: u32,
+    data_start: u32,
+    clusters: u32,
+}
+
+impl Default for Volume {
+    fn default() -> Volume {
+        Volume {
+            fat_type: FatType::Fat16,
+            blocks_per_cluster: 1,
+            fat_start: 0,
+            fat_size: 0,
+            num_fats: 0,
+            root_start: 0,
+            root_blocks: 0,
+            root_cluster: 0,
+            data_start: 0,
+            clusters: 0,
+        }
+    }
+}
+
+impl Volume {
+    /// Reads the boot sector in `block`, which is block number `start`.
+    fn parse(block: &[u8], start: u32) -> Option<Volume> {
+        if block[510] != 0x55 || block[511] != 0xaa || read_u16(block, 11) != BLOCK_SIZE as u32 {
+            return None;
+        }
+        let blocks_per_cluster = block[13] as u32;
+        let reserved = read_u16(block, 14);
+        let num_fats = block[16] as u32;
+        let root_entries = read_u16(block, 17);
+        let total = match read_u16(block, 19) {
+            0 => read_u32(block, 32),
+            total => total,
+        };
+        let fat_size = match read_u16(block, 22) {
+            0 => read_u32(block, 36),
+            size => size,
+        };
+        if !blocks_per_cluster.is_power_of_two() || reserved == 0 || num_fats == 0 {
+            return None;
+        }
+
+        let root_blocks =
+            (root_entries * DIR_ENTRY_SIZE as u32 + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32;
+        let data_start = reserved
+            .checked_add(num_fats.checked_mul(fat_size)?)?
+            .checked_add(root_blocks)?;
+        let clusters = total.checked_sub(data_start)? / blocks_per_cluster;
+        let fat_type = if clusters < 4085 {
+            // FAT12 is not supported
+            return None;
+        } else if clusters < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        let volume = Volume {
+            fat_type: fat_type,
+            blocks_per_cluster: blocks_per_cluster,
+            fat_start: start + reserved,
+            fat_size: fat_size,
+            num_fats: num_fats,
+            root_start: start + reserved + num_fats * fat_size,
+            root_blocks: root_blocks,
+            root_cluster: if fat_type == FatType::Fat32 {
+                read_u32(block, 44)
+            } else {
+                0
+            },
+            data_start: start + data_start,
+            clusters: clusters,
+        };
+        if fat_size < volume.fat_entry(clusters + 1).0 - volume.fat_start + 1 {
+            return None;
+        }
+        Some(volume)
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        self.blocks_per_cluster * BLOCK_SIZE as u32
+    }
+
+    fn cluster_block(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.blocks_per_cluster
+    }
+
+    /// Whether `cluster` is past the end of a chain of clusters, or isn't a
+    /// valid cluster.
+    fn is_end(&self, cluster: u32) -> bool {
+        cluster < 2 || cluster >= self.clusters + 2
+    }
+
+    /// The block of the first FAT and the offset in it of the entry of
+    /// `cluster`.
+    fn fat_entry(&self, cluster: u32) -> (u32, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        };
+        (
+            self.fat_start + offset / BLOCK_SIZE as u32,
+            (offset % BLOCK_SIZE as u32) as usize,
+        )
+    }
+
+    fn read_fat(&self, block: &[u8], cluster: u32) -> u32 {
+        let (_, offset) = self.fat_entry(cluster);
+        match self.fat_type {
+            FatType::Fat16 => read_u16(block, offset),
+            FatType::Fat32 => read_u32(block, offset) & 0x0fff_ffff,
+        }
+    }
+
+    fn write_fat(&self, block: &mut [u8], cluster: u32, value: u32) {
+        let (_, offset) = self.fat_entry(cluster);
+        match self.fat_type {
+            FatType::Fat16 => write_u16(block, offset, value),
+            FatType::Fat32 => {
+                let reserved = read_u32(block, offset) & 0xf000_0000;
+                write_u32(block, offset, reserved | (value & 0x0fff_ffff));
+            }
+        }
+    }
+
+    /// The FAT value marking the end of a chain.
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    /// The start of the directory at `cluster`, where 0 is the root.
+    fn dir_start(&self, cluster: u32) -> DirPos {
+        match (cluster, self.fat_type) {
+            (0, FatType::Fat16) => DirPos::Root(0),
+            (0, FatType::Fat32) => DirPos::Cluster(self.root_cluster, 0),
+            (cluster, _) => DirPos::Cluster(cluster, 0),
+        }
+    }
+
+    fn dir_block(&self, pos: DirPos) -> u32 {
+        match pos {
+            DirPos::Root(block) => self.root_start + block,
+            DirPos::Cluster(cluster, block) => self.cluster_block(cluster) + block,
+        }
+    }
+
+    /// The cluster number directory entries use for the directory at
+    /// `cluster`, which is 0 for the root.
+    fn dir_cluster(&self, cluster: u32) -> u32 {
+        if cluster == self.root_cluster {
+            0
+        } else {
+            cluster
+        }
+    }
+}
+
+/// A block of a directory.
+#[derive(Copy, Clone, Debug)]
+enum DirPos {
+    /// A block of the root directory region of FAT16.
+    Root(u32),
+    /// A block of a cluster.
+    Cluster(u32, u32),
+}
+
+impl Default for DirPos {
+    fn default() -> DirPos {
+        DirPos::Root(0)
+    }
+}
+
+/// Where a directory entry is stored.
+#[derive(Copy, Clone, Debug, Default)]
+struct Location {
+    block: u32,
+    index: usize,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct DirEntry {
+    /// None for the root directory, which has no entry.
+    location: Option<Location>,
+    name: [u8; NAME_LEN],
+    attr: u8,
+    /// The first cluster, 0 if there is none.
+    cluster: u32,
+    size: u32,
+}
+
+impl DirEntry {
+    fn parse(bytes: &[u8], location: Location, fat_type: FatType) -> DirEntry {
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&bytes[..NAME_LEN]);
+        let high = match fat_type {
+            FatType::Fat16 => 0,
+            FatType::Fat32 => read_u16(bytes, 20),
+        };
+        DirEntry {
+            location: Some(location),
+            name: name,
+            attr: bytes[11],
+            cluster: high << 16 | read_u16(bytes, 26),
+            size: read_u32(bytes, 28),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes[..NAME_LEN].copy_from_slice(&self.name);
+        bytes[11] = self.attr;
+        write_u16(bytes, 20, self.cluster >> 16);
+        write_u16(bytes, 26, self.cluster & 0xffff);
+        write_u32(bytes, 28, self.size);
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Operation {
+    Mount,
+    Read { offset: u32, length: u32 },
+    Append { length: u32 },
+    Delete,
+    CreateDir,
+    Stat,
+    ReadDir { index: u32 },
+}
+
+/// What a directory scan looks for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Scan {
+    /// The next name of the path.
+    Lookup,
+    /// The entry after skipping the given number.
+    List(u32),
+    /// Any entry, to check the directory is empty.
+    CheckEmpty,
+}
+
+/// Progress through the directories of the path.
+#[derive(Copy, Clone, Debug, Default)]
+struct Walk {
+    /// The index of the name looked up.
+    depth: usize,
+    /// The first cluster of the directory scanned.
+    dir_cluster: u32,
+    pos: DirPos,
+    /// The first free entry of the directory.
+    free: Option<Location>,
+}
+
+/// Progress through the data of a file, or the directory being created.
+#[derive(Copy, Clone, Debug, Default)]
+struct File {
+    entry: DirEntry,
+    /// Whether the entry has to be created.
+    create: bool,
+    /// The current cluster, and its index in the chain.
+    cluster: u32,
+    index: u32,
+    /// The number of clusters in the chain.
+    clusters: u32,
+    /// The position in the file.
+    pos: u32,
+    /// Bytes read or appended so far, out of `length`.
+    done: u32,
+    length: u32,
+}
+
+/// Finding a free cluster, and linking it after `prev`.
+#[derive(Copy, Clone, Debug, Default)]
+struct Alloc {
+    prev: Option<u32>,
+    cursor: u32,
+    scanned: u32,
+    found: u32,
+}
+
+/// What to do once a FAT block is written to every FAT.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FatWritten {
+    Allocated,
+    Linked,
+    Freed,
+}
+
+/// The I/O in progress.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reading the block at the start of the device or of a partition.
+    Boot(u32),
+    ScanDir,
+    /// Reading the FAT to find the cluster after the given one.
+    NextCluster(u32),
+    ReadData,
+    /// Reading the last block of a file to append to it.
+    AppendRead,
+    AppendWrite,
+    AllocScan,
+    AllocLink,
+    /// Writing a block of the FAT to the given copy of the FAT.
+    WriteFat {
+        block: u32,
+        copy: u32,
+        then: FatWritten,
+    },
+    /// Zeroing the given block of a new directory.
+    ZeroCluster(u32),
+    ReadEntry,
+    WriteEntry,
+    /// Reading the FAT to free clusters.
+    FreeChain,
+}
+
+pub struct FatFs<'a> {
+    device: &'a dyn BlockStorage<'a>,
+    client: OptionalCell<&'a dyn FatClient>,
+    block: TakeCell<'static, [u8]>,
+    /// The buffer of a read or append.
+    data: TakeCell<'static, [u8]>,
+    volume: Cell<Volume>,
+    mounted: Cell<bool>,
+    state: Cell<State>,
+    operation: OptionalCell<Operation>,
+    path: Cell<Path>,
+    scan: Cell<Scan>,
+    walk: Cell<Walk>,
+    file: Cell<File>,
+    alloc: Cell<Alloc>,
+    info: Cell<FileInfo>,
+    /// Where to start looking for free clusters.
+    next_free: Cell<u32>,
+}
+
+impl<'a> FatFs<'a> {
+    pub fn new(device: &'a dyn BlockStorage<'a>, buffer: &'static mut [u8]) -> FatFs<'a> {
+        FatFs {
+            device: device,
+            client: OptionalCell::empty(),
+            block: TakeCell::new(buffer),
+            data: TakeCell::empty(),
+            volume: Cell::new(Volume::default()),
+            mounted: Cell::new(false),
+            state: Cell::new(State::Idle),
+            operation: OptionalCell::empty(),
+            path: Cell::new(Path::new()),
+            scan: Cell::new(Scan::Lookup),
+            walk: Cell::new(Walk::default()),
+            file: Cell::new(File::default()),
+            alloc: Cell::new(Alloc::default()),
+            info: Cell::new(FileInfo::default()),
+            next_free: Cell::new(2),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn FatClient) {
+        self.client.set(client);
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.mounted.get()
+    }
+
+    /// Finds the volume on the device. EOFF means the device isn't ready, and
+    /// ENOSUPPORT means it doesn't have 512-byte blocks or holds no FAT16 or
+    /// FAT32 volume.
+    pub fn mount(&self) -> ReturnCode {
+        if self.operation.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.mounted.set(false);
+        match self.device.geometry() {
+            None => return ReturnCode::EOFF,
+            Some(geometry) if geometry.block_size != BLOCK_SIZE => {
+                return ReturnCode::ENOSUPPORT;
+            }
+            Some(_) => {}
+        }
+        self.operation.set(Operation::Mount);
+        self.start(self.read_block(0, State::Boot(0)))
+    }
+
+    /// Reads up to `length` bytes of the file at `path`, from `offset`.
+    pub fn read(
+        &self,
+        path: Path,
+        offset: u32,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if length > buffer.len() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        self.start_data(
+            path,
+            Operation::Read {
+                offset: offset,
+                length: length as u32,
+            },
+            buffer,
+        )
+    }
+
+    /// Appends the first `length` bytes of `buffer` to the file at `path`,
+    /// creating the file if it doesn't exist.
+    pub fn append(
+        &self,
+        path: Path,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if length > buffer.len() {
+            return Err((ReturnCode::EINVAL, buffer));
+        }
+        self.start_data(
+            path,
+            Operation::Append {
+                length: length as u32,
+            },
+            buffer,
+        )
+    }
+
+    /// Deletes the file or empty directory at `path`.
+    pub fn delete(&self, path: Path) -> ReturnCode {
+        self.start_path(path, Operation::Delete)
+    }
+
+    /// Creates a directory at `path`, in an existing directory.
+    pub fn create_dir(&self, path: Path) -> ReturnCode {
+        self.start_path(path, Operation::CreateDir)
+    }
+
+    /// Gets the size of the file or directory at `path`.
+    pub fn stat(&self, path: Path) -> ReturnCode {
+        self.start_path(path, Operation::Stat)
+    }
+
+    /// Gets entry number `index` of the directory at `path`, not counting
+    /// `.` and `..`.
+    pub fn read_dir(&self, path: Path, index: u32) -> ReturnCode {
+        self.start_path(path, Operation::ReadDir { index: index })
+    }
+
+    fn start_data(
+        &self,
+        path: Path,
+        operation: Operation,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.data.replace(buffer);
+        match self.start_path(path, operation) {
+            ReturnCode::SUCCESS => Ok(()),
+            result => Err((result, self.data.take().unwrap())),
+        }
+    }
+
+    fn start_path(&self, path: Path, operation: Operation) -> ReturnCode {
+        if self.operation.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if self.device.geometry().is_none() {
+            // The device went away, such as an SD card that was removed
+            self.mounted.set(false);
+        }
+        if !self.mounted.get() {
+            return ReturnCode::EOFF;
+        }
+        let scan = match (operation, path.is_empty()) {
+            (Operation::ReadDir { index }, true) => Scan::List(index),
+            // Only the root directory can be listed, not changed
+            (_, true) => return ReturnCode::EINVAL,
+            (_, false) => Scan::Lookup,
+        };
+        self.operation.set(operation);
+        self.path.set(path);
+        self.walk.set(Walk::default());
+        self.start_scan(scan, 0);
+        let volume = self.volume.get();
+        self.start(self.read_block(volume.dir_block(self.walk.get().pos), State::ScanDir))
+    }
+
+    /// Ends an operation that couldn't start.
+    fn start(&self, result: ReturnCode) -> ReturnCode {
+        if result != ReturnCode::SUCCESS {
+            self.operation.clear();
+        }
+        result
+    }
+
+    fn current(&self) -> Option<Operation> {
+        self.operation.map(|operation| *operation)
+    }
+
+    fn read_block(&self, block: u32, state: State) -> ReturnCode {
+        self.block.take().map_or(ReturnCode::EBUSY, |buffer| {
+            self.state.set(state);
+            match self.device.read(block, buffer) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((result, buffer)) => {
+                    self.state.set(State::Idle);
+                    self.block.replace(buffer);
+                    result
+                }
+            }
+        })
+    }
+
+    fn write_block(&self, block: u32, state: State) -> ReturnCode {
+        self.block.take().map_or(ReturnCode::EBUSY, |buffer| {
+            self.state.set(state);
+            match self.device.write(block, buffer) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((result, buffer)) => {
+                    self.state.set(State::Idle);
+                    self.block.replace(buffer);
+                    result
+                }
+            }
+        })
+    }
+
+    /// Continues an operation with a read, finishing it if the read fails.
+    fn continue_read(&self, block: u32, state: State) {
+        let result = self.read_block(block, state);
+        if result != ReturnCode::SUCCESS {
+            self.finish(result);
+        }
+    }
+
+    fn continue_write(&self, block: u32, state: State) {
+        let result = self.write_block(block, state);
+        if result != ReturnCode::SUCCESS {
+            self.finish(result);
+        }
+    }
+
+    fn finish(&self, result: ReturnCode) {
+        self.state.set(State::Idle);
+        let file = self.file.get();
+        let info = self.info.get();
+        self.operation.take().map(|operation| {
+            self.client.map(|client| match operation {
+                Operation::Mount => client.mount_done(result),
+                Operation::Read { .. } => {
+                    self.data.take().map(|buffer| {
+                        client.read_done(result, buffer, file.done as usize);
+                    });
+                }
+                Operation::Append { .. } => {
+                    self.data.take().map(|buffer| {
+                        client.append_done(result, buffer, file.done as usize);
+                    });
+                }
+                Operation::Delete => client.delete_done(result),
+                Operation::CreateDir => client.create_dir_done(result),
+                Operation::Stat => client.stat_done(result, info),
+                Operation::ReadDir { .. } => client.read_dir_done(result, info),
+            });
+        });
+    }
+
+    fn boot_read(&self, start: u32) {
+        let (volume, partition) = self
+            .block
+            .map(|block| {
+                let volume = Volume::parse(block, start);
+                // Otherwise look for the first partition of an MBR
+                let partition = match block[0x1c2] {
+                    0x04 | 0x06 | 0x0b | 0x0c | 0x0e
+                        if start == 0 && block[510] == 0x55 && block[511] == 0xaa =>
+                    {
+                        Some(read_u32(block, 0x1c6))
+                    }
+                    _ => None,
+                };
+                (volume, partition)
+            })
+            .unwrap_or((None, None));
+        match (volume, partition) {
+            (Some(volume), _) => {
+                self.volume.set(volume);
+                self.mounted.set(true);
+                self.next_free.set(2);
+                self.finish(ReturnCode::SUCCESS);
+            }
+            (None, Some(partition)) if partition != 0 => {
+                self.continue_read(partition, State::Boot(partition))
+            }
+            (None, _) => self.finish(ReturnCode::ENOSUPPORT),
+        }
+    }
+
+    fn start_scan(&self, scan: Scan, cluster: u32) {
+        self.scan.set(scan);
+        let mut walk = self.walk.get();
+        walk.dir_cluster = cluster;
+        walk.pos = self.volume.get().dir_start(cluster);
+        walk.free = None;
+        self.walk.set(walk);
+    }
+
+    fn scan_block(&self) {
+        let volume = self.volume.get();
+        self.continue_read(volume.dir_block(self.walk.get().pos), State::ScanDir);
+    }
+
+    fn scan_read(&self) {
+        let volume = self.volume.get();
+        let mut walk = self.walk.get();
+        let mut scan = self.scan.get();
+        let name = self.path.get().names[walk.depth];
+        let block_number = volume.dir_block(walk.pos);
+
+        // Look through the entries of the block
+        let mut end = false;
+        let mut found = None;
+        self.block.map(|block| {
+            for index in 0..ENTRIES_PER_BLOCK {
+                let bytes = &block[index * DIR_ENTRY_SIZE..(index + 1) * DIR_ENTRY_SIZE];
+                let location = Location {
+                    block: block_number,
+                    index: index,
+                };
+                if bytes[0] == 0 || bytes[0] == DELETED {
+                    walk.free = walk.free.or(Some(location));
+                    if bytes[0] == 0 {
+                        end = true;
+                        break;
+                    }
+                    continue;
+                }
+                if bytes[11] == ATTR_LONG_NAME || bytes[11] & ATTR_VOLUME_ID != 0 {
+                    continue;
+                }
+                let entry = DirEntry::parse(bytes, location, volume.fat_type);
+                let matches = match scan {
+                    Scan::Lookup => entry.name == name,
+                    // Skip . and ..
+                    Scan::List(_) | Scan::CheckEmpty if bytes[0] == b'.' => false,
+                    Scan::List(0) | Scan::CheckEmpty => true,
+                    Scan::List(skip) => {
+                        scan = Scan::List(skip - 1);
+                        false
+                    }
+                };
+                if matches {
+                    found = Some(entry);
+                    break;
+                }
+            }
+        });
+        self.walk.set(walk);
+        self.scan.set(scan);
+
+        if let Some(entry) = found {
+            self.scan_found(entry);
+        } else if end {
+            self.scan_end();
+        } else {
+            // Move on to the next block of the directory
+            match walk.pos {
+                DirPos::Root(block) if block + 1 < volume.root_blocks => {
+                    walk.pos = DirPos::Root(block + 1);
+                    self.walk.set(walk);
+                    self.scan_block();
+                }
+                DirPos::Root(_) => self.scan_end(),
+                DirPos::Cluster(cluster, block) if block + 1 < volume.blocks_per_cluster => {
+                    walk.pos = DirPos::Cluster(cluster, block + 1);
+                    self.walk.set(walk);
+                    self.scan_block();
+                }
+                DirPos::Cluster(cluster, _) => self.next_cluster(cluster),
+            }
+        }
+    }
+
+    fn scan_found(&self, entry: DirEntry) {
+        let mut walk = self.walk.get();
+        match self.scan.get() {
+            Scan::Lookup if walk.depth + 1 < self.path.get().len => {
+                if !entry.is_dir() || self.volume.get().is_end(entry.cluster) {
+                    self.finish(ReturnCode::EINVAL);
+                } else {
+                    walk.depth += 1;
+                    self.walk.set(walk);
+                    self.start_scan(Scan::Lookup, entry.cluster);
+                    self.scan_block();
+                }
+            }
+            Scan::Lookup => self.walk_done(Some(entry)),
+            Scan::List(_) => {
+                self.info.set(FileInfo::from_entry(&entry));
+                self.finish(ReturnCode::SUCCESS);
+            }
+            Scan::CheckEmpty => self.finish(ReturnCode::FAIL),
+        }
+    }
+
+    fn scan_end(&self) {
+        match self.scan.get() {
+            Scan::Lookup if self.walk.get().depth + 1 < self.path.get().len => {
+                self.finish(ReturnCode::ENOSUPPORT)
+            }
+            Scan::Lookup => self.walk_done(None),
+            Scan::List(_) => self.finish(ReturnCode::FAIL),
+            // The directory is empty
+            Scan::CheckEmpty => self.entry_update(),
+        }
+    }
+
+    /// The path was looked up, and `found` is its entry.
+    fn walk_done(&self, found: Option<DirEntry>) {
+        let volume = self.volume.get();
+        let walk = self.walk.get();
+        let new_entry = |attr| match walk.free {
+            Some(location) => Some(DirEntry {
+                location: Some(location),
+                name: self.path.get().names[walk.depth],
+                attr: attr,
+                cluster: 0,
+                size: 0,
+            }),
+            None => None,
+        };
+        let operation = match self.current() {
+            Some(operation) => operation,
+            None => return,
+        };
+
+        match (operation, found) {
+            (Operation::Mount, _) => {}
+            (Operation::Stat, None)
+            | (Operation::Read { .. }, None)
+            | (Operation::Delete, None)
+            | (Operation::ReadDir { .. }, None) => self.finish(ReturnCode::ENOSUPPORT),
+            (Operation::Read { offset, length }, Some(entry)) => {
+                if entry.is_dir() {
+                    self.finish(ReturnCode::EINVAL);
+                } else {
+                    self.file.set(File {
+                        entry: entry,
+                        cluster: entry.cluster,
+                        pos: offset,
+                        length: length,
+                        ..File::default()
+                    });
+                    self.read_step();
+                }
+            }
+            (Operation::Append { length }, found) => {
+                let (entry, create) = match found {
+                    Some(entry) if entry.is_dir() => return self.finish(ReturnCode::EINVAL),
+                    Some(entry) => (entry, false),
+                    None => match new_entry(ATTR_ARCHIVE) {
+                        Some(entry) => (entry, true),
+                        None => return self.finish(ReturnCode::ENOMEM),
+                    },
+                };
+                if entry.size.checked_add(length).is_none() {
+                    return self.finish(ReturnCode::ESIZE);
+                }
+                let cluster_bytes = volume.cluster_bytes();
+                self.file.set(File {
+                    entry: entry,
+                    create: create,
+                    cluster: entry.cluster,
+                    clusters: (entry.size + cluster_bytes - 1) / cluster_bytes,
+                    pos: entry.size,
+                    length: length,
+                    ..File::default()
+                });
+                if length == 0 && !create {
+                    self.finish(ReturnCode::SUCCESS);
+                } else {
+                    self.append_step();
+                }
+            }
+            (Operation::Delete, Some(entry)) => {
+                self.file.set(File {
+                    entry: entry,
+                    ..File::default()
+                });
+                if entry.is_dir() && !volume.is_end(entry.cluster) {
+                    self.start_scan(Scan::CheckEmpty, entry.cluster);
+                    self.scan_block();
+                } else {
+                    self.entry_update();
+                }
+            }
+            (Operation::CreateDir, Some(entry)) => {
+                if entry.is_dir() {
+                    self.finish(ReturnCode::SUCCESS);
+                } else {
+                    self.finish(ReturnCode::EINVAL);
+                }
+            }
+            (Operation::CreateDir, None) => match new_entry(ATTR_DIRECTORY) {
+                Some(entry) => {
+                    self.file.set(File {
+                        entry: entry,
+                        create: true,
+                        ..File::default()
+                    });
+                    self.alloc(None);
+                }
+                None => self.finish(ReturnCode::ENOMEM),
+            },
+            (Operation::Stat, Some(entry)) => {
+                self.info.set(FileInfo::from_entry(&entry));
+                self.finish(ReturnCode::SUCCESS);
+            }
+            (Operation::ReadDir { index }, Some(entry)) => {
+                if entry.is_dir() {
+                    self.start_scan(Scan::List(index), entry.cluster);
+                    self.scan_block();
+                } else {
+                    self.finish(ReturnCode::EINVAL);
+                }
+            }
+        }
+    }
+
+    fn next_cluster(&self, cluster: u32) {
+        let (block, _) = self.volume.get().fat_entry(cluster);
+        self.continue_read(block, State::NextCluster(cluster));
+    }
+
+    fn next_cluster_read(&self, cluster: u32) {
+        let volume = self.volume.get();
+        let next = self
+            .block
+            .map_or(0, |block| volume.read_fat(block, cluster));
+        match self.current() {
+            Some(Operation::Read { .. }) | Some(Operation::Append { .. }) => {
+                if volume.is_end(next) {
+                    return self.finish(ReturnCode::FAIL);
+                }
+                let mut file = self.file.get();
+                file.cluster = next;
+                file.index += 1;
+                self.file.set(file);
+                if let Some(Operation::Read { .. }) = self.current() {
+                    self.read_step();
+                } else {
+                    self.append_step();
+                }
+            }
+            _ => {
+                // Scanning a directory
+                if volume.is_end(next) {
+                    self.scan_end();
+                } else {
+                    let mut walk = self.walk.get();
+                    walk.pos = DirPos::Cluster(next, 0);
+                    self.walk.set(walk);
+                    self.scan_block();
+                }
+            }
+        }
+    }
+
+    fn read_step(&self) {
+        let volume = self.volume.get();
+        let file = self.file.get();
+        let cluster_bytes = volume.cluster_bytes();
+        if file.done == file.length || file.pos >= file.entry.size {
+            self.finish(ReturnCode::SUCCESS);
+        } else if volume.is_end(file.cluster) {
+            self.finish(ReturnCode::FAIL);
+        } else if file.index < file.pos / cluster_bytes {
+            self.next_cluster(file.cluster);
+        } else {
+            let block =
+                volume.cluster_block(file.cluster) + file.pos % cluster_bytes / BLOCK_SIZE as u32;
+            self.continue_read(block, State::ReadData);
+        }
+    }
+
+    fn read_data(&self) {
+        let mut file = self.file.get();
+        let offset = file.pos as usize % BLOCK_SIZE;
+        let length = cmp::min(
+            BLOCK_SIZE - offset,
+            cmp::min(file.length - file.done, file.entry.size - file.pos) as usize,
+        );
+        let done = file.done as usize;
+        self.block.map(|block| {
+            self.data.map(|data| {
+                data[done..done + length].copy_from_slice(&block[offset..offset + length]);
+            });
+        });
+        file.pos += length as u32;
+        file.done += length as u32;
+        self.file.set(file);
+        self.read_step();
+    }
+
+    fn append_step(&self) {
+        let volume = self.volume.get();
+        let file = self.file.get();
+        let cluster_bytes = volume.cluster_bytes();
+        if file.done == file.length {
+            self.entry_update();
+        } else if file.clusters == 0 {
+            self.alloc(None);
+        } else if volume.is_end(file.cluster) {
+            self.finish(ReturnCode::FAIL);
+        } else if file.index == file.pos / cluster_bytes {
+            let block =
+                volume.cluster_block(file.cluster) + file.pos % cluster_bytes / BLOCK_SIZE as u32;
+            if file.pos as usize % BLOCK_SIZE == 0 {
+                // Nothing to keep in the block
+                self.block.map(|block| {
+                    for byte in block.iter_mut() {
+                        *byte = 0;
+                    }
+                });
+                self.append_data(block);
+            } else {
+                self.continue_read(block, State::AppendRead);
+            }
+        } else if file.index + 1 < file.clusters {
+            self.next_cluster(file.cluster);
+        } else {
+            self.alloc(Some(file.cluster));
+        }
+    }
+
+    /// Copies the next data into the block, and writes it to block number
+    /// `block`.
+    fn append_data(&self, block: u32) {
+        let file = self.file.get();
+        let offset = file.pos as usize % BLOCK_SIZE;
+        let length = cmp::min(BLOCK_SIZE - offset, (file.length - file.done) as usize);
+        let done = file.done as usize;
+        self.block.map(|block| {
+            self.data.map(|data| {
+                block[offset..offset + length].copy_from_slice(&data[done..done + length]);
+            });
+        });
+        self.continue_write(block, State::AppendWrite);
+    }
+
+    fn append_written(&self) {
+        let mut file = self.file.get();
+        let offset = file.pos as usize % BLOCK_SIZE;
+        let length = cmp::min(BLOCK_SIZE - offset, (file.length - file.done) as usize);
+        file.pos += length as u32;
+        file.done += length as u32;
+        self.file.set(file);
+        self.append_step();
+    }
+
+    fn alloc(&self, prev: Option<u32>) {
+        let volume = self.volume.get();
+        let mut cursor = self.next_free.get();
+        if volume.is_end(cursor) {
+            cursor = 2;
+        }
+        self.alloc.set(Alloc {
+            prev: prev,
+            cursor: cursor,
+            scanned: 0,
+            found: 0,
+        });
+        self.continue_read(volume.fat_entry(cursor).0, State::AllocScan);
+    }
+
+    fn alloc_scan(&self) {
+        let volume = self.volume.get();
+        let mut alloc = self.alloc.get();
+        let (fat_block, _) = volume.fat_entry(alloc.cursor);
+        let mut found = None;
+        self.block.map(|block| {
+            while alloc.scanned < volume.clusters {
+                let cluster = alloc.cursor;
+                if volume.fat_entry(cluster).0 != fat_block {
+                    break;
+                }
+                alloc.scanned += 1;
+                alloc.cursor = if volume.is_end(cluster + 1) {
+                    2
+                } else {
+                    cluster + 1
+                };
+                if volume.read_fat(block, cluster) == 0 {
+                    volume.write_fat(block, cluster, volume.end_of_chain());
+                    found = Some(cluster);
+                    break;
+                }
+            }
+        });
+        self.alloc.set(alloc);
+        match found {
+            Some(cluster) => {
+                alloc.found = cluster;
+                self.alloc.set(alloc);
+                self.write_fat(fat_block, FatWritten::Allocated);
+            }
+            None if alloc.scanned >= volume.clusters => self.finish(ReturnCode::ENOMEM),
+            None => self.continue_read(volume.fat_entry(alloc.cursor).0, State::AllocScan),
+        }
+    }
+
+    /// Writes the block, which is block number `block` of the first FAT, to
+    /// every FAT.
+    fn write_fat(&self, block: u32, then: FatWritten) {
+        self.continue_write(
+            block,
+            State::WriteFat {
+                block: block,
+                copy: 0,
+                then: then,
+            },
+        );
+    }
+
+    fn fat_written(&self, block: u32, copy: u32, then: FatWritten) {
+        let volume = self.volume.get();
+        if copy + 1 < volume.num_fats {
+            return self.continue_write(
+                block + (copy + 1) * volume.fat_size,
+                State::WriteFat {
+                    block: block,
+                    copy: copy + 1,
+                    then: then,
+                },
+            );
+        }
+
+        let alloc = self.alloc.get();
+        match then {
+            FatWritten::Allocated => {
+                self.next_free.set(alloc.found + 1);
+                match alloc.prev {
+                    Some(prev) => self.continue_read(volume.fat_entry(prev).0, State::AllocLink),
+                    None => self.allocated(alloc.found),
+                }
+            }
+            FatWritten::Linked => self.allocated(alloc.found),
+            FatWritten::Freed => self.free_step(),
+        }
+    }
+
+    fn alloc_link(&self) {
+        let volume = self.volume.get();
+        let alloc = self.alloc.get();
+        alloc.prev.map(|prev| {
+            self.block
+                .map(|block| volume.write_fat(block, prev, alloc.found));
+            self.write_fat(volume.fat_entry(prev).0, FatWritten::Linked);
+        });
+    }
+
+    /// The cluster `cluster` was added to the chain.
+    fn allocated(&self, cluster: u32) {
+        let mut file = self.file.get();
+        if file.clusters == 0 {
+            file.entry.cluster = cluster;
+        } else {
+            file.index += 1;
+        }
+        file.cluster = cluster;
+        file.clusters += 1;
+        self.file.set(file);
+        match self.current() {
+            Some(Operation::CreateDir) => self.zero_cluster(0),
+            _ => self.append_step(),
+        }
+    }
+
+    /// Writes block `index` of the new directory, with `.` and `..` in the
+    /// first block.
+    fn zero_cluster(&self, index: u32) {
+        let volume = self.volume.get();
+        let file = self.file.get();
+        let parent = volume.dir_cluster(self.walk.get().dir_cluster);
+        self.block.map(|block| {
+            for byte in block.iter_mut() {
+                *byte = 0;
+            }
+            if index == 0 {
+                let mut dot = DirEntry {
+                    name: *b".          ",
+                    attr: ATTR_DIRECTORY,
+                    cluster: file.cluster,
+                    ..DirEntry::default()
+                };
+                dot.write(&mut block[..DIR_ENTRY_SIZE]);
+                dot.name = *b"..         ";
+                dot.cluster = parent;
+                dot.write(&mut block[DIR_ENTRY_SIZE..2 * DIR_ENTRY_SIZE]);
+            }
+        });
+        self.continue_write(
+            volume.cluster_block(file.cluster) + index,
+            State::ZeroCluster(index),
+        );
+    }
+
+    fn cluster_zeroed(&self, index: u32) {
+        if index + 1 < self.volume.get().blocks_per_cluster {
+            self.zero_cluster(index + 1);
+        } else {
+            self.entry_update();
+        }
+    }
+
+    /// Reads the block of the entry, to create, update or delete it.
+    fn entry_update(&self) {
+        match self.file.get().entry.location {
+            Some(location) => self.continue_read(location.block, State::ReadEntry),
+            None => self.finish(ReturnCode::EINVAL),
+        }
+    }
+
+    fn entry_read(&self) {
+        let file = self.file.get();
+        let deleting = self.operation.contains(&Operation::Delete);
+        let location = file.entry.location.unwrap_or_default();
+        self.block.map(|block| {
+            let bytes =
+                &mut block[location.index * DIR_ENTRY_SIZE..(location.index + 1) * DIR_ENTRY_SIZE];
+            if deleting {
+                bytes[0] = DELETED;
+            } else {
+                if file.create {
+                    for byte in bytes.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+                let mut entry = file.entry;
+                if !entry.is_dir() {
+                    entry.size = file.pos;
+                }
+                entry.write(bytes);
+            }
+        });
+        self.continue_write(location.block, State::WriteEntry);
+    }
+
+    fn entry_written(&self) {
+        if self.operation.contains(&Operation::Delete) {
+            let mut file = self.file.get();
+            file.cluster = file.entry.cluster;
+            self.file.set(file);
+            self.free_step();
+        } else {
+            self.finish(ReturnCode::SUCCESS);
+        }
+    }
+
+    /// Frees the clusters of the chain from the current cluster.
+    fn free_step(&self) {
+        let volume = self.volume.get();
+        let cluster = self.file.get().cluster;
+        if volume.is_end(cluster) {
+            self.finish(ReturnCode::SUCCESS);
+        } else {
+            self.continue_read(volume.fat_entry(cluster).0, State::FreeChain);
+        }
+    }
+
+    fn free_read(&self) {
+        let volume = self.volume.get();
+        let mut file = self.file.get();
+        let (fat_block, _) = volume.fat_entry(file.cluster);
+        self.block.map(|block| {
+            // Free the clusters of the chain in this block of the FAT
+            while !volume.is_end(file.cluster) && volume.fat_entry(file.cluster).0 == fat_block {
+                let next = volume.read_fat(block, file.cluster);
+                volume.write_fat(block, file.cluster, 0);
+                if file.cluster < self.next_free.get() {
+                    self.next_free.set(file.cluster);
+                }
+                file.cluster = next;
+            }
+        });
+        self.file.set(file);
+        self.write_fat(fat_block, FatWritten::Freed);
+    }
+
+    /// A block was read or written, or the device failed.
+    fn transfer_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.block.replace(buffer);
+        if result != ReturnCode::SUCCESS {
+            if self.state.get() != State::Idle {
+                self.finish(ReturnCode::FAIL);
+            }
+            return;
+        }
+        self.block_done();
+    }
+
+    fn block_done(&self) {
+        match self.state.get() {
+            State::Idle => {}
+            State::Boot(start) => self.boot_read(start),
+            State::ScanDir => self.scan_read(),
+            State::NextCluster(cluster) => self.next_cluster_read(cluster),
+            State::ReadData => self.read_data(),
+            State::AppendRead => {
+                let volume = self.volume.get();
+                let file = self.file.get();
+                let cluster_bytes = volume.cluster_bytes();
+                self.append_data(
+                    volume.cluster_block(file.cluster)
+                        + file.pos % cluster_bytes / BLOCK_SIZE as u32,
+                );
+            }
+            State::AppendWrite => self.append_written(),
+            State::AllocScan => self.alloc_scan(),
+            State::AllocLink => self.alloc_link(),
+            State::WriteFat { block, copy, then } => self.fat_written(block, copy, then),
+            State::ZeroCluster(index) => self.cluster_zeroed(index),
+            State::ReadEntry => self.entry_read(),
+            State::WriteEntry => self.entry_written(),
+            State::FreeChain => self.free_read(),
+        }
+    }
+}
+
+impl BlockStorageClient for FatFs<'_> {
+    fn read_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.transfer_done(buffer, result);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.transfer_done(buffer, result);
+    }
+
+    fn erase_done(&self, _result: ReturnCode) {}
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use core::cell::RefCell;
+    use kernel::hil::block_storage::Geometry;
+    use std::boxed::Box;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    /// A disk image, of which only the blocks written are stored.
+    struct TestDisk {
+        blocks: RefCell<BTreeMap<u32, Vec<u8>>>,
+        size: u32,
+        fs: OptionalCell<&'static FatFs<'static>>,
+        pending: TakeCell<'static, [u8]>,
+        writing: Cell<bool>,
+    }
+
+    impl TestDisk {
+        fn block(&self, block: u32) -> Vec<u8> {
+            self.blocks
+                .borrow()
+                .get(&block)
+                .cloned()
+                .unwrap_or_else(|| std::vec![0; BLOCK_SIZE])
+        }
+
+        fn set_block(&self, block: u32, data: &[u8]) {
+            self.blocks.borrow_mut().insert(block, data.to_vec());
+        }
+
+        fn step(&self) -> bool {
+            self.pending.take().map_or(false, |buffer| {
+                self.fs.map(move |fs| {
+                    if self.writing.get() {
+                        fs.write_done(buffer, ReturnCode::SUCCESS);
+                    } else {
+                        fs.read_done(buffer, ReturnCode::SUCCESS);
+                    }
+                });
+                true
+            })
+        }
+    }
+
+    impl BlockStorage<'static> for TestDisk {
+        fn set_client(&self, _client: &'static dyn BlockStorageClient) {}
+
+        fn geometry(&self) -> Option<Geometry> {
+            Some(Geometry {
+                block_size: BLOCK_SIZE,
+                blocks: self.size,
+            })
+        }
+
+        fn read(
+            &self,
+            block: u32,
+            buffer: &'static mut [u8],
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            buffer.copy_from_slice(&self.block(block));
+            self.writing.set(false);
+            self.pending.replace(buffer);
+            Ok(())
+        }
+
+        fn write(
+            &self,
+            block: u32,
+            buffer: &'static mut [u8],
+        ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+            self.set_block(block, buffer);
+            self.writing.set(true);
+            self.pending.replace(buffer);
+            Ok(())
+        }
+
+        fn erase(&self, _block: u32) -> ReturnCode {
+            ReturnCode::ENOSUPPORT
+        }
+    }
+
+    /// Formats a disk of `blocks` blocks, in a partition starting at block
+    /// `start` if it isn't 0.
+    fn format(blocks: u32, start: u32, blocks_per_cluster: u32, fat32: bool) -> TestDisk {
+        let disk = TestDisk {
+            blocks: RefCell::new(BTreeMap::new()),
+            size: blocks,
+            fs: OptionalCell::empty(),
+            pending: TakeCell::empty(),
+            writing: Cell::new(false),
+        };
+        let mut boot = std::vec![0; BLOCK_SIZE];
+        if start != 0 {
+            boot[0x1c2] = if fat32 { 0x0c } else { 0x0e };
+            write_u32(&mut boot, 0x1c6, start);
+            write_u32(&mut boot, 0x1ca, blocks - start);
+            boot[510] = 0x55;
+            boot[511] = 0xaa;
+            disk.set_block(0, &boot);
+            boot = std::vec![0; BLOCK_SIZE];
+        }
+
+        let total = blocks - start;
+        let (reserved, root_entries) = if fat32 { (32, 0) } else { (1, 512) };
+        let entry_size = if fat32 { 4 } else { 2 };
+        let fat_size = (total / blocks_per_cluster + 2) * entry_size / BLOCK_SIZE as u32 + 1;
+        boot[..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+        boot[3..11].copy_from_slice(b"TOCKTEST");
+        write_u16(&mut boot, 11, BLOCK_SIZE as u32);
+        boot[13] = blocks_per_cluster as u8;
+        write_u16(&mut boot, 14, reserved);
+        boot[16] = 2;
+        write_u16(&mut boot, 17, root_entries);
+        write_u32(&mut boot, 32, total);
+        boot[21] = 0xf8;
+        if fat32 {
+            write_u32(&mut boot, 36, fat_size);
+            write_u32(&mut boot, 44, 2);
+        } else {
+            write_u16(&mut boot, 22, fat_size);
+        }
+        boot[510] = 0x55;
+        boot[511] = 0xaa;
+        disk.set_block(start, &boot);
+
+        // Reserve clusters 0 and 1, and the root directory of FAT32
+        let mut fat = std::vec![0; BLOCK_SIZE];
+        if fat32 {
+            write_u32(&mut fat, 0, 0x0fff_fff8);
+            write_u32(&mut fat, 4, 0x0fff_ffff);
+            write_u32(&mut fat, 8, 0x0fff_ffff);
+        } else {
+            write_u16(&mut fat, 0, 0xfff8);
+            write_u16(&mut fat, 2, 0xffff);
+        }
+        disk.set_block(start + reserved, &fat);
+        disk.set_block(start + reserved + fat_size, &fat);
+        disk
+    }
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        result: Cell<Option<ReturnCode>>,
+        length: Cell<usize>,
+        info: Cell<FileInfo>,
+    }
+
+    impl TestClient {
+        fn new() -> &'static TestClient {
+            Box::leak(Box::new(TestClient {
+                buffer: TakeCell::new(Box::leak(std::vec![0; 4096].into_boxed_slice())),
+                result: Cell::new(None),
+                length: Cell::new(0),
+                info: Cell::new(FileInfo::default()),
+            }))
+        }
+    }
+
+    impl FatClient for TestClient {
+        fn mount_done(&self, result: ReturnCode) {
+            self.result.set(Some(result));
+        }
+
+        fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.length.set(length);
+            self.result.set(Some(result));
+        }
+
+        fn append_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.length.set(length);
+            self.result.set(Some(result));
+        }
+
+        fn delete_done(&self, result: ReturnCode) {
+            self.result.set(Some(result));
+        }
+
+        fn create_dir_done(&self, result: ReturnCode) {
+            self.result.set(Some(result));
+        }
+
+        fn stat_done(&self, result: ReturnCode, info: FileInfo) {
+            self.info.set(info);
+            self.result.set(Some(result));
+        }
+
+        fn read_dir_done(&self, result: ReturnCode, info: FileInfo) {
+            self.info.set(info);
+            self.result.set(Some(result));
+        }
+    }
+
+    struct Harness {
+        disk: &'static TestDisk,
+        fs: &'static FatFs<'static>,
+        client: &'static TestClient,
+    }
+
+    impl Harness {
+        fn mount(disk: TestDisk) -> Harness {
+            let disk: &'static TestDisk = Box::leak(Box::new(disk));
+            let fs = Box::leak(Box::new(FatFs::new(
+                disk,
+                Box::leak(Box::new([0; BLOCK_SIZE])),
+            )));
+            let client = TestClient::new();
+            disk.fs.set(fs);
+            fs.set_client(client);
+            let harness = Harness {
+                disk: disk,
+                fs: fs,
+                client: client,
+            };
+            assert_eq!(harness.result(fs.mount()), ReturnCode::SUCCESS);
+            harness
+        }
+
+        fn result(&self, result: ReturnCode) -> ReturnCode {
+            if result != ReturnCode::SUCCESS {
+                return result;
+            }
+            while self.disk.step() {}
+            self.client.result.take().unwrap()
+        }
+
+        fn append(&self, path: &str, data: &[u8]) -> ReturnCode {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..data.len()].copy_from_slice(data);
+            match self.fs.append(path_of(path), buffer, data.len()) {
+                Ok(()) => self.result(ReturnCode::SUCCESS),
+                Err((result, buffer)) => {
+                    self.client.buffer.replace(buffer);
+                    result
+                }
+            }
+        }
+
+        fn read(&self, path: &str, offset: u32, length: usize) -> Result<Vec<u8>, ReturnCode> {
+            let buffer = self.client.buffer.take().unwrap();
+            let result = match self.fs.read(path_of(path), offset, buffer, length) {
+                Ok(()) => self.result(ReturnCode::SUCCESS),
+                Err((result, buffer)) => {
+                    self.client.buffer.replace(buffer);
+                    result
+                }
+            };
+            match result {
+                ReturnCode::SUCCESS => Ok(self
+                    .client
+                    .buffer
+                    .map(|buffer| buffer[..self.client.length.get()].to_vec())
+                    .unwrap()),
+                result => Err(result),
+            }
+        }
+
+        fn stat(&self, path: &str) -> Result<FileInfo, ReturnCode> {
+            match self.result(self.fs.stat(path_of(path))) {
+                ReturnCode::SUCCESS => Ok(self.client.info.get()),
+                result => Err(result),
+            }
+        }
+
+        /// The names of the entries of a directory.
+        fn list(&self, path: &str) -> Vec<std::string::String> {
+            let mut names = Vec::new();
+            loop {
+                let result = self.fs.read_dir(path_of(path), names.len() as u32);
+                match self.result(result) {
+                    ReturnCode::SUCCESS => {
+                        let info = self.client.info.get();
+                        let mut name =
+                            std::string::String::from_utf8(info.name[..info.name_len].to_vec())
+                                .unwrap();
+                        if info.directory {
+                            name.push('/');
+                        }
+                        names.push(name);
+                    }
+                    result => {
+                        assert_eq!(result, ReturnCode::FAIL);
+                        return names;
+                    }
+                }
+            }
+        }
+    }
+
+    fn path_of(path: &str) -> Path {
+        let mut result = Path::new();
+        assert_eq!(result.push(path.as_bytes()), ReturnCode::SUCCESS);
+        result
+    }
+
+    fn data(length: usize, seed: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 + seed) as u8).collect()
+    }
+
+    #[test]
+    fn paths() {
+        let mut path = Path::new();
+        assert_eq!(path.push(b"/logs/Today.txt"), ReturnCode::SUCCESS);
+        assert_eq!(path.len(), 2);
+        assert_eq!(&path.names[0], b"LOGS       ");
+        assert_eq!(&path.names[1], b"TODAY   TXT");
+
+        for bad in [
+            &b".."[..],
+            b"a/./b",
+            b"toolongname",
+            b"a.text",
+            b".txt",
+            b"a b",
+            b"a.b.c",
+        ]
+        .iter()
+        {
+            assert_eq!(Path::new().push(bad), ReturnCode::EINVAL);
+        }
+        assert_eq!(Path::new().push(b"a/b/c/d/e/f/g/h/i"), ReturnCode::ESIZE);
+    }
+
+    #[test]
+    fn fat16_files() {
+        let fs = Harness::mount(format(8192, 0, 1, false));
+        assert_eq!(fs.fs.volume.get().fat_type, FatType::Fat16);
+        assert!(fs.list("").is_empty());
+
+        // Appends spanning blocks and clusters, in pieces not block aligned
+        let notes = data(1500, 1);
+        for piece in notes.chunks(100) {
+            assert_eq!(fs.append("notes.txt", piece), ReturnCode::SUCCESS);
+        }
+        assert_eq!(fs.stat("notes.txt").unwrap().size, 1500);
+        assert_eq!(fs.read("notes.txt", 0, 4096), Ok(notes.clone()));
+        assert_eq!(fs.read("notes.txt", 700, 100), Ok(notes[700..800].to_vec()));
+        assert_eq!(fs.read("notes.txt", 1500, 100), Ok(Vec::new()));
+
+        // The directory entry is where other systems expect it
+        let volume = fs.fs.volume.get();
+        let root = fs.disk.block(volume.root_start);
+        assert_eq!(&root[..NAME_LEN], b"NOTES   TXT");
+        assert_eq!(read_u32(&root, 28), 1500);
+        let first = read_u16(&root, 26);
+        let fat = fs.disk.block(volume.fat_start);
+        assert_eq!(read_u16(&fat, first as usize * 2), first + 1);
+        assert_eq!(fs.disk.block(volume.fat_start + volume.fat_size), fat);
+
+        // Directories
+        assert_eq!(
+            fs.result(fs.fs.create_dir(path_of("logs"))),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            fs.result(fs.fs.create_dir(path_of("logs"))),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            fs.result(fs.fs.create_dir(path_of("notes.txt"))),
+            ReturnCode::EINVAL
+        );
+        assert_eq!(fs.append("logs/a.log", &data(10, 2)), ReturnCode::SUCCESS);
+        assert_eq!(fs.append("logs/b.log", &[]), ReturnCode::SUCCESS);
+        assert_eq!(fs.list(""), ["NOTES.TXT", "LOGS/"]);
+        assert_eq!(fs.list("logs"), ["A.LOG", "B.LOG"]);
+        assert_eq!(fs.stat("logs/b.log").unwrap().size, 0);
+        assert_eq!(fs.read("logs/a.log", 0, 100), Ok(data(10, 2)));
+        assert_eq!(fs.read("logs", 0, 100), Err(ReturnCode::EINVAL));
+        assert_eq!(fs.read("logs/c.log", 0, 100), Err(ReturnCode::ENOSUPPORT));
+        assert_eq!(fs.read("none/a.log", 0, 100), Err(ReturnCode::ENOSUPPORT));
+        assert_eq!(
+            fs.result(fs.fs.read_dir(path_of("notes.txt"), 0)),
+            ReturnCode::EINVAL
+        );
+
+        // Deleting
+        assert_eq!(fs.result(fs.fs.delete(path_of("logs"))), ReturnCode::FAIL);
+        assert_eq!(
+            fs.result(fs.fs.delete(path_of("logs/a.log"))),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            fs.result(fs.fs.delete(path_of("logs/b.log"))),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            fs.result(fs.fs.delete(path_of("logs"))),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            fs.result(fs.fs.delete(path_of("notes.txt"))),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(fs.stat("notes.txt"), Err(ReturnCode::ENOSUPPORT));
+        assert!(fs.list("").is_empty());
+
+        // All clusters are free again
+        let fat = fs.disk.block(volume.fat_start);
+        assert!(fat[4..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn fat32_partition() {
+        let fs = Harness::mount(format(600_000, 2048, 8, true));
+        assert_eq!(fs.fs.volume.get().fat_type, FatType::Fat32);
+
+        assert_eq!(
+            fs.result(fs.fs.create_dir(path_of("a"))),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            fs.result(fs.fs.create_dir(path_of("a/b"))),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            fs.result(fs.fs.create_dir(path_of("a/x/c"))),
+            ReturnCode::ENOSUPPORT
+        );
+
+        // Appends spanning 4 KiB clusters
+        let mut expected = Vec::new();
+        for i in 0..5 {
+            let piece = data(3000, i);
+            assert_eq!(fs.append("a/b/data.bin", &piece), ReturnCode::SUCCESS);
+            expected.extend_from_slice(&piece);
+        }
+        assert_eq!(fs.stat("a/b/data.bin").unwrap().size, 15000);
+        assert_eq!(
+            fs.read("a/b/data.bin", 4000, 4096),
+            Ok(expected[4000..8096].to_vec())
+        );
+        assert_eq!(
+            fs.read("a/b/data.bin", 12000, 4096),
+            Ok(expected[12000..].to_vec())
+        );
+
+        // The filesystem is found again from the disk
+        let disk = fs.disk;
+        let fs = Harness {
+            disk: disk,
+            fs: Box::leak(Box::new(FatFs::new(
+                disk,
+                Box::leak(Box::new([0; BLOCK_SIZE])),
+            ))),
+            client: fs.client,
+        };
+        disk.fs.set(fs.fs);
+        fs.fs.set_client(fs.client);
+        assert_eq!(fs.result(fs.fs.mount()), ReturnCode::SUCCESS);
+        assert_eq!(fs.list(""), ["A/"]);
+        assert_eq!(fs.list("a/b"), ["DATA.BIN"]);
+        assert_eq!(
+            fs.read("a/b/data.bin", 0, 4096),
+            Ok(expected[..4096].to_vec())
+        );
+        assert_eq!(
+            fs.result(fs.fs.delete(path_of("a/b/data.bin"))),
+            ReturnCode::SUCCESS
+        );
+        assert!(fs.list("a/b").is_empty());
+    }
+
+    #[test]
+    fn no_filesystem() {
+        let disk = format(8192, 0, 1, false);
+        disk.set_block(0, &[0; BLOCK_SIZE]);
+        let disk: &'static TestDisk = Box::leak(Box::new(disk));
+        let fs = Box::leak(Box::new(FatFs::new(
+            disk,
+            Box::leak(Box::new([0; BLOCK_SIZE])),
+        )));
+        let client = TestClient::new();
+        disk.fs.set(fs);
+        fs.set_client(client);
+        assert_eq!(fs.stat(path_of("a")), ReturnCode::EOFF);
+        assert_eq!(fs.mount(), ReturnCode::SUCCESS);
+        while disk.step() {}
+        assert_eq!(client.result.get(), Some(ReturnCode::ENOSUPPORT));
+        assert!(!fs.is_mounted());
+    }
+}
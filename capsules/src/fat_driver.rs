@@ -0,0 +1,527 @@
+//! Provides userspace with files on a FAT filesystem.
+//!
+//! Each process is sandboxed in its own directory in the root directory of
+//! the filesystem, named after a hash of the process name, such as
+//! `1A2B3C4D.APP`. The name is not authenticated, so a process installed
+//! with the name of another one gets its files; see `process_identity`.
+//! Paths from a process are relative to its directory, and can't name
+//! anything outside it. The directory is created when the process first
+//! uses the filesystem, and the filesystem is mounted then too, and again
+//! after the card is changed.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
+//! let fat_driver = static_init!(
+//!     capsules::fat_driver::FatDriver<'static, ProcessMgmtCap>,
+//!     capsules::fat_driver::FatDriver::new(
+//!         fat,
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         board_kernel.create_grant(&grant_cap),
+//!         &mut capsules::fat_driver::BUFFER
+//!     )
+//! );
+//! fat.set_client(fat_driver);
+//! ```
+//!
+//! The system call interface is documented in doc/syscalls/50005_fat.md.
+
+use crate::driver;
+use crate::fat::{FatClient, FatFs, FileInfo, Path};
+use crate::process_identity;
+use crate::sha::SHA256_DIGEST_LEN;
+use core::cell::Cell;
+use core::cmp;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, Kernel, ReturnCode, Shared};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::Fat as usize;
+
+/// Longest read or append.
+pub const MAX_DATA_LEN: usize = 512;
+
+/// Buffer for data read and appended.
+pub static mut BUFFER: [u8; MAX_DATA_LEN] = [0; MAX_DATA_LEN];
+
+#[derive(Copy, Clone)]
+enum Operation {
+    Read { path_len: usize, offset: usize },
+    Append { path_len: usize, length: usize },
+    Delete { path_len: usize },
+    CreateDir { path_len: usize },
+    Stat { path_len: usize },
+    ReadDir { path_len: usize, index: usize },
+}
+
+impl Operation {
+    fn path_len(&self) -> usize {
+        match *self {
+            Operation::Read { path_len, .. }
+            | Operation::Append { path_len, .. }
+            | Operation::Delete { path_len }
+            | Operation::CreateDir { path_len }
+            | Operation::Stat { path_len }
+            | Operation::ReadDir { path_len, .. } => path_len,
+        }
+    }
+}
+
+/// The step of the operation of the serving app in progress.
+#[derive(Copy, Clone, PartialEq)]
+enum Step {
+    Mount,
+    CreateSandbox,
+    Operation,
+}
+
+/// The directory of the process with `identity`, named after the start of
+/// the identity. Processes without one are refused.
+fn sandbox_for(identity: Option<[u8; SHA256_DIGEST_LEN]>) -> Result<Path, ReturnCode> {
+    let digest = identity.ok_or(ReturnCode::ENOSUPPORT)?;
+    let mut dir_name = *b"00000000.APP";
+    for (i, byte) in digest[..4].iter().enumerate() {
+        for (j, nibble) in [byte >> 4, byte & 0xf].iter().enumerate() {
+            dir_name[i * 2 + j] = b"0123456789ABCDEF"[*nibble as usize];
+        }
+    }
+    let mut path = Path::new();
+    path.push(&dir_name);
+    Ok(path)
+}
+
+/// The path `name` from a process, in its `sandbox`.
+fn sandboxed(mut sandbox: Path, name: Option<&[u8]>) -> Result<Path, ReturnCode> {
+    match name.map(|name| sandbox.push(name)) {
+        Some(ReturnCode::SUCCESS) => Ok(sandbox),
+        Some(result) => Err(result),
+        None => Err(ReturnCode::EINVAL),
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    path: Option<AppSlice<Shared, u8>>,
+    buffer: Option<AppSlice<Shared, u8>>,
+    /// The operation waiting to run or running.
+    waiting: Option<Operation>,
+    /// Whether the app's directory exists on the mounted filesystem.
+    sandbox_created: bool,
+}
+
+pub struct FatDriver<'a, C: ProcessManagementCapability> {
+    fs: &'a FatFs<'a>,
+    kernel: &'static Kernel,
+    capability: C,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+    step: Cell<Step>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, C: ProcessManagementCapability> FatDriver<'a, C> {
+    pub fn new(
+        fs: &'a FatFs<'a>,
+        kernel: &'static Kernel,
+        capability: C,
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+    ) -> FatDriver<'a, C> {
+        FatDriver {
+            fs: fs,
+            kernel: kernel,
+            capability: capability,
+            apps: grant,
+            serving_app: OptionalCell::empty(),
+            step: Cell::new(Step::Mount),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    /// The directory of a process.
+    fn sandbox_of(&self, appid: AppId) -> Result<Path, ReturnCode> {
+        sandbox_for(process_identity::name_digest(
+            self.kernel,
+            &self.capability,
+            appid,
+        ))
+    }
+
+    /// Starts the next step of the operation of `app`, which must be
+    /// waiting.
+    fn start_operation(&self, appid: AppId, app: &mut App, operation: Operation) -> ReturnCode {
+        if !self.fs.is_mounted() {
+            self.step.set(Step::Mount);
+            return self.fs.mount();
+        }
+        let path = match self.sandbox_of(appid) {
+            Ok(path) => path,
+            Err(result) => return result,
+        };
+        if !app.sandbox_created {
+            self.step.set(Step::CreateSandbox);
+            return self.fs.create_dir(path);
+        }
+        self.step.set(Step::Operation);
+
+        let path_len = operation.path_len();
+        let name = app
+            .path
+            .as_ref()
+            .and_then(|slice| slice.as_ref().get(..path_len));
+        let path = match sandboxed(path, name) {
+            Ok(path) => path,
+            Err(result) => return result,
+        };
+
+        match operation {
+            Operation::Read { offset, .. } => {
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    let length = app
+                        .buffer
+                        .as_ref()
+                        .map_or(0, |slice| cmp::min(slice.len(), buffer.len()));
+                    match self.fs.read(path, offset as u32, buffer, length) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((result, buffer)) => {
+                            self.buffer.replace(buffer);
+                            result
+                        }
+                    }
+                })
+            }
+            Operation::Append { length, .. } => {
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    let copied = app.buffer.as_ref().map_or(false, |slice| {
+                        if length <= slice.len() && length <= buffer.len() {
+                            buffer[..length].copy_from_slice(&slice.as_ref()[..length]);
+                            true
+                        } else {
+                            false
+                        }
+                    });
+                    let result = if copied {
+                        self.fs.append(path, buffer, length)
+                    } else {
+                        Err((ReturnCode::EINVAL, buffer))
+                    };
+                    match result {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((result, buffer)) => {
+                            self.buffer.replace(buffer);
+                            result
+                        }
+                    }
+                })
+            }
+            // The app can't delete its own directory
+            Operation::Delete { .. } if path.len() == 1 => ReturnCode::EINVAL,
+            Operation::Delete { .. } => self.fs.delete(path),
+            Operation::CreateDir { .. } => self.fs.create_dir(path),
+            Operation::Stat { .. } => self.fs.stat(path),
+            Operation::ReadDir { index, .. } => self.fs.read_dir(path, index as u32),
+        }
+    }
+
+    /// Starts the operation of an app, reporting any failure to start it.
+    /// Returns whether it started.
+    fn start_app(&self, appid: AppId, app: &mut App) -> bool {
+        app.waiting.map_or(false, |operation| {
+            let result = self.start_operation(appid, app, operation);
+            if result == ReturnCode::SUCCESS {
+                self.serving_app.set(appid);
+                true
+            } else {
+                // The app's request failed
+                app.waiting = None;
+                if let Some(mut callback) = app.callback {
+                    callback.schedule(From::from(result), 0, 0);
+                }
+                false
+            }
+        })
+    }
+
+    fn serve_waiting_apps(&self) {
+        if self.serving_app.is_some() {
+            // The filesystem is busy with a request
+            return;
+        }
+
+        // Find a waiting app and start its operation
+        for app in self.apps.iter() {
+            let started = app.enter(|app, _| self.start_app(app.appid(), app));
+            if started {
+                break;
+            }
+        }
+    }
+
+    /// Starts the next step of the operation of the serving app.
+    fn continue_operation(&self) {
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| self.start_app(appid, app));
+        });
+        self.serve_waiting_apps();
+    }
+
+    /// Reports the result of the running operation to its app, after
+    /// calling `copy` to copy data to the app.
+    fn operation_complete<F: FnOnce(&mut App)>(
+        &self,
+        result: ReturnCode,
+        arg1: usize,
+        arg2: usize,
+        copy: F,
+    ) {
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.waiting = None;
+                copy(app);
+                if let Some(mut callback) = app.callback {
+                    callback.schedule(From::from(result), arg1, arg2);
+                }
+            });
+        });
+        self.serve_waiting_apps();
+    }
+}
+
+impl<C: ProcessManagementCapability> FatClient for FatDriver<'_, C> {
+    fn mount_done(&self, result: ReturnCode) {
+        if result == ReturnCode::SUCCESS {
+            // The directories may not be on this card
+            for app in self.apps.iter() {
+                app.enter(|app, _| app.sandbox_created = false);
+            }
+            self.continue_operation();
+        } else {
+            self.operation_complete(result, 0, 0, |_| {});
+        }
+    }
+
+    fn read_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize) {
+        self.operation_complete(result, length, 0, |app| {
+            if let Some(slice) = app.buffer.as_mut() {
+                let len = cmp::min(length, slice.len());
+                slice.as_mut()[..len].copy_from_slice(&buffer[..len]);
+            }
+        });
+        self.buffer.replace(buffer);
+    }
+
+    fn append_done(&self, result: ReturnCode, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.operation_complete(result, length, 0, |_| {});
+    }
+
+    fn delete_done(&self, result: ReturnCode) {
+        self.operation_complete(result, 0, 0, |_| {});
+    }
+
+    fn create_dir_done(&self, result: ReturnCode) {
+        if self.step.get() == Step::CreateSandbox && result == ReturnCode::SUCCESS {
+            self.serving_app.map(|appid| {
+                let _ = self.apps.enter(*appid, |app, _| app.sandbox_created = true);
+            });
+            self.continue_operation();
+        } else {
+            self.operation_complete(result, 0, 0, |_| {});
+        }
+    }
+
+    fn stat_done(&self, result: ReturnCode, info: FileInfo) {
+        self.operation_complete(result, info.size as usize, info.directory as usize, |_| {});
+    }
+
+    fn read_dir_done(&self, result: ReturnCode, info: FileInfo) {
+        // Directory names end with a slash
+        let mut name = [0; 13];
+        name[..info.name_len].copy_from_slice(&info.name[..info.name_len]);
+        let mut name_len = info.name_len;
+        if info.directory {
+            name[name_len] = b'/';
+            name_len += 1;
+        }
+        self.operation_complete(result, name_len, info.size as usize, |app| {
+            if let Some(slice) = app.buffer.as_mut() {
+                let len = cmp::min(name_len, slice.len());
+                slice.as_mut()[..len].copy_from_slice(&name[..len]);
+            }
+        });
+    }
+}
+
+impl<C: ProcessManagementCapability> Driver for FatDriver<'_, C> {
+    /// Setup buffers for paths and data.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The path.
+    /// - `1`: The data to append, or the buffer for data or names read.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.path = slice,
+                    1 => app.buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Subscribe to filesystem events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Subscribe to the completion of operations. The callback
+    ///        signature is `fn(result: u32, arg1: usize, arg2: usize)`, with
+    ///        arguments depending on the operation.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Use the files of the process. `data1` is the length of the path at
+    /// the start of the path buffer.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Read a file from offset `data2` into the data buffer.
+    /// - `2`: Append the first `data2` bytes of the data buffer to a file.
+    /// - `3`: Delete a file or empty directory.
+    /// - `4`: Create a directory.
+    /// - `5`: Get the size of a file, and whether it is a directory.
+    /// - `6`: Read the name of entry `data2` of a directory into the data
+    ///        buffer.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        let operation = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => Operation::Read {
+                path_len: data1,
+                offset: data2,
+            },
+            2 => Operation::Append {
+                path_len: data1,
+                length: data2,
+            },
+            3 => Operation::Delete { path_len: data1 },
+            4 => Operation::CreateDir { path_len: data1 },
+            5 => Operation::Stat { path_len: data1 },
+            6 => Operation::ReadDir {
+                path_len: data1,
+                index: data2,
+            },
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+        if let Operation::Append { length, .. } = operation {
+            if length > MAX_DATA_LEN {
+                return ReturnCode::ESIZE;
+            }
+        }
+
+        let result = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.waiting.is_some() {
+                    ReturnCode::EBUSY
+                } else {
+                    app.waiting = Some(operation);
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+        if result == ReturnCode::SUCCESS {
+            self.serve_waiting_apps();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::process_identity::digest_of_name;
+
+    fn path(names: &[u8]) -> Path {
+        let mut path = Path::new();
+        assert_eq!(path.push(names), ReturnCode::SUCCESS);
+        path
+    }
+
+    #[test]
+    fn sandboxes_are_named_after_the_identity() {
+        let mut digest = [0; SHA256_DIGEST_LEN];
+        digest[..4].copy_from_slice(&[0x1a, 0x2b, 0x3c, 0x4d]);
+        assert_eq!(sandbox_for(Some(digest)), Ok(path(b"1A2B3C4D.APP")));
+
+        assert_eq!(sandbox_for(digest_of_name("")), Err(ReturnCode::ENOSUPPORT));
+        assert_ne!(
+            sandbox_for(digest_of_name("blink")),
+            sandbox_for(digest_of_name("sensors"))
+        );
+    }
+
+    #[test]
+    fn paths_stay_in_the_sandbox() {
+        let sandbox = path(b"1A2B3C4D.APP");
+        assert_eq!(
+            sandboxed(sandbox, Some(b"logs/today.txt")),
+            Ok(path(b"1A2B3C4D.APP/LOGS/TODAY.TXT"))
+        );
+        // Leading and repeated separators don't reach the root
+        assert_eq!(
+            sandboxed(sandbox, Some(b"//logs")),
+            Ok(path(b"1A2B3C4D.APP/LOGS"))
+        );
+
+        for name in [
+            &b".."[..],
+            b"../0A0B0C0D.APP",
+            b"logs/../../x",
+            b"./x",
+            b".",
+        ]
+        .iter()
+        {
+            assert_eq!(sandboxed(sandbox, Some(name)), Err(ReturnCode::EINVAL));
+        }
+        assert_eq!(sandboxed(sandbox, None), Err(ReturnCode::EINVAL));
+        assert_eq!(
+            sandboxed(sandbox, Some(b"a/b/c/d/e/f/g/h")),
+            Err(ReturnCode::ESIZE)
+        );
+    }
+}
@@ -0,0 +1,1198 @@
+//! Kernel updates with A/B slots.
+//!
+//! The flash holds two kernel slots of the same size. The kernel runs from
+//! one of them, and `FirmwareUpdate` writes a new image into the other, one
+//! page at a time, through `hil::flash`. Once the whole image is written it
+//! is read back and hashed with SHA-256 through `hil::digest`, and if the
+//! image is signed with the board's update key, checked through
+//! `hil::signature`, the slot is marked for boot in the boot record.
+//!
+//! Images
+//! ------
+//!
+//! An image is the kernel followed by a trailer and a signature:
+//!
+//! ```text
+//! kernel | version (4) | kernel length (4) | magic (4) | signature (64)
+//! ```
+//!
+//! Numbers are little endian, and the magic is `0x53554b54`. The signature
+//! is the Ed25519 signature of the SHA-256 digest of the kernel and the
+//! trailer, so the version can't be changed without the key. Images with a
+//! version below the rollback counter are refused.
+//!
+//! A transport, such as `firmware_update_driver` for a privileged process,
+//! or a capsule receiving images over UART or USB, drives an update:
+//!
+//! 1. `begin()` with the length of the image, signature included.
+//! 2. `write()` each page of the image in order. Every page is a whole page
+//!    long, except the last which holds what is left of the image.
+//! 3. `finish()` to check the signature and version.
+//!
+//! Boot record
+//! -----------
+//!
+//! The boot record says which slot to boot. It is kept in two flash pages
+//! that are written in turn, so that a reset while one is written leaves the
+//! other intact; the valid record with the highest sequence number is
+//! current:
+//!
+//! ```text
+//! magic (4) | sequence (4) | active (1) | pending (1) | tries (1) | 0xff (1) |
+//! rollback counter (4) | pending version (4) | crc (4)
+//! ```
+//!
+//! Numbers are little endian and the CRC covers the rest of the record.
+//! `pending` is 0xff when no update is waiting.
+//!
+//! A verified image is pending, and the bootloader boots it up to
+//! `MAX_TRIES` times, using `BootRecord::select_slot()`. If the new kernel
+//! works, it calls `confirm()`, which makes its slot the active one and
+//! raises the rollback counter to its version. If it never confirms, the
+//! bootloader goes back to the active slot once the tries run out.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! static mut HASH_BUFFER: [u8; 64] = [0; 64];
+//! static mut DIGEST: [u8; 32] = [0; 32];
+//! static mut SIGNATURE: [u8; 64] = [0; 64];
+//!
+//! let page = static_init!(
+//!     sam4l::flashcalw::Sam4lPage,
+//!     sam4l::flashcalw::Sam4lPage::default()
+//! );
+//! let update = static_init!(
+//!     capsules::firmware_update::FirmwareUpdate<'static, Flash, Sha, SignatureVerifier>,
+//!     capsules::firmware_update::FirmwareUpdate::new(
+//!         flash,
+//!         sha,
+//!         verifier,
+//!         UPDATE_PUBLIC_KEY,
+//!         [0x10000 / 512, 0x30000 / 512], // First page of each slot
+//!         0x20000 / 512,                  // Pages in a slot
+//!         0x50000 / 512,                  // First of the two boot record pages
+//!         0,                              // The slot the kernel runs from
+//!         page,
+//!         &mut HASH_BUFFER,
+//!         &mut DIGEST,
+//!         &mut SIGNATURE,
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(flash, update);
+//! sha.set_client(update);
+//! verifier.set_client(update);
+//! update.load();
+//! ```
+
+use crate::crc32::crc32;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest::{self, Digest};
+use kernel::hil::flash::{self, Flash};
+use kernel::hil::signature::{self, SignatureVerify};
+use kernel::ReturnCode;
+
+/// Number of times the bootloader boots a new image before going back to the
+/// active slot.
+pub const MAX_TRIES: u8 = 3;
+
+/// Size of the boot record at the start of each boot record page.
+pub const RECORD_SIZE: usize = 24;
+
+/// Size of the trailer after the kernel in an image.
+pub const TRAILER_SIZE: usize = 12;
+
+/// Size of the signature at the end of an image.
+pub const SIGNATURE_SIZE: usize = 64;
+
+const MAGIC: u32 = 0x5055_4241; // "ABUP"
+const IMAGE_MAGIC: u32 = 0x5355_4b54; // "TKUS"
+const NO_SLOT: u8 = 0xff;
+
+/// Which slot to boot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BootRecord {
+    /// Incremented each time the record is written.
+    pub sequence: u32,
+    /// The slot to boot when no update is pending.
+    pub active: usize,
+    /// A slot holding a verified image that hasn't been confirmed.
+    pub pending: Option<usize>,
+    /// Times left to boot the pending slot.
+    pub tries: u8,
+    /// The lowest image version that can be installed.
+    pub rollback_counter: u32,
+    /// Version of the image in the pending slot.
+    pub pending_version: u32,
+}
+
+impl Default for BootRecord {
+    fn default() -> BootRecord {
+        BootRecord {
+            sequence: 0,
+            active: 0,
+            pending: None,
+            tries: 0,
+            rollback_counter: 0,
+            pending_version: 0,
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+impl BootRecord {
+    /// Parses a record, returning `None` if it isn't intact.
+    pub fn parse(bytes: &[u8]) -> Option<BootRecord> {
+        if bytes.len() < RECORD_SIZE
+            || read_u32(&bytes[0..4]) != MAGIC
+            || read_u32(&bytes[20..24]) != crc32(&bytes[0..20])
+            || bytes[8] > 1
+            || (bytes[9] > 1 && bytes[9] != NO_SLOT)
+        {
+            return None;
+        }
+        Some(BootRecord {
+            sequence: read_u32(&bytes[4..8]),
+            active: bytes[8] as usize,
+            pending: match bytes[9] {
+                NO_SLOT => None,
+                slot => Some(slot as usize),
+            },
+            tries: bytes[10],
+            rollback_counter: read_u32(&bytes[12..16]),
+            pending_version: read_u32(&bytes[16..20]),
+        })
+    }
+
+    /// The current record of the two record pages, or the default record if
+    /// neither is intact.
+    pub fn newest(first: &[u8], second: &[u8]) -> BootRecord {
+        match (BootRecord::parse(first), BootRecord::parse(second)) {
+            (Some(a), Some(b)) if b.sequence.wrapping_sub(a.sequence) as i32 > 0 => b,
+            (Some(a), _) => a,
+            (None, Some(b)) => b,
+            (None, None) => BootRecord::default(),
+        }
+    }
+
+    /// The record page to write this record to: records alternate pages.
+    pub fn page(&self) -> usize {
+        (self.sequence % 2) as usize
+    }
+
+    pub fn encode(&self, bytes: &mut [u8]) {
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        bytes[8] = self.active as u8;
+        bytes[9] = self.pending.map_or(NO_SLOT, |slot| slot as u8);
+        bytes[10] = self.tries;
+        bytes[11] = 0xff;
+        bytes[12..16].copy_from_slice(&self.rollback_counter.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.pending_version.to_le_bytes());
+        let crc = crc32(&bytes[0..20]);
+        bytes[20..24].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Chooses the slot to boot, for bootloaders. A pending slot is booted
+    /// while it has tries left, using one up; after that the pending image is
+    /// dropped and the active slot is booted.
+    ///
+    /// Returns the slot, and whether the record changed and has to be
+    /// written back, with the next sequence number, before booting.
+    pub fn select_slot(&mut self) -> (usize, bool) {
+        match self.pending {
+            Some(slot) if self.tries > 0 => {
+                self.tries -= 1;
+                self.sequence = self.sequence.wrapping_add(1);
+                (slot, true)
+            }
+            Some(_) => {
+                self.pending = None;
+                self.sequence = self.sequence.wrapping_add(1);
+                (self.active, true)
+            }
+            None => (self.active, false),
+        }
+    }
+}
+
+/// Receives the results of an update.
+pub trait UpdateClient {
+    /// The update can be written. FAIL if the boot record couldn't be
+    /// written.
+    fn begin_done(&self, result: ReturnCode);
+
+    /// A page of the image was written.
+    fn write_done(&self, result: ReturnCode);
+
+    /// The image was verified and marked for boot. FAIL means the signature
+    /// or trailer is wrong, and EINVAL that the version is below the
+    /// rollback counter; either way the image can be written again.
+    fn finish_done(&self, result: ReturnCode);
+
+    /// The running slot is now the active slot.
+    fn confirm_done(&self, result: ReturnCode);
+}
+
+/// The record operation that ends with a record write.
+#[derive(Clone, Copy, PartialEq)]
+enum RecordOp {
+    Begin,
+    Finish,
+    Confirm,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    /// Reading a boot record page.
+    Load(usize),
+    EraseRecord(RecordOp),
+    WriteRecord(RecordOp),
+    /// Erasing, then writing, a page of the image.
+    ErasePage(usize),
+    WritePage(usize),
+    /// Reading a page of the image back.
+    ReadPage(usize),
+    /// Hashing a page of the image, up to an offset.
+    HashPage(usize, usize),
+    Digest,
+    Verify,
+}
+
+/// The update being written.
+#[derive(Clone, Copy)]
+struct Image {
+    length: usize,
+    written: usize,
+}
+
+impl Image {
+    /// Length of the kernel and trailer, which are signed.
+    fn signed_length(&self) -> usize {
+        self.length - SIGNATURE_SIZE
+    }
+
+    /// Offset of the trailer in the image.
+    fn trailer_start(&self) -> usize {
+        self.signed_length() - TRAILER_SIZE
+    }
+}
+
+pub struct FirmwareUpdate<'a, F, D, S>
+where
+    F: Flash + 'static,
+    D: Digest<'a, [u8; 32]> + digest::Sha256,
+    S: SignatureVerify<'a> + signature::Ed25519,
+{
+    flash: &'a F,
+    digest: &'a D,
+    verifier: &'a S,
+    /// Ed25519 key that signs updates.
+    public_key: [u8; 32],
+    client: OptionalCell<&'a dyn UpdateClient>,
+    /// First page of each slot.
+    slots: [usize; 2],
+    slot_pages: usize,
+    /// First of the two boot record pages.
+    record_page: usize,
+    /// The slot the kernel runs from.
+    running: usize,
+    page_size: usize,
+    page: TakeCell<'static, F::Page>,
+    hash_buffer: TakeCell<'static, [u8]>,
+    digest_buffer: TakeCell<'static, [u8; 32]>,
+    signature_buffer: TakeCell<'static, [u8]>,
+    /// The trailer and signature of the image, copied out as it is read
+    /// back.
+    trailer: Cell<[u8; TRAILER_SIZE + SIGNATURE_SIZE]>,
+    /// The current boot record, once it has been read.
+    record: Cell<Option<BootRecord>>,
+    /// The record being written.
+    next_record: Cell<Option<BootRecord>>,
+    image: Cell<Option<Image>>,
+    state: Cell<State>,
+}
+
+impl<'a, F, D, S> FirmwareUpdate<'a, F, D, S>
+where
+    F: Flash,
+    D: Digest<'a, [u8; 32]> + digest::Sha256,
+    S: SignatureVerify<'a> + signature::Ed25519,
+{
+    /// `hash_buffer` must be at least 32 bytes long, and `signature_buffer`
+    /// `SIGNATURE_SIZE` bytes.
+    pub fn new(
+        flash: &'a F,
+        digest: &'a D,
+        verifier: &'a S,
+        public_key: [u8; 32],
+        slots: [usize; 2],
+        slot_pages: usize,
+        record_page: usize,
+        running: usize,
+        page: &'static mut F::Page,
+        hash_buffer: &'static mut [u8],
+        digest_buffer: &'static mut [u8; 32],
+        signature_buffer: &'static mut [u8],
+    ) -> FirmwareUpdate<'a, F, D, S> {
+        let page_size = page.as_mut().len();
+        FirmwareUpdate {
+            flash: flash,
+            digest: digest,
+            verifier: verifier,
+            public_key: public_key,
+            client: OptionalCell::empty(),
+            slots: slots,
+            slot_pages: slot_pages,
+            record_page: record_page,
+            running: running,
+            page_size: page_size,
+            page: TakeCell::new(page),
+            hash_buffer: TakeCell::new(hash_buffer),
+            digest_buffer: TakeCell::new(digest_buffer),
+            signature_buffer: TakeCell::new(signature_buffer),
+            trailer: Cell::new([0; TRAILER_SIZE + SIGNATURE_SIZE]),
+            record: Cell::new(None),
+            next_record: Cell::new(None),
+            image: Cell::new(None),
+            state: Cell::new(State::Idle),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn UpdateClient) {
+        self.client.set(client);
+    }
+
+    /// Reads the boot record. Updates can start once it has been read.
+    pub fn load(&self) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        self.read_page(State::Load(0), self.record_page)
+    }
+
+    /// The current boot record, or `None` before it has been read.
+    pub fn boot_record(&self) -> Option<BootRecord> {
+        self.record.get()
+    }
+
+    /// The size of the pages to write.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Starts an update of `length` bytes, signature included. Returns ESIZE
+    /// if the image doesn't fit in a slot or has no kernel, and EBUSY if the
+    /// running kernel is an update that hasn't been confirmed.
+    pub fn begin(&self, length: usize) -> ReturnCode {
+        let record = match self.record.get() {
+            Some(record) => record,
+            None => return ReturnCode::EOFF,
+        };
+        if self.state.get() != State::Idle || record.pending == Some(self.running) {
+            return ReturnCode::EBUSY;
+        }
+        if length <= TRAILER_SIZE + SIGNATURE_SIZE || length > self.slot_pages * self.page_size {
+            return ReturnCode::ESIZE;
+        }
+        self.image.set(Some(Image {
+            length: length,
+            written: 0,
+        }));
+        // Drop any image already waiting in the slot, so that a reset
+        // partway through the update doesn't boot a partial image.
+        let mut next = record;
+        next.pending = None;
+        next.tries = 0;
+        let result = self.write_record(next, RecordOp::Begin);
+        if result != ReturnCode::SUCCESS {
+            self.image.set(None);
+        }
+        result
+    }
+
+    /// Writes the next page of the image. Returns ESIZE if `data` isn't a
+    /// page long, or, for the last page, what is left of the image.
+    pub fn write(&self, data: &[u8]) -> ReturnCode {
+        let image = match self.image.get() {
+            Some(image) => image,
+            None => return ReturnCode::EINVAL,
+        };
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let remaining = image.length - image.written;
+        if remaining == 0 || data.len() != cmp::min(self.page_size, remaining) {
+            return ReturnCode::ESIZE;
+        }
+        self.page.map_or(ReturnCode::EBUSY, |page| {
+            let page = page.as_mut();
+            for byte in page.iter_mut() {
+                *byte = 0xff;
+            }
+            page[..data.len()].copy_from_slice(data);
+            let index = image.written / self.page_size;
+            self.state.set(State::ErasePage(index));
+            let result = self.flash.erase_page(self.slot_page(index));
+            if result != ReturnCode::SUCCESS {
+                self.state.set(State::Idle);
+            }
+            result
+        })
+    }
+
+    /// Checks the signature and version of the image, and marks it for boot
+    /// if they are good.
+    pub fn finish(&self) -> ReturnCode {
+        match self.image.get() {
+            Some(image) if image.written == image.length => {}
+            _ => return ReturnCode::EINVAL,
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if let Err(result) = self.digest.set_mode_sha256() {
+            return result;
+        }
+        self.read_page(State::ReadPage(0), self.slot_page(0))
+    }
+
+    /// Makes the running slot the active slot, if it is a pending update.
+    /// Returns EALREADY if it is already active.
+    pub fn confirm(&self) -> ReturnCode {
+        let record = match self.record.get() {
+            Some(record) => record,
+            None => return ReturnCode::EOFF,
+        };
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if record.pending != Some(self.running) {
+            return if record.active == self.running {
+                ReturnCode::EALREADY
+            } else {
+                ReturnCode::FAIL
+            };
+        }
+        let mut next = record;
+        next.active = self.running;
+        next.pending = None;
+        next.tries = 0;
+        next.rollback_counter = cmp::max(record.rollback_counter, record.pending_version);
+        self.write_record(next, RecordOp::Confirm)
+    }
+
+    /// The slot an update is written to.
+    fn target(&self) -> usize {
+        1 - self.running
+    }
+
+    fn slot_page(&self, index: usize) -> usize {
+        self.slots[self.target()] + index
+    }
+
+    fn read_page(&self, state: State, page_number: usize) -> ReturnCode {
+        self.page.take().map_or(ReturnCode::EBUSY, |page| {
+            self.state.set(state);
+            match self.flash.read_page(page_number, page) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((result, page)) => {
+                    self.page.replace(page);
+                    self.state.set(State::Idle);
+                    result
+                }
+            }
+        })
+    }
+
+    /// Writes `record` with the next sequence number, erasing its page
+    /// first.
+    fn write_record(&self, mut record: BootRecord, op: RecordOp) -> ReturnCode {
+        record.sequence = record.sequence.wrapping_add(1);
+        self.next_record.set(Some(record));
+        self.state.set(State::EraseRecord(op));
+        let result = self.flash.erase_page(self.record_page + record.page());
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+        }
+        result
+    }
+
+    /// How many bytes of page `index` of the image are signed, and hashed.
+    fn signed_in_page(&self, image: Image, index: usize) -> usize {
+        let page_start = index * self.page_size;
+        cmp::min(
+            self.page_size,
+            image.signed_length().saturating_sub(page_start),
+        )
+    }
+
+    /// Copies the parts of the trailer and signature in page `index` of the
+    /// image.
+    fn keep_trailer(&self, image: Image, index: usize, page: &[u8]) {
+        let trailer_start = image.trailer_start();
+        let mut trailer = self.trailer.get();
+        for (offset, byte) in page.iter().enumerate() {
+            let position = index * self.page_size + offset;
+            if position >= trailer_start && position < image.length {
+                trailer[position - trailer_start] = *byte;
+            }
+        }
+        self.trailer.set(trailer);
+    }
+
+    /// Hashes the next part of the page of the image in `page`.
+    fn hash_page(&self, index: usize, offset: usize) -> ReturnCode {
+        let image = match self.image.get() {
+            Some(image) => image,
+            None => return ReturnCode::FAIL,
+        };
+        let signed = self.signed_in_page(image, index);
+        self.hash_buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+            let len = cmp::min(buffer.len(), signed - offset);
+            self.page.map(|page| {
+                buffer[..len].copy_from_slice(&page.as_mut()[offset..offset + len]);
+            });
+            let mut data = LeasableBuffer::new(buffer);
+            data.slice(..len);
+            self.state.set(State::HashPage(index, offset + len));
+            match self.digest.add_data(data) {
+                Ok(_) => ReturnCode::SUCCESS,
+                Err((result, buffer)) => {
+                    self.hash_buffer.replace(buffer);
+                    result
+                }
+            }
+        })
+    }
+
+    /// Moves on once a part of a page of the image has been hashed.
+    fn hashed(&self, index: usize, offset: usize) -> ReturnCode {
+        let image = match self.image.get() {
+            Some(image) => image,
+            None => return ReturnCode::FAIL,
+        };
+        let page_start = index * self.page_size;
+        if offset < self.signed_in_page(image, index) {
+            self.hash_page(index, offset)
+        } else if page_start + self.page_size < image.length {
+            self.read_page(State::ReadPage(index + 1), self.slot_page(index + 1))
+        } else {
+            self.digest_buffer
+                .take()
+                .map_or(ReturnCode::EBUSY, |digest_buffer| {
+                    self.state.set(State::Digest);
+                    match self.digest.run(digest_buffer) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((result, digest_buffer)) => {
+                            self.digest_buffer.replace(digest_buffer);
+                            result
+                        }
+                    }
+                })
+        }
+    }
+
+    /// Checks the signature over the digest in `digest`.
+    fn verify(&self, digest: &[u8; 32]) -> ReturnCode {
+        if let Err(result) = self.verifier.set_mode_ed25519(&self.public_key) {
+            return result;
+        }
+        let trailer = self.trailer.get();
+        match (self.hash_buffer.take(), self.signature_buffer.take()) {
+            (Some(message), Some(signature))
+                if message.len() >= digest.len() && signature.len() == SIGNATURE_SIZE =>
+            {
+                message[..digest.len()].copy_from_slice(digest);
+                signature.copy_from_slice(&trailer[TRAILER_SIZE..]);
+                let mut message = LeasableBuffer::new(message);
+                message.slice(..digest.len());
+                self.state.set(State::Verify);
+                match self.verifier.verify(message, signature) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((result, message, signature)) => {
+                        self.hash_buffer.replace(message);
+                        self.signature_buffer.replace(signature);
+                        result
+                    }
+                }
+            }
+            (message, signature) => {
+                message.map(|message| self.hash_buffer.replace(message));
+                signature.map(|signature| self.signature_buffer.replace(signature));
+                ReturnCode::ESIZE
+            }
+        }
+    }
+
+    /// Marks a verified image for boot, if the trailer is good and the
+    /// version isn't below the rollback counter.
+    fn mark_for_boot(&self) -> ReturnCode {
+        let (image, record) = match (self.image.get(), self.record.get()) {
+            (Some(image), Some(record)) => (image, record),
+            _ => return ReturnCode::FAIL,
+        };
+        let trailer = self.trailer.get();
+        let version = read_u32(&trailer[0..4]);
+        if read_u32(&trailer[8..12]) != IMAGE_MAGIC
+            || read_u32(&trailer[4..8]) as usize != image.trailer_start()
+        {
+            return ReturnCode::FAIL;
+        }
+        if version < record.rollback_counter {
+            return ReturnCode::EINVAL;
+        }
+        let mut next = record;
+        next.pending = Some(self.target());
+        next.tries = MAX_TRIES;
+        next.pending_version = version;
+        self.write_record(next, RecordOp::Finish)
+    }
+
+    /// Ends the operation in progress.
+    fn done(&self, state: State, result: ReturnCode) {
+        self.state.set(State::Idle);
+        match state {
+            State::Load(_) | State::Idle => None,
+            State::EraseRecord(op) | State::WriteRecord(op) => self.client.map(|client| match op {
+                RecordOp::Begin => client.begin_done(result),
+                RecordOp::Finish => client.finish_done(result),
+                RecordOp::Confirm => client.confirm_done(result),
+            }),
+            State::ErasePage(_) | State::WritePage(_) => {
+                self.client.map(|client| client.write_done(result))
+            }
+            State::ReadPage(_) | State::HashPage(_, _) | State::Digest | State::Verify => {
+                self.client.map(|client| client.finish_done(result))
+            }
+        };
+    }
+
+    /// Ends the operation in progress if a step of it failed to start.
+    fn check(&self, state: State, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.done(state, result);
+        }
+    }
+}
+
+impl<'a, F, D, S> flash::Client<F> for FirmwareUpdate<'a, F, D, S>
+where
+    F: Flash,
+    D: Digest<'a, [u8; 32]> + digest::Sha256,
+    S: SignatureVerify<'a> + signature::Ed25519,
+{
+    fn read_complete(&self, page: &'static mut F::Page, error: flash::Error) {
+        let state = self.state.get();
+        match state {
+            State::Load(index) => {
+                if error != flash::Error::CommandComplete {
+                    // Treat an unreadable page like an erased one.
+                    for byte in page.as_mut().iter_mut() {
+                        *byte = 0xff;
+                    }
+                }
+                if index == 0 {
+                    self.hash_buffer.map(|buffer| {
+                        let len = cmp::min(buffer.len(), RECORD_SIZE);
+                        buffer[..len].copy_from_slice(&page.as_mut()[..len]);
+                    });
+                    self.page.replace(page);
+                    let result = self.read_page(State::Load(1), self.record_page + 1);
+                    self.check(state, result);
+                } else {
+                    let record = self.hash_buffer.map_or(BootRecord::default(), |buffer| {
+                        BootRecord::newest(buffer, page.as_mut())
+                    });
+                    self.record.set(Some(record));
+                    self.page.replace(page);
+                    self.done(state, ReturnCode::SUCCESS);
+                }
+            }
+            State::ReadPage(index) => {
+                if error != flash::Error::CommandComplete {
+                    self.page.replace(page);
+                    self.done(state, ReturnCode::FAIL);
+                } else {
+                    self.image
+                        .get()
+                        .map(|image| self.keep_trailer(image, index, page.as_mut()));
+                    self.page.replace(page);
+                    let result = self.hashed(index, 0);
+                    self.check(state, result);
+                }
+            }
+            _ => {
+                self.page.replace(page);
+            }
+        }
+    }
+
+    fn write_complete(&self, page: &'static mut F::Page, error: flash::Error) {
+        self.page.replace(page);
+        let state = self.state.get();
+        if error != flash::Error::CommandComplete {
+            self.done(state, ReturnCode::FAIL);
+            return;
+        }
+        match state {
+            State::WritePage(_) => {
+                self.image.get().map(|mut image| {
+                    image.written += cmp::min(self.page_size, image.length - image.written);
+                    self.image.set(Some(image));
+                });
+                self.done(state, ReturnCode::SUCCESS);
+            }
+            State::WriteRecord(op) => {
+                self.record.set(self.next_record.take());
+                if op == RecordOp::Finish {
+                    self.image.set(None);
+                }
+                self.done(state, ReturnCode::SUCCESS);
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_complete(&self, error: flash::Error) {
+        let state = self.state.get();
+        if error != flash::Error::CommandComplete {
+            self.done(state, ReturnCode::FAIL);
+            return;
+        }
+        let (next_state, page_number) = match state {
+            State::ErasePage(index) => (State::WritePage(index), self.slot_page(index)),
+            State::EraseRecord(op) => {
+                let record = self.next_record.get().unwrap_or_default();
+                self.page.map(|page| {
+                    let page = page.as_mut();
+                    for byte in page.iter_mut() {
+                        *byte = 0xff;
+                    }
+                    record.encode(page);
+                });
+                (State::WriteRecord(op), self.record_page + record.page())
+            }
+            _ => return,
+        };
+        let result = self.page.take().map_or(ReturnCode::EBUSY, |page| {
+            self.state.set(next_state);
+            match self.flash.write_page(page_number, page) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((result, page)) => {
+                    self.page.replace(page);
+                    result
+                }
+            }
+        });
+        self.check(next_state, result);
+    }
+}
+
+impl<'a, F, D, S> digest::Client<'a, [u8; 32]> for FirmwareUpdate<'a, F, D, S>
+where
+    F: Flash,
+    D: Digest<'a, [u8; 32]> + digest::Sha256,
+    S: SignatureVerify<'a> + signature::Ed25519,
+{
+    fn add_data_done(&'a self, result: Result<(), ReturnCode>, data: &'static mut [u8]) {
+        self.hash_buffer.replace(data);
+        let state = self.state.get();
+        if let State::HashPage(index, offset) = state {
+            let result = match result {
+                Ok(()) => self.hashed(index, offset),
+                Err(result) => result,
+            };
+            self.check(state, result);
+        }
+    }
+
+    fn hash_done(&'a self, result: Result<(), ReturnCode>, digest: &'static mut [u8; 32]) {
+        let value = *digest;
+        self.digest_buffer.replace(digest);
+        let state = self.state.get();
+        if state != State::Digest {
+            return;
+        }
+        let result = match result {
+            Ok(()) => self.verify(&value),
+            Err(result) => result,
+        };
+        self.check(state, result);
+    }
+}
+
+impl<'a, F, D, S> signature::Client<'a> for FirmwareUpdate<'a, F, D, S>
+where
+    F: Flash,
+    D: Digest<'a, [u8; 32]> + digest::Sha256,
+    S: SignatureVerify<'a> + signature::Ed25519,
+{
+    fn verification_done(
+        &'a self,
+        result: Result<bool, ReturnCode>,
+        message: &'static mut [u8],
+        signature: &'static mut [u8],
+    ) {
+        self.hash_buffer.replace(message);
+        self.signature_buffer.replace(signature);
+        let state = self.state.get();
+        if state != State::Verify {
+            return;
+        }
+        let result = match result {
+            Ok(true) => self.mark_for_boot(),
+            Ok(false) => ReturnCode::FAIL,
+            Err(result) => result,
+        };
+        self.check(state, result);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::sha::{Sha, Sha256State};
+    use crate::test_util::{self, Medium, Page, SimFlash, WriteMode, PAGE_SIZE};
+    use kernel::common::dynamic_deferred_call::{DeferredCallHandle, DynamicDeferredCallClient};
+    use std::vec::Vec;
+
+    const SLOT_PAGES: usize = 6;
+    const KEY: [u8; 32] = [7; 32];
+    const RECORD_PAGE: usize = 2 * SLOT_PAGES;
+
+    /// Verifier that takes a signature to be the message followed by the
+    /// public key, and completes when `step` is called.
+    struct TestVerifier {
+        client: OptionalCell<&'static dyn signature::Client<'static>>,
+        public_key: Cell<[u8; 32]>,
+        message: TakeCell<'static, [u8]>,
+        signature: TakeCell<'static, [u8]>,
+        valid: Cell<bool>,
+    }
+
+    impl TestVerifier {
+        fn step(&self) -> bool {
+            match (self.message.take(), self.signature.take()) {
+                (Some(message), Some(signature)) => {
+                    let valid = self.valid.get();
+                    self.client
+                        .map(move |client| client.verification_done(Ok(valid), message, signature));
+                    true
+                }
+                _ => false,
+            }
+        }
+    }
+
+    impl SignatureVerify<'static> for TestVerifier {
+        fn set_client(&'static self, client: &'static dyn signature::Client<'static>) {
+            self.client.set(client);
+        }
+
+        fn verify(
+            &'static self,
+            message: LeasableBuffer<'static, u8>,
+            signature: &'static mut [u8],
+        ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+            self.valid.set(
+                signature[..32] == message[..] && signature[32..] == self.public_key.get()[..],
+            );
+            self.message.replace(message.take());
+            self.signature.replace(signature);
+            Ok(())
+        }
+    }
+
+    impl signature::Ed25519 for TestVerifier {
+        fn set_mode_ed25519(&self, public_key: &[u8; 32]) -> Result<(), ReturnCode> {
+            self.public_key.set(*public_key);
+            Ok(())
+        }
+    }
+
+    struct TestClient {
+        result: Cell<Option<ReturnCode>>,
+    }
+
+    impl UpdateClient for TestClient {
+        fn begin_done(&self, result: ReturnCode) {
+            self.result.set(Some(result));
+        }
+
+        fn write_done(&self, result: ReturnCode) {
+            self.result.set(Some(result));
+        }
+
+        fn finish_done(&self, result: ReturnCode) {
+            self.result.set(Some(result));
+        }
+
+        fn confirm_done(&self, result: ReturnCode) {
+            self.result.set(Some(result));
+        }
+    }
+
+    struct Harness {
+        flash: &'static SimFlash,
+        sha: &'static Sha<'static>,
+        handle: DeferredCallHandle,
+        verifier: &'static TestVerifier,
+        update: &'static FirmwareUpdate<'static, SimFlash, Sha<'static>, TestVerifier>,
+        client: &'static TestClient,
+    }
+
+    impl Harness {
+        /// An updater for a kernel running from `running`, over `data`.
+        fn new(data: Vec<u8>, running: usize) -> Harness {
+            let deferred_caller = test_util::deferred_caller(1);
+            let sha: &'static Sha<'static> = test_util::leak(Sha::new(deferred_caller));
+            let handle = deferred_caller.register(sha).unwrap();
+            sha.initialize_callback_handle(handle);
+            let flash = SimFlash::new(Medium::with_data(data), WriteMode::ClearBits);
+            let verifier: &'static TestVerifier = test_util::leak(TestVerifier {
+                client: OptionalCell::empty(),
+                public_key: Cell::new([0; 32]),
+                message: TakeCell::empty(),
+                signature: TakeCell::empty(),
+                valid: Cell::new(false),
+            });
+            let update = test_util::leak(FirmwareUpdate::new(
+                flash,
+                sha,
+                verifier,
+                KEY,
+                [0, SLOT_PAGES],
+                SLOT_PAGES,
+                RECORD_PAGE,
+                running,
+                test_util::leak(Page::default()),
+                test_util::leak([0; 40]),
+                test_util::leak([0; 32]),
+                test_util::leak([0; SIGNATURE_SIZE]),
+            ));
+            let client: &'static TestClient = test_util::leak(TestClient {
+                result: Cell::new(None),
+            });
+            flash.set_client(update);
+            sha.set_client(update);
+            verifier.set_client(update);
+            update.set_client(client);
+            let harness = Harness {
+                flash: flash,
+                sha: sha,
+                handle: handle,
+                verifier: verifier,
+                update: update,
+                client: client,
+            };
+            assert_eq!(update.load(), ReturnCode::SUCCESS);
+            harness.run();
+            harness
+        }
+
+        fn blank() -> Harness {
+            Harness::new(std::vec![0xff; (RECORD_PAGE + 2) * PAGE_SIZE], 0)
+        }
+
+        /// Runs until the operation in progress ends.
+        fn run(&self) -> Option<ReturnCode> {
+            for _ in 0..1000 {
+                if !self.flash.step() && !self.verifier.step() {
+                    self.sha.call(self.handle);
+                }
+                if let Some(result) = self.client.result.take() {
+                    return Some(result);
+                }
+                if self.update.state.get() == State::Idle {
+                    return None;
+                }
+            }
+            panic!("update never finished");
+        }
+
+        fn call(&self, result: ReturnCode) -> Option<ReturnCode> {
+            match result {
+                ReturnCode::SUCCESS => self.run(),
+                result => Some(result),
+            }
+        }
+
+        fn install(&self, image: &[u8]) -> Option<ReturnCode> {
+            let result = self.call(self.update.begin(image.len()));
+            if result != Some(ReturnCode::SUCCESS) {
+                return result;
+            }
+            for chunk in image.chunks(PAGE_SIZE) {
+                let result = self.call(self.update.write(chunk));
+                if result != Some(ReturnCode::SUCCESS) {
+                    return result;
+                }
+            }
+            self.call(self.update.finish())
+        }
+
+        /// Reboots, running the bootloader, and returns the updater of the
+        /// kernel in the slot it chose.
+        fn reboot(&self) -> Harness {
+            let data = self.flash.medium.data.borrow().clone();
+            let records = &data[RECORD_PAGE * PAGE_SIZE..];
+            let mut record = BootRecord::newest(&records[..RECORD_SIZE], &records[PAGE_SIZE..]);
+            let (slot, changed) = record.select_slot();
+            let mut data = data.clone();
+            if changed {
+                let start = (RECORD_PAGE + record.page()) * PAGE_SIZE;
+                let page = &mut data[start..start + PAGE_SIZE];
+                for byte in page.iter_mut() {
+                    *byte = 0xff;
+                }
+                record.encode(page);
+            }
+            Harness::new(data, slot)
+        }
+
+        fn slot(&self, slot: usize) -> Vec<u8> {
+            let start = slot * SLOT_PAGES * PAGE_SIZE;
+            self.flash.medium.data.borrow()[start..start + SLOT_PAGES * PAGE_SIZE].to_vec()
+        }
+    }
+
+    /// An image of a kernel `length` bytes long, signed with `key`.
+    fn image(length: usize, seed: u8, version: u32, key: &[u8; 32]) -> Vec<u8> {
+        let mut image: Vec<u8> = (0..length).map(|i| (i as u8).wrapping_mul(seed)).collect();
+        image.extend_from_slice(&version.to_le_bytes());
+        image.extend_from_slice(&(length as u32).to_le_bytes());
+        image.extend_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        let mut hash = Sha256State::new_sha256();
+        hash.update(&image);
+        let mut digest = [0; 32];
+        hash.finish(&mut digest);
+        image.extend_from_slice(&digest);
+        image.extend_from_slice(key);
+        image
+    }
+
+    #[test]
+    fn update_and_confirm() {
+        let harness = Harness::blank();
+        assert_eq!(harness.update.boot_record(), Some(BootRecord::default()));
+
+        // The signature runs over into a last page, which is partial, and
+        // pages are longer than the hash buffer.
+        let new_image = image(3 * PAGE_SIZE + 200, 7, 5, &KEY);
+        assert_eq!(new_image.len(), 4 * PAGE_SIZE + 20);
+        assert_eq!(harness.install(&new_image), Some(ReturnCode::SUCCESS));
+        assert_eq!(&harness.slot(1)[..new_image.len()], &new_image[..]);
+        let record = harness.update.boot_record().unwrap();
+        assert_eq!(record.pending, Some(1));
+        assert_eq!(record.tries, MAX_TRIES);
+        assert_eq!(record.active, 0);
+
+        // The new kernel boots and confirms itself.
+        let harness = harness.reboot();
+        assert_eq!(harness.update.running, 1);
+        assert_eq!(harness.update.boot_record().unwrap().tries, MAX_TRIES - 1);
+        assert_eq!(
+            harness.call(harness.update.confirm()),
+            Some(ReturnCode::SUCCESS)
+        );
+        let record = harness.update.boot_record().unwrap();
+        assert_eq!((record.active, record.pending), (1, None));
+        assert_eq!(record.rollback_counter, 5);
+        assert_eq!(harness.update.confirm(), ReturnCode::EALREADY);
+
+        // It keeps booting, and refuses older images.
+        let harness = harness.reboot();
+        assert_eq!(harness.update.running, 1);
+        let old_image = image(PAGE_SIZE, 3, 4, &KEY);
+        assert_eq!(harness.install(&old_image), Some(ReturnCode::EINVAL));
+        assert_eq!(harness.update.boot_record().unwrap().pending, None);
+    }
+
+    #[test]
+    fn bad_signature() {
+        let harness = Harness::blank();
+        let other_key = image(2 * PAGE_SIZE, 7, 1, &[8; 32]);
+        assert_eq!(harness.install(&other_key), Some(ReturnCode::FAIL));
+        assert_eq!(harness.update.boot_record().unwrap().pending, None);
+        assert_eq!(harness.reboot().update.running, 0);
+
+        // The version is signed, so it can't be raised.
+        let mut raised = image(2 * PAGE_SIZE, 7, 1, &KEY);
+        raised[2 * PAGE_SIZE] = 9;
+        assert_eq!(harness.install(&raised), Some(ReturnCode::FAIL));
+        assert_eq!(harness.update.boot_record().unwrap().pending, None);
+
+        // Pages must be whole, and the image must hold a kernel and not
+        // outgrow the slot.
+        assert_eq!(harness.update.begin(100), ReturnCode::SUCCESS);
+        harness.run();
+        assert_eq!(harness.update.write(&[0; 9]), ReturnCode::ESIZE);
+        assert_eq!(
+            harness.update.begin(TRAILER_SIZE + SIGNATURE_SIZE),
+            ReturnCode::ESIZE
+        );
+        assert_eq!(
+            harness.update.begin(SLOT_PAGES * PAGE_SIZE + 1),
+            ReturnCode::ESIZE
+        );
+    }
+
+    #[test]
+    fn rollback() {
+        let harness = Harness::blank();
+        let new_image = image(PAGE_SIZE, 9, 2, &KEY);
+        assert_eq!(harness.install(&new_image), Some(ReturnCode::SUCCESS));
+
+        // The new kernel never confirms, so after its tries the bootloader
+        // goes back to the old one.
+        let mut harness = harness.reboot();
+        for _ in 1..MAX_TRIES {
+            assert_eq!(harness.update.running, 1);
+            // An unconfirmed kernel can't be replaced.
+            assert_eq!(harness.update.begin(100), ReturnCode::EBUSY);
+            harness = harness.reboot();
+        }
+        assert_eq!(harness.update.running, 1);
+        let harness = harness.reboot();
+        assert_eq!(harness.update.running, 0);
+        let record = harness.update.boot_record().unwrap();
+        assert_eq!((record.active, record.pending), (0, None));
+        assert_eq!(record.rollback_counter, 0);
+        assert_eq!(harness.update.confirm(), ReturnCode::EALREADY);
+    }
+
+    #[test]
+    fn torn_record() {
+        let mut record = BootRecord::default();
+        record.sequence = 7;
+        record.active = 1;
+        let mut first = [0xff; RECORD_SIZE];
+        let mut second = [0xff; RECORD_SIZE];
+        record.encode(&mut first);
+        record.sequence = 8;
+        record.active = 0;
+        record.encode(&mut second);
+        assert_eq!(BootRecord::newest(&first, &second), record);
+
+        // A reset while the newer record was written leaves the older one.
+        second[3] ^= 1;
+        assert_eq!(BootRecord::newest(&first, &second).active, 1);
+        assert_eq!(
+            BootRecord::newest(&[0xff; RECORD_SIZE], &[0xff; RECORD_SIZE]),
+            BootRecord::default()
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! Lets a privileged process update the kernel.
+//!
+//! The process loaded from a flash region chosen by the board can write a new
+//! kernel image into the inactive slot of a `firmware_update::FirmwareUpdate`,
+//! and confirm the kernel it runs on after an update. Other processes get
+//! ENODEVICE.
+//!
+//! Process names come from TBF headers that any process can set, so they
+//! don't decide who may update the kernel. Where a process is in flash is
+//! decided when it is installed, and processes can only write to their own
+//! flash, so the board reserves a region, such as the first process slot,
+//! for the updater.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let update_driver = static_init!(
+//!     capsules::firmware_update_driver::FirmwareUpdateDriver<'static, Flash, Sha, SignatureVerifier>,
+//!     capsules::firmware_update_driver::FirmwareUpdateDriver::new(
+//!         update,
+//!         0x40000..0x48000, // Flash of the updater process
+//!         board_kernel.create_grant(&grant_cap),
+//!     )
+//! );
+//! update.set_client(update_driver);
+//! ```
+//!
+//! The system call interface is documented in
+//! doc/syscalls/50006_firmware_update.md.
+
+use crate::driver;
+use crate::firmware_update::{FirmwareUpdate, UpdateClient};
+use core::ops::Range;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::digest::{self, Digest};
+use kernel::hil::flash::Flash;
+use kernel::hil::signature::{self, SignatureVerify};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::FirmwareUpdate as usize;
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    buffer: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct FirmwareUpdateDriver<'a, F, D, S>
+where
+    F: Flash + 'static,
+    D: Digest<'a, [u8; 32]> + digest::Sha256,
+    S: SignatureVerify<'a> + signature::Ed25519,
+{
+    update: &'a FirmwareUpdate<'a, F, D, S>,
+    /// Addresses of the flash the updater process is loaded from.
+    updater_flash: Range<usize>,
+    apps: Grant<App>,
+    serving_app: Serving<AppId>,
+}
+
+/// The process an operation of the updater runs for. One operation runs at
+/// a time, and its end is reported to the process that started it.
+struct Serving<T: Copy>(OptionalCell<T>);
+
+impl<T: Copy> Serving<T> {
+    /// Starts `operation` for `process` unless another operation is running.
+    /// If it starts, `process` is served until `done` is called.
+    fn start(&self, process: T, operation: impl FnOnce() -> ReturnCode) -> ReturnCode {
+        if self.0.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let result = operation();
+        if result == ReturnCode::SUCCESS {
+            self.0.set(process);
+        }
+        result
+    }
+
+    /// Ends the running operation, returning the process it ran for.
+    fn done(&self) -> Option<T> {
+        self.0.take()
+    }
+}
+
+/// Whether the editable flash `range` of a process lies in `updater_flash`.
+fn in_updater_flash(updater_flash: &Range<usize>, range: (usize, usize)) -> bool {
+    let (start, end) = range;
+    start < end && updater_flash.start <= start && end <= updater_flash.end
+}
+
+impl<'a, F, D, S> FirmwareUpdateDriver<'a, F, D, S>
+where
+    F: Flash + 'static,
+    D: Digest<'a, [u8; 32]> + digest::Sha256,
+    S: SignatureVerify<'a> + signature::Ed25519,
+{
+    pub fn new(
+        update: &'a FirmwareUpdate<'a, F, D, S>,
+        updater_flash: Range<usize>,
+        grant: Grant<App>,
+    ) -> FirmwareUpdateDriver<'a, F, D, S> {
+        FirmwareUpdateDriver {
+            update: update,
+            updater_flash: updater_flash,
+            apps: grant,
+            serving_app: Serving(OptionalCell::empty()),
+        }
+    }
+
+    /// Whether `appid` was loaded from the updater's flash region.
+    fn is_privileged(&self, appid: AppId) -> bool {
+        in_updater_flash(&self.updater_flash, appid.get_editable_flash_range())
+    }
+
+    /// Starts an operation of the updater for `appid`.
+    fn start(&self, appid: AppId, operation: impl FnOnce(&mut App) -> ReturnCode) -> ReturnCode {
+        self.serving_app.start(appid, || {
+            self.apps
+                .enter(appid, |app, _| operation(app))
+                .unwrap_or_else(|err| err.into())
+        })
+    }
+
+    /// Reports the end of an operation to the serving app.
+    fn operation_done(&self, result: ReturnCode) {
+        self.serving_app.done().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                if let Some(mut callback) = app.callback {
+                    callback.schedule(From::from(result), 0, 0);
+                }
+            });
+        });
+    }
+}
+
+impl<'a, F, D, S> UpdateClient for FirmwareUpdateDriver<'a, F, D, S>
+where
+    F: Flash + 'static,
+    D: Digest<'a, [u8; 32]> + digest::Sha256,
+    S: SignatureVerify<'a> + signature::Ed25519,
+{
+    fn begin_done(&self, result: ReturnCode) {
+        self.operation_done(result);
+    }
+
+    fn write_done(&self, result: ReturnCode) {
+        self.operation_done(result);
+    }
+
+    fn finish_done(&self, result: ReturnCode) {
+        self.operation_done(result);
+    }
+
+    fn confirm_done(&self, result: ReturnCode) {
+        self.operation_done(result);
+    }
+}
+
+impl<'a, F, D, S> Driver for FirmwareUpdateDriver<'a, F, D, S>
+where
+    F: Flash + 'static,
+    D: Digest<'a, [u8; 32]> + digest::Sha256,
+    S: SignatureVerify<'a> + signature::Ed25519,
+{
+    /// Setup the buffer holding pages of the image.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer for pages.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        if !self.is_privileged(appid) {
+            return ReturnCode::ENODEVICE;
+        }
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                0 => {
+                    app.buffer = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Subscribe to the completion of operations.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Completion of an operation. The callback signature is
+    ///        `fn(result: u32)`.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        if !self.is_privileged(appid) {
+            return ReturnCode::ENODEVICE;
+        }
+        self.apps
+            .enter(appid, |app, _| match subscribe_num {
+                0 => {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Update the kernel.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Begin an update, with the image length, signature included,
+    ///        in `arg1`.
+    /// - `2`: Write the next page of the image, the first `arg1` bytes of the
+    ///        buffer.
+    /// - `3`: Verify the signature and version of the image, and mark it
+    ///        for boot.
+    /// - `4`: Confirm the running kernel after an update.
+    /// - `5`: Get the page size.
+    /// - `6`: Get the rollback counter.
+    fn command(&self, command_num: usize, arg1: usize, _arg2: usize, appid: AppId) -> ReturnCode {
+        if command_num == 0 {
+            return ReturnCode::SUCCESS;
+        }
+        if !self.is_privileged(appid) {
+            return ReturnCode::ENODEVICE;
+        }
+        match command_num {
+            1 => self.start(appid, |_| self.update.begin(arg1)),
+            2 => self.start(appid, |app| {
+                app.buffer
+                    .as_ref()
+                    .map_or(ReturnCode::ERESERVE, |buffer| match buffer.len() {
+                        len if arg1 > len => ReturnCode::ESIZE,
+                        _ => self.update.write(&buffer.as_ref()[..arg1]),
+                    })
+            }),
+            3 => self.start(appid, |_| self.update.finish()),
+            4 => self.start(appid, |_| self.update.confirm()),
+            5 => ReturnCode::SuccessWithValue {
+                value: self.update.page_size(),
+            },
+            6 => self
+                .update
+                .boot_record()
+                .map_or(ReturnCode::EOFF, |record| ReturnCode::SuccessWithValue {
+                    value: record.rollback_counter as usize,
+                }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::cell::Cell;
+
+    #[test]
+    fn updater_flash() {
+        let updater_flash = 0x40000..0x48000;
+        assert!(in_updater_flash(&updater_flash, (0x40000, 0x48000)));
+        assert!(in_updater_flash(&updater_flash, (0x40400, 0x44000)));
+        // Partly or entirely outside the region
+        assert!(!in_updater_flash(&updater_flash, (0x3fc00, 0x44000)));
+        assert!(!in_updater_flash(&updater_flash, (0x44000, 0x48400)));
+        assert!(!in_updater_flash(&updater_flash, (0x48000, 0x50000)));
+        // The range of a process that no longer exists
+        assert!(!in_updater_flash(&(0..0x48000), (0, 0)));
+    }
+
+    #[test]
+    fn one_operation_at_a_time() {
+        let serving = Serving(OptionalCell::empty());
+        let started = Cell::new(0);
+        let operation = |result| {
+            started.set(started.get() + 1);
+            result
+        };
+
+        // An operation that fails to start leaves the updater free
+        assert_eq!(
+            serving.start(1, || operation(ReturnCode::EINVAL)),
+            ReturnCode::EINVAL
+        );
+        assert_eq!(serving.done(), None);
+
+        assert_eq!(
+            serving.start(1, || operation(ReturnCode::SUCCESS)),
+            ReturnCode::SUCCESS
+        );
+        // Nothing else starts until the operation is done, for this process
+        // or another one
+        assert_eq!(
+            serving.start(1, || operation(ReturnCode::SUCCESS)),
+            ReturnCode::EBUSY
+        );
+        assert_eq!(
+            serving.start(2, || operation(ReturnCode::SUCCESS)),
+            ReturnCode::EBUSY
+        );
+        assert_eq!(started.get(), 2);
+
+        // The end goes to the process that started the operation, once
+        assert_eq!(serving.done(), Some(1));
+        assert_eq!(serving.done(), None);
+
+        assert_eq!(
+            serving.start(2, || operation(ReturnCode::SUCCESS)),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(serving.done(), Some(2));
+        assert_eq!(started.get(), 3);
+    }
+}
@@ -0,0 +1,965 @@
+//! Flash translation layer with wear leveling.
+//!
+//! The FTL presents a region of flash as a smaller number of logical pages,
+//! and implements `hil::flash::Flash` over them, so it can sit between
+//! `NonvolatileToPages` (or any other user of flash pages) and the flash
+//! driver:
+//!
+//! ```plain
+//! hil::nonvolatile_storage::NonvolatileStorage
+//!                ┌────────────────────┐
+//!                │ NonvolatileToPages │
+//!                └────────────────────┘
+//!               hil::flash::Flash (logical pages)
+//!                ┌────────────────────┐
+//!                │    This module     │
+//!                └────────────────────┘
+//!               hil::flash::Flash (physical pages)
+//! ```
+//!
+//! Flash layout
+//! ------------
+//!
+//! Each physical page in use holds a copy of a logical page after a header:
+//!
+//! ```text
+//! crc (4) | sequence number (4) | erase count (4) | logical page (2) | 0xffff
+//! ```
+//!
+//! All numbers are little endian, and the CRC covers the rest of the page.
+//! Logical pages are therefore `HEADER_SIZE` bytes smaller than physical
+//! pages, which `FtlPage` takes care of. The copy of a logical page with the
+//! highest sequence number is its current contents, and the map from logical
+//! to physical pages is rebuilt from the headers when the FTL is first used.
+//! Logical pages that were never written read as 0xff, and erasing a logical
+//! page writes it full of 0xff.
+//!
+//! Atomic writes and wear leveling
+//! -------------------------------
+//!
+//! A logical page is never written in place. Each write erases a free
+//! physical page and writes the new copy there, and the old copy only
+//! becomes free once the new one is written. If power fails during the
+//! write, the new copy fails its CRC and the old copy is still current, so a
+//! logical page always holds either its old or its new contents.
+//!
+//! Writes go to the free page that has been erased the fewest times. The
+//! region has `spare_pages` more physical pages than logical pages, so writes
+//! to a hot logical page rotate through at least that many physical pages.
+//! So that pages holding data that rarely changes also take their share of
+//! erases, after each write the FTL compares the most worn free page with the
+//! least worn page in use. If the difference is more than `WEAR_THRESHOLD`
+//! erases, the data of the least worn page is moved to the most worn one.
+//!
+//! Erase counts are kept in the page headers. A page whose header is damaged
+//! is assumed to be as worn as the most worn page, and a blank page to be
+//! unused.
+//!
+//! The region must have at most `MAX_PAGES` pages and at least one spare.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::{hil, static_init};
+//!
+//! static mut FTL_PAGE: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//!
+//! let ftl = static_init!(
+//!     capsules::ftl::Ftl<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::ftl::Ftl::new(
+//!         &sam4l::flashcalw::FLASH_CONTROLLER,
+//!         0x60000 / 512,
+//!         32,
+//!         4,
+//!         &mut FTL_PAGE,
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, ftl);
+//! ftl.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ftl)
+//!         .expect("no deferred call slot available for ftl"),
+//! );
+//!
+//! let pagebuffer = static_init!(
+//!     capsules::ftl::FtlPage<sam4l::flashcalw::Sam4lPage>,
+//!     Default::default()
+//! );
+//! let nv_to_page = static_init!(
+//!     capsules::nonvolatile_to_pages::NonvolatileToPages<
+//!         'static,
+//!         capsules::ftl::Ftl<'static, sam4l::flashcalw::FLASHCALW>,
+//!     >,
+//!     capsules::nonvolatile_to_pages::NonvolatileToPages::new(ftl, pagebuffer)
+//! );
+//! hil::flash::HasClient::set_client(ftl, nv_to_page);
+//! ```
+
+use crate::crc32::crc32;
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::flash::{self, Flash};
+use kernel::ReturnCode;
+
+/// Most physical pages in a region.
+pub const MAX_PAGES: usize = 32;
+
+/// Size of the header at the start of each physical page.
+pub const HEADER_SIZE: usize = 16;
+
+/// How many more times the most worn free page may have been erased than the
+/// least worn page in use, before the data of the latter is moved.
+pub const WEAR_THRESHOLD: u32 = 8;
+
+/// Erase count of a page whose header is damaged, while mounting.
+const UNKNOWN_COUNT: u32 = 0xffff_ffff;
+
+/// A logical page: a physical page without the header.
+pub struct FtlPage<P>(P);
+
+impl<P: Default> Default for FtlPage<P> {
+    fn default() -> Self {
+        FtlPage(P::default())
+    }
+}
+
+impl<P: AsMut<[u8]>> AsMut<[u8]> for FtlPage<P> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        let page = self.0.as_mut();
+        let length = page.len() - HEADER_SIZE;
+        &mut page[..length]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Usage {
+    Free,
+    Used { logical: usize, sequence: u32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PhysicalPage {
+    usage: Usage,
+    erase_count: u32,
+}
+
+impl Default for PhysicalPage {
+    fn default() -> Self {
+        PhysicalPage {
+            usage: Usage::Free,
+            erase_count: 0,
+        }
+    }
+}
+
+/// A client operation, on a logical page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Read(usize),
+    Write(usize),
+    Erase(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reading the header of a physical page to rebuild the map.
+    Mount(usize),
+    /// Reading a logical page for the client.
+    Read,
+    /// Erasing physical page `target` to write logical page `logical` to it.
+    /// `moving` is true if the data is being moved for wear leveling, rather
+    /// than written by the client.
+    Erase {
+        target: usize,
+        logical: usize,
+        moving: bool,
+    },
+    Write {
+        target: usize,
+        logical: usize,
+        moving: bool,
+    },
+    /// Reading physical page `from` to move it to the worn page `to`.
+    MoveRead {
+        from: usize,
+        to: usize,
+    },
+    /// Waiting for the deferred call to finish a read of an unwritten page.
+    Deferred,
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buffer[offset],
+        buffer[offset + 1],
+        buffer[offset + 2],
+        buffer[offset + 3],
+    ])
+}
+
+fn write_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Returns the logical page, sequence number and erase count in the header
+/// of `page`, if the page is intact.
+fn parse_header(page: &[u8]) -> Option<(usize, u32, u32)> {
+    if page[14] != 0xff || page[15] != 0xff || read_u32(page, 0) != crc32(&page[4..]) {
+        return None;
+    }
+    let logical = u16::from_le_bytes([page[12], page[13]]) as usize;
+    Some((logical, read_u32(page, 4), read_u32(page, 8)))
+}
+
+fn write_header(page: &mut [u8], logical: usize, sequence: u32, erase_count: u32) {
+    write_u32(page, 4, sequence);
+    write_u32(page, 8, erase_count);
+    page[12..14].copy_from_slice(&(logical as u16).to_le_bytes());
+    page[14] = 0xff;
+    page[15] = 0xff;
+    let crc = crc32(&page[4..]);
+    write_u32(page, 0, crc);
+}
+
+pub struct Ftl<'a, F: Flash + 'static> {
+    driver: &'a F,
+    client: OptionalCell<&'a dyn flash::Client<Ftl<'a, F>>>,
+    /// Flash page number of the first page of the region.
+    first_page: usize,
+    num_pages: usize,
+    spare_pages: usize,
+    pages: [Cell<PhysicalPage>; MAX_PAGES],
+    /// The physical page holding each logical page.
+    map: [Cell<Option<usize>>; MAX_PAGES],
+    /// Buffer for physical pages.
+    buffer: TakeCell<'static, F::Page>,
+    /// The client's page during a read or write.
+    client_page: TakeCell<'static, FtlPage<F::Page>>,
+    mounted: Cell<bool>,
+    state: Cell<State>,
+    operation: OptionalCell<Operation>,
+    /// Sequence number of the next copy written.
+    sequence: Cell<u32>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a, F: Flash> Ftl<'a, F> {
+    /// The region is `num_pages` flash pages from page `first_page`, and
+    /// holds `num_pages - spare_pages` logical pages.
+    pub fn new(
+        driver: &'a F,
+        first_page: usize,
+        num_pages: usize,
+        spare_pages: usize,
+        buffer: &'static mut F::Page,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> Ftl<'a, F> {
+        Ftl {
+            driver: driver,
+            client: OptionalCell::empty(),
+            first_page: first_page,
+            num_pages: num_pages,
+            spare_pages: spare_pages,
+            pages: Default::default(),
+            map: Default::default(),
+            buffer: TakeCell::new(buffer),
+            client_page: TakeCell::empty(),
+            mounted: Cell::new(false),
+            state: Cell::new(State::Idle),
+            operation: OptionalCell::empty(),
+            sequence: Cell::new(0),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Number of logical pages.
+    pub fn logical_pages(&self) -> usize {
+        self.num_pages.saturating_sub(self.spare_pages)
+    }
+
+    fn start_with_page(
+        &self,
+        operation: Operation,
+        page: &'static mut FtlPage<F::Page>,
+    ) -> Result<(), (ReturnCode, &'static mut FtlPage<F::Page>)> {
+        if self.operation.is_some() {
+            return Err((ReturnCode::EBUSY, page));
+        }
+        self.client_page.replace(page);
+        match self.start(operation) {
+            ReturnCode::SUCCESS => Ok(()),
+            result => Err((result, self.client_page.take().unwrap())),
+        }
+    }
+
+    fn start(&self, operation: Operation) -> ReturnCode {
+        if self.operation.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        let logical = match operation {
+            Operation::Read(logical) | Operation::Write(logical) | Operation::Erase(logical) => {
+                logical
+            }
+        };
+        let page_fits = self
+            .buffer
+            .map_or(false, |buffer| buffer.as_mut().len() > HEADER_SIZE);
+        if self.num_pages > MAX_PAGES
+            || self.spare_pages == 0
+            || logical >= self.logical_pages()
+            || !page_fits
+        {
+            return ReturnCode::EINVAL;
+        }
+        self.operation.set(operation);
+        let result = if self.mounted.get() {
+            self.run()
+        } else {
+            for logical in self.map.iter() {
+                logical.set(None);
+            }
+            self.sequence.set(0);
+            self.read_physical(0, State::Mount(0))
+        };
+        if result != ReturnCode::SUCCESS {
+            self.operation.clear();
+        }
+        result
+    }
+
+    /// Runs the operation once the map is known.
+    fn run(&self) -> ReturnCode {
+        match self.operation.map(|operation| *operation) {
+            Some(Operation::Read(logical)) => match self.map[logical].get() {
+                Some(physical) => self.read_physical(physical, State::Read),
+                None => {
+                    self.client_page.map(|page| {
+                        for byte in page.as_mut().iter_mut() {
+                            *byte = 0xff;
+                        }
+                    });
+                    self.handle.map_or(ReturnCode::EOFF, |handle| {
+                        self.state.set(State::Deferred);
+                        self.deferred_caller.set(*handle);
+                        ReturnCode::SUCCESS
+                    })
+                }
+            },
+            Some(Operation::Write(logical)) | Some(Operation::Erase(logical)) => {
+                match self.free_page(false) {
+                    Some(target) => self.erase_physical(
+                        target,
+                        State::Erase {
+                            target: target,
+                            logical: logical,
+                            moving: false,
+                        },
+                    ),
+                    None => ReturnCode::FAIL,
+                }
+            }
+            None => ReturnCode::FAIL,
+        }
+    }
+
+    /// The least or most worn free physical page.
+    fn free_page(&self, most_worn: bool) -> Option<usize> {
+        let free = (0..self.num_pages).filter(|&page| self.pages[page].get().usage == Usage::Free);
+        let erase_count = |page: &usize| self.pages[*page].get().erase_count;
+        if most_worn {
+            free.max_by_key(erase_count)
+        } else {
+            free.min_by_key(erase_count)
+        }
+    }
+
+    /// The least worn page in use and the most worn free page, if they differ
+    /// by more than `WEAR_THRESHOLD` erases.
+    fn worn_pair(&self) -> Option<(usize, usize)> {
+        let cold = (0..self.num_pages)
+            .filter(|&page| self.pages[page].get().usage != Usage::Free)
+            .min_by_key(|&page| self.pages[page].get().erase_count)?;
+        let worn = self.free_page(true)?;
+        if self.pages[worn].get().erase_count > self.pages[cold].get().erase_count + WEAR_THRESHOLD
+        {
+            Some((cold, worn))
+        } else {
+            None
+        }
+    }
+
+    fn read_physical(&self, page: usize, state: State) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            self.state.set(state);
+            match self.driver.read_page(self.first_page + page, buffer) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((result, buffer)) => {
+                    self.state.set(State::Idle);
+                    self.buffer.replace(buffer);
+                    result
+                }
+            }
+        })
+    }
+
+    fn write_physical(&self, page: usize, state: State) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            self.state.set(state);
+            match self.driver.write_page(self.first_page + page, buffer) {
+                Ok(()) => ReturnCode::SUCCESS,
+                Err((result, buffer)) => {
+                    self.state.set(State::Idle);
+                    self.buffer.replace(buffer);
+                    result
+                }
+            }
+        })
+    }
+
+    fn erase_physical(&self, page: usize, state: State) -> ReturnCode {
+        self.state.set(state);
+        let result = self.driver.erase_page(self.first_page + page);
+        if result != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+        }
+        result
+    }
+
+    /// Continues an operation, failing it if the next step can't start.
+    fn step(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.fail();
+        }
+    }
+
+    /// Ends an operation after the flash failed. If data was being moved
+    /// for wear leveling, the client's write already succeeded, and the data
+    /// stays where it was.
+    fn fail(&self) {
+        match self.state.get() {
+            State::MoveRead { .. }
+            | State::Erase { moving: true, .. }
+            | State::Write { moving: true, .. } => self.finish(flash::Error::CommandComplete),
+            _ => self.finish(flash::Error::FlashError),
+        }
+    }
+
+    fn finish(&self, error: flash::Error) {
+        self.state.set(State::Idle);
+        self.operation.take().map(|operation| {
+            self.client.map(|client| match operation {
+                Operation::Read(_) => {
+                    self.client_page
+                        .take()
+                        .map(|page| client.read_complete(page, error));
+                }
+                Operation::Write(_) => {
+                    self.client_page
+                        .take()
+                        .map(|page| client.write_complete(page, error));
+                }
+                Operation::Erase(_) => client.erase_complete(error),
+            });
+        });
+    }
+
+    fn mount_read(&self, page: usize) {
+        let header = self.buffer.map_or(None, |buffer| {
+            let buffer = buffer.as_mut();
+            match parse_header(buffer) {
+                Some(header) => Some(header),
+                None if buffer.iter().all(|byte| *byte == 0xff) => Some((usize::MAX, 0, 0)),
+                None => Some((usize::MAX, 0, UNKNOWN_COUNT)),
+            }
+        });
+        let (logical, sequence, erase_count) = match header {
+            Some(header) => header,
+            None => return self.fail(),
+        };
+        let mut physical = PhysicalPage {
+            usage: Usage::Free,
+            erase_count: erase_count,
+        };
+        if logical < self.logical_pages() {
+            let newer = match self.map[logical].get() {
+                None => true,
+                Some(current) => match self.pages[current].get().usage {
+                    Usage::Used {
+                        sequence: current_sequence,
+                        ..
+                    } => sequence > current_sequence,
+                    Usage::Free => true,
+                },
+            };
+            if newer {
+                if let Some(current) = self.map[logical].get() {
+                    let mut old = self.pages[current].get();
+                    old.usage = Usage::Free;
+                    self.pages[current].set(old);
+                }
+                self.map[logical].set(Some(page));
+                physical.usage = Usage::Used {
+                    logical: logical,
+                    sequence: sequence,
+                };
+            }
+            if sequence >= self.sequence.get() {
+                self.sequence.set(sequence.wrapping_add(1));
+            }
+        }
+        self.pages[page].set(physical);
+
+        if page + 1 < self.num_pages {
+            self.step(self.read_physical(page + 1, State::Mount(page + 1)));
+            return;
+        }
+
+        // Pages with damaged headers are assumed to be as worn as any
+        let most_worn = (0..self.num_pages)
+            .map(|page| self.pages[page].get().erase_count)
+            .filter(|count| *count != UNKNOWN_COUNT)
+            .max()
+            .unwrap_or(0);
+        for page in self.pages[..self.num_pages].iter() {
+            let mut physical = page.get();
+            if physical.erase_count == UNKNOWN_COUNT {
+                physical.erase_count = most_worn;
+                page.set(physical);
+            }
+        }
+        self.mounted.set(true);
+        self.step(self.run());
+    }
+
+    fn client_read(&self) {
+        let intact = self.buffer.map_or(false, |buffer| {
+            let buffer = buffer.as_mut();
+            if parse_header(buffer).is_none() {
+                return false;
+            }
+            self.client_page.map(|page| {
+                page.as_mut().copy_from_slice(&buffer[HEADER_SIZE..]);
+            });
+            true
+        });
+        if intact {
+            self.finish(flash::Error::CommandComplete);
+        } else {
+            self.finish(flash::Error::FlashError);
+        }
+    }
+
+    fn erased(&self, target: usize, logical: usize, moving: bool) {
+        let mut physical = self.pages[target].get();
+        physical.erase_count += 1;
+        self.pages[target].set(physical);
+
+        let operation = self.operation.map(|operation| *operation);
+        self.buffer.map(|buffer| {
+            let buffer = buffer.as_mut();
+            if !moving {
+                match operation {
+                    Some(Operation::Write(_)) => {
+                        self.client_page.map(|page| {
+                            buffer[HEADER_SIZE..].copy_from_slice(page.as_mut());
+                        });
+                    }
+                    _ => {
+                        for byte in buffer[HEADER_SIZE..].iter_mut() {
+                            *byte = 0xff;
+                        }
+                    }
+                }
+            }
+            write_header(buffer, logical, self.sequence.get(), physical.erase_count);
+        });
+        self.step(self.write_physical(
+            target,
+            State::Write {
+                target: target,
+                logical: logical,
+                moving: moving,
+            },
+        ));
+    }
+
+    fn written(&self, target: usize, logical: usize, moving: bool) {
+        let sequence = self.sequence.get();
+        self.sequence.set(sequence.wrapping_add(1));
+        if let Some(old) = self.map[logical].get() {
+            let mut physical = self.pages[old].get();
+            physical.usage = Usage::Free;
+            self.pages[old].set(physical);
+        }
+        self.map[logical].set(Some(target));
+        let mut physical = self.pages[target].get();
+        physical.usage = Usage::Used {
+            logical: logical,
+            sequence: sequence,
+        };
+        self.pages[target].set(physical);
+
+        if moving {
+            self.finish(flash::Error::CommandComplete);
+            return;
+        }
+        match self.worn_pair() {
+            Some((from, to)) => {
+                self.step(self.read_physical(from, State::MoveRead { from: from, to: to }))
+            }
+            None => self.finish(flash::Error::CommandComplete),
+        }
+    }
+
+    fn move_read(&self, from: usize, to: usize) {
+        let intact = self
+            .buffer
+            .map_or(false, |buffer| parse_header(buffer.as_mut()).is_some());
+        match self.pages[from].get().usage {
+            Usage::Used { logical, .. } if intact => self.step(self.erase_physical(
+                to,
+                State::Erase {
+                    target: to,
+                    logical: logical,
+                    moving: true,
+                },
+            )),
+            _ => self.finish(flash::Error::CommandComplete),
+        }
+    }
+}
+
+impl<'a, F: Flash, C: flash::Client<Self>> flash::HasClient<'a, C> for Ftl<'a, F> {
+    fn set_client(&self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl<F: Flash> Flash for Ftl<'_, F> {
+    type Page = FtlPage<F::Page>;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        self.start_with_page(Operation::Read(page_number), buf)
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
+        self.start_with_page(Operation::Write(page_number), buf)
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        self.start(Operation::Erase(page_number))
+    }
+}
+
+impl<F: Flash> flash::Client<F> for Ftl<'_, F> {
+    fn read_complete(&self, buffer: &'static mut F::Page, error: flash::Error) {
+        self.buffer.replace(buffer);
+        if error != flash::Error::CommandComplete {
+            return self.fail();
+        }
+        match self.state.get() {
+            State::Mount(page) => self.mount_read(page),
+            State::Read => self.client_read(),
+            State::MoveRead { from, to } => self.move_read(from, to),
+            _ => {}
+        }
+    }
+
+    fn write_complete(&self, buffer: &'static mut F::Page, error: flash::Error) {
+        self.buffer.replace(buffer);
+        if error != flash::Error::CommandComplete {
+            return self.fail();
+        }
+        if let State::Write {
+            target,
+            logical,
+            moving,
+        } = self.state.get()
+        {
+            self.written(target, logical, moving);
+        }
+    }
+
+    fn erase_complete(&self, error: flash::Error) {
+        if error != flash::Error::CommandComplete {
+            return self.fail();
+        }
+        if let State::Erase {
+            target,
+            logical,
+            moving,
+        } = self.state.get()
+        {
+            self.erased(target, logical, moving);
+        }
+    }
+}
+
+impl<F: Flash> DynamicDeferredCallClient for Ftl<'_, F> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.state.get() == State::Deferred {
+            self.finish(flash::Error::CommandComplete);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::nonvolatile_to_pages::NonvolatileToPages;
+    use crate::test_util::{self, Medium, Page, SimFlash, WriteMode, PAGE_SIZE};
+    use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+
+    const TEST_PAGES: usize = 8;
+    const TEST_SPARE_PAGES: usize = 2;
+    const LOGICAL_PAGE_SIZE: usize = PAGE_SIZE - HEADER_SIZE;
+
+    struct TestClient {
+        page: TakeCell<'static, FtlPage<Page>>,
+        result: Cell<Option<flash::Error>>,
+    }
+
+    impl flash::Client<Ftl<'static, SimFlash>> for TestClient {
+        fn read_complete(&self, page: &'static mut FtlPage<Page>, error: flash::Error) {
+            self.page.replace(page);
+            self.result.set(Some(error));
+        }
+
+        fn write_complete(&self, page: &'static mut FtlPage<Page>, error: flash::Error) {
+            self.page.replace(page);
+            self.result.set(Some(error));
+        }
+
+        fn erase_complete(&self, error: flash::Error) {
+            self.result.set(Some(error));
+        }
+    }
+
+    struct Harness {
+        flash: &'static SimFlash,
+        ftl: &'static Ftl<'static, SimFlash>,
+        client: &'static TestClient,
+        handle: DeferredCallHandle,
+    }
+
+    impl Harness {
+        fn new(medium: &'static Medium) -> Harness {
+            let flash = SimFlash::new(medium, WriteMode::ClearBits);
+            let deferred_caller = test_util::deferred_caller(1);
+            let ftl = test_util::leak(Ftl::new(
+                flash,
+                0,
+                TEST_PAGES,
+                TEST_SPARE_PAGES,
+                test_util::leak(Page::default()),
+                deferred_caller,
+            ));
+            let client: &'static TestClient = test_util::leak(TestClient {
+                page: TakeCell::new(test_util::leak(FtlPage::default())),
+                result: Cell::new(None),
+            });
+            flash.set_client(ftl);
+            ftl.client.set(client);
+            let handle = deferred_caller.register(ftl).unwrap();
+            ftl.initialize_callback_handle(handle);
+            Harness {
+                flash: flash,
+                ftl: ftl,
+                client: client,
+                handle: handle,
+            }
+        }
+
+        fn run(&self) -> Option<flash::Error> {
+            loop {
+                while self.flash.step() {}
+                if self.ftl.state.get() != State::Deferred {
+                    break;
+                }
+                self.ftl.call(self.handle);
+            }
+            self.client.result.take()
+        }
+
+        fn write(&self, page_number: usize, fill: u8) -> Result<Option<flash::Error>, ReturnCode> {
+            let page = self.client.page.take().unwrap();
+            for byte in page.as_mut().iter_mut() {
+                *byte = fill;
+            }
+            if let Err((result, page)) = self.ftl.write_page(page_number, page) {
+                self.client.page.replace(page);
+                return Err(result);
+            }
+            Ok(self.run())
+        }
+
+        fn read(&self, page_number: usize) -> Result<u8, ReturnCode> {
+            let page = self.client.page.take().unwrap();
+            if let Err((result, page)) = self.ftl.read_page(page_number, page) {
+                self.client.page.replace(page);
+                return Err(result);
+            }
+            assert_eq!(self.run(), Some(flash::Error::CommandComplete));
+            self.client.page.map_or(Err(ReturnCode::FAIL), |page| {
+                let page = page.as_mut();
+                assert_eq!(page.len(), LOGICAL_PAGE_SIZE);
+                assert!(page.iter().all(|byte| *byte == page[0]));
+                Ok(page[0])
+            })
+        }
+    }
+
+    const DONE: Result<Option<flash::Error>, ReturnCode> = Ok(Some(flash::Error::CommandComplete));
+
+    #[test]
+    fn pages_survive_remount() {
+        let medium = Medium::new(TEST_PAGES);
+        let ftl = Harness::new(medium);
+        assert_eq!(ftl.ftl.logical_pages(), TEST_PAGES - TEST_SPARE_PAGES);
+        assert_eq!(ftl.read(0), Ok(0xff));
+        for page in 0..6 {
+            assert_eq!(ftl.write(page, page as u8), DONE);
+        }
+        assert_eq!(ftl.write(2, 0x22), DONE);
+        assert_eq!(ftl.write(6, 0x66), Err(ReturnCode::EINVAL));
+        assert_eq!(ftl.ftl.erase_page(3), ReturnCode::SUCCESS);
+        assert_eq!(ftl.run(), Some(flash::Error::CommandComplete));
+
+        let ftl = Harness::new(medium);
+        assert_eq!(ftl.read(0), Ok(0));
+        assert_eq!(ftl.read(1), Ok(1));
+        assert_eq!(ftl.read(2), Ok(0x22));
+        assert_eq!(ftl.read(3), Ok(0xff));
+        assert_eq!(ftl.read(5), Ok(5));
+        assert_eq!(ftl.write(5, 0x55), DONE);
+        assert_eq!(ftl.read(5), Ok(0x55));
+    }
+
+    #[test]
+    fn power_loss() {
+        let medium = Medium::new(TEST_PAGES);
+        let ftl = Harness::new(medium);
+        assert_eq!(ftl.write(0, 0x11), DONE);
+        ftl.flash.writes_left.set(Some(0));
+        assert_eq!(ftl.write(0, 0x22), Ok(None));
+
+        // The torn copy is ignored, and its page is reused
+        let ftl = Harness::new(medium);
+        assert_eq!(ftl.read(0), Ok(0x11));
+        for fill in 0x30..0x40 {
+            assert_eq!(ftl.write(0, fill), DONE);
+        }
+        let ftl = Harness::new(medium);
+        assert_eq!(ftl.read(0), Ok(0x3f));
+    }
+
+    #[test]
+    fn wear_leveling() {
+        let medium = Medium::new(TEST_PAGES);
+        let ftl = Harness::new(medium);
+        // Pages 0 to 4 never change, page 5 is written over and over
+        for page in 0..5 {
+            assert_eq!(ftl.write(page, page as u8), DONE);
+        }
+        let writes = 1000;
+        for i in 0..writes {
+            assert_eq!(ftl.write(5, i as u8), DONE);
+        }
+
+        let erases = medium.erases.borrow().clone();
+        let total: u32 = erases.iter().sum();
+        let most = *erases.iter().max().unwrap();
+        let least = *erases.iter().min().unwrap();
+        // Moving cold data costs a few extra erases...
+        assert!(total < writes * 11 / 10);
+        // ...and spreads the wear over all pages, where writing in place would
+        // have erased one page a thousand times
+        assert!(most - least <= WEAR_THRESHOLD + 1);
+        assert!(most <= total / TEST_PAGES as u32 + WEAR_THRESHOLD);
+
+        // The cold data survives being moved, also after a remount
+        let ftl = Harness::new(medium);
+        for page in 0..5 {
+            assert_eq!(ftl.read(page), Ok(page as u8));
+        }
+        assert_eq!(ftl.read(5), Ok((writes - 1) as u8));
+    }
+
+    struct NvClient {
+        buffer: TakeCell<'static, [u8]>,
+        length: Cell<Option<usize>>,
+    }
+
+    impl NonvolatileStorageClient<'static> for NvClient {
+        fn read_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.length.set(Some(length));
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+            self.buffer.replace(buffer);
+            self.length.set(Some(length));
+        }
+    }
+
+    #[test]
+    fn nonvolatile_storage() {
+        let medium = Medium::new(TEST_PAGES);
+        let ftl = Harness::new(medium);
+        let nv = test_util::leak(NonvolatileToPages::new(
+            ftl.ftl,
+            test_util::leak(FtlPage::default()),
+        ));
+        let client: &'static NvClient = test_util::leak(NvClient {
+            buffer: TakeCell::new(test_util::leak([0u8; 600])),
+            length: Cell::new(None),
+        });
+        ftl.ftl.client.set(nv);
+        nv.set_client(client);
+
+        // A write spanning three logical pages, not aligned to any
+        let buffer = client.buffer.take().unwrap();
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert_eq!(nv.write(buffer, 100, 600), ReturnCode::SUCCESS);
+        ftl.run();
+        assert_eq!(client.length.take(), Some(600));
+
+        let buffer = client.buffer.take().unwrap();
+        for byte in buffer.iter_mut() {
+            *byte = 0;
+        }
+        assert_eq!(nv.read(buffer, 0, 600), ReturnCode::SUCCESS);
+        ftl.run();
+        assert_eq!(client.length.take(), Some(600));
+        client.buffer.map(|buffer| {
+            assert!(buffer[..100].iter().all(|byte| *byte == 0xff));
+            for i in 100..600 {
+                assert_eq!(buffer[i], (i - 100) as u8);
+            }
+        });
+    }
+}
@@ -0,0 +1,109 @@
+//! HKDF key derivation with HMAC-SHA256 (RFC 5869).
+//!
+//! This is a synchronous software implementation on top of
+//! `sha::HmacSha256State`, used by the key store to derive keys without
+//! handing any key material to a process.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let mut key = [0; 16];
+//! capsules::hkdf::derive(salt, input_key, b"storage", &mut key);
+//! ```
+
+use crate::sha::{HmacSha256State, SHA256_DIGEST_LEN};
+use kernel::ReturnCode;
+
+/// The longest output `expand` can produce.
+pub const HKDF_MAX_OUTPUT_LEN: usize = 255 * SHA256_DIGEST_LEN;
+
+/// HKDF-Extract: condenses the input key material `ikm` into a
+/// pseudorandom key. An empty `salt` stands for a block of zeros.
+pub fn extract(salt: &[u8], ikm: &[u8]) -> [u8; SHA256_DIGEST_LEN] {
+    let mut prk = [0; SHA256_DIGEST_LEN];
+    let mut hmac = HmacSha256State::with_key(salt);
+    hmac.update(ikm);
+    hmac.finish(&mut prk);
+    prk
+}
+
+/// HKDF-Expand: fills `okm` with key material derived from the
+/// pseudorandom key `prk` and the context `info`.
+///
+/// Returns ESIZE if `okm` is longer than `HKDF_MAX_OUTPUT_LEN`.
+pub fn expand(prk: &[u8; SHA256_DIGEST_LEN], info: &[u8], okm: &mut [u8]) -> ReturnCode {
+    if okm.len() > HKDF_MAX_OUTPUT_LEN {
+        return ReturnCode::ESIZE;
+    }
+    let mut previous = [0; SHA256_DIGEST_LEN];
+    for (i, chunk) in okm.chunks_mut(SHA256_DIGEST_LEN).enumerate() {
+        let mut hmac = HmacSha256State::new(prk);
+        if i > 0 {
+            hmac.update(&previous);
+        }
+        hmac.update(info);
+        hmac.update(&[i as u8 + 1]);
+        hmac.finish(&mut previous);
+        chunk.copy_from_slice(&previous[..chunk.len()]);
+    }
+    ReturnCode::SUCCESS
+}
+
+/// Extract followed by expand.
+pub fn derive(salt: &[u8], ikm: &[u8], info: &[u8], okm: &mut [u8]) -> ReturnCode {
+    let mut prk = extract(salt, ikm);
+    let res = expand(&prk, info, okm);
+    prk.iter_mut().for_each(|b| *b = 0);
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const IKM: [u8; 22] = [0x0b; 22];
+
+    // RFC 5869, test case 1
+    #[test]
+    fn rfc5869_basic() {
+        let salt = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+        ];
+        let info = [0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9];
+        let prk = extract(&salt, &IKM);
+        assert_eq!(
+            prk,
+            [
+                0x07, 0x77, 0x09, 0x36, 0x2c, 0x2e, 0x32, 0xdf, 0x0d, 0xdc, 0x3f, 0x0d, 0xc4, 0x7b,
+                0xba, 0x63, 0x90, 0xb6, 0xc7, 0x3b, 0xb5, 0x0f, 0x9c, 0x31, 0x22, 0xec, 0x84, 0x4a,
+                0xd7, 0xc2, 0xb3, 0xe5,
+            ]
+        );
+        let mut okm = [0; 42];
+        assert_eq!(expand(&prk, &info, &mut okm), ReturnCode::SUCCESS);
+        assert_eq!(
+            okm[..],
+            [
+                0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36,
+                0x2f, 0x2a, 0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c, 0x5d, 0xb0, 0x2d, 0x56,
+                0xec, 0xc4, 0xc5, 0xbf, 0x34, 0x00, 0x72, 0x08, 0xd5, 0xb8, 0x87, 0x18, 0x58, 0x65,
+            ][..]
+        );
+    }
+
+    // RFC 5869, test case 3: no salt and no info
+    #[test]
+    fn rfc5869_empty_salt_and_info() {
+        let mut okm = [0; 42];
+        assert_eq!(derive(&[], &IKM, &[], &mut okm), ReturnCode::SUCCESS);
+        assert_eq!(
+            okm[..],
+            [
+                0x8d, 0xa4, 0xe7, 0x75, 0xa5, 0x63, 0xc1, 0x8f, 0x71, 0x5f, 0x80, 0x2a, 0x06, 0x3c,
+                0x5a, 0x31, 0xb8, 0xa1, 0x1f, 0x5c, 0x5e, 0xe1, 0x87, 0x9e, 0xc3, 0x45, 0x4e, 0x5f,
+                0x3c, 0x73, 0x8d, 0x2d, 0x9d, 0x20, 0x13, 0x95, 0xfa, 0xa4, 0xb6, 0x1a, 0x96, 0xc8,
+            ][..]
+        );
+    }
+}
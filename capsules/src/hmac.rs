@@ -22,6 +22,9 @@
 //! );
 //! digest::Digest::set_client(virtual_hmac_user, hmac);
 //! ```
+//!
+//! With `hmac.set_key_store(key_store)`, processes can use one of their keys
+//! in a `key_store::KeyStore` by its handle instead of giving the key.
 
 use crate::driver;
 /// Syscall driver number.
@@ -34,10 +37,12 @@ use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::common::leasable_buffer::LeasableBuffer;
 use kernel::hil::digest;
 use kernel::hil::digest::DigestType;
+use kernel::hil::key_store::{KeyHandle, KeyStore};
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 
 pub struct HmacDriver<'a, H: digest::Digest<'a, T>, T: 'static + DigestType> {
     hmac: &'a H,
+    key_store: OptionalCell<&'a dyn KeyStore>,
 
     active: Cell<bool>,
 
@@ -62,6 +67,7 @@ where
     ) -> HmacDriver<'a, H, T> {
         HmacDriver {
             hmac: hmac,
+            key_store: OptionalCell::empty(),
             active: Cell::new(false),
             apps: grant,
             appid: OptionalCell::empty(),
@@ -72,17 +78,35 @@ where
         }
     }
 
+    /// Lets processes use their keys in `key_store` by handle.
+    pub fn set_key_store(&self, key_store: &'a dyn KeyStore) {
+        self.key_store.set(key_store);
+    }
+
     fn run(&self) -> ReturnCode {
         self.appid.map_or(ReturnCode::ERESERVE, move |appid| {
             self.apps
                 .enter(*appid, |app, _| {
-                    match app.key.as_ref() {
-                        Some(k) => {
+                    match (app.key_handle, app.key.as_ref()) {
+                        (Some(handle), _) => {
+                            // Shorter keys are padded with zeros, which gives
+                            // the same MAC
+                            let mut key = [0; 32];
+                            let res = self.key_store.map_or(Err(ReturnCode::ENOSUPPORT), |ks| {
+                                ks.read_key(handle, Some(*appid), &mut key)
+                            });
+                            let res = res.and_then(|_| self.hmac.set_mode_hmacsha256(&key));
+                            key.iter_mut().for_each(|b| *b = 0);
+                            if let Err(e) = res {
+                                return e;
+                            }
+                        }
+                        (None, Some(k)) => {
                             self.hmac
                                 .set_mode_hmacsha256(k.as_ref().try_into().unwrap())
                                 .unwrap();
                         }
-                        None => {
+                        (None, None) => {
                             return ReturnCode::ERESERVE;
                         }
                     };
@@ -287,6 +311,7 @@ impl<'a, H: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> digest::C
 /// ### `allow_num`
 ///
 /// - `0`: Allow a buffer for storing the key.
+///        This stops the use of a key handle.
 ///        The kernel will read from this when running
 ///        This should not be changed after running `run` until the HMAC
 ///        has completed
@@ -312,6 +337,7 @@ impl<'a, H: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> Driver
                 .apps
                 .enter(appid, |app, _| {
                     app.key = slice;
+                    app.key_handle = None;
                     ReturnCode::SUCCESS
                 })
                 .unwrap_or(ReturnCode::FAIL),
@@ -386,6 +412,8 @@ impl<'a, H: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> Driver
     ///
     /// - `0`: set_algorithm
     /// - `1`: run
+    /// - `2`: use the key with handle `data1` in the key store instead of
+    ///        the key buffer
     fn command(&self, command_num: usize, data1: usize, _data2: usize, appid: AppId) -> ReturnCode {
         let match_or_empty_or_nonexistant = self.appid.map_or(true, |owning_app| {
             // We have recorded that an app has ownership of the HMAC.
@@ -456,6 +484,19 @@ impl<'a, H: digest::Digest<'a, T> + digest::HMACSha256, T: DigestType> Driver
                 }
             }
 
+            // use key handle
+            2 => {
+                if self.key_store.is_none() {
+                    return ReturnCode::ENOSUPPORT;
+                }
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.key_handle = Some(KeyHandle::new(data1));
+                        ReturnCode::SUCCESS
+                    })
+                    .unwrap_or_else(|err| err.into())
+            }
+
             // default
             _ => ReturnCode::ENOSUPPORT,
         }
@@ -466,6 +507,7 @@ pub struct App {
     callback: OptionalCell<Callback>,
     pending_run_app: Option<AppId>,
     key: Option<AppSlice<Shared, u8>>,
+    key_handle: Option<KeyHandle>,
     data: Option<AppSlice<Shared, u8>>,
     dest: Option<AppSlice<Shared, u8>>,
 }
@@ -476,6 +518,7 @@ impl Default for App {
             callback: OptionalCell::empty(),
             pending_run_app: None,
             key: None,
+            key_handle: None,
             data: None,
             dest: None,
         }
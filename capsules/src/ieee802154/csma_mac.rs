@@ -0,0 +1,1267 @@
+//! Radio-agnostic IEEE 802.15.4 MAC layer with unslotted CSMA-CA,
+//! acknowledgements and channel scanning.
+//!
+//! `AwakeMac` passes frames straight to the radio, so whether a frame is sent
+//! after a clear channel assessment, acknowledged or retransmitted depends on
+//! the radio driver: the RF233 does all of this in hardware, while the nRF52
+//! radio only backs off on a busy channel. `CsmaMac` implements these parts of
+//! the 802.15.4 MAC (IEEE 802.15.4-2015, section 6.2) on top of any
+//! `kernel::hil::radio::Radio`, so that the radios behave the same:
+//!
+//!   * Unslotted CSMA-CA: each transmission is delayed by a random number of
+//!     backoff periods. The radio HIL has no clear channel assessment, so a
+//!     busy radio or a transmission the radio rejects or aborts because of a
+//!     busy channel counts as a busy channel, increasing the backoff exponent.
+//!     After `macMaxCSMABackoffs` busy channels the transmission fails with
+//!     `ReturnCode::EBUSY`.
+//!   * Acknowledgements: unicast frames with the ACK request bit set are
+//!     retransmitted up to `macMaxFrameRetries` times if no matching ACK is
+//!     received, after which the transmission completes with
+//!     `ReturnCode::ENOACK`. ACKs reported by the radio itself are also
+//!     accepted. ACK frames are not passed to the receive client.
+//!   * ACK generation: received data and command frames addressed to this node
+//!     that request an ACK are acknowledged in software, with the frame pending
+//!     bit set by `set_frame_pending`. Radios that acknowledge frames in
+//!     hardware (such as the RF233) should disable this with
+//!     `set_software_ack(false)`.
+//!   * Passive and active scans (`scan`), which report received beacons to a
+//!     `ScanClient`, and optional responses to beacon requests when acting as
+//!     a coordinator (`set_coordinator`).
+//!
+//! Received frames that are not addressed to this node (by short or long
+//! address, or to the broadcast address) and PAN are dropped.
+//!
+//! A software ACK is only sent after the radio's own processing of the
+//! transmission, so it can arrive later than `macAckWaitDuration`. The ACK
+//! timeout is therefore longer than the standard's by default and can be
+//! changed with `set_ack_wait`.
+//!
+//! Usage
+//! -----
+//! This capsule implements the `capsules::ieee802154::mac::Mac` interface and
+//! can replace `AwakeMac` as the backend of a
+//! `capsules::ieee802154::device::MacDevice`. Unlike `AwakeMac`, it needs an
+//! alarm and the configuration callbacks of the radio. Boards can use
+//! `components::ieee802154::Ieee802154CsmaComponent`, which sets it up as
+//! follows for `imix`:
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! use capsules::ieee802154::csma_mac::CsmaMac;
+//! use capsules::ieee802154::mac::Mac;
+//! type CsmaMacDevice = CsmaMac<'static, RF233Device, VirtualMuxAlarm<'static, Ast>>;
+//!
+//! // CsmaMac needs one buffer for the frames it generates itself (ACKs,
+//! // beacons and beacon requests).
+//! static mut MAC_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+//! // ...
+//! let mac = static_init!(CsmaMacDevice, CsmaMac::new(rf233, mac_alarm));
+//! mac_alarm.set_client(mac);
+//!
+//! rf233.set_transmit_client(mac);
+//! rf233.set_receive_client(mac, &mut RF233_RX_BUF);
+//! rf233.set_config_client(mac);
+//!
+//! mac.initialize(&mut MAC_BUF);
+//! // The RF233 acknowledges frames in hardware.
+//! mac.set_software_ack(false);
+//!
+//! let mac_device = static_init!(
+//!     capsules::ieee802154::framer::Framer<'static, CsmaMacDevice>,
+//!     capsules::ieee802154::framer::Framer::new(mac));
+//! mac.set_transmit_client(mac_device);
+//! mac.set_receive_client(mac_device);
+//! mac.set_config_client(mac_device);
+//! ```
+
+use crate::ieee802154::mac::Mac;
+use crate::net::ieee802154::{FrameType, FrameVersion, Header, MacAddress, PanID};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+
+// aUnitBackoffPeriod: 20 symbols of 16 us
+const UNIT_BACKOFF_US: u32 = 320;
+// aBaseSuperframeDuration: 960 symbols of 16 us
+const BASE_SUPERFRAME_US: u32 = 15_360;
+// macAckWaitDuration is 54 symbols (864 us); the default leaves time for a
+// software ACK to be generated and for the radio to deliver it.
+const DEFAULT_ACK_WAIT_US: u32 = 2_000;
+
+// Default MAC PIB attributes
+const DEFAULT_MIN_BE: u8 = 3;
+const DEFAULT_MAX_BE: u8 = 5;
+const DEFAULT_MAX_CSMA_BACKOFFS: u8 = 4;
+const DEFAULT_MAX_FRAME_RETRIES: u8 = 3;
+
+const BROADCAST_ADDRESS: u16 = 0xffff;
+const BROADCAST_PAN: PanID = 0xffff;
+// Short addresses that indicate the node only uses its long address
+const NO_SHORT_ADDRESS: u16 = 0xfffe;
+
+// Channels of the 2.4 GHz O-QPSK PHY
+const FIRST_CHANNEL: u8 = 11;
+const LAST_CHANNEL: u8 = 26;
+/// Channel mask for `scan` that includes all channels.
+pub const ALL_CHANNELS: u32 = ((1 << (LAST_CHANNEL + 1)) - 1) & !((1 << FIRST_CHANNEL) - 1);
+/// Largest scan duration exponent accepted by `scan`.
+pub const MAX_SCAN_DURATION: u8 = 14;
+
+// MAC command frame identifiers
+const CMD_BEACON_REQUEST: u8 = 0x07;
+
+// Superframe specification fields
+const SUPERFRAME_BEACON_ORDER_NONE: u16 = 0x000f;
+const SUPERFRAME_ORDER_NONE: u16 = 0x00f0;
+const SUPERFRAME_FINAL_CAP_SLOT: u16 = 0x0f00;
+const SUPERFRAME_PAN_COORDINATOR: u16 = 1 << 14;
+const SUPERFRAME_ASSOCIATION_PERMIT: u16 = 1 << 15;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ScanType {
+    /// Only listens for beacons on each channel.
+    Passive,
+    /// Sends a beacon request on each channel before listening.
+    Active,
+}
+
+/// A coordinator found by a scan.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PanDescriptor {
+    pub channel: u8,
+    pub coord_pan: PanID,
+    pub coord_addr: MacAddress,
+    pub superframe_spec: u16,
+}
+
+pub trait ScanClient {
+    /// Called for each beacon received during a scan, with the beacon payload.
+    fn beacon_received(&self, descriptor: &PanDescriptor, payload: &[u8]);
+    /// Called when all channels have been scanned and the original channel
+    /// is restored.
+    fn scan_done(&self, result: ReturnCode);
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum TxState {
+    Idle,
+    // Waiting for a random backoff before transmitting
+    Backoff,
+    // The frame is being transmitted by the radio
+    Transmitting,
+    // The frame was sent; waiting for an ACK
+    WaitingForAck,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ScanState {
+    Idle,
+    // Waiting for the radio to switch to the next channel
+    ChangingChannel,
+    // Listening for beacons until the timer fires
+    Listening,
+    // Waiting for the radio to return to the original channel
+    Restoring,
+}
+
+pub struct CsmaMac<'a, R: radio::Radio, A: Alarm<'a>> {
+    radio: &'a R,
+    alarm: &'a A,
+    tx_client: OptionalCell<&'static dyn radio::TxClient>,
+    rx_client: OptionalCell<&'static dyn radio::RxClient>,
+    config_client: OptionalCell<&'static dyn radio::ConfigClient>,
+    scan_client: OptionalCell<&'a dyn ScanClient>,
+
+    tx_state: Cell<TxState>,
+    tx_buf: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    // Sequence number of the frame being sent, if it must be acknowledged
+    tx_ack_seq: Cell<Option<u8>>,
+    // Number of backoffs (NB), backoff exponent (BE) and retransmissions
+    // of the current frame
+    backoffs: Cell<u8>,
+    backoff_exponent: Cell<u8>,
+    retries: Cell<u8>,
+
+    min_be: Cell<u8>,
+    max_be: Cell<u8>,
+    max_csma_backoffs: Cell<u8>,
+    max_frame_retries: Cell<u8>,
+    ack_wait_us: Cell<u32>,
+
+    software_ack: Cell<bool>,
+    frame_pending: Cell<bool>,
+    ack_frame_pending: Cell<bool>,
+    coordinator: Cell<bool>,
+
+    // Holds the frames generated by this layer. It is in use while such a
+    // frame is being transmitted.
+    mac_buf: TakeCell<'static, [u8]>,
+    mac_seq: Cell<u8>,
+
+    scan_state: Cell<ScanState>,
+    scan_type: Cell<ScanType>,
+    scan_channels: Cell<u32>,
+    scan_channel: Cell<u8>,
+    scan_duration_us: Cell<u32>,
+    scan_original_channel: Cell<u8>,
+
+    random: Cell<u32>,
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> CsmaMac<'a, R, A> {
+    pub fn new(radio: &'a R, alarm: &'a A) -> CsmaMac<'a, R, A> {
+        CsmaMac {
+            radio: radio,
+            alarm: alarm,
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            config_client: OptionalCell::empty(),
+            scan_client: OptionalCell::empty(),
+            tx_state: Cell::new(TxState::Idle),
+            tx_buf: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_ack_seq: Cell::new(None),
+            backoffs: Cell::new(0),
+            backoff_exponent: Cell::new(DEFAULT_MIN_BE),
+            retries: Cell::new(0),
+            min_be: Cell::new(DEFAULT_MIN_BE),
+            max_be: Cell::new(DEFAULT_MAX_BE),
+            max_csma_backoffs: Cell::new(DEFAULT_MAX_CSMA_BACKOFFS),
+            max_frame_retries: Cell::new(DEFAULT_MAX_FRAME_RETRIES),
+            ack_wait_us: Cell::new(DEFAULT_ACK_WAIT_US),
+            software_ack: Cell::new(true),
+            frame_pending: Cell::new(false),
+            ack_frame_pending: Cell::new(false),
+            coordinator: Cell::new(false),
+            mac_buf: TakeCell::empty(),
+            mac_seq: Cell::new(0),
+            scan_state: Cell::new(ScanState::Idle),
+            scan_type: Cell::new(ScanType::Passive),
+            scan_channels: Cell::new(0),
+            scan_channel: Cell::new(0),
+            scan_duration_us: Cell::new(0),
+            scan_original_channel: Cell::new(0),
+            random: Cell::new(0),
+        }
+    }
+
+    pub fn set_scan_client(&self, client: &'a dyn ScanClient) {
+        self.scan_client.set(client);
+    }
+
+    /// Sets the CSMA-CA parameters macMinBE, macMaxBE and
+    /// macMaxCSMABackoffs.
+    pub fn set_csma_parameters(&self, min_be: u8, max_be: u8, max_backoffs: u8) -> ReturnCode {
+        if min_be > max_be || max_be < 3 || max_be > 8 || max_backoffs > 5 {
+            return ReturnCode::EINVAL;
+        }
+        self.min_be.set(min_be);
+        self.max_be.set(max_be);
+        self.max_csma_backoffs.set(max_backoffs);
+        ReturnCode::SUCCESS
+    }
+
+    /// Sets macMaxFrameRetries, the number of retransmissions of a frame
+    /// that is not acknowledged.
+    pub fn set_max_frame_retries(&self, retries: u8) -> ReturnCode {
+        if retries > 7 {
+            return ReturnCode::EINVAL;
+        }
+        self.max_frame_retries.set(retries);
+        ReturnCode::SUCCESS
+    }
+
+    /// Sets how long to wait for an ACK after a frame is sent.
+    pub fn set_ack_wait(&self, us: u32) {
+        self.ack_wait_us.set(us);
+    }
+
+    /// Enables or disables acknowledging received frames in software.
+    pub fn set_software_ack(&self, enabled: bool) {
+        self.software_ack.set(enabled);
+    }
+
+    /// Sets the frame pending bit of the ACKs sent by this layer, to indicate
+    /// that more data is waiting for the sender.
+    pub fn set_frame_pending(&self, pending: bool) {
+        self.frame_pending.set(pending);
+    }
+
+    /// Returns the frame pending bit of the last ACK received for a
+    /// transmitted frame.
+    pub fn ack_frame_pending(&self) -> bool {
+        self.ack_frame_pending.get()
+    }
+
+    /// Enables or disables answering beacon requests with a beacon, as the
+    /// coordinator of the PAN. This also accepts frames without a
+    /// destination address, which are sent to the coordinator.
+    pub fn set_coordinator(&self, coordinator: bool) {
+        self.coordinator.set(coordinator);
+    }
+
+    /// Scans the channels set in `channels` (bit `n` for channel `n`, see
+    /// `ALL_CHANNELS`) for beacons, listening for
+    /// `aBaseSuperframeDuration * (2^duration + 1)` on each channel.
+    /// Beacons are reported to the scan client. Transmissions are rejected
+    /// with `ReturnCode::EBUSY` until the scan is done.
+    pub fn scan(&self, scan_type: ScanType, channels: u32, duration: u8) -> ReturnCode {
+        if self.scan_state.get() != ScanState::Idle || self.tx_state.get() != TxState::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let channels = channels & ALL_CHANNELS;
+        if channels == 0 || duration > MAX_SCAN_DURATION {
+            return ReturnCode::EINVAL;
+        }
+        self.scan_type.set(scan_type);
+        self.scan_channels.set(channels);
+        self.scan_duration_us
+            .set(BASE_SUPERFRAME_US * ((1 << duration) + 1));
+        self.scan_original_channel.set(self.radio.get_channel());
+        self.scan_next_channel();
+        ReturnCode::SUCCESS
+    }
+
+    fn scan_next_channel(&self) {
+        let mut channels = self.scan_channels.get();
+        while channels != 0 {
+            let channel = channels.trailing_zeros() as u8;
+            channels &= !(1 << channel);
+            self.scan_channels.set(channels);
+            if self.radio.set_channel(channel) == ReturnCode::SUCCESS {
+                self.scan_channel.set(channel);
+                self.scan_state.set(ScanState::ChangingChannel);
+                self.radio.config_commit();
+                return;
+            }
+        }
+        self.scan_state.set(ScanState::Restoring);
+        self.radio.set_channel(self.scan_original_channel.get());
+        self.radio.config_commit();
+    }
+
+    fn scan_channel_ready(&self) {
+        self.scan_state.set(ScanState::Listening);
+        if self.scan_type.get() == ScanType::Active {
+            let header = Header {
+                frame_type: FrameType::MACCommand,
+                frame_pending: false,
+                ack_requested: false,
+                version: FrameVersion::V2003,
+                seq: Some(self.next_mac_seq()),
+                dst_pan: Some(BROADCAST_PAN),
+                dst_addr: Some(MacAddress::Short(BROADCAST_ADDRESS)),
+                src_pan: None,
+                src_addr: None,
+                security: None,
+                header_ies: Default::default(),
+                header_ies_len: 0,
+                payload_ies: Default::default(),
+                payload_ies_len: 0,
+            };
+            // If the request cannot be sent, the channel is still scanned
+            // passively.
+            self.transmit_mac_frame(&header, &[CMD_BEACON_REQUEST]);
+        }
+        self.set_timer_us(self.scan_duration_us.get());
+    }
+
+    fn beacon_received(&self, header: &Header, payload: &[u8]) {
+        let (coord_pan, coord_addr) = match (header.src_pan, header.src_addr) {
+            (Some(pan), Some(addr)) => (pan, addr),
+            _ => return,
+        };
+        if let Some((superframe_spec, beacon_payload)) = decode_beacon(payload) {
+            let descriptor = PanDescriptor {
+                channel: self.scan_channel.get(),
+                coord_pan: coord_pan,
+                coord_addr: coord_addr,
+                superframe_spec: superframe_spec,
+            };
+            self.scan_client
+                .map(|client| client.beacon_received(&descriptor, beacon_payload));
+        }
+    }
+
+    fn send_beacon(&self) {
+        let address = self.radio.get_address();
+        let src_addr = if address == BROADCAST_ADDRESS || address == NO_SHORT_ADDRESS {
+            MacAddress::Long(self.radio.get_address_long())
+        } else {
+            MacAddress::Short(address)
+        };
+        let header = Header {
+            frame_type: FrameType::Beacon,
+            frame_pending: false,
+            ack_requested: false,
+            version: FrameVersion::V2003,
+            seq: Some(self.next_mac_seq()),
+            dst_pan: None,
+            dst_addr: None,
+            src_pan: Some(self.radio.get_pan()),
+            src_addr: Some(src_addr),
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        // Non-beacon-enabled PAN, no GTS and no pending addresses
+        let superframe_spec = SUPERFRAME_BEACON_ORDER_NONE
+            | SUPERFRAME_ORDER_NONE
+            | SUPERFRAME_FINAL_CAP_SLOT
+            | SUPERFRAME_PAN_COORDINATOR
+            | SUPERFRAME_ASSOCIATION_PERMIT;
+        let spec = superframe_spec.to_le_bytes();
+        self.transmit_mac_frame(&header, &[spec[0], spec[1], 0, 0]);
+    }
+
+    fn send_ack(&self, seq: u8) {
+        let header = Header {
+            frame_type: FrameType::Acknowledgement,
+            frame_pending: self.frame_pending.get(),
+            ack_requested: false,
+            version: FrameVersion::V2006,
+            seq: Some(seq),
+            dst_pan: None,
+            dst_addr: None,
+            src_pan: None,
+            src_addr: None,
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        self.transmit_mac_frame(&header, &[]);
+    }
+
+    // Sends a frame generated by this layer in `mac_buf`, without CSMA-CA.
+    // Fails if the buffer is in use or the radio cannot send the frame now.
+    fn transmit_mac_frame(&self, header: &Header, payload: &[u8]) -> ReturnCode {
+        let buf = match self.mac_buf.take() {
+            Some(buf) => buf,
+            None => return ReturnCode::EBUSY,
+        };
+        let frame_len = match header
+            .encode(&mut buf[radio::PSDU_OFFSET..], !payload.is_empty())
+            .done()
+        {
+            Some((header_len, _)) => header_len + payload.len(),
+            None => 0,
+        };
+        if frame_len == 0 || radio::PSDU_OFFSET + frame_len + radio::MFR_SIZE > buf.len() {
+            self.mac_buf.replace(buf);
+            return ReturnCode::ESIZE;
+        }
+        buf[radio::PSDU_OFFSET + frame_len - payload.len()..radio::PSDU_OFFSET + frame_len]
+            .copy_from_slice(payload);
+        let (result, buf) = self.radio.transmit(buf, frame_len);
+        buf.map(|buf| self.mac_buf.replace(buf));
+        result
+    }
+
+    fn next_mac_seq(&self) -> u8 {
+        let seq = self.mac_seq.get();
+        self.mac_seq.set(seq.wrapping_add(1));
+        seq
+    }
+
+    // The backoffs must be drawn synchronously, so a small xorshift generator
+    // seeded from the node address and the time of the first transmission is
+    // used instead of an asynchronous `hil::rng::Rng`.
+    fn next_random(&self) -> u32 {
+        let mut x = self.random.get();
+        if x == 0 {
+            x = self
+                .radio
+                .get_address_long()
+                .iter()
+                .fold(self.alarm.now(), |x, &b| x.rotate_left(5) ^ b as u32)
+                | 1;
+        }
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random.set(x);
+        x
+    }
+
+    fn set_timer_us(&self, us: u32) {
+        let tics = (us as u64 * <A::Frequency>::frequency() as u64 / 1_000_000) as u32;
+        self.alarm
+            .set_alarm(self.alarm.now().wrapping_add(cmp::max(tics, 1)));
+    }
+
+    // Starts CSMA-CA for the current frame.
+    fn start_csma(&self) {
+        self.backoffs.set(0);
+        self.backoff_exponent.set(self.min_be.get());
+        self.backoff();
+    }
+
+    fn backoff(&self) {
+        let periods = self.next_random() & ((1 << self.backoff_exponent.get()) - 1);
+        self.tx_state.set(TxState::Backoff);
+        self.set_timer_us(periods * UNIT_BACKOFF_US);
+    }
+
+    fn channel_busy(&self) {
+        self.backoffs.set(self.backoffs.get() + 1);
+        self.backoff_exponent
+            .set(cmp::min(self.backoff_exponent.get() + 1, self.max_be.get()));
+        if self.backoffs.get() > self.max_csma_backoffs.get() {
+            // Channel access failure
+            self.complete_transmit(false, ReturnCode::EBUSY);
+        } else {
+            self.backoff();
+        }
+    }
+
+    fn attempt_transmit(&self) {
+        if self.radio.busy() {
+            self.channel_busy();
+            return;
+        }
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return,
+        };
+        self.tx_state.set(TxState::Transmitting);
+        let (result, buf) = self.radio.transmit(buf, self.tx_len.get());
+        buf.map(|buf| self.tx_buf.replace(buf));
+        match result {
+            ReturnCode::SUCCESS => {}
+            ReturnCode::EBUSY => self.channel_busy(),
+            _ => self.complete_transmit(false, result),
+        }
+    }
+
+    fn ack_timeout(&self) {
+        self.retries.set(self.retries.get() + 1);
+        if self.retries.get() > self.max_frame_retries.get() {
+            self.complete_transmit(false, ReturnCode::ENOACK);
+        } else {
+            self.start_csma();
+        }
+    }
+
+    fn ack_received(&self, seq: Option<u8>, frame_pending: bool) {
+        if self.tx_state.get() == TxState::WaitingForAck && seq == self.tx_ack_seq.get() {
+            self.alarm.disable();
+            self.ack_frame_pending.set(frame_pending);
+            self.complete_transmit(true, ReturnCode::SUCCESS);
+        }
+    }
+
+    fn complete_transmit(&self, acked: bool, result: ReturnCode) {
+        self.tx_state.set(TxState::Idle);
+        self.tx_buf.take().map(|buf| {
+            self.tx_client
+                .map(move |client| client.send_done(buf, acked, result));
+        });
+    }
+
+    fn is_addressed_to_us(&self, header: &Header) -> bool {
+        let pan_match = match header.dst_pan {
+            Some(pan) => pan == BROADCAST_PAN || pan == self.radio.get_pan(),
+            None => true,
+        };
+        let addr_match = match header.dst_addr {
+            Some(MacAddress::Short(addr)) => {
+                addr == BROADCAST_ADDRESS || addr == self.radio.get_address()
+            }
+            Some(MacAddress::Long(addr)) => addr == self.radio.get_address_long(),
+            None => self.coordinator.get(),
+        };
+        pan_match && addr_match
+    }
+}
+
+// Decodes the MAC payload of a beacon frame into the superframe
+// specification and the beacon payload.
+fn decode_beacon(payload: &[u8]) -> Option<(u16, &[u8])> {
+    if payload.len() < 4 {
+        return None;
+    }
+    let superframe_spec = u16::from_le_bytes([payload[0], payload[1]]);
+    let mut off = 2;
+
+    // GTS fields
+    let gts_count = (payload[off] & 0x07) as usize;
+    off += 1;
+    if gts_count > 0 {
+        // GTS directions and descriptors
+        off += 1 + gts_count * 3;
+    }
+
+    // Pending address fields
+    let pending = *payload.get(off)?;
+    off += 1;
+    let short_count = (pending & 0x07) as usize;
+    let long_count = ((pending >> 4) & 0x07) as usize;
+    off += short_count * 2 + long_count * 8;
+
+    payload
+        .get(off..)
+        .map(|beacon_payload| (superframe_spec, beacon_payload))
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> time::AlarmClient for CsmaMac<'a, R, A> {
+    fn fired(&self) {
+        if self.scan_state.get() == ScanState::Listening {
+            self.scan_next_channel();
+            return;
+        }
+        match self.tx_state.get() {
+            TxState::Backoff => self.attempt_transmit(),
+            TxState::WaitingForAck => self.ack_timeout(),
+            TxState::Idle | TxState::Transmitting => {}
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> Mac for CsmaMac<'a, R, A> {
+    fn initialize(&self, mac_buf: &'static mut [u8]) -> ReturnCode {
+        self.mac_buf.replace(mac_buf);
+        ReturnCode::SUCCESS
+    }
+
+    fn is_on(&self) -> bool {
+        self.radio.is_on()
+    }
+
+    fn set_config_client(&self, client: &'static dyn radio::ConfigClient) {
+        self.config_client.set(client);
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.radio.set_address(addr)
+    }
+
+    fn set_address_long(&self, addr: [u8; 8]) {
+        self.radio.set_address_long(addr)
+    }
+
+    fn set_pan(&self, id: u16) {
+        self.radio.set_pan(id)
+    }
+
+    fn get_address(&self) -> u16 {
+        self.radio.get_address()
+    }
+
+    fn get_address_long(&self) -> [u8; 8] {
+        self.radio.get_address_long()
+    }
+
+    fn get_pan(&self) -> u16 {
+        self.radio.get_pan()
+    }
+
+    fn config_commit(&self) {
+        self.radio.config_commit()
+    }
+
+    fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+        self.tx_client.set(client);
+    }
+
+    fn set_receive_client(&self, client: &'static dyn radio::RxClient) {
+        self.rx_client.set(client);
+    }
+
+    fn set_receive_buffer(&self, buffer: &'static mut [u8]) {
+        self.radio.set_receive_buffer(buffer);
+    }
+
+    fn transmit(
+        &self,
+        full_mac_frame: &'static mut [u8],
+        frame_len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.tx_state.get() != TxState::Idle || self.scan_state.get() != ScanState::Idle {
+            return (ReturnCode::EBUSY, Some(full_mac_frame));
+        } else if radio::PSDU_OFFSET + frame_len + radio::MFR_SIZE > full_mac_frame.len() {
+            return (ReturnCode::ESIZE, Some(full_mac_frame));
+        }
+
+        // Only unicast frames are acknowledged
+        let ack_seq = match Header::decode(&full_mac_frame[radio::PSDU_OFFSET..], false).done() {
+            Some((_, (header, _))) => match header.dst_addr {
+                Some(MacAddress::Short(BROADCAST_ADDRESS)) | None => None,
+                Some(_) if header.ack_requested => header.seq,
+                Some(_) => None,
+            },
+            None => return (ReturnCode::EINVAL, Some(full_mac_frame)),
+        };
+
+        self.tx_buf.replace(full_mac_frame);
+        self.tx_len.set(frame_len);
+        self.tx_ack_seq.set(ack_seq);
+        self.retries.set(0);
+        self.start_csma();
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::TxClient for CsmaMac<'a, R, A> {
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+        if self.tx_state.get() != TxState::Transmitting {
+            // A frame generated by this layer
+            self.mac_buf.replace(buf);
+            return;
+        }
+
+        self.tx_buf.replace(buf);
+        match result {
+            ReturnCode::SUCCESS => {
+                if self.tx_ack_seq.get().is_none() {
+                    self.complete_transmit(false, ReturnCode::SUCCESS);
+                } else if acked {
+                    // Acknowledged in hardware
+                    self.ack_frame_pending.set(false);
+                    self.complete_transmit(true, ReturnCode::SUCCESS);
+                } else {
+                    self.tx_state.set(TxState::WaitingForAck);
+                    self.set_timer_us(self.ack_wait_us.get());
+                }
+            }
+            // The radio's own channel assessment failed
+            ReturnCode::EBUSY | ReturnCode::FAIL => self.channel_busy(),
+            _ => self.complete_transmit(false, result),
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::RxClient for CsmaMac<'a, R, A> {
+    fn receive(
+        &self,
+        buf: &'static mut [u8],
+        frame_len: usize,
+        crc_valid: bool,
+        result: ReturnCode,
+    ) {
+        if !crc_valid || result != ReturnCode::SUCCESS || radio::PSDU_OFFSET + frame_len > buf.len()
+        {
+            self.radio.set_receive_buffer(buf);
+            return;
+        }
+
+        let frame = &buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len];
+        let (header, payload_offset) = match Header::decode(frame, false).done() {
+            Some((_, decoded)) => decoded,
+            None => {
+                self.radio.set_receive_buffer(buf);
+                return;
+            }
+        };
+        let payload = &frame[payload_offset..];
+
+        let pass_up = match header.frame_type {
+            FrameType::Acknowledgement => {
+                self.ack_received(header.seq, header.frame_pending);
+                false
+            }
+            FrameType::Beacon => {
+                if self.scan_state.get() == ScanState::Listening {
+                    self.beacon_received(&header, payload);
+                }
+                false
+            }
+            FrameType::MACCommand
+                if self.coordinator.get() && payload.first() == Some(&CMD_BEACON_REQUEST) =>
+            {
+                self.send_beacon();
+                false
+            }
+            _ => {
+                let for_us = self.is_addressed_to_us(&header);
+                // Broadcast frames are not acknowledged
+                let broadcast = header.dst_addr == Some(MacAddress::Short(BROADCAST_ADDRESS));
+                if for_us && !broadcast && header.ack_requested && self.software_ack.get() {
+                    header.seq.map(|seq| self.send_ack(seq));
+                }
+                for_us
+            }
+        };
+
+        if pass_up {
+            self.rx_client
+                .map(move |client| client.receive(buf, frame_len, crc_valid, result));
+        } else {
+            self.radio.set_receive_buffer(buf);
+        }
+    }
+}
+
+impl<'a, R: radio::Radio, A: Alarm<'a>> radio::ConfigClient for CsmaMac<'a, R, A> {
+    fn config_done(&self, result: ReturnCode) {
+        match self.scan_state.get() {
+            ScanState::ChangingChannel => self.scan_channel_ready(),
+            ScanState::Restoring => {
+                self.scan_state.set(ScanState::Idle);
+                self.scan_client.map(|client| client.scan_done(result));
+            }
+            ScanState::Idle | ScanState::Listening => {
+                self.config_client.map(|client| client.config_done(result));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{leak, SimAlarm};
+    use core::cell::RefCell;
+    use kernel::hil::radio::RadioData;
+    use kernel::hil::time::Time;
+    use std::vec::Vec;
+
+    const PAN: PanID = 0xabcd;
+    const ADDRESS: u16 = 0x0001;
+    const PEER: u16 = 0x0002;
+
+    /// A radio that accepts frames until the test completes them with
+    /// `finish`, and whose channel can be made busy.
+    struct SimRadio {
+        busy: Cell<bool>,
+        busy_checks: Cell<usize>,
+        // Returned by the next `transmit`, which then keeps the frame
+        reject: Cell<ReturnCode>,
+        pending: TakeCell<'static, [u8]>,
+        // The PSDU of each frame the radio accepted
+        sent: RefCell<Vec<Vec<u8>>>,
+        rx_buf: TakeCell<'static, [u8]>,
+        tx_client: OptionalCell<&'static dyn radio::TxClient>,
+        channel: Cell<u8>,
+    }
+
+    impl SimRadio {
+        fn new() -> SimRadio {
+            SimRadio {
+                busy: Cell::new(false),
+                busy_checks: Cell::new(0),
+                reject: Cell::new(ReturnCode::SUCCESS),
+                pending: TakeCell::empty(),
+                sent: RefCell::new(Vec::new()),
+                rx_buf: TakeCell::empty(),
+                tx_client: OptionalCell::empty(),
+                channel: Cell::new(FIRST_CHANNEL),
+            }
+        }
+
+        /// Completes the frame being transmitted.
+        fn finish(&self, acked: bool, result: ReturnCode) {
+            let buf = self.pending.take().expect("no frame being transmitted");
+            self.tx_client
+                .map(move |client| client.send_done(buf, acked, result));
+        }
+
+        fn sent(&self) -> usize {
+            self.sent.borrow().len()
+        }
+    }
+
+    impl radio::RadioConfig for SimRadio {
+        fn initialize(
+            &self,
+            _spi_buf: &'static mut [u8],
+            _reg_write: &'static mut [u8],
+            _reg_read: &'static mut [u8],
+        ) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn reset(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn start(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn stop(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn is_on(&self) -> bool {
+            true
+        }
+        fn busy(&self) -> bool {
+            self.busy_checks.set(self.busy_checks.get() + 1);
+            self.busy.get() || self.pending.is_some()
+        }
+        fn set_power_client(&self, _client: &'static dyn radio::PowerClient) {}
+        fn config_commit(&self) {}
+        fn set_config_client(&self, _client: &'static dyn radio::ConfigClient) {}
+        fn get_address(&self) -> u16 {
+            ADDRESS
+        }
+        fn get_address_long(&self) -> [u8; 8] {
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        }
+        fn get_pan(&self) -> u16 {
+            PAN
+        }
+        fn get_tx_power(&self) -> i8 {
+            0
+        }
+        fn get_channel(&self) -> u8 {
+            self.channel.get()
+        }
+        fn set_address(&self, _addr: u16) {}
+        fn set_address_long(&self, _addr: [u8; 8]) {}
+        fn set_pan(&self, _id: u16) {}
+        fn set_tx_power(&self, _power: i8) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+        fn set_channel(&self, chan: u8) -> ReturnCode {
+            self.channel.set(chan);
+            ReturnCode::SUCCESS
+        }
+    }
+
+    impl radio::RadioData for SimRadio {
+        fn set_transmit_client(&self, client: &'static dyn radio::TxClient) {
+            self.tx_client.set(client);
+        }
+        fn set_receive_client(
+            &self,
+            _client: &'static dyn radio::RxClient,
+            receive_buffer: &'static mut [u8],
+        ) {
+            self.rx_buf.replace(receive_buffer);
+        }
+        fn set_receive_buffer(&self, receive_buffer: &'static mut [u8]) {
+            self.rx_buf.replace(receive_buffer);
+        }
+        fn transmit(
+            &self,
+            buf: &'static mut [u8],
+            frame_len: usize,
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            let result = self.reject.replace(ReturnCode::SUCCESS);
+            if result != ReturnCode::SUCCESS {
+                return (result, Some(buf));
+            }
+            self.sent
+                .borrow_mut()
+                .push(buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame_len].to_vec());
+            self.pending.replace(buf);
+            (ReturnCode::SUCCESS, None)
+        }
+    }
+
+    impl radio::Radio for SimRadio {}
+
+    #[derive(Default)]
+    struct Client {
+        sent: RefCell<Vec<(bool, ReturnCode)>>,
+        received: Cell<usize>,
+    }
+
+    impl radio::TxClient for Client {
+        fn send_done(&self, _buf: &'static mut [u8], acked: bool, result: ReturnCode) {
+            self.sent.borrow_mut().push((acked, result));
+        }
+    }
+
+    impl radio::RxClient for Client {
+        fn receive(
+            &self,
+            _buf: &'static mut [u8],
+            _frame_len: usize,
+            _crc_valid: bool,
+            _result: ReturnCode,
+        ) {
+            self.received.set(self.received.get() + 1);
+        }
+    }
+
+    type TestMac = CsmaMac<'static, SimRadio, SimAlarm<'static>>;
+
+    fn setup() -> (
+        &'static SimRadio,
+        &'static SimAlarm<'static>,
+        &'static TestMac,
+        &'static Client,
+    ) {
+        let radio = leak(SimRadio::new());
+        let alarm = leak(SimAlarm::new(1000));
+        let mac = leak(CsmaMac::new(&*radio, &*alarm));
+        let client = leak(Client::default());
+        alarm.set_client(mac);
+        radio.set_transmit_client(mac);
+        mac.initialize(leak([0; radio::MAX_BUF_SIZE]));
+        mac.set_transmit_client(client);
+        mac.set_receive_client(client);
+        (radio, alarm, mac, client)
+    }
+
+    // Builds a frame of the given type from `src` to `dst` in a new buffer,
+    // returning the buffer and the frame length.
+    fn frame(
+        frame_type: FrameType,
+        src: Option<u16>,
+        dst: Option<u16>,
+        ack_requested: bool,
+        seq: u8,
+    ) -> (&'static mut [u8], usize) {
+        let header = Header {
+            frame_type: frame_type,
+            frame_pending: false,
+            ack_requested: ack_requested,
+            version: FrameVersion::V2006,
+            seq: Some(seq),
+            dst_pan: dst.map(|_| PAN),
+            dst_addr: dst.map(MacAddress::Short),
+            src_pan: src.map(|_| PAN),
+            src_addr: src.map(MacAddress::Short),
+            security: None,
+            header_ies: Default::default(),
+            header_ies_len: 0,
+            payload_ies: Default::default(),
+            payload_ies_len: 0,
+        };
+        let payload: &[u8] = if frame_type == FrameType::Acknowledgement {
+            &[]
+        } else {
+            &[0xaa, 0xbb, 0xcc, 0xdd]
+        };
+        let buf = leak([0; radio::MAX_BUF_SIZE]);
+        let (header_len, _) = header
+            .encode(&mut buf[radio::PSDU_OFFSET..], !payload.is_empty())
+            .done()
+            .unwrap();
+        let start = radio::PSDU_OFFSET + header_len;
+        buf[start..start + payload.len()].copy_from_slice(payload);
+        (buf, header_len + payload.len())
+    }
+
+    fn data_frame(dst: u16, ack_requested: bool, seq: u8) -> (&'static mut [u8], usize) {
+        frame(
+            FrameType::Data,
+            Some(ADDRESS),
+            Some(dst),
+            ack_requested,
+            seq,
+        )
+    }
+
+    fn receive_ack(mac: &TestMac, seq: u8) {
+        let (buf, len) = frame(FrameType::Acknowledgement, None, None, false, seq);
+        radio::RxClient::receive(mac, buf, len, true, ReturnCode::SUCCESS);
+    }
+
+    // The time until the alarm fires, in 1 ms tics
+    fn alarm_delay(alarm: &SimAlarm) -> u32 {
+        alarm.get_alarm().wrapping_sub(alarm.now())
+    }
+
+    // A backoff of `2^be - 1` periods, in 1 ms tics
+    fn max_backoff(be: u8) -> u32 {
+        cmp::max(((1 << be) - 1) * UNIT_BACKOFF_US / 1000, 1)
+    }
+
+    #[test]
+    fn decode_beacon_payload() {
+        // No GTS or pending addresses
+        let beacon = [0xff, 0xcf, 0x00, 0x00, 0xaa, 0xbb];
+        assert_eq!(decode_beacon(&beacon), Some((0xcfff, &beacon[4..])));
+
+        // Two GTS descriptors, one short and one long pending address
+        let mut beacon = [0u8; 23];
+        beacon[0] = 0x11;
+        beacon[2] = 0x02;
+        beacon[10] = 0x11;
+        beacon[21] = 0x42;
+        assert_eq!(decode_beacon(&beacon), Some((0x0011, &beacon[21..])));
+
+        // Truncated pending address list
+        assert_eq!(decode_beacon(&beacon[..15]), None);
+        assert_eq!(decode_beacon(&[0xff, 0xcf, 0x00]), None);
+    }
+
+    #[test]
+    fn transmission_waits_for_backoff() {
+        let (radio, alarm, mac, client) = setup();
+
+        // Broadcast frames are not acknowledged, even if they ask for it
+        let (buf, len) = data_frame(BROADCAST_ADDRESS, true, 1);
+        assert_eq!(mac.transmit(buf, len).0, ReturnCode::SUCCESS);
+        assert_eq!(radio.sent(), 0);
+        assert!(alarm.is_enabled());
+        assert!(alarm_delay(alarm) <= max_backoff(DEFAULT_MIN_BE));
+
+        let (buf, len) = data_frame(PEER, false, 2);
+        assert_eq!(mac.transmit(buf, len).0, ReturnCode::EBUSY);
+
+        alarm.advance(10);
+        assert_eq!(radio.sent(), 1);
+        assert_eq!(radio.sent.borrow()[0].len(), len);
+        radio.finish(false, ReturnCode::SUCCESS);
+        assert_eq!(*client.sent.borrow(), [(false, ReturnCode::SUCCESS)]);
+        assert!(!alarm.is_enabled());
+    }
+
+    #[test]
+    fn busy_channel_fails_after_max_backoffs() {
+        let (radio, alarm, mac, client) = setup();
+        assert_eq!(mac.set_csma_parameters(3, 5, 4), ReturnCode::SUCCESS);
+        radio.busy.set(true);
+
+        let (buf, len) = data_frame(PEER, false, 1);
+        mac.transmit(buf, len);
+        // The backoff exponent grows with each busy channel, up to macMaxBE
+        for be in &[3, 4, 5, 5, 5] {
+            assert!(client.sent.borrow().is_empty());
+            assert_eq!(mac.backoff_exponent.get(), *be);
+            assert!(alarm_delay(alarm) <= max_backoff(*be));
+            alarm.advance(10);
+        }
+
+        assert_eq!(radio.busy_checks.get(), 5);
+        assert_eq!(radio.sent(), 0);
+        assert_eq!(*client.sent.borrow(), [(false, ReturnCode::EBUSY)]);
+        assert!(!alarm.is_enabled());
+    }
+
+    #[test]
+    fn busy_channel_is_retried() {
+        let (radio, alarm, mac, client) = setup();
+        radio.busy.set(true);
+
+        let (buf, len) = data_frame(PEER, false, 1);
+        mac.transmit(buf, len);
+        alarm.advance(10);
+        assert_eq!(mac.backoffs.get(), 1);
+        radio.busy.set(false);
+
+        // A transmission the radio rejects is a busy channel
+        radio.reject.set(ReturnCode::EBUSY);
+        alarm.advance(10);
+        assert_eq!(mac.backoffs.get(), 2);
+        assert_eq!(radio.sent(), 0);
+
+        // So is a transmission the radio aborts
+        alarm.advance(10);
+        assert_eq!(radio.sent(), 1);
+        radio.finish(false, ReturnCode::FAIL);
+        assert_eq!(mac.backoffs.get(), 3);
+        assert!(client.sent.borrow().is_empty());
+
+        alarm.advance(10);
+        assert_eq!(radio.sent(), 2);
+        radio.finish(false, ReturnCode::SUCCESS);
+        assert_eq!(*client.sent.borrow(), [(false, ReturnCode::SUCCESS)]);
+    }
+
+    #[test]
+    fn other_errors_end_transmission() {
+        let (radio, alarm, mac, client) = setup();
+
+        let (buf, len) = data_frame(PEER, false, 1);
+        mac.transmit(buf, len);
+        radio.reject.set(ReturnCode::EOFF);
+        alarm.advance(10);
+        assert_eq!(*client.sent.borrow(), [(false, ReturnCode::EOFF)]);
+        assert_eq!(radio.sent(), 0);
+        assert!(!alarm.is_enabled());
+    }
+
+    #[test]
+    fn unacknowledged_frame_is_retried() {
+        let (radio, alarm, mac, client) = setup();
+        assert_eq!(mac.set_max_frame_retries(2), ReturnCode::SUCCESS);
+
+        let (buf, len) = data_frame(PEER, true, 7);
+        mac.transmit(buf, len);
+        for sent in 1..=3 {
+            assert!(client.sent.borrow().is_empty());
+            // Each retransmission starts CSMA-CA again
+            assert_eq!(mac.backoffs.get(), 0);
+            assert!(alarm_delay(alarm) <= max_backoff(DEFAULT_MIN_BE));
+            alarm.advance(10);
+            assert_eq!(radio.sent(), sent);
+            radio.finish(false, ReturnCode::SUCCESS);
+            assert_eq!(alarm_delay(alarm), DEFAULT_ACK_WAIT_US / 1000);
+            alarm.advance(10);
+        }
+
+        assert_eq!(*client.sent.borrow(), [(false, ReturnCode::ENOACK)]);
+        let sent = radio.sent.borrow();
+        assert!(sent.iter().all(|frame| *frame == sent[0]));
+    }
+
+    #[test]
+    fn ack_completes_transmission() {
+        let (radio, alarm, mac, client) = setup();
+
+        let (buf, len) = data_frame(PEER, true, 7);
+        mac.transmit(buf, len);
+        alarm.advance(10);
+        radio.finish(false, ReturnCode::SUCCESS);
+
+        // ACKs for other frames are ignored
+        receive_ack(mac, 6);
+        assert!(client.sent.borrow().is_empty());
+        assert!(alarm.is_enabled());
+
+        receive_ack(mac, 7);
+        assert_eq!(*client.sent.borrow(), [(true, ReturnCode::SUCCESS)]);
+        assert!(!alarm.is_enabled());
+        assert_eq!(radio.sent(), 1);
+        // ACKs are not passed up
+        assert_eq!(client.received.get(), 0);
+    }
+
+    #[test]
+    fn hardware_ack_completes_transmission() {
+        let (radio, alarm, mac, client) = setup();
+
+        let (buf, len) = data_frame(PEER, true, 7);
+        mac.transmit(buf, len);
+        alarm.advance(10);
+        radio.finish(true, ReturnCode::SUCCESS);
+        assert_eq!(*client.sent.borrow(), [(true, ReturnCode::SUCCESS)]);
+        assert!(!alarm.is_enabled());
+    }
+
+    #[test]
+    fn received_frames_are_acknowledged() {
+        let (radio, _alarm, mac, client) = setup();
+
+        // Frames for another node are dropped
+        let (buf, len) = frame(FrameType::Data, Some(PEER), Some(0x0003), true, 4);
+        radio::RxClient::receive(mac, buf, len, true, ReturnCode::SUCCESS);
+        assert_eq!(client.received.get(), 0);
+        assert_eq!(radio.sent(), 0);
+
+        let (buf, len) = frame(FrameType::Data, Some(PEER), Some(ADDRESS), true, 5);
+        radio::RxClient::receive(mac, buf, len, true, ReturnCode::SUCCESS);
+        assert_eq!(client.received.get(), 1);
+        assert_eq!(radio.sent(), 1);
+        let sent = radio.sent.borrow();
+        let (_, (ack, _)) = Header::decode(&sent[0], false).done().unwrap();
+        assert_eq!(ack.frame_type, FrameType::Acknowledgement);
+        assert_eq!(ack.seq, Some(5));
+        drop(sent);
+
+        // The ACK does not go through CSMA-CA or reach the client
+        radio.finish(false, ReturnCode::SUCCESS);
+        assert!(client.sent.borrow().is_empty());
+        assert!(mac.mac_buf.is_some());
+
+        // Without software ACKs, the frame is only passed up
+        mac.set_software_ack(false);
+        let (buf, len) = frame(FrameType::Data, Some(PEER), Some(ADDRESS), true, 6);
+        radio::RxClient::receive(mac, buf, len, true, ReturnCode::SUCCESS);
+        assert_eq!(client.received.get(), 2);
+        assert_eq!(radio.sent(), 1);
+    }
+}
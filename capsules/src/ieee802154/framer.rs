@@ -124,6 +124,12 @@ impl Frame {
         self.buf
     }
 
+    /// Returns the MAC header and payload of the frame as they are before
+    /// security processing, without the MIC and FCS
+    pub fn unsecured_frame(&self) -> &[u8] {
+        &self.buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + self.info.unsecured_length()]
+    }
+
     /// Calculates how much more data this frame can hold
     pub fn remaining_data_capacity(&self) -> usize {
         self.buf.len() - radio::PSDU_OFFSET - radio::MFR_SIZE - self.info.secured_length()
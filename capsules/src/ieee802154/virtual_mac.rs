@@ -27,12 +27,17 @@
 //!     capsules::ieee802154::virtual_mac::MacUser::new(mux_mac));
 //! mux_mac.add_user(virtual_mac);
 //! ```
+//!
+//! All received and transmitted frames can be mirrored to a packet capture
+//! sink (see `net::pcap`) with `set_capture_tap`.
 
 use crate::ieee802154::{device, framer};
 use crate::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
+use crate::net::pcap::{CaptureTap, Direction, LinkType};
 use core::cell::Cell;
 use kernel::common::cells::{MapCell, OptionalCell};
 use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::radio;
 use kernel::ReturnCode;
 
 /// IEE 802.15.4 MAC device muxer that keeps a list of MAC users and sequences
@@ -42,6 +47,7 @@ pub struct MuxMac<'a> {
     mac: &'a dyn device::MacDevice<'a>,
     users: List<'a, MacUser<'a>>,
     inflight: OptionalCell<&'a MacUser<'a>>,
+    capture_tap: OptionalCell<&'a dyn CaptureTap>,
 }
 
 impl device::TxClient for MuxMac<'_> {
@@ -55,6 +61,13 @@ impl device::TxClient for MuxMac<'_> {
 
 impl device::RxClient for MuxMac<'_> {
     fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+        self.capture_tap.map(|tap| {
+            tap.capture(
+                LinkType::Ieee802154NoFcs,
+                Direction::Received,
+                &buf[radio::PSDU_OFFSET..data_offset + data_len],
+            )
+        });
         for user in self.users.iter() {
             user.receive(buf, header, data_offset, data_len);
         }
@@ -67,9 +80,26 @@ impl<'a> MuxMac<'a> {
             mac: mac,
             users: List::new(),
             inflight: OptionalCell::empty(),
+            capture_tap: OptionalCell::empty(),
         }
     }
 
+    /// Sets a packet capture sink that is passed every frame received and
+    /// transmitted through this mux.
+    pub fn set_capture_tap(&self, tap: &'a dyn CaptureTap) {
+        self.capture_tap.set(tap);
+    }
+
+    fn capture_transmit(&self, frame: &framer::Frame) {
+        self.capture_tap.map(|tap| {
+            tap.capture(
+                LinkType::Ieee802154NoFcs,
+                Direction::Transmitted,
+                frame.unsecured_frame(),
+            )
+        });
+    }
+
     /// Registers a MAC user with this MAC mux device. Each MAC user should only
     /// be registered once.
     pub fn add_user(&self, user: &'a MacUser<'a>) {
@@ -103,6 +133,7 @@ impl<'a> MuxMac<'a> {
     /// buffer to the `MacUser` via its transmit client.
     fn perform_op_async(&self, node: &'a MacUser<'a>, op: Op) {
         if let Op::Transmit(frame) = op {
+            self.capture_transmit(&frame);
             let (result, mbuf) = self.mac.transmit(frame);
             // If a buffer is returned, the transmission failed,
             // otherwise it succeeded.
@@ -125,6 +156,7 @@ impl<'a> MuxMac<'a> {
         op: Op,
     ) -> Option<(ReturnCode, Option<&'static mut [u8]>)> {
         if let Op::Transmit(frame) = op {
+            self.capture_transmit(&frame);
             let (result, mbuf) = self.mac.transmit(frame);
             if result == ReturnCode::SUCCESS {
                 self.inflight.set(node);
@@ -0,0 +1,870 @@
+//! Key store that keeps key material in the kernel.
+//!
+//! Keys live in a table of slots in a region of nonvolatile storage that
+//! only this capsule uses. The table is read into RAM by `load()` at boot,
+//! and every change is written back to its slot. A change whose write the
+//! storage refuses is undone, and the error returned. Keys are referred to by a
+//! `hil::key_store::KeyHandle`, which is the slot number.
+//!
+//! Each key belongs either to the kernel or to one process. Processes are
+//! identified by a hash of their name, so that a process keeps its keys
+//! across reboots and updates; processes with the same name share keys.
+//! The name is not authenticated; see `process_identity`. Processes without
+//! a name can't have keys.
+//! Processes can import keys, derive new keys from their keys with HKDF
+//! (RFC 5869) and delete their keys, but can never read a key back. Instead
+//! they pass the handle to a crypto driver (for example `aes::AesDriver` or
+//! `hmac::HmacDriver`), which reads the key through the
+//! `hil::key_store::KeyStore` trait on their behalf.
+//!
+//! ```text
+//!   +-----------------+        +-------------------------------+
+//!   |    userspace    |        | crypto drivers (AES, HMAC...) |
+//!   +-----------------+        +-------------------------------+
+//!     kernel::Driver             hil::key_store::KeyStore
+//!   +----------------------------------------------------------+
+//!   |           capsules::key_store::KeyStore (this)           |
+//!   +----------------------------------------------------------+
+//!             hil::nonvolatile_storage::NonvolatileStorage
+//!   +----------------------------------------------------------+
+//!   |      Dedicated storage (e.g. NonvolatileToPages over     |
+//!   |                 a virtual_flash::FlashUser)              |
+//!   +----------------------------------------------------------+
+//! ```
+//!
+//! The region must be `KEY_STORE_SIZE` bytes long and must not be
+//! accessible to processes, since it holds the keys in the clear.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
+//! let key_store = static_init!(
+//!     capsules::key_store::KeyStore<'static, ProcessMgmtCap>,
+//!     capsules::key_store::KeyStore::new(
+//!         nv_to_page,
+//!         0x3f000,           // Start address of the key region
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         board_kernel.create_grant(&grant_cap),
+//!         &mut capsules::key_store::BUFFER
+//!     )
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, key_store);
+//! key_store.load();
+//! aes.set_key_store(key_store);
+//! ```
+
+use crate::driver;
+use crate::hkdf;
+use crate::process_identity;
+use crate::sha::SHA256_DIGEST_LEN;
+use core::cell::Cell;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::hil::key_store::KeyHandle;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, Kernel, ReturnCode, Shared};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::KeyStore as usize;
+
+/// Number of keys the store can hold.
+pub const NUM_KEY_SLOTS: usize = 16;
+/// Size of a slot in storage.
+pub const KEY_SLOT_SIZE: usize = 64;
+/// Size of the storage region.
+pub const KEY_STORE_SIZE: usize = NUM_KEY_SLOTS * KEY_SLOT_SIZE;
+/// Longest key the store can hold.
+pub const MAX_KEY_LEN: usize = 32;
+/// Number of keys a single process can own.
+pub const MAX_KEYS_PER_APP: usize = 4;
+
+/// A slot buffer and a reserve one.
+pub static mut BUFFER: [u8; 2 * KEY_SLOT_SIZE] = [0; 2 * KEY_SLOT_SIZE];
+
+// Layout of a slot: magic, flags, key length, reserved, owner, key. Slots
+// without the magic are free.
+const SLOT_MAGIC: u8 = 0x4b;
+const SLOT_FLAG_KERNEL: u8 = 0x01;
+const OWNER_OFFSET: usize = 4;
+const OWNER_LEN: usize = 16;
+const KEY_OFFSET: usize = OWNER_OFFSET + OWNER_LEN;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Owner {
+    Kernel,
+    /// Truncated SHA-256 of the process name
+    App([u8; OWNER_LEN]),
+}
+
+impl Owner {
+    /// The owner for the process with `identity`. Processes without one are
+    /// refused.
+    fn of_app(identity: Option<[u8; SHA256_DIGEST_LEN]>) -> Result<Owner, ReturnCode> {
+        let digest = identity.ok_or(ReturnCode::ENOSUPPORT)?;
+        let mut id = [0; OWNER_LEN];
+        id.copy_from_slice(&digest[..OWNER_LEN]);
+        Ok(Owner::App(id))
+    }
+}
+
+#[derive(Copy, Clone, Default)]
+struct Slot {
+    // `None` if the slot is free
+    owner: Option<Owner>,
+    length: usize,
+    key: [u8; MAX_KEY_LEN],
+}
+
+impl Slot {
+    fn decode(buf: &[u8]) -> Slot {
+        let length = buf[2] as usize;
+        if buf[0] != SLOT_MAGIC || length == 0 || length > MAX_KEY_LEN {
+            return Slot::default();
+        }
+        let owner = if buf[1] & SLOT_FLAG_KERNEL != 0 {
+            Owner::Kernel
+        } else {
+            let mut id = [0; OWNER_LEN];
+            id.copy_from_slice(&buf[OWNER_OFFSET..OWNER_OFFSET + OWNER_LEN]);
+            Owner::App(id)
+        };
+        let mut key = [0; MAX_KEY_LEN];
+        key[..length].copy_from_slice(&buf[KEY_OFFSET..KEY_OFFSET + length]);
+        Slot {
+            owner: Some(owner),
+            length: length,
+            key: key,
+        }
+    }
+
+    /// Free slots are written as zeros, which also overwrites the old key.
+    fn encode(&self, buf: &mut [u8]) {
+        buf[..KEY_SLOT_SIZE].iter_mut().for_each(|b| *b = 0);
+        if let Some(owner) = self.owner {
+            buf[0] = SLOT_MAGIC;
+            buf[2] = self.length as u8;
+            match owner {
+                Owner::Kernel => buf[1] = SLOT_FLAG_KERNEL,
+                Owner::App(id) => buf[OWNER_OFFSET..OWNER_OFFSET + OWNER_LEN].copy_from_slice(&id),
+            }
+            buf[KEY_OFFSET..KEY_OFFSET + self.length].copy_from_slice(&self.key[..self.length]);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum State {
+    /// Reading the given slot at boot
+    Loading(usize),
+    Idle,
+    /// Writing the given slot back
+    Writing(usize),
+}
+
+pub struct App {
+    callback: OptionalCell<Callback>,
+    // The slot whose write the process is waiting for
+    pending_slot: Option<usize>,
+    key: Option<AppSlice<Shared, u8>>,
+    salt: Option<AppSlice<Shared, u8>>,
+    info: Option<AppSlice<Shared, u8>>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: OptionalCell::empty(),
+            pending_slot: None,
+            key: None,
+            salt: None,
+            info: None,
+        }
+    }
+}
+
+/// The slots in RAM and their copy in storage, which is kept up to date
+/// one slot at a time.
+struct KeyTable<'a> {
+    storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+    // Address of the first slot in storage
+    start_address: usize,
+    slots: [Cell<Slot>; NUM_KEY_SLOTS],
+    // Bit i is set if slot i changed and must be written back
+    dirty: Cell<u32>,
+    state: Cell<State>,
+    buffer: TakeCell<'static, [u8]>,
+    // Replaces `buffer` when the storage refuses an operation, since it does
+    // not give the buffer back then
+    reserve: TakeCell<'static, [u8]>,
+}
+
+impl<'a> KeyTable<'a> {
+    fn new(
+        storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        start_address: usize,
+        buffer: &'static mut [u8],
+    ) -> KeyTable<'a> {
+        let (buffer, reserve) = buffer.split_at_mut(KEY_SLOT_SIZE);
+        KeyTable {
+            storage: storage,
+            start_address: start_address,
+            slots: <[Cell<Slot>; NUM_KEY_SLOTS]>::default(),
+            dirty: Cell::new(0),
+            state: Cell::new(State::Loading(0)),
+            buffer: TakeCell::new(buffer),
+            reserve: TakeCell::new(reserve),
+        }
+    }
+
+    fn load(&self) -> ReturnCode {
+        if self.state.get() != State::Loading(0) {
+            return ReturnCode::EALREADY;
+        }
+        self.read_slot(0)
+    }
+
+    fn is_loading(&self) -> bool {
+        match self.state.get() {
+            State::Loading(_) => true,
+            _ => false,
+        }
+    }
+
+    /// A free slot, and what it holds once `key` of `owner` is put in it.
+    fn new_slot(&self, owner: Owner, key: &[u8]) -> Result<(usize, Slot), ReturnCode> {
+        if self.is_loading() {
+            return Err(ReturnCode::EBUSY);
+        }
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return Err(ReturnCode::EINVAL);
+        }
+        if owner != Owner::Kernel {
+            let owned = self
+                .slots
+                .iter()
+                .filter(|slot| slot.get().owner == Some(owner))
+                .count();
+            if owned >= MAX_KEYS_PER_APP {
+                return Err(ReturnCode::ENOMEM);
+            }
+        }
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| slot.get().owner.is_none())
+            .ok_or(ReturnCode::ENOMEM)?;
+
+        let mut slot = Slot {
+            owner: Some(owner),
+            length: key.len(),
+            key: [0; MAX_KEY_LEN],
+        };
+        slot.key[..key.len()].copy_from_slice(key);
+        Ok((index, slot))
+    }
+
+    /// The slot `index`, if it holds a key of `owner`.
+    fn owned_slot(&self, index: usize, owner: Owner) -> Result<usize, ReturnCode> {
+        if self.is_loading() {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.slots
+            .get(index)
+            .filter(|slot| slot.get().owner == Some(owner))
+            .map(|_| index)
+            .ok_or(ReturnCode::EINVAL)
+    }
+
+    fn copy_key(&self, index: usize, owner: Owner, key: &mut [u8]) -> Result<usize, ReturnCode> {
+        let slot = self.slots[self.owned_slot(index, owner)?].get();
+        if key.len() < slot.length {
+            return Err(ReturnCode::ESIZE);
+        }
+        key[..slot.length].copy_from_slice(&slot.key[..slot.length]);
+        Ok(slot.length)
+    }
+
+    /// Puts `slot` in slot `index` and writes it back, after the write in
+    /// progress if there is one. If the write can't be started, the slot is
+    /// changed back and the error returned.
+    fn change(&self, index: usize, slot: Slot) -> ReturnCode {
+        let old = self.slots[index].replace(slot);
+        let was_dirty = self.dirty.get() & (1 << index);
+        self.dirty.set(self.dirty.get() | 1 << index);
+        match self.write_next() {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((_, result)) => {
+                self.slots[index].set(old);
+                self.dirty.set(self.dirty.get() & !(1 << index) | was_dirty);
+                result
+            }
+        }
+    }
+
+    fn read_slot(&self, index: usize) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::ENOMEM, |buffer| {
+            self.state.set(State::Loading(index));
+            let result = self.storage.read(
+                buffer,
+                self.start_address + index * KEY_SLOT_SIZE,
+                KEY_SLOT_SIZE,
+            );
+            if result != ReturnCode::SUCCESS {
+                self.buffer_refused();
+            }
+            result
+        })
+    }
+
+    /// Starts writing back the next changed slot, if there is one and no
+    /// write is in progress. If the write can't be started, the slot stays
+    /// changed, and is written after the next change, and the slot and the
+    /// error are returned.
+    fn write_next(&self) -> Result<(), (usize, ReturnCode)> {
+        let dirty = self.dirty.get();
+        if self.state.get() != State::Idle || dirty == 0 {
+            return Ok(());
+        }
+        let index = dirty.trailing_zeros() as usize;
+        let buffer = self.buffer.take().ok_or((index, ReturnCode::ENOMEM))?;
+        self.slots[index].get().encode(buffer);
+        // Clear the bit now, so that changes made during the write cause
+        // another one
+        self.dirty.set(dirty & !(1 << index));
+        self.state.set(State::Writing(index));
+        let result = self.storage.write(
+            buffer,
+            self.start_address + index * KEY_SLOT_SIZE,
+            KEY_SLOT_SIZE,
+        );
+        if result != ReturnCode::SUCCESS {
+            self.dirty.set(self.dirty.get() | 1 << index);
+            self.buffer_refused();
+            return Err((index, result));
+        }
+        Ok(())
+    }
+
+    /// Goes back to idle after the storage refused an operation and kept
+    /// the buffer.
+    fn buffer_refused(&self) {
+        self.state.set(State::Idle);
+        self.reserve
+            .take()
+            .map(|reserve| self.buffer.replace(reserve));
+    }
+
+    fn put_buffer(&self, buffer: &'static mut [u8]) {
+        buffer.iter_mut().for_each(|b| *b = 0);
+        if self.buffer.is_none() {
+            self.buffer.replace(buffer);
+        } else {
+            self.reserve.replace(buffer);
+        }
+    }
+
+    fn read_done(&self, buffer: &'static mut [u8]) {
+        if let State::Loading(index) = self.state.get() {
+            self.slots[index].set(Slot::decode(buffer));
+        }
+        self.put_buffer(buffer);
+
+        match self.state.get() {
+            State::Loading(index) if index + 1 < NUM_KEY_SLOTS => {
+                if self.read_slot(index + 1) != ReturnCode::SUCCESS {
+                    // Use the keys read so far rather than none at all
+                    self.state.set(State::Idle);
+                }
+            }
+            _ => self.state.set(State::Idle),
+        }
+    }
+
+    /// Finishes a write and starts the next one. `written` is called with
+    /// the result for each slot whose latest change is in storage, or which
+    /// could not be written.
+    fn write_done<F: FnMut(usize, ReturnCode)>(&self, buffer: &'static mut [u8], mut written: F) {
+        self.put_buffer(buffer);
+        if let State::Writing(index) = self.state.get() {
+            self.state.set(State::Idle);
+            // If the slot was changed again during the write, wait for the
+            // next one
+            if self.dirty.get() & (1 << index) == 0 {
+                written(index, ReturnCode::SUCCESS);
+            }
+        }
+        if let Err((index, result)) = self.write_next() {
+            written(index, result);
+        }
+    }
+}
+
+pub struct KeyStore<'a, C: ProcessManagementCapability> {
+    table: KeyTable<'a>,
+    kernel: &'static Kernel,
+    capability: C,
+    apps: Grant<App>,
+}
+
+impl<'a, C: ProcessManagementCapability> KeyStore<'a, C> {
+    /// `buffer` must be `2 * KEY_SLOT_SIZE` bytes long, such as `BUFFER`.
+    pub fn new(
+        storage: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
+        start_address: usize,
+        kernel: &'static Kernel,
+        capability: C,
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+    ) -> KeyStore<'a, C> {
+        KeyStore {
+            table: KeyTable::new(storage, start_address, buffer),
+            kernel: kernel,
+            capability: capability,
+            apps: grant,
+        }
+    }
+
+    /// Reads the key table from storage. Keys can't be used until this has
+    /// finished.
+    pub fn load(&self) -> ReturnCode {
+        self.table.load()
+    }
+
+    /// Stores a copy of `key` for `owner` (`None` for the kernel). The key
+    /// is written to storage in the background.
+    pub fn add_key(&self, owner: Option<AppId>, key: &[u8]) -> Result<KeyHandle, ReturnCode> {
+        let (index, slot) = self.table.new_slot(self.owner_of(owner)?, key)?;
+        match self.table.change(index, slot) {
+            ReturnCode::SUCCESS => Ok(KeyHandle::new(index)),
+            result => Err(result),
+        }
+    }
+
+    /// Derives a new key of `length` bytes from the key `parent` with
+    /// HKDF-SHA256, using `salt` and `info` as the HKDF salt and context.
+    /// The new key has the same owner as `parent`.
+    pub fn derive_key(
+        &self,
+        parent: KeyHandle,
+        owner: Option<AppId>,
+        salt: &[u8],
+        info: &[u8],
+        length: usize,
+    ) -> Result<KeyHandle, ReturnCode> {
+        let (index, slot) = self.derived_slot(parent, self.owner_of(owner)?, salt, info, length)?;
+        match self.table.change(index, slot) {
+            ReturnCode::SUCCESS => Ok(KeyHandle::new(index)),
+            result => Err(result),
+        }
+    }
+
+    /// Removes the key `handle` of `owner`, in RAM now and in storage in the
+    /// background.
+    pub fn delete_key(&self, handle: KeyHandle, owner: Option<AppId>) -> ReturnCode {
+        let index = match self
+            .owner_of(owner)
+            .and_then(|owner| self.table.owned_slot(handle.id(), owner))
+        {
+            Ok(index) => index,
+            Err(result) => return result,
+        };
+        self.table.change(index, Slot::default())
+    }
+
+    /// A free slot, and what it holds once the key derived from `parent` is
+    /// put in it.
+    fn derived_slot(
+        &self,
+        parent: KeyHandle,
+        owner: Owner,
+        salt: &[u8],
+        info: &[u8],
+        length: usize,
+    ) -> Result<(usize, Slot), ReturnCode> {
+        if length == 0 || length > MAX_KEY_LEN {
+            return Err(ReturnCode::EINVAL);
+        }
+        let mut input = [0; MAX_KEY_LEN];
+        let input_length = self.table.copy_key(parent.id(), owner, &mut input)?;
+        let mut key = [0; MAX_KEY_LEN];
+        let res = hkdf::derive(salt, &input[..input_length], info, &mut key[..length]);
+        let slot = if res == ReturnCode::SUCCESS {
+            self.table.new_slot(owner, &key[..length])
+        } else {
+            Err(res)
+        };
+        input.iter_mut().for_each(|b| *b = 0);
+        key.iter_mut().for_each(|b| *b = 0);
+        slot
+    }
+
+    fn owner_of(&self, appid: Option<AppId>) -> Result<Owner, ReturnCode> {
+        appid.map_or(Ok(Owner::Kernel), |appid| {
+            Owner::of_app(process_identity::name_digest(
+                self.kernel,
+                &self.capability,
+                appid,
+            ))
+        })
+    }
+
+    /// Tells processes waiting for slot `index` that it has been written.
+    fn notify(&self, index: usize, res: ReturnCode) {
+        for cntr in self.apps.iter() {
+            cntr.enter(|app, _| {
+                if app.pending_slot == Some(index) {
+                    app.pending_slot = None;
+                    app.callback
+                        .map(|cb| cb.schedule(usize::from(res), index, 0));
+                }
+            });
+        }
+    }
+
+    /// Runs a key operation for a process, which returns the slot to change
+    /// and its new contents. The process is notified once the slot has been
+    /// written, or gets the error at once if the write can't be started.
+    fn app_operation<F>(&self, appid: AppId, operation: F) -> ReturnCode
+    where
+        F: FnOnce(&mut App, Owner) -> Result<(usize, Slot), ReturnCode>,
+    {
+        if self.table.is_loading() {
+            return ReturnCode::EBUSY;
+        }
+        let owner = match self.owner_of(Some(appid)) {
+            Ok(owner) => owner,
+            Err(result) => return result,
+        };
+        self.apps
+            .enter(appid, |app, _| {
+                if app.pending_slot.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                let (index, slot) = match operation(app, owner) {
+                    Ok(change) => change,
+                    Err(result) => return result,
+                };
+                app.pending_slot = Some(index);
+                let result = self.table.change(index, slot);
+                if result != ReturnCode::SUCCESS {
+                    app.pending_slot = None;
+                }
+                result
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+impl<C: ProcessManagementCapability> hil::key_store::KeyStore for KeyStore<'_, C> {
+    fn read_key(
+        &self,
+        handle: KeyHandle,
+        owner: Option<AppId>,
+        key: &mut [u8],
+    ) -> Result<usize, ReturnCode> {
+        self.table.copy_key(handle.id(), self.owner_of(owner)?, key)
+    }
+}
+
+impl<C: ProcessManagementCapability> hil::nonvolatile_storage::NonvolatileStorageClient<'static>
+    for KeyStore<'_, C>
+{
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.table.read_done(buffer);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.table
+            .write_done(buffer, |index, res| self.notify(index, res));
+    }
+}
+
+/// Specify memory regions to be used.
+///
+/// ### `allow_num`
+///
+/// - `0`: The key to import. It is copied when the import runs.
+/// - `1`: The salt for key derivation. May be empty.
+/// - `2`: The context (info) for key derivation. May be empty.
+impl<C: ProcessManagementCapability> Driver for KeyStore<'_, C> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.key = slice,
+                    1 => app.salt = slice,
+                    2 => app.info = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or(ReturnCode::FAIL)
+    }
+
+    /// Subscribe to key store events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Subscribe to the completion of imports, derivations and
+    ///        deletions, once the change is in storage. The callback
+    ///        signature is `fn(result: u32, handle: usize)`.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback.insert(callback);
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or(ReturnCode::FAIL),
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Manage the keys of the process.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Import the first `data1` bytes of the key buffer as a new key.
+    /// - `2`: Derive a new key of `data2` bytes from the key with handle
+    ///        `data1`, using the salt and info buffers.
+    /// - `3`: Delete the key with handle `data1`.
+    ///
+    /// Keys are at most `MAX_KEY_LEN` bytes long. A process can have one
+    /// operation in progress at a time.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            // import
+            1 => self.app_operation(appid, |app, owner| {
+                let key = app
+                    .key
+                    .as_ref()
+                    .ok_or(ReturnCode::ERESERVE)?
+                    .as_ref()
+                    .get(..data1)
+                    .ok_or(ReturnCode::EINVAL)?;
+                self.table.new_slot(owner, key)
+            }),
+
+            // derive
+            2 => self.app_operation(appid, |app, owner| {
+                let salt = app.salt.as_ref().map_or(&[][..], |salt| salt.as_ref());
+                let info = app.info.as_ref().map_or(&[][..], |info| info.as_ref());
+                self.derived_slot(KeyHandle::new(data1), owner, salt, info, data2)
+            }),
+
+            // delete
+            3 => self.app_operation(appid, |_, owner| {
+                self.table
+                    .owned_slot(data1, owner)
+                    .map(|index| (index, Slot::default()))
+            }),
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::nonvolatile_to_pages::NonvolatileToPages;
+    use crate::test_util::{leak, Medium, Page, SimFlash, WriteMode, PAGE_SIZE};
+    use core::cell::RefCell;
+    use kernel::common::cells::OptionalCell;
+    use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+    use std::vec::Vec;
+
+    const APP: Owner = Owner::App([1; OWNER_LEN]);
+    const OTHER_APP: Owner = Owner::App([2; OWNER_LEN]);
+
+    /// Passes storage callbacks to the table, and records the slots it
+    /// reports written.
+    struct Harness {
+        flash: &'static SimFlash,
+        table: OptionalCell<&'static KeyTable<'static>>,
+        written: RefCell<Vec<(usize, ReturnCode)>>,
+    }
+
+    impl NonvolatileStorageClient<'static> for Harness {
+        fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+            self.table.map(move |table| table.read_done(buffer));
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+            self.table.map(move |table| {
+                table.write_done(buffer, |index, res| {
+                    self.written.borrow_mut().push((index, res))
+                })
+            });
+        }
+    }
+
+    impl Harness {
+        /// A table over the key region at the start of `medium`, loaded.
+        fn load(medium: &'static Medium) -> &'static Harness {
+            let flash = SimFlash::new(medium, WriteMode::Replace);
+            let storage = leak(NonvolatileToPages::new(flash, leak(Page::default())));
+            flash.set_client(storage);
+            let table = leak(KeyTable::new(storage, 0, leak([0; 2 * KEY_SLOT_SIZE])));
+            let harness = leak(Harness {
+                flash: flash,
+                table: OptionalCell::new(table),
+                written: RefCell::new(Vec::new()),
+            });
+            storage.set_client(harness);
+            assert_eq!(table.load(), ReturnCode::SUCCESS);
+            assert_eq!(table.new_slot(APP, &[1]).err(), Some(ReturnCode::EBUSY));
+            harness.run();
+            assert!(!table.is_loading());
+            harness
+        }
+
+        fn table(&self) -> &'static KeyTable<'static> {
+            self.table.map(|table| *table).unwrap()
+        }
+
+        fn run(&self) {
+            while self.flash.step() {}
+        }
+
+        fn add(&self, owner: Owner, key: &[u8]) -> Result<usize, ReturnCode> {
+            let (index, slot) = self.table().new_slot(owner, key)?;
+            match self.table().change(index, slot) {
+                ReturnCode::SUCCESS => Ok(index),
+                result => Err(result),
+            }
+        }
+
+        fn key(&self, index: usize, owner: Owner) -> Result<Vec<u8>, ReturnCode> {
+            let mut key = [0; MAX_KEY_LEN];
+            let length = self.table().copy_key(index, owner, &mut key)?;
+            Ok(key[..length].to_vec())
+        }
+    }
+
+    fn medium() -> &'static Medium {
+        Medium::new(KEY_STORE_SIZE / PAGE_SIZE)
+    }
+
+    #[test]
+    fn keys_are_loaded_after_a_reset() {
+        let medium = medium();
+        let store = Harness::load(medium);
+        let kernel_key = store.add(Owner::Kernel, &[0x11; 16]).unwrap();
+        let app_key = store.add(APP, &[0x22; 32]).unwrap();
+        store.run();
+        assert_eq!(
+            *store.written.borrow(),
+            [
+                (kernel_key, ReturnCode::SUCCESS),
+                (app_key, ReturnCode::SUCCESS)
+            ]
+        );
+
+        let store = Harness::load(medium);
+        assert_eq!(
+            store.key(kernel_key, Owner::Kernel),
+            Ok([0x11; 16].to_vec())
+        );
+        assert_eq!(store.key(app_key, APP), Ok([0x22; 32].to_vec()));
+    }
+
+    #[test]
+    fn keys_belong_to_their_owner() {
+        let store = Harness::load(medium());
+        let kernel_key = store.add(Owner::Kernel, &[0x11; 16]).unwrap();
+        let app_key = store.add(APP, &[0x22; 16]).unwrap();
+        store.run();
+
+        let table = store.table();
+        assert_eq!(store.key(kernel_key, APP), Err(ReturnCode::EINVAL));
+        assert_eq!(store.key(app_key, Owner::Kernel), Err(ReturnCode::EINVAL));
+        assert_eq!(store.key(app_key, OTHER_APP), Err(ReturnCode::EINVAL));
+        assert_eq!(
+            table.owned_slot(app_key, OTHER_APP),
+            Err(ReturnCode::EINVAL)
+        );
+        assert_eq!(
+            table.owned_slot(NUM_KEY_SLOTS, APP),
+            Err(ReturnCode::EINVAL)
+        );
+        assert_eq!(store.key(app_key, APP), Ok([0x22; 16].to_vec()));
+
+        for _ in 1..MAX_KEYS_PER_APP {
+            store.add(APP, &[0x33; 16]).unwrap();
+        }
+        assert_eq!(store.add(APP, &[0x44; 16]), Err(ReturnCode::ENOMEM));
+        assert!(store.add(OTHER_APP, &[0x44; 16]).is_ok());
+    }
+
+    #[test]
+    fn changes_during_a_write_are_written_after_it() {
+        let medium = medium();
+        let store = Harness::load(medium);
+        let index = store.add(APP, &[0x55; 16]).unwrap();
+        // Delete the key while it is being written, and overwrite it
+        assert_eq!(
+            store.table().change(index, Slot::default()),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(store.add(APP, &[0x66; 8]), Ok(index));
+        store.run();
+        assert_eq!(*store.written.borrow(), [(index, ReturnCode::SUCCESS)]);
+
+        let store = Harness::load(medium);
+        assert_eq!(store.key(index, APP), Ok([0x66; 8].to_vec()));
+        assert_eq!(
+            store.table().change(index, Slot::default()),
+            ReturnCode::SUCCESS
+        );
+        store.run();
+        let store = Harness::load(medium);
+        assert_eq!(store.key(index, APP), Err(ReturnCode::EINVAL));
+    }
+
+    #[test]
+    fn refused_writes_are_undone() {
+        let store = Harness::load(medium());
+        let kept = store.add(APP, &[0x77; 16]).unwrap();
+        store.run();
+
+        store.flash.broken_page.set(Some(0));
+        assert_eq!(store.add(APP, &[0x88; 16]), Err(ReturnCode::FAIL));
+        assert_eq!(store.key(kept + 1, APP), Err(ReturnCode::EINVAL));
+        assert_eq!(store.table().dirty.get(), 0);
+        // The storage kept the buffer and stays busy with it, but the table
+        // has its reserve buffer to try again with
+        assert_eq!(
+            store.table().change(kept, Slot::default()),
+            ReturnCode::EBUSY
+        );
+        assert_eq!(store.key(kept, APP), Ok([0x77; 16].to_vec()));
+        assert_eq!(*store.written.borrow(), [(kept, ReturnCode::SUCCESS)]);
+    }
+}
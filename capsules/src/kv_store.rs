@@ -0,0 +1,1210 @@
+//! Key-value store on flash.
+//!
+//! Values are stored as append-only records in the pages of a flash region,
+//! and the newest record of a key holds its value. A delete appends a record
+//! marking the key as deleted. Keys live in namespaces (see
+//! `hil::kv_store::Namespace`), so each user of the store has its own keys.
+//!
+//! Flash layout
+//! ------------
+//!
+//! Each used page starts with a header, followed by records:
+//!
+//! ```text
+//! page header:  magic (4) | crc (4) | sequence number (4) | length (2) | 0xffff
+//! record:       crc (4) | key length (1) | flags (1) | value length (2) |
+//!               namespace (8) | key | value
+//! ```
+//!
+//! All numbers are little endian. The page CRC covers the rest of the page
+//! header, and each record CRC covers the rest of its record. The length is
+//! the number of bytes of records in the page. Pages are ordered by their
+//! sequence numbers, and a page is only used if its header and all of its
+//! records are intact.
+//!
+//! Power-fail safety and wear leveling
+//! -----------------------------------
+//!
+//! Pages are only ever written once after an erase. To add a record to the
+//! newest (head) page, the store writes the head page with the new record to
+//! an erased page with a higher sequence number, and erases the old copy
+//! afterwards. If power fails during the write, the new copy fails its CRCs
+//! and is erased on the next mount, while the old copy is still intact. If it
+//! fails before the erase, both copies are valid and replaying the old one
+//! first gives the same values. Writes go to the next erased page after the
+//! last one written, so they rotate through the whole region.
+//!
+//! When fewer than two pages are erased, the store garbage collects the
+//! oldest page before adding a record: records that are newer than any other
+//! record of their key are copied to the head, and the page is erased.
+//!
+//! The region must have at least 3 pages, and at most `MAX_PAGES`. Pages up
+//! to 4 kB are supported.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static mut PAGEBUFFER: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//! static mut GC_PAGEBUFFER: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//!
+//! let kv_store = static_init!(
+//!     capsules::kv_store::KvStore<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::kv_store::KvStore::new(
+//!         &sam4l::flashcalw::FLASH_CONTROLLER,
+//!         0x70000,
+//!         0x4000,
+//!         &mut PAGEBUFFER,
+//!         &mut GC_PAGEBUFFER,
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! kernel::hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, kv_store);
+//! kv_store.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(kv_store)
+//!         .expect("no deferred call slot available for kv store"),
+//! );
+//! kv_store.mount();
+//! ```
+
+use crate::crc32::crc32;
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::flash::{self, Flash};
+use kernel::hil::kv_store::{KVStore, KVStoreClient, Namespace, NAMESPACE_LEN};
+use kernel::ReturnCode;
+
+/// Longest key in bytes.
+pub const MAX_KEY_LEN: usize = 32;
+/// Most pages a store can use.
+pub const MAX_PAGES: usize = 32;
+/// Size of the header of each page.
+pub const PAGE_HEADER_LEN: usize = 16;
+/// Size of the header of each record.
+pub const RECORD_HEADER_LEN: usize = 8 + NAMESPACE_LEN;
+
+/// Enough for 4 kB pages full of the smallest records.
+const MAX_RECORDS_PER_PAGE: usize = 256;
+const PAGE_MAGIC: u32 = 0x5356_4b54;
+const FLAG_DELETED: u8 = 0x01;
+/// Erased pages to keep for garbage collection.
+const RESERVED_PAGES: usize = 2;
+const ERASED_BYTE: u8 = 0xff;
+
+/// What a page of the region holds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PageState {
+    Erased,
+    /// Valid records, with the sequence number of the page
+    Used(u32),
+    /// Anything else, such as a page torn by a power failure
+    Dirty,
+}
+
+impl Default for PageState {
+    fn default() -> Self {
+        PageState::Dirty
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Operation {
+    Get,
+    Set,
+    Delete,
+}
+
+/// Store state keeps track of any in-progress asynchronous operations.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    Unmounted,
+    /// Reading each page while mounting.
+    Mount(usize),
+    /// Erasing a dirty page while mounting.
+    MountErase(usize),
+    Idle,
+    /// Looking for the key in a page, newest page first.
+    Lookup(usize),
+    /// Reading the head page to add a record to it.
+    ReadHead,
+    /// Reading the oldest page to garbage collect it.
+    GcReadOldest(usize),
+    /// Reading a page to find records superseding those of the oldest page.
+    GcScan(usize),
+    /// Writing the page image in `pagebuffer` to a page.
+    Write(usize),
+    /// Erasing the old copy of the head page.
+    EraseReplaced(usize),
+    /// Erasing the garbage collected page.
+    GcErase(usize),
+    /// Waiting for the deferred call to report the result.
+    Done,
+}
+
+/// A record in a page.
+struct Record<'b> {
+    namespace: &'b [u8],
+    key: &'b [u8],
+    value: &'b [u8],
+    deleted: bool,
+    /// Length of the whole record.
+    len: usize,
+}
+
+fn get_u16(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn get_u32(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+/// Parses the record at the start of `data`, if it is intact.
+fn parse_record(data: &[u8]) -> Option<Record> {
+    if data.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let key_len = data[4] as usize;
+    let value_len = get_u16(&data[6..8]) as usize;
+    let len = RECORD_HEADER_LEN + key_len + value_len;
+    if key_len == 0 || key_len > MAX_KEY_LEN || len > data.len() {
+        return None;
+    }
+    if get_u32(&data[0..4]) != crc32(&data[4..len]) {
+        return None;
+    }
+    let key_start = RECORD_HEADER_LEN;
+    let value_start = key_start + key_len;
+    Some(Record {
+        namespace: &data[8..8 + NAMESPACE_LEN],
+        key: &data[key_start..value_start],
+        value: &data[value_start..len],
+        deleted: data[5] & FLAG_DELETED != 0,
+        len: len,
+    })
+}
+
+/// Writes a record to the start of `buf` and returns its length.
+fn write_record(
+    buf: &mut [u8],
+    namespace: &Namespace,
+    key: &[u8],
+    value: &[u8],
+    deleted: bool,
+) -> usize {
+    let len = RECORD_HEADER_LEN + key.len() + value.len();
+    buf[4] = key.len() as u8;
+    buf[5] = if deleted { FLAG_DELETED } else { 0 };
+    buf[6..8].copy_from_slice(&(value.len() as u16).to_le_bytes());
+    buf[8..8 + NAMESPACE_LEN].copy_from_slice(&namespace.0);
+    buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + key.len()].copy_from_slice(key);
+    buf[RECORD_HEADER_LEN + key.len()..len].copy_from_slice(value);
+    let crc = crc32(&buf[4..len]);
+    buf[0..4].copy_from_slice(&crc.to_le_bytes());
+    len
+}
+
+fn write_page_header(page: &mut [u8], seq: u32, len: usize) {
+    page[0..4].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+    page[8..12].copy_from_slice(&seq.to_le_bytes());
+    page[12..14].copy_from_slice(&(len as u16).to_le_bytes());
+    page[14..16].copy_from_slice(&[ERASED_BYTE; 2]);
+    let crc = crc32(&page[8..PAGE_HEADER_LEN]);
+    page[4..8].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Iterator over the records of a page, with their offsets in the page.
+struct Records<'b> {
+    page: &'b [u8],
+    offset: usize,
+    end: usize,
+}
+
+impl<'b> Iterator for Records<'b> {
+    type Item = (usize, Record<'b>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.end {
+            return None;
+        }
+        let offset = self.offset;
+        parse_record(&self.page[offset..self.end]).map(|record| {
+            self.offset += record.len;
+            (offset, record)
+        })
+    }
+}
+
+/// Returns the sequence number and the length of the records of a page with
+/// an intact header and intact records.
+fn parse_page(page: &[u8]) -> Option<(u32, usize)> {
+    if get_u32(&page[0..4]) != PAGE_MAGIC || get_u32(&page[4..8]) != crc32(&page[8..16]) {
+        return None;
+    }
+    let seq = get_u32(&page[8..12]);
+    let len = get_u16(&page[12..14]) as usize;
+    if PAGE_HEADER_LEN + len > page.len() {
+        return None;
+    }
+    let mut end = PAGE_HEADER_LEN;
+    for (offset, record) in records(page, len) {
+        end = offset + record.len;
+    }
+    if end == PAGE_HEADER_LEN + len {
+        Some((seq, len))
+    } else {
+        None
+    }
+}
+
+/// The records of a page holding `len` bytes of records.
+fn records(page: &[u8], len: usize) -> Records {
+    Records {
+        page: page,
+        offset: PAGE_HEADER_LEN,
+        end: PAGE_HEADER_LEN + len,
+    }
+}
+
+/// The length of the records of a used page.
+fn records_len(page: &[u8]) -> usize {
+    get_u16(&page[12..14]) as usize
+}
+
+fn classify_page(page: &[u8]) -> PageState {
+    match parse_page(page) {
+        Some((seq, _)) => PageState::Used(seq),
+        None if page.iter().all(|b| *b == ERASED_BYTE) => PageState::Erased,
+        None => PageState::Dirty,
+    }
+}
+
+pub struct KvStore<'a, F: Flash + 'static> {
+    /// Flash interface.
+    driver: &'a F,
+    /// Number of the first page of the region in flash.
+    first_page: usize,
+    num_pages: usize,
+    /// Size of a flash page.
+    page_size: usize,
+    pages: [Cell<PageState>; MAX_PAGES],
+    /// Page holding the newest records, which new records are added to.
+    head_page: OptionalCell<usize>,
+    /// Length of the records in the head page.
+    head_len: Cell<usize>,
+    /// Sequence number for the next page written.
+    next_seq: Cell<u32>,
+    /// The page last written, after which the next write goes.
+    last_written: Cell<usize>,
+
+    /// Buffer for reading pages and building the next page to write.
+    pagebuffer: TakeCell<'static, F::Page>,
+    /// Buffer holding the page being garbage collected.
+    gc_pagebuffer: TakeCell<'static, F::Page>,
+    /// Length of the records of the page being built in `pagebuffer`.
+    image_len: Cell<usize>,
+    /// Whether the page being built has records not written yet.
+    image_dirty: Cell<bool>,
+    /// The copy of the head page the page being built replaces.
+    image_replaces: OptionalCell<usize>,
+
+    state: Cell<State>,
+    client: OptionalCell<&'a dyn KVStoreClient>,
+    /// Deferred caller for reporting results to the client.
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+
+    // The operation in progress
+    operation: Cell<Operation>,
+    namespace: Cell<Namespace>,
+    key: Cell<[u8; MAX_KEY_LEN]>,
+    key_len: Cell<usize>,
+    buffer: TakeCell<'static, [u8]>,
+    /// Length of the value to set, or of the value found.
+    length: Cell<usize>,
+    result: Cell<ReturnCode>,
+
+    // Garbage collection state
+    collecting: Cell<bool>,
+    gc_rounds: Cell<usize>,
+    /// The page being garbage collected.
+    gc_page: Cell<usize>,
+    /// The records of that page still to copy, as a bitmap.
+    gc_live: Cell<[u32; MAX_RECORDS_PER_PAGE / 32]>,
+}
+
+impl<'a, F: Flash + 'static> KvStore<'a, F> {
+    pub fn new(
+        driver: &'a F,
+        start_address: usize,
+        length: usize,
+        pagebuffer: &'static mut F::Page,
+        gc_pagebuffer: &'static mut F::Page,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> KvStore<'a, F> {
+        let page_size = pagebuffer.as_mut().len();
+        KvStore {
+            driver: driver,
+            first_page: start_address / page_size,
+            num_pages: length / page_size,
+            page_size: page_size,
+            pages: Default::default(),
+            head_page: OptionalCell::empty(),
+            head_len: Cell::new(0),
+            next_seq: Cell::new(0),
+            last_written: Cell::new(0),
+            pagebuffer: TakeCell::new(pagebuffer),
+            gc_pagebuffer: TakeCell::new(gc_pagebuffer),
+            image_len: Cell::new(0),
+            image_dirty: Cell::new(false),
+            image_replaces: OptionalCell::empty(),
+            state: Cell::new(State::Unmounted),
+            client: OptionalCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+            operation: Cell::new(Operation::Get),
+            namespace: Cell::new(Namespace::default()),
+            key: Cell::new([0; MAX_KEY_LEN]),
+            key_len: Cell::new(0),
+            buffer: TakeCell::empty(),
+            length: Cell::new(0),
+            result: Cell::new(ReturnCode::SUCCESS),
+            collecting: Cell::new(false),
+            gc_rounds: Cell::new(0),
+            gc_page: Cell::new(0),
+            gc_live: Cell::new([0; MAX_RECORDS_PER_PAGE / 32]),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Reads all pages to find the records and erases damaged pages.
+    /// Operations fail with EBUSY until this is done.
+    pub fn mount(&self) -> ReturnCode {
+        if self.state.get() != State::Unmounted {
+            return ReturnCode::EALREADY;
+        }
+        if self.num_pages < 3
+            || self.num_pages > MAX_PAGES
+            || self.page_size < 128
+            || self.page_size > 4096
+        {
+            return ReturnCode::EINVAL;
+        }
+        self.read_page(0, State::Mount(0))
+    }
+
+    fn read_page(&self, page: usize, state: State) -> ReturnCode {
+        let buffer = match state {
+            State::GcReadOldest(_) => &self.gc_pagebuffer,
+            _ => &self.pagebuffer,
+        };
+        buffer.take().map_or(ReturnCode::ERESERVE, |pagebuffer| {
+            match self.driver.read_page(self.first_page + page, pagebuffer) {
+                Ok(()) => {
+                    self.state.set(state);
+                    ReturnCode::SUCCESS
+                }
+                Err((error, pagebuffer)) => {
+                    buffer.replace(pagebuffer);
+                    error
+                }
+            }
+        })
+    }
+
+    fn erase_page(&self, page: usize, state: State) -> ReturnCode {
+        let result = self.driver.erase_page(self.first_page + page);
+        if result == ReturnCode::SUCCESS {
+            self.state.set(state);
+        }
+        result
+    }
+
+    /// Ends the operation, and reports `result` from a deferred call.
+    fn finish(&self, result: ReturnCode) {
+        self.collecting.set(false);
+        self.result.set(result);
+        self.state.set(State::Done);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+    }
+
+    /// Ends the operation if starting a flash operation failed.
+    fn check(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.finish(result);
+        }
+    }
+
+    fn mount_erase_next(&self, from: usize) {
+        let dirty = (from..self.num_pages).find(|page| self.pages[*page].get() == PageState::Dirty);
+        match dirty {
+            Some(page) => {
+                if self.erase_page(page, State::MountErase(page)) != ReturnCode::SUCCESS {
+                    self.state.set(State::Unmounted);
+                }
+            }
+            None => self.state.set(State::Idle),
+        }
+    }
+
+    fn seq_of(&self, page: usize) -> Option<u32> {
+        match self.pages[page].get() {
+            PageState::Used(seq) => Some(seq),
+            _ => None,
+        }
+    }
+
+    /// The used page with the highest sequence number below `below`.
+    fn newest_page_below(&self, below: Option<u32>) -> Option<usize> {
+        (0..self.num_pages)
+            .filter_map(|page| self.seq_of(page).map(|seq| (page, seq)))
+            .filter(|(_, seq)| below.map_or(true, |below| *seq < below))
+            .max_by_key(|(_, seq)| *seq)
+            .map(|(page, _)| page)
+    }
+
+    fn oldest_page(&self) -> Option<usize> {
+        (0..self.num_pages)
+            .filter_map(|page| self.seq_of(page).map(|seq| (page, seq)))
+            .min_by_key(|(_, seq)| *seq)
+            .map(|(page, _)| page)
+    }
+
+    fn erased_pages(&self) -> usize {
+        self.pages[..self.num_pages]
+            .iter()
+            .filter(|page| page.get() == PageState::Erased)
+            .count()
+    }
+
+    /// The next erased page after the last one written.
+    fn next_erased_page(&self) -> Option<usize> {
+        (1..=self.num_pages)
+            .map(|i| (self.last_written.get() + i) % self.num_pages)
+            .find(|page| self.pages[*page].get() == PageState::Erased)
+    }
+
+    fn is_current_key(&self, record: &Record) -> bool {
+        record.namespace == &self.namespace.get().0[..]
+            && record.key == &self.key.get()[..self.key_len.get()]
+    }
+
+    /// Checks that an operation can start, and records its key.
+    fn start_operation(
+        &self,
+        operation: Operation,
+        namespace: Namespace,
+        key: &[u8],
+    ) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return ReturnCode::EINVAL;
+        }
+        let mut key_copy = [0; MAX_KEY_LEN];
+        key_copy[..key.len()].copy_from_slice(key);
+        self.operation.set(operation);
+        self.namespace.set(namespace);
+        self.key.set(key_copy);
+        self.key_len.set(key.len());
+        self.gc_rounds.set(0);
+        ReturnCode::SUCCESS
+    }
+
+    // Lookup, for get and delete
+
+    fn start_lookup(&self) {
+        match self.newest_page_below(None) {
+            Some(page) => self.check(self.read_page(page, State::Lookup(page))),
+            None => self.finish(ReturnCode::ENOSUPPORT),
+        }
+    }
+
+    /// Looks for the key in the page just read, and moves on to the next
+    /// older page if it isn't there.
+    fn lookup_page(&self, page: usize) {
+        let operation = self.operation.get();
+        let found = self.pagebuffer.map_or(None, |pagebuffer| {
+            let data = pagebuffer.as_mut();
+            records(data, records_len(data))
+                .filter(|(_, record)| self.is_current_key(record))
+                .last()
+                .map(|(_, record)| {
+                    if record.deleted {
+                        ReturnCode::ENOSUPPORT
+                    } else if operation == Operation::Get {
+                        let len = record.value.len();
+                        self.length.set(len);
+                        self.buffer.map_or(ReturnCode::ERESERVE, |buffer| {
+                            let copied = cmp::min(len, buffer.len());
+                            buffer[..copied].copy_from_slice(&record.value[..copied]);
+                            if copied < len {
+                                ReturnCode::ESIZE
+                            } else {
+                                ReturnCode::SUCCESS
+                            }
+                        })
+                    } else {
+                        ReturnCode::SUCCESS
+                    }
+                })
+        });
+
+        match found {
+            Some(ReturnCode::SUCCESS) if operation == Operation::Delete => self.start_set(),
+            Some(result) => self.finish(result),
+            None => match self.newest_page_below(self.seq_of(page)) {
+                Some(next) => self.check(self.read_page(next, State::Lookup(next))),
+                None => self.finish(ReturnCode::ENOSUPPORT),
+            },
+        }
+    }
+
+    // Adding records, for set and delete
+
+    fn start_set(&self) {
+        if self.erased_pages() < RESERVED_PAGES {
+            self.start_gc();
+        } else {
+            let value_len = match self.operation.get() {
+                Operation::Set => self.length.get(),
+                Operation::Get | Operation::Delete => 0,
+            };
+            self.start_image(RECORD_HEADER_LEN + self.key_len.get() + value_len);
+        }
+    }
+
+    /// Starts building a page in `pagebuffer` with room for `len` bytes of
+    /// records, by reading the head page if it has room, or by starting an
+    /// empty page.
+    fn start_image(&self, len: usize) {
+        let head = self.head_page.and_then(|head| {
+            if PAGE_HEADER_LEN + self.head_len.get() + len <= self.page_size {
+                Some(head)
+            } else {
+                None
+            }
+        });
+        match head {
+            Some(head) => self.check(self.read_page(head, State::ReadHead)),
+            None => {
+                self.new_image();
+                self.image_ready();
+            }
+        }
+    }
+
+    fn new_image(&self) {
+        self.pagebuffer.map(|pagebuffer| {
+            for b in pagebuffer.as_mut().iter_mut() {
+                *b = ERASED_BYTE;
+            }
+        });
+        self.image_len.set(0);
+        self.image_dirty.set(false);
+        self.image_replaces.clear();
+    }
+
+    /// Adds records to the page in `pagebuffer`, and writes it.
+    fn image_ready(&self) {
+        if self.collecting.get() {
+            return self.gc_copy();
+        }
+
+        let namespace = self.namespace.get();
+        let key = self.key.get();
+        let key = &key[..self.key_len.get()];
+        let length = self.length.get();
+        let image_len = self.image_len.get();
+        let len = self.pagebuffer.map_or(0, |pagebuffer| {
+            let record = &mut pagebuffer.as_mut()[PAGE_HEADER_LEN + image_len..];
+            match self.operation.get() {
+                Operation::Delete => write_record(record, &namespace, key, &[], true),
+                _ => self.buffer.map_or(0, |value| {
+                    write_record(record, &namespace, key, &value[..length], false)
+                }),
+            }
+        });
+        self.image_len.set(image_len + len);
+        self.image_dirty.set(true);
+        self.write_image();
+    }
+
+    /// Writes the page built in `pagebuffer` to the next erased page.
+    fn write_image(&self) {
+        let page = match self.next_erased_page() {
+            Some(page) => page,
+            None => return self.finish(ReturnCode::ENOMEM),
+        };
+        let result = self
+            .pagebuffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, |pagebuffer| {
+                write_page_header(
+                    pagebuffer.as_mut(),
+                    self.next_seq.get(),
+                    self.image_len.get(),
+                );
+                match self.driver.write_page(self.first_page + page, pagebuffer) {
+                    Ok(()) => {
+                        self.state.set(State::Write(page));
+                        ReturnCode::SUCCESS
+                    }
+                    Err((error, pagebuffer)) => {
+                        self.pagebuffer.replace(pagebuffer);
+                        error
+                    }
+                }
+            });
+        self.check(result);
+    }
+
+    /// Carries on once the page built has been written and the copy of the
+    /// head page it replaces erased.
+    fn image_written(&self) {
+        if !self.collecting.get() {
+            self.finish(ReturnCode::SUCCESS);
+        } else if self.gc_live.get().iter().any(|bits| *bits != 0) {
+            // The records left did not fit in the head page
+            self.new_image();
+            self.gc_copy();
+        } else {
+            let gc_page = self.gc_page.get();
+            self.check(self.erase_page(gc_page, State::GcErase(gc_page)));
+        }
+    }
+
+    // Garbage collection
+
+    fn start_gc(&self) {
+        let oldest = match self.oldest_page() {
+            Some(oldest) if !self.head_page.contains(&oldest) => oldest,
+            _ => return self.finish(ReturnCode::ENOMEM),
+        };
+        if self.gc_rounds.get() >= self.num_pages {
+            return self.finish(ReturnCode::ENOMEM);
+        }
+        self.gc_rounds.set(self.gc_rounds.get() + 1);
+        self.collecting.set(true);
+        self.gc_page.set(oldest);
+        self.check(self.read_page(oldest, State::GcReadOldest(oldest)));
+    }
+
+    /// Marks the records of the page being collected that are not superseded
+    /// within the page. Deleted keys need no record once the oldest page is
+    /// gone.
+    fn gc_mark(&self) {
+        let mut live = [0; MAX_RECORDS_PER_PAGE / 32];
+        self.gc_pagebuffer.map(|gc| {
+            let gc = gc.as_mut();
+            let len = records_len(gc);
+            for (i, (offset, record)) in records(gc, len).enumerate() {
+                let superseded = records(gc, len)
+                    .skip_while(|(later, _)| *later <= offset)
+                    .any(|(_, later)| {
+                        later.namespace == record.namespace && later.key == record.key
+                    });
+                if !record.deleted && !superseded {
+                    live[i / 32] |= 1 << (i % 32);
+                }
+            }
+        });
+        self.gc_live.set(live);
+    }
+
+    /// Unmarks the records of the page being collected that have newer
+    /// records in the page in `pagebuffer`.
+    fn gc_sweep(&self) {
+        let mut live = self.gc_live.get();
+        self.gc_pagebuffer.map(|gc| {
+            self.pagebuffer.map(|pagebuffer| {
+                let gc = gc.as_mut();
+                let page = pagebuffer.as_mut();
+                for (i, (_, record)) in records(gc, records_len(gc)).enumerate() {
+                    if records(page, records_len(page)).any(|(_, newer)| {
+                        newer.namespace == record.namespace && newer.key == record.key
+                    }) {
+                        live[i / 32] &= !(1 << (i % 32));
+                    }
+                }
+            });
+        });
+        self.gc_live.set(live);
+    }
+
+    /// Reads the next used page after `after` to find newer records, or
+    /// starts copying the live records once all pages are read.
+    fn gc_scan_next(&self, after: Option<usize>) {
+        let gc_page = self.gc_page.get();
+        let from = after.map_or(0, |page| page + 1);
+        let next =
+            (from..self.num_pages).find(|page| *page != gc_page && self.seq_of(*page).is_some());
+        match next {
+            Some(page) => self.check(self.read_page(page, State::GcScan(page))),
+            None if self.gc_live.get().iter().all(|bits| *bits == 0) => {
+                self.check(self.erase_page(gc_page, State::GcErase(gc_page)));
+            }
+            None => {
+                // Make room for at least the longest record
+                let len = self.gc_pagebuffer.map_or(0, |gc| {
+                    let gc = gc.as_mut();
+                    records(gc, records_len(gc))
+                        .map(|(_, record)| record.len)
+                        .max()
+                        .unwrap_or(0)
+                });
+                self.start_image(len);
+            }
+        }
+    }
+
+    /// Copies the live records of the page being collected to the page being
+    /// built, and writes it.
+    fn gc_copy(&self) {
+        let mut live = self.gc_live.get();
+        let mut image_len = self.image_len.get();
+        self.gc_pagebuffer.map(|gc| {
+            self.pagebuffer.map(|pagebuffer| {
+                let gc = gc.as_mut();
+                let image = pagebuffer.as_mut();
+                for (i, (offset, record)) in records(gc, records_len(gc)).enumerate() {
+                    if live[i / 32] & (1 << (i % 32)) == 0 {
+                        continue;
+                    }
+                    let start = PAGE_HEADER_LEN + image_len;
+                    if start + record.len > image.len() {
+                        break;
+                    }
+                    image[start..start + record.len]
+                        .copy_from_slice(&gc[offset..offset + record.len]);
+                    image_len += record.len;
+                    live[i / 32] &= !(1 << (i % 32));
+                }
+            });
+        });
+        if image_len != self.image_len.get() {
+            self.image_len.set(image_len);
+            self.image_dirty.set(true);
+        }
+        self.gc_live.set(live);
+
+        if self.image_dirty.get() {
+            self.write_image();
+        } else {
+            self.finish(ReturnCode::ENOMEM);
+        }
+    }
+}
+
+impl<'a, F: Flash + 'static> flash::Client<F> for KvStore<'a, F> {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, error: flash::Error) {
+        let state = self.state.get();
+        if let State::GcReadOldest(_) = state {
+            self.gc_pagebuffer.replace(pagebuffer);
+        } else {
+            self.pagebuffer.replace(pagebuffer);
+        }
+        if error != flash::Error::CommandComplete {
+            if let State::Mount(_) = state {
+                // Without all pages the records are unknown
+                self.state.set(State::Unmounted);
+            } else {
+                self.finish(ReturnCode::FAIL);
+            }
+            return;
+        }
+
+        match state {
+            State::Mount(page) => {
+                let (page_state, len) =
+                    self.pagebuffer.map_or((PageState::Dirty, 0), |pagebuffer| {
+                        let data = pagebuffer.as_mut();
+                        (classify_page(data), records_len(data))
+                    });
+                self.pages[page].set(page_state);
+                if let PageState::Used(seq) = page_state {
+                    if self.head_page.is_none() || seq >= self.next_seq.get() {
+                        self.next_seq.set(seq.wrapping_add(1));
+                        self.head_page.set(page);
+                        self.head_len.set(len);
+                        self.last_written.set(page);
+                    }
+                }
+                if page + 1 < self.num_pages {
+                    if self.read_page(page + 1, State::Mount(page + 1)) != ReturnCode::SUCCESS {
+                        self.state.set(State::Unmounted);
+                    }
+                } else {
+                    self.mount_erase_next(0);
+                }
+            }
+            State::Lookup(page) => self.lookup_page(page),
+            State::ReadHead => {
+                self.image_len.set(self.head_len.get());
+                self.image_dirty.set(false);
+                self.image_replaces.insert(self.head_page.and_then(Some));
+                self.image_ready();
+            }
+            State::GcReadOldest(_) => {
+                self.gc_mark();
+                self.gc_scan_next(None);
+            }
+            State::GcScan(page) => {
+                self.gc_sweep();
+                self.gc_scan_next(Some(page));
+            }
+            _ => {}
+        }
+    }
+
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: flash::Error) {
+        self.pagebuffer.replace(pagebuffer);
+        if let State::Write(page) = self.state.get() {
+            if error != flash::Error::CommandComplete {
+                // The page may be partly written
+                self.pages[page].set(PageState::Dirty);
+                return self.finish(ReturnCode::FAIL);
+            }
+            self.pages[page].set(PageState::Used(self.next_seq.get()));
+            self.next_seq.set(self.next_seq.get().wrapping_add(1));
+            self.head_page.set(page);
+            self.head_len.set(self.image_len.get());
+            self.last_written.set(page);
+            self.image_dirty.set(false);
+            match self.image_replaces.take() {
+                Some(old) => self.check(self.erase_page(old, State::EraseReplaced(old))),
+                None => self.image_written(),
+            }
+        }
+    }
+
+    fn erase_complete(&self, error: flash::Error) {
+        let page_state = if error == flash::Error::CommandComplete {
+            PageState::Erased
+        } else {
+            PageState::Dirty
+        };
+        match self.state.get() {
+            State::MountErase(page) => {
+                self.pages[page].set(page_state);
+                self.mount_erase_next(page + 1);
+            }
+            State::EraseReplaced(page) => {
+                self.pages[page].set(page_state);
+                self.image_written();
+            }
+            State::GcErase(page) => {
+                self.pages[page].set(page_state);
+                self.collecting.set(false);
+                self.start_set();
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<'a, F: Flash + 'static> KVStore<'a> for KvStore<'a, F> {
+    fn set_client(&self, client: &'a dyn KVStoreClient) {
+        self.client.set(client);
+    }
+
+    fn get(
+        &self,
+        namespace: Namespace,
+        key: &[u8],
+        value: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+        let result = self.start_operation(Operation::Get, namespace, key);
+        if result != ReturnCode::SUCCESS {
+            return Err((result, Some(value)));
+        }
+        self.buffer.replace(value);
+        self.length.set(0);
+        self.start_lookup();
+        Ok(())
+    }
+
+    fn set(
+        &self,
+        namespace: Namespace,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+        if length > value.len()
+            || PAGE_HEADER_LEN + RECORD_HEADER_LEN + key.len() + length > self.page_size
+        {
+            return Err((ReturnCode::ESIZE, Some(value)));
+        }
+        let result = self.start_operation(Operation::Set, namespace, key);
+        if result != ReturnCode::SUCCESS {
+            return Err((result, Some(value)));
+        }
+        self.buffer.replace(value);
+        self.length.set(length);
+        self.start_set();
+        Ok(())
+    }
+
+    fn delete(&self, namespace: Namespace, key: &[u8]) -> ReturnCode {
+        let result = self.start_operation(Operation::Delete, namespace, key);
+        if result == ReturnCode::SUCCESS {
+            self.length.set(0);
+            self.start_lookup();
+        }
+        result
+    }
+}
+
+impl<'a, F: Flash + 'static> DynamicDeferredCallClient for KvStore<'a, F> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if self.state.get() != State::Done {
+            return;
+        }
+        self.state.set(State::Idle);
+        let result = self.result.get();
+        let length = self.length.get();
+        match self.operation.get() {
+            Operation::Get => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.get_complete(result, buffer, length));
+                });
+            }
+            Operation::Set => {
+                self.buffer.take().map(|buffer| {
+                    self.client
+                        .map(move |client| client.set_complete(result, buffer));
+                });
+            }
+            Operation::Delete => {
+                self.client.map(|client| client.delete_complete(result));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{self, Medium, Page, SimFlash, WriteMode, PAGE_SIZE};
+    use std::vec::Vec;
+
+    const TEST_PAGES: usize = 6;
+
+    struct TestClient {
+        result: Cell<Option<ReturnCode>>,
+        value: TakeCell<'static, [u8]>,
+        length: Cell<usize>,
+    }
+
+    impl KVStoreClient for TestClient {
+        fn get_complete(&self, result: ReturnCode, value: &'static mut [u8], length: usize) {
+            self.result.set(Some(result));
+            self.value.replace(value);
+            self.length.set(length);
+        }
+
+        fn set_complete(&self, result: ReturnCode, value: &'static mut [u8]) {
+            self.result.set(Some(result));
+            self.value.replace(value);
+        }
+
+        fn delete_complete(&self, result: ReturnCode) {
+            self.result.set(Some(result));
+        }
+    }
+
+    struct Harness {
+        flash: &'static SimFlash,
+        store: &'static KvStore<'static, SimFlash>,
+        client: &'static TestClient,
+        handle: DeferredCallHandle,
+    }
+
+    const APP_A: Namespace = Namespace([1; NAMESPACE_LEN]);
+    const APP_B: Namespace = Namespace([2; NAMESPACE_LEN]);
+
+    impl Harness {
+        fn mount(medium: &'static Medium) -> Harness {
+            let flash = SimFlash::new(medium, WriteMode::Replace);
+            let deferred_caller = test_util::deferred_caller(1);
+            let store = test_util::leak(KvStore::new(
+                flash,
+                0,
+                TEST_PAGES * PAGE_SIZE,
+                test_util::leak(Page::default()),
+                test_util::leak(Page::default()),
+                deferred_caller,
+            ));
+            let client: &'static TestClient = test_util::leak(TestClient {
+                result: Cell::new(None),
+                value: TakeCell::new(test_util::leak([0u8; 64])),
+                length: Cell::new(0),
+            });
+            flash.set_client(store);
+            store.set_client(client);
+            let handle = deferred_caller.register(store).unwrap();
+            store.initialize_callback_handle(handle);
+
+            let harness = Harness {
+                flash: flash,
+                store: store,
+                client: client,
+                handle: handle,
+            };
+            assert_eq!(store.mount(), ReturnCode::SUCCESS);
+            harness.run();
+            assert_eq!(store.state.get(), State::Idle);
+            harness
+        }
+
+        fn run(&self) {
+            while self.flash.step() {}
+            self.store.call(self.handle);
+        }
+
+        fn result(&self) -> Option<ReturnCode> {
+            self.run();
+            self.client.result.take()
+        }
+
+        fn set(&self, namespace: Namespace, key: &[u8], value: &[u8]) -> Option<ReturnCode> {
+            let buffer = self.client.value.take().unwrap();
+            buffer[..value.len()].copy_from_slice(value);
+            if let Err((result, buffer)) = self.store.set(namespace, key, buffer, value.len()) {
+                self.client.value.put(buffer);
+                return Some(result);
+            }
+            self.result()
+        }
+
+        fn get(&self, namespace: Namespace, key: &[u8]) -> Result<Vec<u8>, ReturnCode> {
+            let buffer = self.client.value.take().unwrap();
+            if let Err((result, buffer)) = self.store.get(namespace, key, buffer) {
+                self.client.value.put(buffer);
+                return Err(result);
+            }
+            match self.result() {
+                Some(ReturnCode::SUCCESS) => {
+                    let len = self.client.length.get();
+                    Ok(self
+                        .client
+                        .value
+                        .map(|value| value[..len].to_vec())
+                        .unwrap())
+                }
+                result => Err(result.unwrap_or(ReturnCode::FAIL)),
+            }
+        }
+
+        fn delete(&self, namespace: Namespace, key: &[u8]) -> Option<ReturnCode> {
+            let result = self.store.delete(namespace, key);
+            if result != ReturnCode::SUCCESS {
+                return Some(result);
+            }
+            self.result()
+        }
+    }
+
+    #[test]
+    fn set_get_delete() {
+        let kv = Harness::mount(Medium::new(TEST_PAGES));
+        assert_eq!(kv.get(APP_A, b"name"), Err(ReturnCode::ENOSUPPORT));
+        assert_eq!(kv.set(APP_A, b"name", b"tock"), Some(ReturnCode::SUCCESS));
+        assert_eq!(kv.set(APP_B, b"name", b"other"), Some(ReturnCode::SUCCESS));
+        assert_eq!(kv.get(APP_A, b"name"), Ok(b"tock".to_vec()));
+        assert_eq!(kv.get(APP_B, b"name"), Ok(b"other".to_vec()));
+
+        assert_eq!(
+            kv.set(APP_A, b"name", b"tock os"),
+            Some(ReturnCode::SUCCESS)
+        );
+        assert_eq!(kv.get(APP_A, b"name"), Ok(b"tock os".to_vec()));
+
+        assert_eq!(kv.delete(APP_A, b"name"), Some(ReturnCode::SUCCESS));
+        assert_eq!(kv.get(APP_A, b"name"), Err(ReturnCode::ENOSUPPORT));
+        assert_eq!(kv.delete(APP_A, b"name"), Some(ReturnCode::ENOSUPPORT));
+        assert_eq!(kv.get(APP_B, b"name"), Ok(b"other".to_vec()));
+
+        assert_eq!(kv.set(APP_A, b"", b"x"), Some(ReturnCode::EINVAL));
+        assert_eq!(kv.set(APP_A, b"big", &[0; 64]), Some(ReturnCode::SUCCESS));
+        assert_eq!(kv.set(APP_A, &[b'k'; 33], b"x"), Some(ReturnCode::EINVAL));
+
+        // Values persist across a remount
+        let kv = Harness::mount(kv.flash.medium);
+        assert_eq!(kv.get(APP_B, b"name"), Ok(b"other".to_vec()));
+        assert_eq!(kv.get(APP_A, b"name"), Err(ReturnCode::ENOSUPPORT));
+        assert_eq!(kv.get(APP_A, b"big"), Ok([0; 64].to_vec()));
+    }
+
+    #[test]
+    fn garbage_collection() {
+        let kv = Harness::mount(Medium::new(TEST_PAGES));
+        assert_eq!(
+            kv.set(APP_B, b"kept", b"forever"),
+            Some(ReturnCode::SUCCESS)
+        );
+        // Many times more data than fits in the region
+        for i in 0..200u32 {
+            let value = [i as u8; 40];
+            assert_eq!(kv.set(APP_A, b"counter", &value), Some(ReturnCode::SUCCESS));
+            assert_eq!(kv.get(APP_A, b"counter"), Ok(value.to_vec()));
+        }
+        assert_eq!(kv.get(APP_B, b"kept"), Ok(b"forever".to_vec()));
+
+        let kv = Harness::mount(kv.flash.medium);
+        assert_eq!(kv.get(APP_B, b"kept"), Ok(b"forever".to_vec()));
+        assert_eq!(kv.get(APP_A, b"counter"), Ok([199; 40].to_vec()));
+    }
+
+    #[test]
+    fn power_loss_during_write() {
+        // Lose power at each write of a sequence of sets in turn
+        for fail_at in 0..40 {
+            let kv = Harness::mount(Medium::new(TEST_PAGES));
+            kv.flash.writes_left.set(Some(fail_at));
+            let mut written = None;
+            for i in 0..20u8 {
+                if kv.set(APP_A, b"key", &[i; 30]) == Some(ReturnCode::SUCCESS) {
+                    written = Some(i);
+                } else {
+                    break;
+                }
+                if kv.set(APP_B, &[i + 1], &[i; 20]) != Some(ReturnCode::SUCCESS) {
+                    break;
+                }
+            }
+
+            // The last completed set survives, or the one that was cut off
+            let kv = Harness::mount(kv.flash.medium);
+            let value = kv.get(APP_A, b"key");
+            match written {
+                Some(i) => assert!(
+                    value == Ok([i; 30].to_vec()) || value == Ok([i + 1; 30].to_vec()),
+                    "failed at write {}",
+                    fail_at
+                ),
+                None => assert!(value.is_err() || value == Ok([0; 30].to_vec())),
+            }
+            for i in 0..written.unwrap_or(0) {
+                assert_eq!(kv.get(APP_B, &[i + 1]), Ok([i; 20].to_vec()));
+            }
+
+            // The store still works
+            assert_eq!(kv.set(APP_A, b"key", b"after"), Some(ReturnCode::SUCCESS));
+            assert_eq!(kv.get(APP_A, b"key"), Ok(b"after".to_vec()));
+        }
+    }
+}
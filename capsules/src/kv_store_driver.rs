@@ -0,0 +1,363 @@
+//! Provides userspace with access to a key-value store.
+//!
+//! Each process gets its own namespace in the store, identified by a hash of
+//! the process name. A process keeps its values across reboots and updates,
+//! and processes with the same name share values. The name is not
+//! authenticated; see `process_identity`. The store is any
+//! `hil::kv_store::KVStore`, such as `kv_store::KvStore` on flash.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
+//! let kv_store_driver = static_init!(
+//!     capsules::kv_store_driver::KvStoreDriver<'static, ProcessMgmtCap>,
+//!     capsules::kv_store_driver::KvStoreDriver::new(
+//!         kv_store,
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         board_kernel.create_grant(&grant_cap),
+//!         &mut capsules::kv_store_driver::BUFFER
+//!     )
+//! );
+//! hil::kv_store::KVStore::set_client(kv_store, kv_store_driver);
+//! ```
+//!
+//! The system call interface is documented in doc/syscalls/50003_kv_store.md.
+
+use crate::driver;
+use crate::process_identity;
+use crate::sha::SHA256_DIGEST_LEN;
+use core::cmp;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::kv_store::{KVStore, KVStoreClient, Namespace, NAMESPACE_LEN};
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, Kernel, ReturnCode, Shared};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::KvStore as usize;
+
+/// Longest key a process can use.
+pub const MAX_KEY_LEN: usize = 32;
+/// Longest value a process can store.
+pub const MAX_VALUE_LEN: usize = 192;
+
+/// Buffer for values passed to and from the store.
+pub static mut BUFFER: [u8; MAX_VALUE_LEN] = [0; MAX_VALUE_LEN];
+
+#[derive(Copy, Clone)]
+enum Operation {
+    /// Get the value of a key of the given length
+    Get(usize),
+    /// Set a key of the given length to a value of the given length
+    Set(usize, usize),
+    /// Delete a key of the given length
+    Delete(usize),
+}
+
+/// The namespace of the process with `identity`. Processes without one are
+/// refused.
+fn namespace_for(identity: Option<[u8; SHA256_DIGEST_LEN]>) -> Result<Namespace, ReturnCode> {
+    let digest = identity.ok_or(ReturnCode::ENOSUPPORT)?;
+    let mut namespace = Namespace::default();
+    namespace.0.copy_from_slice(&digest[..NAMESPACE_LEN]);
+    Ok(namespace)
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    value: Option<AppSlice<Shared, u8>>,
+    /// The operation waiting to run or running.
+    waiting: Option<Operation>,
+}
+
+pub struct KvStoreDriver<'a, C: ProcessManagementCapability> {
+    store: &'a dyn KVStore<'a>,
+    kernel: &'static Kernel,
+    capability: C,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, C: ProcessManagementCapability> KvStoreDriver<'a, C> {
+    pub fn new(
+        store: &'a dyn KVStore<'a>,
+        kernel: &'static Kernel,
+        capability: C,
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+    ) -> KvStoreDriver<'a, C> {
+        KvStoreDriver {
+            store: store,
+            kernel: kernel,
+            capability: capability,
+            apps: grant,
+            serving_app: OptionalCell::empty(),
+            buffer: TakeCell::new(buffer),
+        }
+    }
+
+    /// The namespace of a process: the start of its identity.
+    fn namespace_of(&self, appid: AppId) -> Result<Namespace, ReturnCode> {
+        namespace_for(process_identity::name_digest(
+            self.kernel,
+            &self.capability,
+            appid,
+        ))
+    }
+
+    /// Starts an operation for `app`, which must be waiting.
+    fn start_operation(&self, appid: AppId, app: &mut App, operation: Operation) -> ReturnCode {
+        let key_len = match operation {
+            Operation::Get(key_len) | Operation::Set(key_len, _) | Operation::Delete(key_len) => {
+                key_len
+            }
+        };
+        let mut key = [0; MAX_KEY_LEN];
+        match app.key.as_ref() {
+            Some(slice) if key_len <= slice.len() => {
+                key[..key_len].copy_from_slice(&slice.as_ref()[..key_len]);
+            }
+            _ => return ReturnCode::EINVAL,
+        }
+        let key = &key[..key_len];
+        let namespace = match self.namespace_of(appid) {
+            Ok(namespace) => namespace,
+            Err(result) => return result,
+        };
+
+        match operation {
+            Operation::Get(_) => {
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    match self.store.get(namespace, key, buffer) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((result, buffer)) => {
+                            buffer.map(|buffer| self.buffer.replace(buffer));
+                            result
+                        }
+                    }
+                })
+            }
+            Operation::Set(_, value_len) => {
+                self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                    let copied = app.value.as_ref().map_or(false, |value| {
+                        if value_len <= value.len() && value_len <= buffer.len() {
+                            buffer[..value_len].copy_from_slice(&value.as_ref()[..value_len]);
+                            true
+                        } else {
+                            false
+                        }
+                    });
+                    let result = if copied {
+                        self.store.set(namespace, key, buffer, value_len)
+                    } else {
+                        Err((ReturnCode::EINVAL, Some(buffer)))
+                    };
+                    match result {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((result, buffer)) => {
+                            buffer.map(|buffer| self.buffer.replace(buffer));
+                            result
+                        }
+                    }
+                })
+            }
+            Operation::Delete(_) => self.store.delete(namespace, key),
+        }
+    }
+
+    fn serve_waiting_apps(&self) {
+        if self.serving_app.is_some() {
+            // The store is busy with a request
+            return;
+        }
+
+        // Find a waiting app and start its operation
+        for app in self.apps.iter() {
+            let started = app.enter(|app, _| {
+                app.waiting.map_or(false, |operation| {
+                    let result = self.start_operation(app.appid(), app, operation);
+                    if result == ReturnCode::SUCCESS {
+                        self.serving_app.set(app.appid());
+                        true
+                    } else {
+                        // The app's request failed
+                        app.waiting = None;
+                        if let Some(mut callback) = app.callback {
+                            callback.schedule(From::from(result), 0, 0);
+                        }
+                        false
+                    }
+                })
+            });
+            if started {
+                break;
+            }
+        }
+    }
+
+    /// Reports the result of the running operation to its app, copying
+    /// `value` to the app's value buffer.
+    fn operation_complete(&self, result: ReturnCode, value: &[u8], length: usize) {
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.waiting = None;
+                if let Some(slice) = app.value.as_mut() {
+                    let len = cmp::min(value.len(), slice.len());
+                    slice.as_mut()[..len].copy_from_slice(&value[..len]);
+                }
+                if let Some(mut callback) = app.callback {
+                    callback.schedule(From::from(result), length, 0);
+                }
+            });
+        });
+        self.serve_waiting_apps();
+    }
+}
+
+impl<C: ProcessManagementCapability> KVStoreClient for KvStoreDriver<'_, C> {
+    fn get_complete(&self, result: ReturnCode, value: &'static mut [u8], length: usize) {
+        let copied = match result {
+            ReturnCode::SUCCESS | ReturnCode::ESIZE => cmp::min(length, value.len()),
+            _ => 0,
+        };
+        self.operation_complete(result, &value[..copied], length);
+        self.buffer.replace(value);
+    }
+
+    fn set_complete(&self, result: ReturnCode, value: &'static mut [u8]) {
+        self.buffer.replace(value);
+        self.operation_complete(result, &[], 0);
+    }
+
+    fn delete_complete(&self, result: ReturnCode) {
+        self.operation_complete(result, &[], 0);
+    }
+}
+
+impl<C: ProcessManagementCapability> Driver for KvStoreDriver<'_, C> {
+    /// Setup buffers for keys and values.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The key.
+    /// - `1`: The value to set, or the buffer for the value read.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.key = slice,
+                    1 => app.value = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Subscribe to key-value store events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Subscribe to the completion of operations. The callback
+    ///        signature is `fn(result: u32, length: usize)`, where `length`
+    ///        is the length of the value for a get.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // default
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Use the values of the process.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Get the value of the key made of the first `data1` bytes of the
+    ///        key buffer, into the value buffer.
+    /// - `2`: Set the key of `data1` bytes to the first `data2` bytes of the
+    ///        value buffer.
+    /// - `3`: Delete the key of `data1` bytes.
+    ///
+    /// Keys are at most `MAX_KEY_LEN` bytes long and values at most
+    /// `MAX_VALUE_LEN` bytes long. A process can have one operation in
+    /// progress at a time.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        let operation = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => Operation::Get(data1),
+            2 => Operation::Set(data1, data2),
+            3 => Operation::Delete(data1),
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+        if data1 == 0 || data1 > MAX_KEY_LEN {
+            return ReturnCode::EINVAL;
+        }
+        if let Operation::Set(_, value_len) = operation {
+            if value_len > MAX_VALUE_LEN {
+                return ReturnCode::ESIZE;
+            }
+        }
+
+        let result = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.waiting.is_some() {
+                    ReturnCode::EBUSY
+                } else {
+                    app.waiting = Some(operation);
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+        if result == ReturnCode::SUCCESS {
+            self.serve_waiting_apps();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::process_identity::digest_of_name;
+
+    #[test]
+    fn processes_without_a_name_are_refused() {
+        assert_eq!(
+            namespace_for(digest_of_name("")),
+            Err(ReturnCode::ENOSUPPORT)
+        );
+
+        let blink = namespace_for(digest_of_name("blink")).unwrap();
+        assert_eq!(namespace_for(digest_of_name("blink")), Ok(blink));
+        assert_ne!(namespace_for(digest_of_name("sensors")), Ok(blink));
+    }
+}
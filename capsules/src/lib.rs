@@ -3,40 +3,58 @@
 #![no_std]
 
 pub mod test;
+#[cfg(test)]
+mod test_util;
 
 #[macro_use]
 pub mod net;
 
 pub mod adc;
+pub mod aes;
 pub mod aes_ccm;
+pub mod aes_gcm;
 pub mod alarm;
 pub mod ambient_light;
 pub mod analog_comparator;
 pub mod analog_sensor;
 pub mod app_flash_driver;
+pub mod ble;
 pub mod ble_advertising_driver;
 pub mod button;
 pub mod buzzer_driver;
 pub mod console;
 pub mod crc;
+pub mod crc32;
 pub mod dac;
 pub mod debug_process_restart;
+pub mod drbg;
 pub mod driver;
+pub mod encrypted_storage;
+pub mod fat;
+pub mod fat_driver;
+pub mod firmware_update;
+pub mod firmware_update_driver;
 pub mod fm25cl;
 pub mod ft6x06;
+pub mod ftl;
 pub mod fxos8700cq;
 pub mod gpio;
 pub mod gpio_async;
 pub mod hd44780;
+pub mod hkdf;
 pub mod hmac;
 pub mod humidity;
 pub mod i2c_master;
 pub mod i2c_master_slave_driver;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod key_store;
+pub mod kv_store;
+pub mod kv_store_driver;
 pub mod l3gd20;
 pub mod led;
 pub mod log;
+pub mod log_driver;
 pub mod low_level_debug;
 pub mod lps25hb;
 pub mod lsm303dlhc;
@@ -52,13 +70,17 @@ pub mod nrf51822_serialization;
 pub mod panic_button;
 pub mod pca9544a;
 pub mod process_console;
+pub mod process_identity;
+pub mod ram_disk;
 pub mod rf233;
 pub mod rf233_const;
 pub mod rng;
 pub mod screen;
 pub mod sdcard;
 pub mod segger_rtt;
+pub mod sha;
 pub mod si7021;
+pub mod signature;
 pub mod spi_controller;
 pub mod spi_peripheral;
 pub mod st7735;
@@ -66,6 +88,7 @@ pub mod temperature;
 pub mod touch;
 pub mod tsl2561;
 pub mod usb;
+pub mod virtual_aes;
 pub mod virtual_alarm;
 pub mod virtual_digest;
 pub mod virtual_flash;
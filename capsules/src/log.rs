@@ -20,7 +20,18 @@
 //! written to a 4 page log, then page #0 will now have an offset of 2048). Thus, the ID of an
 //! entry can be calculated by taking the offset of the page within the log and adding the offset
 //! of the entry within the page to find the position of the entry within the log (which is the
-//! ID). Entries also have a header of their own, which contains the length of the entry.
+//! ID). Entries also have a header of their own, which contains a CRC-32 and the length of the
+//! entry. The CRC covers the length and the data of the entry.
+//!
+//! The page header also holds a word identifying the format of the page. Pages written by earlier
+//! versions of the log, whose entries have no CRC, are not read: they are counted in
+//! `recovery_report()` and the log starts afresh, overwriting them as it grows.
+//!
+//! If power is lost while a page is being written, the page can be left holding a partially
+//! written tail. When reconstructing a log, the newest page is truncated before the first entry
+//! that is not intact, and `recovery_report()` describes what was found. Damaged entries in older
+//! pages are skipped when reading, along with the rest of their page, since their length can't be
+//! trusted.
 //!
 //! Logs support the following basic operations:
 //!     * Read:     Read back previously written entries in whole. Entries are read in their
@@ -66,6 +77,7 @@
 //!     log.set_append_client(log_storage_append_client);
 //! ```
 
+use crate::crc32::crc32;
 use core::cell::Cell;
 use core::convert::TryFrom;
 use core::mem::size_of;
@@ -82,13 +94,61 @@ use kernel::ReturnCode;
 type EntryID = usize;
 
 /// Maximum page header size.
-pub const PAGE_HEADER_SIZE: usize = size_of::<EntryID>();
+pub const PAGE_HEADER_SIZE: usize = size_of::<EntryID>() + FORMAT_SIZE;
 /// Maximum entry header size.
-pub const ENTRY_HEADER_SIZE: usize = size_of::<usize>();
+pub const ENTRY_HEADER_SIZE: usize = CRC_SIZE + size_of::<usize>();
+
+/// Size of the CRC at the start of each entry header.
+const CRC_SIZE: usize = size_of::<u32>();
+
+/// Size of the format word following the page ID in each page header.
+const FORMAT_SIZE: usize = size_of::<u32>();
+
+/// Format word of the pages of this version of the log: "LOG" and the version number, 2. The
+/// pages of version 1 have no format word, and in its place the length of their first entry,
+/// which is always smaller than this.
+const FORMAT_VERSION: u32 = 0x4c4f_4702;
 
 /// Byte used to pad the end of a page.
 const PAD_BYTE: u8 = 0xFF;
 
+/// What was found in flash when reconstructing a log.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+    /// Number of pages with a valid header.
+    pub valid_pages: usize,
+    /// Number of pages with a damaged header, which were ignored.
+    pub damaged_pages: usize,
+    /// Number of pages written by an earlier version of the log, which were ignored.
+    pub old_format_pages: usize,
+    /// Number of intact entries in the newest page.
+    pub last_page_entries: usize,
+    /// Number of bytes dropped from the end of the newest page because they did not hold an
+    /// intact entry, such as an entry torn by a power failure.
+    pub truncated_bytes: usize,
+}
+
+/// Returns the length of the data of the entry at the start of `bytes` if the entry is intact
+/// and fits within `bytes`.
+fn parse_entry(bytes: &[u8]) -> Option<usize> {
+    const LENGTH_SIZE: usize = size_of::<usize>();
+    if bytes.len() < ENTRY_HEADER_SIZE {
+        return None;
+    }
+    let length_bytes = <[u8; LENGTH_SIZE]>::try_from(&bytes[CRC_SIZE..ENTRY_HEADER_SIZE]).unwrap();
+    let length = usize::from_ne_bytes(length_bytes);
+    if length == 0 || length > bytes.len() - ENTRY_HEADER_SIZE {
+        return None;
+    }
+
+    let crc_bytes = <[u8; CRC_SIZE]>::try_from(&bytes[..CRC_SIZE]).unwrap();
+    if u32::from_ne_bytes(crc_bytes) == crc32(&bytes[CRC_SIZE..ENTRY_HEADER_SIZE + length]) {
+        Some(length)
+    } else {
+        None
+    }
+}
+
 /// Log state keeps track of any in-progress asynchronous operations.
 #[derive(Clone, Copy, PartialEq)]
 enum State {
@@ -141,6 +201,8 @@ pub struct Log<'a, F: Flash + 'static> {
     records_lost: Cell<bool>,
     /// Error returned by previously executed operation (or SUCCESS).
     error: Cell<ReturnCode>,
+    /// What was found when reconstructing the log.
+    recovery: Cell<RecoveryReport>,
 }
 
 impl<'a, F: Flash + 'static> Log<'a, F> {
@@ -173,6 +235,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             length: Cell::new(0),
             records_lost: Cell::new(false),
             error: Cell::new(ReturnCode::ENODEVICE),
+            recovery: Cell::new(RecoveryReport::default()),
         };
 
         log.reconstruct();
@@ -197,12 +260,6 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         }
     }
 
-    /// Gets the byte at the given position in the log.
-    fn get_byte(&self, pos: usize, pagebuffer: &mut F::Page) -> u8 {
-        let buffer = self.get_buffer(pos, pagebuffer);
-        buffer[pos % buffer.len()]
-    }
-
     /// Gets a `num_bytes` long slice of bytes starting from a position within the log.
     fn get_bytes<'b>(&self, pos: usize, num_bytes: usize, pagebuffer: &'b mut F::Page) -> &'b [u8] {
         let buffer = self.get_buffer(pos, pagebuffer);
@@ -219,64 +276,76 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             for e in pagebuffer.as_mut().iter_mut() {
                 *e = 0;
             }
+            pagebuffer.as_mut()[size_of::<EntryID>()..PAGE_HEADER_SIZE]
+                .copy_from_slice(&FORMAT_VERSION.to_ne_bytes());
             self.pagebuffer.replace(pagebuffer);
             true
         })
     }
 
+    /// Returns what was found in flash when the log was reconstructed at boot.
+    pub fn recovery_report(&self) -> RecoveryReport {
+        self.recovery.get()
+    }
+
     /// Reconstructs a log from flash.
     fn reconstruct(&self) {
+        let mut report = RecoveryReport::default();
+
         // Read page headers, get IDs of oldest and newest pages.
         let mut oldest_page_id: EntryID = core::usize::MAX;
         let mut newest_page_id: EntryID = 0;
         for header_pos in (0..self.volume.len()).step_by(self.page_size) {
+            const ID_SIZE: usize = size_of::<EntryID>();
             let page_id = {
-                const ID_SIZE: usize = size_of::<EntryID>();
                 let id_bytes = &self.volume[header_pos..header_pos + ID_SIZE];
                 let id_bytes = <[u8; ID_SIZE]>::try_from(id_bytes).unwrap();
                 usize::from_ne_bytes(id_bytes)
             };
+            let format = {
+                let format_bytes =
+                    &self.volume[header_pos + ID_SIZE..header_pos + PAGE_HEADER_SIZE];
+                let format_bytes = <[u8; FORMAT_SIZE]>::try_from(format_bytes).unwrap();
+                u32::from_ne_bytes(format_bytes)
+            };
 
-            // Validate page ID read from header.
-            if page_id % self.volume.len() == header_pos {
+            // Validate page ID and format read from header.
+            let in_place = page_id % self.volume.len() == header_pos;
+            if in_place && format == FORMAT_VERSION {
+                report.valid_pages += 1;
                 if page_id < oldest_page_id {
                     oldest_page_id = page_id;
                 }
                 if page_id > newest_page_id {
                     newest_page_id = page_id;
                 }
+            } else if in_place && format != 0 && format != core::u32::MAX {
+                // A page written by an earlier version of the log, whose entries can't be checked.
+                // Pages of that version with no entries look blank.
+                report.old_format_pages += 1;
+            } else if !in_place && page_id != 0 && page_id != core::usize::MAX {
+                // Neither a page of this log nor a blank page.
+                report.damaged_pages += 1;
             }
         }
 
         // Reconstruct log if at least one valid page was found (meaning oldest page ID was set to
         // something not usize::MAX).
         if oldest_page_id != core::usize::MAX {
-            // Walk entries in last (newest) page to calculate last page length.
+            // Walk intact entries in last (newest) page to calculate last page length.
+            let page_start = newest_page_id % self.volume.len();
+            let page = &self.volume[page_start..page_start + self.page_size];
             let mut last_page_len = PAGE_HEADER_SIZE;
-            loop {
-                // Check if next byte is start of valid entry.
-                let volume_offset = newest_page_id % self.volume.len() + last_page_len;
-                if self.volume[volume_offset] == 0 || self.volume[volume_offset] == PAD_BYTE {
-                    break;
-                }
+            while let Some(length) = parse_entry(&page[last_page_len..]) {
+                last_page_len += ENTRY_HEADER_SIZE + length;
+                report.last_page_entries += 1;
+            }
 
-                // Get next entry length.
-                let entry_length = {
-                    const LENGTH_SIZE: usize = size_of::<usize>();
-                    let length_bytes = &self.volume[volume_offset..volume_offset + LENGTH_SIZE];
-                    let length_bytes = <[u8; LENGTH_SIZE]>::try_from(length_bytes).unwrap();
-                    usize::from_ne_bytes(length_bytes)
-                } + ENTRY_HEADER_SIZE;
-
-                // Add to page length if length is valid (fits within remainder of page.
-                if last_page_len + entry_length <= self.page_size {
-                    last_page_len += entry_length;
-                    if last_page_len == self.page_size {
-                        break;
-                    }
-                } else {
-                    break;
-                }
+            // Anything after the last intact entry other than padding is a damaged entry, which
+            // is truncated.
+            let tail = &page[last_page_len..];
+            if !tail.iter().all(|b| *b == PAD_BYTE) && !tail.iter().all(|b| *b == 0) {
+                report.truncated_bytes = tail.len();
             }
 
             // Set tracked entry IDs.
@@ -308,6 +377,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             // No valid pages found, create fresh log.
             self.reset();
         }
+        self.recovery.set(report);
     }
 
     /// Returns the ID of the next entry to read or an error if no entry could be retrieved.
@@ -321,10 +391,16 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
                 let mut entry_id = self.read_entry_id.get();
 
                 // Skip page header if at start of page or skip padded bytes if at end of page.
+                let page_remaining = self.page_size - entry_id % self.page_size;
                 if entry_id % self.page_size == 0 {
                     entry_id += PAGE_HEADER_SIZE;
-                } else if self.get_byte(entry_id, pagebuffer) == PAD_BYTE {
-                    entry_id += self.page_size - entry_id % self.page_size + PAGE_HEADER_SIZE;
+                } else if page_remaining < ENTRY_HEADER_SIZE
+                    || self
+                        .get_bytes(entry_id, ENTRY_HEADER_SIZE, pagebuffer)
+                        .iter()
+                        .all(|b| *b == PAD_BYTE)
+                {
+                    entry_id += page_remaining + PAGE_HEADER_SIZE;
                 }
 
                 // Check if end of log was reached and return.
@@ -337,28 +413,19 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             })
     }
 
-    /// Reads and returns the contents of an entry header with the given ID. Fails if the header
-    /// data is invalid.
+    /// Reads and returns the length of the entry with the given ID. Fails if the entry is
+    /// damaged.
     /// ReturnCodes used:
-    ///     * FAIL: entry header invalid.
+    ///     * FAIL: entry header invalid or entry CRC mismatch.
     ///     * ERESERVE: client or internal pagebuffer missing.
     fn read_entry_header(&self, entry_id: EntryID) -> Result<usize, ReturnCode> {
         self.pagebuffer
             .take()
             .map_or(Err(ReturnCode::ERESERVE), move |pagebuffer| {
-                // Get length.
-                const LENGTH_SIZE: usize = size_of::<usize>();
-                let length_bytes = self.get_bytes(entry_id, LENGTH_SIZE, pagebuffer);
-                let length_bytes = <[u8; LENGTH_SIZE]>::try_from(length_bytes).unwrap();
-                let length = usize::from_ne_bytes(length_bytes);
-
-                // Return length of next entry.
+                let page_remaining = self.page_size - entry_id % self.page_size;
+                let length = parse_entry(self.get_bytes(entry_id, page_remaining, pagebuffer));
                 self.pagebuffer.replace(pagebuffer);
-                if length == 0 || length > self.page_size - PAGE_HEADER_SIZE - ENTRY_HEADER_SIZE {
-                    Err(ReturnCode::FAIL)
-                } else {
-                    Ok(length)
-                }
+                length.ok_or(ReturnCode::FAIL)
             })
     }
 
@@ -369,9 +436,19 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
     ///     * ERESERVE: internal pagebuffer missing, log is presumably broken.
     ///     * ESIZE: buffer not large enough to contain entry being read.
     fn read_entry(&self, buffer: &mut [u8], length: usize) -> Result<usize, ReturnCode> {
-        // Get next entry to read. Immediately returns FAIL in event of failure.
-        let entry_id = self.get_next_entry()?;
-        let entry_length = self.read_entry_header(entry_id)?;
+        // Get next intact entry to read. Immediately returns FAIL at the end of the log.
+        let (entry_id, entry_length) = loop {
+            let entry_id = self.get_next_entry()?;
+            match self.read_entry_header(entry_id) {
+                Ok(entry_length) => break (entry_id, entry_length),
+                Err(ReturnCode::FAIL) => {
+                    // Damaged entry, skip the rest of its page.
+                    self.read_entry_id
+                        .set(entry_id + self.page_size - entry_id % self.page_size);
+                }
+                Err(return_code) => return Err(return_code),
+            }
+        };
 
         // Read entry into buffer.
         self.pagebuffer
@@ -397,14 +474,13 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
             })
     }
 
-    /// Writes an entry header at the given position within a page. Must write at most
-    /// ENTRY_HEADER_SIZE bytes.
+    /// Writes an entry header at the given position within a page, for an entry whose data is
+    /// already in the pagebuffer. Must write at most ENTRY_HEADER_SIZE bytes.
     fn write_entry_header(&self, length: usize, pos: usize, pagebuffer: &mut F::Page) {
-        let mut offset = 0;
-        for byte in &length.to_ne_bytes() {
-            pagebuffer.as_mut()[pos + offset] = *byte;
-            offset += 1;
-        }
+        let page = pagebuffer.as_mut();
+        page[pos + CRC_SIZE..pos + ENTRY_HEADER_SIZE].copy_from_slice(&length.to_ne_bytes());
+        let crc = crc32(&page[pos + CRC_SIZE..pos + ENTRY_HEADER_SIZE + length]);
+        page[pos..pos + CRC_SIZE].copy_from_slice(&crc.to_ne_bytes());
     }
 
     /// Appends data from a buffer onto the end of the log. Requires that there is enough space
@@ -417,16 +493,14 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
     ) {
         // Offset within page to append to.
         let append_entry_id = self.append_entry_id.get();
-        let mut page_offset = append_entry_id % self.page_size;
+        let page_offset = append_entry_id % self.page_size;
+
+        // Copy data to pagebuffer.
+        let data_offset = page_offset + ENTRY_HEADER_SIZE;
+        pagebuffer.as_mut()[data_offset..data_offset + length].copy_from_slice(&buffer[..length]);
 
         // Write entry header to pagebuffer.
         self.write_entry_header(length, page_offset, pagebuffer);
-        page_offset += ENTRY_HEADER_SIZE;
-
-        // Copy data to pagebuffer.
-        for offset in 0..length {
-            pagebuffer.as_mut()[page_offset + offset] = buffer[offset];
-        }
 
         // Increment append offset by number of bytes appended.
         let append_entry_id = append_entry_id + length + ENTRY_HEADER_SIZE;
@@ -459,11 +533,14 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         // padding pointer points to start of the page following the one we want to flush after the
         // padding operation.
         let page_number = self.page_number(pad_ptr - self.page_size);
-        let overwritten_page = (pad_ptr - self.volume.len() - self.page_size) / self.page_size;
+        // No log page is overwritten until the log has wrapped around.
+        let overwritten_page = (pad_ptr - self.page_size)
+            .checked_sub(self.volume.len())
+            .map(|entry_id| entry_id / self.page_size);
 
         // Advance read and oldest entry IDs, if within flash page being overwritten.
         let read_entry_id = self.read_entry_id.get();
-        if read_entry_id / self.page_size == overwritten_page {
+        if Some(read_entry_id / self.page_size) == overwritten_page {
             // Move read entry ID to start of next page.
             self.read_entry_id.set(
                 read_entry_id + self.page_size + PAGE_HEADER_SIZE - read_entry_id % self.page_size,
@@ -471,7 +548,7 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
         }
 
         let oldest_entry_id = self.oldest_entry_id.get();
-        if oldest_entry_id / self.page_size == overwritten_page {
+        if Some(oldest_entry_id / self.page_size) == overwritten_page {
             self.oldest_entry_id.set(oldest_entry_id + self.page_size);
         }
 
@@ -503,8 +580,9 @@ impl<'a, F: Flash + 'static> Log<'a, F> {
 
         // Write page header to pagebuffer.
         let id_bytes = append_entry_id.to_ne_bytes();
-        for index in 0..id_bytes.len() {
-            pagebuffer.as_mut()[index] = id_bytes[index];
+        let format_bytes = FORMAT_VERSION.to_ne_bytes();
+        for (index, byte) in id_bytes.iter().chain(format_bytes.iter()).enumerate() {
+            pagebuffer.as_mut()[index] = *byte;
         }
 
         // Note: this is the only place where the append entry ID can cross page boundaries.
@@ -766,12 +844,15 @@ impl<'a, F: Flash + 'static> LogWrite<'a> for Log<'a, F> {
     ///     * SUCCESS: append succeeded.
     ///     * FAIL: write failed due to flash error.
     fn sync(&self) -> ReturnCode {
-        if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE {
-            // Pagebuffer empty, don't need to flush.
-            return ReturnCode::SUCCESS;
-        } else if self.state.get() != State::Idle {
+        if self.state.get() != State::Idle {
             // Log busy, try appending again later.
             return ReturnCode::EBUSY;
+        } else if self.append_entry_id.get() % self.page_size == PAGE_HEADER_SIZE {
+            // Pagebuffer empty, don't need to flush.
+            self.state.set(State::Sync);
+            self.error.set(ReturnCode::SUCCESS);
+            self.deferred_client_callback();
+            return ReturnCode::SUCCESS;
         }
 
         self.pagebuffer
@@ -907,3 +988,332 @@ impl<'a, F: Flash + 'static> DynamicDeferredCallClient for Log<'a, F> {
         self.client_callback();
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{self, Medium, Page, SimFlash, WriteMode, PAGE_SIZE};
+    use std::vec::Vec;
+
+    const TEST_PAGES: usize = 4;
+    const VOLUME_LEN: usize = PAGE_SIZE * TEST_PAGES;
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        length: Cell<usize>,
+        result: Cell<Option<ReturnCode>>,
+    }
+
+    impl LogReadClient for TestClient {
+        fn read_done(&self, buffer: &'static mut [u8], length: usize, error: ReturnCode) {
+            self.buffer.replace(buffer);
+            self.length.set(length);
+            self.result.set(Some(error));
+        }
+
+        fn seek_done(&self, error: ReturnCode) {
+            self.result.set(Some(error));
+        }
+    }
+
+    impl LogWriteClient for TestClient {
+        fn append_done(
+            &self,
+            buffer: &'static mut [u8],
+            _length: usize,
+            _records_lost: bool,
+            error: ReturnCode,
+        ) {
+            self.buffer.replace(buffer);
+            self.result.set(Some(error));
+        }
+
+        fn sync_done(&self, error: ReturnCode) {
+            self.result.set(Some(error));
+        }
+
+        fn erase_done(&self, error: ReturnCode) {
+            self.result.set(Some(error));
+        }
+    }
+
+    struct Harness {
+        flash: &'static SimFlash,
+        log: &'static Log<'static, SimFlash>,
+        client: &'static TestClient,
+        handle: DeferredCallHandle,
+    }
+
+    impl Harness {
+        /// Boots a log from the flash contents in `medium`.
+        fn boot(medium: &'static Medium, circular: bool) -> Harness {
+            // The log finds page numbers from the volume's address, so align it
+            let memory: &'static mut [u8] =
+                test_util::leak(std::vec![0; VOLUME_LEN + PAGE_SIZE]).as_mut_slice();
+            let offset = PAGE_SIZE - memory.as_ptr() as usize % PAGE_SIZE;
+            let volume = &mut memory[offset..offset + VOLUME_LEN];
+            volume.copy_from_slice(&medium.data.borrow());
+            let volume: &'static [u8] = volume;
+
+            let flash = SimFlash::at(
+                medium,
+                volume.as_ptr() as usize / PAGE_SIZE,
+                WriteMode::Replace,
+            );
+            let deferred_caller = test_util::deferred_caller(1);
+            let log = test_util::leak(Log::new(
+                volume,
+                flash,
+                test_util::leak(Page::default()),
+                deferred_caller,
+                circular,
+            ));
+            let client: &'static TestClient = test_util::leak(TestClient {
+                buffer: TakeCell::new(test_util::leak([0u8; 64])),
+                length: Cell::new(0),
+                result: Cell::new(None),
+            });
+            flash.set_client(log);
+            log.set_read_client(client);
+            log.set_append_client(client);
+            let handle = deferred_caller.register(log).unwrap();
+            log.initialize_callback_handle(handle);
+            Harness {
+                flash: flash,
+                log: log,
+                client: client,
+                handle: handle,
+            }
+        }
+
+        fn result(&self) -> Option<ReturnCode> {
+            while self.flash.step() {}
+            self.log.call(self.handle);
+            self.client.result.take()
+        }
+
+        fn append(&self, entry: &[u8]) -> Option<ReturnCode> {
+            let buffer = self.client.buffer.take().unwrap();
+            buffer[..entry.len()].copy_from_slice(entry);
+            if let Err((result, buffer)) = self.log.append(buffer, entry.len()) {
+                buffer.map(|buffer| self.client.buffer.replace(buffer));
+                return Some(result);
+            }
+            self.result()
+        }
+
+        fn sync(&self) -> Option<ReturnCode> {
+            match self.log.sync() {
+                ReturnCode::SUCCESS => self.result(),
+                result => Some(result),
+            }
+        }
+
+        /// Reads all remaining entries.
+        fn read_all(&self) -> Vec<Vec<u8>> {
+            let mut entries = Vec::new();
+            loop {
+                let buffer = self.client.buffer.take().unwrap();
+                if let Err((result, buffer)) = self.log.read(buffer, 64) {
+                    self.client.buffer.replace(buffer.unwrap());
+                    assert_eq!(result, ReturnCode::FAIL);
+                    return entries;
+                }
+                assert_eq!(self.result(), Some(ReturnCode::SUCCESS));
+                let length = self.client.length.get();
+                entries.push(self.client.buffer.map(|b| b[..length].to_vec()).unwrap());
+            }
+        }
+    }
+
+    /// Entries of different lengths, all different.
+    fn entry(i: usize) -> Vec<u8> {
+        (0..10 + i % 7 * 5).map(|j| (i * 31 + j) as u8).collect()
+    }
+
+    /// A blank volume, as `storage_volume!` creates.
+    fn storage() -> &'static Medium {
+        Medium::with_data(std::vec![0; VOLUME_LEN])
+    }
+
+    #[test]
+    fn entries_survive_reboot() {
+        let storage = storage();
+        let log = Harness::boot(storage, false);
+        for i in 0..20 {
+            assert_eq!(log.append(&entry(i)), Some(ReturnCode::SUCCESS));
+        }
+        assert_eq!(log.sync(), Some(ReturnCode::SUCCESS));
+        // Nothing left to sync
+        assert_eq!(log.sync(), Some(ReturnCode::SUCCESS));
+
+        let log = Harness::boot(storage, false);
+        let report = log.log.recovery_report();
+        assert_eq!(report.damaged_pages, 0);
+        assert_eq!(report.truncated_bytes, 0);
+        assert!(report.last_page_entries > 0);
+        assert_eq!(log.read_all(), (0..20).map(entry).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn damaged_entries_are_dropped() {
+        let storage = storage();
+        let log = Harness::boot(storage, false);
+        for i in 0..20 {
+            assert_eq!(log.append(&entry(i)), Some(ReturnCode::SUCCESS));
+        }
+        assert_eq!(log.sync(), Some(ReturnCode::SUCCESS));
+        let entries = Harness::boot(storage, false).read_all();
+
+        // Corrupt the last entry of the newest page, and an entry in the first page
+        let newest_page = (0..TEST_PAGES)
+            .max_by_key(|page| storage.data.borrow()[page * PAGE_SIZE])
+            .unwrap();
+        let last_page_entries = Harness::boot(storage, false)
+            .log
+            .recovery_report()
+            .last_page_entries;
+        let mut end = newest_page * PAGE_SIZE + PAGE_HEADER_SIZE;
+        for _ in 0..last_page_entries {
+            end += ENTRY_HEADER_SIZE + parse_entry(&storage.data.borrow()[end..]).unwrap();
+        }
+        storage.data.borrow_mut()[end - 1] ^= 0x01;
+        storage.data.borrow_mut()[PAGE_HEADER_SIZE + ENTRY_HEADER_SIZE] ^= 0x80;
+
+        let log = Harness::boot(storage, false);
+        let report = log.log.recovery_report();
+        assert_eq!(report.last_page_entries, last_page_entries - 1);
+        assert!(report.truncated_bytes > 0);
+        // The first page is skipped, and the damaged entry is gone
+        let first_page_entries = entries.len() - log.read_all().len() - 1;
+        assert!(first_page_entries > 0);
+        let log = Harness::boot(storage, false);
+        assert_eq!(
+            log.read_all(),
+            entries[first_page_entries..entries.len() - 1].to_vec()
+        );
+    }
+
+    #[test]
+    fn old_format_pages_are_replaced() {
+        // A page of the first version of the log: the page ID, then entries that are only
+        // preceded by their length.
+        let storage = storage();
+        {
+            let mut data = storage.data.borrow_mut();
+            let mut pos = size_of::<EntryID>();
+            for i in 0..3 {
+                data[pos..pos + size_of::<usize>()].copy_from_slice(&entry(i).len().to_ne_bytes());
+                pos += size_of::<usize>();
+                data[pos..pos + entry(i).len()].copy_from_slice(&entry(i));
+                pos += entry(i).len();
+            }
+        }
+
+        let log = Harness::boot(storage, false);
+        let report = log.log.recovery_report();
+        assert_eq!(report.old_format_pages, 1);
+        assert_eq!(report.valid_pages, 0);
+        assert_eq!(report.damaged_pages, 0);
+        assert!(log.read_all().is_empty());
+
+        assert_eq!(log.append(&entry(10)), Some(ReturnCode::SUCCESS));
+        assert_eq!(log.sync(), Some(ReturnCode::SUCCESS));
+        let log = Harness::boot(storage, false);
+        let report = log.log.recovery_report();
+        assert_eq!(report.old_format_pages, 0);
+        assert_eq!(report.valid_pages, 1);
+        assert_eq!(log.read_all(), std::vec![entry(10)]);
+    }
+
+    /// Loses power at each page write in turn while appending and syncing,
+    /// then checks that the log recovers intact entries in order, including
+    /// every entry that was written to a page other than the torn one.
+    fn power_loss_at_every_write(circular: bool) {
+        let mut truncated = false;
+        for fail_at in 0.. {
+            let storage = storage();
+            let log = Harness::boot(storage, circular);
+            log.flash.writes_left.set(Some(fail_at));
+
+            // For each entry appended, its page and the number of page writes
+            // completed when it was appended
+            let mut appended: Vec<(usize, usize)> = Vec::new();
+            for i in 0..60 {
+                let entry_id = log.log.log_end();
+                if log.append(&entry(i)) != Some(ReturnCode::SUCCESS) {
+                    break;
+                }
+                let entry_id = if log.log.log_end() - entry_id == ENTRY_HEADER_SIZE + entry(i).len()
+                {
+                    entry_id
+                } else {
+                    // The entry went to a new page
+                    log.log.log_end() - ENTRY_HEADER_SIZE - entry(i).len()
+                };
+                let page = entry_id % VOLUME_LEN / PAGE_SIZE;
+                appended.push((page, log.flash.writes().len()));
+                if i % 3 == 2 && log.sync() != Some(ReturnCode::SUCCESS) {
+                    break;
+                }
+            }
+            let torn_page = match log.flash.torn_page.get() {
+                Some(page) => page,
+                // The power never failed, so every write was tested
+                None => break,
+            };
+
+            // Entries in pages written after them are durable, unless the
+            // page was torn, or in a circular log, overwritten later.
+            let written = log.flash.writes();
+            let durable = |&(page, writes): &(usize, usize)| {
+                page != torn_page && written[writes..].iter().any(|p| *p == page)
+            };
+
+            let log = Harness::boot(storage, circular);
+            truncated |= log.log.recovery_report().truncated_bytes > 0;
+            let entries = log.read_all();
+            let first = (0..appended.len() + 1)
+                .find(|i| entries.first().map_or(true, |e| *e == entry(*i)))
+                .unwrap();
+            for (i, e) in entries.iter().enumerate() {
+                assert_eq!(*e, entry(first + i), "power lost at write {}", fail_at);
+            }
+            let recovered = first..first + entries.len();
+            assert!(recovered.end <= appended.len());
+            if !circular {
+                assert_eq!(first, 0);
+            }
+            for (i, _) in appended.iter().enumerate().filter(|(_, a)| durable(a)) {
+                // Older entries of a circular log may have been overwritten
+                assert!(
+                    recovered.contains(&i) || (circular && i < first),
+                    "entry {} lost after power loss at write {}",
+                    i,
+                    fail_at
+                );
+            }
+
+            // The log is usable after recovery
+            let end = log.log.log_end();
+            assert_eq!(log.append(&entry(100)), Some(ReturnCode::SUCCESS));
+            assert_eq!(log.sync(), Some(ReturnCode::SUCCESS));
+            let log = Harness::boot(storage, circular);
+            assert!(log.log.log_end() > end);
+            assert_eq!(log.read_all().last(), Some(&entry(100)));
+        }
+        assert!(truncated);
+    }
+
+    #[test]
+    fn power_loss_linear() {
+        power_loss_at_every_write(false);
+    }
+
+    #[test]
+    fn power_loss_circular() {
+        power_loss_at_every_write(true);
+    }
+}
@@ -0,0 +1,681 @@
+//! Provides userspace with access to persistent logs.
+//!
+//! Each log is a `log::Log` on its own storage volume, and belongs to the
+//! process with a given name. Processes can append entries to their log, read
+//! entries back in order, seek to entries by ID, sync the log to flash and
+//! erase it. Processes without a log get ENODEVICE.
+//!
+//! The logs share one buffer, so operations on all logs run one at a time.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
+//! let logs = static_init!(
+//!     [capsules::log_driver::AppLog<'static, Log>; 2],
+//!     [
+//!         capsules::log_driver::AppLog::new("sensors", sensor_log),
+//!         capsules::log_driver::AppLog::new("events", event_log),
+//!     ]
+//! );
+//! let log_driver = static_init!(
+//!     capsules::log_driver::LogDriver<'static, Log, ProcessMgmtCap>,
+//!     capsules::log_driver::LogDriver::new(
+//!         logs,
+//!         board_kernel,
+//!         ProcessMgmtCap,
+//!         board_kernel.create_grant(&grant_cap),
+//!         &mut capsules::log_driver::BUFFER
+//!     )
+//! );
+//! log_driver.set_clients();
+//! ```
+//!
+//! The system call interface is documented in doc/syscalls/50004_log.md.
+
+use crate::driver;
+use core::cmp;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil::log::{LogRead, LogReadClient, LogWrite, LogWriteClient};
+use kernel::introspection::KernelInfo;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, Kernel, ReturnCode, Shared};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = driver::NUM::Log as usize;
+
+/// Buffer for entries read and appended, which limits the size of entries.
+pub static mut BUFFER: [u8; 256] = [0; 256];
+
+/// A log and the name of the process it belongs to.
+pub struct AppLog<'a, L> {
+    process_name: &'static str,
+    log: &'a L,
+}
+
+impl<'a, L> AppLog<'a, L> {
+    pub const fn new(process_name: &'static str, log: &'a L) -> AppLog<'a, L> {
+        AppLog {
+            process_name: process_name,
+            log: log,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Operation {
+    /// Read an entry of up to the given length
+    Read(usize),
+    /// Seek to the given entry ID
+    Seek(usize),
+    /// Append an entry of the given length
+    Append(usize),
+    Sync,
+    Erase,
+}
+
+impl Operation {
+    /// The operation started by `command_num`, if it starts one.
+    fn from_command(command_num: usize, data1: usize) -> Option<Operation> {
+        match command_num {
+            1 => Some(Operation::Read(data1)),
+            2 => Some(Operation::Append(data1)),
+            3 => Some(Operation::Seek(data1)),
+            4 => Some(Operation::Sync),
+            5 => Some(Operation::Erase),
+            _ => None,
+        }
+    }
+}
+
+/// The logs and the buffer they share, apart from the process bookkeeping of
+/// `LogDriver`.
+struct Logs<'a, L> {
+    logs: &'a [AppLog<'a, L>],
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, L> Logs<'a, L>
+where
+    L: LogRead<'a, EntryID = usize> + LogWrite<'a>,
+{
+    /// The log of the process named `name`.
+    fn named(&self, name: &str) -> Option<&'a L> {
+        self.logs
+            .iter()
+            .find(|app_log| app_log.process_name == name)
+            .map(|app_log| app_log.log)
+    }
+
+    /// Starts an operation on `log`. Appends copy their entry from `data`.
+    fn start(&self, log: &L, operation: Operation, data: Option<&[u8]>) -> ReturnCode {
+        match operation {
+            Operation::Read(len) => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                let len = cmp::min(len, buffer.len());
+                match log.read(buffer, len) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((result, buffer)) => {
+                        buffer.map(|buffer| self.buffer.replace(buffer));
+                        result
+                    }
+                }
+            }),
+            Operation::Seek(entry_id) => log.seek(entry_id),
+            Operation::Append(len) => self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+                let copied = data.map_or(false, |data| {
+                    if len <= data.len() && len <= buffer.len() {
+                        buffer[..len].copy_from_slice(&data[..len]);
+                        true
+                    } else {
+                        false
+                    }
+                });
+                let result = if copied {
+                    log.append(buffer, len)
+                } else {
+                    Err((ReturnCode::EINVAL, Some(buffer)))
+                };
+                match result {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((result, buffer)) => {
+                        buffer.map(|buffer| self.buffer.replace(buffer));
+                        result
+                    }
+                }
+            }),
+            Operation::Sync => log.sync(),
+            Operation::Erase => log.erase(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    read_buffer: Option<AppSlice<Shared, u8>>,
+    write_buffer: Option<AppSlice<Shared, u8>>,
+    read_callback: Option<Callback>,
+    write_callback: Option<Callback>,
+    /// The operation waiting to run or running.
+    waiting: Option<Operation>,
+}
+
+pub struct LogDriver<'a, L, C: ProcessManagementCapability> {
+    logs: Logs<'a, L>,
+    kernel: &'static Kernel,
+    capability: C,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+}
+
+impl<'a, L, C> LogDriver<'a, L, C>
+where
+    L: LogRead<'a, EntryID = usize> + LogWrite<'a>,
+    C: ProcessManagementCapability,
+{
+    pub fn new(
+        logs: &'a [AppLog<'a, L>],
+        kernel: &'static Kernel,
+        capability: C,
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+    ) -> LogDriver<'a, L, C> {
+        LogDriver {
+            logs: Logs {
+                logs: logs,
+                buffer: TakeCell::new(buffer),
+            },
+            kernel: kernel,
+            capability: capability,
+            apps: grant,
+            serving_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Makes this driver the client of all of its logs.
+    pub fn set_clients(&'a self) {
+        for app_log in self.logs.logs.iter() {
+            app_log.log.set_read_client(self);
+            app_log.log.set_append_client(self);
+        }
+    }
+
+    /// The log of the process.
+    fn log_of(&self, appid: AppId) -> Option<&'a L> {
+        let name = KernelInfo::new(self.kernel).process_name(appid, &self.capability);
+        self.logs.named(name)
+    }
+
+    fn serve_waiting_apps(&self) {
+        if self.serving_app.is_some() {
+            // A log is busy with a request
+            return;
+        }
+
+        // Find a waiting app and start its operation
+        for app in self.apps.iter() {
+            let started = app.enter(|app, _| {
+                let appid = app.appid();
+                app.waiting.map_or(false, |operation| {
+                    let result = self.log_of(appid).map_or(ReturnCode::ENODEVICE, |log| {
+                        let data = app.write_buffer.as_ref().map(|data| data.as_ref());
+                        self.logs.start(log, operation, data)
+                    });
+                    if result == ReturnCode::SUCCESS {
+                        self.serving_app.set(appid);
+                        true
+                    } else {
+                        // The app's request failed
+                        app.waiting = None;
+                        let callback = match operation {
+                            Operation::Read(_) | Operation::Seek(_) => app.read_callback,
+                            _ => app.write_callback,
+                        };
+                        if let Some(mut callback) = callback {
+                            callback.schedule(From::from(result), 0, 0);
+                        }
+                        false
+                    }
+                })
+            });
+            if started {
+                break;
+            }
+        }
+    }
+
+    /// Reports a completed read or seek to the serving app, copying `entry`
+    /// to its read buffer.
+    fn read_operation_done(&self, entry: &[u8], result: ReturnCode) {
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.waiting = None;
+                let mut length = 0;
+                if let Some(slice) = app.read_buffer.as_mut() {
+                    length = cmp::min(entry.len(), slice.len());
+                    slice.as_mut()[..length].copy_from_slice(&entry[..length]);
+                }
+                if let Some(mut callback) = app.read_callback {
+                    callback.schedule(From::from(result), length, 0);
+                }
+            });
+        });
+        self.serve_waiting_apps();
+    }
+
+    /// Reports a completed append, sync or erase to the serving app.
+    fn write_operation_done(&self, result: ReturnCode, length: usize, records_lost: bool) {
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.waiting = None;
+                if let Some(mut callback) = app.write_callback {
+                    callback.schedule(From::from(result), length, records_lost as usize);
+                }
+            });
+        });
+        self.serve_waiting_apps();
+    }
+}
+
+impl<'a, L, C> LogReadClient for LogDriver<'a, L, C>
+where
+    L: LogRead<'a, EntryID = usize> + LogWrite<'a>,
+    C: ProcessManagementCapability,
+{
+    fn read_done(&self, buffer: &'static mut [u8], length: usize, error: ReturnCode) {
+        let length = if error == ReturnCode::SUCCESS {
+            cmp::min(length, buffer.len())
+        } else {
+            0
+        };
+        self.read_operation_done(&buffer[..length], error);
+        self.logs.buffer.replace(buffer);
+    }
+
+    fn seek_done(&self, error: ReturnCode) {
+        self.read_operation_done(&[], error);
+    }
+}
+
+impl<'a, L, C> LogWriteClient for LogDriver<'a, L, C>
+where
+    L: LogRead<'a, EntryID = usize> + LogWrite<'a>,
+    C: ProcessManagementCapability,
+{
+    fn append_done(
+        &self,
+        buffer: &'static mut [u8],
+        length: usize,
+        records_lost: bool,
+        error: ReturnCode,
+    ) {
+        self.logs.buffer.replace(buffer);
+        self.write_operation_done(error, length, records_lost);
+    }
+
+    fn sync_done(&self, error: ReturnCode) {
+        self.write_operation_done(error, 0, false);
+    }
+
+    fn erase_done(&self, error: ReturnCode) {
+        self.write_operation_done(error, 0, false);
+    }
+}
+
+impl<'a, L, C> Driver for LogDriver<'a, L, C>
+where
+    L: LogRead<'a, EntryID = usize> + LogWrite<'a>,
+    C: ProcessManagementCapability,
+{
+    /// Setup buffers for entries.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer that entries are read into.
+    /// - `1`: Buffer holding the entry to append.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.read_buffer = slice,
+                    1 => app.write_buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Subscribe to log events.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Completion of reads and seeks. The callback signature is
+    ///        `fn(result: u32, length: usize)`, where `length` is the length
+    ///        of the entry read.
+    /// - `1`: Completion of appends, syncs and erases. The callback signature
+    ///        is `fn(result: u32, length: usize, records_lost: bool)`.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match subscribe_num {
+                    0 => app.read_callback = callback,
+                    1 => app.write_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Use the log of the process.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Read the next entry, of up to `data1` bytes.
+    /// - `2`: Append the first `data1` bytes of the write buffer as an entry.
+    /// - `3`: Seek to the entry with ID `data1`.
+    /// - `4`: Sync the log to flash.
+    /// - `5`: Erase the log.
+    /// - `6`: Get the ID of the oldest entry.
+    /// - `7`: Get the ID of the entry the next append creates.
+    /// - `8`: Get the ID of the next entry to read.
+    /// - `9`: Get the approximate capacity of the log in bytes.
+    ///
+    /// A process can have one operation in progress at a time.
+    fn command(&self, command_num: usize, data1: usize, _: usize, appid: AppId) -> ReturnCode {
+        let operation = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            6..=9 => {
+                return self.log_of(appid).map_or(ReturnCode::ENODEVICE, |log| {
+                    ReturnCode::SuccessWithValue {
+                        value: match command_num {
+                            6 => log.log_start(),
+                            7 => log.log_end(),
+                            8 => log.next_read_entry_id(),
+                            _ => log.get_size(),
+                        },
+                    }
+                });
+            }
+            _ => match Operation::from_command(command_num, data1) {
+                Some(operation) => operation,
+                None => return ReturnCode::ENOSUPPORT,
+            },
+        };
+        if self.log_of(appid).is_none() {
+            return ReturnCode::ENODEVICE;
+        }
+
+        let result = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.waiting.is_some() {
+                    ReturnCode::EBUSY
+                } else {
+                    app.waiting = Some(operation);
+                    ReturnCode::SUCCESS
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+        if result == ReturnCode::SUCCESS {
+            self.serve_waiting_apps();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::leak;
+    use core::cell::{Cell, RefCell};
+    use std::vec::Vec;
+
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        Read(usize),
+        Append(Vec<u8>),
+        Seek(usize),
+        Sync,
+        Erase,
+    }
+
+    /// A log that records the operations started on it, and keeps the
+    /// buffer of a read or append until `finish` is called.
+    struct SimLog {
+        calls: RefCell<Vec<Call>>,
+        // The result of the next operation, which then does not start
+        fail: Cell<Option<ReturnCode>>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl SimLog {
+        fn new() -> SimLog {
+            SimLog {
+                calls: RefCell::new(Vec::new()),
+                fail: Cell::new(None),
+                buffer: TakeCell::empty(),
+            }
+        }
+
+        fn start(
+            &self,
+            call: Call,
+            buffer: &'static mut [u8],
+        ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+            self.calls.borrow_mut().push(call);
+            match self.fail.take() {
+                Some(result) => Err((result, Some(buffer))),
+                None => {
+                    self.buffer.replace(buffer);
+                    Ok(())
+                }
+            }
+        }
+
+        fn result(&self, call: Call) -> ReturnCode {
+            self.calls.borrow_mut().push(call);
+            self.fail.take().unwrap_or(ReturnCode::SUCCESS)
+        }
+
+        /// Completes a read or append, returning the buffer to `logs`.
+        fn finish(&self, logs: &Logs<SimLog>) {
+            logs.buffer.replace(self.buffer.take().unwrap());
+        }
+    }
+
+    impl<'a> LogRead<'a> for SimLog {
+        type EntryID = usize;
+
+        fn set_read_client(&'a self, _read_client: &'a dyn LogReadClient) {}
+
+        fn read(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+        ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+            self.start(Call::Read(length), buffer)
+        }
+
+        fn log_start(&self) -> usize {
+            0
+        }
+
+        fn log_end(&self) -> usize {
+            0
+        }
+
+        fn next_read_entry_id(&self) -> usize {
+            0
+        }
+
+        fn seek(&self, entry: usize) -> ReturnCode {
+            self.result(Call::Seek(entry))
+        }
+
+        fn get_size(&self) -> usize {
+            0
+        }
+    }
+
+    impl<'a> LogWrite<'a> for SimLog {
+        fn set_append_client(&'a self, _append_client: &'a dyn LogWriteClient) {}
+
+        fn append(
+            &self,
+            buffer: &'static mut [u8],
+            length: usize,
+        ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)> {
+            self.start(Call::Append(buffer[..length].to_vec()), buffer)
+        }
+
+        fn sync(&self) -> ReturnCode {
+            self.result(Call::Sync)
+        }
+
+        fn erase(&self) -> ReturnCode {
+            self.result(Call::Erase)
+        }
+    }
+
+    fn logs(log: &'static SimLog) -> Logs<'static, SimLog> {
+        Logs {
+            logs: leak([AppLog::new("sensors", log)]),
+            buffer: TakeCell::new(leak([0; 16])),
+        }
+    }
+
+    #[test]
+    fn commands_start_operations() {
+        assert_eq!(Operation::from_command(1, 8), Some(Operation::Read(8)));
+        assert_eq!(Operation::from_command(2, 8), Some(Operation::Append(8)));
+        assert_eq!(Operation::from_command(3, 8), Some(Operation::Seek(8)));
+        assert_eq!(Operation::from_command(4, 8), Some(Operation::Sync));
+        assert_eq!(Operation::from_command(5, 8), Some(Operation::Erase));
+        for command_num in &[0, 6, 9, 10] {
+            assert_eq!(Operation::from_command(*command_num, 8), None);
+        }
+    }
+
+    #[test]
+    fn logs_belong_to_named_processes() {
+        let log = leak(SimLog::new());
+        let logs = logs(log);
+        assert!(core::ptr::eq(logs.named("sensors").unwrap(), log));
+        assert!(logs.named("sensor").is_none());
+        assert!(logs.named("").is_none());
+    }
+
+    #[test]
+    fn operations_share_the_buffer() {
+        let log = leak(SimLog::new());
+        let logs = logs(log);
+
+        let entry = [1, 2, 3, 4];
+        assert_eq!(
+            logs.start(log, Operation::Append(3), Some(&entry)),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(*log.calls.borrow(), [Call::Append(std::vec![1, 2, 3])]);
+
+        // Reads and appends wait for the buffer, seeks, syncs and erases do
+        // not
+        assert_eq!(logs.start(log, Operation::Read(4), None), ReturnCode::EBUSY);
+        assert_eq!(
+            logs.start(log, Operation::Append(1), Some(&entry)),
+            ReturnCode::EBUSY
+        );
+        assert_eq!(
+            logs.start(log, Operation::Seek(7), None),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(logs.start(log, Operation::Sync, None), ReturnCode::SUCCESS);
+        assert_eq!(logs.start(log, Operation::Erase, None), ReturnCode::SUCCESS);
+        log.finish(&logs);
+
+        // Reads are limited to the buffer
+        assert_eq!(
+            logs.start(log, Operation::Read(100), None),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            log.calls.borrow()[1..],
+            [Call::Seek(7), Call::Sync, Call::Erase, Call::Read(16)]
+        );
+        assert!(logs.buffer.is_none());
+    }
+
+    #[test]
+    fn invalid_appends_are_rejected() {
+        let log = leak(SimLog::new());
+        let logs = logs(log);
+
+        let entry = [0; 32];
+        // No write buffer, or an entry longer than it or the shared buffer
+        assert_eq!(
+            logs.start(log, Operation::Append(1), None),
+            ReturnCode::EINVAL
+        );
+        assert_eq!(
+            logs.start(log, Operation::Append(5), Some(&entry[..4])),
+            ReturnCode::EINVAL
+        );
+        assert_eq!(
+            logs.start(log, Operation::Append(17), Some(&entry)),
+            ReturnCode::EINVAL
+        );
+        assert!(log.calls.borrow().is_empty());
+        assert!(logs.buffer.is_some());
+
+        // An empty write buffer still allows empty entries
+        assert_eq!(
+            logs.start(log, Operation::Append(0), Some(&[])),
+            ReturnCode::SUCCESS
+        );
+    }
+
+    #[test]
+    fn failed_operations_return_the_buffer() {
+        let log = leak(SimLog::new());
+        let logs = logs(log);
+
+        log.fail.set(Some(ReturnCode::FAIL));
+        assert_eq!(logs.start(log, Operation::Read(4), None), ReturnCode::FAIL);
+        assert!(logs.buffer.is_some());
+
+        log.fail.set(Some(ReturnCode::ESIZE));
+        assert_eq!(
+            logs.start(log, Operation::Append(2), Some(&[5, 6])),
+            ReturnCode::ESIZE
+        );
+        assert!(logs.buffer.is_some());
+
+        log.fail.set(Some(ReturnCode::EINVAL));
+        assert_eq!(
+            logs.start(log, Operation::Seek(3), None),
+            ReturnCode::EINVAL
+        );
+        assert_eq!(
+            *log.calls.borrow(),
+            [Call::Read(4), Call::Append(std::vec![5, 6]), Call::Seek(3)]
+        );
+    }
+}
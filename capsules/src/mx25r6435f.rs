@@ -63,6 +63,7 @@ pub static mut RXBUFFER: [u8; PAGE_SIZE as usize + 4] = [0; PAGE_SIZE as usize +
 const SPI_SPEED: u32 = 8000000;
 const SECTOR_SIZE: u32 = 4096;
 const PAGE_SIZE: u32 = 256;
+const SECTORS: u32 = 8 * 1024 * 1024 / SECTOR_SIZE;
 
 /// This is a wrapper around a u8 array that is sized to a single page for the
 /// MX25R6435F. The page size is 4k because that is the smallest size that can
@@ -178,6 +179,9 @@ pub struct MX25R6435F<
     rxbuffer: TakeCell<'static, [u8]>,
     client: OptionalCell<&'a dyn hil::flash::Client<MX25R6435F<'a, S, P, A>>>,
     client_sector: TakeCell<'static, Mx25r6435fSector>,
+    block_client: OptionalCell<&'a dyn hil::block_storage::BlockStorageClient>,
+    block_buffer: TakeCell<'static, [u8]>,
+    block_erase: Cell<bool>,
 }
 
 impl<
@@ -205,6 +209,9 @@ impl<
             rxbuffer: TakeCell::new(rxbuffer),
             client: OptionalCell::empty(),
             client_sector: TakeCell::empty(),
+            block_client: OptionalCell::empty(),
+            block_buffer: TakeCell::empty(),
+            block_erase: Cell::new(false),
         }
     }
 
@@ -258,15 +265,10 @@ impl<
         self.enable_write()
     }
 
-    fn read_sector(
-        &self,
-        sector_index: u32,
-        sector: &'static mut Mx25r6435fSector,
-    ) -> Result<(), (ReturnCode, &'static mut Mx25r6435fSector)> {
+    fn read_sector(&self, sector_index: u32) -> ReturnCode {
         self.configure_spi();
 
-        let retval = self
-            .txbuffer
+        self.txbuffer
             .take()
             .map_or(ReturnCode::ERESERVE, |txbuffer| {
                 self.rxbuffer
@@ -289,33 +291,86 @@ impl<
                             (PAGE_SIZE + 4) as usize,
                         )
                     })
-            });
-
-        if retval == ReturnCode::SUCCESS {
-            self.client_sector.replace(sector);
-            Ok(())
-        } else {
-            Err((retval, sector))
-        }
+            })
     }
 
-    fn write_sector(
-        &self,
-        sector_index: u32,
-        sector: &'static mut Mx25r6435fSector,
-    ) -> Result<(), (ReturnCode, &'static mut Mx25r6435fSector)> {
+    fn write_sector(&self, sector_index: u32) -> ReturnCode {
         self.configure_spi();
         self.state.set(State::EraseSectorWriteEnable {
             sector_index,
             operation: Operation::Write { sector_index },
         });
-        let retval = self.enable_write();
+        self.enable_write()
+    }
 
-        if retval == ReturnCode::SUCCESS {
-            self.client_sector.replace(sector);
-            Ok(())
+    /// Copy bytes read from the chip into the buffer of the current operation,
+    /// which is either a flash client's sector or a block client's buffer.
+    fn copy_to_client(&self, offset: usize, data: &[u8]) {
+        self.client_sector.map(|sector| {
+            sector.0[offset..offset + data.len()].copy_from_slice(data);
+        });
+        self.block_buffer.map(|buffer| {
+            buffer[offset..offset + data.len()].copy_from_slice(data);
+        });
+    }
+
+    /// Copy bytes to be written to the chip out of the buffer of the current
+    /// operation.
+    fn copy_from_client(&self, offset: usize, data: &mut [u8]) {
+        self.client_sector.map(|sector| {
+            data.copy_from_slice(&sector.0[offset..offset + data.len()]);
+        });
+        self.block_buffer.map(|buffer| {
+            data.copy_from_slice(&buffer[offset..offset + data.len()]);
+        });
+    }
+
+    fn read_complete(&self) {
+        if let Some(sector) = self.client_sector.take() {
+            self.client.map(move |client| {
+                client.read_complete(sector, hil::flash::Error::CommandComplete);
+            });
+        } else if let Some(buffer) = self.block_buffer.take() {
+            self.block_client.map(move |client| {
+                client.read_done(buffer, ReturnCode::SUCCESS);
+            });
+        }
+    }
+
+    fn write_complete(&self) {
+        if let Some(sector) = self.client_sector.take() {
+            self.client.map(move |client| {
+                client.write_complete(sector, hil::flash::Error::CommandComplete);
+            });
+        } else if let Some(buffer) = self.block_buffer.take() {
+            self.block_client.map(move |client| {
+                client.write_done(buffer, ReturnCode::SUCCESS);
+            });
+        }
+    }
+
+    fn erase_complete(&self) {
+        if self.block_erase.replace(false) {
+            self.block_client.map(|client| {
+                client.erase_done(ReturnCode::SUCCESS);
+            });
         } else {
-            Err((retval, sector))
+            self.client.map(|client| {
+                client.erase_complete(hil::flash::Error::CommandComplete);
+            });
+        }
+    }
+
+    /// Check that an operation on a sector can start now. Flash and block
+    /// operations share the chip and the buffer of the current operation, so
+    /// neither can start while the other is in progress.
+    fn check_access(&self, sector: usize, buffer_len: usize) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            ReturnCode::EBUSY
+        } else if sector >= SECTORS as usize || buffer_len < SECTOR_SIZE as usize {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
         }
     }
 }
@@ -348,43 +403,37 @@ impl<
                 sector_index,
                 page_index,
             } => {
-                self.client_sector.take().map(|sector| {
-                    read_buffer.map(move |read_buffer| {
-                        // Copy read in bytes to user page
-                        for i in 0..(PAGE_SIZE as usize) {
-                            // Skip the command and address bytes (hence the +4).
-                            sector[i + (page_index * PAGE_SIZE) as usize] = read_buffer[i + 4];
-                        }
-
-                        if (page_index + 1) * PAGE_SIZE == SECTOR_SIZE {
-                            // Done reading
-                            self.state.set(State::Idle);
-                            self.txbuffer.replace(write_buffer);
-                            self.rxbuffer.replace(read_buffer);
-
-                            self.client.map(move |client| {
-                                client.read_complete(sector, hil::flash::Error::CommandComplete);
-                            });
-                        } else {
-                            let address =
-                                (sector_index * SECTOR_SIZE) + ((page_index + 1) * PAGE_SIZE);
-                            write_buffer[0] = Opcodes::READ as u8;
-                            write_buffer[1] = (address >> 16) as u8;
-                            write_buffer[2] = (address >> 8) as u8;
-                            write_buffer[3] = (address >> 0) as u8;
-
-                            self.state.set(State::ReadSector {
-                                sector_index,
-                                page_index: page_index + 1,
-                            });
-                            self.client_sector.replace(sector);
-                            self.spi.read_write_bytes(
-                                write_buffer,
-                                Some(read_buffer),
-                                (PAGE_SIZE + 4) as usize,
-                            );
-                        }
-                    });
+                read_buffer.map(move |read_buffer| {
+                    // Copy read in bytes to user page, skipping the command and
+                    // address bytes (hence the +4).
+                    self.copy_to_client(
+                        (page_index * PAGE_SIZE) as usize,
+                        &read_buffer[4..(PAGE_SIZE + 4) as usize],
+                    );
+
+                    if (page_index + 1) * PAGE_SIZE == SECTOR_SIZE {
+                        // Done reading
+                        self.state.set(State::Idle);
+                        self.txbuffer.replace(write_buffer);
+                        self.rxbuffer.replace(read_buffer);
+                        self.read_complete();
+                    } else {
+                        let address = (sector_index * SECTOR_SIZE) + ((page_index + 1) * PAGE_SIZE);
+                        write_buffer[0] = Opcodes::READ as u8;
+                        write_buffer[1] = (address >> 16) as u8;
+                        write_buffer[2] = (address >> 8) as u8;
+                        write_buffer[3] = (address >> 0) as u8;
+
+                        self.state.set(State::ReadSector {
+                            sector_index,
+                            page_index: page_index + 1,
+                        });
+                        self.spi.read_write_bytes(
+                            write_buffer,
+                            Some(read_buffer),
+                            (PAGE_SIZE + 4) as usize,
+                        );
+                    }
                 });
             }
             State::EraseSectorWriteEnable {
@@ -436,9 +485,7 @@ impl<
                 // No need to disable write, chip does it automatically.
                 self.state.set(State::Idle);
                 self.txbuffer.replace(write_buffer);
-                self.client.map(|client| {
-                    client.erase_complete(hil::flash::Error::CommandComplete);
-                });
+                self.erase_complete();
             }
             State::WriteSectorWriteEnable {
                 sector_index,
@@ -450,11 +497,7 @@ impl<
                     // No need to disable writes since it happens automatically.
                     self.state.set(State::Idle);
                     self.txbuffer.replace(write_buffer);
-                    self.client.map(|client| {
-                        self.client_sector.take().map(|sector| {
-                            client.write_complete(sector, hil::flash::Error::CommandComplete);
-                        });
-                    });
+                    self.write_complete();
                 } else {
                     self.state.set(State::WriteSectorWrite {
                         sector_index,
@@ -480,11 +523,10 @@ impl<
                 write_buffer[2] = (address >> 8) as u8;
                 write_buffer[3] = (address >> 0) as u8;
 
-                self.client_sector.map(|sector| {
-                    for i in 0..(PAGE_SIZE as usize) {
-                        write_buffer[i + 4] = sector[i + (page_index * PAGE_SIZE) as usize];
-                    }
-                });
+                self.copy_from_client(
+                    (page_index * PAGE_SIZE) as usize,
+                    &mut write_buffer[4..(PAGE_SIZE + 4) as usize],
+                );
 
                 self.spi
                     .read_write_bytes(write_buffer, None, (PAGE_SIZE + 4) as usize);
@@ -579,7 +621,17 @@ impl<
         page_number: usize,
         buf: &'static mut Self::Page,
     ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
-        self.read_sector(page_number as u32, buf)
+        let retval = match self.check_access(page_number, SECTOR_SIZE as usize) {
+            ReturnCode::SUCCESS => self.read_sector(page_number as u32),
+            retval => retval,
+        };
+        match retval {
+            ReturnCode::SUCCESS => {
+                self.client_sector.replace(buf);
+                Ok(())
+            }
+            retval => Err((retval, buf)),
+        }
     }
 
     fn write_page(
@@ -587,10 +639,87 @@ impl<
         page_number: usize,
         buf: &'static mut Self::Page,
     ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
-        self.write_sector(page_number as u32, buf)
+        let retval = match self.check_access(page_number, SECTOR_SIZE as usize) {
+            ReturnCode::SUCCESS => self.write_sector(page_number as u32),
+            retval => retval,
+        };
+        match retval {
+            ReturnCode::SUCCESS => {
+                self.client_sector.replace(buf);
+                Ok(())
+            }
+            retval => Err((retval, buf)),
+        }
     }
 
     fn erase_page(&self, page_number: usize) -> ReturnCode {
-        self.erase_sector(page_number as u32)
+        match self.check_access(page_number, SECTOR_SIZE as usize) {
+            ReturnCode::SUCCESS => self.erase_sector(page_number as u32),
+            retval => retval,
+        }
+    }
+}
+
+impl<
+        'a,
+        S: hil::spi::SpiMasterDevice + 'a,
+        P: hil::gpio::Pin + 'a,
+        A: hil::time::Alarm<'a> + 'a,
+    > hil::block_storage::BlockStorage<'a> for MX25R6435F<'a, S, P, A>
+{
+    fn set_client(&self, client: &'a dyn hil::block_storage::BlockStorageClient) {
+        self.block_client.set(client);
+    }
+
+    fn geometry(&self) -> Option<hil::block_storage::Geometry> {
+        Some(hil::block_storage::Geometry {
+            block_size: SECTOR_SIZE as usize,
+            blocks: SECTORS,
+        })
+    }
+
+    fn read(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let retval = match self.check_access(block as usize, buffer.len()) {
+            ReturnCode::SUCCESS => self.read_sector(block),
+            retval => retval,
+        };
+        if retval == ReturnCode::SUCCESS {
+            self.block_buffer.replace(buffer);
+            Ok(())
+        } else {
+            Err((retval, buffer))
+        }
+    }
+
+    fn write(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let retval = match self.check_access(block as usize, buffer.len()) {
+            ReturnCode::SUCCESS => self.write_sector(block),
+            retval => retval,
+        };
+        if retval == ReturnCode::SUCCESS {
+            self.block_buffer.replace(buffer);
+            Ok(())
+        } else {
+            Err((retval, buffer))
+        }
+    }
+
+    fn erase(&self, block: u32) -> ReturnCode {
+        let retval = match self.check_access(block as usize, SECTOR_SIZE as usize) {
+            ReturnCode::SUCCESS => self.erase_sector(block),
+            retval => retval,
+        };
+        if retval == ReturnCode::SUCCESS {
+            self.block_erase.set(true);
+        }
+        retval
     }
 }
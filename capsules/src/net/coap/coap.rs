@@ -0,0 +1,759 @@
+//! This file contains the structs and functions used to encode and decode
+//! CoAP messages as defined in RFC 7252, along with the Block option from
+//! RFC 7959 used for block-wise transfers.
+//!
+//! A CoAP message consists of a fixed 4 byte header, a token of up to 8
+//! bytes, a sequence of options and an optional payload, which is separated
+//! from the options by the `0xFF` payload marker. Options are encoded as
+//! deltas from the previous option number, so they must be written in
+//! ascending order of option number.
+//!
+//! Decoding does not copy anything out of the received buffer: a
+//! `CoapMessage` holds the parsed header and borrows the option and payload
+//! bytes from the buffer it was decoded from.
+
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+/// The only CoAP protocol version defined so far.
+pub const COAP_VERSION: u8 = 1;
+
+/// Size of the fixed part of the CoAP header.
+pub const COAP_HEADER_LEN: usize = 4;
+
+/// Maximum length of a CoAP token.
+pub const COAP_MAX_TOKEN_LEN: usize = 8;
+
+/// Byte separating the options from the payload.
+pub const PAYLOAD_MARKER: u8 = 0xff;
+
+/// Default UDP port for CoAP.
+pub const COAP_PORT: u16 = 5683;
+
+/// Method and response codes. The upper three bits hold the class and the
+/// lower five bits the detail, so `CONTENT` (2.05) is `(2 << 5) | 5`.
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+
+    pub const GET: u8 = 0x01;
+    pub const POST: u8 = 0x02;
+    pub const PUT: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+
+    pub const CREATED: u8 = 0x41;
+    pub const DELETED: u8 = 0x42;
+    pub const VALID: u8 = 0x43;
+    pub const CHANGED: u8 = 0x44;
+    pub const CONTENT: u8 = 0x45;
+    pub const CONTINUE: u8 = 0x5f;
+
+    pub const BAD_REQUEST: u8 = 0x80;
+    pub const BAD_OPTION: u8 = 0x82;
+    pub const NOT_FOUND: u8 = 0x84;
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85;
+    pub const REQUEST_ENTITY_INCOMPLETE: u8 = 0x88;
+    pub const REQUEST_ENTITY_TOO_LARGE: u8 = 0x8d;
+
+    pub const INTERNAL_SERVER_ERROR: u8 = 0xa0;
+    pub const SERVICE_UNAVAILABLE: u8 = 0xa3;
+
+    /// Returns the class of a code (0 for requests, 2, 4 or 5 for responses).
+    pub fn class(code: u8) -> u8 {
+        code >> 5
+    }
+
+    /// Returns true if the code is a request method.
+    pub fn is_request(code: u8) -> bool {
+        class(code) == 0 && code != EMPTY
+    }
+
+    /// Returns true if the code is a response code.
+    pub fn is_response(code: u8) -> bool {
+        class(code) >= 2
+    }
+}
+
+/// Option numbers used by this implementation.
+pub mod option {
+    pub const IF_MATCH: u16 = 1;
+    pub const URI_HOST: u16 = 3;
+    pub const ETAG: u16 = 4;
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PORT: u16 = 7;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const MAX_AGE: u16 = 14;
+    pub const URI_QUERY: u16 = 15;
+    pub const ACCEPT: u16 = 17;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+    pub const SIZE2: u16 = 28;
+    pub const SIZE1: u16 = 60;
+
+    /// Critical options must be understood by the receiver; unrecognized
+    /// critical options cause a request to be rejected with 4.02.
+    pub fn is_critical(number: u16) -> bool {
+        number & 0x01 != 0
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CoapType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+impl CoapType {
+    fn from_bits(bits: u8) -> CoapType {
+        match bits & 0x03 {
+            0 => CoapType::Confirmable,
+            1 => CoapType::NonConfirmable,
+            2 => CoapType::Acknowledgement,
+            _ => CoapType::Reset,
+        }
+    }
+}
+
+/// The fixed CoAP header together with the message token.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CoapHeader {
+    pub msg_type: CoapType,
+    pub code: u8,
+    pub message_id: u16,
+    token: [u8; COAP_MAX_TOKEN_LEN],
+    token_len: u8,
+}
+
+impl CoapHeader {
+    pub fn new(msg_type: CoapType, code: u8, message_id: u16) -> CoapHeader {
+        CoapHeader {
+            msg_type: msg_type,
+            code: code,
+            message_id: message_id,
+            token: [0; COAP_MAX_TOKEN_LEN],
+            token_len: 0,
+        }
+    }
+
+    /// Sets the token of the message. Tokens longer than 8 bytes are
+    /// truncated.
+    pub fn set_token(&mut self, token: &[u8]) {
+        let len = core::cmp::min(token.len(), COAP_MAX_TOKEN_LEN);
+        self.token[..len].copy_from_slice(&token[..len]);
+        self.token_len = len as u8;
+    }
+
+    pub fn get_token(&self) -> &[u8] {
+        &self.token[..self.token_len as usize]
+    }
+
+    /// Returns the encoded length of the header including the token.
+    pub fn get_hdr_size(&self) -> usize {
+        COAP_HEADER_LEN + self.token_len as usize
+    }
+
+    /// This function serializes the `CoapHeader` and token into the provided
+    /// buffer.
+    ///
+    /// # Arguments
+    ///
+    /// `buf` - A mutable buffer to serialize the `CoapHeader` into
+    /// `offset` - The current offset into the provided buffer
+    ///
+    /// # Return Value
+    ///
+    /// This function returns the new offset into the buffer wrapped in an
+    /// SResult.
+    pub fn encode(&self, buf: &mut [u8], offset: usize) -> SResult<usize> {
+        stream_len_cond!(buf, self.get_hdr_size() + offset);
+
+        let first = (COAP_VERSION << 6) | ((self.msg_type as u8) << 4) | self.token_len;
+        let mut off = offset;
+        off = enc_consume!(buf, off; encode_u8, first);
+        off = enc_consume!(buf, off; encode_u8, self.code);
+        off = enc_consume!(buf, off; encode_u16, self.message_id);
+        off = enc_consume!(buf, off; encode_bytes, self.get_token());
+        stream_done!(off, off);
+    }
+
+    /// This function deserializes the `CoapHeader` and token from the
+    /// provided buffer. Messages with an unknown version or a token length
+    /// of 9 to 15 are format errors.
+    pub fn decode(buf: &[u8]) -> SResult<CoapHeader> {
+        stream_len_cond!(buf, COAP_HEADER_LEN);
+        let (off, first) = dec_try!(buf, 0; decode_u8);
+        let (off, code) = dec_try!(buf, off; decode_u8);
+        let (off, message_id) = dec_try!(buf, off; decode_u16);
+
+        stream_cond!(first >> 6 == COAP_VERSION);
+        let token_len = (first & 0x0f) as usize;
+        stream_cond!(token_len <= COAP_MAX_TOKEN_LEN);
+        stream_len_cond!(buf, off + token_len);
+
+        let mut header = CoapHeader::new(CoapType::from_bits(first >> 4), code, message_id);
+        header.set_token(&buf[off..off + token_len]);
+        stream_done!(off + token_len, header);
+    }
+}
+
+/// A single option borrowed from a decoded message.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CoapOption<'b> {
+    pub number: u16,
+    pub value: &'b [u8],
+}
+
+impl<'b> CoapOption<'b> {
+    /// Interprets the option value as a variable length unsigned integer.
+    pub fn as_uint(&self) -> u32 {
+        self.value
+            .iter()
+            .take(4)
+            .fold(0, |acc, b| (acc << 8) | *b as u32)
+    }
+}
+
+/// Reads an option delta or length nibble, consuming any extended bytes.
+fn decode_option_field(buf: &[u8], nibble: u8) -> SResult<u16> {
+    match nibble {
+        0..=12 => stream_done!(0, nibble as u16),
+        13 => {
+            let (off, ext) = dec_try!(buf, 0; decode_u8);
+            stream_done!(off, ext as u16 + 13);
+        }
+        14 => {
+            let (off, ext) = dec_try!(buf, 0; decode_u16);
+            stream_cond!(ext <= u16::max_value() - 269);
+            stream_done!(off, ext + 269);
+        }
+        _ => stream_err!(),
+    }
+}
+
+/// Decodes the option at the start of `buf` following an option with number
+/// `prev`. Returns `None` as output if `buf` starts with the payload marker
+/// or is empty.
+fn decode_option(buf: &[u8], prev: u16) -> SResult<Option<CoapOption>> {
+    if buf.is_empty() {
+        stream_done!(0, None);
+    }
+    let (off, first) = dec_try!(buf, 0; decode_u8);
+    if first == PAYLOAD_MARKER {
+        stream_done!(off, None);
+    }
+    let (off, delta) = dec_try!(buf, off; decode_option_field, first >> 4);
+    let (off, len) = dec_try!(buf, off; decode_option_field, first & 0x0f);
+    stream_cond!(delta <= u16::max_value() - prev);
+    stream_len_cond!(buf, off + len as usize);
+    let opt = CoapOption {
+        number: prev + delta,
+        value: &buf[off..off + len as usize],
+    };
+    stream_done!(off + len as usize, Some(opt));
+}
+
+/// Returns the nibble and the number of extended bytes used to encode an
+/// option delta or length.
+fn option_field(value: u16) -> (u8, usize) {
+    if value < 13 {
+        (value as u8, 0)
+    } else if value < 269 {
+        (13, 1)
+    } else {
+        (14, 2)
+    }
+}
+
+fn encode_option_field(buf: &mut [u8], value: u16) -> SResult {
+    match option_field(value).1 {
+        0 => stream_done!(0),
+        1 => {
+            let off = enc_consume!(buf, 0; encode_u8, (value - 13) as u8);
+            stream_done!(off);
+        }
+        _ => {
+            let off = enc_consume!(buf, 0; encode_u16, value - 269);
+            stream_done!(off);
+        }
+    }
+}
+
+/// Returns the encoded size of an option with the given delta and value
+/// length.
+pub fn option_size(delta: u16, len: usize) -> usize {
+    1 + option_field(delta).1 + option_field(len as u16).1 + len
+}
+
+/// This function serializes a single option into the provided buffer.
+///
+/// # Arguments
+///
+/// `buf` - A mutable buffer to serialize the option into
+/// `offset` - The current offset into the provided buffer
+/// `prev` - The number of the previously encoded option, or 0
+/// `number` - The number of the option to encode; must not be less than
+/// `prev`
+/// `value` - The option value
+///
+/// # Return Value
+///
+/// This function returns the new offset into the buffer wrapped in an
+/// SResult.
+pub fn encode_option(
+    buf: &mut [u8],
+    offset: usize,
+    prev: u16,
+    number: u16,
+    value: &[u8],
+) -> SResult<usize> {
+    stream_cond!(number >= prev && value.len() <= u16::max_value() as usize);
+    let delta = number - prev;
+    stream_len_cond!(buf, offset + option_size(delta, value.len()));
+
+    let (delta_nibble, _) = option_field(delta);
+    let (len_nibble, _) = option_field(value.len() as u16);
+    let mut off = offset;
+    off = enc_consume!(buf, off; encode_u8, (delta_nibble << 4) | len_nibble);
+    off = enc_consume!(buf, off; encode_option_field, delta);
+    off = enc_consume!(buf, off; encode_option_field, value.len() as u16);
+    off = enc_consume!(buf, off; encode_bytes, value);
+    stream_done!(off, off);
+}
+
+/// Encodes `value` as a minimal length unsigned integer option value into
+/// `out`, returning the number of bytes used.
+pub fn encode_uint(value: u32, out: &mut [u8; 4]) -> usize {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    let len = 4 - skip;
+    out[..len].copy_from_slice(&bytes[skip..]);
+    len
+}
+
+/// Iterator over the options of a decoded message.
+pub struct OptionIterator<'b> {
+    buf: &'b [u8],
+    offset: usize,
+    prev: u16,
+}
+
+impl<'b> Iterator for OptionIterator<'b> {
+    type Item = CoapOption<'b>;
+
+    fn next(&mut self) -> Option<CoapOption<'b>> {
+        // The options were validated when the message was decoded.
+        match decode_option(&self.buf[self.offset..], self.prev) {
+            SResult::Done(off, Some(opt)) => {
+                self.offset += off;
+                self.prev = opt.number;
+                Some(opt)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The Block1 and Block2 options (RFC 7959) used for block-wise transfers.
+/// The block size is `16 << szx` bytes, so `szx` ranges from 0 (16 bytes)
+/// to 6 (1024 bytes).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BlockOption {
+    pub num: u32,
+    pub more: bool,
+    pub szx: u8,
+}
+
+impl BlockOption {
+    pub const MAX_SZX: u8 = 6;
+
+    pub fn new(num: u32, more: bool, szx: u8) -> BlockOption {
+        BlockOption {
+            num: num,
+            more: more,
+            szx: szx,
+        }
+    }
+
+    /// Returns the largest block size exponent whose block fits in `len`
+    /// bytes, or `None` if `len` is smaller than 16 bytes.
+    pub fn szx_for_len(len: usize) -> Option<u8> {
+        (0..=Self::MAX_SZX).rev().find(|szx| 16 << szx <= len)
+    }
+
+    pub fn size(&self) -> usize {
+        16 << self.szx
+    }
+
+    /// Byte offset of this block within the whole representation.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// Decodes a block option value. An szx of 7 is reserved and rejected.
+    pub fn decode(value: &[u8]) -> Option<BlockOption> {
+        if value.len() > 3 {
+            return None;
+        }
+        let raw = value.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        let szx = (raw & 0x07) as u8;
+        if szx > Self::MAX_SZX {
+            return None;
+        }
+        Some(BlockOption::new(raw >> 4, raw & 0x08 != 0, szx))
+    }
+
+    /// Encodes the block option value into `out`, returning the number of
+    /// bytes used.
+    pub fn encode(&self, out: &mut [u8; 4]) -> usize {
+        let raw = (self.num << 4) | ((self.more as u32) << 3) | self.szx as u32;
+        encode_uint(raw, out)
+    }
+}
+
+/// A decoded CoAP message borrowing its options and payload from the
+/// receive buffer.
+#[derive(Copy, Clone, Debug)]
+pub struct CoapMessage<'b> {
+    pub header: CoapHeader,
+    options: &'b [u8],
+    pub payload: &'b [u8],
+}
+
+impl<'b> CoapMessage<'b> {
+    /// This function deserializes a complete CoAP message. All options are
+    /// validated so that iterating over them later cannot fail. Empty
+    /// messages (code 0.00) must not carry a token, options or payload, and
+    /// a payload marker followed by an empty payload is a format error.
+    pub fn decode(buf: &'b [u8]) -> SResult<CoapMessage<'b>> {
+        let (opt_start, header) = dec_try!(buf; CoapHeader::decode);
+
+        let mut off = opt_start;
+        let mut prev = 0;
+        loop {
+            match decode_option(&buf[off..], prev) {
+                SResult::Done(len, Some(opt)) => {
+                    off += len;
+                    prev = opt.number;
+                }
+                SResult::Done(len, None) => {
+                    let opt_end = off;
+                    off += len;
+                    // A payload marker must be followed by a payload.
+                    stream_cond!(len == 0 || off < buf.len());
+                    if header.code == code::EMPTY {
+                        stream_cond!(header.token_len == 0 && off == COAP_HEADER_LEN);
+                    }
+                    let msg = CoapMessage {
+                        header: header,
+                        options: &buf[opt_start..opt_end],
+                        payload: &buf[off..],
+                    };
+                    stream_done!(buf.len(), msg);
+                }
+                _ => stream_err!(),
+            }
+        }
+    }
+
+    pub fn options(&self) -> OptionIterator<'b> {
+        OptionIterator {
+            buf: self.options,
+            offset: 0,
+            prev: 0,
+        }
+    }
+
+    /// Returns the first option with the given number.
+    pub fn find_option(&self, number: u16) -> Option<CoapOption<'b>> {
+        self.options().find(|opt| opt.number == number)
+    }
+
+    pub fn block1(&self) -> Option<BlockOption> {
+        self.find_option(option::BLOCK1)
+            .and_then(|opt| BlockOption::decode(opt.value))
+    }
+
+    pub fn block2(&self) -> Option<BlockOption> {
+        self.find_option(option::BLOCK2)
+            .and_then(|opt| BlockOption::decode(opt.value))
+    }
+
+    /// Returns the first critical option that is not in `known`, if any.
+    pub fn unknown_critical_option(&self, known: &[u16]) -> Option<u16> {
+        self.options()
+            .map(|opt| opt.number)
+            .find(|number| option::is_critical(*number) && !known.contains(number))
+    }
+
+    /// Compares the Uri-Path options of the message against `path`, given as
+    /// `/`-separated segments. Leading and trailing slashes in `path` are
+    /// ignored, so `b"/sensors/temp"` matches a request for `sensors/temp`.
+    pub fn uri_path_matches(&self, path: &[u8]) -> bool {
+        let mut segments = path.split(|b| *b == b'/').filter(|s| !s.is_empty());
+        for opt in self.options().filter(|opt| opt.number == option::URI_PATH) {
+            match segments.next() {
+                Some(segment) if segment == opt.value => {}
+                _ => return false,
+            }
+        }
+        segments.next().is_none()
+    }
+}
+
+/// Helper for serializing a message into a buffer. Options must be added in
+/// ascending order of option number, followed by the payload.
+pub struct CoapMessageWriter<'b> {
+    buf: &'b mut [u8],
+    offset: usize,
+    prev: u16,
+}
+
+impl<'b> CoapMessageWriter<'b> {
+    /// Writes `header` to the start of `buf`. Returns `None` if the buffer is
+    /// too small for the header.
+    pub fn new(buf: &'b mut [u8], header: &CoapHeader) -> Option<CoapMessageWriter<'b>> {
+        let offset = header.encode(buf, 0).done()?.0;
+        Some(CoapMessageWriter {
+            buf: buf,
+            offset: offset,
+            prev: 0,
+        })
+    }
+
+    /// Number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.offset
+    }
+
+    /// Number of the last option written, or 0 if none was.
+    pub fn last_option(&self) -> u16 {
+        self.prev
+    }
+
+    /// Appends an option. Fails if options are not written in ascending
+    /// order or the buffer is full.
+    pub fn add_option(&mut self, number: u16, value: &[u8]) -> Result<(), ()> {
+        let (off, _) = encode_option(self.buf, self.offset, self.prev, number, value)
+            .done()
+            .ok_or(())?;
+        self.offset = off;
+        self.prev = number;
+        Ok(())
+    }
+
+    pub fn add_uint_option(&mut self, number: u16, value: u32) -> Result<(), ()> {
+        let mut raw = [0; 4];
+        let len = encode_uint(value, &mut raw);
+        self.add_option(number, &raw[..len])
+    }
+
+    pub fn add_block_option(&mut self, number: u16, block: BlockOption) -> Result<(), ()> {
+        let mut raw = [0; 4];
+        let len = block.encode(&mut raw);
+        self.add_option(number, &raw[..len])
+    }
+
+    /// Appends one Uri-Path option per non-empty `/`-separated segment of
+    /// `path`.
+    pub fn add_uri_path(&mut self, path: &[u8]) -> Result<(), ()> {
+        for segment in path.split(|b| *b == b'/').filter(|s| !s.is_empty()) {
+            self.add_option(option::URI_PATH, segment)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the space left for a payload after the payload marker.
+    pub fn payload_capacity(&self) -> usize {
+        self.buf.len().saturating_sub(self.offset + 1)
+    }
+
+    /// Returns the buffer the payload can be written into directly, to be
+    /// followed by a call to `finish` with the number of bytes written.
+    pub fn payload_buf(&mut self) -> &mut [u8] {
+        let start = core::cmp::min(self.offset + 1, self.buf.len());
+        &mut self.buf[start..]
+    }
+
+    /// Appends the payload marker and `payload_len` bytes previously written
+    /// into `payload_buf`, and returns the total length of the message.
+    pub fn finish(self, payload_len: usize) -> Result<usize, ()> {
+        if payload_len == 0 {
+            return Ok(self.offset);
+        }
+        if payload_len > self.payload_capacity() {
+            return Err(());
+        }
+        self.buf[self.offset] = PAYLOAD_MARKER;
+        Ok(self.offset + 1 + payload_len)
+    }
+
+    /// Copies `payload` into the message and returns its total length.
+    pub fn finish_with_payload(mut self, payload: &[u8]) -> Result<usize, ()> {
+        if payload.len() > self.payload_capacity() {
+            return Err(());
+        }
+        self.payload_buf()[..payload.len()].copy_from_slice(payload);
+        self.finish(payload.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header_round_trip() {
+        let mut header = CoapHeader::new(CoapType::Confirmable, code::GET, 0x1234);
+        header.set_token(&[0xde, 0xad, 0xbe, 0xef]);
+        let mut buf = [0; 16];
+        assert_eq!(header.encode(&mut buf, 0).done(), Some((8, 8)));
+        assert_eq!(&buf[..8], &[0x44, 0x01, 0x12, 0x34, 0xde, 0xad, 0xbe, 0xef]);
+        let (off, decoded) = CoapHeader::decode(&buf[..8]).done().unwrap();
+        assert_eq!(off, 8);
+        assert_eq!(decoded, header);
+    }
+
+    #[test]
+    fn header_rejects_bad_version_and_token_len() {
+        assert!(CoapHeader::decode(&[0x80, 0x01, 0, 0]).is_err());
+        assert!(CoapHeader::decode(&[0x49, 0x01, 0, 0]).is_err());
+        assert!(CoapHeader::decode(&[0x44, 0x01, 0, 0, 1]).is_needed());
+    }
+
+    #[test]
+    fn option_extended_encodings() {
+        let mut buf = [0; 600];
+        let value = [0xaa; 300];
+        let off = encode_option(&mut buf, 0, 0, 11, b"temp").done().unwrap().0;
+        assert_eq!(&buf[..5], &[0xb4, b't', b'e', b'm', b'p']);
+        // Delta 49 needs one extended byte, length 300 needs two.
+        let off2 = encode_option(&mut buf, off, 11, 60, &value)
+            .done()
+            .unwrap()
+            .0;
+        assert_eq!(
+            &buf[off..off + 4],
+            &[0xde, 49 - 13, 0x00, (300 - 269) as u8]
+        );
+        assert_eq!(off2, off + 4 + 300);
+        assert_eq!(option_size(49, 300), 304);
+
+        let opt = decode_option(&buf[off..off2], 11)
+            .done()
+            .unwrap()
+            .1
+            .unwrap();
+        assert_eq!(opt.number, 60);
+        assert_eq!(opt.value.len(), 300);
+    }
+
+    #[test]
+    fn option_out_of_order_rejected() {
+        let mut buf = [0; 8];
+        assert!(encode_option(&mut buf, 0, 12, 11, b"x").is_err());
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let mut buf = [0; 64];
+        let mut header = CoapHeader::new(CoapType::NonConfirmable, code::PUT, 7);
+        header.set_token(&[1, 2]);
+        let mut writer = CoapMessageWriter::new(&mut buf, &header).unwrap();
+        writer.add_uri_path(b"/sensors/temp").unwrap();
+        writer.add_uint_option(option::CONTENT_FORMAT, 0).unwrap();
+        writer
+            .add_block_option(option::BLOCK2, BlockOption::new(3, true, 2))
+            .unwrap();
+        assert!(writer.add_option(option::URI_PATH, b"late").is_err());
+        let len = writer.finish_with_payload(b"21.5").unwrap();
+
+        let msg = CoapMessage::decode(&buf[..len]).done().unwrap().1;
+        assert_eq!(msg.header, header);
+        assert_eq!(msg.payload, b"21.5");
+        assert!(msg.uri_path_matches(b"sensors/temp"));
+        assert!(msg.uri_path_matches(b"/sensors/temp/"));
+        assert!(!msg.uri_path_matches(b"sensors"));
+        assert!(!msg.uri_path_matches(b"sensors/temp/x"));
+        assert_eq!(msg.block2(), Some(BlockOption::new(3, true, 2)));
+        assert_eq!(msg.block1(), None);
+        assert!(msg
+            .find_option(option::CONTENT_FORMAT)
+            .unwrap()
+            .value
+            .is_empty());
+        let mut numbers = msg.options().map(|opt| opt.number);
+        assert_eq!(numbers.next(), Some(option::URI_PATH));
+        assert_eq!(numbers.next(), Some(option::URI_PATH));
+        assert_eq!(numbers.next(), Some(option::CONTENT_FORMAT));
+        assert_eq!(numbers.next(), Some(option::BLOCK2));
+        assert_eq!(numbers.next(), None);
+        assert_eq!(
+            msg.unknown_critical_option(&[option::URI_PATH]),
+            Some(option::BLOCK2)
+        );
+        assert_eq!(
+            msg.unknown_critical_option(&[option::URI_PATH, option::BLOCK2]),
+            None
+        );
+    }
+
+    #[test]
+    fn message_format_errors() {
+        // Payload marker without payload.
+        assert!(CoapMessage::decode(&[0x40, 0x01, 0, 1, 0xff]).is_err());
+        // Reserved option delta 15.
+        assert!(CoapMessage::decode(&[0x40, 0x01, 0, 1, 0xf1, 0]).is_err());
+        // Option value runs past the end of the message.
+        assert!(CoapMessage::decode(&[0x40, 0x01, 0, 1, 0xb4, b'a']).is_err());
+        // Empty message with a token.
+        assert!(CoapMessage::decode(&[0x61, 0x00, 0, 1, 9]).is_err());
+        // Empty ACK.
+        let msg = CoapMessage::decode(&[0x60, 0x00, 0x12, 0x34])
+            .done()
+            .unwrap()
+            .1;
+        assert_eq!(msg.header.msg_type, CoapType::Acknowledgement);
+        assert_eq!(msg.header.message_id, 0x1234);
+        assert!(msg.payload.is_empty());
+    }
+
+    #[test]
+    fn unknown_critical_option_detected() {
+        let mut buf = [0; 32];
+        let header = CoapHeader::new(CoapType::Confirmable, code::GET, 1);
+        let mut writer = CoapMessageWriter::new(&mut buf, &header).unwrap();
+        writer.add_uri_path(b"a").unwrap();
+        writer.add_option(option::URI_QUERY, b"x=1").unwrap();
+        writer.add_option(option::SIZE2, &[]).unwrap();
+        let len = writer.finish(0).unwrap();
+        let msg = CoapMessage::decode(&buf[..len]).done().unwrap().1;
+        // Size2 (28) is elective, Uri-Query (15) is critical.
+        assert_eq!(
+            msg.unknown_critical_option(&[option::URI_PATH]),
+            Some(option::URI_QUERY)
+        );
+    }
+
+    #[test]
+    fn block_option_values() {
+        let mut raw = [0; 4];
+        assert_eq!(BlockOption::new(0, false, 0).encode(&mut raw), 0);
+        let block = BlockOption::new(21, true, 6);
+        let len = block.encode(&mut raw);
+        assert_eq!(&raw[..len], &[0x01, 0x5e]);
+        assert_eq!(BlockOption::decode(&raw[..len]), Some(block));
+        assert_eq!(block.size(), 1024);
+        assert_eq!(block.offset(), 21 * 1024);
+        assert_eq!(BlockOption::decode(&[0x07]), None);
+        assert_eq!(
+            BlockOption::decode(&[]),
+            Some(BlockOption::new(0, false, 0))
+        );
+        assert_eq!(BlockOption::szx_for_len(100), Some(2));
+        assert_eq!(BlockOption::szx_for_len(4096), Some(6));
+        assert_eq!(BlockOption::szx_for_len(15), None);
+    }
+}
@@ -0,0 +1,1144 @@
+//! This file implements the CoAP message layer (RFC 7252) on top of the UDP
+//! stack, and the request/response layer used by kernel capsules.
+//!
+//! The `CoapLayer` binds to a UDP port (5683 by default) and acts as both a
+//! client and a server:
+//!
+//! - A `CoapClient` can have a single request outstanding at a time. Requests
+//!   may be confirmable, in which case they are retransmitted with
+//!   exponential backoff until they are acknowledged or `MAX_RETRANSMIT`
+//!   retransmissions have been sent. Responses are matched to the request by
+//!   their token, and both piggybacked and separate responses are
+//!   supported. If a response carries a Block2 option with the "more" flag
+//!   set, the layer requests the following blocks automatically and passes
+//!   each of them to the client.
+//! - A `CoapServer` is handed every incoming request along with a buffer for
+//!   the response payload. Responses to confirmable requests are piggybacked
+//!   on the acknowledgement. If the server's representation does not fit
+//!   in a single message, the layer responds block-wise using the Block2
+//!   option; Block1 options of incoming requests are echoed in the response
+//!   so that servers can accept block-wise uploads.
+//!
+//! The layer only ever transmits from its single send buffer, so a reply
+//! that cannot be sent because a previous datagram is still in flight is
+//! dropped; the peer recovers from this through its own retransmissions.
+//! A request that UDP fails to send the first time is completed with the
+//! error; later retransmissions are only retried at the next timeout.
+//!
+//! The layer remembers the last request and the last separate response it
+//! received to recognize their duplicates. A duplicate request is answered
+//! with the response code sent the first time, without passing it to the
+//! server again, except for GET requests, which are served again so that
+//! the payload can be sent. A duplicate response is acknowledged again but
+//! not delivered to the client.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//! # use capsules::net::coap::coap_layer::CoapLayer;
+//! # use capsules::net::coap::coap::COAP_PORT;
+//!
+//! let coap = static_init!(
+//!     CoapLayer<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     CoapLayer::new(
+//!         udp_send,
+//!         udp_recv,
+//!         udp_port_table,
+//!         coap_alarm,
+//!         LeasableBuffer::new(&mut COAP_SEND_BUF),
+//!         &mut COAP_REQUEST_BUF,
+//!         net_cap,
+//!     )
+//! );
+//! udp_send.set_client(coap);
+//! udp_recv.set_client(coap);
+//! coap_alarm.set_client(coap);
+//! coap.bind(COAP_PORT);
+//! ```
+
+use crate::net::coap::coap::{code, option};
+use crate::net::coap::coap::{encode_option, PAYLOAD_MARKER};
+use crate::net::coap::coap::{BlockOption, CoapHeader, CoapMessage, CoapMessageWriter, CoapType};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSender};
+use core::cell::Cell;
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+
+/// Initial acknowledgement timeout for confirmable requests.
+pub const ACK_TIMEOUT_SECONDS: u32 = 2;
+/// Number of retransmissions of a confirmable request before giving up.
+pub const MAX_RETRANSMIT: u8 = 4;
+/// How long to wait for a separate response, or for the response to a
+/// non-confirmable request.
+pub const RESPONSE_TIMEOUT_SECONDS: u32 = 30;
+
+/// Largest encoded size of a Block1 or Block2 option in a response.
+const MAX_BLOCK_OPTION_LEN: usize = 5;
+
+/// Critical options the layer or its clients know how to process. Requests
+/// carrying any other critical option are rejected with 4.02 Bad Option.
+const KNOWN_CRITICAL_OPTIONS: [u16; 7] = [
+    option::URI_HOST,
+    option::URI_PORT,
+    option::URI_PATH,
+    option::URI_QUERY,
+    option::ACCEPT,
+    option::BLOCK2,
+    option::BLOCK1,
+];
+
+/// Clients of the `CoapLayer` that send requests implement this trait to
+/// receive responses.
+pub trait CoapClient {
+    /// Called for every response to the outstanding request. For block-wise
+    /// responses this is called once per block, with `block` describing the
+    /// position of `payload` in the whole representation; the request is
+    /// complete once a block without the "more" flag is delivered. If the
+    /// request was reset by the peer or no response arrived in time, `result`
+    /// is `ECANCEL` or `FAIL` respectively and `code` is `code::EMPTY`. If
+    /// the request could not be sent, `result` is the error UDP reported.
+    fn response(&self, result: ReturnCode, code: u8, block: Option<BlockOption>, payload: &[u8]);
+}
+
+/// What a `CoapServer` wrote in response to a request.
+#[derive(Copy, Clone, Debug)]
+pub struct CoapResponse {
+    /// Response code, e.g. `code::CONTENT`.
+    pub code: u8,
+    /// Number of payload bytes written into the response buffer.
+    pub len: usize,
+    /// Total length of the representation the payload was taken from. If
+    /// this is larger than `len`, the response is sent block-wise.
+    pub total_len: usize,
+}
+
+impl CoapResponse {
+    /// A response without payload.
+    pub fn empty(code: u8) -> CoapResponse {
+        CoapResponse {
+            code: code,
+            len: 0,
+            total_len: 0,
+        }
+    }
+}
+
+/// Clients of the `CoapLayer` that serve resources implement this trait.
+pub trait CoapServer {
+    /// Handles an incoming request from `src_addr`:`src_port`. The server
+    /// copies the representation starting at byte `offset` into `response`,
+    /// writing at most `response.len()` bytes, and returns the response
+    /// code and lengths. `offset` is only non-zero when the client asks for
+    /// a later block of a block-wise response.
+    fn request(
+        &self,
+        src_addr: IPAddr,
+        src_port: u16,
+        request: &CoapMessage,
+        offset: usize,
+        response: &mut [u8],
+    ) -> CoapResponse;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum RequestState {
+    /// Waiting for the acknowledgement of a confirmable request.
+    AwaitingAck,
+    /// Waiting for a separate response, or the response to a
+    /// non-confirmable request.
+    AwaitingResponse,
+}
+
+/// The outstanding request. The encoded message is kept in the request
+/// buffer so it can be retransmitted.
+#[derive(Copy, Clone)]
+struct Request {
+    dest: IPAddr,
+    port: u16,
+    message_id: u16,
+    token: u16,
+    confirmable: bool,
+    state: RequestState,
+    retransmits: u8,
+    timeout: u32,
+    /// Length of the encoded request.
+    len: usize,
+    /// Offset and number of the last option after which a Block2 option is
+    /// written to request further blocks. Only requests without a payload
+    /// can be continued this way.
+    options_end: usize,
+    last_option: u16,
+    has_payload: bool,
+}
+
+/// A message received from a peer, to recognize its duplicates by.
+#[derive(Copy, Clone, PartialEq)]
+struct Received {
+    src_addr: IPAddr,
+    src_port: u16,
+    message_id: u16,
+}
+
+pub struct CoapLayer<'a, A: Alarm<'a>> {
+    udp_sender: &'a dyn UDPSender<'a>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    alarm: &'a A,
+    send_buffer: MapCell<LeasableBuffer<'static, u8>>,
+    request_buffer: TakeCell<'static, [u8]>,
+    request: OptionalCell<Request>,
+    /// The message ID of the request in the send buffer, if it holds one.
+    sent_request: Cell<Option<u16>>,
+    /// The last request received, with the code of the response to it.
+    last_request: Cell<Option<(Received, u8)>>,
+    /// The last separate response received.
+    last_response: Cell<Option<Received>>,
+    next_message_id: Cell<u16>,
+    next_token: Cell<u16>,
+    client: OptionalCell<&'a dyn CoapClient>,
+    server: OptionalCell<&'a dyn CoapServer>,
+    net_cap: &'static NetworkCapability,
+}
+
+impl<'a, A: Alarm<'a>> CoapLayer<'a, A> {
+    pub fn new(
+        udp_sender: &'a dyn UDPSender<'a>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        alarm: &'a A,
+        send_buffer: LeasableBuffer<'static, u8>,
+        request_buffer: &'static mut [u8],
+        net_cap: &'static NetworkCapability,
+    ) -> CoapLayer<'a, A> {
+        CoapLayer {
+            udp_sender: udp_sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            alarm: alarm,
+            send_buffer: MapCell::new(send_buffer),
+            request_buffer: TakeCell::new(request_buffer),
+            request: OptionalCell::empty(),
+            sent_request: Cell::new(None),
+            last_request: Cell::new(None),
+            last_response: Cell::new(None),
+            next_message_id: Cell::new(1),
+            next_token: Cell::new(1),
+            client: OptionalCell::empty(),
+            server: OptionalCell::empty(),
+            net_cap: net_cap,
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn CoapClient) {
+        self.client.set(client);
+    }
+
+    pub fn set_server(&self, server: &'a dyn CoapServer) {
+        self.server.set(server);
+    }
+
+    /// Seeds the message ID and token sequences. Boards with a random number
+    /// generator should call this with a random value so that IDs are not
+    /// reused across reboots.
+    pub fn set_seed(&self, seed: u32) {
+        self.next_message_id.set(seed as u16);
+        self.next_token.set((seed >> 16) as u16);
+    }
+
+    /// Binds the layer to `port` for both sending and receiving. Returns
+    /// `EALREADY` if the layer is already bound and `EBUSY` if the port is
+    /// in use or not permitted by the network capability.
+    pub fn bind(&self, port: u16) -> ReturnCode {
+        if self.udp_sender.is_bound() || self.udp_receiver.is_bound() {
+            return ReturnCode::EALREADY;
+        }
+        match self.port_table.create_socket() {
+            Ok(socket) => match self.port_table.bind(socket, port, self.net_cap) {
+                Ok((send_binding, recv_binding)) => {
+                    self.udp_sender.set_binding(send_binding);
+                    self.udp_receiver.set_binding(recv_binding);
+                    ReturnCode::SUCCESS
+                }
+                // Dropping the socket releases it.
+                Err(_socket) => ReturnCode::EBUSY,
+            },
+            Err(rcode) => rcode,
+        }
+    }
+
+    /// Returns true if a request is outstanding.
+    pub fn is_busy(&self) -> bool {
+        self.request.is_some()
+    }
+
+    /// Sends a request with the given method to `dest`:`port` for the
+    /// resource at `path` (`/`-separated). The response is passed to the
+    /// client set with `set_client`. Returns `EBUSY` if a request is already
+    /// outstanding and `ESIZE` if the request does not fit in the request
+    /// buffer.
+    pub fn request(
+        &self,
+        dest: IPAddr,
+        port: u16,
+        method: u8,
+        path: &[u8],
+        payload: &[u8],
+        confirmable: bool,
+    ) -> ReturnCode {
+        if !code::is_request(method) {
+            return ReturnCode::EINVAL;
+        }
+        if self.request.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if !self.udp_sender.is_bound() {
+            return ReturnCode::ERESERVE;
+        }
+
+        let message_id = self.new_message_id();
+        let token = self.next_token.get();
+        self.next_token.set(token.wrapping_add(1));
+
+        let msg_type = if confirmable {
+            CoapType::Confirmable
+        } else {
+            CoapType::NonConfirmable
+        };
+        let mut header = CoapHeader::new(msg_type, method, message_id);
+        header.set_token(&token.to_be_bytes());
+
+        let encoded = self.request_buffer.map_or(None, |buf| {
+            let mut writer = CoapMessageWriter::new(buf, &header)?;
+            writer.add_uri_path(path).ok()?;
+            let options_end = writer.len();
+            let last_option = writer.last_option();
+            let len = writer.finish_with_payload(payload).ok()?;
+            Some((len, options_end, last_option))
+        });
+        let (len, options_end, last_option) = match encoded {
+            Some(encoded) => encoded,
+            None => return ReturnCode::ESIZE,
+        };
+
+        let state = if confirmable {
+            RequestState::AwaitingAck
+        } else {
+            RequestState::AwaitingResponse
+        };
+        self.request.set(Request {
+            dest: dest,
+            port: port,
+            message_id: message_id,
+            token: token,
+            confirmable: confirmable,
+            state: state,
+            retransmits: 0,
+            timeout: self.initial_timeout(confirmable, message_id),
+            len: len,
+            options_end: options_end,
+            last_option: last_option,
+            has_payload: !payload.is_empty(),
+        });
+        let result = self.send_request();
+        if result != ReturnCode::SUCCESS {
+            self.request.clear();
+        }
+        result
+    }
+
+    /// Abandons the outstanding request, if any. No further responses to it
+    /// are delivered to the client.
+    pub fn cancel(&self) {
+        if self.request.take().is_some() {
+            self.alarm.disable();
+        }
+    }
+
+    fn new_message_id(&self) -> u16 {
+        let id = self.next_message_id.get();
+        self.next_message_id.set(id.wrapping_add(1));
+        id
+    }
+
+    /// The initial timeout is chosen between `ACK_TIMEOUT` and 1.5 times
+    /// `ACK_TIMEOUT` (the default `ACK_RANDOM_FACTOR`). The message ID stands
+    /// in for a random number to spread retransmissions of different nodes.
+    fn initial_timeout(&self, confirmable: bool, message_id: u16) -> u32 {
+        let freq = <A::Frequency>::frequency();
+        if confirmable {
+            let base = freq * ACK_TIMEOUT_SECONDS;
+            base + (base / 32) * (message_id as u32 % 16)
+        } else {
+            freq * RESPONSE_TIMEOUT_SECONDS
+        }
+    }
+
+    fn set_timeout(&self, tics: u32) {
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(tics));
+    }
+
+    /// (Re)transmits the stored request and arms the timeout.
+    fn send_request(&self) -> ReturnCode {
+        self.request.map_or(ReturnCode::FAIL, |req| {
+            let result = self.request_buffer.map_or(ReturnCode::ENOMEM, |buf| {
+                let msg = &buf[..req.len];
+                self.transmit(req.dest, req.port, |dgram| {
+                    if msg.len() > dgram.len() {
+                        return None;
+                    }
+                    dgram[..msg.len()].copy_from_slice(msg);
+                    Some(msg.len())
+                })
+            });
+            if result == ReturnCode::SUCCESS {
+                self.sent_request.set(Some(req.message_id));
+            }
+            // A busy send buffer is not fatal for retransmissions, the next
+            // timeout will try again.
+            if result == ReturnCode::SUCCESS || req.retransmits > 0 {
+                self.set_timeout(req.timeout);
+            }
+            result
+        })
+    }
+
+    /// Lets `encode` write a message into the send buffer and passes it to
+    /// UDP. `encode` returns the length of the message, or `None` if it did
+    /// not fit.
+    fn transmit<F>(&self, dest: IPAddr, port: u16, encode: F) -> ReturnCode
+    where
+        F: FnOnce(&mut [u8]) -> Option<usize>,
+    {
+        self.send_buffer
+            .take()
+            .map_or(ReturnCode::EBUSY, |mut dgram| {
+                match encode(&mut dgram[..]) {
+                    Some(len) => {
+                        dgram.slice(0..len);
+                        match self.udp_sender.send_to(dest, port, dgram, self.net_cap) {
+                            Ok(()) => ReturnCode::SUCCESS,
+                            Err(mut dgram) => {
+                                dgram.reset();
+                                self.send_buffer.replace(dgram);
+                                ReturnCode::FAIL
+                            }
+                        }
+                    }
+                    None => {
+                        self.send_buffer.replace(dgram);
+                        ReturnCode::ESIZE
+                    }
+                }
+            })
+    }
+
+    /// Sends an empty acknowledgement or reset for `message_id`.
+    fn send_empty(&self, dest: IPAddr, port: u16, msg_type: CoapType, message_id: u16) {
+        let header = CoapHeader::new(msg_type, code::EMPTY, message_id);
+        self.transmit(dest, port, |buf| header.encode(buf, 0).done().map(|r| r.0));
+    }
+
+    /// Finishes the outstanding request and notifies the client.
+    fn complete(&self, result: ReturnCode, code: u8, block: Option<BlockOption>, payload: &[u8]) {
+        self.request.clear();
+        self.alarm.disable();
+        self.client
+            .map(|client| client.response(result, code, block, payload));
+    }
+
+    /// Rewrites the stored request to ask for the block following `block`.
+    /// The new request gets a fresh message ID but keeps its token.
+    fn request_next_block(&self, mut req: Request, block: BlockOption) -> ReturnCode {
+        if req.has_payload {
+            return ReturnCode::ENOSUPPORT;
+        }
+        let next = BlockOption::new(block.num + 1, false, block.szx);
+        let mut raw = [0; 4];
+        let raw_len = next.encode(&mut raw);
+        req.message_id = self.new_message_id();
+        let len = self.request_buffer.map_or(None, |buf| {
+            buf[2..4].copy_from_slice(&req.message_id.to_be_bytes());
+            encode_option(
+                buf,
+                req.options_end,
+                req.last_option,
+                option::BLOCK2,
+                &raw[..raw_len],
+            )
+            .done()
+            .map(|r| r.0)
+        });
+        match len {
+            Some(len) => {
+                req.len = len;
+                req.state = if req.confirmable {
+                    RequestState::AwaitingAck
+                } else {
+                    RequestState::AwaitingResponse
+                };
+                req.retransmits = 0;
+                req.timeout = self.initial_timeout(req.confirmable, req.message_id);
+                self.request.set(req);
+                self.send_request()
+            }
+            None => ReturnCode::ESIZE,
+        }
+    }
+
+    /// Handles a response to the outstanding request.
+    fn deliver_response(&self, req: Request, msg: &CoapMessage) {
+        let block = msg.block2();
+        match block {
+            Some(block) if block.more && code::class(msg.header.code) == 2 => {
+                self.client.map(|client| {
+                    client.response(
+                        ReturnCode::SUCCESS,
+                        msg.header.code,
+                        Some(block),
+                        msg.payload,
+                    )
+                });
+                let result = self.request_next_block(req, block);
+                if result != ReturnCode::SUCCESS {
+                    self.complete(result, code::EMPTY, None, &[]);
+                }
+            }
+            _ => self.complete(ReturnCode::SUCCESS, msg.header.code, block, msg.payload),
+        }
+    }
+
+    /// Handles acknowledgements, resets and responses.
+    fn handle_reply(&self, src_addr: IPAddr, src_port: u16, msg: &CoapMessage) {
+        let header = &msg.header;
+        let req = match self.request.map(|req| *req) {
+            Some(req) if req.dest == src_addr && req.port == src_port => Some(req),
+            _ => None,
+        };
+        let token_matches = |req: &Request| header.get_token() == &req.token.to_be_bytes()[..];
+        let received = Received {
+            src_addr: src_addr,
+            src_port: src_port,
+            message_id: header.message_id,
+        };
+
+        match header.msg_type {
+            CoapType::Acknowledgement | CoapType::Reset => match req {
+                Some(mut req) if req.message_id == header.message_id => {
+                    if header.msg_type == CoapType::Reset {
+                        self.complete(ReturnCode::ECANCEL, code::EMPTY, None, &[]);
+                    } else if header.code == code::EMPTY {
+                        // The response will follow in a separate message.
+                        req.state = RequestState::AwaitingResponse;
+                        req.timeout = <A::Frequency>::frequency() * RESPONSE_TIMEOUT_SECONDS;
+                        self.request.set(req);
+                        self.set_timeout(req.timeout);
+                    } else if token_matches(&req) {
+                        self.deliver_response(req, msg);
+                    }
+                }
+                _ => {}
+            },
+            CoapType::Confirmable | CoapType::NonConfirmable => match req {
+                _ if self.last_response.get() == Some(received) => {
+                    // Our acknowledgement was lost.
+                    if header.msg_type == CoapType::Confirmable {
+                        self.send_empty(
+                            src_addr,
+                            src_port,
+                            CoapType::Acknowledgement,
+                            header.message_id,
+                        );
+                    }
+                }
+                Some(req) if token_matches(&req) => {
+                    self.last_response.set(Some(received));
+                    if header.msg_type == CoapType::Confirmable {
+                        self.send_empty(
+                            src_addr,
+                            src_port,
+                            CoapType::Acknowledgement,
+                            header.message_id,
+                        );
+                    }
+                    self.deliver_response(req, msg);
+                }
+                _ => {
+                    // Reject responses we are not waiting for.
+                    if header.msg_type == CoapType::Confirmable {
+                        self.send_empty(src_addr, src_port, CoapType::Reset, header.message_id);
+                    }
+                }
+            },
+        }
+    }
+
+    /// Handles an incoming request by asking the server for the response and
+    /// sending it back, block-wise if necessary.
+    fn handle_request(&self, src_addr: IPAddr, src_port: u16, msg: &CoapMessage) {
+        let received = Received {
+            src_addr: src_addr,
+            src_port: src_port,
+            message_id: msg.header.message_id,
+        };
+        // GET requests have no side effects, so they are served again.
+        let duplicate_code = match self.last_request.get() {
+            Some((last, code)) if last == received && msg.header.code != code::GET => Some(code),
+            _ => None,
+        };
+        let confirmable = msg.header.msg_type == CoapType::Confirmable;
+        let mut header = if confirmable {
+            CoapHeader::new(
+                CoapType::Acknowledgement,
+                code::EMPTY,
+                msg.header.message_id,
+            )
+        } else {
+            CoapHeader::new(CoapType::NonConfirmable, code::EMPTY, self.new_message_id())
+        };
+        header.set_token(msg.header.get_token());
+
+        self.transmit(src_addr, src_port, |buf| {
+            // Reserve room for the header, a Block2 and a Block1 option and
+            // the payload marker ahead of the payload.
+            let payload_start = header.get_hdr_size() + 2 * MAX_BLOCK_OPTION_LEN + 1;
+            let capacity = buf.len().checked_sub(payload_start)?;
+            let max_szx = BlockOption::szx_for_len(capacity)?;
+            let requested = msg.block2();
+
+            let (offset, space) = match requested {
+                Some(block) => {
+                    let szx = core::cmp::min(block.szx, max_szx);
+                    let block = BlockOption::new(block.num, false, szx);
+                    (block.offset(), block.size())
+                }
+                None => (0, capacity),
+            };
+
+            let mut response = if let Some(code) = duplicate_code {
+                CoapResponse::empty(code)
+            } else if msg
+                .unknown_critical_option(&KNOWN_CRITICAL_OPTIONS)
+                .is_some()
+            {
+                CoapResponse::empty(code::BAD_OPTION)
+            } else {
+                self.server
+                    .map_or(CoapResponse::empty(code::NOT_FOUND), |server| {
+                        server.request(
+                            src_addr,
+                            src_port,
+                            msg,
+                            offset,
+                            &mut buf[payload_start..payload_start + space],
+                        )
+                    })
+            };
+            response.len = core::cmp::min(response.len, space);
+
+            // Work out whether the response has to be sent block-wise.
+            let mut block2 = None;
+            if code::class(response.code) == 2 {
+                match requested {
+                    Some(block) => {
+                        if offset > 0 && offset >= response.total_len {
+                            response = CoapResponse::empty(code::BAD_OPTION);
+                        } else {
+                            let szx = core::cmp::min(block.szx, max_szx);
+                            let more = offset + response.len < response.total_len;
+                            block2 = Some(BlockOption::new(block.num, more, szx));
+                        }
+                    }
+                    None => {
+                        if response.total_len > response.len
+                            || response.len > BlockOption::new(0, false, max_szx).size()
+                        {
+                            let block = BlockOption::new(0, true, max_szx);
+                            response.len = core::cmp::min(response.len, block.size());
+                            block2 = Some(block);
+                        }
+                    }
+                }
+            }
+
+            header.code = response.code;
+            self.last_request.set(Some((received, response.code)));
+            let mut writer = CoapMessageWriter::new(buf, &header)?;
+            if let Some(block) = block2 {
+                writer.add_block_option(option::BLOCK2, block).ok()?;
+            }
+            if code::class(response.code) == 2 {
+                if let Some(block) = msg.block1() {
+                    writer.add_block_option(option::BLOCK1, block).ok()?;
+                }
+            }
+            let options_end = writer.len();
+            if response.len == 0 {
+                return Some(options_end);
+            }
+            buf[options_end] = PAYLOAD_MARKER;
+            buf.copy_within(payload_start..payload_start + response.len, options_end + 1);
+            Some(options_end + 1 + response.len)
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> time::AlarmClient for CoapLayer<'a, A> {
+    fn fired(&self) {
+        self.request.take().map(|mut req| {
+            if req.state == RequestState::AwaitingAck && req.retransmits < MAX_RETRANSMIT {
+                req.retransmits += 1;
+                req.timeout = req.timeout.wrapping_mul(2);
+                self.request.set(req);
+                self.send_request();
+            } else {
+                self.request.set(req);
+                self.complete(ReturnCode::FAIL, code::EMPTY, None, &[]);
+            }
+        });
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPSendClient for CoapLayer<'a, A> {
+    fn send_done(&self, result: ReturnCode, mut dgram: LeasableBuffer<'static, u8>) {
+        dgram.reset();
+        self.send_buffer.replace(dgram);
+        let sent_request = self.sent_request.take();
+        // Unless an earlier copy of the request may still be answered, the
+        // request is lost.
+        let lost = result != ReturnCode::SUCCESS
+            && self.request.map_or(false, |req| {
+                sent_request == Some(req.message_id) && req.retransmits == 0
+            });
+        if lost {
+            self.complete(result, code::EMPTY, None, &[]);
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> UDPRecvClient for CoapLayer<'a, A> {
+    fn receive(
+        &self,
+        src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        let msg = match CoapMessage::decode(payload).done() {
+            Some((_, msg)) => msg,
+            None => {
+                // Malformed confirmable messages are rejected with a reset,
+                // anything else is silently dropped.
+                if let Some((_, header)) = CoapHeader::decode(payload).done() {
+                    if header.msg_type == CoapType::Confirmable {
+                        self.send_empty(src_addr, src_port, CoapType::Reset, header.message_id);
+                    }
+                }
+                return;
+            }
+        };
+
+        let header = msg.header;
+        if code::is_request(header.code) {
+            match header.msg_type {
+                CoapType::Confirmable | CoapType::NonConfirmable => {
+                    self.handle_request(src_addr, src_port, &msg)
+                }
+                _ => {}
+            }
+        } else if code::is_response(header.code) || header.code == code::EMPTY {
+            if header.code == code::EMPTY && header.msg_type == CoapType::Confirmable {
+                // A CoAP ping is answered with a reset.
+                self.send_empty(src_addr, src_port, CoapType::Reset, header.message_id);
+            } else {
+                self.handle_reply(src_addr, src_port, &msg);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::net::coap::coap::COAP_PORT;
+    use crate::net::network_capabilities::{AddrRange, PortRange, UdpVisibilityCapability};
+    use crate::net::udp::udp::UDPHeader;
+    use crate::net::udp::udp_port_table::{SocketBindingEntry, UdpPortBindingTx};
+    use crate::test_util::{leak, SimAlarm};
+    use core::cell::RefCell;
+    use kernel::capabilities::UdpDriverCapability;
+    use std::vec::Vec;
+
+    const PEER_PORT: u16 = 61616;
+
+    type TestLayer = CoapLayer<'static, SimAlarm<'static>>;
+
+    /// A UDP sender that keeps each datagram until the test completes it.
+    struct TestUdp {
+        client: OptionalCell<&'static dyn UDPSendClient>,
+        in_flight: MapCell<LeasableBuffer<'static, u8>>,
+        sent: RefCell<Vec<(IPAddr, u16, Vec<u8>)>>,
+    }
+
+    impl TestUdp {
+        /// Completes the datagram in flight with `result`.
+        fn complete(&self, result: ReturnCode) {
+            let dgram = self.in_flight.take().expect("nothing sent");
+            self.client
+                .map(move |client| client.send_done(result, dgram));
+        }
+
+        /// The datagrams sent since the last call, each completed.
+        fn sent(&self) -> Vec<Vec<u8>> {
+            if self.in_flight.is_some() {
+                self.complete(ReturnCode::SUCCESS);
+            }
+            self.sent
+                .borrow_mut()
+                .drain(..)
+                .map(|(_, _, dgram)| dgram)
+                .collect()
+        }
+    }
+
+    impl UDPSender<'static> for TestUdp {
+        fn set_client(&self, client: &'static dyn UDPSendClient) {
+            self.client.set(client);
+        }
+
+        fn send_to(
+            &'static self,
+            dest: IPAddr,
+            dst_port: u16,
+            buf: LeasableBuffer<'static, u8>,
+            _net_cap: &'static NetworkCapability,
+        ) -> Result<(), LeasableBuffer<'static, u8>> {
+            if self.in_flight.is_some() {
+                return Err(buf);
+            }
+            self.sent
+                .borrow_mut()
+                .push((dest, dst_port, buf[..].to_vec()));
+            self.in_flight.replace(buf);
+            Ok(())
+        }
+
+        fn driver_send_to(
+            &'static self,
+            _dest: IPAddr,
+            _dst_port: u16,
+            _src_port: u16,
+            buf: LeasableBuffer<'static, u8>,
+            _driver_send_cap: &dyn UdpDriverCapability,
+            _net_cap: &'static NetworkCapability,
+        ) -> Result<(), LeasableBuffer<'static, u8>> {
+            Err(buf)
+        }
+
+        fn send(
+            &'static self,
+            _dest: IPAddr,
+            _udp_header: UDPHeader,
+            buf: LeasableBuffer<'static, u8>,
+            _net_cap: &'static NetworkCapability,
+        ) -> Result<(), LeasableBuffer<'static, u8>> {
+            Err(buf)
+        }
+
+        fn get_binding(&self) -> Option<UdpPortBindingTx> {
+            None
+        }
+
+        fn is_bound(&self) -> bool {
+            true
+        }
+
+        fn set_binding(&self, binding: UdpPortBindingTx) -> Option<UdpPortBindingTx> {
+            Some(binding)
+        }
+    }
+
+    #[derive(Default)]
+    struct TestClient {
+        responses: RefCell<Vec<(ReturnCode, u8, Vec<u8>)>>,
+    }
+
+    impl CoapClient for TestClient {
+        fn response(
+            &self,
+            result: ReturnCode,
+            code: u8,
+            _block: Option<BlockOption>,
+            payload: &[u8],
+        ) {
+            self.responses
+                .borrow_mut()
+                .push((result, code, payload.to_vec()));
+        }
+    }
+
+    /// Serves "hello" to GET requests and takes PUT requests, counting them.
+    #[derive(Default)]
+    struct TestServer {
+        requests: Cell<usize>,
+    }
+
+    impl CoapServer for TestServer {
+        fn request(
+            &self,
+            _src_addr: IPAddr,
+            _src_port: u16,
+            request: &CoapMessage,
+            _offset: usize,
+            response: &mut [u8],
+        ) -> CoapResponse {
+            self.requests.set(self.requests.get() + 1);
+            match request.header.code {
+                code::GET => {
+                    response[..5].copy_from_slice(b"hello");
+                    CoapResponse {
+                        code: code::CONTENT,
+                        len: 5,
+                        total_len: 5,
+                    }
+                }
+                _ => CoapResponse::empty(code::CHANGED),
+            }
+        }
+    }
+
+    struct Harness {
+        udp: &'static TestUdp,
+        alarm: &'static SimAlarm<'static>,
+        layer: &'static TestLayer,
+        client: &'static TestClient,
+        server: &'static TestServer,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let udp = leak(TestUdp {
+                client: OptionalCell::empty(),
+                in_flight: MapCell::empty(),
+                sent: RefCell::new(Vec::new()),
+            });
+            let alarm = leak(SimAlarm::new(0));
+            let udp_vis = leak(UdpVisibilityCapability::for_tests());
+            let port_table = leak(UdpPortManager::for_tests(
+                leak([None::<SocketBindingEntry>; 1]),
+                udp_vis,
+            ));
+            let layer = leak(CoapLayer::new(
+                &*udp,
+                leak(UDPReceiver::new()),
+                port_table,
+                &*alarm,
+                LeasableBuffer::new(leak([0; 128])),
+                leak([0; 128]),
+                leak(NetworkCapability::for_tests(
+                    AddrRange::Any,
+                    PortRange::Any,
+                    PortRange::Any,
+                )),
+            ));
+            let client = leak(TestClient::default());
+            let server = leak(TestServer::default());
+            udp.set_client(layer);
+            alarm.set_client(layer);
+            layer.set_client(client);
+            layer.set_server(server);
+            Harness {
+                udp: udp,
+                alarm: alarm,
+                layer: layer,
+                client: client,
+                server: server,
+            }
+        }
+
+        fn request(&self, method: u8, confirmable: bool) -> ReturnCode {
+            self.layer
+                .request(peer(), PEER_PORT, method, b"res", &[], confirmable)
+        }
+
+        fn receive(&self, msg_type: CoapType, code: u8, message_id: u16, token: u16) {
+            let mut header = CoapHeader::new(msg_type, code, message_id);
+            header.set_token(&token.to_be_bytes());
+            let mut buf = [0; 32];
+            let len = CoapMessageWriter::new(&mut buf, &header)
+                .unwrap()
+                .finish_with_payload(if code == code::CONTENT { b"hi" } else { b"" })
+                .unwrap();
+            self.layer
+                .receive(peer(), IPAddr::new(), PEER_PORT, COAP_PORT, &buf[..len]);
+        }
+
+        fn responses(&self) -> Vec<(ReturnCode, u8, Vec<u8>)> {
+            self.client.responses.borrow_mut().drain(..).collect()
+        }
+    }
+
+    fn peer() -> IPAddr {
+        let mut addr = IPAddr::new();
+        addr.0[0] = 0xfe;
+        addr.0[1] = 0x80;
+        addr.0[15] = 7;
+        addr
+    }
+
+    fn decode(dgram: &[u8]) -> (CoapType, u8, u16, Vec<u8>) {
+        let (_, msg) = CoapMessage::decode(dgram).done().unwrap();
+        (
+            msg.header.msg_type,
+            msg.header.code,
+            msg.header.message_id,
+            msg.payload.to_vec(),
+        )
+    }
+
+    #[test]
+    fn confirmable_requests_back_off() {
+        let harness = Harness::new();
+        assert_eq!(harness.request(code::GET, true), ReturnCode::SUCCESS);
+        let first = harness.udp.sent();
+        assert_eq!(first.len(), 1);
+        assert_eq!(decode(&first[0]).0, CoapType::Confirmable);
+
+        // The first timeout is spread by the message ID, 1 here, and
+        // doubles with each retransmission.
+        let mut timeout = 2000 + 2000 / 32;
+        for _ in 0..MAX_RETRANSMIT {
+            harness.alarm.advance(timeout - 1);
+            assert!(harness.udp.sent().is_empty());
+            harness.alarm.advance(1);
+            assert_eq!(harness.udp.sent(), first);
+            timeout *= 2;
+        }
+        harness.alarm.advance(timeout - 1);
+        assert!(harness.responses().is_empty());
+        harness.alarm.advance(1);
+        assert!(harness.udp.sent().is_empty());
+        assert_eq!(
+            harness.responses(),
+            [(ReturnCode::FAIL, code::EMPTY, Vec::new())]
+        );
+        assert!(!harness.layer.is_busy());
+    }
+
+    #[test]
+    fn replies_are_matched_to_the_request() {
+        let harness = Harness::new();
+        assert_eq!(harness.request(code::GET, true), ReturnCode::SUCCESS);
+        harness.udp.sent();
+
+        // An acknowledgement of another message, or for another token.
+        harness.receive(CoapType::Acknowledgement, code::CONTENT, 2, 1);
+        harness.receive(CoapType::Acknowledgement, code::CONTENT, 1, 2);
+        assert!(harness.responses().is_empty());
+        assert!(harness.layer.is_busy());
+
+        harness.receive(CoapType::Acknowledgement, code::CONTENT, 1, 1);
+        assert_eq!(
+            harness.responses(),
+            [(ReturnCode::SUCCESS, code::CONTENT, b"hi".to_vec())]
+        );
+        assert!(!harness.layer.is_busy());
+        assert!(!harness.alarm.is_enabled());
+
+        // A separate response is acknowledged, and a duplicate of it is
+        // acknowledged again but not delivered.
+        assert_eq!(harness.request(code::GET, true), ReturnCode::SUCCESS);
+        harness.udp.sent();
+        harness.receive(CoapType::Acknowledgement, code::EMPTY, 2, 2);
+        assert!(harness.udp.sent().is_empty());
+        for _ in 0..2 {
+            harness.receive(CoapType::Confirmable, code::CONTENT, 0x9000, 2);
+            let ack = harness.udp.sent();
+            assert_eq!(
+                decode(&ack[0]),
+                (CoapType::Acknowledgement, code::EMPTY, 0x9000, Vec::new())
+            );
+        }
+        assert_eq!(
+            harness.responses(),
+            [(ReturnCode::SUCCESS, code::CONTENT, b"hi".to_vec())]
+        );
+
+        // Responses nobody waits for are reset.
+        harness.receive(CoapType::Confirmable, code::CONTENT, 0x9001, 2);
+        assert_eq!(decode(&harness.udp.sent()[0]).0, CoapType::Reset);
+        assert!(harness.responses().is_empty());
+    }
+
+    #[test]
+    fn duplicate_requests_are_not_served_twice() {
+        let harness = Harness::new();
+        for _ in 0..2 {
+            harness.receive(CoapType::Confirmable, code::PUT, 7, 1);
+            assert_eq!(
+                decode(&harness.udp.sent()[0]),
+                (CoapType::Acknowledgement, code::CHANGED, 7, Vec::new())
+            );
+        }
+        assert_eq!(harness.server.requests.get(), 1);
+
+        // GET requests have no side effects, and are served again.
+        for _ in 0..2 {
+            harness.receive(CoapType::Confirmable, code::GET, 8, 1);
+            assert_eq!(
+                decode(&harness.udp.sent()[0]),
+                (
+                    CoapType::Acknowledgement,
+                    code::CONTENT,
+                    8,
+                    b"hello".to_vec()
+                )
+            );
+        }
+        assert_eq!(harness.server.requests.get(), 3);
+
+        // A new message ID is a new request.
+        harness.receive(CoapType::Confirmable, code::PUT, 9, 1);
+        assert_eq!(harness.server.requests.get(), 4);
+    }
+
+    #[test]
+    fn failed_sends_are_reported() {
+        let harness = Harness::new();
+        assert_eq!(harness.request(code::GET, false), ReturnCode::SUCCESS);
+        harness.udp.complete(ReturnCode::FAIL);
+        assert_eq!(
+            harness.responses(),
+            [(ReturnCode::FAIL, code::EMPTY, Vec::new())]
+        );
+        assert!(!harness.layer.is_busy());
+        harness.udp.sent();
+
+        // A retransmission that fails is tried again at the next timeout,
+        // since the peer may have the request already.
+        assert_eq!(harness.request(code::GET, true), ReturnCode::SUCCESS);
+        harness.udp.sent();
+        harness.alarm.advance(2000 + 2 * (2000 / 32));
+        harness.udp.complete(ReturnCode::FAIL);
+        assert!(harness.responses().is_empty());
+        harness.alarm.advance(2 * (2000 + 2 * (2000 / 32)));
+        assert_eq!(harness.udp.sent().len(), 2);
+        assert!(harness.layer.is_busy());
+
+        // Requests that can't be sent at all are refused.
+        harness.layer.cancel();
+        harness.receive(CoapType::NonConfirmable, code::GET, 10, 1);
+        assert_eq!(harness.request(code::GET, true), ReturnCode::EBUSY);
+        assert!(!harness.layer.is_busy());
+    }
+}
@@ -0,0 +1,363 @@
+//! CoAP userspace interface.
+//!
+//! Lets processes expose resources through the kernel's CoAP server and send
+//! requests to remote CoAP servers.
+//!
+//! A process registers a resource by allowing its path (for example
+//! `sensors/temp`) and then issuing the register command. GET requests for
+//! that path are answered directly from the representation buffer the
+//! process has allowed, so the process does not need to be scheduled to
+//! serve them; large representations are served block-wise. The payload of
+//! PUT and POST requests is copied into the process' receive buffer, after
+//! which the resource callback is scheduled.
+//!
+//! Only one client request can be outstanding at a time across all
+//! processes. The response payload is copied into the process' receive
+//! buffer, with block-wise responses reassembled at their offsets.
+
+use crate::net::coap::coap::{code, BlockOption, CoapMessage};
+use crate::net::coap::coap_layer::{CoapClient, CoapLayer, CoapResponse, CoapServer};
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::util::host_slice_to_u16;
+use core::cmp;
+use core::mem;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::Alarm;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::Coap as usize;
+
+/// Length of the destination configuration: an IPv6 address followed by a
+/// port in host byte order, as used by the UDP driver.
+const ENDPOINT_LEN: usize = mem::size_of::<IPAddr>() + mem::size_of::<u16>();
+
+#[derive(Default)]
+pub struct App {
+    resource_callback: Option<Callback>,
+    response_callback: Option<Callback>,
+    resource_path: Option<AppSlice<Shared, u8>>,
+    representation: Option<AppSlice<Shared, u8>>,
+    rx_buffer: Option<AppSlice<Shared, u8>>,
+    request_cfg: Option<AppSlice<Shared, u8>>,
+    request_path: Option<AppSlice<Shared, u8>>,
+    request_payload: Option<AppSlice<Shared, u8>>,
+    registered: bool,
+    resource_len: usize,
+    response_len: usize,
+}
+
+pub struct CoapDriver<'a, A: Alarm<'a>> {
+    coap: &'a CoapLayer<'a, A>,
+    apps: Grant<App>,
+    /// Process whose client request is outstanding.
+    current_app: OptionalCell<AppId>,
+}
+
+impl<'a, A: Alarm<'a>> CoapDriver<'a, A> {
+    pub fn new(coap: &'a CoapLayer<'a, A>, grant: Grant<App>) -> CoapDriver<'a, A> {
+        CoapDriver {
+            coap: coap,
+            apps: grant,
+            current_app: OptionalCell::empty(),
+        }
+    }
+
+    /// Utility function to perform an action on an app in a system call.
+    #[inline]
+    fn do_with_app<F>(&self, appid: AppId, closure: F) -> ReturnCode
+    where
+        F: FnOnce(&mut App) -> ReturnCode,
+    {
+        self.apps
+            .enter(appid, |app, _| closure(app))
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Returns true if a process other than `appid` has registered a
+    /// resource at `path`.
+    fn path_in_use(&self, appid: AppId, path: &[u8]) -> bool {
+        let mut in_use = false;
+        for app in self.apps.iter() {
+            app.enter(|other, _| {
+                if other.appid() != appid && other.registered {
+                    other.resource_path.as_ref().map(|other_path| {
+                        if other_path.as_ref() == path {
+                            in_use = true;
+                        }
+                    });
+                }
+            });
+        }
+        in_use
+    }
+
+    fn register(&self, appid: AppId) -> ReturnCode {
+        let path_ok = self
+            .apps
+            .enter(appid, |app, _| {
+                app.resource_path
+                    .as_ref()
+                    .map_or(ReturnCode::EINVAL, |path| {
+                        if path.len() == 0 {
+                            ReturnCode::EINVAL
+                        } else if self.path_in_use(appid, path.as_ref()) {
+                            ReturnCode::EBUSY
+                        } else {
+                            ReturnCode::SUCCESS
+                        }
+                    })
+            })
+            .unwrap_or_else(|err| err.into());
+        if path_ok != ReturnCode::SUCCESS {
+            return path_ok;
+        }
+        self.do_with_app(appid, |app| {
+            app.registered = true;
+            ReturnCode::SUCCESS
+        })
+    }
+
+    fn send_request(&self, appid: AppId, method: u8, confirmable: bool) -> ReturnCode {
+        if self.current_app.is_some() || self.coap.is_busy() {
+            return ReturnCode::EBUSY;
+        }
+        let result = self.do_with_app(appid, |app| {
+            let dest = match app.request_cfg.as_ref() {
+                Some(cfg) if cfg.len() == ENDPOINT_LEN => {
+                    let (a, p) = cfg.as_ref().split_at(mem::size_of::<IPAddr>());
+                    let mut addr = IPAddr::new();
+                    addr.0.copy_from_slice(a);
+                    (addr, host_slice_to_u16(p))
+                }
+                _ => return ReturnCode::EINVAL,
+            };
+            let path = app.request_path.as_ref().map_or(&[][..], |p| p.as_ref());
+            let payload = app.request_payload.as_ref().map_or(&[][..], |p| p.as_ref());
+            app.response_len = 0;
+            self.coap
+                .request(dest.0, dest.1, method, path, payload, confirmable)
+        });
+        if result == ReturnCode::SUCCESS {
+            self.current_app.set(appid);
+        }
+        result
+    }
+
+    /// Serves a request for a resource registered by `app`.
+    fn serve(
+        &self,
+        app: &mut App,
+        request: &CoapMessage,
+        offset: usize,
+        response: &mut [u8],
+    ) -> (CoapResponse, bool) {
+        match request.header.code {
+            code::GET => match app.representation.as_ref() {
+                Some(repr) => {
+                    let repr = repr.as_ref();
+                    let start = cmp::min(offset, repr.len());
+                    let len = cmp::min(repr.len() - start, response.len());
+                    response[..len].copy_from_slice(&repr[start..start + len]);
+                    let resp = CoapResponse {
+                        code: code::CONTENT,
+                        len: len,
+                        total_len: repr.len(),
+                    };
+                    (resp, false)
+                }
+                None => (CoapResponse::empty(code::SERVICE_UNAVAILABLE), false),
+            },
+            code::PUT | code::POST => {
+                let block = request.block1();
+                let start = block.map_or(0, |block| block.offset());
+                let end = start + request.payload.len();
+                let stored = app.rx_buffer.as_mut().map_or(false, |rx| {
+                    if end > rx.len() {
+                        return false;
+                    }
+                    rx.as_mut()[start..end].copy_from_slice(request.payload);
+                    true
+                });
+                if !stored {
+                    return (CoapResponse::empty(code::REQUEST_ENTITY_TOO_LARGE), false);
+                }
+                app.resource_len = end;
+                if block.map_or(false, |block| block.more) {
+                    (CoapResponse::empty(code::CONTINUE), false)
+                } else {
+                    (CoapResponse::empty(code::CHANGED), true)
+                }
+            }
+            _ => (CoapResponse::empty(code::METHOD_NOT_ALLOWED), false),
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> Driver for CoapDriver<'a, A> {
+    /// Setup buffers to read/write from.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Resource path, as `/`-separated segments (e.g. `sensors/temp`).
+    /// - `1`: Resource representation returned for GET requests.
+    /// - `2`: Receive buffer. Holds the payload of PUT/POST requests to the
+    ///        resource, and the payload of responses to client requests.
+    /// - `3`: Request destination: 16 byte IPv6 address followed by the
+    ///        port in host byte order.
+    /// - `4`: Request path.
+    /// - `5`: Request payload.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0..=5 => self.do_with_app(appid, |app| {
+                match allow_num {
+                    0 => {
+                        // Changing the path unregisters the resource.
+                        app.registered = false;
+                        app.resource_path = slice;
+                    }
+                    1 => app.representation = slice,
+                    2 => app.rx_buffer = slice,
+                    3 => app.request_cfg = slice,
+                    4 => app.request_path = slice,
+                    _ => app.request_payload = slice,
+                }
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Resource callback, scheduled after a PUT or POST request to
+    ///        the registered resource. Arguments are the method and the
+    ///        payload length.
+    /// - `1`: Response callback, scheduled when a client request completes.
+    ///        Arguments are the result, the response code and the total
+    ///        payload length, which may exceed the receive buffer.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.do_with_app(app_id, |app| {
+                app.resource_callback = callback;
+                ReturnCode::SUCCESS
+            }),
+            1 => self.do_with_app(app_id, |app| {
+                app.response_callback = callback;
+                ReturnCode::SUCCESS
+            }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// CoAP control
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Register the resource at the path in allow buffer `0`. Returns
+    ///        EINVAL if no path was allowed and EBUSY if another process
+    ///        already registered the same path.
+    /// - `2`: Unregister the resource.
+    /// - `3`: Send a request. `arg1` is the method (1: GET, 2: POST, 3: PUT,
+    ///        4: DELETE), `arg2` is non-zero for a confirmable request. The
+    ///        destination, path and payload are taken from allow buffers
+    ///        `3`, `4` and `5`. Returns EBUSY if a request is outstanding.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.register(appid),
+            2 => self.do_with_app(appid, |app| {
+                app.registered = false;
+                ReturnCode::SUCCESS
+            }),
+            3 => {
+                if arg1 == 0 || arg1 > code::DELETE as usize {
+                    return ReturnCode::EINVAL;
+                }
+                self.send_request(appid, arg1 as u8, arg2 != 0)
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>> CoapServer for CoapDriver<'a, A> {
+    fn request(
+        &self,
+        _src_addr: IPAddr,
+        _src_port: u16,
+        request: &CoapMessage,
+        offset: usize,
+        response: &mut [u8],
+    ) -> CoapResponse {
+        let mut result = CoapResponse::empty(code::NOT_FOUND);
+        for app in self.apps.iter() {
+            let mut found = false;
+            app.enter(|app, _| {
+                let matches = app.registered
+                    && app
+                        .resource_path
+                        .as_ref()
+                        .map_or(false, |path| request.uri_path_matches(path.as_ref()));
+                if !matches {
+                    return;
+                }
+                found = true;
+                let (resp, notify) = self.serve(app, request, offset, response);
+                if notify {
+                    let method = request.header.code as usize;
+                    let len = app.resource_len;
+                    app.resource_callback
+                        .map(|mut cb| cb.schedule(method, len, 0));
+                }
+                result = resp;
+            });
+            if found {
+                break;
+            }
+        }
+        result
+    }
+}
+
+impl<'a, A: Alarm<'a>> CoapClient for CoapDriver<'a, A> {
+    fn response(&self, result: ReturnCode, code: u8, block: Option<BlockOption>, payload: &[u8]) {
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                let start = block.map_or(0, |block| block.offset());
+                let end = start + payload.len();
+                app.rx_buffer.as_mut().map(|rx| {
+                    if start < rx.len() {
+                        let len = cmp::min(end, rx.len()) - start;
+                        rx.as_mut()[start..start + len].copy_from_slice(&payload[..len]);
+                    }
+                });
+                app.response_len = cmp::max(app.response_len, end);
+
+                if block.map_or(false, |block| block.more) && result == ReturnCode::SUCCESS {
+                    // Wait for the remaining blocks.
+                    return;
+                }
+                let len = app.response_len;
+                app.response_callback
+                    .map(|mut cb| cb.schedule(result.into(), code as usize, len));
+            });
+        });
+        if !self.coap.is_busy() {
+            self.current_app.clear();
+        }
+    }
+}
@@ -0,0 +1,8 @@
+//! Constrained Application Protocol (CoAP, RFC 7252) over the UDP stack.
+
+pub mod coap;
+pub mod coap_layer;
+pub mod driver;
+
+pub use self::driver::CoapDriver;
+pub use self::driver::DRIVER_NUM;
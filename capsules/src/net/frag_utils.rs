@@ -1,5 +1,9 @@
 const BITMAP_SIZE: usize = 20;
 
+/// Number of bits in a `Bitmap`. As each bit tracks 8 bytes, a `Bitmap` can
+/// track the reassembly of packets of up to `BITMAP_BITS * 8` bytes.
+pub const BITMAP_BITS: usize = BITMAP_SIZE * 8;
+
 pub struct Bitmap {
     map: [u8; BITMAP_SIZE],
 }
@@ -27,6 +31,10 @@ impl Bitmap {
         self.map[map_idx] |= 1 << (idx % 8);
     }
 
+    pub fn is_set(&self, idx: usize) -> bool {
+        idx < BITMAP_BITS && self.map[idx / 8] & (1 << (idx % 8)) != 0
+    }
+
     // Sets bits from start_idx (inclusive) to end_idx (exclusive).
     // Returns false if any bits set overlap with already set bits,
     // true otherwise.
@@ -37,8 +45,10 @@ impl Bitmap {
     // must be in 8-byte groups), and thus we can store 8*8 = 64 "bytes" per
     // byte in the bitmap.
     pub fn set_bits(&mut self, start_idx: usize, end_idx: usize) -> bool {
-        if start_idx > end_idx {
+        if start_idx > end_idx || end_idx > BITMAP_BITS {
             return false;
+        } else if start_idx == end_idx {
+            return true;
         }
         let start_byte_idx = start_idx / 8;
         let end_byte_idx = end_idx / 8;
@@ -54,9 +64,13 @@ impl Bitmap {
             result
         } else {
             let mut result = (self.map[start_byte_idx] & first) == 0;
-            result = result && ((self.map[end_byte_idx] & second) == 0);
             self.map[start_byte_idx] |= first;
-            self.map[end_byte_idx] |= second;
+            // The end byte is past the end of the map if `end_idx` is
+            // BITMAP_BITS, in which case no bits of it need to be set.
+            if second != 0 {
+                result = result && ((self.map[end_byte_idx] & second) == 0);
+                self.map[end_byte_idx] |= second;
+            }
             // Set all bytes between start and end bytes.
             for i in start_byte_idx + 1..end_byte_idx {
                 result = result && (self.map[i] == 0);
@@ -66,14 +80,51 @@ impl Bitmap {
         }
     }
 
+    // Returns true if exactly the first `total_length` bits are set.
     pub fn is_complete(&self, total_length: usize) -> bool {
+        if total_length > BITMAP_BITS {
+            return false;
+        }
         let mut result = true;
         for i in 0..total_length / 8 {
             result = result && (self.map[i] == 0xff);
         }
-        // Check last byte.
-        let mask = 0xff >> (8 - (total_length % 8));
-        result = result && (self.map[total_length / 8] == mask);
+        // Check last byte, if it is partially used.
+        if total_length % 8 != 0 {
+            let mask = 0xff >> (8 - (total_length % 8));
+            result = result && (self.map[total_length / 8] == mask);
+        }
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_bits() {
+        let mut bitmap = Bitmap::new();
+        assert!(bitmap.set_bits(0, 5));
+        assert!(bitmap.set_bits(5, 16));
+        assert!(bitmap.is_complete(16));
+        assert!(!bitmap.is_complete(17));
+        assert!(bitmap.set_bits(20, BITMAP_BITS));
+        assert!(!bitmap.is_complete(BITMAP_BITS));
+        assert!(bitmap.set_bits(16, 20));
+        assert!(bitmap.is_complete(BITMAP_BITS));
+        assert!(!bitmap.set_bits(15, 17));
+        assert!(!bitmap.set_bits(0, BITMAP_BITS + 1));
+    }
+
+    #[test]
+    fn is_set() {
+        let mut bitmap = Bitmap::new();
+        bitmap.set_bit(9);
+        assert!(bitmap.is_set(9));
+        assert!(!bitmap.is_set(8));
+        assert!(!bitmap.is_set(BITMAP_BITS));
+        bitmap.clear_bit(9);
+        assert!(!bitmap.is_set(9));
+    }
+}
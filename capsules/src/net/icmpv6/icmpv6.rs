@@ -19,10 +19,34 @@ pub struct ICMP6Header {
 
 #[derive(Copy, Clone)]
 pub enum ICMP6HeaderOptions {
-    Type1 { unused: u32 },
-    Type3 { unused: u32 },
-    Type128 { id: u16, seqno: u16 },
-    Type129 { id: u16, seqno: u16 },
+    Type1 {
+        unused: u32,
+    },
+    Type3 {
+        unused: u32,
+    },
+    Type128 {
+        id: u16,
+        seqno: u16,
+    },
+    Type129 {
+        id: u16,
+        seqno: u16,
+    },
+    Type133 {
+        reserved: u32,
+    },
+    Type134 {
+        hop_limit: u8,
+        flags: u8,
+        router_lifetime: u16,
+    },
+    Type135 {
+        reserved: u32,
+    },
+    Type136 {
+        flags: u32,
+    },
 }
 
 #[derive(Copy, Clone)]
@@ -31,6 +55,10 @@ pub enum ICMP6Type {
     Type3,   // Time Exceeded
     Type128, // Echo Request
     Type129, // Echo Reply
+    Type133, // Router Solicitation
+    Type134, // Router Advertisement
+    Type135, // Neighbor Solicitation
+    Type136, // Neighbor Advertisement
 }
 
 impl ICMP6Header {
@@ -40,6 +68,14 @@ impl ICMP6Header {
             ICMP6Type::Type3 => ICMP6HeaderOptions::Type3 { unused: 0 },
             ICMP6Type::Type128 => ICMP6HeaderOptions::Type128 { id: 0, seqno: 0 },
             ICMP6Type::Type129 => ICMP6HeaderOptions::Type129 { id: 0, seqno: 0 },
+            ICMP6Type::Type133 => ICMP6HeaderOptions::Type133 { reserved: 0 },
+            ICMP6Type::Type134 => ICMP6HeaderOptions::Type134 {
+                hop_limit: 0,
+                flags: 0,
+                router_lifetime: 0,
+            },
+            ICMP6Type::Type135 => ICMP6HeaderOptions::Type135 { reserved: 0 },
+            ICMP6Type::Type136 => ICMP6HeaderOptions::Type136 { flags: 0 },
         };
 
         ICMP6Header {
@@ -51,12 +87,7 @@ impl ICMP6Header {
     }
 
     pub fn set_type(&mut self, icmp_type: ICMP6Type) {
-        match icmp_type {
-            ICMP6Type::Type1 => self.set_options(ICMP6HeaderOptions::Type1 { unused: 0 }),
-            ICMP6Type::Type3 => self.set_options(ICMP6HeaderOptions::Type3 { unused: 0 }),
-            ICMP6Type::Type128 => self.set_options(ICMP6HeaderOptions::Type128 { id: 0, seqno: 0 }),
-            ICMP6Type::Type129 => self.set_options(ICMP6HeaderOptions::Type129 { id: 0, seqno: 0 }),
-        }
+        self.set_options(ICMP6Header::new(icmp_type).options);
     }
 
     pub fn set_code(&mut self, code: u8) {
@@ -81,6 +112,10 @@ impl ICMP6Header {
             ICMP6HeaderOptions::Type3 { .. } => ICMP6Type::Type3,
             ICMP6HeaderOptions::Type128 { .. } => ICMP6Type::Type128,
             ICMP6HeaderOptions::Type129 { .. } => ICMP6Type::Type129,
+            ICMP6HeaderOptions::Type133 { .. } => ICMP6Type::Type133,
+            ICMP6HeaderOptions::Type134 { .. } => ICMP6Type::Type134,
+            ICMP6HeaderOptions::Type135 { .. } => ICMP6Type::Type135,
+            ICMP6HeaderOptions::Type136 { .. } => ICMP6Type::Type136,
         }
     }
 
@@ -90,6 +125,21 @@ impl ICMP6Header {
             ICMP6Type::Type3 => 3,
             ICMP6Type::Type128 => 128,
             ICMP6Type::Type129 => 129,
+            ICMP6Type::Type133 => 133,
+            ICMP6Type::Type134 => 134,
+            ICMP6Type::Type135 => 135,
+            ICMP6Type::Type136 => 136,
+        }
+    }
+
+    /// Returns true for the Neighbor Discovery message types (RFC 4861),
+    /// which receivers only accept with a hop limit of 255.
+    pub fn is_neighbor_discovery(&self) -> bool {
+        match self.get_type() {
+            ICMP6Type::Type133 | ICMP6Type::Type134 | ICMP6Type::Type135 | ICMP6Type::Type136 => {
+                true
+            }
+            _ => false,
         }
     }
 
@@ -132,9 +182,22 @@ impl ICMP6Header {
         off = enc_consume!(buf, off; encode_u16, self.cksum);
 
         match self.options {
-            ICMP6HeaderOptions::Type1 { unused } | ICMP6HeaderOptions::Type3 { unused } => {
+            ICMP6HeaderOptions::Type1 { unused }
+            | ICMP6HeaderOptions::Type3 { unused }
+            | ICMP6HeaderOptions::Type133 { reserved: unused }
+            | ICMP6HeaderOptions::Type135 { reserved: unused }
+            | ICMP6HeaderOptions::Type136 { flags: unused } => {
                 off = enc_consume!(buf, off; encode_u32, unused);
             }
+            ICMP6HeaderOptions::Type134 {
+                hop_limit,
+                flags,
+                router_lifetime,
+            } => {
+                off = enc_consume!(buf, off; encode_u8, hop_limit);
+                off = enc_consume!(buf, off; encode_u8, flags);
+                off = enc_consume!(buf, off; encode_u16, router_lifetime);
+            }
             ICMP6HeaderOptions::Type128 { id, seqno }
             | ICMP6HeaderOptions::Type129 { id, seqno } => {
                 off = enc_consume!(buf, off; encode_u16, id);
@@ -163,6 +226,10 @@ impl ICMP6Header {
             3 => ICMP6Type::Type3,
             128 => ICMP6Type::Type128,
             129 => ICMP6Type::Type129,
+            133 => ICMP6Type::Type133,
+            134 => ICMP6Type::Type134,
+            135 => ICMP6Type::Type135,
+            136 => ICMP6Type::Type136,
             _ => return SResult::Error(()),
         };
 
@@ -198,6 +265,30 @@ impl ICMP6Header {
                 let seqno = u16::from_be(seqno);
                 icmp_header.set_options(ICMP6HeaderOptions::Type129 { id, seqno });
             }
+            // The Neighbor Discovery fields are kept in host byte order, so
+            // they can be used directly by the NDP code.
+            ICMP6Type::Type133 => {
+                let (_off, reserved) = dec_try!(buf, off; decode_u32);
+                icmp_header.set_options(ICMP6HeaderOptions::Type133 { reserved });
+            }
+            ICMP6Type::Type134 => {
+                let (off, hop_limit) = dec_try!(buf, off; decode_u8);
+                let (off, flags) = dec_try!(buf, off; decode_u8);
+                let (_off, router_lifetime) = dec_try!(buf, off; decode_u16);
+                icmp_header.set_options(ICMP6HeaderOptions::Type134 {
+                    hop_limit,
+                    flags,
+                    router_lifetime,
+                });
+            }
+            ICMP6Type::Type135 => {
+                let (_off, reserved) = dec_try!(buf, off; decode_u32);
+                icmp_header.set_options(ICMP6HeaderOptions::Type135 { reserved });
+            }
+            ICMP6Type::Type136 => {
+                let (_off, flags) = dec_try!(buf, off; decode_u32);
+                icmp_header.set_options(ICMP6HeaderOptions::Type136 { flags });
+            }
         }
 
         stream_done!(off, icmp_header);
@@ -1,2 +1,3 @@
 pub mod icmpv6;
 pub mod icmpv6_send;
+pub mod ndp;
@@ -0,0 +1,274 @@
+//! Encoding and decoding of the Neighbor Discovery (RFC 4861) message bodies
+//! and options that are needed for stateless address autoconfiguration.
+//!
+//! The fixed part of each message up to and including its first 32-bit word
+//! is represented by the `ICMP6Header` (types 133 to 136); the functions in
+//! this file operate on the bytes that follow it.
+//!
+//! Link-layer address options carry 802.15.4 addresses as described in
+//! RFC 4944, section 8.
+
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_bytes, decode_u32, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+/// Neighbor Discovery option types.
+pub mod nd_opt {
+    pub const SOURCE_LL_ADDR: u8 = 1;
+    pub const TARGET_LL_ADDR: u8 = 2;
+    pub const PREFIX_INFO: u8 = 3;
+    pub const MTU: u8 = 5;
+}
+
+/// Router Advertisement flags.
+pub const RA_FLAG_MANAGED: u8 = 0x80;
+pub const RA_FLAG_OTHER: u8 = 0x40;
+
+/// Neighbor Advertisement flags, as stored in `ICMP6HeaderOptions::Type136`.
+pub const NA_FLAG_ROUTER: u32 = 0x8000_0000;
+pub const NA_FLAG_SOLICITED: u32 = 0x4000_0000;
+pub const NA_FLAG_OVERRIDE: u32 = 0x2000_0000;
+
+/// Length of the Router Advertisement body preceding its options (reachable
+/// time and retransmission timer).
+pub const RA_BODY_LEN: usize = 8;
+
+/// Length of the Neighbor Solicitation/Advertisement body preceding its
+/// options (the target address).
+pub const NS_BODY_LEN: usize = 16;
+
+const PREFIX_INFO_LEN: usize = 30;
+const PREFIX_FLAG_ON_LINK: u8 = 0x80;
+const PREFIX_FLAG_AUTONOMOUS: u8 = 0x40;
+
+/// A single Neighbor Discovery option. `value` holds the option contents
+/// following the type and length bytes.
+#[derive(Copy, Clone, Debug)]
+pub struct NdOption<'b> {
+    pub opt_type: u8,
+    pub value: &'b [u8],
+}
+
+/// Iterates over the options of a Neighbor Discovery message. Iteration
+/// stops at the first malformed option; RFC 4861 requires such messages to
+/// be discarded, which callers can detect with `is_malformed`.
+pub struct NdOptionIterator<'b> {
+    buf: &'b [u8],
+    offset: usize,
+    malformed: bool,
+}
+
+impl<'b> NdOptionIterator<'b> {
+    pub fn new(buf: &'b [u8]) -> NdOptionIterator<'b> {
+        NdOptionIterator {
+            buf: buf,
+            offset: 0,
+            malformed: false,
+        }
+    }
+
+    pub fn is_malformed(&self) -> bool {
+        self.malformed
+    }
+}
+
+impl<'b> Iterator for NdOptionIterator<'b> {
+    type Item = NdOption<'b>;
+
+    fn next(&mut self) -> Option<NdOption<'b>> {
+        if self.malformed || self.offset + 2 > self.buf.len() {
+            return None;
+        }
+        let opt_type = self.buf[self.offset];
+        // The length is in units of 8 bytes and includes the type and
+        // length fields, so zero is invalid.
+        let len = self.buf[self.offset + 1] as usize * 8;
+        if len == 0 || self.offset + len > self.buf.len() {
+            self.malformed = true;
+            return None;
+        }
+        let value = &self.buf[self.offset + 2..self.offset + len];
+        self.offset += len;
+        Some(NdOption {
+            opt_type: opt_type,
+            value: value,
+        })
+    }
+}
+
+/// The contents of a Prefix Information option.
+#[derive(Copy, Clone, Debug)]
+pub struct PrefixInfo {
+    pub prefix_len: u8,
+    pub on_link: bool,
+    pub autonomous: bool,
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+    pub prefix: IPAddr,
+}
+
+impl PrefixInfo {
+    /// Decodes a Prefix Information option from its value (as returned by
+    /// `NdOptionIterator`).
+    pub fn decode(value: &[u8]) -> SResult<PrefixInfo> {
+        stream_len_cond!(value, PREFIX_INFO_LEN);
+        let (off, prefix_len) = dec_try!(value, 0; decode_u8);
+        let (off, flags) = dec_try!(value, off; decode_u8);
+        let (off, valid_lifetime) = dec_try!(value, off; decode_u32);
+        let (off, preferred_lifetime) = dec_try!(value, off; decode_u32);
+        // Skip the reserved field
+        let off = off + 4;
+        let mut prefix = IPAddr::new();
+        let off = dec_consume!(value, off; decode_bytes, &mut prefix.0);
+        if prefix_len > 128 {
+            return SResult::Error(());
+        }
+        stream_done!(
+            off,
+            PrefixInfo {
+                prefix_len: prefix_len,
+                on_link: flags & PREFIX_FLAG_ON_LINK != 0,
+                autonomous: flags & PREFIX_FLAG_AUTONOMOUS != 0,
+                valid_lifetime: valid_lifetime,
+                preferred_lifetime: preferred_lifetime,
+                prefix: prefix,
+            }
+        );
+    }
+}
+
+/// Decodes the target address at the start of a Neighbor Solicitation or
+/// Advertisement body.
+pub fn decode_target(buf: &[u8]) -> SResult<IPAddr> {
+    let mut target = IPAddr::new();
+    let off = dec_consume!(buf, 0; decode_bytes, &mut target.0);
+    stream_done!(off, target);
+}
+
+/// Encodes the target address of a Neighbor Solicitation or Advertisement.
+pub fn encode_target(buf: &mut [u8], offset: usize, target: &IPAddr) -> SResult<usize> {
+    let off = enc_consume!(buf, offset; encode_bytes, &target.0);
+    stream_done!(off, off);
+}
+
+/// Encodes a source or target link-layer address option for an 802.15.4
+/// address, padded to a multiple of 8 bytes.
+pub fn encode_ll_addr_option(
+    buf: &mut [u8],
+    offset: usize,
+    opt_type: u8,
+    mac_addr: MacAddress,
+) -> SResult<usize> {
+    let units = match mac_addr {
+        MacAddress::Short(_) => 1,
+        MacAddress::Long(_) => 2,
+    };
+    stream_len_cond!(buf, offset + units * 8);
+    let mut off = enc_consume!(buf, offset; encode_u8, opt_type);
+    off = enc_consume!(buf, off; encode_u8, units as u8);
+    off = match mac_addr {
+        MacAddress::Short(addr) => enc_consume!(buf, off; encode_u16, addr),
+        MacAddress::Long(ref addr) => enc_consume!(buf, off; encode_bytes, addr),
+    };
+    let end = offset + units * 8;
+    for b in buf[off..end].iter_mut() {
+        *b = 0;
+    }
+    stream_done!(end, end);
+}
+
+/// Decodes an 802.15.4 address from the value of a link-layer address
+/// option.
+pub fn decode_ll_addr(value: &[u8]) -> Option<MacAddress> {
+    match value.len() {
+        14 => {
+            let mut addr = [0; 8];
+            addr.copy_from_slice(&value[..8]);
+            Some(MacAddress::Long(addr))
+        }
+        6 => Some(MacAddress::Short((value[0] as u16) << 8 | value[1] as u16)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefix_info_option() {
+        let mut msg = [0u8; 40];
+        msg[0] = nd_opt::SOURCE_LL_ADDR;
+        msg[1] = 1;
+        msg[8] = nd_opt::PREFIX_INFO;
+        msg[9] = 4;
+        msg[10] = 64;
+        msg[11] = PREFIX_FLAG_ON_LINK | PREFIX_FLAG_AUTONOMOUS;
+        msg[12..16].copy_from_slice(&[0, 0, 0x0e, 0x10]);
+        msg[16..20].copy_from_slice(&[0, 0, 0x07, 0x08]);
+        msg[24] = 0x20;
+        msg[25] = 0x01;
+        msg[26] = 0x0d;
+        msg[27] = 0xb8;
+
+        let mut iter = NdOptionIterator::new(&msg);
+        let sllao = iter.next().unwrap();
+        assert_eq!(sllao.opt_type, nd_opt::SOURCE_LL_ADDR);
+        assert_eq!(sllao.value.len(), 6);
+        let pio = iter.next().unwrap();
+        assert_eq!(pio.opt_type, nd_opt::PREFIX_INFO);
+        assert!(iter.next().is_none());
+        assert!(!iter.is_malformed());
+
+        let info = PrefixInfo::decode(pio.value).done().unwrap().1;
+        assert_eq!(info.prefix_len, 64);
+        assert!(info.on_link && info.autonomous);
+        assert_eq!(info.valid_lifetime, 3600);
+        assert_eq!(info.preferred_lifetime, 1800);
+        assert_eq!(&info.prefix.0[..4], &[0x20, 0x01, 0x0d, 0xb8]);
+    }
+
+    #[test]
+    fn malformed_option() {
+        let msg = [nd_opt::MTU, 0, 0, 0, 0, 0, 0, 0];
+        let mut iter = NdOptionIterator::new(&msg);
+        assert!(iter.next().is_none());
+        assert!(iter.is_malformed());
+
+        let msg = [nd_opt::MTU, 2, 0, 0, 0, 0, 0, 0];
+        let mut iter = NdOptionIterator::new(&msg);
+        assert!(iter.next().is_none());
+        assert!(iter.is_malformed());
+    }
+
+    #[test]
+    fn ll_addr_option() {
+        let mut buf = [0xffu8; 24];
+        let ext = [1, 2, 3, 4, 5, 6, 7, 8];
+        let off = encode_ll_addr_option(&mut buf, 0, nd_opt::SOURCE_LL_ADDR, MacAddress::Long(ext))
+            .done()
+            .unwrap()
+            .0;
+        assert_eq!(off, 16);
+        assert_eq!(&buf[..10], &[1, 2, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(buf[10..16].iter().all(|&b| b == 0));
+
+        let opt = NdOptionIterator::new(&buf[..16]).next().unwrap();
+        assert!(decode_ll_addr(opt.value) == Some(MacAddress::Long(ext)));
+
+        let off = encode_ll_addr_option(
+            &mut buf,
+            16,
+            nd_opt::TARGET_LL_ADDR,
+            MacAddress::Short(0x1234),
+        )
+        .done()
+        .unwrap()
+        .0;
+        assert_eq!(off, 24);
+        let opt = NdOptionIterator::new(&buf[16..24]).next().unwrap();
+        assert!(decode_ll_addr(opt.value) == Some(MacAddress::Short(0x1234)));
+    }
+}
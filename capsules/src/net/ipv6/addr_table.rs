@@ -0,0 +1,395 @@
+//! Table of the IPv6 addresses assigned to the local interface.
+//!
+//! Addresses are either configured statically by the board or assigned at
+//! runtime by stateless address autoconfiguration (see `autoconf.rs`).
+//! Each address has a state (RFC 4862): a tentative address is still
+//! undergoing duplicate address detection and must not be used, a deprecated
+//! address remains valid but should not be chosen for new communication, and
+//! a duplicate address was found to be in use by another node.
+//!
+//! The table is shared by the IP layer (source address selection and
+//! filtering of received packets), the UDP driver (binding and the interface
+//! list exposed to processes) and network capabilities that permit
+//! communication with on-link addresses.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let addr_entries = static_init!([Option<AddrEntry>; 4], [None; 4]);
+//! let addr_table = static_init!(IPAddrTable<'static>, IPAddrTable::new(addr_entries));
+//! addr_table.add_static(IPAddr::generate_from_mac(src_mac), 64);
+//! ip_send.set_addr_table(addr_table);
+//! ip_receive.set_addr_table(addr_table);
+//! ```
+
+use crate::net::ipv6::ip_utils::{IPAddr, ALL_NODES_MCAST};
+use kernel::common::cells::TakeCell;
+use kernel::ReturnCode;
+
+/// Lifetime value representing infinity.
+pub const INFINITE_LIFETIME: u32 = 0xffff_ffff;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AddrState {
+    Tentative,
+    Preferred,
+    Deprecated,
+    Duplicate,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AddrOrigin {
+    /// Configured by the board; never expires.
+    Static,
+    /// Link-local address formed from the interface identifier.
+    LinkLocal,
+    /// Formed from a prefix advertised by a router.
+    Slaac,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct AddrEntry {
+    pub addr: IPAddr,
+    pub prefix_len: u8,
+    pub state: AddrState,
+    pub origin: AddrOrigin,
+    /// Remaining valid and preferred lifetimes in seconds.
+    pub valid_lifetime: u32,
+    pub preferred_lifetime: u32,
+}
+
+impl AddrEntry {
+    /// An address can be used as a source and can receive unicast traffic
+    /// once duplicate address detection has completed.
+    pub fn is_assigned(&self) -> bool {
+        self.state == AddrState::Preferred || self.state == AddrState::Deprecated
+    }
+}
+
+pub struct IPAddrTable<'a> {
+    entries: TakeCell<'a, [Option<AddrEntry>]>,
+}
+
+impl<'a> IPAddrTable<'a> {
+    pub fn new(entries: &'a mut [Option<AddrEntry>]) -> IPAddrTable<'a> {
+        IPAddrTable {
+            entries: TakeCell::new(entries),
+        }
+    }
+
+    /// Adds an address to the table. Returns EALREADY if the address is
+    /// already present and ENOMEM if the table is full.
+    pub fn add(&self, entry: AddrEntry) -> ReturnCode {
+        self.entries.map_or(ReturnCode::FAIL, |entries| {
+            if entries
+                .iter()
+                .any(|e| e.map_or(false, |e| e.addr == entry.addr))
+            {
+                return ReturnCode::EALREADY;
+            }
+            match entries.iter_mut().find(|e| e.is_none()) {
+                Some(slot) => {
+                    *slot = Some(entry);
+                    ReturnCode::SUCCESS
+                }
+                None => ReturnCode::ENOMEM,
+            }
+        })
+    }
+
+    /// Adds a statically configured address, which is immediately
+    /// preferred and never expires.
+    pub fn add_static(&self, addr: IPAddr, prefix_len: u8) -> ReturnCode {
+        self.add(AddrEntry {
+            addr: addr,
+            prefix_len: prefix_len,
+            state: AddrState::Preferred,
+            origin: AddrOrigin::Static,
+            valid_lifetime: INFINITE_LIFETIME,
+            preferred_lifetime: INFINITE_LIFETIME,
+        })
+    }
+
+    pub fn remove(&self, addr: IPAddr) -> ReturnCode {
+        self.update(addr, |_| false)
+    }
+
+    /// Returns a copy of the entry for `addr`, if present.
+    pub fn find(&self, addr: IPAddr) -> Option<AddrEntry> {
+        self.entries.map_or(None, |entries| {
+            entries.iter().filter_map(|e| *e).find(|e| e.addr == addr)
+        })
+    }
+
+    /// Calls `f` with the entry for `addr`. The entry is removed if `f`
+    /// returns false. Returns EINVAL if the address is not in the table.
+    pub fn update<F>(&self, addr: IPAddr, f: F) -> ReturnCode
+    where
+        F: FnOnce(&mut AddrEntry) -> bool,
+    {
+        self.entries.map_or(ReturnCode::FAIL, |entries| {
+            for slot in entries.iter_mut() {
+                if let Some(ref mut entry) = slot {
+                    if entry.addr == addr {
+                        if !f(entry) {
+                            *slot = None;
+                        }
+                        return ReturnCode::SUCCESS;
+                    }
+                }
+            }
+            ReturnCode::EINVAL
+        })
+    }
+
+    /// Returns the first tentative address, which is the next one to
+    /// undergo duplicate address detection.
+    pub fn first_tentative(&self) -> Option<IPAddr> {
+        self.entries.map_or(None, |entries| {
+            entries
+                .iter()
+                .filter_map(|e| *e)
+                .find(|e| e.state == AddrState::Tentative)
+                .map(|e| e.addr)
+        })
+    }
+
+    /// Returns true if `addr` is assigned to the interface.
+    pub fn contains(&self, addr: IPAddr) -> bool {
+        self.find(addr).map_or(false, |e| e.is_assigned())
+    }
+
+    /// Returns the number of assigned addresses.
+    pub fn len(&self) -> usize {
+        self.entries.map_or(0, |entries| {
+            entries
+                .iter()
+                .filter(|e| e.map_or(false, |e| e.is_assigned()))
+                .count()
+        })
+    }
+
+    /// Returns the `index`th assigned address.
+    pub fn get(&self, index: usize) -> Option<IPAddr> {
+        self.entries.map_or(None, |entries| {
+            entries
+                .iter()
+                .filter_map(|e| *e)
+                .filter(|e| e.is_assigned())
+                .nth(index)
+                .map(|e| e.addr)
+        })
+    }
+
+    /// Returns true if a packet sent to `addr` should be accepted by this
+    /// interface: assigned unicast addresses, the all-nodes address, and the
+    /// solicited-node addresses of all (including tentative) addresses, so
+    /// that duplicate address detection messages are received.
+    pub fn is_local(&self, addr: IPAddr) -> bool {
+        if addr == ALL_NODES_MCAST {
+            return true;
+        }
+        self.entries.map_or(false, |entries| {
+            entries.iter().filter_map(|e| *e).any(|e| {
+                (e.is_assigned() && e.addr == addr)
+                    || (e.state != AddrState::Duplicate && e.addr.solicited_node() == addr)
+            })
+        })
+    }
+
+    /// Returns true if `addr` can be reached without a router: link-local
+    /// unicast and multicast addresses and addresses within the prefix of
+    /// an assigned address.
+    pub fn is_on_link(&self, addr: IPAddr) -> bool {
+        if addr.is_unicast_link_local() || addr.is_link_local_multicast() {
+            return true;
+        }
+        self.entries.map_or(false, |entries| {
+            entries
+                .iter()
+                .filter_map(|e| *e)
+                .any(|e| e.is_assigned() && addr.matches_prefix(&e.addr, e.prefix_len))
+        })
+    }
+
+    /// Selects the source address for a packet sent to `dst`, following a
+    /// simplified version of RFC 6724: link-local destinations use a
+    /// link-local source, otherwise preferred addresses are chosen over
+    /// deprecated ones and longer matching prefixes over shorter ones.
+    ///
+    /// Returns the unspecified address for the duplicate address detection
+    /// solicitation of a tentative address, and `None` if no address is
+    /// assigned.
+    pub fn select_source(&self, dst: IPAddr) -> Option<IPAddr> {
+        self.entries.map_or(None, |entries| {
+            let dad_probe = entries
+                .iter()
+                .filter_map(|e| *e)
+                .any(|e| e.state == AddrState::Tentative && e.addr.solicited_node() == dst);
+            if dad_probe {
+                return Some(IPAddr::new());
+            }
+            let link_scope = dst.is_unicast_link_local() || dst.is_link_local_multicast();
+            let mut best: Option<(u8, IPAddr)> = None;
+            for entry in entries.iter().filter_map(|e| *e) {
+                if !entry.is_assigned() {
+                    continue;
+                }
+                // Order candidates by matching scope, then state, then the
+                // length of the common prefix.
+                let scope_match = entry.addr.is_unicast_link_local() == link_scope;
+                let preferred = entry.state == AddrState::Preferred;
+                let prefix = if dst.is_multicast() {
+                    0
+                } else {
+                    entry.addr.common_prefix_len(&dst)
+                };
+                let rank = (scope_match as u8) << 7 | (preferred as u8) << 6 | prefix / 2;
+                if best.map_or(true, |(best_rank, _)| rank > best_rank) {
+                    best = Some((rank, entry.addr));
+                }
+            }
+            best.map(|(_, addr)| addr)
+        })
+    }
+
+    /// Ages the lifetimes of non-static addresses by `seconds`. Addresses
+    /// whose preferred lifetime expires become deprecated, and addresses
+    /// whose valid lifetime expires are removed. Returns true if the state
+    /// of any address changed.
+    pub fn tick(&self, seconds: u32) -> bool {
+        self.entries.map_or(false, |entries| {
+            let mut changed = false;
+            for slot in entries.iter_mut() {
+                if let Some(ref mut entry) = slot {
+                    if entry.origin == AddrOrigin::Static || entry.state == AddrState::Duplicate {
+                        continue;
+                    }
+                    if entry.valid_lifetime != INFINITE_LIFETIME {
+                        entry.valid_lifetime = entry.valid_lifetime.saturating_sub(seconds);
+                    }
+                    if entry.preferred_lifetime != INFINITE_LIFETIME {
+                        entry.preferred_lifetime = entry.preferred_lifetime.saturating_sub(seconds);
+                    }
+                    if entry.valid_lifetime == 0 {
+                        *slot = None;
+                        changed = true;
+                    } else if entry.preferred_lifetime == 0 && entry.state == AddrState::Preferred {
+                        entry.state = AddrState::Deprecated;
+                        changed = true;
+                    }
+                }
+            }
+            changed
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(prefix: u8, iid: u8) -> IPAddr {
+        let mut addr = IPAddr::new();
+        if prefix == 0 {
+            addr.set_unicast_link_local();
+        } else {
+            addr.0[0] = 0x20;
+            addr.0[1] = 0x01;
+            addr.0[7] = prefix;
+        }
+        addr.0[15] = iid;
+        addr
+    }
+
+    fn slaac(addr: IPAddr, state: AddrState, valid: u32, preferred: u32) -> AddrEntry {
+        AddrEntry {
+            addr: addr,
+            prefix_len: 64,
+            state: state,
+            origin: AddrOrigin::Slaac,
+            valid_lifetime: valid,
+            preferred_lifetime: preferred,
+        }
+    }
+
+    #[test]
+    fn add_and_list() {
+        let mut entries = [None; 2];
+        let table = IPAddrTable::new(&mut entries);
+        assert_eq!(table.add_static(addr(0, 1), 64), ReturnCode::SUCCESS);
+        assert_eq!(table.add_static(addr(0, 1), 64), ReturnCode::EALREADY);
+        let tentative = slaac(addr(1, 1), AddrState::Tentative, 100, 50);
+        assert_eq!(table.add(tentative), ReturnCode::SUCCESS);
+        assert_eq!(table.add_static(addr(2, 1), 64), ReturnCode::ENOMEM);
+
+        // Tentative addresses are not assigned, but their solicited-node
+        // address is accepted.
+        assert_eq!(table.len(), 1);
+        assert!(table.get(0) == Some(addr(0, 1)));
+        assert!(table.get(1).is_none());
+        assert!(!table.contains(addr(1, 1)));
+        assert!(!table.is_local(addr(1, 1)));
+        assert!(table.is_local(addr(1, 1).solicited_node()));
+        assert!(table.first_tentative() == Some(addr(1, 1)));
+
+        table.update(addr(1, 1), |e| {
+            e.state = AddrState::Preferred;
+            true
+        });
+        assert_eq!(table.len(), 2);
+        assert!(table.contains(addr(1, 1)));
+        assert_eq!(table.remove(addr(0, 1)), ReturnCode::SUCCESS);
+        assert_eq!(table.remove(addr(0, 1)), ReturnCode::EINVAL);
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn source_selection() {
+        let mut entries = [None; 4];
+        let table = IPAddrTable::new(&mut entries);
+        assert!(table.select_source(addr(1, 9)).is_none());
+
+        table.add(slaac(addr(0, 1), AddrState::Preferred, 100, 100));
+        table.add(slaac(addr(1, 1), AddrState::Deprecated, 100, 0));
+        table.add(slaac(addr(2, 1), AddrState::Preferred, 100, 100));
+        table.add(slaac(addr(3, 1), AddrState::Tentative, 100, 100));
+
+        // Link-local destinations use the link-local address
+        assert!(table.select_source(addr(0, 9)) == Some(addr(0, 1)));
+        // Preferred addresses win over deprecated ones with a longer match
+        assert!(table.select_source(addr(1, 9)) == Some(addr(2, 1)));
+        assert!(table.select_source(addr(2, 9)) == Some(addr(2, 1)));
+        // Duplicate address detection probes use the unspecified address
+        assert!(table.select_source(addr(3, 1).solicited_node()) == Some(IPAddr::new()));
+
+        assert!(table.is_on_link(addr(0, 9)));
+        assert!(table.is_on_link(addr(1, 9)));
+        assert!(!table.is_on_link(addr(3, 9)));
+        assert!(!table.is_on_link(addr(4, 9)));
+    }
+
+    #[test]
+    fn lifetimes() {
+        let mut entries = [None; 3];
+        let table = IPAddrTable::new(&mut entries);
+        table.add_static(addr(0, 1), 64);
+        table.add(slaac(addr(1, 1), AddrState::Preferred, 20, 10));
+        table.add(slaac(
+            addr(2, 1),
+            AddrState::Preferred,
+            INFINITE_LIFETIME,
+            INFINITE_LIFETIME,
+        ));
+
+        assert!(!table.tick(9));
+        assert!(table.tick(1));
+        assert!(table.find(addr(1, 1)).map(|e| e.state) == Some(AddrState::Deprecated));
+        assert!(table.contains(addr(1, 1)));
+        assert!(table.tick(10));
+        assert!(table.find(addr(1, 1)).is_none());
+        assert_eq!(table.len(), 2);
+        assert!(table.find(addr(2, 1)).map(|e| e.state) == Some(AddrState::Preferred));
+    }
+}
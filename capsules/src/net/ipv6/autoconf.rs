@@ -0,0 +1,1105 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862) with optional
+//! stateless DHCPv6 (RFC 8415, section 6.1).
+//!
+//! `Autoconf` assigns addresses in the `IPAddrTable` shared with the rest of
+//! the IP stack:
+//!
+//! - On `start`, a link-local address is formed from the 802.15.4 extended
+//!   address (RFC 4944, section 6) and verified with duplicate address
+//!   detection (DAD): a Neighbor Solicitation is sent from the unspecified
+//!   address and, if no other node claims the address within
+//!   `RETRANS_TIMER_SECONDS`, it becomes preferred.
+//! - Router Solicitations are then sent until a Router Advertisement
+//!   arrives. For every autonomous /64 prefix in the advertisement an
+//!   address is formed from the prefix and the same interface identifier,
+//!   which again undergoes DAD. Lifetimes are aged once a second and
+//!   refreshed by later advertisements, following the two-hour rule of
+//!   RFC 4862, section 5.5.3. If no router answers any of the solicitations,
+//!   the fallback addresses set by the board are assigned as static
+//!   addresses.
+//! - Duplicates are detected from Neighbor Solicitations for tentative
+//!   addresses and Neighbor Advertisements for any local address; such
+//!   addresses are marked `AddrState::Duplicate` and never used. Duplicate
+//!   address detection probes for assigned addresses are answered with a
+//!   Neighbor Advertisement to defend the address. Address resolution is not
+//!   needed on 6LoWPAN links, where link-layer addresses are derived from
+//!   interface identifiers, so other solicitations are ignored.
+//! - If DHCPv6 is enabled and an advertisement has the Managed or Other
+//!   configuration flag set, an Information-Request is sent to obtain the
+//!   DNS servers. Stateful address assignment is not supported.
+//!
+//! ICMPv6 messages are sent through a `UDPSendStruct` so that they are
+//! queued with UDP traffic in the `MuxUdpSender`; the same struct is bound to
+//! the DHCPv6 client port. Received ICMPv6 messages come from the
+//! `IP6Receiver` ICMP client. The network capability of the layer must allow
+//! the link-local multicast addresses and the routers' link-local addresses,
+//! for example with `AddrRange::OnLink`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let autoconf = static_init!(
+//!     Autoconf<'static, VirtualMuxAlarm<'static, Ast>, IP6SendStruct<...>>,
+//!     Autoconf::new(addr_table, udp_send, udp_recv, udp_port_table, alarm,
+//!                   LeasableBuffer::new(&mut AUTOCONF_BUF), ext_addr, net_cap)
+//! );
+//! udp_send.set_client(autoconf);
+//! udp_recv.set_client(autoconf);
+//! ip_receive.set_icmp_client(autoconf);
+//! alarm.set_client(autoconf);
+//! autoconf.set_fallback_addrs(&FALLBACK_ADDRS);
+//! autoconf.enable_dhcpv6();
+//! autoconf.start();
+//! ```
+
+use crate::net::icmpv6::icmpv6::{ICMP6Header, ICMP6HeaderOptions, ICMP6Type};
+use crate::net::icmpv6::ndp::{self, nd_opt, NdOptionIterator, PrefixInfo};
+use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::addr_table::INFINITE_LIFETIME;
+use crate::net::ipv6::addr_table::{AddrEntry, AddrOrigin, AddrState, IPAddrTable};
+use crate::net::ipv6::dhcpv6::{self, Dhcpv6Message};
+use crate::net::ipv6::ip_utils::{IPAddr, ALL_NODES_MCAST, ALL_ROUTERS_MCAST};
+use crate::net::ipv6::ipv6::IP6Header;
+use crate::net::ipv6::ipv6_recv::IP6RecvClient;
+use crate::net::ipv6::ipv6_send::IP6Sender;
+use crate::net::network_capabilities::NetworkCapability;
+use crate::net::udp::udp_port_table::UdpPortManager;
+use crate::net::udp::udp_recv::{UDPReceiver, UDPRecvClient};
+use crate::net::udp::udp_send::{UDPSendClient, UDPSendStruct, UDPSender};
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{MapCell, OptionalCell};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::ReturnCode;
+
+/// Time to wait for a reply to a duplicate address detection probe.
+pub const RETRANS_TIMER_SECONDS: u8 = 1;
+/// Number of Router Solicitations sent before giving up.
+pub const MAX_RTR_SOLICITATIONS: u8 = 3;
+pub const RTR_SOLICITATION_INTERVAL_SECONDS: u8 = 4;
+/// Number of DNS servers remembered from a DHCPv6 Reply.
+pub const MAX_DNS_SERVERS: usize = 2;
+
+const TWO_HOURS_SECONDS: u32 = 2 * 60 * 60;
+const IID_PREFIX_LEN: u8 = 64;
+
+// DHCPv6 Information-Request timing (RFC 8415, sections 7.6 and 21.23)
+const INF_TIMEOUT_SECONDS: u32 = 1;
+const INF_MAX_RT_SECONDS: u32 = 3600;
+const IRT_DEFAULT_SECONDS: u32 = 86400;
+const IRT_MINIMUM_SECONDS: u32 = 600;
+
+/// Clients are notified when the configuration changes.
+pub trait AutoconfClient {
+    /// An address was assigned, deprecated, removed or found to be a
+    /// duplicate. The current addresses can be read from the address table.
+    fn addresses_changed(&self);
+
+    /// New DNS servers were received via DHCPv6.
+    fn dns_servers_changed(&self);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum DhcpState {
+    Disabled,
+    Idle,
+    Requesting,
+    Configured,
+}
+
+/// A message autoconfiguration has to send.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Message {
+    /// An unsolicited Neighbor Advertisement defending an address.
+    Advertisement(IPAddr),
+    /// A duplicate address detection probe.
+    Solicitation(IPAddr),
+    RouterSolicitation,
+    InformationRequest {
+        xid: u32,
+        elapsed: u16,
+    },
+}
+
+/// The addresses, routers and DHCPv6 configuration, and the timers that
+/// drive them. `Autoconf` sends the messages this asks for and passes it
+/// the messages it receives.
+struct AutoconfState<'a> {
+    addr_table: &'a IPAddrTable<'a>,
+    ext_addr: [u8; 8],
+    client: OptionalCell<&'a dyn AutoconfClient>,
+    running: Cell<bool>,
+
+    /// Address currently undergoing duplicate address detection.
+    dad_addr: OptionalCell<IPAddr>,
+    dad_sent: Cell<bool>,
+    dad_timer: Cell<u8>,
+    /// Address whose duplicate address detection probe must be answered.
+    defend_addr: OptionalCell<IPAddr>,
+
+    rs_remaining: Cell<u8>,
+    rs_timer: Cell<u8>,
+    /// Whether Router Solicitations were sent and no router has answered.
+    soliciting: Cell<bool>,
+    /// Addresses assigned if no router answers.
+    fallback_addrs: Cell<&'a [IPAddr]>,
+    router: OptionalCell<IPAddr>,
+    router_lifetime: Cell<u32>,
+
+    dhcp_state: Cell<DhcpState>,
+    dhcp_xid: Cell<u32>,
+    /// Seconds until the next Information-Request.
+    dhcp_timer: Cell<u32>,
+    dhcp_rt: Cell<u32>,
+    dhcp_elapsed: Cell<u32>,
+    dns_servers: Cell<[IPAddr; MAX_DNS_SERVERS]>,
+    dns_count: Cell<usize>,
+}
+
+impl<'a> AutoconfState<'a> {
+    fn new(addr_table: &'a IPAddrTable<'a>, ext_addr: [u8; 8]) -> AutoconfState<'a> {
+        AutoconfState {
+            addr_table: addr_table,
+            ext_addr: ext_addr,
+            client: OptionalCell::empty(),
+            running: Cell::new(false),
+            dad_addr: OptionalCell::empty(),
+            dad_sent: Cell::new(false),
+            dad_timer: Cell::new(0),
+            defend_addr: OptionalCell::empty(),
+            rs_remaining: Cell::new(0),
+            rs_timer: Cell::new(0),
+            soliciting: Cell::new(false),
+            fallback_addrs: Cell::new(&[]),
+            router: OptionalCell::empty(),
+            router_lifetime: Cell::new(0),
+            dhcp_state: Cell::new(DhcpState::Disabled),
+            dhcp_xid: Cell::new(0),
+            dhcp_timer: Cell::new(0),
+            dhcp_rt: Cell::new(INF_TIMEOUT_SECONDS),
+            dhcp_elapsed: Cell::new(0),
+            dns_servers: Cell::new([IPAddr::new(); MAX_DNS_SERVERS]),
+            dns_count: Cell::new(0),
+        }
+    }
+
+    /// Adds the link-local address as a tentative address, or solicits
+    /// routers if the board already configured it.
+    fn start(&self) -> ReturnCode {
+        if self.running.get() {
+            return ReturnCode::EALREADY;
+        }
+        let link_local = self.link_local_addr();
+        if self.addr_table.find(link_local).is_some() {
+            self.rs_remaining.set(MAX_RTR_SOLICITATIONS);
+        } else {
+            let result = self.addr_table.add(AddrEntry {
+                addr: link_local,
+                prefix_len: IID_PREFIX_LEN,
+                state: AddrState::Tentative,
+                origin: AddrOrigin::LinkLocal,
+                valid_lifetime: INFINITE_LIFETIME,
+                preferred_lifetime: INFINITE_LIFETIME,
+            });
+            if result != ReturnCode::SUCCESS {
+                return result;
+            }
+        }
+        self.running.set(true);
+        ReturnCode::SUCCESS
+    }
+
+    fn link_local_addr(&self) -> IPAddr {
+        IPAddr::generate_from_mac(MacAddress::Long(self.ext_addr))
+    }
+
+    fn notify_addresses_changed(&self) {
+        self.client.map(|client| client.addresses_changed());
+    }
+
+    /// Runs once a second: ages lifetimes and advances the DAD, router
+    /// solicitation and DHCPv6 timers.
+    fn tick(&self) {
+        if self.addr_table.tick(1) {
+            self.notify_addresses_changed();
+        }
+
+        match self.dad_addr.map(|addr| *addr) {
+            None => {
+                if let Some(addr) = self.addr_table.first_tentative() {
+                    self.dad_addr.set(addr);
+                    self.dad_sent.set(false);
+                }
+            }
+            Some(addr) => {
+                if self.dad_sent.get() {
+                    if self.dad_timer.get() > 0 {
+                        self.dad_timer.set(self.dad_timer.get() - 1);
+                    } else {
+                        self.dad_complete(addr);
+                    }
+                }
+            }
+        }
+
+        if self.rs_timer.get() > 0 {
+            self.rs_timer.set(self.rs_timer.get() - 1);
+            if self.rs_timer.get() == 0 && self.rs_remaining.get() == 0 && self.soliciting.get() {
+                self.soliciting.set(false);
+                self.assign_fallback_addrs();
+            }
+        }
+
+        if self.router.is_some() {
+            let lifetime = self.router_lifetime.get().saturating_sub(1);
+            self.router_lifetime.set(lifetime);
+            if lifetime == 0 {
+                self.router.clear();
+            }
+        }
+
+        match self.dhcp_state.get() {
+            DhcpState::Requesting | DhcpState::Configured => {
+                self.dhcp_timer.set(self.dhcp_timer.get().saturating_sub(1));
+                self.dhcp_elapsed.set(self.dhcp_elapsed.get() + 1);
+            }
+            _ => {}
+        }
+    }
+
+    /// No router answered the last Router Solicitation.
+    fn assign_fallback_addrs(&self) {
+        let mut changed = false;
+        for addr in self.fallback_addrs.get().iter() {
+            changed |= self.addr_table.add_static(*addr, IID_PREFIX_LEN) == ReturnCode::SUCCESS;
+        }
+        if changed {
+            self.notify_addresses_changed();
+        }
+    }
+
+    /// No other node claimed `addr` within the retransmission timer, so it
+    /// can be used.
+    fn dad_complete(&self, addr: IPAddr) {
+        self.dad_addr.clear();
+        self.addr_table.update(addr, |entry| {
+            if entry.state == AddrState::Tentative {
+                entry.state = if entry.preferred_lifetime > 0 {
+                    AddrState::Preferred
+                } else {
+                    AddrState::Deprecated
+                };
+            }
+            true
+        });
+        if addr == self.link_local_addr() {
+            self.rs_remaining.set(MAX_RTR_SOLICITATIONS);
+            self.rs_timer.set(0);
+        }
+        self.notify_addresses_changed();
+    }
+
+    fn duplicate_detected(&self, addr: IPAddr) {
+        self.addr_table.update(addr, |entry| {
+            entry.state = AddrState::Duplicate;
+            true
+        });
+        if self.dad_addr.contains(&addr) {
+            self.dad_addr.clear();
+        }
+        if addr == self.link_local_addr() {
+            // Addresses formed from a prefix share the interface identifier
+            // of the link-local address, so stop autoconfiguration
+            // (RFC 4862, section 5.4.5).
+            self.rs_remaining.set(0);
+        }
+        self.notify_addresses_changed();
+    }
+
+    /// The highest priority message to send. Defending an address is only
+    /// tried once: unsolicited advertisements are not retransmitted, the
+    /// peer's DAD simply detects the next probe.
+    fn next_message(&self) -> Option<Message> {
+        if let Some(target) = self.defend_addr.take() {
+            return Some(Message::Advertisement(target));
+        }
+
+        if let Some(target) = self.dad_addr.map(|addr| *addr) {
+            if !self.dad_sent.get() {
+                return Some(Message::Solicitation(target));
+            }
+        }
+
+        let link_local_ok = self.addr_table.contains(self.link_local_addr());
+        if self.rs_remaining.get() > 0 && self.rs_timer.get() == 0 && link_local_ok {
+            return Some(Message::RouterSolicitation);
+        }
+
+        if self.dhcp_state.get() == DhcpState::Configured && self.dhcp_timer.get() == 0 {
+            // Information refresh time expired
+            self.start_dhcp_request();
+        }
+        if self.dhcp_state.get() == DhcpState::Requesting
+            && self.dhcp_timer.get() == 0
+            && link_local_ok
+        {
+            // Elapsed time is in hundredths of a second.
+            let elapsed = cmp::min(self.dhcp_elapsed.get() * 100, 0xffff) as u16;
+            return Some(Message::InformationRequest {
+                xid: self.dhcp_xid.get(),
+                elapsed: elapsed,
+            });
+        }
+        None
+    }
+
+    /// `message` was queued for sending, so its timer starts.
+    fn message_sent(&self, message: Message) {
+        match message {
+            Message::Advertisement(_) => {}
+            Message::Solicitation(_) => {
+                self.dad_sent.set(true);
+                self.dad_timer.set(RETRANS_TIMER_SECONDS);
+            }
+            Message::RouterSolicitation => {
+                self.rs_remaining.set(self.rs_remaining.get() - 1);
+                self.rs_timer.set(RTR_SOLICITATION_INTERVAL_SECONDS);
+                self.soliciting.set(true);
+            }
+            Message::InformationRequest { .. } => {
+                let rt = self.dhcp_rt.get();
+                self.dhcp_timer.set(rt);
+                self.dhcp_rt.set(cmp::min(rt * 2, INF_MAX_RT_SECONDS));
+            }
+        }
+    }
+
+    fn start_dhcp_request(&self) {
+        // The extended address stands in for a random number to make
+        // transaction ids of different nodes differ.
+        let seed = self
+            .ext_addr
+            .iter()
+            .fold(self.dhcp_xid.get(), |acc, &b| acc.rotate_left(5) ^ b as u32);
+        self.dhcp_xid.set(seed.wrapping_add(1) & 0x00ff_ffff);
+        self.dhcp_state.set(DhcpState::Requesting);
+        self.dhcp_timer.set(0);
+        self.dhcp_rt.set(INF_TIMEOUT_SECONDS);
+        self.dhcp_elapsed.set(0);
+    }
+
+    fn receive_ra(&self, src: IPAddr, flags: u8, router_lifetime: u16, body: &[u8]) {
+        if !src.is_unicast_link_local() || body.len() < ndp::RA_BODY_LEN {
+            return;
+        }
+        let options = &body[ndp::RA_BODY_LEN..];
+        let mut iter = NdOptionIterator::new(options);
+        while iter.next().is_some() {}
+        if iter.is_malformed() {
+            return;
+        }
+
+        self.rs_remaining.set(0);
+        self.soliciting.set(false);
+        if router_lifetime > 0 {
+            self.router.set(src);
+            self.router_lifetime.set(router_lifetime as u32);
+        } else if self.router.contains(&src) {
+            self.router.clear();
+        }
+
+        let link_local_ok = self
+            .addr_table
+            .find(self.link_local_addr())
+            .map_or(false, |entry| entry.state != AddrState::Duplicate);
+        let mut changed = false;
+        if link_local_ok {
+            for option in NdOptionIterator::new(options) {
+                if option.opt_type != nd_opt::PREFIX_INFO {
+                    continue;
+                }
+                if let Some((_, info)) = PrefixInfo::decode(option.value).done() {
+                    changed |= self.process_prefix(&info);
+                }
+            }
+        }
+
+        if flags & (ndp::RA_FLAG_MANAGED | ndp::RA_FLAG_OTHER) != 0
+            && self.dhcp_state.get() == DhcpState::Idle
+        {
+            self.start_dhcp_request();
+        }
+        if changed {
+            self.notify_addresses_changed();
+        }
+    }
+
+    /// Forms or refreshes an address from a Prefix Information option
+    /// (RFC 4862, section 5.5.3). Returns true if the state of an assigned
+    /// address changed.
+    fn process_prefix(&self, info: &PrefixInfo) -> bool {
+        if !info.autonomous
+            || info.prefix.is_unicast_link_local()
+            || info.preferred_lifetime > info.valid_lifetime
+            || info.prefix_len != IID_PREFIX_LEN
+        {
+            return false;
+        }
+        let mut addr = self.link_local_addr();
+        addr.set_prefix(&info.prefix.0, info.prefix_len);
+
+        match self.addr_table.find(addr) {
+            Some(entry) => {
+                if entry.state == AddrState::Duplicate || entry.origin != AddrOrigin::Slaac {
+                    return false;
+                }
+                let mut changed = false;
+                self.addr_table.update(addr, |entry| {
+                    entry.preferred_lifetime = info.preferred_lifetime;
+                    let remaining = entry.valid_lifetime;
+                    if info.valid_lifetime > TWO_HOURS_SECONDS || info.valid_lifetime > remaining {
+                        entry.valid_lifetime = info.valid_lifetime;
+                    } else if remaining > TWO_HOURS_SECONDS {
+                        entry.valid_lifetime = TWO_HOURS_SECONDS;
+                    }
+                    if entry.state == AddrState::Deprecated && entry.preferred_lifetime > 0 {
+                        entry.state = AddrState::Preferred;
+                        changed = true;
+                    } else if entry.state == AddrState::Preferred && entry.preferred_lifetime == 0 {
+                        entry.state = AddrState::Deprecated;
+                        changed = true;
+                    }
+                    true
+                });
+                changed
+            }
+            None => {
+                if info.valid_lifetime > 0 {
+                    // Assigned once duplicate address detection completes.
+                    self.addr_table.add(AddrEntry {
+                        addr: addr,
+                        prefix_len: info.prefix_len,
+                        state: AddrState::Tentative,
+                        origin: AddrOrigin::Slaac,
+                        valid_lifetime: info.valid_lifetime,
+                        preferred_lifetime: info.preferred_lifetime,
+                    });
+                }
+                false
+            }
+        }
+    }
+
+    fn receive_ns(&self, src: IPAddr, body: &[u8]) {
+        let target = match ndp::decode_target(body).done() {
+            Some((_, target)) => target,
+            None => return,
+        };
+        if !src.is_unspecified() {
+            // Address resolution, which is not used on 6LoWPAN links.
+            return;
+        }
+        match self.addr_table.find(target).map(|entry| entry.state) {
+            Some(AddrState::Tentative) => self.duplicate_detected(target),
+            Some(AddrState::Preferred) | Some(AddrState::Deprecated) => {
+                self.defend_addr.set(target);
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_na(&self, body: &[u8]) {
+        let target = match ndp::decode_target(body).done() {
+            Some((_, target)) => target,
+            None => return,
+        };
+        match self.addr_table.find(target).map(|entry| entry.state) {
+            Some(AddrState::Duplicate) | None => {}
+            Some(_) => self.duplicate_detected(target),
+        }
+    }
+
+    fn receive_dhcp(&self, payload: &[u8]) {
+        if self.dhcp_state.get() != DhcpState::Requesting {
+            return;
+        }
+        let msg = match Dhcpv6Message::decode(payload).done() {
+            Some((_, msg)) => msg,
+            None => return,
+        };
+        if msg.msg_type != dhcpv6::msg_type::REPLY
+            || msg.xid != self.dhcp_xid.get()
+            || msg.status_code() != 0
+        {
+            return;
+        }
+
+        let mut servers = [IPAddr::new(); MAX_DNS_SERVERS];
+        let count = msg.dns_servers(&mut servers);
+        self.dns_servers.set(servers);
+        self.dns_count.set(count);
+
+        let refresh = msg.refresh_time().map_or(IRT_DEFAULT_SECONDS, |time| {
+            cmp::max(time, IRT_MINIMUM_SECONDS)
+        });
+        self.dhcp_state.set(DhcpState::Configured);
+        self.dhcp_timer.set(refresh);
+        self.client.map(|client| client.dns_servers_changed());
+    }
+}
+
+pub struct Autoconf<'a, A: Alarm<'a>, T: IP6Sender<'a>> {
+    sender: &'a UDPSendStruct<'a, T>,
+    udp_receiver: &'a UDPReceiver<'a>,
+    port_table: &'static UdpPortManager,
+    alarm: &'a A,
+    buffer: MapCell<LeasableBuffer<'static, u8>>,
+    net_cap: &'static NetworkCapability,
+    sending: Cell<bool>,
+    state: AutoconfState<'a>,
+}
+
+impl<'a, A: Alarm<'a>, T: IP6Sender<'a>> Autoconf<'a, A, T> {
+    pub fn new(
+        addr_table: &'a IPAddrTable<'a>,
+        sender: &'a UDPSendStruct<'a, T>,
+        udp_receiver: &'a UDPReceiver<'a>,
+        port_table: &'static UdpPortManager,
+        alarm: &'a A,
+        buffer: LeasableBuffer<'static, u8>,
+        ext_addr: [u8; 8],
+        net_cap: &'static NetworkCapability,
+    ) -> Autoconf<'a, A, T> {
+        Autoconf {
+            sender: sender,
+            udp_receiver: udp_receiver,
+            port_table: port_table,
+            alarm: alarm,
+            buffer: MapCell::new(buffer),
+            net_cap: net_cap,
+            sending: Cell::new(false),
+            state: AutoconfState::new(addr_table, ext_addr),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn AutoconfClient) {
+        self.state.client.set(client);
+    }
+
+    /// Sets the /64 addresses assigned as static addresses if no router
+    /// answers the Router Solicitations.
+    pub fn set_fallback_addrs(&self, addrs: &'a [IPAddr]) {
+        self.state.fallback_addrs.set(addrs);
+    }
+
+    /// Binds the DHCPv6 client port so that stateless DHCPv6 is used when
+    /// routers advertise it. Must be called before `start`.
+    pub fn enable_dhcpv6(&self) -> ReturnCode {
+        if self.state.dhcp_state.get() != DhcpState::Disabled {
+            return ReturnCode::EALREADY;
+        }
+        match self.port_table.create_socket() {
+            Ok(socket) => match self
+                .port_table
+                .bind(socket, dhcpv6::CLIENT_PORT, self.net_cap)
+            {
+                Ok((send_binding, recv_binding)) => {
+                    self.sender.set_binding(send_binding);
+                    self.udp_receiver.set_binding(recv_binding);
+                    self.state.dhcp_state.set(DhcpState::Idle);
+                    ReturnCode::SUCCESS
+                }
+                // Dropping the socket releases it.
+                Err(_socket) => ReturnCode::EBUSY,
+            },
+            Err(rcode) => rcode,
+        }
+    }
+
+    /// Starts autoconfiguration by adding the link-local address as a
+    /// tentative address. If the board already configured the link-local
+    /// address, routers are solicited immediately.
+    pub fn start(&self) -> ReturnCode {
+        let result = self.state.start();
+        if result == ReturnCode::SUCCESS {
+            self.schedule_tick();
+        }
+        result
+    }
+
+    /// The link-local address formed from the extended address.
+    pub fn link_local_addr(&self) -> IPAddr {
+        self.state.link_local_addr()
+    }
+
+    /// The link-local address of the default router, if a router with a
+    /// non-zero lifetime has advertised itself.
+    pub fn router(&self) -> Option<IPAddr> {
+        self.state.router.map(|router| *router)
+    }
+
+    /// Returns the `index`th DNS server received via DHCPv6.
+    pub fn dns_server(&self, index: usize) -> Option<IPAddr> {
+        if index < self.state.dns_count.get() {
+            Some(self.state.dns_servers.get()[index])
+        } else {
+            None
+        }
+    }
+
+    fn schedule_tick(&self) {
+        let interval = <A::Frequency>::frequency();
+        self.alarm
+            .set_alarm(self.alarm.now().wrapping_add(interval));
+    }
+
+    /// Sends the highest priority pending message, unless a message is
+    /// already being sent; `send_done` calls this again. Messages that
+    /// can't be queued are retried on the next tick.
+    fn send_pending(&self) {
+        if self.sending.get() {
+            return;
+        }
+        if let Some(message) = self.state.next_message() {
+            if self.send(message) {
+                self.state.message_sent(message);
+            }
+        }
+    }
+
+    fn send(&self, message: Message) -> bool {
+        let ext_addr = self.state.ext_addr;
+        match message {
+            Message::Advertisement(target) => {
+                let mut header = ICMP6Header::new(ICMP6Type::Type136);
+                header.set_options(ICMP6HeaderOptions::Type136 {
+                    flags: ndp::NA_FLAG_OVERRIDE,
+                });
+                self.send_nd(ALL_NODES_MCAST, header, |buf| {
+                    let off = ndp::encode_target(buf, 0, &target).done()?.0;
+                    let ll_addr = MacAddress::Long(ext_addr);
+                    ndp::encode_ll_addr_option(buf, off, nd_opt::TARGET_LL_ADDR, ll_addr)
+                        .done()
+                        .map(|(off, _)| off)
+                })
+            }
+            // The probe is sent from the unspecified address (see
+            // `IPAddrTable::select_source`) and therefore carries no
+            // link-layer address option.
+            Message::Solicitation(target) => {
+                let header = ICMP6Header::new(ICMP6Type::Type135);
+                self.send_nd(target.solicited_node(), header, |buf| {
+                    ndp::encode_target(buf, 0, &target)
+                        .done()
+                        .map(|(off, _)| off)
+                })
+            }
+            Message::RouterSolicitation => {
+                let header = ICMP6Header::new(ICMP6Type::Type133);
+                self.send_nd(ALL_ROUTERS_MCAST, header, |buf| {
+                    let ll_addr = MacAddress::Long(ext_addr);
+                    ndp::encode_ll_addr_option(buf, 0, nd_opt::SOURCE_LL_ADDR, ll_addr)
+                        .done()
+                        .map(|(off, _)| off)
+                })
+            }
+            Message::InformationRequest { xid, elapsed } => self.transmit(
+                |buf| {
+                    dhcpv6::encode_information_request(buf, xid, elapsed, &ext_addr)
+                        .done()
+                        .map(|(off, _)| off)
+                },
+                |buf| {
+                    self.sender.send_to(
+                        dhcpv6::ALL_DHCP_SERVERS,
+                        dhcpv6::SERVER_PORT,
+                        buf,
+                        self.net_cap,
+                    )
+                },
+            ),
+        }
+    }
+
+    /// Encodes a message with `build` into the buffer and passes it to
+    /// `send`. Returns true if the message was queued.
+    fn transmit<B, S>(&self, build: B, send: S) -> bool
+    where
+        B: FnOnce(&mut [u8]) -> Option<usize>,
+        S: FnOnce(LeasableBuffer<'static, u8>) -> Result<(), LeasableBuffer<'static, u8>>,
+    {
+        let mut buf = match self.buffer.take() {
+            Some(buf) => buf,
+            None => return false,
+        };
+        buf.reset();
+        match build(&mut buf[..]) {
+            Some(len) => {
+                buf.slice(0..len);
+                match send(buf) {
+                    Ok(()) => {
+                        self.sending.set(true);
+                        true
+                    }
+                    Err(buf) => {
+                        self.buffer.replace(buf);
+                        false
+                    }
+                }
+            }
+            None => {
+                self.buffer.replace(buf);
+                false
+            }
+        }
+    }
+
+    fn send_nd(
+        &self,
+        dst: IPAddr,
+        header: ICMP6Header,
+        build: impl FnOnce(&mut [u8]) -> Option<usize>,
+    ) -> bool {
+        self.transmit(build, |buf| {
+            self.sender.send_icmp(dst, header, buf, self.net_cap)
+        })
+    }
+}
+
+impl<'a, A: Alarm<'a>, T: IP6Sender<'a>> time::AlarmClient for Autoconf<'a, A, T> {
+    fn fired(&self) {
+        if self.state.running.get() {
+            self.state.tick();
+            self.send_pending();
+            self.schedule_tick();
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, T: IP6Sender<'a>> IP6RecvClient for Autoconf<'a, A, T> {
+    fn receive(&self, ip_header: IP6Header, payload: &[u8]) {
+        if !self.state.running.get()
+            || payload.len() < ICMP6Header::new(ICMP6Type::Type1).get_hdr_size()
+        {
+            return;
+        }
+        let header = match ICMP6Header::decode(payload).done() {
+            Some((_, header)) => header,
+            None => return,
+        };
+        // Neighbor Discovery messages must not have been forwarded by a
+        // router (RFC 4861, section 6.1).
+        if !header.is_neighbor_discovery() || ip_header.hop_limit != 255 || header.get_code() != 0 {
+            return;
+        }
+        let src = ip_header.get_src_addr();
+        let body = &payload[header.get_hdr_size()..];
+        match header.get_options() {
+            ICMP6HeaderOptions::Type134 {
+                flags,
+                router_lifetime,
+                ..
+            } => self.state.receive_ra(src, flags, router_lifetime, body),
+            ICMP6HeaderOptions::Type135 { .. } => {
+                self.state.receive_ns(src, body);
+                self.send_pending();
+            }
+            ICMP6HeaderOptions::Type136 { .. } => self.state.receive_na(body),
+            _ => {}
+        }
+    }
+}
+
+impl<'a, A: Alarm<'a>, T: IP6Sender<'a>> UDPRecvClient for Autoconf<'a, A, T> {
+    fn receive(
+        &self,
+        _src_addr: IPAddr,
+        _dst_addr: IPAddr,
+        _src_port: u16,
+        _dst_port: u16,
+        payload: &[u8],
+    ) {
+        self.state.receive_dhcp(payload);
+    }
+}
+
+impl<'a, A: Alarm<'a>, T: IP6Sender<'a>> UDPSendClient for Autoconf<'a, A, T> {
+    fn send_done(&self, _result: ReturnCode, dgram: LeasableBuffer<'static, u8>) {
+        // Lost messages are covered by the DAD, router solicitation and
+        // DHCPv6 retransmission timers.
+        self.buffer.replace(dgram);
+        self.sending.set(false);
+        self.send_pending();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    const EXT_ADDR: [u8; 8] = [0x02, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+    const PREFIX: [u8; 8] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1];
+
+    #[derive(Default)]
+    struct TestClient {
+        addresses_changed: Cell<usize>,
+        dns_servers_changed: Cell<usize>,
+    }
+
+    impl AutoconfClient for TestClient {
+        fn addresses_changed(&self) {
+            self.addresses_changed.set(self.addresses_changed.get() + 1);
+        }
+
+        fn dns_servers_changed(&self) {
+            self.dns_servers_changed
+                .set(self.dns_servers_changed.get() + 1);
+        }
+    }
+
+    fn router_addr() -> IPAddr {
+        let mut addr = IPAddr::new();
+        addr.set_unicast_link_local();
+        addr.0[15] = 1;
+        addr
+    }
+
+    fn global_addr(state: &AutoconfState) -> IPAddr {
+        let mut addr = state.link_local_addr();
+        addr.set_prefix(&PREFIX, 64);
+        addr
+    }
+
+    /// The body of a Router Advertisement with a Prefix Information option
+    /// for `PREFIX`.
+    fn ra_body(valid: u32, preferred: u32) -> Vec<u8> {
+        let mut body = std::vec![0; ndp::RA_BODY_LEN];
+        body.extend_from_slice(&[nd_opt::PREFIX_INFO, 4, 64, 0xc0]);
+        body.extend_from_slice(&valid.to_be_bytes());
+        body.extend_from_slice(&preferred.to_be_bytes());
+        body.extend_from_slice(&[0; 4]);
+        body.extend_from_slice(&PREFIX);
+        body.extend_from_slice(&[0; 8]);
+        body
+    }
+
+    /// A DHCPv6 Reply with one DNS server.
+    fn dhcp_reply(xid: u32, refresh: u32) -> Vec<u8> {
+        let mut reply = std::vec![dhcpv6::msg_type::REPLY];
+        reply.extend_from_slice(&xid.to_be_bytes()[1..]);
+        reply.extend_from_slice(&[0, 23, 0, 16]);
+        reply.extend_from_slice(&router_addr().0);
+        reply.extend_from_slice(&[0, 32, 0, 4]);
+        reply.extend_from_slice(&refresh.to_be_bytes());
+        reply
+    }
+
+    /// Sends the messages that are due, and returns them.
+    fn flush(state: &AutoconfState) -> Vec<Message> {
+        let mut sent = Vec::new();
+        while let Some(message) = state.next_message() {
+            state.message_sent(message);
+            sent.push(message);
+        }
+        sent
+    }
+
+    /// Runs for `seconds`, and returns the messages sent.
+    fn run(state: &AutoconfState, seconds: usize) -> Vec<Message> {
+        let mut sent = Vec::new();
+        for _ in 0..seconds {
+            state.tick();
+            sent.extend(flush(state));
+        }
+        sent
+    }
+
+    fn state_of(addr_table: &IPAddrTable, addr: IPAddr) -> Option<AddrState> {
+        addr_table.find(addr).map(|entry| entry.state)
+    }
+
+    #[test]
+    fn link_local_dad() {
+        let mut entries = [None; 4];
+        let addr_table = IPAddrTable::new(&mut entries);
+        let client = TestClient::default();
+        let state = AutoconfState::new(&addr_table, EXT_ADDR);
+        state.client.set(&client);
+        let link_local = state.link_local_addr();
+
+        assert_eq!(state.start(), ReturnCode::SUCCESS);
+        assert_eq!(state.start(), ReturnCode::EALREADY);
+        assert_eq!(
+            state_of(&addr_table, link_local),
+            Some(AddrState::Tentative)
+        );
+
+        // A probe, then the address is assigned once nobody claims it.
+        assert_eq!(run(&state, 2), [Message::Solicitation(link_local)]);
+        assert_eq!(
+            state_of(&addr_table, link_local),
+            Some(AddrState::Tentative)
+        );
+        assert_eq!(run(&state, 1), [Message::RouterSolicitation]);
+        assert_eq!(
+            state_of(&addr_table, link_local),
+            Some(AddrState::Preferred)
+        );
+        assert_eq!(client.addresses_changed.get(), 1);
+
+        // Probes for an assigned address are answered once.
+        let mut unspecified = IPAddr::new();
+        state.receive_ns(unspecified, &link_local.0);
+        assert_eq!(flush(&state), [Message::Advertisement(link_local)]);
+
+        // Solicitations for address resolution are ignored.
+        unspecified.0[15] = 9;
+        state.receive_ns(unspecified, &link_local.0);
+        assert_eq!(flush(&state), []);
+    }
+
+    #[test]
+    fn link_local_duplicate() {
+        let mut entries = [None; 4];
+        let addr_table = IPAddrTable::new(&mut entries);
+        let state = AutoconfState::new(&addr_table, EXT_ADDR);
+        let link_local = state.link_local_addr();
+
+        state.start();
+        assert_eq!(run(&state, 1), [Message::Solicitation(link_local)]);
+        // Another node probes for the same address.
+        state.receive_ns(IPAddr::new(), &link_local.0);
+        assert_eq!(
+            state_of(&addr_table, link_local),
+            Some(AddrState::Duplicate)
+        );
+
+        // Without an interface identifier there is nothing to configure.
+        assert_eq!(run(&state, 20), []);
+        state.receive_ra(router_addr(), 0, 1800, &ra_body(3600, 1800));
+        assert_eq!(state_of(&addr_table, global_addr(&state)), None);
+    }
+
+    #[test]
+    fn router_advertisement() {
+        let mut entries = [None; 4];
+        let addr_table = IPAddrTable::new(&mut entries);
+        let client = TestClient::default();
+        let state = AutoconfState::new(&addr_table, EXT_ADDR);
+        state.client.set(&client);
+        let global = global_addr(&state);
+
+        state.start();
+        run(&state, 3);
+        state.receive_ra(router_addr(), 0, 1800, &ra_body(3600, 1800));
+        assert_eq!(state.router.map(|router| *router), Some(router_addr()));
+        assert_eq!(state_of(&addr_table, global), Some(AddrState::Tentative));
+
+        // The new address goes through DAD, and routers are no longer
+        // solicited.
+        assert_eq!(run(&state, 20), [Message::Solicitation(global)]);
+        assert_eq!(state_of(&addr_table, global), Some(AddrState::Preferred));
+
+        // A duplicate found later is never used.
+        state.receive_na(&global.0);
+        assert_eq!(state_of(&addr_table, global), Some(AddrState::Duplicate));
+        state.receive_ra(router_addr(), 0, 1800, &ra_body(3600, 1800));
+        assert_eq!(state_of(&addr_table, global), Some(AddrState::Duplicate));
+        assert_eq!(client.addresses_changed.get(), 3);
+
+        // The router expires with its lifetime.
+        state.receive_ra(router_addr(), 0, 2, &ra_body(3600, 1800));
+        run(&state, 2);
+        assert_eq!(state.router.map(|router| *router), None);
+    }
+
+    #[test]
+    fn router_solicitations_fall_back() {
+        let mut entries = [None; 4];
+        let addr_table = IPAddrTable::new(&mut entries);
+        let state = AutoconfState::new(&addr_table, EXT_ADDR);
+        let mut fallback = IPAddr::new();
+        fallback.0[15] = 1;
+        let fallback_addrs = [fallback];
+        state.fallback_addrs.set(&fallback_addrs);
+
+        state.start();
+        run(&state, 3);
+        let interval = RTR_SOLICITATION_INTERVAL_SECONDS as usize;
+        for _ in 1..MAX_RTR_SOLICITATIONS {
+            assert_eq!(run(&state, interval), [Message::RouterSolicitation]);
+        }
+        assert_eq!(run(&state, interval - 1), []);
+        assert_eq!(state_of(&addr_table, fallback), None);
+        run(&state, 1);
+        assert_eq!(state_of(&addr_table, fallback), Some(AddrState::Preferred));
+        assert_eq!(run(&state, 20), []);
+    }
+
+    #[test]
+    fn router_answers_before_fall_back() {
+        let mut entries = [None; 4];
+        let addr_table = IPAddrTable::new(&mut entries);
+        let state = AutoconfState::new(&addr_table, EXT_ADDR);
+        let mut fallback = IPAddr::new();
+        fallback.0[15] = 1;
+        let fallback_addrs = [fallback];
+        state.fallback_addrs.set(&fallback_addrs);
+
+        state.start();
+        run(&state, 3 + 2 * RTR_SOLICITATION_INTERVAL_SECONDS as usize);
+        state.receive_ra(router_addr(), 0, 1800, &ra_body(3600, 1800));
+        run(&state, 20);
+        assert_eq!(state_of(&addr_table, fallback), None);
+    }
+
+    #[test]
+    fn dhcpv6_information_request() {
+        let mut entries = [None; 4];
+        let addr_table = IPAddrTable::new(&mut entries);
+        let client = TestClient::default();
+        let state = AutoconfState::new(&addr_table, EXT_ADDR);
+        state.client.set(&client);
+        state.dhcp_state.set(DhcpState::Idle);
+
+        state.start();
+        run(&state, 3);
+        state.receive_ra(router_addr(), ndp::RA_FLAG_OTHER, 1800, &[0; 8]);
+        let xid = state.dhcp_xid.get();
+        assert_eq!(
+            flush(&state),
+            [Message::InformationRequest { xid, elapsed: 0 }]
+        );
+
+        // Requests are retransmitted with exponential backoff, and replies
+        // to other transactions are ignored.
+        assert_eq!(
+            run(&state, 1),
+            [Message::InformationRequest { xid, elapsed: 100 }]
+        );
+        assert_eq!(run(&state, 1), []);
+        state.receive_dhcp(&dhcp_reply(xid + 1, 0));
+        assert_eq!(
+            run(&state, 1),
+            [Message::InformationRequest { xid, elapsed: 300 }]
+        );
+
+        state.receive_dhcp(&dhcp_reply(xid, 60));
+        assert_eq!(client.dns_servers_changed.get(), 1);
+        assert_eq!(state.dns_count.get(), 1);
+        assert_eq!(state.dns_servers.get()[0], router_addr());
+
+        // The refresh time is at least IRT_MINIMUM.
+        let minimum = IRT_MINIMUM_SECONDS as usize;
+        assert_eq!(run(&state, minimum - 1), []);
+        match run(&state, 1)[..] {
+            [Message::InformationRequest { xid: new_xid, .. }] => assert_ne!(new_xid, xid),
+            ref sent => panic!("expected a new request, sent {:?}", sent),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Encoding and decoding of the DHCPv6 (RFC 8415) messages used by
+//! stateless DHCPv6 (RFC 8415, section 6.1): the client sends an
+//! Information-Request and the server answers with a Reply carrying
+//! configuration options such as the recursive DNS servers (RFC 3646).
+//!
+//! Stateful address assignment is not implemented; addresses are configured
+//! with SLAAC (see `autoconf.rs`).
+
+use crate::net::ipv6::ip_utils::IPAddr;
+use crate::net::stream::SResult;
+use crate::net::stream::{decode_u16, decode_u32, decode_u8};
+use crate::net::stream::{encode_bytes, encode_u16, encode_u8};
+
+pub const CLIENT_PORT: u16 = 546;
+pub const SERVER_PORT: u16 = 547;
+
+/// The All_DHCP_Relay_Agents_and_Servers address (`ff02::1:2`).
+pub const ALL_DHCP_SERVERS: IPAddr =
+    IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0, 0x02]);
+
+pub const HEADER_LEN: usize = 4;
+
+pub mod msg_type {
+    pub const REPLY: u8 = 7;
+    pub const INFORMATION_REQUEST: u8 = 11;
+}
+
+pub mod option {
+    pub const CLIENT_ID: u16 = 1;
+    pub const SERVER_ID: u16 = 2;
+    pub const ORO: u16 = 6;
+    pub const ELAPSED_TIME: u16 = 8;
+    pub const STATUS_CODE: u16 = 13;
+    pub const DNS_SERVERS: u16 = 23;
+    pub const INFORMATION_REFRESH_TIME: u16 = 32;
+}
+
+/// DUID type for a DUID based on a link-layer address (DUID-LL).
+const DUID_LL: u16 = 3;
+/// IANA hardware type for EUI-64 addresses, used for 802.15.4.
+const HW_TYPE_EUI64: u16 = 27;
+
+/// Encodes an Information-Request requesting the DNS servers and the
+/// information refresh time. `xid` is the 24-bit transaction id,
+/// `elapsed` the time since the first transmission in hundredths of a
+/// second, and `ext_addr` the 802.15.4 extended address used to form the
+/// client DUID.
+pub fn encode_information_request(
+    buf: &mut [u8],
+    xid: u32,
+    elapsed: u16,
+    ext_addr: &[u8; 8],
+) -> SResult<usize> {
+    let mut off = enc_consume!(buf, 0; encode_u8, msg_type::INFORMATION_REQUEST);
+    off = enc_consume!(buf, off; encode_u8, (xid >> 16) as u8);
+    off = enc_consume!(buf, off; encode_u16, xid as u16);
+
+    off = enc_consume!(buf, off; encode_u16, option::CLIENT_ID);
+    off = enc_consume!(buf, off; encode_u16, 4 + ext_addr.len() as u16);
+    off = enc_consume!(buf, off; encode_u16, DUID_LL);
+    off = enc_consume!(buf, off; encode_u16, HW_TYPE_EUI64);
+    off = enc_consume!(buf, off; encode_bytes, ext_addr);
+
+    off = enc_consume!(buf, off; encode_u16, option::ELAPSED_TIME);
+    off = enc_consume!(buf, off; encode_u16, 2);
+    off = enc_consume!(buf, off; encode_u16, elapsed);
+
+    off = enc_consume!(buf, off; encode_u16, option::ORO);
+    off = enc_consume!(buf, off; encode_u16, 4);
+    off = enc_consume!(buf, off; encode_u16, option::DNS_SERVERS);
+    off = enc_consume!(buf, off; encode_u16, option::INFORMATION_REFRESH_TIME);
+    stream_done!(off, off);
+}
+
+/// A decoded DHCPv6 client/server message.
+pub struct Dhcpv6Message<'b> {
+    pub msg_type: u8,
+    pub xid: u32,
+    options: &'b [u8],
+}
+
+impl<'b> Dhcpv6Message<'b> {
+    pub fn decode(buf: &'b [u8]) -> SResult<Dhcpv6Message<'b>> {
+        let (off, msg_type) = dec_try!(buf, 0; decode_u8);
+        let (off, xid_high) = dec_try!(buf, off; decode_u8);
+        let (off, xid_low) = dec_try!(buf, off; decode_u16);
+        stream_done!(
+            off,
+            Dhcpv6Message {
+                msg_type: msg_type,
+                xid: (xid_high as u32) << 16 | xid_low as u32,
+                options: &buf[off..],
+            }
+        );
+    }
+
+    /// Returns an iterator over the options of the message as
+    /// `(option code, option data)` pairs.
+    pub fn options(&self) -> Dhcpv6OptionIterator<'b> {
+        Dhcpv6OptionIterator {
+            buf: self.options,
+            offset: 0,
+        }
+    }
+
+    /// Returns the data of the first option with code `code`.
+    pub fn find_option(&self, code: u16) -> Option<&'b [u8]> {
+        self.options()
+            .find(|&(opt_code, _)| opt_code == code)
+            .map(|(_, data)| data)
+    }
+
+    /// Returns the status code of the message. A missing Status Code option
+    /// means success (0).
+    pub fn status_code(&self) -> u16 {
+        self.find_option(option::STATUS_CODE)
+            .and_then(|data| decode_u16(data).done())
+            .map_or(0, |(_, code)| code)
+    }
+
+    /// Returns the information refresh time in seconds, if present.
+    pub fn refresh_time(&self) -> Option<u32> {
+        self.find_option(option::INFORMATION_REFRESH_TIME)
+            .and_then(|data| decode_u32(data).done())
+            .map(|(_, time)| time)
+    }
+
+    /// Copies the DNS server addresses of the message into `servers` and
+    /// returns their number.
+    pub fn dns_servers(&self, servers: &mut [IPAddr]) -> usize {
+        self.find_option(option::DNS_SERVERS).map_or(0, |data| {
+            let mut count = 0;
+            for (server, addr) in servers.iter_mut().zip(data.chunks_exact(16)) {
+                server.0.copy_from_slice(addr);
+                count += 1;
+            }
+            count
+        })
+    }
+}
+
+pub struct Dhcpv6OptionIterator<'b> {
+    buf: &'b [u8],
+    offset: usize,
+}
+
+impl<'b> Iterator for Dhcpv6OptionIterator<'b> {
+    type Item = (u16, &'b [u8]);
+
+    fn next(&mut self) -> Option<(u16, &'b [u8])> {
+        let (off, code) = decode_u16(&self.buf[self.offset..]).done()?;
+        let (_, len) = decode_u16(&self.buf[self.offset + off..]).done()?;
+        let start = self.offset + 4;
+        let end = start + len as usize;
+        if end > self.buf.len() {
+            // Truncated option: stop iterating.
+            self.offset = self.buf.len();
+            return None;
+        }
+        self.offset = end;
+        Some((code, &self.buf[start..end]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn information_request() {
+        let mut buf = [0u8; 64];
+        let ext_addr = [1, 2, 3, 4, 5, 6, 7, 8];
+        let len = encode_information_request(&mut buf, 0x123456, 100, &ext_addr)
+            .done()
+            .unwrap()
+            .0;
+        assert_eq!(len, 34);
+        assert_eq!(&buf[..4], &[11, 0x12, 0x34, 0x56]);
+
+        let msg = Dhcpv6Message::decode(&buf[..len]).done().unwrap().1;
+        assert_eq!(msg.msg_type, msg_type::INFORMATION_REQUEST);
+        assert_eq!(msg.xid, 0x123456);
+        assert_eq!(
+            msg.find_option(option::CLIENT_ID),
+            Some(&[0, 3, 0, 27, 1, 2, 3, 4, 5, 6, 7, 8][..])
+        );
+        assert_eq!(msg.find_option(option::ELAPSED_TIME), Some(&[0, 100][..]));
+        assert_eq!(msg.find_option(option::ORO), Some(&[0, 23, 0, 32][..]));
+    }
+
+    #[test]
+    fn reply() {
+        let mut reply = [0u8; 4 + 4 + 32 + 4 + 4 + 4 + 2];
+        reply[..4].copy_from_slice(&[msg_type::REPLY, 0xab, 0xcd, 0xef]);
+        reply[4..8].copy_from_slice(&[0, 23, 0, 32]);
+        reply[8] = 0x20;
+        reply[23] = 0x01;
+        reply[24] = 0x20;
+        reply[39] = 0x02;
+        reply[40..48].copy_from_slice(&[0, 32, 0, 4, 0, 0, 0x0e, 0x10]);
+        // Truncated trailing option
+        reply[48..].copy_from_slice(&[0, 13, 0, 8, 0, 1]);
+
+        let msg = Dhcpv6Message::decode(&reply).done().unwrap().1;
+        assert_eq!(msg.msg_type, msg_type::REPLY);
+        assert_eq!(msg.xid, 0xabcdef);
+        assert_eq!(msg.refresh_time(), Some(3600));
+        assert_eq!(msg.status_code(), 0);
+
+        let mut servers = [IPAddr::new(); 1];
+        assert_eq!(msg.dns_servers(&mut servers), 1);
+        assert_eq!(servers[0].0[15], 0x01);
+        assert_eq!(msg.options().count(), 2);
+    }
+}
@@ -111,8 +111,55 @@ impl IPAddr {
     pub fn is_multicast(&self) -> bool {
         self.0[0] == 0xff
     }
+
+    /// Returns true for multicast addresses with interface-local or
+    /// link-local scope (RFC 4291, section 2.7).
+    pub fn is_link_local_multicast(&self) -> bool {
+        self.is_multicast() && (self.0[1] & 0x0f) <= 0x2
+    }
+
+    /// Returns the solicited-node multicast address for this address
+    /// (`ff02::1:ffXX:XXXX`), formed from its low-order 24 bits as described
+    /// in RFC 4291, section 2.7.1.
+    pub fn solicited_node(&self) -> IPAddr {
+        let mut addr = IPAddr([0; 16]);
+        addr.0[0] = 0xff;
+        addr.0[1] = 0x02;
+        addr.0[11] = 0x01;
+        addr.0[12] = 0xff;
+        addr.0[13..16].copy_from_slice(&self.0[13..16]);
+        addr
+    }
+
+    /// Returns the number of leading bits this address has in common with
+    /// `other`.
+    pub fn common_prefix_len(&self, other: &IPAddr) -> u8 {
+        let mut len = 0;
+        for (a, b) in self.0.iter().zip(other.0.iter()) {
+            let diff = a ^ b;
+            if diff != 0 {
+                return len + diff.leading_zeros() as u8;
+            }
+            len += 8;
+        }
+        len
+    }
+
+    /// Returns true if the first `prefix_len` bits of this address match
+    /// those of `prefix`.
+    pub fn matches_prefix(&self, prefix: &IPAddr, prefix_len: u8) -> bool {
+        self.common_prefix_len(prefix) >= prefix_len
+    }
 }
 
+/// The link-local all-nodes multicast address (`ff02::1`).
+pub const ALL_NODES_MCAST: IPAddr =
+    IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+
+/// The link-local all-routers multicast address (`ff02::2`).
+pub const ALL_ROUTERS_MCAST: IPAddr =
+    IPAddr([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+
 pub fn compute_udp_checksum(
     ip6_header: &IP6Header,
     udp_header: &UDPHeader,
@@ -197,7 +244,11 @@ pub fn compute_icmp_checksum(
 
     // add options
     match icmp_header.get_options() {
-        ICMP6HeaderOptions::Type1 { unused } | ICMP6HeaderOptions::Type3 { unused } => {
+        ICMP6HeaderOptions::Type1 { unused }
+        | ICMP6HeaderOptions::Type3 { unused }
+        | ICMP6HeaderOptions::Type133 { reserved: unused }
+        | ICMP6HeaderOptions::Type135 { reserved: unused }
+        | ICMP6HeaderOptions::Type136 { flags: unused } => {
             sum += unused >> 16; // upper 16 bits
             sum += unused & 0xffff; // lower 16 bits
         }
@@ -205,6 +256,14 @@ pub fn compute_icmp_checksum(
             sum += id as u32;
             sum += seqno as u32;
         }
+        ICMP6HeaderOptions::Type134 {
+            hop_limit,
+            flags,
+            router_lifetime,
+        } => {
+            sum += (hop_limit as u32) << 8 | flags as u32;
+            sum += router_lifetime as u32;
+        }
     }
 
     // add icmp payload
@@ -215,7 +274,7 @@ pub fn compute_icmp_checksum(
     while sum > 0xffff {
         let sum_upper = sum >> 16;
         let sum_lower = sum & 0xffff;
-        sum += sum_upper + sum_lower;
+        sum = sum_upper + sum_lower;
     }
 
     sum = !sum;
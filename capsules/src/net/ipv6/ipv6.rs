@@ -281,17 +281,20 @@ impl IP6Header {
                 ReturnCode::SUCCESS
             }
             ip6_nh::ICMP => {
-                // Untested (10/5/18)
                 let mut icmp_header: [u8; ICMP_HDR_LEN] = [0; ICMP_HDR_LEN];
                 icmp_header.copy_from_slice(&buf[..ICMP_HDR_LEN]);
-                let checksum = match ICMP6Header::decode(&icmp_header).done() {
+                // The computed checksum does not cover the received
+                // checksum field, so compare it against that field instead
+                // of expecting zero.
+                let valid = match ICMP6Header::decode(&icmp_header).done() {
                     Some((_offset, mut hdr)) => {
                         hdr.set_len(buf.len() as u16);
                         u16::from_be(compute_icmp_checksum(&self, &hdr, &buf[ICMP_HDR_LEN..]))
+                            == hdr.get_cksum()
                     }
-                    None => 0xffff, //Will be dropped, as ones comp -0 checksum is invalid
+                    None => false,
                 };
-                if checksum != 0 {
+                if !valid {
                     return ReturnCode::FAIL; //Incorrect cksum
                 }
                 ReturnCode::SUCCESS
@@ -1,4 +1,7 @@
+use crate::net::ipv6::addr_table::IPAddrTable;
+use crate::net::ipv6::ip_utils::ip6_nh;
 use crate::net::ipv6::ipv6::IP6Header;
+use crate::net::pcap::{CaptureTap, Direction, LinkType};
 use crate::net::sixlowpan::sixlowpan_state::SixlowpanRxClient;
 use kernel::common::cells::OptionalCell;
 use kernel::debug;
@@ -36,26 +39,53 @@ pub trait IP6RecvClient {
 /// The receiver receives IP packets destined for any local address.
 /// The receiver should drop any packets with destination addresses
 /// that are not among the local addresses of this device.
+///
+/// ICMPv6 packets are passed to a separate client, if one is set, so that
+/// Neighbor Discovery can be handled without involving the UDP layer.
 pub trait IP6Receiver<'a> {
     fn set_client(&self, client: &'a dyn IP6RecvClient);
+    fn set_icmp_client(&self, client: &'a dyn IP6RecvClient);
 }
 
 pub struct IP6RecvStruct<'a> {
     client: OptionalCell<&'a dyn IP6RecvClient>,
+    icmp_client: OptionalCell<&'a dyn IP6RecvClient>,
+    addr_table: OptionalCell<&'a IPAddrTable<'a>>,
+    capture_tap: OptionalCell<&'a dyn CaptureTap>,
 }
 
 impl<'a> IP6Receiver<'a> for IP6RecvStruct<'a> {
     fn set_client(&self, client: &'a dyn IP6RecvClient) {
         self.client.set(client);
     }
+
+    fn set_icmp_client(&self, client: &'a dyn IP6RecvClient) {
+        self.icmp_client.set(client);
+    }
 }
 
 impl<'a> IP6RecvStruct<'a> {
     pub fn new() -> IP6RecvStruct<'a> {
         IP6RecvStruct {
             client: OptionalCell::empty(),
+            icmp_client: OptionalCell::empty(),
+            addr_table: OptionalCell::empty(),
+            capture_tap: OptionalCell::empty(),
         }
     }
+
+    /// Sets the table of local addresses. Once set, packets whose
+    /// destination is not a local address (see `IPAddrTable::is_local`) or
+    /// another multicast address are dropped.
+    pub fn set_addr_table(&self, addr_table: &'a IPAddrTable<'a>) {
+        self.addr_table.set(addr_table);
+    }
+
+    /// Sets a packet capture sink that is passed every reassembled IPv6
+    /// packet, before it is filtered by destination or checksum.
+    pub fn set_capture_tap(&self, tap: &'a dyn CaptureTap) {
+        self.capture_tap.set(tap);
+    }
 }
 
 impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
@@ -64,6 +94,8 @@ impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
         if len > buf.len() || result != ReturnCode::SUCCESS {
             return;
         }
+        self.capture_tap
+            .map(|tap| tap.capture(LinkType::Ipv6, Direction::Received, &buf[..len]));
         match IP6Header::decode(buf).done() {
             Some((offset, ip6_header)) => {
                 let checksum_result = ip6_header.check_transport_checksum(&buf[offset..len]);
@@ -74,8 +106,21 @@ impl<'a> SixlowpanRxClient for IP6RecvStruct<'a> {
                 // Note: Protocols for which checksum verification is not implemented (TCP, etc.)
                 // are automatically assumed as fine, rather than dropped
 
-                self.client
-                    .map(|client| client.receive(ip6_header, &buf[offset..len]));
+                let dst_addr = ip6_header.get_dst_addr();
+                let is_local = self.addr_table.map_or(true, |table| {
+                    dst_addr.is_multicast() || table.is_local(dst_addr)
+                });
+                if !is_local {
+                    return; //Dropped.
+                }
+
+                if ip6_header.next_header == ip6_nh::ICMP {
+                    self.icmp_client
+                        .map(|client| client.receive(ip6_header, &buf[offset..len]));
+                } else {
+                    self.client
+                        .map(|client| client.receive(ip6_header, &buf[offset..len]));
+                }
             }
             None => {
                 debug!("failed to decode ipv6 header");
@@ -18,9 +18,11 @@
 
 use crate::ieee802154::device::{MacDevice, TxClient};
 use crate::net::ieee802154::MacAddress;
+use crate::net::ipv6::addr_table::IPAddrTable;
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::ipv6::ipv6::{IP6Header, IP6Packet, TransportHeader};
 use crate::net::network_capabilities::{IpVisibilityCapability, NetworkCapability};
+use crate::net::pcap::{CaptureTap, Direction, LinkType};
 use crate::net::sixlowpan::sixlowpan_state::TxState;
 use core::cell::Cell;
 use kernel::common::cells::{OptionalCell, TakeCell};
@@ -104,6 +106,8 @@ pub struct IP6SendStruct<'a, A: time::Alarm<'a>> {
     dst_mac_addr: MacAddress,
     src_mac_addr: MacAddress,
     client: OptionalCell<&'a dyn IP6SendClient>,
+    addr_table: OptionalCell<&'a IPAddrTable<'a>>,
+    capture_tap: OptionalCell<&'a dyn CaptureTap>,
     ip_vis: &'static IpVisibilityCapability,
 }
 
@@ -132,16 +136,35 @@ impl<'a, A: time::Alarm<'a>> IP6Sender<'a> for IP6SendStruct<'a, A> {
         payload: &LeasableBuffer<'static, u8>,
         net_cap: &'static NetworkCapability,
     ) -> ReturnCode {
-        if !net_cap.remote_addr_valid(dst, self.ip_vis) {
+        let addr_valid = self.addr_table.map_or_else(
+            || net_cap.remote_addr_valid(dst, self.ip_vis),
+            |table| net_cap.remote_addr_valid_on(dst, table, self.ip_vis),
+        );
+        if !addr_valid {
             return ReturnCode::FAIL;
         }
-        self.sixlowpan.init(
-            self.src_mac_addr,
-            self.dst_mac_addr,
-            self.radio.get_pan(),
-            None,
-        );
+        // Multicast packets are sent to the 802.15.4 broadcast address
+        // (RFC 4944, section 9).
+        let dst_mac_addr = if dst.is_multicast() {
+            MacAddress::Short(0xffff)
+        } else {
+            self.dst_mac_addr
+        };
+        self.sixlowpan
+            .init(self.src_mac_addr, dst_mac_addr, self.radio.get_pan(), None);
         self.init_packet(dst, transport_header, payload);
+        self.capture_tap.map(|tap| {
+            self.ip6_packet.map(|ip6_packet| {
+                tap.capture_with(
+                    LinkType::Ipv6,
+                    Direction::Transmitted,
+                    ip6_packet.get_total_len() as usize,
+                    &|buf: &mut [u8]| {
+                        let _ = ip6_packet.encode(buf);
+                    },
+                )
+            })
+        });
         let ret = self.send_next_fragment();
         ret
     }
@@ -169,10 +192,27 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
             dst_mac_addr: dst_mac_addr,
             src_mac_addr: src_mac_addr,
             client: OptionalCell::empty(),
+            addr_table: OptionalCell::empty(),
+            capture_tap: OptionalCell::empty(),
             ip_vis: ip_vis,
         }
     }
 
+    /// Sets the table of local addresses. Once set, the source address of
+    /// each packet is selected from the table based on its destination, with
+    /// the address passed to `set_addr` used only while no address is
+    /// assigned, and `AddrRange::OnLink` capabilities are checked against
+    /// the assigned prefixes.
+    pub fn set_addr_table(&self, addr_table: &'a IPAddrTable<'a>) {
+        self.addr_table.set(addr_table);
+    }
+
+    /// Sets a packet capture sink that is passed every IPv6 packet before it
+    /// is compressed and fragmented.
+    pub fn set_capture_tap(&self, tap: &'a dyn CaptureTap) {
+        self.capture_tap.set(tap);
+    }
+
     fn init_packet(
         &self,
         dst_addr: IPAddr,
@@ -185,7 +225,10 @@ impl<'a, A: time::Alarm<'a>> IP6SendStruct<'a, A> {
             },
             |ip6_packet| {
                 ip6_packet.header = IP6Header::default();
-                ip6_packet.header.src_addr = self.src_addr.get();
+                ip6_packet.header.src_addr = self
+                    .addr_table
+                    .and_then(|table| table.select_source(dst_addr))
+                    .unwrap_or_else(|| self.src_addr.get());
                 ip6_packet.header.dst_addr = dst_addr;
                 ip6_packet.set_payload(transport_header, payload);
                 ip6_packet.set_transport_checksum();
@@ -1,3 +1,6 @@
+pub mod addr_table;
+pub mod autoconf;
+pub mod dhcpv6;
 pub mod ip_utils;
 pub mod ipv6;
 pub mod ipv6_recv;
@@ -18,6 +18,7 @@
 //! bind has a capability to send from that port. Therefore, we check the
 //! network capability of the caller. In order to check the UDP-specific aspect
 //! of the network capability, the port table must posses a UdpVisibilityCapability reference.
+use crate::net::ipv6::addr_table::IPAddrTable;
 use crate::net::ipv6::ip_utils::IPAddr;
 
 const MAX_ADDR_SET_SIZE: usize = 8;
@@ -32,6 +33,7 @@ pub enum AddrRange {
     AddrSet([IPAddr; MAX_ADDR_SET_SIZE]),
     Addr(IPAddr),
     Subnet(IPAddr, usize), // address, prefix length (max 128)
+    OnLink,                // Link-local addresses and assigned prefixes
 }
 
 impl AddrRange {
@@ -54,6 +56,19 @@ impl AddrRange {
                         == allowed_addr.0[full_bytes] >> (8 - remainder_bits)
                 }
             }
+            // Without an address table only link-local addresses are known
+            // to be on-link.
+            AddrRange::OnLink => addr.is_unicast_link_local() || addr.is_link_local_multicast(),
+        }
+    }
+
+    /// Like `is_addr_valid`, but resolves `OnLink` against the prefixes of
+    /// the addresses currently assigned in `addr_table`, which may change at
+    /// runtime through address autoconfiguration.
+    pub fn is_addr_valid_on(&self, addr: IPAddr, addr_table: &IPAddrTable) -> bool {
+        match self {
+            AddrRange::OnLink => addr_table.is_on_link(addr),
+            _ => self.is_addr_valid(addr),
         }
     }
 }
@@ -98,6 +113,15 @@ impl UdpVisibilityCapability {
     }
 }
 
+#[cfg(test)]
+impl UdpVisibilityCapability {
+    /// A capability for unit tests, which can't implement the unsafe
+    /// capability traits since capsules forbid unsafe code.
+    pub fn for_tests() -> UdpVisibilityCapability {
+        UdpVisibilityCapability { _priv: () }
+    }
+}
+
 impl IpVisibilityCapability {
     pub fn new(
         _create_net_cap: &dyn NetworkCapabilityCreationCapability,
@@ -116,6 +140,23 @@ pub struct NetworkCapability {
     local_ports: PortRange,  // ports from which the holder may send
 }
 
+#[cfg(test)]
+impl NetworkCapability {
+    /// A capability for unit tests, which can't implement the unsafe
+    /// capability traits since capsules forbid unsafe code.
+    pub fn for_tests(
+        remote_addrs: AddrRange,
+        remote_ports: PortRange,
+        local_ports: PortRange,
+    ) -> NetworkCapability {
+        NetworkCapability {
+            remote_addrs: remote_addrs,
+            remote_ports: remote_ports,
+            local_ports: local_ports,
+        }
+    }
+}
+
 impl NetworkCapability {
     pub fn new(
         remote_addrs: AddrRange,
@@ -142,6 +183,15 @@ impl NetworkCapability {
         self.remote_addrs.is_addr_valid(remote_addr)
     }
 
+    pub fn remote_addr_valid_on(
+        &self,
+        remote_addr: IPAddr,
+        addr_table: &IPAddrTable,
+        _ip_cap: &'static IpVisibilityCapability,
+    ) -> bool {
+        self.remote_addrs.is_addr_valid_on(remote_addr, addr_table)
+    }
+
     pub fn get_remote_ports(&self, _udp_cap: &'static UdpVisibilityCapability) -> PortRange {
         self.remote_ports
     }
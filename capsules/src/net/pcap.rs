@@ -0,0 +1,472 @@
+//! Packet capture for the 802.15.4 and IPv6 stack.
+//!
+//! `PcapCapture` mirrors frames received and transmitted by the networking
+//! stack to a UART (or any other `hil::uart::Transmit` implementation, such
+//! as the USB CDC-ACM device), so that traffic on a deployed node can be
+//! inspected with Wireshark. Layers that support capture expose a
+//! `set_capture_tap` method taking a `CaptureTap`:
+//!
+//! - `ieee802154::virtual_mac::MuxMac` captures every MAC frame (without
+//!   the FCS) as link type `IEEE802_15_4_NOFCS`. Transmitted frames are
+//!   captured before any link-layer security is applied.
+//! - `net::ipv6::ipv6_recv::IP6RecvStruct` and
+//!   `net::ipv6::ipv6_send::IP6SendStruct` capture decompressed and
+//!   reassembled IPv6 packets as link type `IPV6`.
+//!
+//! Stream format
+//! -------------
+//!
+//! Each captured packet is sent as one record. All fields are little
+//! endian. Bytes 8 to 23 and the packet data form a standard pcap packet
+//! record, so a host tool only needs to add the pcap file header (see
+//! `tools/pcap`). Records may be interleaved with other output on the same
+//! UART, such as the console; the magic number and checksum allow a reader
+//! to find record boundaries and discard everything else.
+//!
+//! ```txt
+//! offset  size  field
+//!      0     4  magic: 0xa1b2c3d4
+//!      4     2  pcap link type (230: 802.15.4 without FCS, 229: IPv6)
+//!      6     1  direction (0: received, 1: transmitted)
+//!      7     1  reserved (0)
+//!      8     4  timestamp, seconds
+//!     12     4  timestamp, microseconds
+//!     16     4  captured length
+//!     20     4  original length
+//!     24     n  packet data
+//!   24+n     2  16-bit sum of bytes 4 to 24+n
+//! ```
+//!
+//! Records are queued in a buffer while the previous batch is transmitted.
+//! Packets that do not fit in the queue are dropped and counted (see
+//! `dropped`). Timestamps are the time since boot according to the clock
+//! passed to `new`, which must be read at least once per wraparound for
+//! the seconds to stay correct.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let pcap_uart = static_init!(UartDevice, UartDevice::new(uart_mux, false));
+//! pcap_uart.setup();
+//! let pcap = static_init!(
+//!     PcapCapture<'static, UartDevice<'static>, sam4l::ast::Ast<'static>>,
+//!     PcapCapture::new(pcap_uart, &sam4l::ast::AST, &mut PCAP_BUF1, &mut PCAP_BUF2)
+//! );
+//! pcap_uart.set_transmit_client(pcap);
+//! mux_mac.set_capture_tap(pcap);
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::TakeCell;
+use kernel::hil::time::{self, Frequency};
+use kernel::hil::uart;
+use kernel::ReturnCode;
+
+pub const RECORD_MAGIC: u32 = 0xa1b2_c3d4;
+pub const RECORD_HEADER_LEN: usize = 24;
+pub const RECORD_TRAILER_LEN: usize = 2;
+
+/// pcap link types (see https://www.tcpdump.org/linktypes.html).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LinkType {
+    Ieee802154NoFcs = 230,
+    Ipv6 = 229,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Received = 0,
+    Transmitted = 1,
+}
+
+/// Implemented by packet capture sinks. Networking layers call the tap for
+/// each packet they receive or transmit.
+pub trait CaptureTap {
+    /// Captures the packet in `data`.
+    fn capture(&self, link_type: LinkType, direction: Direction, data: &[u8]);
+
+    /// Captures a packet of `len` bytes that is serialized by `write`, for
+    /// packets that are not stored contiguously by the caller. `write` is
+    /// passed a buffer of exactly `len` bytes.
+    fn capture_with(
+        &self,
+        link_type: LinkType,
+        direction: Direction,
+        len: usize,
+        write: &dyn Fn(&mut [u8]),
+    );
+}
+
+pub struct PcapCapture<'a, U: uart::Transmit<'a>, T: time::Time> {
+    uart: &'a U,
+    clock: &'a T,
+    // Records waiting to be sent
+    queue: TakeCell<'static, [u8]>,
+    queue_len: Cell<usize>,
+    queue_records: Cell<u32>,
+    // The other buffer, when it is not being transmitted
+    spare: TakeCell<'static, [u8]>,
+    enabled: Cell<bool>,
+    dropped: Cell<u32>,
+    // Tracks clock wraparounds to extend the timestamp beyond the range of
+    // the clock.
+    last_now: Cell<u32>,
+    tics_base: Cell<u64>,
+}
+
+impl<'a, U: uart::Transmit<'a>, T: time::Time> PcapCapture<'a, U, T> {
+    /// Creates a new `PcapCapture`. Captured records are queued in one of
+    /// `buffer1` and `buffer2` while the other is being transmitted, so each
+    /// must be large enough for the largest packet to capture plus
+    /// `RECORD_HEADER_LEN + RECORD_TRAILER_LEN` bytes.
+    pub fn new(
+        uart: &'a U,
+        clock: &'a T,
+        buffer1: &'static mut [u8],
+        buffer2: &'static mut [u8],
+    ) -> PcapCapture<'a, U, T> {
+        PcapCapture {
+            uart: uart,
+            clock: clock,
+            queue: TakeCell::new(buffer1),
+            queue_len: Cell::new(0),
+            queue_records: Cell::new(0),
+            spare: TakeCell::new(buffer2),
+            enabled: Cell::new(true),
+            dropped: Cell::new(0),
+            last_now: Cell::new(0),
+            tics_base: Cell::new(0),
+        }
+    }
+
+    /// Starts or stops capturing packets. Records already queued are still
+    /// sent.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled);
+    }
+
+    /// Returns the number of packets that were dropped because the queue was
+    /// full or the UART refused to transmit them.
+    pub fn dropped(&self) -> u32 {
+        self.dropped.get()
+    }
+
+    // Returns the time since boot as (seconds, microseconds).
+    fn timestamp(&self) -> (u32, u32) {
+        let now = self.clock.now();
+        if now < self.last_now.get() {
+            self.tics_base
+                .set(self.tics_base.get() + self.clock.max_tics() as u64 + 1);
+        }
+        self.last_now.set(now);
+        let tics = self.tics_base.get() + now as u64;
+        let frequency = T::Frequency::frequency() as u64;
+        let usecs = (tics % frequency) * 1_000_000 / frequency;
+        ((tics / frequency) as u32, usecs as u32)
+    }
+
+    fn enqueue(
+        &self,
+        link_type: LinkType,
+        direction: Direction,
+        len: usize,
+        write: &dyn Fn(&mut [u8]),
+    ) {
+        if !self.enabled.get() {
+            return;
+        }
+        let (secs, usecs) = self.timestamp();
+        let start = self.queue_len.get();
+        let end = start + RECORD_HEADER_LEN + len + RECORD_TRAILER_LEN;
+        let queued = self.queue.map_or(false, |queue| {
+            if end > queue.len() {
+                return false;
+            }
+            let record = &mut queue[start..end];
+            record[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+            record[4..6].copy_from_slice(&(link_type as u16).to_le_bytes());
+            record[6] = direction as u8;
+            record[7] = 0;
+            record[8..12].copy_from_slice(&secs.to_le_bytes());
+            record[12..16].copy_from_slice(&usecs.to_le_bytes());
+            record[16..20].copy_from_slice(&(len as u32).to_le_bytes());
+            record[20..24].copy_from_slice(&(len as u32).to_le_bytes());
+            write(&mut record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len]);
+            let sum = record[4..RECORD_HEADER_LEN + len]
+                .iter()
+                .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+            record[RECORD_HEADER_LEN + len..].copy_from_slice(&sum.to_le_bytes());
+            true
+        });
+        if queued {
+            self.queue_len.set(end);
+            self.queue_records.set(self.queue_records.get() + 1);
+            self.send_queue();
+        } else {
+            self.dropped.set(self.dropped.get() + 1);
+        }
+    }
+
+    // Transmits the queued records if the UART is idle, and starts queueing
+    // records in the spare buffer.
+    fn send_queue(&self) {
+        let len = self.queue_len.get();
+        if len == 0 || !self.spare.is_some() {
+            return;
+        }
+        let records = self.queue_records.get();
+        let spare = self.spare.take();
+        let queue = self.queue.take();
+        self.queue.put(spare);
+        self.queue_len.set(0);
+        self.queue_records.set(0);
+        queue.map(|buf| {
+            let (result, buf) = self.uart.transmit_buffer(buf, len);
+            if result != ReturnCode::SUCCESS {
+                // The queued records are lost.
+                self.dropped.set(self.dropped.get() + records);
+                buf.map(|buf| self.spare.replace(buf));
+            }
+        });
+    }
+}
+
+impl<'a, U: uart::Transmit<'a>, T: time::Time> CaptureTap for PcapCapture<'a, U, T> {
+    fn capture(&self, link_type: LinkType, direction: Direction, data: &[u8]) {
+        self.enqueue(link_type, direction, data.len(), &|buf: &mut [u8]| {
+            buf.copy_from_slice(data)
+        });
+    }
+
+    fn capture_with(
+        &self,
+        link_type: LinkType,
+        direction: Direction,
+        len: usize,
+        write: &dyn Fn(&mut [u8]),
+    ) {
+        self.enqueue(link_type, direction, len, write);
+    }
+}
+
+impl<'a, U: uart::Transmit<'a>, T: time::Time> uart::TransmitClient for PcapCapture<'a, U, T> {
+    fn transmitted_buffer(&self, tx_buffer: &'static mut [u8], _tx_len: usize, _rval: ReturnCode) {
+        self.spare.replace(tx_buffer);
+        self.send_queue();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{leak, SimAlarm};
+    use core::cell::RefCell;
+    use kernel::common::cells::OptionalCell;
+    use std::vec::Vec;
+
+    const BUFFER_LEN: usize = 64;
+
+    /// A UART that keeps what it is given to transmit until `complete` is
+    /// called, or refuses it with `result`.
+    struct TestUart {
+        client: OptionalCell<&'static dyn uart::TransmitClient>,
+        buffer: TakeCell<'static, [u8]>,
+        len: Cell<usize>,
+        sent: RefCell<Vec<u8>>,
+        result: Cell<ReturnCode>,
+    }
+
+    impl TestUart {
+        fn complete(&self) {
+            let buffer = self.buffer.take().unwrap();
+            let len = self.len.get();
+            self.sent.borrow_mut().extend_from_slice(&buffer[..len]);
+            self.client
+                .map(move |client| client.transmitted_buffer(buffer, len, ReturnCode::SUCCESS));
+        }
+    }
+
+    impl uart::Transmit<'static> for TestUart {
+        fn set_transmit_client(&self, client: &'static dyn uart::TransmitClient) {
+            self.client.set(client);
+        }
+
+        fn transmit_buffer(
+            &self,
+            tx_buffer: &'static mut [u8],
+            tx_len: usize,
+        ) -> (ReturnCode, Option<&'static mut [u8]>) {
+            if self.result.get() != ReturnCode::SUCCESS {
+                return (self.result.get(), Some(tx_buffer));
+            }
+            assert!(self.buffer.is_none());
+            self.buffer.replace(tx_buffer);
+            self.len.set(tx_len);
+            (ReturnCode::SUCCESS, None)
+        }
+
+        fn transmit_word(&self, _word: u32) -> ReturnCode {
+            ReturnCode::FAIL
+        }
+
+        fn transmit_abort(&self) -> ReturnCode {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    type TestCapture = PcapCapture<'static, TestUart, SimAlarm<'static>>;
+
+    fn harness(
+        start: u32,
+    ) -> (
+        &'static TestUart,
+        &'static SimAlarm<'static>,
+        &'static TestCapture,
+    ) {
+        let uart = leak(TestUart {
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            len: Cell::new(0),
+            sent: RefCell::new(Vec::new()),
+            result: Cell::new(ReturnCode::SUCCESS),
+        });
+        let clock = leak(SimAlarm::new(start));
+        let pcap = leak(PcapCapture::new(
+            uart,
+            clock,
+            leak([0; BUFFER_LEN]),
+            leak([0; BUFFER_LEN]),
+        ));
+        uart::Transmit::set_transmit_client(uart, pcap);
+        (uart, clock, pcap)
+    }
+
+    // The record expected for `data` captured at `secs` and `usecs`
+    fn record(link_type: u16, direction: u8, secs: u32, usecs: u32, data: &[u8]) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&[0xd4, 0xc3, 0xb2, 0xa1]);
+        record.extend_from_slice(&link_type.to_le_bytes());
+        record.extend_from_slice(&[direction, 0]);
+        record.extend_from_slice(&secs.to_le_bytes());
+        record.extend_from_slice(&usecs.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(data);
+        let sum = record[4..].iter().map(|&b| b as u32).sum::<u32>() as u16;
+        record.extend_from_slice(&sum.to_le_bytes());
+        record
+    }
+
+    #[test]
+    fn records() {
+        let (uart, clock, pcap) = harness(1500);
+        pcap.capture(LinkType::Ieee802154NoFcs, Direction::Received, &[1, 2, 3]);
+        uart.complete();
+        clock.advance(2);
+        pcap.capture_with(
+            LinkType::Ipv6,
+            Direction::Transmitted,
+            2,
+            &|buf: &mut [u8]| buf.copy_from_slice(&[0xff, 0xfe]),
+        );
+        uart.complete();
+
+        let mut expected = record(230, 0, 1, 500_000, &[1, 2, 3]);
+        expected.extend(record(229, 1, 1, 502_000, &[0xff, 0xfe]));
+        assert_eq!(*uart.sent.borrow(), expected);
+        assert_eq!(pcap.dropped(), 0);
+
+        // Disabled captures are not sent
+        pcap.set_enabled(false);
+        pcap.capture(LinkType::Ipv6, Direction::Received, &[1]);
+        assert!(uart.buffer.is_none());
+    }
+
+    #[test]
+    fn checksum() {
+        let (uart, _clock, pcap) = harness(0);
+        let data = [0xff; 30];
+        pcap.capture(LinkType::Ipv6, Direction::Received, &data);
+        uart.complete();
+
+        let sent = uart.sent.borrow();
+        let sum = 229 + 30 + 30 + 30 * 0xff;
+        assert_eq!(sent.len(), RECORD_HEADER_LEN + 30 + RECORD_TRAILER_LEN);
+        assert_eq!(sent[RECORD_HEADER_LEN + 30..], (sum as u16).to_le_bytes());
+        assert_eq!(*sent, record(229, 0, 0, 0, &data));
+    }
+
+    #[test]
+    fn timestamps_wrap_around() {
+        // 500 ms before the 1 kHz clock wraps around
+        let (_uart, clock, pcap) = harness(0u32.wrapping_sub(500));
+        let before = (1u64 << 32) - 500;
+        assert_eq!(
+            pcap.timestamp(),
+            ((before / 1000) as u32, (before % 1000 * 1000) as u32)
+        );
+
+        clock.advance(1000);
+        let after = before + 1000;
+        assert_eq!(
+            pcap.timestamp(),
+            ((after / 1000) as u32, (after % 1000 * 1000) as u32)
+        );
+        clock.advance(1);
+        assert_eq!(
+            pcap.timestamp(),
+            ((after / 1000) as u32, ((after + 1) % 1000 * 1000) as u32)
+        );
+    }
+
+    #[test]
+    fn full_queue_drops() {
+        let (uart, _clock, pcap) = harness(0);
+        let data = [7; 10];
+        let record_len = RECORD_HEADER_LEN + data.len() + RECORD_TRAILER_LEN;
+
+        // The first record is transmitted, the second one queued, and the
+        // third one doesn't fit in the queue
+        for _ in 0..3 {
+            pcap.capture(LinkType::Ipv6, Direction::Received, &data);
+        }
+        assert_eq!(pcap.dropped(), 1);
+        uart.complete();
+        uart.complete();
+        assert_eq!(uart.sent.borrow().len(), 2 * record_len);
+
+        // A packet too large for the buffers is dropped
+        pcap.capture(LinkType::Ipv6, Direction::Received, &[0; BUFFER_LEN]);
+        assert_eq!(pcap.dropped(), 2);
+        assert!(uart.buffer.is_none());
+    }
+
+    #[test]
+    fn refused_transmit_drops_every_record() {
+        let (uart, _clock, pcap) = harness(0);
+
+        // Two records are queued while the first is transmitted, and the UART
+        // refuses them
+        pcap.capture(LinkType::Ipv6, Direction::Received, &[1]);
+        pcap.capture(LinkType::Ipv6, Direction::Received, &[2]);
+        pcap.capture(LinkType::Ipv6, Direction::Received, &[3]);
+        uart.result.set(ReturnCode::EOFF);
+        uart.complete();
+        assert_eq!(pcap.dropped(), 2);
+
+        // Both buffers are still usable once the UART accepts again
+        uart.result.set(ReturnCode::SUCCESS);
+        pcap.capture(LinkType::Ipv6, Direction::Received, &[4]);
+        pcap.capture(LinkType::Ipv6, Direction::Received, &[5]);
+        uart.complete();
+        uart.complete();
+        assert_eq!(pcap.dropped(), 2);
+        let mut expected = record(229, 0, 0, 0, &[1]);
+        expected.extend(record(229, 0, 0, 0, &[4]));
+        expected.extend(record(229, 0, 0, 0, &[5]));
+        assert_eq!(*uart.sent.borrow(), expected);
+    }
+}
@@ -0,0 +1,83 @@
+//! 6LoWPAN statistics userspace interface.
+//!
+//! Exposes the receive counters of one or more 6LoWPAN interfaces (see
+//! `SixlowpanStats`) to processes, for example to monitor fragment loss on a
+//! network. The counters are shared by all processes.
+
+use crate::net::sixlowpan::sixlowpan_state::SixlowpanStatistics;
+use kernel::{AppId, Driver, ReturnCode};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::LowpanStats as usize;
+
+/// Indices of the counters that can be read with command 2.
+pub mod stat {
+    pub const RX_FRAMES: usize = 0;
+    pub const RX_PACKETS: usize = 1;
+    pub const RX_DROPPED: usize = 2;
+    pub const RX_NO_BUFFER: usize = 3;
+    pub const DECOMPRESSION_FAILURES: usize = 4;
+    pub const INVALID_FRAGMENTS: usize = 5;
+    pub const REASSEMBLY_TIMEOUTS: usize = 6;
+    pub const DUPLICATE_FRAGMENTS: usize = 7;
+}
+
+pub struct SixlowpanStatsDriver<'a> {
+    interfaces: &'a [&'a dyn SixlowpanStatistics],
+}
+
+impl<'a> SixlowpanStatsDriver<'a> {
+    pub fn new(interfaces: &'a [&'a dyn SixlowpanStatistics]) -> SixlowpanStatsDriver<'a> {
+        SixlowpanStatsDriver {
+            interfaces: interfaces,
+        }
+    }
+
+    fn read_stat(&self, iface: usize, index: usize) -> ReturnCode {
+        let stats = match self.interfaces.get(iface) {
+            Some(iface) => iface.stats(),
+            None => return ReturnCode::EINVAL,
+        };
+        let value = match index {
+            stat::RX_FRAMES => stats.rx_frames,
+            stat::RX_PACKETS => stats.rx_packets,
+            stat::RX_DROPPED => stats.rx_dropped,
+            stat::RX_NO_BUFFER => stats.rx_no_buffer,
+            stat::DECOMPRESSION_FAILURES => stats.decompression_failures,
+            stat::INVALID_FRAGMENTS => stats.invalid_fragments,
+            stat::REASSEMBLY_TIMEOUTS => stats.reassembly_timeouts,
+            stat::DUPLICATE_FRAGMENTS => stats.duplicate_fragments,
+            _ => return ReturnCode::EINVAL,
+        };
+        ReturnCode::SuccessWithValue {
+            value: value as usize,
+        }
+    }
+}
+
+impl<'a> Driver for SixlowpanStatsDriver<'a> {
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Returns the number of 6LoWPAN interfaces.
+    /// - `2`: Returns the counter `arg2` (see the `stat` module) of interface
+    ///        `arg1`.
+    /// - `3`: Resets the counters of interface `arg1`.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, _appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => ReturnCode::SuccessWithValue {
+                value: self.interfaces.len(),
+            },
+            2 => self.read_stat(arg1, arg2),
+            3 => self
+                .interfaces
+                .get(arg1)
+                .map_or(ReturnCode::EINVAL, |iface| {
+                    iface.reset_stats();
+                    ReturnCode::SUCCESS
+                }),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -1,2 +1,6 @@
+pub mod driver;
 pub mod sixlowpan_compression;
 pub mod sixlowpan_state;
+
+pub use self::driver::SixlowpanStatsDriver;
+pub use self::driver::DRIVER_NUM;
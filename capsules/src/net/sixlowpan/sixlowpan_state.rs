@@ -226,7 +226,7 @@
 
 use crate::ieee802154::device::{MacDevice, RxClient};
 use crate::ieee802154::framer::Frame;
-use crate::net::frag_utils::Bitmap;
+use crate::net::frag_utils::{Bitmap, BITMAP_BITS};
 use crate::net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
 use crate::net::ipv6::ipv6::IP6Packet;
 use crate::net::sixlowpan::sixlowpan_compression;
@@ -241,9 +241,39 @@ use kernel::hil::time;
 use kernel::hil::time::Frequency;
 use kernel::ReturnCode;
 
-// Reassembly timeout in seconds
+// Default reassembly timeout in seconds
 const FRAG_TIMEOUT: u32 = 60;
 
+/// Counters for the receive path of a [Sixlowpan](struct.Sixlowpan.html)
+/// interface.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SixlowpanStats {
+    /// Frames received from the MAC layer.
+    pub rx_frames: u32,
+    /// IPv6 packets passed to the receive client.
+    pub rx_packets: u32,
+    /// Frames dropped for any reason.
+    pub rx_dropped: u32,
+    /// Frames dropped because no `RxState` or packet buffer was available.
+    pub rx_no_buffer: u32,
+    /// Frames whose 6LoWPAN header could not be decompressed.
+    pub decompression_failures: u32,
+    /// Fragments that overlapped previously received fragments or did not
+    /// fit in the datagram or reassembly buffer.
+    pub invalid_fragments: u32,
+    /// Fragments dropped because they had already been received, such as
+    /// retransmissions after a lost acknowledgement.
+    pub duplicate_fragments: u32,
+    /// Reassemblies discarded because not all fragments arrived in time.
+    pub reassembly_timeouts: u32,
+}
+
+/// Provides access to the receive statistics of a 6LoWPAN interface.
+pub trait SixlowpanStatistics {
+    fn stats(&self) -> SixlowpanStats;
+    fn reset_stats(&self);
+}
+
 /// Objects that implement this trait can set themselves to be the client
 /// for the [Sixlowpan](struct.Sixlowpan.html) struct, and will then receive
 /// a callback once an IPv6 packet has been fully reassembled.
@@ -295,6 +325,37 @@ fn is_fragment(packet: &[u8]) -> bool {
     (mask == lowpan_frag::FRAGN_HDR) || (mask == lowpan_frag::FRAG1_HDR)
 }
 
+// Decompresses the 6LoWPAN header at the start of `payload` into `packet` and
+// copies the rest of the payload after the decompressed headers. Returns the
+// number of bytes written, FAIL if the header could not be decompressed, or
+// EINVAL if the result does not fit in `packet`.
+fn decompress_frame(
+    ctx_store: &dyn ContextStore,
+    payload: &[u8],
+    src_mac_addr: MacAddress,
+    dst_mac_addr: MacAddress,
+    packet: &mut [u8],
+    dgram_size: u16,
+    is_fragment: bool,
+) -> Result<usize, ReturnCode> {
+    let (consumed, written) = sixlowpan_compression::decompress(
+        ctx_store,
+        payload,
+        src_mac_addr,
+        dst_mac_addr,
+        packet,
+        dgram_size,
+        is_fragment,
+    )
+    .map_err(|_| ReturnCode::FAIL)?;
+    let remaining = payload.len() - consumed;
+    if written + remaining > packet.len() {
+        return Err(ReturnCode::EINVAL);
+    }
+    packet[written..written + remaining].copy_from_slice(&payload[consumed..]);
+    Ok(written + remaining)
+}
+
 pub trait SixlowpanState<'a> {
     fn next_dgram_tag(&self) -> u16;
     fn get_ctx_store(&self) -> &dyn ContextStore;
@@ -626,6 +687,9 @@ impl<'a> TxState<'a> {
 pub struct RxState<'a> {
     packet: TakeCell<'static, [u8]>,
     bitmap: MapCell<Bitmap>,
+    // The first bit in `bitmap` of each fragment received, to tell
+    // duplicate fragments from overlapping ones.
+    fragment_starts: MapCell<Bitmap>,
     dst_mac_addr: Cell<MacAddress>,
     src_mac_addr: Cell<MacAddress>,
     dgram_tag: Cell<u16>,
@@ -656,6 +720,7 @@ impl<'a> RxState<'a> {
         RxState {
             packet: TakeCell::new(packet),
             bitmap: MapCell::new(Bitmap::new()),
+            fragment_starts: MapCell::new(Bitmap::new()),
             dst_mac_addr: Cell::new(MacAddress::Short(0)),
             src_mac_addr: Cell::new(MacAddress::Short(0)),
             dgram_tag: Cell::new(0),
@@ -680,16 +745,23 @@ impl<'a> RxState<'a> {
             && (self.dst_mac_addr.get() == dst_mac_addr)
     }
 
-    // Checks if a given RxState is free or expired (and thus, can be freed).
-    // This function implements the reassembly timeout for 6LoWPAN lazily.
-    fn is_busy(&self, frequency: u32, current_time: u32) -> bool {
-        let expired = current_time >= (self.start_time.get() + FRAG_TIMEOUT * frequency);
-        if expired {
-            self.end_receive(None, ReturnCode::FAIL);
-        }
+    fn is_busy(&self) -> bool {
         self.busy.get()
     }
 
+    // Returns the number of tics until the reassembly in progress times out,
+    // or 0 if it has already expired. The comparison is done on the elapsed
+    // time so that it is correct when the clock wraps.
+    fn time_remaining(&self, now: u32, timeout: u32) -> u32 {
+        timeout.saturating_sub(now.wrapping_sub(self.start_time.get()))
+    }
+
+    // The largest datagram that can be reassembled in this `RxState`.
+    fn capacity(&self) -> usize {
+        self.packet
+            .map_or(0, |packet| min(packet.len(), BITMAP_BITS * 8))
+    }
+
     fn start_receive(
         &self,
         src_mac_addr: MacAddress,
@@ -704,12 +776,32 @@ impl<'a> RxState<'a> {
         self.dgram_size.set(dgram_size);
         self.busy.set(true);
         self.bitmap.map(|bitmap| bitmap.clear());
+        self.fragment_starts.map(|starts| starts.clear());
         self.start_time.set(current_tics);
     }
 
+    // Returns true if a fragment covering exactly the bits from `start` to
+    // `end` of the bitmap has already been received. A received fragment
+    // ends at the first bit that is not set or that starts another fragment.
+    fn is_duplicate(&self, start: usize, end: usize) -> bool {
+        start < end
+            && self.bitmap.map_or(false, |bitmap| {
+                self.fragment_starts.map_or(false, |starts| {
+                    starts.is_set(start)
+                        && (start + 1..end).all(|i| bitmap.is_set(i) && !starts.is_set(i))
+                        && (!bitmap.is_set(end) || starts.is_set(end))
+                })
+            })
+    }
+
     // This function assumes that the payload is a slice starting from the
     // actual payload (no 802.15.4 headers, no fragmentation headers), and
-    // returns true if the packet is completely reassembled.
+    // returns true if the packet is completely reassembled. Fragments that
+    // extend past `dgram_size` or overlap previously received fragments are
+    // rejected with EINVAL, and the caller should discard the reassembly.
+    // A fragment with the same offset and size as one already received is a
+    // duplicate (RFC 4944, Section 5.3): it is rejected with EALREADY and the
+    // reassembly continues.
     fn receive_next_frame(
         &self,
         payload: &[u8],
@@ -718,37 +810,47 @@ impl<'a> RxState<'a> {
         dgram_offset: usize,
         ctx_store: &dyn ContextStore,
     ) -> Result<bool, ReturnCode> {
-        let mut packet = self.packet.take().ok_or(ReturnCode::ENOMEM)?;
+        let packet = self.packet.take().ok_or(ReturnCode::ENOMEM)?;
         let uncompressed_len = if dgram_offset == 0 {
-            let (consumed, written) = sixlowpan_compression::decompress(
+            decompress_frame(
                 ctx_store,
-                &payload[0..payload_len as usize],
+                &payload[0..payload_len],
                 self.src_mac_addr.get(),
                 self.dst_mac_addr.get(),
-                &mut packet,
+                &mut packet[..dgram_size as usize],
                 dgram_size,
                 true,
             )
-            .map_err(|_| ReturnCode::FAIL)?;
-            let remaining = payload_len - consumed;
-            packet[written..written + remaining]
-                .copy_from_slice(&payload[consumed..consumed + remaining]);
-            written + remaining
+        } else if dgram_offset + payload_len > dgram_size as usize {
+            Err(ReturnCode::EINVAL)
         } else {
             packet[dgram_offset..dgram_offset + payload_len]
                 .copy_from_slice(&payload[0..payload_len]);
-            payload_len
+            Ok(payload_len)
         };
         self.packet.replace(packet);
-        if !self.bitmap.map_or(false, |bitmap| {
-            bitmap.set_bits(dgram_offset / 8, (dgram_offset + uncompressed_len) / 8)
-        }) {
-            // If this fails, we received an overlapping fragment. We can simply
-            // drop the packet in this case.
-            Err(ReturnCode::FAIL)
+        let uncompressed_len = uncompressed_len?;
+
+        // Only the final fragment may end on an offset that is not a
+        // multiple of 8, so round up to cover its last bytes.
+        let start = dgram_offset / 8;
+        let end = (dgram_offset + uncompressed_len + 7) / 8;
+        if self.is_duplicate(start, end) {
+            // The fragment was copied over itself, so the reassembly is
+            // unchanged.
+            Err(ReturnCode::EALREADY)
+        } else if !self
+            .bitmap
+            .map_or(false, |bitmap| bitmap.set_bits(start, end))
+        {
+            // We received an overlapping fragment.
+            Err(ReturnCode::EINVAL)
         } else {
+            if start < end {
+                self.fragment_starts.map(|starts| starts.set_bit(start));
+            }
             self.bitmap
-                .map(|bitmap| bitmap.is_complete((dgram_size as usize) / 8))
+                .map(|bitmap| bitmap.is_complete((dgram_size as usize + 7) / 8))
                 .ok_or(ReturnCode::FAIL)
         }
     }
@@ -756,6 +858,7 @@ impl<'a> RxState<'a> {
     fn end_receive(&self, client: Option<&'a dyn SixlowpanRxClient>, result: ReturnCode) {
         self.busy.set(false);
         self.bitmap.map(|bitmap| bitmap.clear());
+        self.fragment_starts.map(|starts| starts.clear());
         self.start_time.set(0);
         client.map(move |client| {
             // Since packet is borrowed from the upper layer, failing to return it
@@ -781,6 +884,10 @@ impl<'a> RxState<'a> {
 /// [RxState](struct.RxState.html)s allow the `Sixlowpan` to receive more
 /// packets concurrently.
 ///
+/// Reassemblies that are not completed within the reassembly timeout (60
+/// seconds by default, see `set_reassembly_timeout`) are discarded when the
+/// alarm fires, so the `Sixlowpan` must be set as the client of its alarm.
+///
 /// Finally, `set_client` controls the client that will receive transmission
 /// completion and reception callbacks.
 pub struct Sixlowpan<'a, A: time::Alarm<'a>, C: ContextStore> {
@@ -791,6 +898,8 @@ pub struct Sixlowpan<'a, A: time::Alarm<'a>, C: ContextStore> {
 
     // Receive state
     rx_states: List<'a, RxState<'a>>,
+    reassembly_timeout: Cell<u32>,
+    stats: Cell<SixlowpanStats>,
 }
 
 // This function is called after receiving a frame
@@ -803,18 +912,66 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> RxClient for Sixlowpan<'a, A, C> {
         let src_mac_addr = header.src_addr.unwrap_or(MacAddress::Short(0));
         let dst_mac_addr = header.dst_addr.unwrap_or(MacAddress::Short(0));
 
+        self.update_stats(|stats| stats.rx_frames += 1);
         let (rx_state, returncode) = self.receive_frame(
             &buf[data_offset..data_offset + data_len],
             data_len,
             src_mac_addr,
             dst_mac_addr,
         );
+        self.update_stats(|stats| match returncode {
+            ReturnCode::SUCCESS => {
+                if rx_state.is_some() {
+                    stats.rx_packets += 1;
+                }
+            }
+            ReturnCode::ENOMEM => {
+                stats.rx_dropped += 1;
+                stats.rx_no_buffer += 1;
+            }
+            ReturnCode::EINVAL => {
+                stats.rx_dropped += 1;
+                stats.invalid_fragments += 1;
+            }
+            ReturnCode::EALREADY => {
+                stats.rx_dropped += 1;
+                stats.duplicate_fragments += 1;
+            }
+            _ => {
+                stats.rx_dropped += 1;
+                stats.decompression_failures += 1;
+            }
+        });
         // Reception completed if rx_state is not None. Note that this can
         // also occur for some fail states (e.g. dropping an invalid packet)
         rx_state.map(|state| state.end_receive(self.rx_client.get(), returncode));
     }
 }
 
+impl<'a, A: time::Alarm<'a>, C: ContextStore> time::AlarmClient for Sixlowpan<'a, A, C> {
+    fn fired(&self) {
+        let now = self.clock.now();
+        let timeout = self.timeout_tics();
+        for state in self.rx_states.iter() {
+            if state.is_busy() && state.time_remaining(now, timeout) == 0 {
+                state.end_receive(None, ReturnCode::FAIL);
+                self.update_stats(|stats| stats.reassembly_timeouts += 1);
+            }
+        }
+        self.schedule_timeout();
+    }
+}
+
+impl<'a, A: time::Alarm<'a>, C: ContextStore> SixlowpanStatistics for Sixlowpan<'a, A, C> {
+    fn stats(&self) -> SixlowpanStats {
+        self.stats.get()
+    }
+
+    fn reset_stats(&self) {
+        self.stats.set(SixlowpanStats::default());
+    }
+}
+
 impl<'a, A: time::Alarm<'a>, C: ContextStore> SixlowpanState<'a> for Sixlowpan<'a, A, C> {
     fn next_dgram_tag(&self) -> u16 {
         // Increment dgram_tag
@@ -858,8 +1015,9 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
     /// frame.
     ///
     /// * `clock` - A implementation of `Alarm` used for tracking the timing of
-    /// frame arrival. The clock should be continue running during sleep and
-    /// have an accuracy of at least 60 seconds.
+    /// frame arrival and expiring incomplete reassemblies. The clock should be
+    /// continue running during sleep and have an accuracy of at least 60
+    /// seconds.
     pub fn new(ctx_store: C, clock: &'a A) -> Sixlowpan<'a, A, C> {
         Sixlowpan {
             ctx_store: ctx_store,
@@ -868,9 +1026,53 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
             rx_client: Cell::new(None),
 
             rx_states: List::new(),
+            reassembly_timeout: Cell::new(FRAG_TIMEOUT),
+            stats: Cell::new(SixlowpanStats::default()),
+        }
+    }
+
+    /// Sets the time in seconds after which an incomplete reassembly is
+    /// discarded. Applies to reassemblies already in progress.
+    pub fn set_reassembly_timeout(&self, seconds: u32) {
+        self.reassembly_timeout.set(seconds);
+        self.schedule_timeout();
+    }
+
+    fn timeout_tics(&self) -> u32 {
+        // Limit the timeout to half the range of the clock so that elapsed
+        // times can be computed across wraparound.
+        min(
+            self.reassembly_timeout
+                .get()
+                .saturating_mul(A::Frequency::frequency()),
+            u32::MAX / 2,
+        )
+    }
+
+    // Sets the alarm for the reassembly that will time out first, or
+    // disables it if there is no reassembly in progress.
+    fn schedule_timeout(&self) {
+        let now = self.clock.now();
+        let timeout = self.timeout_tics();
+        let next = self
+            .rx_states
+            .iter()
+            .filter(|state| state.is_busy())
+            .map(|state| state.time_remaining(now, timeout))
+            .min();
+        match next {
+            // Never set the alarm to the current time, as it may be missed.
+            Some(remaining) => self.clock.set_alarm(now.wrapping_add(remaining.max(1))),
+            None => self.clock.disable(),
         }
     }
 
+    fn update_stats<F: FnOnce(&mut SixlowpanStats)>(&self, f: F) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
     fn receive_frame(
         &self,
         packet: &[u8],
@@ -878,7 +1080,12 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
     ) -> (Option<&RxState<'a>>, ReturnCode) {
-        if is_fragment(packet) {
+        if packet_len == 0 {
+            (None, ReturnCode::EINVAL)
+        } else if is_fragment(packet) {
+            if packet_len < lowpan_frag::FRAGN_HDR_SIZE {
+                return (None, ReturnCode::EINVAL);
+            }
             let (is_frag1, dgram_size, dgram_tag, dgram_offset) = get_frag_hdr(&packet[0..5]);
             let offset_to_payload = if is_frag1 {
                 lowpan_frag::FRAG1_HDR_SIZE
@@ -906,10 +1113,7 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
     ) -> (Option<&RxState<'a>>, ReturnCode) {
-        let rx_state = self
-            .rx_states
-            .iter()
-            .find(|state| !state.is_busy(self.clock.now(), A::Frequency::frequency()));
+        let rx_state = self.rx_states.iter().find(|state| !state.is_busy());
         rx_state.map_or((None, ReturnCode::ENOMEM), |state| {
             state.start_receive(
                 src_mac_addr,
@@ -925,33 +1129,34 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
                 "Error: `packet` in RxState struct is `None` \
                  in call to `receive_single_packet`.",
             );
-            if is_lowpan(payload) {
-                let decompressed = sixlowpan_compression::decompress(
+            let result = if is_lowpan(payload) {
+                decompress_frame(
                     &self.ctx_store,
-                    &payload[0..payload_len as usize],
+                    &payload[0..payload_len],
                     src_mac_addr,
                     dst_mac_addr,
                     &mut packet,
                     0,
                     false,
-                );
-                match decompressed {
-                    Ok((consumed, written)) => {
-                        let remaining = payload_len - consumed;
-                        packet[written..written + remaining]
-                            .copy_from_slice(&payload[consumed..consumed + remaining]);
-                        // Want dgram_size to contain decompressed size of packet
-                        state.dgram_size.set((written + remaining) as u16);
-                    }
-                    Err(_) => {
-                        return (None, ReturnCode::FAIL);
-                    }
-                }
+                )
+            } else if payload_len > packet.len() {
+                Err(ReturnCode::EINVAL)
             } else {
                 packet[0..payload_len].copy_from_slice(&payload[0..payload_len]);
-            }
+                Ok(payload_len)
+            };
             state.packet.replace(packet);
-            (Some(state), ReturnCode::SUCCESS)
+            match result {
+                Ok(len) => {
+                    // Want dgram_size to contain decompressed size of packet
+                    state.dgram_size.set(len as u16);
+                    (Some(state), ReturnCode::SUCCESS)
+                }
+                Err(returncode) => {
+                    state.end_receive(None, returncode);
+                    (None, returncode)
+                }
+            }
         })
     }
 
@@ -976,22 +1181,22 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
 
         // Else find a free state
         if rx_state.is_none() {
-            rx_state = self
-                .rx_states
-                .iter()
-                .find(|state| !state.is_busy(self.clock.now(), A::Frequency::frequency()));
-            // Initialize new state
-            rx_state.map(|state| {
-                state.start_receive(
-                    src_mac_addr,
-                    dst_mac_addr,
-                    dgram_size,
-                    dgram_tag,
-                    self.clock.now(),
-                )
-            });
-            if rx_state.is_none() {
-                return (None, ReturnCode::ENOMEM);
+            rx_state = self.rx_states.iter().find(|state| !state.is_busy());
+            match rx_state {
+                None => return (None, ReturnCode::ENOMEM),
+                Some(state) => {
+                    if dgram_size == 0 || dgram_size as usize > state.capacity() {
+                        return (None, ReturnCode::EINVAL);
+                    }
+                    state.start_receive(
+                        src_mac_addr,
+                        dst_mac_addr,
+                        dgram_size,
+                        dgram_tag,
+                        self.clock.now(),
+                    );
+                    self.schedule_timeout();
+                }
             }
         }
         rx_state.map_or((None, ReturnCode::ENOMEM), |state| {
@@ -1004,8 +1209,10 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
                 &self.ctx_store,
             );
             match res {
-                // Some error occurred
-                Err(_) => (Some(state), ReturnCode::FAIL),
+                // A duplicate fragment, so keep the reassembly
+                Err(ReturnCode::EALREADY) => (None, ReturnCode::EALREADY),
+                // Some error occurred, so discard the whole packet
+                Err(returncode) => (Some(state), returncode),
                 Ok(complete) => {
                     if complete {
                         // Packet fully reassembled
@@ -1031,3 +1238,194 @@ impl<'a, A: time::Alarm<'a>, C: ContextStore> Sixlowpan<'a, A, C> {
         // TODO: Need to get buffer back from Mac layer on disassociation
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::net::ieee802154::{
+        FrameType, FrameVersion, HeaderIE, PayloadIE, MAX_HEADER_IES, MAX_PAYLOAD_IES,
+    };
+    use crate::net::sixlowpan::sixlowpan_compression::Context;
+    use crate::test_util::{leak, SimAlarm};
+    use core::cell::RefCell;
+    use kernel::hil::time::Alarm;
+    use std::vec::Vec;
+
+    const TAG: u16 = 0x1234;
+    // The IPv6 header decompressed from the first fragment, followed by the
+    // payload carried in three fragments.
+    const DGRAM_SIZE: usize = 40 + 96;
+
+    struct Received(RefCell<Vec<(Vec<u8>, ReturnCode)>>);
+
+    impl SixlowpanRxClient for Received {
+        fn receive<'a>(&self, buf: &'a [u8], len: usize, result: ReturnCode) {
+            self.0.borrow_mut().push((buf[..len].to_vec(), result));
+        }
+    }
+
+    type TestSixlowpan = Sixlowpan<'static, SimAlarm<'static>, Context>;
+
+    fn setup() -> (
+        &'static TestSixlowpan,
+        &'static SimAlarm<'static>,
+        &'static Received,
+    ) {
+        let alarm: &SimAlarm = leak(SimAlarm::new(0));
+        let context = Context {
+            prefix: [0; 16],
+            prefix_len: 0,
+            id: 0,
+            compress: false,
+        };
+        let sixlowpan: &TestSixlowpan = leak(Sixlowpan::new(context, alarm));
+        alarm.set_client(sixlowpan);
+        for _ in 0..2 {
+            sixlowpan.add_rx_state(leak(RxState::new(leak([0; 1280]))));
+        }
+        let received: &Received = leak(Received(RefCell::new(Vec::new())));
+        sixlowpan.set_rx_client(received);
+        (sixlowpan, alarm, received)
+    }
+
+    fn payload_byte(i: usize) -> u8 {
+        (i * 7) as u8
+    }
+
+    // The fragment carrying bytes `start..end` of the datagram payload.
+    fn fragment(start: usize, end: usize) -> Vec<u8> {
+        let mut frame = std::vec![0; lowpan_frag::FRAGN_HDR_SIZE];
+        set_frag_hdr(DGRAM_SIZE as u16, TAG, 40 + start, &mut frame, start == 0);
+        if start == 0 {
+            frame.truncate(lowpan_frag::FRAG1_HDR_SIZE);
+            // LOWPAN_IPHC with elided traffic class, flow label and
+            // addresses, a hop limit of 255 and No Next Header inline.
+            frame.extend_from_slice(&[0x7b, 0x33, 59]);
+        }
+        frame.extend((start..end).map(payload_byte));
+        frame
+    }
+
+    fn deliver(sixlowpan: &TestSixlowpan, frame: &[u8]) {
+        let header = Header {
+            frame_type: FrameType::Data,
+            frame_pending: false,
+            ack_requested: true,
+            version: FrameVersion::V2006,
+            seq: Some(0),
+            dst_pan: Some(0xabcd),
+            dst_addr: Some(MacAddress::Short(2)),
+            src_pan: Some(0xabcd),
+            src_addr: Some(MacAddress::Short(1)),
+            security: None,
+            header_ies: [HeaderIE::default(); MAX_HEADER_IES],
+            header_ies_len: 0,
+            payload_ies: [PayloadIE::default(); MAX_PAYLOAD_IES],
+            payload_ies_len: 0,
+        };
+        sixlowpan.receive(frame, header, 0, frame.len());
+    }
+
+    fn assert_datagram(received: &Received) {
+        let received = received.0.borrow();
+        assert_eq!(received.len(), 1);
+        let (packet, result) = &received[0];
+        assert_eq!(*result, ReturnCode::SUCCESS);
+        assert_eq!(packet.len(), DGRAM_SIZE);
+        assert_eq!(packet[6], 59);
+        assert!(packet[40..]
+            .iter()
+            .enumerate()
+            .all(|(i, byte)| *byte == payload_byte(i)));
+    }
+
+    #[test]
+    fn reassembles_fragments_out_of_order() {
+        let (sixlowpan, _, received) = setup();
+        deliver(sixlowpan, &fragment(64, 96));
+        deliver(sixlowpan, &fragment(0, 32));
+        assert!(received.0.borrow().is_empty());
+        deliver(sixlowpan, &fragment(32, 64));
+        assert_datagram(received);
+
+        let stats = sixlowpan.stats();
+        assert_eq!(stats.rx_frames, 3);
+        assert_eq!(stats.rx_packets, 1);
+        assert_eq!(stats.rx_dropped, 0);
+    }
+
+    #[test]
+    fn duplicate_fragments_keep_reassembly() {
+        let (sixlowpan, _, received) = setup();
+        deliver(sixlowpan, &fragment(0, 32));
+        deliver(sixlowpan, &fragment(32, 64));
+        // Retransmissions after lost acknowledgements
+        deliver(sixlowpan, &fragment(32, 64));
+        deliver(sixlowpan, &fragment(0, 32));
+        assert!(received.0.borrow().is_empty());
+        deliver(sixlowpan, &fragment(64, 96));
+        assert_datagram(received);
+
+        let stats = sixlowpan.stats();
+        assert_eq!(stats.rx_frames, 5);
+        assert_eq!(stats.rx_packets, 1);
+        assert_eq!(stats.rx_dropped, 2);
+        assert_eq!(stats.duplicate_fragments, 2);
+        assert_eq!(stats.invalid_fragments, 0);
+    }
+
+    #[test]
+    fn overlapping_fragment_discards_reassembly() {
+        let (sixlowpan, _, received) = setup();
+        deliver(sixlowpan, &fragment(0, 32));
+        deliver(sixlowpan, &fragment(32, 64));
+        // Covers the second fragment but ends elsewhere
+        deliver(sixlowpan, &fragment(32, 48));
+        assert_eq!(received.0.borrow().len(), 1);
+        assert_eq!(received.0.borrow()[0].1, ReturnCode::EINVAL);
+
+        // The rest of the datagram no longer completes it.
+        deliver(sixlowpan, &fragment(64, 96));
+        deliver(sixlowpan, &fragment(32, 64));
+        assert_eq!(received.0.borrow().len(), 1);
+
+        let stats = sixlowpan.stats();
+        assert_eq!(stats.invalid_fragments, 1);
+        assert_eq!(stats.duplicate_fragments, 0);
+        assert_eq!(stats.rx_packets, 0);
+    }
+
+    #[test]
+    fn incomplete_reassembly_times_out() {
+        let (sixlowpan, alarm, received) = setup();
+        sixlowpan.set_reassembly_timeout(10);
+        deliver(sixlowpan, &fragment(0, 32));
+        deliver(sixlowpan, &fragment(32, 64));
+        alarm.advance(9_999);
+        assert_eq!(sixlowpan.stats().reassembly_timeouts, 0);
+        alarm.advance(1);
+        assert_eq!(sixlowpan.stats().reassembly_timeouts, 1);
+        assert!(!alarm.is_enabled());
+
+        deliver(sixlowpan, &fragment(64, 96));
+        assert!(received.0.borrow().is_empty());
+        deliver(sixlowpan, &fragment(0, 32));
+        deliver(sixlowpan, &fragment(32, 64));
+        assert_datagram(received);
+    }
+
+    #[test]
+    fn reset_stats() {
+        let (sixlowpan, _, _) = setup();
+        deliver(sixlowpan, &fragment(0, 32));
+        deliver(sixlowpan, &fragment(0, 32));
+        assert_eq!(sixlowpan.stats().duplicate_fragments, 1);
+        sixlowpan.reset_stats();
+        let stats = sixlowpan.stats();
+        assert_eq!(stats.rx_frames, 0);
+        assert_eq!(stats.rx_dropped, 0);
+        assert_eq!(stats.duplicate_fragments, 0);
+    }
+}
@@ -3,9 +3,11 @@
 //! Implements a userspace interface for sending and receiving UDP messages.
 //! Processes use this driver to send UDP packets from a common interface
 //! and bind to UDP ports for receiving packets.
-//! Also exposes a list of interface addresses to the application (currently
-//! hard-coded).
+//! Also exposes a list of interface addresses to the application. The list
+//! is read from the address table on every request, so it includes addresses
+//! assigned at runtime by address autoconfiguration.
 
+use crate::net::ipv6::addr_table::IPAddrTable;
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::network_capabilities::NetworkCapability;
 use crate::net::stream::encode_u16;
@@ -82,8 +84,8 @@ pub struct UDPDriver<'a> {
     /// ID of app whose transmission request is being processed.
     current_app: Cell<Option<AppId>>,
 
-    /// List of IP Addresses of the interfaces on the device
-    interface_list: &'static [IPAddr],
+    /// IP Addresses assigned to the interfaces on the device
+    addr_table: &'static IPAddrTable<'static>,
 
     /// Maximum length payload that an app can transmit via this driver
     max_tx_pyld_len: usize,
@@ -102,7 +104,7 @@ impl<'a> UDPDriver<'a> {
     pub fn new(
         sender: &'a dyn UDPSender<'a>,
         grant: Grant<App>,
-        interface_list: &'static [IPAddr],
+        addr_table: &'static IPAddrTable<'static>,
         max_tx_pyld_len: usize,
         port_table: &'static UdpPortManager,
         kernel_buffer: LeasableBuffer<'static, u8>,
@@ -113,7 +115,7 @@ impl<'a> UDPDriver<'a> {
             sender: sender,
             apps: grant,
             current_app: Cell::new(None),
-            interface_list: interface_list,
+            addr_table: addr_table,
             max_tx_pyld_len: max_tx_pyld_len,
             port_table: port_table,
             kernel_buffer: MapCell::new(kernel_buffer),
@@ -483,16 +485,16 @@ impl<'a> Driver for UDPDriver<'a> {
             //  Writes the requested number of network interface addresses
             // `arg1`: number of interfaces requested that will fit into the buffer
             1 => self.do_with_cfg_mut(appid, arg1 * mem::size_of::<IPAddr>(), |cfg| {
-                let n_ifaces_to_copy = cmp::min(arg1, self.interface_list.len());
+                let n_ifaces = self.addr_table.len();
+                let n_ifaces_to_copy = cmp::min(arg1, n_ifaces);
                 let iface_size = mem::size_of::<IPAddr>();
                 for i in 0..n_ifaces_to_copy {
-                    cfg[i * iface_size..(i + 1) * iface_size]
-                        .copy_from_slice(&self.interface_list[i].0);
+                    self.addr_table.get(i).map(|addr| {
+                        cfg[i * iface_size..(i + 1) * iface_size].copy_from_slice(&addr.0)
+                    });
                 }
                 // Returns total number of interfaces
-                ReturnCode::SuccessWithValue {
-                    value: self.interface_list.len(),
-                }
+                ReturnCode::SuccessWithValue { value: n_ifaces }
             }),
 
             // Transmits UDP packet stored in tx_buf
@@ -557,13 +559,7 @@ impl<'a> Driver for UDPDriver<'a> {
                             return ReturnCode::SUCCESS;
                         }
                         // Check that requested addr is a local interface
-                        let mut requested_is_local = false;
-                        for i in 0..self.interface_list.len() {
-                            if requested_addr.addr == self.interface_list[i] {
-                                requested_is_local = true;
-                            }
-                        }
-                        if !requested_is_local {
+                        if !self.addr_table.contains(requested_addr.addr) {
                             return ReturnCode::EINVAL;
                         }
                         let mut addr_already_bound = false;
@@ -158,6 +158,20 @@ impl UdpPortManager {
         }
     }
 
+    /// A port table for unit tests, which can't implement the unsafe
+    /// capability traits since capsules forbid unsafe code.
+    #[cfg(test)]
+    pub fn for_tests(
+        used_kernel_ports: &'static mut [Option<SocketBindingEntry>],
+        udp_vis: &'static UdpVisibilityCapability,
+    ) -> UdpPortManager {
+        UdpPortManager {
+            port_array: TakeCell::new(used_kernel_ports),
+            user_ports: OptionalCell::empty(),
+            udp_vis: udp_vis,
+        }
+    }
+
     // This function is called to set a reference to the UDP driver, so that the ports
     // bound by applications can be queried from within this file.
     pub fn set_user_ports(
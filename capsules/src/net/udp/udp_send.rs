@@ -15,6 +15,7 @@
 //! the userspace driver must queue app packets on its own, as it can only pass a single
 //! packet to the MuxUdpSender queue at a time.
 
+use crate::net::icmpv6::icmpv6::ICMP6Header;
 use crate::net::ipv6::ip_utils::IPAddr;
 use crate::net::ipv6::ipv6::TransportHeader;
 use crate::net::ipv6::ipv6_send::{IP6SendClient, IP6Sender};
@@ -315,17 +316,7 @@ impl<'a, T: IP6Sender<'a>> UDPSender<'a> for UDPSendStruct<'a, T> {
         net_cap: &'static NetworkCapability,
     ) -> Result<(), LeasableBuffer<'static, u8>> {
         udp_header.set_len((buf.len() + udp_header.get_hdr_size()) as u16);
-        let transport_header = TransportHeader::UDP(udp_header);
-        self.tx_buffer.replace(buf);
-        self.next_dest.replace(dest);
-        self.next_th.replace(transport_header); // th = transport header
-        match self
-            .udp_mux_sender
-            .send_to(dest, transport_header, &self, net_cap)
-        {
-            ReturnCode::SUCCESS => Ok(()),
-            _ => Err(self.tx_buffer.take().unwrap()),
-        }
+        self.queue_send(dest, TransportHeader::UDP(udp_header), buf, net_cap)
     }
 
     fn get_binding(&self) -> Option<UdpPortBindingTx> {
@@ -358,4 +349,43 @@ impl<'a, T: IP6Sender<'a>> UDPSendStruct<'a, T> {
             net_cap: OptionalCell::empty(),
         }
     }
+
+    /// Sends an ICMPv6 message through the same queue as UDP packets, so
+    /// that kernel capsules such as Neighbor Discovery can share the IP
+    /// sender with the UDP stack. No port binding is required. The
+    /// `send_done` callback is delivered to the `UDPSendClient` as for UDP
+    /// packets.
+    ///
+    /// # Arguments
+    /// `dest` - IPv6 address to send the ICMPv6 message to
+    /// `icmp_header` - ICMPv6 header of the message
+    /// `buf` - ICMPv6 message body following the header
+    pub fn send_icmp(
+        &'a self,
+        dest: IPAddr,
+        icmp_header: ICMP6Header,
+        buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        self.queue_send(dest, TransportHeader::ICMP(icmp_header), buf, net_cap)
+    }
+
+    fn queue_send(
+        &'a self,
+        dest: IPAddr,
+        transport_header: TransportHeader,
+        buf: LeasableBuffer<'static, u8>,
+        net_cap: &'static NetworkCapability,
+    ) -> Result<(), LeasableBuffer<'static, u8>> {
+        self.tx_buffer.replace(buf);
+        self.next_dest.replace(dest);
+        self.next_th.replace(transport_header); // th = transport header
+        match self
+            .udp_mux_sender
+            .send_to(dest, transport_header, &self, net_cap)
+        {
+            ReturnCode::SUCCESS => Ok(()),
+            _ => Err(self.tx_buffer.take().unwrap()),
+        }
+    }
 }
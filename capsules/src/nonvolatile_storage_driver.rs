@@ -1,14 +1,39 @@
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! Each application gets a region of its own in the memory space that has
+//! been provided to userland, and can't read or write the regions of other
+//! applications. Applications see their region as starting at address 0.
 //!
 //! However, the kernel accessible memory does not have to be the same range
 //! as the userspace accessible address space. The kernel memory can overlap
 //! if desired, or can be a completely separate range.
 //!
+//! Application regions
+//! -------------------
+//!
+//! An application is identified by a hash of its process name, so it gets
+//! the same region after a reboot, and after it is reinstalled. The name is
+//! not authenticated, so an application installed with the name of another
+//! one gets its region; see `process_identity`. The size of a region is the
+//! size the application asks for with the Nonvolatile Storage element of its
+//! TBF header, or the default size set by the board otherwise, up to the
+//! maximum size set by the board.
+//!
+//! A region is allocated when the application first reads or writes, and
+//! recorded in an allocation table at the start of the userspace memory
+//! space. The table holds up to `MAX_APPS` entries:
+//!
+//! ```text
+//! entry:  crc (4) | app id (8) | start (4) | length (4)
+//! ```
+//!
+//! All numbers are little endian, the CRC covers the rest of the entry, and
+//! the start is relative to the end of the table. Regions are allocated one
+//! after another and never freed, and the table ends at the first entry that
+//! fails its CRC. A region keeps the size it was allocated with, even if the
+//! application later asks for a different size. Once the table is full, or
+//! the next region doesn't fit, applications without a region get ENOMEM.
+//!
 //! Here is a diagram of the expected stack with this capsule:
 //! Boxes are components and between the boxes are the traits that are the
 //! interfaces between components. This capsule provides both a kernel and
@@ -39,14 +64,22 @@
 //! ```rust
 //! # use kernel::static_init;
 //!
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
 //! let nonvolatile_storage = static_init!(
-//!     capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+//!     capsules::nonvolatile_storage_driver::NonvolatileStorage<'static, ProcessMgmtCap>,
 //!     capsules::nonvolatile_storage_driver::NonvolatileStorage::new(
 //!         fm25cl,                      // The underlying storage driver.
 //!         board_kernel.create_grant(&grant_cap),     // Storage for app-specific state.
+//!         board_kernel,                // To find the names and TBF headers of apps.
+//!         ProcessMgmtCap,
 //!         3000,                        // The byte start address for the userspace
 //!                                      // accessible memory region.
 //!         2000,                        // The length of the userspace region.
+//!         256,                         // The size of an app region if the app
+//!                                      // doesn't ask for a size.
+//!         512,                         // The largest region an app can get.
 //!         0,                           // The byte start address of the region
 //!                                      // that is accessible by the kernel.
 //!         3000,                        // The length of the kernel region.
@@ -54,11 +87,16 @@
 //! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_storage);
 //! ```
 
+use crate::crc32::crc32;
+use crate::process_identity;
+use crate::sha::SHA256_DIGEST_LEN;
 use core::cell::Cell;
 use core::cmp;
+use kernel::capabilities::ProcessManagementCapability;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil;
-use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::introspection::KernelInfo;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, Kernel, ReturnCode, Shared};
 
 /// Syscall driver number.
 use crate::driver;
@@ -66,6 +104,16 @@ pub const DRIVER_NUM: usize = driver::NUM::NvmStorage as usize;
 
 pub static mut BUFFER: [u8; 512] = [0; 512];
 
+/// Most applications with a region.
+pub const MAX_APPS: usize = 16;
+
+/// Length of the identifier of an application.
+const APP_ID_LEN: usize = 8;
+const ENTRY_SIZE: usize = 4 + APP_ID_LEN + 4 + 4;
+
+/// Size of the allocation table at the start of the userspace region.
+pub const TABLE_SIZE: usize = MAX_APPS * ENTRY_SIZE;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
@@ -76,8 +124,152 @@ pub enum NonvolatileCommand {
 
 #[derive(Clone, Copy)]
 pub enum NonvolatileUser {
-    App { app_id: AppId },
+    App {
+        app_id: AppId,
+    },
     Kernel,
+    /// Reading or adding to the allocation table.
+    Table,
+}
+
+/// The region of an application, relative to the end of the allocation
+/// table.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Region {
+    id: [u8; APP_ID_LEN],
+    start: usize,
+    length: usize,
+}
+
+impl Region {
+    fn parse(entry: &[u8]) -> Option<Region> {
+        let crc = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+        if crc != crc32(&entry[4..ENTRY_SIZE]) {
+            return None;
+        }
+        let mut id = [0; APP_ID_LEN];
+        id.copy_from_slice(&entry[4..4 + APP_ID_LEN]);
+        let start = &entry[4 + APP_ID_LEN..];
+        Some(Region {
+            id: id,
+            start: u32::from_le_bytes([start[0], start[1], start[2], start[3]]) as usize,
+            length: u32::from_le_bytes([start[4], start[5], start[6], start[7]]) as usize,
+        })
+    }
+
+    fn encode(&self, entry: &mut [u8]) {
+        entry[4..4 + APP_ID_LEN].copy_from_slice(&self.id);
+        let start = 4 + APP_ID_LEN;
+        entry[start..start + 4].copy_from_slice(&(self.start as u32).to_le_bytes());
+        entry[start + 4..start + 8].copy_from_slice(&(self.length as u32).to_le_bytes());
+        let crc = crc32(&entry[4..ENTRY_SIZE]);
+        entry[0..4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// The end of the region, unless a corrupt entry overflows.
+    fn end(&self) -> Option<usize> {
+        self.start.checked_add(self.length)
+    }
+}
+
+/// The allocation table, once it has been read.
+struct AllocationTable {
+    regions: [Cell<Option<Region>>; MAX_APPS],
+    loaded: Cell<bool>,
+    /// Bytes of the userspace memory space after the table.
+    space: usize,
+    /// Size of the region of an app that doesn't ask for a size.
+    default_region_size: usize,
+    /// Largest region an app can get.
+    max_region_size: usize,
+}
+
+impl AllocationTable {
+    fn new(userspace_length: usize, default_region_size: usize, max_region_size: usize) -> Self {
+        AllocationTable {
+            regions: Default::default(),
+            loaded: Cell::new(false),
+            space: userspace_length.saturating_sub(TABLE_SIZE),
+            default_region_size: default_region_size,
+            max_region_size: max_region_size,
+        }
+    }
+
+    /// The size of the region an app gets when it asks for `requested`
+    /// bytes in its TBF header.
+    fn region_size(&self, requested: usize) -> usize {
+        match requested {
+            0 => cmp::min(self.default_region_size, self.max_region_size),
+            size => cmp::min(size, self.max_region_size),
+        }
+    }
+
+    /// Reads the regions from the entries in `table`.
+    fn load(&self, table: &[u8]) {
+        // The table ends at the first entry that isn't intact, or whose
+        // region isn't in the userspace memory space.
+        let mut intact = true;
+        for (entry, region) in table[..TABLE_SIZE]
+            .chunks(ENTRY_SIZE)
+            .zip(self.regions.iter())
+        {
+            let parsed = if intact {
+                Region::parse(entry)
+                    .filter(|region| region.end().map_or(false, |end| end <= self.space))
+            } else {
+                None
+            };
+            intact = parsed.is_some();
+            region.set(parsed);
+        }
+        self.loaded.set(true);
+    }
+
+    fn find(&self, id: [u8; APP_ID_LEN]) -> Option<Region> {
+        self.regions
+            .iter()
+            .filter_map(|region| region.get())
+            .find(|region| region.id == id)
+    }
+
+    /// Where a new region of `length` bytes would go in the table and in
+    /// the userspace memory space, if there is room.
+    fn next_region(&self, id: [u8; APP_ID_LEN], length: usize) -> Option<(usize, Region)> {
+        if length == 0 || length > self.max_region_size {
+            return None;
+        }
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.get().is_none())?;
+        let mut start = 0;
+        for region in self.regions.iter().filter_map(|region| region.get()) {
+            start = cmp::max(start, region.end()?);
+        }
+        let region = Region {
+            id: id,
+            start: start,
+            length: length,
+        };
+        if region.end()? > self.space {
+            return None;
+        }
+        Some((index, region))
+    }
+
+    /// Records that `region` was written to entry `index` of the table.
+    fn add(&self, index: usize, region: Region) {
+        self.regions[index].set(Some(region));
+    }
+}
+
+/// The identifier of the app with `identity`: the start of the identity.
+/// Apps without one are refused.
+fn app_id_for(identity: Option<[u8; SHA256_DIGEST_LEN]>) -> Result<[u8; APP_ID_LEN], ReturnCode> {
+    let digest = identity.ok_or(ReturnCode::ENOSUPPORT)?;
+    let mut id = [0; APP_ID_LEN];
+    id.copy_from_slice(&digest[..APP_ID_LEN]);
+    Ok(id)
 }
 
 pub struct App {
@@ -89,6 +281,8 @@ pub struct App {
     length: usize,
     buffer_read: Option<AppSlice<Shared, u8>>,
     buffer_write: Option<AppSlice<Shared, u8>>,
+    /// The region of the app, once it is known.
+    region: Option<Region>,
 }
 
 impl Default for App {
@@ -102,15 +296,19 @@ impl Default for App {
             length: 0,
             buffer_read: None,
             buffer_write: None,
+            region: None,
         }
     }
 }
 
-pub struct NonvolatileStorage<'a> {
+pub struct NonvolatileStorage<'a, C: ProcessManagementCapability> {
     // The underlying physical storage device.
     driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
     // Per-app state.
     apps: Grant<App>,
+    // To find the name and TBF header of each app.
+    kernel: &'static Kernel,
+    capability: C,
 
     // Internal buffer for copying appslices into.
     buffer: TakeCell<'static, [u8]>,
@@ -119,13 +317,16 @@ pub struct NonvolatileStorage<'a> {
 
     // The first byte that is accessible from userspace.
     userspace_start_address: usize,
-    // How many bytes allocated to userspace.
-    userspace_length: usize,
     // The first byte that is accessible from the kernel.
     kernel_start_address: usize,
     // How many bytes allocated to kernel.
     kernel_length: usize,
 
+    // The allocation table, and the sizes of regions.
+    table: AllocationTable,
+    // The entry being added to the table, and where.
+    new_entry: OptionalCell<(usize, Region)>,
+
     // Optional client for the kernel. Only needed if the kernel intends to use
     // this nonvolatile storage.
     kernel_client:
@@ -142,25 +343,32 @@ pub struct NonvolatileStorage<'a> {
     kernel_readwrite_address: Cell<usize>,
 }
 
-impl<'a> NonvolatileStorage<'a> {
+impl<'a, C: ProcessManagementCapability> NonvolatileStorage<'a, C> {
     pub fn new(
         driver: &'a dyn hil::nonvolatile_storage::NonvolatileStorage<'static>,
         grant: Grant<App>,
+        kernel: &'static Kernel,
+        capability: C,
         userspace_start_address: usize,
         userspace_length: usize,
+        default_region_size: usize,
+        max_region_size: usize,
         kernel_start_address: usize,
         kernel_length: usize,
         buffer: &'static mut [u8],
-    ) -> NonvolatileStorage<'a> {
+    ) -> NonvolatileStorage<'a, C> {
         NonvolatileStorage {
             driver: driver,
             apps: grant,
+            kernel: kernel,
+            capability: capability,
             buffer: TakeCell::new(buffer),
             current_user: OptionalCell::empty(),
             userspace_start_address: userspace_start_address,
-            userspace_length: userspace_length,
             kernel_start_address: kernel_start_address,
             kernel_length: kernel_length,
+            table: AllocationTable::new(userspace_length, default_region_size, max_region_size),
+            new_entry: OptionalCell::empty(),
             kernel_client: OptionalCell::empty(),
             kernel_pending_command: Cell::new(false),
             kernel_command: Cell::new(NonvolatileCommand::KernelRead),
@@ -170,6 +378,81 @@ impl<'a> NonvolatileStorage<'a> {
         }
     }
 
+    /// The identifier of an app.
+    fn app_id_of(&self, appid: AppId) -> Result<[u8; APP_ID_LEN], ReturnCode> {
+        app_id_for(process_identity::name_digest(
+            self.kernel,
+            &self.capability,
+            appid,
+        ))
+    }
+
+    /// The size of region an app gets.
+    fn requested_size(&self, appid: AppId) -> usize {
+        self.table.region_size(
+            KernelInfo::new(self.kernel).nonvolatile_storage_size(appid, &self.capability),
+        )
+    }
+
+    /// Finds the region of an app. Returns `Ok(None)` if the allocation table
+    /// has to be read or added to first, ENOMEM if there is no room for the
+    /// app and ENOSUPPORT if the app has no identity.
+    fn region_of(&self, app: &mut App, appid: AppId) -> Result<Option<Region>, ReturnCode> {
+        if app.region.is_some() || !self.table.loaded.get() {
+            return Ok(app.region);
+        }
+        let id = self.app_id_of(appid)?;
+        if let Some(region) = self.table.find(id) {
+            app.region = Some(region);
+            return Ok(Some(region));
+        }
+        match self.table.next_region(id, self.requested_size(appid)) {
+            Some(_) => Ok(None),
+            None => Err(ReturnCode::ENOMEM),
+        }
+    }
+
+    /// Reads the allocation table.
+    fn load_table(&self) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            self.current_user.set(NonvolatileUser::Table);
+            let result = self
+                .driver
+                .read(buffer, self.userspace_start_address, TABLE_SIZE);
+            if result != ReturnCode::SUCCESS {
+                self.current_user.clear();
+            }
+            result
+        })
+    }
+
+    /// Adds an entry for the region of an app to the allocation table.
+    fn add_region(&self, appid: AppId) -> ReturnCode {
+        let id = match self.app_id_of(appid) {
+            Ok(id) => id,
+            Err(result) => return result,
+        };
+        let (index, region) = match self.table.next_region(id, self.requested_size(appid)) {
+            Some(entry) => entry,
+            None => return ReturnCode::ENOMEM,
+        };
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            region.encode(buffer);
+            self.current_user.set(NonvolatileUser::Table);
+            self.new_entry.set((index, region));
+            let result = self.driver.write(
+                buffer,
+                self.userspace_start_address + index * ENTRY_SIZE,
+                ENTRY_SIZE,
+            );
+            if result != ReturnCode::SUCCESS {
+                self.current_user.clear();
+                self.new_entry.clear();
+            }
+            result
+        })
+    }
+
     // Check so see if we are doing something. If not, go ahead and do this
     // command. If so, this is queued and will be run when the pending
     // command completes.
@@ -180,25 +463,20 @@ impl<'a> NonvolatileStorage<'a> {
         length: usize,
         app_id: Option<AppId>,
     ) -> ReturnCode {
-        // Do bounds check.
+        // Do bounds check. Userspace commands are checked against the region
+        // of the app once it is known.
         match command {
-            NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length
-                    || length > self.userspace_length
-                    || offset + length > self.userspace_length
-                {
-                    return ReturnCode::EINVAL;
-                }
-            }
+            NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {}
             NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
                 // Because the kernel uses the NonvolatileStorage interface,
                 // its calls are absolute addresses.
+                let kernel_end = self.kernel_start_address + self.kernel_length;
                 if offset < self.kernel_start_address
-                    || offset >= self.kernel_start_address + self.kernel_length
+                    || offset >= kernel_end
                     || length > self.kernel_length
-                    || offset + length > self.kernel_start_address + self.kernel_length
+                    || offset
+                        .checked_add(length)
+                        .map_or(true, |end| end > kernel_end)
                 {
                     return ReturnCode::EINVAL;
                 }
@@ -209,7 +487,7 @@ impl<'a> NonvolatileStorage<'a> {
         // or from the kernel.
         match command {
             NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                app_id.map_or(ReturnCode::FAIL, |appid| {
+                let result = app_id.map_or(ReturnCode::FAIL, |appid| {
                     self.apps
                         .enter(appid, |app, _| {
                             // Get the length of the correct allowed buffer.
@@ -228,56 +506,54 @@ impl<'a> NonvolatileStorage<'a> {
                                 return ReturnCode::ERESERVE;
                             }
 
+                            // Userspace sees memory that starts at address 0
+                            // even if it is offset in the physical memory.
+                            let region = match self.region_of(app, appid) {
+                                Ok(region) => region,
+                                Err(result) => return result,
+                            };
+                            if let Some(region) = region {
+                                if !in_region(region, offset, length) {
+                                    return ReturnCode::EINVAL;
+                                }
+                            }
+
                             // Shorten the length if the application gave us nowhere to
                             // put it.
                             let active_len = cmp::min(length, allow_buf_len);
 
                             // First need to determine if we can execute this or must
-                            // queue it.
-                            if self.current_user.is_none() {
-                                // No app is currently using the underlying storage.
-                                // Mark this app as active, and then execute the command.
-                                self.current_user
-                                    .set(NonvolatileUser::App { app_id: appid });
-
-                                // Need to copy bytes if this is a write!
-                                if command == NonvolatileCommand::UserspaceWrite {
-                                    app.buffer_write.as_mut().map(|app_buffer| {
-                                        self.buffer.map(|kernel_buffer| {
-                                            // Check that the internal buffer and the buffer that was
-                                            // allowed are long enough.
-                                            let write_len =
-                                                cmp::min(active_len, kernel_buffer.len());
-
-                                            let d = &mut app_buffer.as_mut()[0..write_len];
-                                            for (i, c) in
-                                                kernel_buffer[0..write_len].iter_mut().enumerate()
-                                            {
-                                                *c = d[i];
-                                            }
-                                        });
-                                    });
-                                }
-
-                                self.userspace_call_driver(command, offset, active_len)
-                            } else {
-                                // Some app is using the storage, we must wait.
-                                if app.pending_command == true {
-                                    // No more room in the queue, nowhere to store this
-                                    // request.
-                                    ReturnCode::ENOMEM
-                                } else {
-                                    // We can store this, so lets do it.
-                                    app.pending_command = true;
-                                    app.command = command;
-                                    app.offset = offset;
-                                    app.length = active_len;
-                                    ReturnCode::SUCCESS
+                            // queue it. If the region of the app isn't known
+                            // yet, it is found before the command runs.
+                            match region {
+                                Some(region) if self.current_user.is_none() => self
+                                    .start_app_command(
+                                        appid, app, region, command, offset, active_len,
+                                    ),
+                                _ => {
+                                    // Some app is using the storage, we must wait.
+                                    if app.pending_command == true {
+                                        // No more room in the queue, nowhere to store this
+                                        // request.
+                                        ReturnCode::ENOMEM
+                                    } else {
+                                        // We can store this, so lets do it.
+                                        app.pending_command = true;
+                                        app.command = command;
+                                        app.offset = offset;
+                                        app.length = active_len;
+                                        ReturnCode::SUCCESS
+                                    }
                                 }
                             }
                         })
                         .unwrap_or_else(|err| err.into())
-                })
+                });
+                // The command may be waiting for the allocation table.
+                if self.current_user.is_none() {
+                    self.check_queue();
+                }
+                result
             }
             NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
                 self.kernel_buffer
@@ -316,6 +592,42 @@ impl<'a> NonvolatileStorage<'a> {
         }
     }
 
+    /// Starts a read or write of an app, in its region.
+    fn start_app_command(
+        &self,
+        appid: AppId,
+        app: &mut App,
+        region: Region,
+        command: NonvolatileCommand,
+        offset: usize,
+        length: usize,
+    ) -> ReturnCode {
+        // Mark this app as active, and then execute the command.
+        self.current_user
+            .set(NonvolatileUser::App { app_id: appid });
+
+        // Need to copy bytes if this is a write!
+        if command == NonvolatileCommand::UserspaceWrite {
+            app.buffer_write.as_mut().map(|app_buffer| {
+                self.buffer.map(|kernel_buffer| {
+                    // Check that the internal buffer and the buffer that was
+                    // allowed are long enough.
+                    let write_len =
+                        cmp::min(length, cmp::min(kernel_buffer.len(), app_buffer.len()));
+
+                    let d = &app_buffer.as_ref()[0..write_len];
+                    kernel_buffer[0..write_len].copy_from_slice(d);
+                });
+            });
+        }
+
+        let result = self.userspace_call_driver(command, region.start + offset, length);
+        if result != ReturnCode::SUCCESS {
+            self.current_user.clear();
+        }
+        result
+    }
+
     fn userspace_call_driver(
         &self,
         command: NonvolatileCommand,
@@ -324,14 +636,13 @@ impl<'a> NonvolatileStorage<'a> {
     ) -> ReturnCode {
         // Calculate where we want to actually read from in the physical
         // storage.
-        let physical_address = offset + self.userspace_start_address;
+        let physical_address = offset + self.userspace_start_address + TABLE_SIZE;
 
         self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
             // Check that the internal buffer and the buffer that was
             // allowed are long enough.
             let active_len = cmp::min(length, buffer.len());
 
-            // self.current_app.set(Some(appid));
             match command {
                 NonvolatileCommand::UserspaceRead => {
                     self.driver.read(buffer, physical_address, active_len)
@@ -369,16 +680,35 @@ impl<'a> NonvolatileStorage<'a> {
             // If the kernel is not requesting anything, check all of the apps.
             for cntr in self.apps.iter() {
                 let started_command = cntr.enter(|app, _| {
-                    if app.pending_command {
-                        app.pending_command = false;
-                        self.current_user.set(NonvolatileUser::App {
-                            app_id: app.appid(),
-                        });
-                        self.userspace_call_driver(app.command, app.offset, app.length)
-                            == ReturnCode::SUCCESS
+                    if !app.pending_command {
+                        return false;
+                    }
+                    let appid = app.appid();
+                    let result = if !self.table.loaded.get() {
+                        self.load_table()
                     } else {
-                        false
+                        match self.region_of(app, appid) {
+                            Ok(Some(region)) => {
+                                app.pending_command = false;
+                                let (command, offset, length) =
+                                    (app.command, app.offset, app.length);
+                                if in_region(region, offset, length) {
+                                    self.start_app_command(
+                                        appid, app, region, command, offset, length,
+                                    )
+                                } else {
+                                    ReturnCode::EINVAL
+                                }
+                            }
+                            Ok(None) => self.add_region(appid),
+                            Err(result) => result,
+                        }
+                    };
+                    if result != ReturnCode::SUCCESS {
+                        app.pending_command = false;
+                        fail_command(app);
                     }
+                    result == ReturnCode::SUCCESS
                 });
                 if started_command {
                     break;
@@ -388,8 +718,28 @@ impl<'a> NonvolatileStorage<'a> {
     }
 }
 
+/// Whether `length` bytes from `offset` are in `region`.
+fn in_region(region: Region, offset: usize, length: usize) -> bool {
+    offset < region.length
+        && offset
+            .checked_add(length)
+            .map_or(false, |end| end <= region.length)
+}
+
+/// Reports that a waiting command of an app couldn't run, as a read or
+/// write of 0 bytes.
+fn fail_command(app: &mut App) {
+    let callback = match app.command {
+        NonvolatileCommand::UserspaceRead => app.callback_read,
+        _ => app.callback_write,
+    };
+    callback.map(|mut cb| cb.schedule(0, 0, 0));
+}
+
 /// This is the callback client for the underlying physical storage driver.
-impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for NonvolatileStorage<'_> {
+impl<C: ProcessManagementCapability> hil::nonvolatile_storage::NonvolatileStorageClient<'static>
+    for NonvolatileStorage<'_, C>
+{
     fn read_done(&self, buffer: &'static mut [u8], length: usize) {
         // Switch on which user of this capsule generated this callback.
         self.current_user.take().map(|user| {
@@ -418,6 +768,10 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                         app.callback_read.map(|mut cb| cb.schedule(length, 0, 0));
                     });
                 }
+                NonvolatileUser::Table => {
+                    self.table.load(buffer);
+                    self.buffer.replace(buffer);
+                }
             }
         });
 
@@ -442,6 +796,12 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
                         app.callback_write.map(|mut cb| cb.schedule(length, 0, 0));
                     });
                 }
+                NonvolatileUser::Table => {
+                    self.new_entry
+                        .take()
+                        .map(|(index, region)| self.table.add(index, region));
+                    self.buffer.replace(buffer);
+                }
             }
         });
 
@@ -450,7 +810,9 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient<'static> for Nonvolatile
 }
 
 /// Provide an interface for the kernel.
-impl hil::nonvolatile_storage::NonvolatileStorage<'static> for NonvolatileStorage<'_> {
+impl<C: ProcessManagementCapability> hil::nonvolatile_storage::NonvolatileStorage<'static>
+    for NonvolatileStorage<'_, C>
+{
     fn set_client(&self, client: &'static dyn hil::nonvolatile_storage::NonvolatileStorageClient) {
         self.kernel_client.set(client);
     }
@@ -467,7 +829,7 @@ impl hil::nonvolatile_storage::NonvolatileStorage<'static> for NonvolatileStorag
 }
 
 /// Provide an interface for userland.
-impl Driver for NonvolatileStorage<'_> {
+impl<C: ProcessManagementCapability> Driver for NonvolatileStorage<'_, C> {
     /// Setup shared buffers.
     ///
     /// ### `allow_num`
@@ -523,7 +885,7 @@ impl Driver for NonvolatileStorage<'_> {
     /// ### `command_num`
     ///
     /// - `0`: Return SUCCESS if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
+    /// - `1`: Return the number of bytes in the region of this app.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
     fn command(&self, arg0: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
@@ -536,10 +898,20 @@ impl Driver for NonvolatileStorage<'_> {
                 ReturnCode::SUCCESS
             }
 
-            // How many bytes are accessible from userspace.
-            1 => ReturnCode::SuccessWithValue {
-                value: self.userspace_length,
-            },
+            // How many bytes are accessible from this app. Before the region
+            // is allocated, this is the size it will get.
+            1 => self
+                .apps
+                .enter(appid, |app, _| match self.region_of(app, appid) {
+                    Ok(Some(region)) => ReturnCode::SuccessWithValue {
+                        value: region.length,
+                    },
+                    Ok(None) => ReturnCode::SuccessWithValue {
+                        value: self.requested_size(appid),
+                    },
+                    Err(result) => result,
+                })
+                .unwrap_or_else(|err| err.into()),
 
             // Issue a read
             2 => {
@@ -569,3 +941,150 @@ impl Driver for NonvolatileStorage<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::process_identity::digest_of_name;
+    use std::vec::Vec;
+
+    const SPACE: usize = 0x1000;
+
+    fn table() -> AllocationTable {
+        AllocationTable::new(TABLE_SIZE + SPACE, 0x100, 0x400)
+    }
+
+    /// Allocates a region for `id`, and writes its entry to `entries`.
+    fn allocate(
+        table: &AllocationTable,
+        entries: &mut [u8],
+        id: u8,
+        requested: usize,
+    ) -> Option<Region> {
+        let (index, region) = table.next_region([id; APP_ID_LEN], table.region_size(requested))?;
+        region.encode(&mut entries[index * ENTRY_SIZE..]);
+        table.add(index, region);
+        Some(region)
+    }
+
+    #[test]
+    fn table_persists() {
+        let mut entries = std::vec![0xff; TABLE_SIZE];
+        let first = table();
+        first.load(&entries);
+        let regions: Vec<Region> = (1..4)
+            .map(|id| allocate(&first, &mut entries, id, 0x80 * id as usize).unwrap())
+            .collect();
+
+        // After a reset, every app gets the region it had.
+        let second = table();
+        second.load(&entries);
+        for (id, region) in (1..4).zip(regions.iter()) {
+            assert_eq!(second.find([id; APP_ID_LEN]), Some(*region));
+        }
+        assert_eq!(
+            allocate(&second, &mut entries, 4, 0).unwrap().start,
+            regions[2].end().unwrap()
+        );
+
+        // A torn entry ends the table.
+        entries[ENTRY_SIZE + 5] ^= 1;
+        let third = table();
+        third.load(&entries);
+        assert_eq!(third.find([1; APP_ID_LEN]), Some(regions[0]));
+        assert_eq!(third.find([2; APP_ID_LEN]), None);
+        assert_eq!(third.find([3; APP_ID_LEN]), None);
+    }
+
+    #[test]
+    fn apps_isolated() {
+        let mut entries = std::vec![0xff; TABLE_SIZE];
+        let table = table();
+        table.load(&entries);
+        let a = allocate(&table, &mut entries, 1, 0).unwrap();
+        let b = allocate(&table, &mut entries, 2, 0x200).unwrap();
+
+        assert_eq!(a.length, 0x100);
+        assert_eq!(b.length, 0x200);
+        assert!(a.end().unwrap() <= b.start);
+        assert_eq!(table.find([1; APP_ID_LEN]), Some(a));
+        assert_eq!(table.find([2; APP_ID_LEN]), Some(b));
+
+        // An app can only reach its own region.
+        assert!(in_region(a, 0, 0x100));
+        assert!(!in_region(a, 0, 0x101));
+        assert!(!in_region(a, 0x100, 1));
+        assert!(!in_region(a, 1, usize::MAX));
+    }
+
+    #[test]
+    fn apps_without_a_name_refused() {
+        assert_eq!(app_id_for(digest_of_name("")), Err(ReturnCode::ENOSUPPORT));
+
+        let entries = std::vec![0xff; TABLE_SIZE];
+        let table = table();
+        table.load(&entries);
+        let blink = app_id_for(digest_of_name("blink")).unwrap();
+        let sensors = app_id_for(digest_of_name("sensors")).unwrap();
+        assert_ne!(blink, sensors);
+        let (_, region) = table.next_region(blink, 0x100).unwrap();
+        table.add(0, region);
+        assert_eq!(table.find(blink), Some(region));
+        assert_eq!(table.find(sensors), None);
+    }
+
+    #[test]
+    fn out_of_space() {
+        let mut entries = std::vec![0xff; TABLE_SIZE];
+        let table = table();
+        table.load(&entries);
+
+        // Apps get no more than the largest region size.
+        assert_eq!(table.region_size(usize::MAX), 0x400);
+        assert_eq!(table.next_region([1; APP_ID_LEN], 0x401), None);
+        assert_eq!(table.next_region([1; APP_ID_LEN], 0), None);
+
+        // The space runs out before the table does.
+        for id in 0..4 {
+            assert!(allocate(&table, &mut entries, id, 0x400).is_some());
+        }
+        assert_eq!(allocate(&table, &mut entries, 4, 1), None);
+
+        // The table runs out before the space does.
+        let mut entries = std::vec![0xff; TABLE_SIZE];
+        let table = AllocationTable::new(TABLE_SIZE + SPACE, 0x10, 0x10);
+        table.load(&entries);
+        for id in 0..MAX_APPS {
+            assert!(allocate(&table, &mut entries, id as u8, 0).is_some());
+        }
+        assert_eq!(allocate(&table, &mut entries, MAX_APPS as u8, 0), None);
+    }
+
+    #[test]
+    fn corrupt_entries_ignored() {
+        // An intact entry for a region past the end of the memory space.
+        let mut entries = std::vec![0xff; TABLE_SIZE];
+        Region {
+            id: [1; APP_ID_LEN],
+            start: u32::MAX as usize,
+            length: u32::MAX as usize,
+        }
+        .encode(&mut entries);
+        let table = table();
+        table.load(&entries);
+        assert_eq!(table.find([1; APP_ID_LEN]), None);
+        assert_eq!(
+            table.next_region([2; APP_ID_LEN], 0x100),
+            Some((
+                0,
+                Region {
+                    id: [2; APP_ID_LEN],
+                    start: 0,
+                    length: 0x100,
+                }
+            ))
+        );
+    }
+}
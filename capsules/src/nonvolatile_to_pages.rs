@@ -6,6 +6,9 @@
 //!
 //! This module is designed to be used on top of any flash storage and below any
 //! user of `NonvolatileStorage`. This module handles different sized pages.
+//! Writes rewrite whole pages in place, so to spread the wear and make page
+//! writes atomic, put the flash translation layer in `ftl.rs` between this
+//! module and the flash.
 //!
 //! ```plain
 //! hil::nonvolatile_storage::NonvolatileStorage
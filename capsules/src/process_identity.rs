@@ -0,0 +1,55 @@
+//! Identifies processes across reboots and updates, for capsules that keep
+//! data for each process.
+//!
+//! A process is identified by the SHA-256 of its package name from its TBF
+//! header, so it keeps its identity when it is updated or moved in flash.
+//! The package name is not authenticated: the kernel does not check which
+//! processes may use a name, so any process that is installed with the name
+//! of another one gets the data of that process, and processes with the same
+//! name share it. Capsules that use this identity isolate processes from one
+//! another only on boards where every installed process is trusted to use
+//! its own name.
+//!
+//! A process without a package name has no identity, since all such
+//! processes would share one. Capsules refuse them service with
+//! `ENOSUPPORT`.
+
+use crate::sha::{Sha256State, SHA256_DIGEST_LEN};
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::introspection::KernelInfo;
+use kernel::{AppId, Kernel};
+
+/// The identity of the process of `appid`: the SHA-256 of its package name,
+/// or `None` if it has no name. Callers keep as many bytes from the start as
+/// they have room for.
+pub fn name_digest(
+    kernel: &'static Kernel,
+    capability: &dyn ProcessManagementCapability,
+    appid: AppId,
+) -> Option<[u8; SHA256_DIGEST_LEN]> {
+    digest_of_name(KernelInfo::new(kernel).process_name(appid, capability))
+}
+
+/// The identity of a process named `name`.
+pub fn digest_of_name(name: &str) -> Option<[u8; SHA256_DIGEST_LEN]> {
+    if name.is_empty() {
+        return None;
+    }
+    let mut hash = Sha256State::new_sha256();
+    hash.update(name.as_bytes());
+    let mut digest = [0; SHA256_DIGEST_LEN];
+    hash.finish(&mut digest);
+    Some(digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unnamed_processes_have_no_identity() {
+        assert_eq!(digest_of_name(""), None);
+        assert!(digest_of_name("blink").is_some());
+        assert_ne!(digest_of_name("blink"), digest_of_name("blink2"));
+    }
+}
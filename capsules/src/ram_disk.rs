@@ -0,0 +1,306 @@
+//! Block storage in RAM.
+//!
+//! A RAM disk splits a buffer into blocks and implements
+//! `hil::block_storage::BlockStorage` over it, so that filesystems and other
+//! block users can run without a storage device, for example in tests or for
+//! scratch files. The contents are lost on reset. Erasing a block fills it
+//! with 0xff.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! static mut RAM_DISK: [u8; 32768] = [0; 32768];
+//!
+//! let ram_disk = static_init!(
+//!     capsules::ram_disk::RamDisk<'static>,
+//!     capsules::ram_disk::RamDisk::new(&mut RAM_DISK, 512, dynamic_deferred_caller)
+//! );
+//! ram_disk.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(ram_disk)
+//!         .expect("no deferred call slot available for ram disk"),
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::hil::block_storage::{BlockStorage, BlockStorageClient, Geometry};
+use kernel::ReturnCode;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Read(u32),
+    Write(u32),
+    Erase(u32),
+}
+
+pub struct RamDisk<'a> {
+    memory: TakeCell<'static, [u8]>,
+    block_size: usize,
+    blocks: u32,
+    client: OptionalCell<&'a dyn BlockStorageClient>,
+    buffer: TakeCell<'static, [u8]>,
+    operation: Cell<Option<Operation>>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> RamDisk<'a> {
+    /// Any bytes of `memory` past the last whole block are unused.
+    pub fn new(
+        memory: &'static mut [u8],
+        block_size: usize,
+        deferred_caller: &'a DynamicDeferredCall,
+    ) -> RamDisk<'a> {
+        let blocks = (memory.len() / block_size) as u32;
+        RamDisk {
+            memory: TakeCell::new(memory),
+            block_size: block_size,
+            blocks: blocks,
+            client: OptionalCell::empty(),
+            buffer: TakeCell::empty(),
+            operation: Cell::new(None),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Starts an operation, which finishes from a deferred call.
+    fn start(&self, operation: Operation, buffer_len: usize) -> ReturnCode {
+        let block = match operation {
+            Operation::Read(block) | Operation::Write(block) | Operation::Erase(block) => block,
+        };
+        if self.operation.get().is_some() {
+            ReturnCode::EBUSY
+        } else if block >= self.blocks || buffer_len < self.block_size {
+            ReturnCode::EINVAL
+        } else {
+            self.handle.map_or(ReturnCode::EOFF, |handle| {
+                self.operation.set(Some(operation));
+                self.deferred_caller.set(*handle);
+                ReturnCode::SUCCESS
+            })
+        }
+    }
+
+    fn start_with_buffer(
+        &self,
+        operation: Operation,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        match self.start(operation, buffer.len()) {
+            ReturnCode::SUCCESS => {
+                self.buffer.replace(buffer);
+                Ok(())
+            }
+            result => Err((result, buffer)),
+        }
+    }
+
+    fn block_range(&self, block: u32) -> core::ops::Range<usize> {
+        let start = block as usize * self.block_size;
+        start..start + self.block_size
+    }
+}
+
+impl<'a> BlockStorage<'a> for RamDisk<'a> {
+    fn set_client(&self, client: &'a dyn BlockStorageClient) {
+        self.client.set(client);
+    }
+
+    fn geometry(&self) -> Option<Geometry> {
+        Some(Geometry {
+            block_size: self.block_size,
+            blocks: self.blocks,
+        })
+    }
+
+    fn read(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start_with_buffer(Operation::Read(block), buffer)
+    }
+
+    fn write(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        self.start_with_buffer(Operation::Write(block), buffer)
+    }
+
+    fn erase(&self, block: u32) -> ReturnCode {
+        self.start(Operation::Erase(block), self.block_size)
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for RamDisk<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        let block_size = self.block_size;
+        match self.operation.take() {
+            Some(Operation::Read(block)) => {
+                let range = self.block_range(block);
+                self.buffer.take().map(|buffer| {
+                    self.memory.map(|memory| {
+                        buffer[..block_size].copy_from_slice(&memory[range]);
+                    });
+                    self.client
+                        .map(move |client| client.read_done(buffer, ReturnCode::SUCCESS));
+                });
+            }
+            Some(Operation::Write(block)) => {
+                let range = self.block_range(block);
+                self.buffer.take().map(|buffer| {
+                    self.memory.map(|memory| {
+                        memory[range].copy_from_slice(&buffer[..block_size]);
+                    });
+                    self.client
+                        .map(move |client| client.write_done(buffer, ReturnCode::SUCCESS));
+                });
+            }
+            Some(Operation::Erase(block)) => {
+                let range = self.block_range(block);
+                self.memory.map(|memory| {
+                    for byte in memory[range].iter_mut() {
+                        *byte = 0xff;
+                    }
+                });
+                self.client
+                    .map(|client| client.erase_done(ReturnCode::SUCCESS));
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use kernel::common::dynamic_deferred_call::DynamicDeferredCallClientState;
+    use std::boxed::Box;
+
+    const TEST_BLOCK_SIZE: usize = 64;
+
+    struct TestClient {
+        buffer: TakeCell<'static, [u8]>,
+        result: Cell<Option<ReturnCode>>,
+    }
+
+    impl BlockStorageClient for TestClient {
+        fn read_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+            self.buffer.replace(buffer);
+            self.result.set(Some(result));
+        }
+
+        fn write_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+            self.buffer.replace(buffer);
+            self.result.set(Some(result));
+        }
+
+        fn erase_done(&self, result: ReturnCode) {
+            self.result.set(Some(result));
+        }
+    }
+
+    struct Harness {
+        disk: &'static RamDisk<'static>,
+        client: &'static TestClient,
+        handle: DeferredCallHandle,
+    }
+
+    impl Harness {
+        fn new(size: usize) -> Harness {
+            let client_states: &'static [DynamicDeferredCallClientState; 1] =
+                Box::leak(Box::new(Default::default()));
+            let deferred_caller = Box::leak(Box::new(DynamicDeferredCall::new(client_states)));
+            let memory: &'static mut [u8] = Box::leak(std::vec![0; size].into_boxed_slice());
+            let disk = Box::leak(Box::new(RamDisk::new(
+                memory,
+                TEST_BLOCK_SIZE,
+                deferred_caller,
+            )));
+            let client: &'static TestClient = Box::leak(Box::new(TestClient {
+                buffer: TakeCell::new(Box::leak(Box::new([0u8; TEST_BLOCK_SIZE]))),
+                result: Cell::new(None),
+            }));
+            disk.set_client(client);
+            let handle = deferred_caller.register(disk).unwrap();
+            disk.initialize_callback_handle(handle);
+            Harness {
+                disk: disk,
+                client: client,
+                handle: handle,
+            }
+        }
+
+        fn result(&self) -> Option<ReturnCode> {
+            self.disk.call(self.handle);
+            self.client.result.take()
+        }
+
+        fn write(&self, block: u32, fill: u8) -> Option<ReturnCode> {
+            let buffer = self.client.buffer.take().unwrap();
+            for byte in buffer.iter_mut() {
+                *byte = fill;
+            }
+            if let Err((result, buffer)) = self.disk.write(block, buffer) {
+                self.client.buffer.replace(buffer);
+                return Some(result);
+            }
+            self.result()
+        }
+
+        fn read(&self, block: u32) -> (Option<ReturnCode>, u8) {
+            let buffer = self.client.buffer.take().unwrap();
+            if let Err((result, buffer)) = self.disk.read(block, buffer) {
+                self.client.buffer.replace(buffer);
+                return (Some(result), 0);
+            }
+            let result = self.result();
+            let buffer = self.client.buffer.take().unwrap();
+            let first = buffer[0];
+            assert!(buffer.iter().all(|byte| *byte == first));
+            self.client.buffer.replace(buffer);
+            (result, first)
+        }
+    }
+
+    #[test]
+    fn blocks() {
+        // The partial block at the end is unused.
+        let harness = Harness::new(TEST_BLOCK_SIZE * 4 + 10);
+        assert_eq!(
+            harness.disk.geometry(),
+            Some(Geometry {
+                block_size: TEST_BLOCK_SIZE,
+                blocks: 4,
+            })
+        );
+
+        assert_eq!(harness.write(1, 0x11), Some(ReturnCode::SUCCESS));
+        assert_eq!(harness.write(3, 0x33), Some(ReturnCode::SUCCESS));
+        assert_eq!(harness.read(0), (Some(ReturnCode::SUCCESS), 0));
+        assert_eq!(harness.read(1), (Some(ReturnCode::SUCCESS), 0x11));
+        assert_eq!(harness.read(3), (Some(ReturnCode::SUCCESS), 0x33));
+        assert_eq!(harness.write(4, 0x44), Some(ReturnCode::EINVAL));
+        assert_eq!(harness.read(4).0, Some(ReturnCode::EINVAL));
+
+        assert_eq!(harness.disk.erase(1), ReturnCode::SUCCESS);
+        assert_eq!(harness.disk.erase(2), ReturnCode::EBUSY);
+        assert_eq!(harness.result(), Some(ReturnCode::SUCCESS));
+        assert_eq!(harness.read(1), (Some(ReturnCode::SUCCESS), 0xff));
+        assert_eq!(harness.read(3), (Some(ReturnCode::SUCCESS), 0x33));
+    }
+}
@@ -1,6 +1,8 @@
 //! Provides driver for accessing an SD Card and a userspace Driver.
 //!
-//! This allows initialization and block reads or writes on top of SPI.
+//! This allows initialization and block reads or writes on top of SPI. Once
+//! initialized, the card is also a `hil::block_storage::BlockStorage` device
+//! of 512-byte blocks.
 //!
 //! Usage
 //! -----
@@ -80,6 +82,18 @@ pub struct SDCard<'a, A: hil::time::Alarm<'a>> {
     client: OptionalCell<&'static dyn SDCardClient>,
     client_buffer: TakeCell<'static, [u8]>,
     client_offset: Cell<usize>,
+
+    block_client: OptionalCell<&'a dyn hil::block_storage::BlockStorageClient>,
+    /// The operation in progress for `block_client`, if any
+    block_operation: Cell<Option<BlockOperation>>,
+    /// Number of blocks on the initialized card
+    blocks: Cell<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockOperation {
+    Read,
+    Write,
 }
 
 /// SD card command codes
@@ -189,7 +203,9 @@ pub trait SDCardClient {
     fn init_done(&self, block_size: u32, total_size: u64);
     fn read_done(&self, data: &'static mut [u8], len: usize);
     fn write_done(&self, buffer: &'static mut [u8]);
-    fn error(&self, error: u32);
+    /// An operation failed. `buffer` is the buffer of the read or write that
+    /// failed, if any.
+    fn error(&self, error: u32, buffer: Option<&'static mut [u8]>);
 }
 
 /// Functions for initializing and accessing an SD card
@@ -241,6 +257,53 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
             client: OptionalCell::empty(),
             client_buffer: TakeCell::empty(),
             client_offset: Cell::new(0),
+            block_client: OptionalCell::empty(),
+            block_operation: Cell::new(None),
+            blocks: Cell::new(0),
+        }
+    }
+
+    /// Reports a finished read to the client that started it
+    fn read_complete(&self, buffer: &'static mut [u8], len: usize) {
+        if self.block_operation.take().is_some() {
+            self.block_client.map(move |client| {
+                client.read_done(buffer, ReturnCode::SUCCESS);
+            });
+        } else {
+            self.client.map(move |client| {
+                client.read_done(buffer, len);
+            });
+        }
+    }
+
+    /// Reports a finished write to the client that started it
+    fn write_complete(&self, buffer: &'static mut [u8]) {
+        if self.block_operation.take().is_some() {
+            self.block_client.map(move |client| {
+                client.write_done(buffer, ReturnCode::SUCCESS);
+            });
+        } else {
+            self.client.map(move |client| {
+                client.write_done(buffer);
+            });
+        }
+    }
+
+    /// Reports an error, returning the buffer of any read or write that failed
+    fn report_error(&self, error: ErrorCode) {
+        let buffer = self.client_buffer.take();
+        match (self.block_operation.take(), buffer) {
+            (Some(operation), Some(buffer)) => {
+                self.block_client.map(move |client| match operation {
+                    BlockOperation::Read => client.read_done(buffer, ReturnCode::FAIL),
+                    BlockOperation::Write => client.write_done(buffer, ReturnCode::FAIL),
+                });
+            }
+            (_, buffer) => {
+                self.client.map(move |client| {
+                    client.error(error as u32, buffer);
+                });
+            }
         }
     }
 
@@ -448,9 +511,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -519,9 +580,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -548,9 +607,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -616,9 +673,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -655,9 +710,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -678,9 +731,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -728,6 +779,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     // initialization complete
                     self.state.set(SpiState::Idle);
                     self.is_initialized.set(true);
+                    self.blocks.set((total_size / 512) as u32);
 
                     // perform callback
                     self.client.map(move |client| {
@@ -740,9 +792,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -767,9 +817,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
@@ -798,9 +846,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
@@ -824,9 +870,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                         // callback
                         let read_len = cmp::min(read_buffer.len(), cmp::min(buffer.len(), 512));
-                        self.client.map(move |client| {
-                            client.read_done(buffer, read_len);
-                        });
+                        self.read_complete(buffer, read_len);
                     });
                 });
             }
@@ -857,9 +901,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
@@ -908,9 +950,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
 
                     // read finished, perform callback
                     self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.read_done(buffer, self.client_offset.get());
-                        });
+                        self.read_complete(buffer, self.client_offset.get());
                     });
                 } else {
                     // error, send callback and quit
@@ -919,9 +959,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
@@ -972,9 +1010,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                         self.state.set(SpiState::Idle);
                         self.alarm_state.set(AlarmState::Idle);
                         self.alarm_count.set(0);
-                        self.client.map(move |client| {
-                            client.error(ErrorCode::WriteFailure as u32);
-                        });
+                        self.report_error(ErrorCode::WriteFailure);
                     }
                 } else {
                     // error, send callback and quit
@@ -983,9 +1019,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::WriteFailure as u32);
-                    });
+                    self.report_error(ErrorCode::WriteFailure);
                 }
             }
 
@@ -1007,9 +1041,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client.map(move |client| {
-                        client.error(ErrorCode::WriteFailure as u32);
-                    });
+                    self.report_error(ErrorCode::WriteFailure);
                 }
             }
 
@@ -1024,9 +1056,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
                     self.state.set(SpiState::Idle);
                     self.alarm_count.set(0);
                     self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.write_done(buffer);
-                        });
+                        self.write_complete(buffer);
                     });
                 } else {
                     // replace buffers
@@ -1060,9 +1090,7 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
             self.alarm_count.set(0);
-            self.client.map(move |client| {
-                client.error(ErrorCode::TimeoutFailure as u32);
-            });
+            self.report_error(ErrorCode::TimeoutFailure);
         } else {
             self.alarm_count.set(repeats + 1);
         }
@@ -1232,6 +1260,22 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
         }
     }
 
+    /// Whether a block can be read or written now, into a buffer of
+    /// `buffer_len` bytes
+    fn block_access(&self, block: u32, buffer_len: usize) -> ReturnCode {
+        if !self.is_installed() {
+            ReturnCode::EUNINSTALLED
+        } else if !self.is_initialized() {
+            ReturnCode::ERESERVE
+        } else if block >= self.blocks.get() || buffer_len < 512 {
+            ReturnCode::EINVAL
+        } else if self.txbuffer.is_none() || self.rxbuffer.is_none() {
+            ReturnCode::EBUSY
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
     pub fn read_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
         // only if initialized and installed
         if self.is_installed() {
@@ -1332,6 +1376,60 @@ impl<'a, A: hil::time::Alarm<'a>> SDCard<'a, A> {
     }
 }
 
+/// Single block access for capsules layered on the card, such as
+/// filesystems
+impl<'a, A: hil::time::Alarm<'a>> hil::block_storage::BlockStorage<'a> for SDCard<'a, A> {
+    fn set_client(&self, client: &'a dyn hil::block_storage::BlockStorageClient) {
+        self.block_client.set(client);
+    }
+
+    fn geometry(&self) -> Option<hil::block_storage::Geometry> {
+        if self.is_installed() && self.is_initialized() {
+            Some(hil::block_storage::Geometry {
+                block_size: 512,
+                blocks: self.blocks.get(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn read(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        match self.block_access(block, buffer.len()) {
+            ReturnCode::SUCCESS => {
+                self.block_operation.set(Some(BlockOperation::Read));
+                self.read_blocks(buffer, block, 1);
+                Ok(())
+            }
+            error => Err((error, buffer)),
+        }
+    }
+
+    fn write(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        match self.block_access(block, buffer.len()) {
+            ReturnCode::SUCCESS => {
+                self.block_operation.set(Some(BlockOperation::Write));
+                self.write_blocks(buffer, block, 1);
+                Ok(())
+            }
+            error => Err((error, buffer)),
+        }
+    }
+
+    fn erase(&self, _block: u32) -> ReturnCode {
+        // Writes don't need erases
+        ReturnCode::ENOSUPPORT
+    }
+}
+
 /// Handle callbacks from the SPI peripheral
 impl<'a, A: hil::time::Alarm<'a>> hil::spi::SpiMasterClient for SDCard<'a, A> {
     fn read_write_done(
@@ -1363,9 +1461,7 @@ impl<'a, A: hil::time::Alarm<'a>> hil::gpio::Client for SDCard<'a, A> {
             //  send an error callback
             self.state.set(SpiState::Idle);
             self.alarm_state.set(AlarmState::Idle);
-            self.client.map(move |client| {
-                client.error(ErrorCode::CardStateChanged as u32);
-            });
+            self.report_error(ErrorCode::CardStateChanged);
         }
 
         // either the card is new or gone, in either case it isn't initialized
@@ -1481,7 +1577,8 @@ impl<'a, A: hil::time::Alarm<'a>> SDCardClient for SDCardDriver<'a, A> {
         });
     }
 
-    fn error(&self, error: u32) {
+    fn error(&self, error: u32, buffer: Option<&'static mut [u8]>) {
+        buffer.map(|buffer| self.kernel_buf.replace(buffer));
         self.app.map(|app| {
             app.callback.map(|mut cb| {
                 cb.schedule(4, error as usize, 0);
@@ -0,0 +1,787 @@
+//! Software implementation of the SHA-2 hash functions (FIPS 180-4).
+//!
+//! `Sha256State` (SHA-224 and SHA-256), `Sha512State` (SHA-512 and
+//! SHA-512/256) and `HmacSha256State` (RFC 2104) compute digests
+//! synchronously in constant memory, so other capsules can use them directly.
+//!
+//! `Sha` implements `hil::digest::Digest` on top of them for boards without a
+//! hash engine. Data is hashed as soon as it is added, and the
+//! `add_data_done()` and `hash_done()` callbacks are delivered from a
+//! deferred call. `Sha` computes SHA-256 unless another mode is set: SHA-224
+//! digests fill the first 28 bytes of the digest buffer and the last 4 are
+//! zeroed. The mode and the HMAC key are kept across `run()` calls until
+//! `clear_data()` or a new mode is set, which overwrite the key pads.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let sha = static_init!(capsules::sha::Sha<'static>, capsules::sha::Sha::new(dynamic_deferred_caller));
+//! sha.initialize_callback_handle(
+//!     dynamic_deferred_caller.register(sha).expect("no deferred call slot available for sha"),
+//! );
+//! ```
+
+use core::sync::atomic::{self, Ordering};
+use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::digest;
+use kernel::ReturnCode;
+
+pub const SHA224_DIGEST_LEN: usize = 28;
+pub const SHA256_DIGEST_LEN: usize = 32;
+pub const SHA512_DIGEST_LEN: usize = 64;
+
+const SHA256_BLOCK_LEN: usize = 64;
+const SHA512_BLOCK_LEN: usize = 128;
+
+const SHA224_H: [u32; 8] = [
+    0xc1059ed8, 0x367cd507, 0x3070dd17, 0xf70e5939, 0xffc00b31, 0x68581511, 0x64f98fa7, 0xbefa4fa4,
+];
+
+const SHA256_H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const SHA512_H: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SHA512_256_H: [u64; 8] = [
+    0x22312194fc2bf72c,
+    0x9f555fa3c84c64c2,
+    0x2393b86b6f53b151,
+    0x963877195940eabd,
+    0x96283ee2a88effe3,
+    0xbe5e1e2553863992,
+    0x2b0199fc2c85b8aa,
+    0x0eb72ddc81c52ca2,
+];
+
+const SHA512_K: [u64; 80] = [
+    0x428a2f98d728ae22,
+    0x7137449123ef65cd,
+    0xb5c0fbcfec4d3b2f,
+    0xe9b5dba58189dbbc,
+    0x3956c25bf348b538,
+    0x59f111f1b605d019,
+    0x923f82a4af194f9b,
+    0xab1c5ed5da6d8118,
+    0xd807aa98a3030242,
+    0x12835b0145706fbe,
+    0x243185be4ee4b28c,
+    0x550c7dc3d5ffb4e2,
+    0x72be5d74f27b896f,
+    0x80deb1fe3b1696b1,
+    0x9bdc06a725c71235,
+    0xc19bf174cf692694,
+    0xe49b69c19ef14ad2,
+    0xefbe4786384f25e3,
+    0x0fc19dc68b8cd5b5,
+    0x240ca1cc77ac9c65,
+    0x2de92c6f592b0275,
+    0x4a7484aa6ea6e483,
+    0x5cb0a9dcbd41fbd4,
+    0x76f988da831153b5,
+    0x983e5152ee66dfab,
+    0xa831c66d2db43210,
+    0xb00327c898fb213f,
+    0xbf597fc7beef0ee4,
+    0xc6e00bf33da88fc2,
+    0xd5a79147930aa725,
+    0x06ca6351e003826f,
+    0x142929670a0e6e70,
+    0x27b70a8546d22ffc,
+    0x2e1b21385c26c926,
+    0x4d2c6dfc5ac42aed,
+    0x53380d139d95b3df,
+    0x650a73548baf63de,
+    0x766a0abb3c77b2a8,
+    0x81c2c92e47edaee6,
+    0x92722c851482353b,
+    0xa2bfe8a14cf10364,
+    0xa81a664bbc423001,
+    0xc24b8b70d0f89791,
+    0xc76c51a30654be30,
+    0xd192e819d6ef5218,
+    0xd69906245565a910,
+    0xf40e35855771202a,
+    0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8,
+    0x1e376c085141ab53,
+    0x2748774cdf8eeb99,
+    0x34b0bcb5e19b48a8,
+    0x391c0cb3c5c95a63,
+    0x4ed8aa4ae3418acb,
+    0x5b9cca4f7763e373,
+    0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc,
+    0x78a5636f43172f60,
+    0x84c87814a1f0ab72,
+    0x8cc702081a6439ec,
+    0x90befffa23631e28,
+    0xa4506cebde82bde9,
+    0xbef9a3f7b2c67915,
+    0xc67178f2e372532b,
+    0xca273eceea26619c,
+    0xd186b8c721c0c207,
+    0xeada7dd6cde0eb1e,
+    0xf57d4f7fee6ed178,
+    0x06f067aa72176fba,
+    0x0a637dc5a2c898a6,
+    0x113f9804bef90dae,
+    0x1b710b35131c471b,
+    0x28db77f523047d84,
+    0x32caab7b40c72493,
+    0x3c9ebe0a15c9bebc,
+    0x431d67c49c100d4c,
+    0x4cc5d4becb3e42b6,
+    0x597f299cfc657e2a,
+    0x5fcb6fab3ad6faec,
+    0x6c44198c4a475817,
+];
+
+/// SHA-224 or SHA-256 computation.
+#[derive(Copy, Clone)]
+pub struct Sha256State {
+    h: [u32; 8],
+    block: [u8; SHA256_BLOCK_LEN],
+    block_len: usize,
+    total_len: u64,
+    digest_len: usize,
+}
+
+impl Sha256State {
+    pub fn new_sha256() -> Sha256State {
+        Sha256State::new(SHA256_H, SHA256_DIGEST_LEN)
+    }
+
+    pub fn new_sha224() -> Sha256State {
+        Sha256State::new(SHA224_H, SHA224_DIGEST_LEN)
+    }
+
+    fn new(h: [u32; 8], digest_len: usize) -> Sha256State {
+        Sha256State {
+            h: h,
+            block: [0; SHA256_BLOCK_LEN],
+            block_len: 0,
+            total_len: 0,
+            digest_len: digest_len,
+        }
+    }
+
+    /// Length of the digest in bytes.
+    pub fn digest_len(&self) -> usize {
+        self.digest_len
+    }
+
+    /// Overwrites the hash state and any buffered data with zeros, for
+    /// states derived from secrets. The state cannot be used afterwards.
+    pub fn clear(&mut self) {
+        self.h = [0; 8];
+        self.block = [0; SHA256_BLOCK_LEN];
+        self.block_len = 0;
+        self.total_len = 0;
+        // Keep the zeroing from being optimized away
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len = self.total_len.wrapping_add(data.len() as u64);
+        while !data.is_empty() {
+            let n = core::cmp::min(SHA256_BLOCK_LEN - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+            if self.block_len == SHA256_BLOCK_LEN {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Writes the digest to the first `digest_len()` bytes of `digest`, which
+    /// must be long enough.
+    pub fn finish(mut self, digest: &mut [u8]) {
+        self.pad_and_digest(digest);
+    }
+
+    // Pads the message and writes the digest, leaving the state in place so
+    // that it can be cleared afterwards.
+    fn pad_and_digest(&mut self, digest: &mut [u8]) {
+        let bit_len = self.total_len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block_len != SHA256_BLOCK_LEN - 8 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_be_bytes());
+        for (chunk, word) in digest[..self.digest_len].chunks_mut(4).zip(self.h.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes()[..chunk.len()]);
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, chunk) in self.block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut v = self.h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [
+                t1.wrapping_add(t2),
+                v[0],
+                v[1],
+                v[2],
+                v[3].wrapping_add(t1),
+                v[4],
+                v[5],
+                v[6],
+            ];
+        }
+        for (h, v) in self.h.iter_mut().zip(v.iter()) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+}
+
+/// SHA-512 or SHA-512/256 computation.
+#[derive(Copy, Clone)]
+pub struct Sha512State {
+    h: [u64; 8],
+    block: [u8; SHA512_BLOCK_LEN],
+    block_len: usize,
+    total_len: u64,
+    digest_len: usize,
+}
+
+impl Sha512State {
+    pub fn new_sha512() -> Sha512State {
+        Sha512State::new(SHA512_H, SHA512_DIGEST_LEN)
+    }
+
+    pub fn new_sha512_256() -> Sha512State {
+        Sha512State::new(SHA512_256_H, SHA256_DIGEST_LEN)
+    }
+
+    fn new(h: [u64; 8], digest_len: usize) -> Sha512State {
+        Sha512State {
+            h: h,
+            block: [0; SHA512_BLOCK_LEN],
+            block_len: 0,
+            total_len: 0,
+            digest_len: digest_len,
+        }
+    }
+
+    /// Length of the digest in bytes.
+    pub fn digest_len(&self) -> usize {
+        self.digest_len
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len = self.total_len.wrapping_add(data.len() as u64);
+        while !data.is_empty() {
+            let n = core::cmp::min(SHA512_BLOCK_LEN - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + n].copy_from_slice(&data[..n]);
+            self.block_len += n;
+            data = &data[n..];
+            if self.block_len == SHA512_BLOCK_LEN {
+                self.compress();
+                self.block_len = 0;
+            }
+        }
+    }
+
+    /// Writes the digest to the first `digest_len()` bytes of `digest`, which
+    /// must be long enough.
+    pub fn finish(mut self, digest: &mut [u8]) {
+        // The message length is a 128-bit number of bits
+        let bit_len_high = self.total_len >> 61;
+        let bit_len_low = self.total_len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.block_len != SHA512_BLOCK_LEN - 16 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len_high.to_be_bytes());
+        self.update(&bit_len_low.to_be_bytes());
+        for (chunk, word) in digest[..self.digest_len].chunks_mut(8).zip(self.h.iter()) {
+            chunk.copy_from_slice(&word.to_be_bytes()[..chunk.len()]);
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u64; 80];
+        for (i, chunk) in self.block.chunks(8).enumerate() {
+            let mut word = [0; 8];
+            word.copy_from_slice(chunk);
+            w[i] = u64::from_be_bytes(word);
+        }
+        for i in 16..80 {
+            let s0 = w[i - 15].rotate_right(1) ^ w[i - 15].rotate_right(8) ^ (w[i - 15] >> 7);
+            let s1 = w[i - 2].rotate_right(19) ^ w[i - 2].rotate_right(61) ^ (w[i - 2] >> 6);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut v = self.h;
+        for i in 0..80 {
+            let s1 = v[4].rotate_right(14) ^ v[4].rotate_right(18) ^ v[4].rotate_right(41);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA512_K[i])
+                .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(28) ^ v[0].rotate_right(34) ^ v[0].rotate_right(39);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [
+                t1.wrapping_add(t2),
+                v[0],
+                v[1],
+                v[2],
+                v[3].wrapping_add(t1),
+                v[4],
+                v[5],
+                v[6],
+            ];
+        }
+        for (h, v) in self.h.iter_mut().zip(v.iter()) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+}
+
+/// HMAC-SHA256 computation.
+///
+/// This holds the key, so it is not `Copy`. Its key and inner state are
+/// overwritten once the MAC is finished.
+#[derive(Clone)]
+pub struct HmacSha256State {
+    inner: Sha256State,
+    key: [u8; SHA256_BLOCK_LEN],
+}
+
+impl HmacSha256State {
+    pub fn new(key: &[u8; SHA256_DIGEST_LEN]) -> HmacSha256State {
+        HmacSha256State::with_key(key)
+    }
+
+    /// Starts a MAC with a key of any length. Keys longer than the block
+    /// length are hashed first, shorter ones are padded with zeros.
+    pub fn with_key(key: &[u8]) -> HmacSha256State {
+        let mut state = HmacSha256State {
+            inner: Sha256State::new_sha256(),
+            key: [0; SHA256_BLOCK_LEN],
+        };
+        if key.len() > SHA256_BLOCK_LEN {
+            let mut hash = Sha256State::new_sha256();
+            hash.update(key);
+            hash.pad_and_digest(&mut state.key);
+            hash.clear();
+        } else {
+            state.key[..key.len()].copy_from_slice(key);
+        }
+        let mut block = HmacSha256State::pad(&state.key, 0x36);
+        state.inner.update(&block);
+        zero(&mut block);
+        state
+    }
+
+    // The key block XORed with `pad`.
+    fn pad(key: &[u8; SHA256_BLOCK_LEN], pad: u8) -> [u8; SHA256_BLOCK_LEN] {
+        let mut block = [pad; SHA256_BLOCK_LEN];
+        for (b, k) in block.iter_mut().zip(key.iter()) {
+            *b ^= k;
+        }
+        block
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    /// Writes the MAC to the first 32 bytes of `mac`.
+    pub fn finish(mut self, mac: &mut [u8]) {
+        let mut inner_digest = [0; SHA256_DIGEST_LEN];
+        self.inner.pad_and_digest(&mut inner_digest);
+        let mut block = HmacSha256State::pad(&self.key, 0x5c);
+        let mut outer = Sha256State::new_sha256();
+        outer.update(&block);
+        outer.update(&inner_digest);
+        outer.pad_and_digest(mac);
+        outer.clear();
+        zero(&mut block);
+        zero(&mut inner_digest);
+        self.clear();
+    }
+
+    /// Overwrites the key and the inner hash state, which is derived from
+    /// the key, with zeros. The state cannot be used afterwards.
+    fn clear(&mut self) {
+        self.key = [0; SHA256_BLOCK_LEN];
+        self.inner.clear();
+    }
+}
+
+// Overwrites a buffer that held key material with zeros.
+fn zero(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        *b = 0;
+    }
+    // Keep the zeroing from being optimized away
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+#[derive(Clone)]
+enum Hasher {
+    Sha256(Sha256State),
+    Sha512(Sha512State),
+    HmacSha256(HmacSha256State),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(state) => state.update(data),
+            Hasher::Sha512(state) => state.update(data),
+            Hasher::HmacSha256(state) => state.update(data),
+        }
+    }
+
+    fn finish(self, digest: &mut [u8]) {
+        match self {
+            Hasher::Sha256(state) => state.finish(digest),
+            Hasher::Sha512(state) => state.finish(digest),
+            Hasher::HmacSha256(state) => state.finish(digest),
+        }
+    }
+
+    /// Overwrites any key material.
+    fn clear(&mut self) {
+        if let Hasher::HmacSha256(state) = self {
+            state.clear();
+        }
+    }
+}
+
+/// `hil::digest::Digest` implementation that hashes in software.
+pub struct Sha<'a> {
+    client: OptionalCell<&'a dyn digest::Client<'a, [u8; 32]>>,
+    /// The hash of the data added since the last `run()`, which also holds
+    /// the mode and the HMAC key.
+    hasher: MapCell<Hasher>,
+    /// The initial state of `hasher` in the current mode.
+    initial: MapCell<Hasher>,
+    data: TakeCell<'static, [u8]>,
+    digest: TakeCell<'static, [u8; 32]>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> Sha<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> Sha<'a> {
+        let initial = Hasher::Sha256(Sha256State::new_sha256());
+        Sha {
+            client: OptionalCell::empty(),
+            hasher: MapCell::new(initial.clone()),
+            initial: MapCell::new(initial),
+            data: TakeCell::empty(),
+            digest: TakeCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    /// Initializes a callback handle for deferred callbacks.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn set_mode(&self, initial: Hasher) -> Result<(), ReturnCode> {
+        if self.data.is_some() || self.digest.is_some() {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.reset(initial);
+        Ok(())
+    }
+
+    /// Starts over in the mode `initial`, overwriting the key of the
+    /// current mode.
+    fn reset(&self, initial: Hasher) {
+        self.hasher.map(Hasher::clear);
+        self.initial.map(Hasher::clear);
+        self.hasher.replace(initial.clone());
+        self.initial.replace(initial);
+    }
+}
+
+impl<'a> digest::Digest<'a, [u8; 32]> for Sha<'a> {
+    fn set_client(&'a self, client: &'a dyn digest::Client<'a, [u8; 32]>) {
+        self.client.set(client);
+    }
+
+    fn add_data(
+        &self,
+        data: LeasableBuffer<'static, u8>,
+    ) -> Result<usize, (ReturnCode, &'static mut [u8])> {
+        if self.data.is_some() {
+            return Err((ReturnCode::EBUSY, data.take()));
+        }
+        let len = data.len();
+        self.hasher.map(|hasher| hasher.update(&data[..]));
+        self.data.replace(data.take());
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(len)
+    }
+
+    fn run(
+        &'a self,
+        digest: &'static mut [u8; 32],
+    ) -> Result<(), (ReturnCode, &'static mut [u8; 32])> {
+        if self.digest.is_some() {
+            return Err((ReturnCode::EBUSY, digest));
+        }
+        *digest = [0; 32];
+        let initial = self.initial.map(|initial| initial.clone());
+        initial
+            .and_then(|initial| self.hasher.replace(initial))
+            .map(|hasher| hasher.finish(digest));
+        self.digest.replace(digest);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
+    }
+
+    fn clear_data(&self) {
+        self.reset(Hasher::Sha256(Sha256State::new_sha256()));
+    }
+}
+
+impl digest::Sha224 for Sha<'_> {
+    fn set_mode_sha224(&self) -> Result<(), ReturnCode> {
+        self.set_mode(Hasher::Sha256(Sha256State::new_sha224()))
+    }
+}
+
+impl digest::Sha256 for Sha<'_> {
+    fn set_mode_sha256(&self) -> Result<(), ReturnCode> {
+        self.set_mode(Hasher::Sha256(Sha256State::new_sha256()))
+    }
+}
+
+impl digest::Sha512Trunc256 for Sha<'_> {
+    fn set_mode_sha512_256(&self) -> Result<(), ReturnCode> {
+        self.set_mode(Hasher::Sha512(Sha512State::new_sha512_256()))
+    }
+}
+
+impl digest::HMACSha256 for Sha<'_> {
+    fn set_mode_hmacsha256(&self, key: &[u8; 32]) -> Result<(), ReturnCode> {
+        self.set_mode(Hasher::HmacSha256(HmacSha256State::new(key)))
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for Sha<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        self.data.take().map(|data| {
+            self.client
+                .map(move |client| client.add_data_done(Ok(()), data));
+        });
+        self.digest.take().map(|digest| {
+            self.client
+                .map(move |client| client.hash_done(Ok(()), digest));
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(digest: &[u8]) -> [u8; 128] {
+        let mut out = [b' '; 128];
+        for (i, b) in digest.iter().enumerate() {
+            out[2 * i] = b"0123456789abcdef"[(b >> 4) as usize];
+            out[2 * i + 1] = b"0123456789abcdef"[(b & 0xf) as usize];
+        }
+        out
+    }
+
+    fn check(digest: &[u8], expected: &str) {
+        assert_eq!(&hex(digest)[..2 * digest.len()], expected.as_bytes());
+    }
+
+    const ABC: &[u8] = b"abc";
+    const TWO_BLOCKS_256: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+    const TWO_BLOCKS_512: &[u8] = b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmn\
+                                    hijklmnoijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu";
+
+    fn sha256(state: Sha256State, data: &[u8]) -> [u8; 32] {
+        let mut state = state;
+        let mut digest = [0; 32];
+        // Feed the data in uneven pieces
+        for chunk in data.chunks(7) {
+            state.update(chunk);
+        }
+        let len = state.digest_len();
+        state.finish(&mut digest[..len]);
+        digest
+    }
+
+    fn sha512(state: Sha512State, data: &[u8]) -> [u8; 64] {
+        let mut state = state;
+        let mut digest = [0; 64];
+        for chunk in data.chunks(13) {
+            state.update(chunk);
+        }
+        let len = state.digest_len();
+        state.finish(&mut digest[..len]);
+        digest
+    }
+
+    #[test]
+    fn sha256_nist_vectors() {
+        check(
+            &sha256(Sha256State::new_sha256(), b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        );
+        check(
+            &sha256(Sha256State::new_sha256(), ABC),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+        );
+        check(
+            &sha256(Sha256State::new_sha256(), TWO_BLOCKS_256),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+        );
+        check(
+            &sha256(Sha256State::new_sha224(), ABC)[..SHA224_DIGEST_LEN],
+            "23097d223405d8228642a477bda255b32aadbce4bda0b3f7e36c9da7",
+        );
+        check(
+            &sha256(Sha256State::new_sha224(), TWO_BLOCKS_256)[..SHA224_DIGEST_LEN],
+            "75388b16512776cc5dba5da1fd890150b0c6455cb4f58b1952522525",
+        );
+
+        let mut state = Sha256State::new_sha256();
+        for _ in 0..1000 {
+            state.update(&[b'a'; 1000]);
+        }
+        let mut digest = [0; 32];
+        state.finish(&mut digest);
+        check(
+            &digest,
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0",
+        );
+    }
+
+    #[test]
+    fn sha512_nist_vectors() {
+        check(
+            &sha512(Sha512State::new_sha512(), ABC),
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+        );
+        check(
+            &sha512(Sha512State::new_sha512(), TWO_BLOCKS_512),
+            "8e959b75dae313da8cf4f72814fc143f8f7779c6eb9f7fa17299aeadb6889018\
+             501d289e4900f7e4331b99dec4b5433ac7d329eeb6dd26545e96e55b874be909",
+        );
+        check(
+            &sha512(Sha512State::new_sha512_256(), ABC)[..SHA256_DIGEST_LEN],
+            "53048e2681941ef99b2e29b76b4c7dabe4c2d0c634fc6d46e0e2f13107e7af23",
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_rfc4231() {
+        // Test cases 1 and 2, with the keys padded with zeros
+        let mut key = [0; 32];
+        key[..20].copy_from_slice(&[0x0b; 20]);
+        let mut hmac = HmacSha256State::new(&key);
+        hmac.update(b"Hi There");
+        let mut mac = [0; 32];
+        hmac.finish(&mut mac);
+        check(
+            &mac,
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+        );
+
+        let mut key = [0; 32];
+        key[..4].copy_from_slice(b"Jefe");
+        let mut hmac = HmacSha256State::new(&key);
+        hmac.update(b"what do ya want ");
+        hmac.update(b"for nothing?");
+        hmac.finish(&mut mac);
+        check(
+            &mac,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        );
+    }
+
+    #[test]
+    fn clearing_hmac_overwrites_the_key() {
+        let mut hasher = Hasher::HmacSha256(HmacSha256State::new(&[0x0b; 32]));
+        hasher.update(b"Hi There");
+        hasher.clear();
+        match hasher {
+            Hasher::HmacSha256(state) => {
+                assert_eq!(&state.key[..], &[0; 64][..]);
+                assert_eq!(state.inner.h, [0; 8]);
+                assert_eq!(&state.inner.block[..], &[0; 64][..]);
+            }
+            _ => panic!("mode changed"),
+        }
+    }
+
+    #[test]
+    fn clearing_sha256_overwrites_the_state() {
+        let mut state = Sha256State::new_sha256();
+        state.update(&[0x5c; 100]);
+        state.clear();
+        assert_eq!(state.h, [0; 8]);
+        assert_eq!(&state.block[..], &[0; 64][..]);
+        assert_eq!((state.block_len, state.total_len), (0, 0));
+    }
+}
@@ -0,0 +1,241 @@
+//! Arithmetic on 256-bit integers modulo an odd number.
+//!
+//! Integers are arrays of eight 32-bit limbs, least significant first.
+//! `Modulus` multiplies in the Montgomery domain: values are kept as
+//! `a * 2^256 mod m`, converted in with `to_mont()` and out with
+//! `from_mont()`.
+//!
+//! Nothing here runs in constant time. It is only used to verify
+//! signatures, which involves public data only.
+
+use core::cmp::Ordering;
+
+pub type U256 = [u32; 8];
+
+pub const ZERO: U256 = [0; 8];
+pub const ONE: U256 = [1, 0, 0, 0, 0, 0, 0, 0];
+
+/// Reads a big endian integer of up to 32 bytes.
+pub fn from_be_bytes(bytes: &[u8]) -> U256 {
+    let mut a = ZERO;
+    for (i, byte) in bytes.iter().rev().take(32).enumerate() {
+        a[i / 4] |= (*byte as u32) << (8 * (i % 4));
+    }
+    a
+}
+
+/// Reads a little endian integer of up to 32 bytes.
+pub fn from_le_bytes(bytes: &[u8]) -> U256 {
+    let mut a = ZERO;
+    for (i, byte) in bytes.iter().take(32).enumerate() {
+        a[i / 4] |= (*byte as u32) << (8 * (i % 4));
+    }
+    a
+}
+
+/// Writes `a` to the 32 bytes of `bytes`, little endian.
+pub fn to_le_bytes(a: &U256, bytes: &mut [u8]) {
+    for (i, byte) in bytes[..32].iter_mut().enumerate() {
+        *byte = (a[i / 4] >> (8 * (i % 4))) as u8;
+    }
+}
+
+pub fn cmp(a: &U256, b: &U256) -> Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+pub fn is_zero(a: &U256) -> bool {
+    a.iter().all(|limb| *limb == 0)
+}
+
+/// Returns bit `i` of `a`.
+pub fn bit(a: &U256, i: usize) -> bool {
+    (a[i / 32] >> (i % 32)) & 1 == 1
+}
+
+/// Shifts `a` right by fewer than 32 bits.
+pub fn shr(a: &U256, n: u32) -> U256 {
+    let mut r = ZERO;
+    for i in 0..8 {
+        r[i] = a[i] >> n;
+        if n > 0 && i < 7 {
+            r[i] |= a[i + 1] << (32 - n);
+        }
+    }
+    r
+}
+
+fn add(a: &U256, b: &U256) -> (U256, bool) {
+    let mut r = ZERO;
+    let mut carry = 0u64;
+    for i in 0..8 {
+        let s = a[i] as u64 + b[i] as u64 + carry;
+        r[i] = s as u32;
+        carry = s >> 32;
+    }
+    (r, carry != 0)
+}
+
+/// Returns `a - b` and whether it borrowed.
+pub fn sub(a: &U256, b: &U256) -> (U256, bool) {
+    let mut r = ZERO;
+    let mut borrow = 0i64;
+    for i in 0..8 {
+        let d = a[i] as i64 - b[i] as i64 + borrow;
+        r[i] = d as u32;
+        borrow = d >> 32;
+    }
+    (r, borrow != 0)
+}
+
+/// An odd modulus.
+pub struct Modulus {
+    m: U256,
+    /// `-m^-1 mod 2^32`
+    m_prime: u32,
+    /// `2^256 mod m`, 1 in the Montgomery domain
+    r: U256,
+    /// `2^512 mod m`
+    r2: U256,
+}
+
+impl Modulus {
+    pub fn new(m: U256) -> Modulus {
+        // Newton's iteration doubles the number of correct low bits of the
+        // inverse each step
+        let mut inv = 1u32;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(m[0].wrapping_mul(inv)));
+        }
+        let mut modulus = Modulus {
+            m: m,
+            m_prime: inv.wrapping_neg(),
+            r: ZERO,
+            r2: ZERO,
+        };
+        let mut x = modulus.reduce_small(&ONE);
+        for i in 0..512 {
+            if i == 256 {
+                modulus.r = x;
+            }
+            x = modulus.add(&x, &x);
+        }
+        modulus.r2 = x;
+        modulus
+    }
+
+    // Reduces `a < 2m`.
+    fn reduce_small(&self, a: &U256) -> U256 {
+        match sub(a, &self.m) {
+            (r, false) => r,
+            (_, true) => *a,
+        }
+    }
+
+    pub fn modulus(&self) -> &U256 {
+        &self.m
+    }
+
+    /// Returns `a mod m` for any `a`.
+    pub fn reduce(&self, a: &U256) -> U256 {
+        self.from_mont(&self.to_mont(a))
+    }
+
+    /// Returns `(hi * 2^256 + lo) mod m`.
+    pub fn reduce_wide(&self, lo: &U256, hi: &U256) -> U256 {
+        // mont(hi, 2^512) = hi * 2^256 mod m
+        self.add(&self.mul(hi, &self.r2), &self.reduce(lo))
+    }
+
+    /// Converts any `a` to the Montgomery domain.
+    pub fn to_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &self.r2)
+    }
+
+    pub fn from_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &ONE)
+    }
+
+    /// 1 in the Montgomery domain.
+    pub fn one(&self) -> U256 {
+        self.r
+    }
+
+    /// Adds two reduced numbers.
+    pub fn add(&self, a: &U256, b: &U256) -> U256 {
+        match add(a, b) {
+            (r, true) => sub(&r, &self.m).0,
+            (r, false) => self.reduce_small(&r),
+        }
+    }
+
+    /// Subtracts two reduced numbers.
+    pub fn sub(&self, a: &U256, b: &U256) -> U256 {
+        match sub(a, b) {
+            (r, true) => add(&r, &self.m).0,
+            (r, false) => r,
+        }
+    }
+
+    pub fn neg(&self, a: &U256) -> U256 {
+        self.sub(&ZERO, a)
+    }
+
+    /// Montgomery multiplication: `a * b / 2^256 mod m`. The product `a * b`
+    /// must be less than `m * 2^256`, which holds if either is reduced.
+    pub fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let m = &self.m;
+        let mut t = [0u32; 10];
+        for i in 0..8 {
+            let mut c = 0u64;
+            for j in 0..8 {
+                let s = t[j] as u64 + a[j] as u64 * b[i] as u64 + c;
+                t[j] = s as u32;
+                c = s >> 32;
+            }
+            let s = t[8] as u64 + c;
+            t[8] = s as u32;
+            t[9] = (s >> 32) as u32;
+
+            let q = t[0].wrapping_mul(self.m_prime) as u64;
+            let mut c = (t[0] as u64 + q * m[0] as u64) >> 32;
+            for j in 1..8 {
+                let s = t[j] as u64 + q * m[j] as u64 + c;
+                t[j - 1] = s as u32;
+                c = s >> 32;
+            }
+            let s = t[8] as u64 + c;
+            t[7] = s as u32;
+            t[8] = t[9] + (s >> 32) as u32;
+        }
+        let mut r = ZERO;
+        r.copy_from_slice(&t[..8]);
+        if t[8] != 0 {
+            sub(&r, m).0
+        } else {
+            self.reduce_small(&r)
+        }
+    }
+
+    pub fn square(&self, a: &U256) -> U256 {
+        self.mul(a, a)
+    }
+
+    /// Raises `a`, in the Montgomery domain, to the power `e`.
+    pub fn pow(&self, a: &U256, e: &U256) -> U256 {
+        let mut r = self.one();
+        for i in (0..256).rev() {
+            r = self.square(&r);
+            if bit(e, i) {
+                r = self.mul(&r, a);
+            }
+        }
+        r
+    }
+
+    /// Inverts `a`, in the Montgomery domain. The modulus must be prime.
+    pub fn inv(&self, a: &U256) -> U256 {
+        let e = sub(&self.m, &[2, 0, 0, 0, 0, 0, 0, 0]).0;
+        self.pow(a, &e)
+    }
+}
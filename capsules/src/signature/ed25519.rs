@@ -0,0 +1,275 @@
+//! Ed25519 signature verification (RFC 8032, section 5.1.7).
+
+use crate::sha::Sha512State;
+use crate::signature::bignum::{self, Modulus, U256};
+use core::cmp::Ordering;
+
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// 2^255 - 19, big endian
+const P: [u8; 32] = [
+    0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xed,
+];
+
+/// The order of the base point, 2^252 + 27742317777372353535851937790883648493,
+/// big endian
+const L: [u8; 32] = [
+    0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x14, 0xde, 0xf9, 0xde, 0xa2, 0xf7, 0x9c, 0xd6, 0x58, 0x12, 0x63, 0x1a, 0x5c, 0xf5, 0xd3, 0xed,
+];
+
+/// Encoding of the base point, whose y coordinate is 4/5
+const BASE: [u8; 32] = [
+    0x58, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+    0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+];
+
+/// A point in extended coordinates (x = X/Z, y = Y/Z, xy = T/Z), in the
+/// Montgomery domain.
+#[derive(Copy, Clone)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+    t: U256,
+}
+
+struct Curve {
+    p: Modulus,
+    d: U256,
+    d2: U256,
+    sqrt_m1: U256,
+}
+
+impl Curve {
+    fn new() -> Curve {
+        let p = Modulus::new(bignum::from_be_bytes(&P));
+        // d = -121665 / 121666
+        let d = p.neg(&p.mul(
+            &p.to_mont(&[121665, 0, 0, 0, 0, 0, 0, 0]),
+            &p.inv(&p.to_mont(&[121666, 0, 0, 0, 0, 0, 0, 0])),
+        ));
+        let d2 = p.add(&d, &d);
+        // sqrt(-1) = 2^((p - 1) / 4)
+        let e = bignum::shr(&bignum::sub(p.modulus(), &bignum::ONE).0, 2);
+        let sqrt_m1 = p.pow(&p.to_mont(&[2, 0, 0, 0, 0, 0, 0, 0]), &e);
+        Curve {
+            p: p,
+            d: d,
+            d2: d2,
+            sqrt_m1: sqrt_m1,
+        }
+    }
+
+    fn identity(&self) -> Point {
+        Point {
+            x: bignum::ZERO,
+            y: self.p.one(),
+            z: self.p.one(),
+            t: bignum::ZERO,
+        }
+    }
+
+    // Decodes a point (section 5.1.3).
+    fn decode(&self, bytes: &[u8; 32]) -> Option<Point> {
+        let p = &self.p;
+        let sign = bytes[31] >> 7;
+        let mut y = bignum::from_le_bytes(bytes);
+        y[7] &= 0x7fff_ffff;
+        if bignum::cmp(&y, p.modulus()) != Ordering::Less {
+            return None;
+        }
+        let y = p.to_mont(&y);
+
+        // x^2 = u / v
+        let y2 = p.square(&y);
+        let u = p.sub(&y2, &p.one());
+        let v = p.add(&p.mul(&self.d, &y2), &p.one());
+        // x = u v^3 (u v^7)^((p - 5) / 8)
+        let v3 = p.mul(&p.square(&v), &v);
+        let v7 = p.mul(&p.square(&v3), &v);
+        let e = bignum::shr(&bignum::sub(p.modulus(), &[5, 0, 0, 0, 0, 0, 0, 0]).0, 3);
+        let mut x = p.mul(&p.mul(&u, &v3), &p.pow(&p.mul(&u, &v7), &e));
+        let vx2 = p.mul(&v, &p.square(&x));
+        if vx2 == p.neg(&u) {
+            x = p.mul(&x, &self.sqrt_m1);
+        } else if vx2 != u {
+            return None;
+        }
+
+        let x_normal = p.from_mont(&x);
+        if bignum::is_zero(&x_normal) && sign == 1 {
+            return None;
+        }
+        if (x_normal[0] & 1) as u8 != sign {
+            x = p.neg(&x);
+        }
+        Some(Point {
+            x: x,
+            y: y,
+            z: p.one(),
+            t: p.mul(&x, &y),
+        })
+    }
+
+    fn encode(&self, point: &Point) -> [u8; 32] {
+        let p = &self.p;
+        let z_inv = p.inv(&point.z);
+        let x = p.from_mont(&p.mul(&point.x, &z_inv));
+        let y = p.from_mont(&p.mul(&point.y, &z_inv));
+        let mut bytes = [0; 32];
+        bignum::to_le_bytes(&y, &mut bytes);
+        bytes[31] |= ((x[0] & 1) as u8) << 7;
+        bytes
+    }
+
+    fn neg(&self, point: &Point) -> Point {
+        Point {
+            x: self.p.neg(&point.x),
+            y: point.y,
+            z: point.z,
+            t: self.p.neg(&point.t),
+        }
+    }
+
+    // Unified addition, which also doubles (add-2008-hwcd-3).
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        let p = &self.p;
+        let aa = p.mul(&p.sub(&a.y, &a.x), &p.sub(&b.y, &b.x));
+        let bb = p.mul(&p.add(&a.y, &a.x), &p.add(&b.y, &b.x));
+        let cc = p.mul(&p.mul(&a.t, &self.d2), &b.t);
+        let zz = p.mul(&a.z, &b.z);
+        let dd = p.add(&zz, &zz);
+        let e = p.sub(&bb, &aa);
+        let f = p.sub(&dd, &cc);
+        let g = p.add(&dd, &cc);
+        let h = p.add(&bb, &aa);
+        Point {
+            x: p.mul(&e, &f),
+            y: p.mul(&g, &h),
+            z: p.mul(&f, &g),
+            t: p.mul(&e, &h),
+        }
+    }
+}
+
+/// Returns whether `public_key` encodes a point on the curve.
+pub fn public_key_valid(public_key: &[u8; PUBLIC_KEY_LEN]) -> bool {
+    Curve::new().decode(public_key).is_some()
+}
+
+/// Returns whether `signature` is a valid signature of `message` by the
+/// owner of `public_key`.
+pub fn verify(public_key: &[u8; PUBLIC_KEY_LEN], message: &[u8], signature: &[u8]) -> bool {
+    if signature.len() != SIGNATURE_LEN {
+        return false;
+    }
+    let curve = Curve::new();
+    let l = Modulus::new(bignum::from_be_bytes(&L));
+
+    let s = bignum::from_le_bytes(&signature[32..]);
+    if bignum::cmp(&s, l.modulus()) != Ordering::Less {
+        return false;
+    }
+    let a = match curve.decode(public_key) {
+        Some(a) => a,
+        None => return false,
+    };
+    let base = match curve.decode(&BASE) {
+        Some(base) => base,
+        None => return false,
+    };
+
+    // k = SHA-512(R || A || M) mod L
+    let mut hash = [0; 64];
+    let mut sha = Sha512State::new_sha512();
+    sha.update(&signature[..32]);
+    sha.update(public_key);
+    sha.update(message);
+    sha.finish(&mut hash);
+    let k = l.reduce_wide(
+        &bignum::from_le_bytes(&hash[..32]),
+        &bignum::from_le_bytes(&hash[32..]),
+    );
+
+    // Check that [S]B - [k]A encodes to R
+    let minus_a = curve.neg(&a);
+    let mut q = curve.identity();
+    for i in (0..256).rev() {
+        q = curve.add(&q, &q);
+        if bignum::bit(&s, i) {
+            q = curve.add(&q, &base);
+        }
+        if bignum::bit(&k, i) {
+            q = curve.add(&q, &minus_a);
+        }
+    }
+    curve.encode(&q)[..] == signature[..32]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn unhex<'b>(hex: &str, buf: &'b mut [u8]) -> &'b mut [u8] {
+        let hex = hex.as_bytes();
+        let digit = |c: u8| (c as char).to_digit(16).unwrap() as u8;
+        for (i, byte) in buf.iter_mut().enumerate().take(hex.len() / 2) {
+            *byte = digit(hex[2 * i]) << 4 | digit(hex[2 * i + 1]);
+        }
+        &mut buf[..hex.len() / 2]
+    }
+
+    // RFC 8032, section 7.1, tests 1 to 3
+    const VECTORS: [(&str, &str, &str); 3] = [
+        (
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+             5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ),
+        (
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+             085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ),
+        (
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac\
+             18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ),
+    ];
+
+    #[test]
+    fn rfc8032_vectors() {
+        for &(public_key, message, signature) in VECTORS.iter() {
+            let mut key = [0; 32];
+            unhex(public_key, &mut key);
+            let mut message_buf = [0; 8];
+            let message = unhex(message, &mut message_buf);
+            let mut signature_buf = [0; 64];
+            let signature = unhex(signature, &mut signature_buf);
+            assert!(public_key_valid(&key));
+            assert!(verify(&key, message, signature));
+
+            // A different message
+            let mut other = [0; 9];
+            other[..message.len()].copy_from_slice(message);
+            assert!(!verify(&key, &other[..message.len() + 1], signature));
+            // A corrupted signature
+            signature[40] ^= 1;
+            assert!(!verify(&key, message, signature));
+            signature[40] ^= 1;
+            signature[3] ^= 0x10;
+            assert!(!verify(&key, message, signature));
+            signature[3] ^= 0x10;
+            // S must be reduced
+            bignum::to_le_bytes(&bignum::from_be_bytes(&L), &mut signature[32..]);
+            assert!(!verify(&key, message, signature));
+        }
+    }
+}
@@ -0,0 +1,6 @@
+//! Software public-key signature verification.
+
+pub mod bignum;
+pub mod ed25519;
+pub mod p256;
+pub mod verifier;
@@ -0,0 +1,264 @@
+//! ECDSA signature verification over the NIST P-256 curve (FIPS 186-4,
+//! section 6.4.2).
+
+use crate::signature::bignum::{self, Modulus, U256};
+use core::cmp::Ordering;
+
+pub const PUBLIC_KEY_LEN: usize = 64;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Field prime, big endian
+const P: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+
+/// Order of the base point, big endian
+const N: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xbc, 0xe6, 0xfa, 0xad, 0xa7, 0x17, 0x9e, 0x84, 0xf3, 0xb9, 0xca, 0xc2, 0xfc, 0x63, 0x25, 0x51,
+];
+
+/// Constant term of the curve equation y^2 = x^3 - 3x + b, big endian
+const B: [u8; 32] = [
+    0x5a, 0xc6, 0x35, 0xd8, 0xaa, 0x3a, 0x93, 0xe7, 0xb3, 0xeb, 0xbd, 0x55, 0x76, 0x98, 0x86, 0xbc,
+    0x65, 0x1d, 0x06, 0xb0, 0xcc, 0x53, 0xb0, 0xf6, 0x3b, 0xce, 0x3c, 0x3e, 0x27, 0xd2, 0x60, 0x4b,
+];
+
+/// Coordinates of the base point, big endian
+const G: [u8; 64] = [
+    0x6b, 0x17, 0xd1, 0xf2, 0xe1, 0x2c, 0x42, 0x47, 0xf8, 0xbc, 0xe6, 0xe5, 0x63, 0xa4, 0x40, 0xf2,
+    0x77, 0x03, 0x7d, 0x81, 0x2d, 0xeb, 0x33, 0xa0, 0xf4, 0xa1, 0x39, 0x45, 0xd8, 0x98, 0xc2, 0x96,
+    0x4f, 0xe3, 0x42, 0xe2, 0xfe, 0x1a, 0x7f, 0x9b, 0x8e, 0xe7, 0xeb, 0x4a, 0x7c, 0x0f, 0x9e, 0x16,
+    0x2b, 0xce, 0x33, 0x57, 0x6b, 0x31, 0x5e, 0xce, 0xcb, 0xb6, 0x40, 0x68, 0x37, 0xbf, 0x51, 0xf5,
+];
+
+/// A point in Jacobian coordinates (x = X/Z^2, y = Y/Z^3), in the Montgomery
+/// domain. Z is zero for the point at infinity.
+#[derive(Copy, Clone)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+struct Curve {
+    p: Modulus,
+}
+
+impl Curve {
+    fn new() -> Curve {
+        Curve {
+            p: Modulus::new(bignum::from_be_bytes(&P)),
+        }
+    }
+
+    fn infinity(&self) -> Point {
+        Point {
+            x: self.p.one(),
+            y: self.p.one(),
+            z: bignum::ZERO,
+        }
+    }
+
+    // Decodes the big endian coordinates of a point, which must be on the
+    // curve.
+    fn decode(&self, bytes: &[u8]) -> Option<Point> {
+        let p = &self.p;
+        let x = bignum::from_be_bytes(&bytes[..32]);
+        let y = bignum::from_be_bytes(&bytes[32..64]);
+        if bignum::cmp(&x, p.modulus()) != Ordering::Less
+            || bignum::cmp(&y, p.modulus()) != Ordering::Less
+        {
+            return None;
+        }
+        let x = p.to_mont(&x);
+        let y = p.to_mont(&y);
+        // y^2 = x^3 - 3x + b
+        let three_x = p.add(&p.add(&x, &x), &x);
+        let rhs = p.add(
+            &p.sub(&p.mul(&p.square(&x), &x), &three_x),
+            &p.to_mont(&bignum::from_be_bytes(&B)),
+        );
+        if p.square(&y) != rhs {
+            return None;
+        }
+        Some(Point {
+            x: x,
+            y: y,
+            z: p.one(),
+        })
+    }
+
+    // Returns the affine x coordinate, not in the Montgomery domain.
+    fn affine_x(&self, point: &Point) -> U256 {
+        let p = &self.p;
+        let z_inv = p.inv(&point.z);
+        p.from_mont(&p.mul(&point.x, &p.square(&z_inv)))
+    }
+
+    // dbl-2001-b
+    fn double(&self, a: &Point) -> Point {
+        let p = &self.p;
+        if bignum::is_zero(&a.z) {
+            return *a;
+        }
+        let delta = p.square(&a.z);
+        let gamma = p.square(&a.y);
+        let beta = p.mul(&a.x, &gamma);
+        let t = p.mul(&p.sub(&a.x, &delta), &p.add(&a.x, &delta));
+        let alpha = p.add(&p.add(&t, &t), &t);
+        let beta2 = p.add(&beta, &beta);
+        let beta4 = p.add(&beta2, &beta2);
+        let beta8 = p.add(&beta4, &beta4);
+        let x = p.sub(&p.square(&alpha), &beta8);
+        let z = p.sub(&p.sub(&p.square(&p.add(&a.y, &a.z)), &gamma), &delta);
+        let gamma2 = p.square(&gamma);
+        let gamma2_2 = p.add(&gamma2, &gamma2);
+        let gamma2_4 = p.add(&gamma2_2, &gamma2_2);
+        let gamma2_8 = p.add(&gamma2_4, &gamma2_4);
+        let y = p.sub(&p.mul(&alpha, &p.sub(&beta4, &x)), &gamma2_8);
+        Point { x: x, y: y, z: z }
+    }
+
+    // add-1998-cmo-2, falling back to doubling for equal points
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        let p = &self.p;
+        if bignum::is_zero(&a.z) {
+            return *b;
+        }
+        if bignum::is_zero(&b.z) {
+            return *a;
+        }
+        let z1z1 = p.square(&a.z);
+        let z2z2 = p.square(&b.z);
+        let u1 = p.mul(&a.x, &z2z2);
+        let u2 = p.mul(&b.x, &z1z1);
+        let s1 = p.mul(&p.mul(&a.y, &b.z), &z2z2);
+        let s2 = p.mul(&p.mul(&b.y, &a.z), &z1z1);
+        let h = p.sub(&u2, &u1);
+        let r = p.sub(&s2, &s1);
+        if bignum::is_zero(&h) {
+            return if bignum::is_zero(&r) {
+                self.double(a)
+            } else {
+                self.infinity()
+            };
+        }
+        let hh = p.square(&h);
+        let hhh = p.mul(&h, &hh);
+        let v = p.mul(&u1, &hh);
+        let x = p.sub(&p.sub(&p.square(&r), &hhh), &p.add(&v, &v));
+        let y = p.sub(&p.mul(&r, &p.sub(&v, &x)), &p.mul(&s1, &hhh));
+        let z = p.mul(&p.mul(&a.z, &b.z), &h);
+        Point { x: x, y: y, z: z }
+    }
+}
+
+/// Returns whether `public_key` holds the coordinates of a point on the
+/// curve.
+pub fn public_key_valid(public_key: &[u8; PUBLIC_KEY_LEN]) -> bool {
+    Curve::new().decode(public_key).is_some()
+}
+
+/// Returns whether `signature` (r and s) is a valid signature of the digest
+/// `hash` by the owner of `public_key`. Only the leftmost 32 bytes of longer
+/// digests are used.
+pub fn verify(public_key: &[u8; PUBLIC_KEY_LEN], hash: &[u8], signature: &[u8]) -> bool {
+    if signature.len() != SIGNATURE_LEN {
+        return false;
+    }
+    let curve = Curve::new();
+    let n = Modulus::new(bignum::from_be_bytes(&N));
+
+    let r = bignum::from_be_bytes(&signature[..32]);
+    let s = bignum::from_be_bytes(&signature[32..]);
+    for v in [r, s].iter() {
+        if bignum::is_zero(v) || bignum::cmp(v, n.modulus()) != Ordering::Less {
+            return false;
+        }
+    }
+    let q = match curve.decode(public_key) {
+        Some(q) => q,
+        None => return false,
+    };
+    let g = match curve.decode(&G) {
+        Some(g) => g,
+        None => return false,
+    };
+
+    let e = bignum::from_be_bytes(&hash[..core::cmp::min(hash.len(), 32)]);
+    let w = n.inv(&n.to_mont(&s));
+    let u1 = n.from_mont(&n.mul(&n.to_mont(&e), &w));
+    let u2 = n.from_mont(&n.mul(&n.to_mont(&r), &w));
+
+    // [u1]G + [u2]Q
+    let g_plus_q = curve.add(&g, &q);
+    let mut x = curve.infinity();
+    for i in (0..256).rev() {
+        x = curve.double(&x);
+        match (bignum::bit(&u1, i), bignum::bit(&u2, i)) {
+            (true, true) => x = curve.add(&x, &g_plus_q),
+            (true, false) => x = curve.add(&x, &g),
+            (false, true) => x = curve.add(&x, &q),
+            (false, false) => {}
+        }
+    }
+    if bignum::is_zero(&x.z) {
+        return false;
+    }
+    n.reduce(&curve.affine_x(&x)) == r
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sha::Sha256State;
+
+    fn unhex(hex: &str, buf: &mut [u8]) {
+        let hex = hex.as_bytes();
+        let digit = |c: u8| (c as char).to_digit(16).unwrap() as u8;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = digit(hex[2 * i]) << 4 | digit(hex[2 * i + 1]);
+        }
+    }
+
+    #[test]
+    fn rfc6979_vector() {
+        // RFC 6979, section A.2.5, SHA-256 signature of "sample"
+        let mut public_key = [0; 64];
+        unhex(
+            "60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6\
+             7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299",
+            &mut public_key,
+        );
+        let mut signature = [0; 64];
+        unhex(
+            "efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716\
+             f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8",
+            &mut signature,
+        );
+        let mut hash = [0; 32];
+        let mut sha = Sha256State::new_sha256();
+        sha.update(b"sample");
+        sha.finish(&mut hash);
+
+        assert!(public_key_valid(&public_key));
+        assert!(verify(&public_key, &hash, &signature));
+
+        hash[0] ^= 1;
+        assert!(!verify(&public_key, &hash, &signature));
+        hash[0] ^= 1;
+        signature[63] ^= 1;
+        assert!(!verify(&public_key, &hash, &signature));
+        signature[63] ^= 1;
+        // s = 0
+        let mut zero_s = signature;
+        zero_s[32..].copy_from_slice(&[0; 32]);
+        assert!(!verify(&public_key, &hash, &zero_s));
+        // Not a point on the curve
+        public_key[63] ^= 1;
+        assert!(!public_key_valid(&public_key));
+        assert!(!verify(&public_key, &hash, &signature));
+    }
+}
@@ -0,0 +1,130 @@
+//! `hil::signature::SignatureVerify` implementation that checks signatures in
+//! software.
+//!
+//! Signatures are checked synchronously when `verify()` is called, and the
+//! result is delivered to the client from a deferred call. A check takes
+//! a few hundred thousand multiplications, so this suits occasional checks
+//! such as firmware images and app credentials rather than traffic.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let verifier = static_init!(
+//!     capsules::signature::verifier::SignatureVerifier<'static>,
+//!     capsules::signature::verifier::SignatureVerifier::new(dynamic_deferred_caller)
+//! );
+//! verifier.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(verifier)
+//!         .expect("no deferred call slot available for signature verifier"),
+//! );
+//! ```
+
+use crate::signature::{ed25519, p256};
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::leasable_buffer::LeasableBuffer;
+use kernel::hil::signature;
+use kernel::ReturnCode;
+
+#[derive(Copy, Clone)]
+enum PublicKey {
+    None,
+    Ed25519([u8; ed25519::PUBLIC_KEY_LEN]),
+    EcdsaP256([u8; p256::PUBLIC_KEY_LEN]),
+}
+
+pub struct SignatureVerifier<'a> {
+    client: OptionalCell<&'a dyn signature::Client<'a>>,
+    public_key: Cell<PublicKey>,
+    message: TakeCell<'static, [u8]>,
+    signature: TakeCell<'static, [u8]>,
+    valid: Cell<bool>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a> SignatureVerifier<'a> {
+    pub fn new(deferred_caller: &'a DynamicDeferredCall) -> SignatureVerifier<'a> {
+        SignatureVerifier {
+            client: OptionalCell::empty(),
+            public_key: Cell::new(PublicKey::None),
+            message: TakeCell::empty(),
+            signature: TakeCell::empty(),
+            valid: Cell::new(false),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    /// Initializes a callback handle for deferred callbacks.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    fn set_public_key(&self, public_key: PublicKey) -> Result<(), ReturnCode> {
+        if self.signature.is_some() {
+            return Err(ReturnCode::EBUSY);
+        }
+        self.public_key.set(public_key);
+        Ok(())
+    }
+}
+
+impl<'a> signature::SignatureVerify<'a> for SignatureVerifier<'a> {
+    fn set_client(&'a self, client: &'a dyn signature::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn verify(
+        &'a self,
+        message: LeasableBuffer<'static, u8>,
+        signature: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+        if self.signature.is_some() {
+            return Err((ReturnCode::EBUSY, message.take(), signature));
+        }
+        let valid = match self.public_key.get() {
+            PublicKey::None => return Err((ReturnCode::ENOSUPPORT, message.take(), signature)),
+            PublicKey::Ed25519(public_key) => ed25519::verify(&public_key, &message[..], signature),
+            PublicKey::EcdsaP256(public_key) => p256::verify(&public_key, &message[..], signature),
+        };
+        self.valid.set(valid);
+        self.message.replace(message.take());
+        self.signature.replace(signature);
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
+        Ok(())
+    }
+}
+
+impl signature::Ed25519 for SignatureVerifier<'_> {
+    fn set_mode_ed25519(&self, public_key: &[u8; 32]) -> Result<(), ReturnCode> {
+        if !ed25519::public_key_valid(public_key) {
+            return Err(ReturnCode::EINVAL);
+        }
+        self.set_public_key(PublicKey::Ed25519(*public_key))
+    }
+}
+
+impl signature::EcdsaP256 for SignatureVerifier<'_> {
+    fn set_mode_ecdsa_p256(&self, public_key: &[u8; 64]) -> Result<(), ReturnCode> {
+        if !p256::public_key_valid(public_key) {
+            return Err(ReturnCode::EINVAL);
+        }
+        self.set_public_key(PublicKey::EcdsaP256(*public_key))
+    }
+}
+
+impl<'a> DynamicDeferredCallClient for SignatureVerifier<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        if let (Some(message), Some(signature)) = (self.message.take(), self.signature.take()) {
+            let valid = self.valid.get();
+            self.client
+                .map(move |client| client.verification_done(Ok(valid), message, signature));
+        }
+    }
+}
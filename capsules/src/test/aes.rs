@@ -231,7 +231,7 @@ impl<'a, A: AES128<'a> + AES128Ctr> TestAes128Ctr<'a, A> {
 }
 
 impl<'a, A: AES128<'a> + AES128Ctr> hil::symmetric_encryption::Client<'a> for TestAes128Ctr<'a, A> {
-    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8], result: ReturnCode) {
         if self.use_source.get() {
             // Take back the source buffer
             self.source.put(source);
@@ -246,9 +246,11 @@ impl<'a, A: AES128<'a> + AES128Ctr> hil::symmetric_encryption::Client<'a> for Te
             &PTXT
         };
 
-        if self.data.map_or(false, |data| {
-            &data[DATA_OFFSET..DATA_OFFSET + DATA_LEN] == expected.as_ref()
-        }) {
+        if result == ReturnCode::SUCCESS
+            && self.data.map_or(false, |data| {
+                &data[DATA_OFFSET..DATA_OFFSET + DATA_LEN] == expected.as_ref()
+            })
+        {
             debug!(
                 "aes_test CTR passed: (CTR {} {} {})",
                 if self.encrypting.get() { "Enc" } else { "Dec" },
@@ -383,7 +385,7 @@ impl<'a, A: AES128<'a> + AES128CBC> TestAes128Cbc<'a, A> {
 }
 
 impl<'a, A: AES128<'a> + AES128CBC> hil::symmetric_encryption::Client<'a> for TestAes128Cbc<'a, A> {
-    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8], result: ReturnCode) {
         if self.use_source.get() {
             // Take back the source buffer
             self.source.put(source);
@@ -398,9 +400,11 @@ impl<'a, A: AES128<'a> + AES128CBC> hil::symmetric_encryption::Client<'a> for Te
             &PTXT
         };
 
-        if self.data.map_or(false, |data| {
-            &data[DATA_OFFSET..DATA_OFFSET + DATA_LEN] == expected.as_ref()
-        }) {
+        if result == ReturnCode::SUCCESS
+            && self.data.map_or(false, |data| {
+                &data[DATA_OFFSET..DATA_OFFSET + DATA_LEN] == expected.as_ref()
+            })
+        {
             debug!(
                 "aes_test passed (CBC {} {})",
                 if self.encrypting.get() { "Enc" } else { "Dec" },
@@ -438,7 +442,7 @@ impl<'a, A: AES128<'a> + AES128CBC> hil::symmetric_encryption::Client<'a> for Te
 }
 
 impl<'a, A: AES128<'a> + AES128ECB> hil::symmetric_encryption::Client<'a> for TestAes128Ecb<'a, A> {
-    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8]) {
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8], result: ReturnCode) {
         if self.use_source.get() {
             // Take back the source buffer
             self.source.put(source);
@@ -453,9 +457,11 @@ impl<'a, A: AES128<'a> + AES128ECB> hil::symmetric_encryption::Client<'a> for Te
             &PTXT
         };
 
-        if self.data.map_or(false, |data| {
-            &data[DATA_OFFSET..DATA_OFFSET + DATA_LEN] == expected.as_ref()
-        }) {
+        if result == ReturnCode::SUCCESS
+            && self.data.map_or(false, |data| {
+                &data[DATA_OFFSET..DATA_OFFSET + DATA_LEN] == expected.as_ref()
+            })
+        {
             debug!(
                 "aes_test passed (ECB {} {})",
                 if self.encrypting.get() { "Enc" } else { "Dec" },
@@ -0,0 +1,212 @@
+//! Test the AES GCM implementation on top of AES hardware.
+
+use core::cell::Cell;
+use kernel::common::cells::TakeCell;
+use kernel::debug;
+use kernel::hil::symmetric_encryption::{
+    GCMClient, AES128GCM, AES128_KEY_SIZE, GCM_NONCE_LENGTH, GCM_TAG_LENGTH,
+};
+use kernel::ReturnCode;
+
+pub struct Test<'a, A: AES128GCM<'a>> {
+    aes_gcm: &'a A,
+
+    buf: TakeCell<'static, [u8]>,
+    current_test: Cell<usize>,
+    encrypting: Cell<bool>,
+
+    // (key, iv, a_data, m_data, c_data followed by the tag)
+    tests: [(
+        &'static [u8],
+        &'static [u8],
+        &'static [u8],
+        &'static [u8],
+        &'static [u8],
+    ); 4],
+}
+
+impl<'a, A: AES128GCM<'a>> Test<'a, A> {
+    pub fn new(aes_gcm: &'a A, buf: &'static mut [u8]) -> Test<'a, A> {
+        Test {
+            aes_gcm: aes_gcm,
+            buf: TakeCell::new(buf),
+            current_test: Cell::new(0),
+            encrypting: Cell::new(true),
+            tests: [
+                (&ZERO_KEY, &ZERO_IV, &[], &[], &TEST_1_TAG),
+                (&ZERO_KEY, &ZERO_IV, &[], &ZERO_BLOCK, &TEST_2_SECURED),
+                (&KEY, &IV, &[], &PLAINTEXT, &TEST_3_SECURED),
+                (&KEY, &IV, &A_DATA, &PLAINTEXT[..60], &TEST_4_SECURED),
+            ],
+        }
+    }
+
+    pub fn run(&self) {
+        debug!("AES GCM encryption/decryption tests");
+        self.trigger_test();
+    }
+
+    fn next_test(&self) -> bool {
+        if self.encrypting.get() {
+            self.encrypting.set(false);
+        } else {
+            self.encrypting.set(true);
+            self.current_test.set(self.current_test.get() + 1);
+            if self.current_test.get() >= self.tests.len() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn trigger_test(&self) {
+        let (key, iv, a_data, m_data, c_data) = self.tests[self.current_test.get()];
+        let (a_off, m_off, m_len) = (0, a_data.len(), m_data.len());
+        let encrypting = self.encrypting.get();
+
+        let buf = match self.buf.take() {
+            None => panic!("aes_gcm_test failed: buffer is not present."),
+            Some(buf) => buf,
+        };
+
+        buf[a_off..m_off].copy_from_slice(a_data);
+        if encrypting {
+            buf[m_off..m_off + m_len].copy_from_slice(m_data);
+        } else {
+            buf[m_off..m_off + m_len + GCM_TAG_LENGTH].copy_from_slice(c_data);
+        }
+
+        if self.aes_gcm.set_key(key) != ReturnCode::SUCCESS
+            || self.aes_gcm.set_iv(iv) != ReturnCode::SUCCESS
+        {
+            panic!("aes_gcm_test failed: cannot set key or iv.");
+        }
+
+        let (res, opt_buf) = self.aes_gcm.crypt(buf, a_off, m_off, m_len, encrypting);
+        if res != ReturnCode::SUCCESS {
+            debug!("Failed to start test.")
+        }
+        if let Some(buf) = opt_buf {
+            self.buf.replace(buf);
+        }
+    }
+
+    fn check_test(&self, tag_is_valid: bool) {
+        let (_key, _iv, a_data, m_data, c_data) = self.tests[self.current_test.get()];
+        let (a_off, m_off, m_len) = (0, a_data.len(), m_data.len());
+        let encrypting = self.encrypting.get();
+
+        let buf = match self.buf.take() {
+            None => panic!("aes_gcm_test failed: buffer is not present."),
+            Some(buf) => buf,
+        };
+
+        let a_matches = buf[a_off..m_off]
+            .iter()
+            .zip(a_data.iter())
+            .all(|(a, b)| *a == *b);
+        let (expected, len) = if encrypting {
+            (c_data, m_len + GCM_TAG_LENGTH)
+        } else {
+            (m_data, m_len)
+        };
+        let data_matches = buf[m_off..m_off + len]
+            .iter()
+            .zip(expected.iter())
+            .all(|(a, b)| *a == *b);
+        if a_matches && data_matches && tag_is_valid {
+            debug!(
+                "aes_gcm_test passed: (current_test={}, encrypting={}, tag_is_valid={})",
+                self.current_test.get(),
+                encrypting,
+                tag_is_valid
+            );
+        } else {
+            debug!(
+                "aes_gcm_test failed: a_matches={}, data_matches={}, (current_test={}, encrypting={}, tag_is_valid={})",
+                a_matches,
+                data_matches,
+                self.current_test.get(),
+                encrypting,
+                tag_is_valid
+            );
+            for (a, b) in buf[m_off..m_off + len].iter().zip(expected.iter()) {
+                debug!("{:x} vs {:x}", *a, *b);
+            }
+        }
+
+        self.buf.replace(buf);
+    }
+}
+
+impl<'a, A: AES128GCM<'a>> GCMClient for Test<'a, A> {
+    fn crypt_done(&self, buf: &'static mut [u8], res: ReturnCode, tag_is_valid: bool) {
+        self.buf.replace(buf);
+        if res != ReturnCode::SUCCESS {
+            debug!("aes_gcm_test failed: crypt_done returned {:?}", res);
+        } else {
+            self.check_test(tag_is_valid);
+            if self.next_test() {
+                self.trigger_test()
+            }
+        }
+    }
+}
+
+// The test cases are from "The Galois/Counter Mode of Operation (GCM)" by
+// McGrew and Viega, appendix B, test cases 1 to 4.
+
+static ZERO_KEY: [u8; AES128_KEY_SIZE] = [0x00; AES128_KEY_SIZE];
+
+static ZERO_IV: [u8; GCM_NONCE_LENGTH] = [0x00; GCM_NONCE_LENGTH];
+
+static ZERO_BLOCK: [u8; 16] = [0x00; 16];
+
+// Test case 1, empty message and additional data
+static TEST_1_TAG: [u8; GCM_TAG_LENGTH] = [
+    0x58, 0xe2, 0xfc, 0xce, 0xfa, 0x7e, 0x30, 0x61, 0x36, 0x7f, 0x1d, 0x57, 0xa4, 0xe7, 0x45, 0x5a,
+];
+
+// Test case 2, one block of zeros
+static TEST_2_SECURED: [u8; 16 + GCM_TAG_LENGTH] = [
+    0x03, 0x88, 0xda, 0xce, 0x60, 0xb6, 0xa3, 0x92, 0xf3, 0x28, 0xc2, 0xb9, 0x71, 0xb2, 0xfe, 0x78,
+    0xab, 0x6e, 0x47, 0xd4, 0x2c, 0xec, 0x13, 0xbd, 0xf5, 0x3a, 0x67, 0xb2, 0x12, 0x57, 0xbd, 0xdf,
+];
+
+static KEY: [u8; AES128_KEY_SIZE] = [
+    0xfe, 0xff, 0xe9, 0x92, 0x86, 0x65, 0x73, 0x1c, 0x6d, 0x6a, 0x8f, 0x94, 0x67, 0x30, 0x83, 0x08,
+];
+
+static IV: [u8; GCM_NONCE_LENGTH] = [
+    0xca, 0xfe, 0xba, 0xbe, 0xfa, 0xce, 0xdb, 0xad, 0xde, 0xca, 0xf8, 0x88,
+];
+
+static PLAINTEXT: [u8; 64] = [
+    0xd9, 0x31, 0x32, 0x25, 0xf8, 0x84, 0x06, 0xe5, 0xa5, 0x59, 0x09, 0xc5, 0xaf, 0xf5, 0x26, 0x9a,
+    0x86, 0xa7, 0xa9, 0x53, 0x15, 0x34, 0xf7, 0xda, 0x2e, 0x4c, 0x30, 0x3d, 0x8a, 0x31, 0x8a, 0x72,
+    0x1c, 0x3c, 0x0c, 0x95, 0x95, 0x68, 0x09, 0x53, 0x2f, 0xcf, 0x0e, 0x24, 0x49, 0xa6, 0xb5, 0x25,
+    0xb1, 0x6a, 0xed, 0xf5, 0xaa, 0x0d, 0xe6, 0x57, 0xba, 0x63, 0x7b, 0x39, 0x1a, 0xaf, 0xd2, 0x55,
+];
+
+// Test case 3, four blocks
+static TEST_3_SECURED: [u8; 64 + GCM_TAG_LENGTH] = [
+    0x42, 0x83, 0x1e, 0xc2, 0x21, 0x77, 0x74, 0x24, 0x4b, 0x72, 0x21, 0xb7, 0x84, 0xd0, 0xd4, 0x9c,
+    0xe3, 0xaa, 0x21, 0x2f, 0x2c, 0x02, 0xa4, 0xe0, 0x35, 0xc1, 0x7e, 0x23, 0x29, 0xac, 0xa1, 0x2e,
+    0x21, 0xd5, 0x14, 0xb2, 0x54, 0x66, 0x93, 0x1c, 0x7d, 0x8f, 0x6a, 0x5a, 0xac, 0x84, 0xaa, 0x05,
+    0x1b, 0xa3, 0x0b, 0x39, 0x6a, 0x0a, 0xac, 0x97, 0x3d, 0x58, 0xe0, 0x91, 0x47, 0x3f, 0x59, 0x85,
+    0x4d, 0x5c, 0x2a, 0xf3, 0x27, 0xcd, 0x64, 0xa6, 0x2c, 0xf3, 0x5a, 0xbd, 0x2b, 0xa6, 0xfa, 0xb4,
+];
+
+// Test case 4, additional data and a partial last block
+static A_DATA: [u8; 20] = [
+    0xfe, 0xed, 0xfa, 0xce, 0xde, 0xad, 0xbe, 0xef, 0xfe, 0xed, 0xfa, 0xce, 0xde, 0xad, 0xbe, 0xef,
+    0xab, 0xad, 0xda, 0xd2,
+];
+
+static TEST_4_SECURED: [u8; 60 + GCM_TAG_LENGTH] = [
+    0x42, 0x83, 0x1e, 0xc2, 0x21, 0x77, 0x74, 0x24, 0x4b, 0x72, 0x21, 0xb7, 0x84, 0xd0, 0xd4, 0x9c,
+    0xe3, 0xaa, 0x21, 0x2f, 0x2c, 0x02, 0xa4, 0xe0, 0x35, 0xc1, 0x7e, 0x23, 0x29, 0xac, 0xa1, 0x2e,
+    0x21, 0xd5, 0x14, 0xb2, 0x54, 0x66, 0x93, 0x1c, 0x7d, 0x8f, 0x6a, 0x5a, 0xac, 0x84, 0xaa, 0x05,
+    0x1b, 0xa3, 0x0b, 0x39, 0x6a, 0x0a, 0xac, 0x97, 0x3d, 0x58, 0xe0, 0x91, 0x5b, 0xc9, 0x4f, 0xbc,
+    0x32, 0x21, 0xa5, 0xdb, 0x94, 0xfa, 0xe9, 0x5a, 0xe7, 0x12, 0x1a, 0x47,
+];
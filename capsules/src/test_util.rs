@@ -0,0 +1,743 @@
+//! Simulated hardware for the unit tests of capsules.
+//!
+//! `SimFlash` is a `hil::flash` device over a `Medium` that outlives it, so
+//! that a test can drop a capsule and mount a new one over the same contents,
+//! as after a reset. Operations complete when `step()` is called, and the
+//! flash can lose power partway through a chosen write.
+//!
+//! `SimAlarm` is a `hil::time::Alarm` whose clock only moves when `advance()`
+//! is called, which fires the alarm if it was set within the time advanced.
+//!
+//! `SimAes` is a `hil::symmetric_encryption::AES128` device in software, in
+//! CTR and CBC modes, whose operations complete when `step()` is called.
+//!
+//! `SimUsb` is a `hil::usb::UsbController` through which a test plays the
+//! host: it runs control transfers, polls an IN endpoint of its client and
+//! sends it bulk OUT packets.
+
+extern crate std;
+
+use core::cell::{Cell, RefCell};
+use core::marker::PhantomData;
+use kernel::common::cells::{OptionalCell, TakeCell, VolatileCell};
+use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
+use kernel::hil::flash::{self, Flash};
+use kernel::hil::symmetric_encryption::{
+    self, AES128Ctr, AES128, AES128CBC, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::hil::time::{Alarm, AlarmClient, Freq1KHz, Frequency, Time};
+use kernel::hil::usb::{self, CtrlInResult, CtrlOutResult, CtrlSetupResult, InResult, OutResult};
+use kernel::ReturnCode;
+use std::boxed::Box;
+use std::vec::Vec;
+
+pub const PAGE_SIZE: usize = 256;
+
+/// Leaks `value`, for the `'static` references capsules take.
+pub fn leak<T>(value: T) -> &'static mut T {
+    Box::leak(Box::new(value))
+}
+
+/// A deferred caller with room for `clients` clients.
+pub fn deferred_caller(clients: usize) -> &'static DynamicDeferredCall {
+    let client_states: Vec<DynamicDeferredCallClientState> = (0..clients)
+        .map(|_| DynamicDeferredCallClientState::default())
+        .collect();
+    leak(DynamicDeferredCall::new(Box::leak(
+        client_states.into_boxed_slice(),
+    )))
+}
+
+pub struct Page(pub [u8; PAGE_SIZE]);
+
+impl Default for Page {
+    fn default() -> Self {
+        Page([0; PAGE_SIZE])
+    }
+}
+
+impl AsMut<[u8]> for Page {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// An operation started on the flash, with the page number it was given.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Op {
+    Read(usize),
+    Write(usize),
+    Erase(usize),
+}
+
+/// What a write does to its page.
+#[derive(Copy, Clone, PartialEq)]
+pub enum WriteMode {
+    /// The page is erased and written, like internal flash controllers do.
+    Replace,
+    /// Writes can only clear bits, so pages must be erased before they are
+    /// written, like NOR flash.
+    ClearBits,
+}
+
+/// The contents of a flash chip, and how many times each page was erased.
+pub struct Medium {
+    pub data: RefCell<Vec<u8>>,
+    pub erases: RefCell<Vec<u32>>,
+}
+
+impl Medium {
+    /// An erased chip of `pages` pages.
+    pub fn new(pages: usize) -> &'static Medium {
+        Medium::with_data(std::vec![0xff; pages * PAGE_SIZE])
+    }
+
+    pub fn with_data(data: Vec<u8>) -> &'static Medium {
+        let pages = data.len() / PAGE_SIZE;
+        leak(Medium {
+            data: RefCell::new(data),
+            erases: RefCell::new(std::vec![0; pages]),
+        })
+    }
+}
+
+pub struct SimFlash {
+    pub medium: &'static Medium,
+    /// The page number of the first page of the medium.
+    first_page: usize,
+    mode: WriteMode,
+    client: OptionalCell<&'static dyn flash::Client<SimFlash>>,
+    pending: Cell<Option<Op>>,
+    buffer: TakeCell<'static, Page>,
+    /// Writes left before the power fails halfway through one. Once it has
+    /// failed, nothing completes.
+    pub writes_left: Cell<Option<usize>>,
+    powered: Cell<bool>,
+    /// The index in the medium of the page whose write was cut off.
+    pub torn_page: Cell<Option<usize>>,
+    /// A page number the flash refuses operations on.
+    pub broken_page: Cell<Option<usize>>,
+    /// The operations started, in order.
+    pub started: RefCell<Vec<Op>>,
+}
+
+impl SimFlash {
+    pub fn new(medium: &'static Medium, mode: WriteMode) -> &'static SimFlash {
+        SimFlash::at(medium, 0, mode)
+    }
+
+    /// A flash whose page numbers start at `first_page`.
+    pub fn at(medium: &'static Medium, first_page: usize, mode: WriteMode) -> &'static SimFlash {
+        leak(SimFlash {
+            medium: medium,
+            first_page: first_page,
+            mode: mode,
+            client: OptionalCell::empty(),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            writes_left: Cell::new(None),
+            powered: Cell::new(true),
+            torn_page: Cell::new(None),
+            broken_page: Cell::new(None),
+            started: RefCell::new(Vec::new()),
+        })
+    }
+
+    pub fn set_client(&self, client: &'static dyn flash::Client<SimFlash>) {
+        self.client.set(client);
+    }
+
+    /// The indices in the medium of the pages written, in order.
+    pub fn writes(&self) -> Vec<usize> {
+        self.started
+            .borrow()
+            .iter()
+            .filter_map(|op| match *op {
+                Op::Write(page_number) => Some(page_number - self.first_page),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Completes the operation in progress. Returns false if there was none.
+    pub fn step(&self) -> bool {
+        let done = flash::Error::CommandComplete;
+        match self.pending.take() {
+            Some(Op::Read(_)) => self.buffer.take().map(|buffer| {
+                self.client
+                    .map(move |client| client.read_complete(buffer, done))
+            }),
+            Some(Op::Write(_)) => self.buffer.take().map(|buffer| {
+                self.client
+                    .map(move |client| client.write_complete(buffer, done))
+            }),
+            Some(Op::Erase(_)) => Some(self.client.map(|client| client.erase_complete(done))),
+            None => None,
+        }
+        .is_some()
+    }
+
+    /// The range of the medium holding `page_number`.
+    fn range(&self, page_number: usize) -> core::ops::Range<usize> {
+        let start = (page_number - self.first_page) * PAGE_SIZE;
+        start..start + PAGE_SIZE
+    }
+
+    /// Starts `op`, or returns why the flash refuses it.
+    fn start(&self, op: Op, page_number: usize) -> ReturnCode {
+        if self.pending.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if self.broken_page.get() == Some(page_number) {
+            return ReturnCode::FAIL;
+        }
+        self.started.borrow_mut().push(op);
+        if self.powered.get() {
+            self.pending.set(Some(op));
+        }
+        ReturnCode::SUCCESS
+    }
+
+    fn erase(&self, page_number: usize) {
+        for byte in self.medium.data.borrow_mut()[self.range(page_number)].iter_mut() {
+            *byte = 0xff;
+        }
+        self.medium.erases.borrow_mut()[page_number - self.first_page] += 1;
+    }
+}
+
+impl Flash for SimFlash {
+    type Page = Page;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Page,
+    ) -> Result<(), (ReturnCode, &'static mut Page)> {
+        match self.start(Op::Read(page_number), page_number) {
+            ReturnCode::SUCCESS => {
+                buf.0
+                    .copy_from_slice(&self.medium.data.borrow()[self.range(page_number)]);
+                self.buffer.replace(buf);
+                Ok(())
+            }
+            result => Err((result, buf)),
+        }
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Page,
+    ) -> Result<(), (ReturnCode, &'static mut Page)> {
+        let powered = self.powered.get();
+        match self.start(Op::Write(page_number), page_number) {
+            ReturnCode::SUCCESS if powered => {}
+            ReturnCode::SUCCESS => return Ok(()),
+            result => return Err((result, buf)),
+        }
+        let mut len = PAGE_SIZE;
+        if let Some(left) = self.writes_left.get() {
+            if left == 0 {
+                // Power fails halfway through the write
+                len = PAGE_SIZE / 2;
+                self.powered.set(false);
+                self.pending.set(None);
+                self.torn_page.set(Some(page_number - self.first_page));
+            }
+            self.writes_left.set(left.checked_sub(1));
+        }
+        if self.mode == WriteMode::Replace {
+            self.erase(page_number);
+        }
+        let start = self.range(page_number).start;
+        for (stored, byte) in self.medium.data.borrow_mut()[start..start + len]
+            .iter_mut()
+            .zip(buf.0.iter())
+        {
+            *stored &= *byte;
+        }
+        self.buffer.replace(buf);
+        Ok(())
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        let powered = self.powered.get();
+        let result = self.start(Op::Erase(page_number), page_number);
+        if result == ReturnCode::SUCCESS && powered {
+            self.erase(page_number);
+        }
+        result
+    }
+}
+
+/// An alarm with a 1 kHz clock that starts at `start`.
+pub struct SimAlarm<'a, F = Freq1KHz> {
+    now: Cell<u32>,
+    alarm: Cell<u32>,
+    enabled: Cell<bool>,
+    client: OptionalCell<&'a dyn AlarmClient>,
+    frequency: PhantomData<F>,
+}
+
+impl<'a> SimAlarm<'a> {
+    pub fn new(start: u32) -> SimAlarm<'a> {
+        SimAlarm::with_frequency(start)
+    }
+}
+
+impl<'a, F: Frequency> SimAlarm<'a, F> {
+    /// An alarm counting at frequency `F` instead of 1 kHz.
+    pub fn with_frequency(start: u32) -> SimAlarm<'a, F> {
+        SimAlarm {
+            now: Cell::new(start),
+            alarm: Cell::new(0),
+            enabled: Cell::new(false),
+            client: OptionalCell::empty(),
+            frequency: PhantomData,
+        }
+    }
+
+    /// Moves the clock `tics` forward, and fires the alarm if it was set
+    /// within that time.
+    pub fn advance(&self, tics: u32) {
+        let then = self.now.get();
+        self.now.set(then.wrapping_add(tics));
+        if self.enabled.get() && self.alarm.get().wrapping_sub(then) <= tics {
+            self.enabled.set(false);
+            self.client.map(|client| client.fired());
+        }
+    }
+}
+
+impl<F: Frequency> Time for SimAlarm<'_, F> {
+    type Frequency = F;
+
+    fn now(&self) -> u32 {
+        self.now.get()
+    }
+
+    fn max_tics(&self) -> u32 {
+        u32::MAX
+    }
+}
+
+impl<'a, F: Frequency> Alarm<'a> for SimAlarm<'a, F> {
+    fn set_alarm(&self, tics: u32) {
+        self.alarm.set(tics);
+        self.enabled.set(true);
+    }
+
+    fn get_alarm(&self) -> u32 {
+        self.alarm.get()
+    }
+
+    fn set_client(&'a self, client: &'a dyn AlarmClient) {
+        self.client.set(client);
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    fn disable(&self) {
+        self.enabled.set(false);
+    }
+}
+
+/// Multiplies in GF(2^8) with the AES polynomial.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+/// The AES S-box, computed rather than tabulated.
+fn sub_byte(x: u8) -> u8 {
+    // x^254 is the inverse of x, and 0 for 0.
+    let mut inverse = 1;
+    for _ in 0..254 {
+        inverse = gf_mul(inverse, x);
+    }
+    inverse
+        ^ inverse.rotate_left(1)
+        ^ inverse.rotate_left(2)
+        ^ inverse.rotate_left(3)
+        ^ inverse.rotate_left(4)
+        ^ 0x63
+}
+
+/// Encrypts one block with AES-128, as in FIPS-197.
+pub fn aes128_encrypt(key: &[u8; AES128_KEY_SIZE], block: &mut [u8; AES128_BLOCK_SIZE]) {
+    let mut round_keys = [[0u8; AES128_BLOCK_SIZE]; 11];
+    round_keys[0] = *key;
+    let mut rcon = 1;
+    for round in 1..11 {
+        let previous = round_keys[round - 1];
+        let mut word = [previous[13], previous[14], previous[15], previous[12]];
+        word.iter_mut().for_each(|byte| *byte = sub_byte(*byte));
+        word[0] ^= rcon;
+        rcon = gf_mul(rcon, 2);
+        for i in 0..AES128_BLOCK_SIZE {
+            word[i % 4] ^= previous[i];
+            round_keys[round][i] = word[i % 4];
+        }
+    }
+
+    let add_round_key = |block: &mut [u8; AES128_BLOCK_SIZE], round: usize| {
+        block
+            .iter_mut()
+            .zip(round_keys[round].iter())
+            .for_each(|(byte, key)| *byte ^= key);
+    };
+    add_round_key(block, 0);
+    for round in 1..11 {
+        // SubBytes and ShiftRows; byte r + 4c is row r of column c.
+        let state = *block;
+        for i in 0..AES128_BLOCK_SIZE {
+            let (row, column) = (i % 4, i / 4);
+            block[i] = sub_byte(state[row + 4 * ((column + row) % 4)]);
+        }
+        if round < 10 {
+            for column in block.chunks_mut(4) {
+                let a = [column[0], column[1], column[2], column[3]];
+                for row in 0..4 {
+                    column[row] = gf_mul(a[row], 2)
+                        ^ gf_mul(a[(row + 1) % 4], 3)
+                        ^ a[(row + 2) % 4]
+                        ^ a[(row + 3) % 4];
+                }
+            }
+        }
+        add_round_key(block, round);
+    }
+}
+
+/// AES-128 in software, in CTR mode and in CBC mode for encryption, which is
+/// what `aes_ccm` uses.
+pub struct SimAes<'a> {
+    client: OptionalCell<&'a dyn symmetric_encryption::Client<'a>>,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+    /// The chaining value in CBC mode, or the counter in CTR mode.
+    chain: Cell<[u8; AES128_BLOCK_SIZE]>,
+    ctr: Cell<bool>,
+    source: TakeCell<'a, [u8]>,
+    dest: TakeCell<'a, [u8]>,
+    range: Cell<(usize, usize)>,
+}
+
+impl<'a> SimAes<'a> {
+    pub fn new() -> SimAes<'a> {
+        SimAes {
+            client: OptionalCell::empty(),
+            key: Cell::new([0; AES128_KEY_SIZE]),
+            iv: Cell::new([0; AES128_BLOCK_SIZE]),
+            chain: Cell::new([0; AES128_BLOCK_SIZE]),
+            ctr: Cell::new(false),
+            source: TakeCell::empty(),
+            dest: TakeCell::empty(),
+            range: Cell::new((0, 0)),
+        }
+    }
+
+    /// Completes the operation in progress, if any, and reports whether
+    /// there was one.
+    pub fn step(&self) -> bool {
+        let dest = match self.dest.take() {
+            Some(dest) => dest,
+            None => return false,
+        };
+        let source = self.source.take();
+        let (start, stop) = self.range.get();
+        let key = self.key.get();
+        let mut chain = self.chain.get();
+        for (i, out) in dest[start..stop].chunks_mut(AES128_BLOCK_SIZE).enumerate() {
+            let mut input = [0; AES128_BLOCK_SIZE];
+            match source {
+                Some(ref source) => input
+                    .copy_from_slice(&source[i * AES128_BLOCK_SIZE..(i + 1) * AES128_BLOCK_SIZE]),
+                None => input.copy_from_slice(out),
+            }
+            if self.ctr.get() {
+                let mut keystream = chain;
+                aes128_encrypt(&key, &mut keystream);
+                for ((out, input), key) in out.iter_mut().zip(input.iter()).zip(keystream.iter()) {
+                    *out = input ^ key;
+                }
+                // The counter is the whole block, big endian.
+                for byte in chain.iter_mut().rev() {
+                    *byte = byte.wrapping_add(1);
+                    if *byte != 0 {
+                        break;
+                    }
+                }
+            } else {
+                chain
+                    .iter_mut()
+                    .zip(input.iter())
+                    .for_each(|(chain, input)| *chain ^= input);
+                aes128_encrypt(&key, &mut chain);
+                out.copy_from_slice(&chain);
+            }
+        }
+        self.chain.set(chain);
+        self.client
+            .map(move |client| client.crypt_done(source, dest, ReturnCode::SUCCESS));
+        true
+    }
+}
+
+impl<'a> AES128<'a> for SimAes<'a> {
+    fn enable(&self) {}
+
+    fn disable(&self) {}
+
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_key = [0; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> ReturnCode {
+        if iv.len() != AES128_BLOCK_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_iv = [0; AES128_BLOCK_SIZE];
+        new_iv.copy_from_slice(iv);
+        self.iv.set(new_iv);
+        ReturnCode::SUCCESS
+    }
+
+    fn start_message(&self) {
+        if self.dest.is_none() {
+            self.chain.set(self.iv.get());
+        }
+    }
+
+    fn crypt(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.dest.is_some() {
+            return Some((ReturnCode::EBUSY, source, dest));
+        }
+        let length = stop_index.wrapping_sub(start_index);
+        if start_index > stop_index
+            || stop_index > dest.len()
+            || length % AES128_BLOCK_SIZE != 0
+            || source
+                .as_ref()
+                .map_or(false, |source| source.len() != length)
+        {
+            return Some((ReturnCode::EINVAL, source, dest));
+        }
+        source.map(|source| self.source.replace(source));
+        self.dest.replace(dest);
+        self.range.set((start_index, stop_index));
+        None
+    }
+}
+
+impl AES128Ctr for SimAes<'_> {
+    fn set_mode_aes128ctr(&self, _encrypting: bool) {
+        self.ctr.set(true);
+    }
+}
+
+impl AES128CBC for SimAes<'_> {
+    fn set_mode_aes128cbc(&self, encrypting: bool) {
+        assert!(encrypting, "SimAes can't decrypt in CBC mode");
+        self.ctr.set(false);
+    }
+}
+
+/// A USB controller with one IN and one OUT endpoint, driven by the test as
+/// the host.
+pub struct SimUsb<'a> {
+    client: OptionalCell<&'a dyn usb::Client<'a>>,
+    ctrl_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    in_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    out_buffer: OptionalCell<&'a [VolatileCell<u8>]>,
+    pub attached: Cell<bool>,
+    /// How many times the client resumed its IN endpoint.
+    pub resumed_in: Cell<usize>,
+    /// How many times the client resumed its OUT endpoint.
+    pub resumed_out: Cell<usize>,
+}
+
+impl<'a> SimUsb<'a> {
+    pub fn new() -> SimUsb<'a> {
+        SimUsb {
+            client: OptionalCell::empty(),
+            ctrl_buffer: OptionalCell::empty(),
+            in_buffer: OptionalCell::empty(),
+            out_buffer: OptionalCell::empty(),
+            attached: Cell::new(false),
+            resumed_in: Cell::new(0),
+            resumed_out: Cell::new(0),
+        }
+    }
+
+    fn client(&self) -> &'a dyn usb::Client<'a> {
+        self.client.expect("no USB client")
+    }
+
+    fn ctrl_buffer(&self) -> &'a [VolatileCell<u8>] {
+        self.ctrl_buffer.expect("control endpoint not set up")
+    }
+
+    /// Sends a SETUP packet to the control endpoint.
+    pub fn setup(&self, packet: [u8; 8]) -> CtrlSetupResult {
+        for (b, p) in self.ctrl_buffer().iter().zip(packet.iter()) {
+            b.set(*p);
+        }
+        self.client().ctrl_setup(0)
+    }
+
+    /// Runs a control read, returning the data the device sent.
+    pub fn control_in(&self, packet: [u8; 8]) -> Result<Vec<u8>, CtrlSetupResult> {
+        match self.setup(packet) {
+            CtrlSetupResult::Ok => {}
+            error => return Err(error),
+        }
+        let mut data = Vec::new();
+        loop {
+            match self.client().ctrl_in(0) {
+                CtrlInResult::Packet(len, done) => {
+                    data.extend(self.ctrl_buffer()[..len].iter().map(|b| b.get()));
+                    if done {
+                        break;
+                    }
+                }
+                CtrlInResult::Delay | CtrlInResult::Error => panic!("control read failed"),
+            }
+        }
+        self.client().ctrl_status_complete(0);
+        Ok(data)
+    }
+
+    /// Runs a control write of `data`, in packets of up to `max_packet`
+    /// bytes.
+    pub fn control_out(
+        &self,
+        packet: [u8; 8],
+        data: &[u8],
+        max_packet: usize,
+    ) -> Result<(), CtrlSetupResult> {
+        match self.setup(packet) {
+            CtrlSetupResult::Ok => {}
+            error => return Err(error),
+        }
+        for chunk in data.chunks(max_packet) {
+            for (b, d) in self.ctrl_buffer().iter().zip(chunk.iter()) {
+                b.set(*d);
+            }
+            match self.client().ctrl_out(0, chunk.len() as u32) {
+                CtrlOutResult::Ok => {}
+                CtrlOutResult::Delay | CtrlOutResult::Halted => panic!("control write failed"),
+            }
+        }
+        self.client().ctrl_status(0);
+        self.client().ctrl_status_complete(0);
+        Ok(())
+    }
+
+    /// Polls the interrupt IN `endpoint`, returning the packet the device
+    /// sent, if any.
+    pub fn poll_in(&self, endpoint: usize) -> Option<Vec<u8>> {
+        self.packet_in(usb::TransferType::Interrupt, endpoint)
+    }
+
+    /// Polls the bulk IN `endpoint`, returning the packet the device sent,
+    /// if any.
+    pub fn bulk_in(&self, endpoint: usize) -> Option<Vec<u8>> {
+        self.packet_in(usb::TransferType::Bulk, endpoint)
+    }
+
+    /// Sends `packet` to the bulk OUT `endpoint`.
+    pub fn bulk_out(&self, endpoint: usize, packet: &[u8]) -> OutResult {
+        let buffer = self.out_buffer.expect("OUT endpoint not set up");
+        for (b, p) in buffer.iter().zip(packet.iter()) {
+            b.set(*p);
+        }
+        self.client()
+            .packet_out(usb::TransferType::Bulk, endpoint, packet.len() as u32)
+    }
+
+    fn packet_in(&self, transfer_type: usb::TransferType, endpoint: usize) -> Option<Vec<u8>> {
+        match self.client().packet_in(transfer_type, endpoint) {
+            InResult::Packet(len) => {
+                let buffer = self.in_buffer.expect("IN endpoint not set up");
+                let packet = buffer[..len].iter().map(|b| b.get()).collect();
+                self.client().packet_transmitted(endpoint);
+                Some(packet)
+            }
+            InResult::Delay => None,
+            InResult::Error => panic!("IN transfer failed"),
+        }
+    }
+}
+
+impl<'a> usb::UsbController<'a> for SimUsb<'a> {
+    fn set_client(&self, client: &'a dyn usb::Client<'a>) {
+        self.client.set(client);
+    }
+
+    fn endpoint_set_ctrl_buffer(&self, buf: &'a [VolatileCell<u8>]) {
+        self.ctrl_buffer.set(buf);
+    }
+
+    fn endpoint_set_in_buffer(&self, _endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.in_buffer.set(buf);
+    }
+
+    fn endpoint_set_out_buffer(&self, _endpoint: usize, buf: &'a [VolatileCell<u8>]) {
+        self.out_buffer.set(buf);
+    }
+
+    fn enable_as_device(&self, _speed: usb::DeviceSpeed) {}
+
+    fn attach(&self) {
+        self.attached.set(true);
+    }
+
+    fn detach(&self) {
+        self.attached.set(false);
+    }
+
+    fn set_address(&self, _addr: u16) {}
+
+    fn enable_address(&self) {}
+
+    fn endpoint_in_enable(&self, _transfer_type: usb::TransferType, _endpoint: usize) {}
+
+    fn endpoint_out_enable(&self, _transfer_type: usb::TransferType, _endpoint: usize) {}
+
+    fn endpoint_in_out_enable(&self, _transfer_type: usb::TransferType, _endpoint: usize) {}
+
+    fn endpoint_resume_in(&self, _endpoint: usize) {
+        self.resumed_in.set(self.resumed_in.get() + 1);
+    }
+
+    fn endpoint_resume_out(&self, _endpoint: usize) {
+        self.resumed_out.set(self.resumed_out.get() + 1);
+    }
+}
@@ -0,0 +1,787 @@
+//! Human Interface Device Class for USB
+//!
+//! This capsule lets Tock act as a USB HID device, such as a keyboard or a
+//! custom device for a test fixture. Input reports go to the host over an
+//! interrupt IN endpoint. Output and feature reports from the host arrive as
+//! SET_REPORT requests on the control endpoint, and GET_REPORT requests for
+//! feature reports are answered with the report set by `set_feature_report`.
+//!
+//! The report descriptor is copied into a kernel buffer with
+//! `set_report_descriptor`, by the board or on behalf of a process, and must
+//! be set before the device attaches.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let hid = static_init!(
+//!     capsules::usb::hid::UsbHid<'static, sam4l::usbc::Usbc<'static>>,
+//!     capsules::usb::hid::UsbHid::new(
+//!         &sam4l::usbc::USBC,
+//!         capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_SAM4L,
+//!         0x6667,
+//!         0xabcf,
+//!         strings,
+//!         &mut capsules::usb::hid::REPORT_DESCRIPTOR_BUFFER,
+//!     )
+//! );
+//! sam4l::usbc::USBC.set_client(hid);
+//! hid.set_report_descriptor(KEYBOARD_REPORT_DESCRIPTOR);
+//! hid.enable();
+//! hid.attach();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::DescriptorType;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::HIDCountryCode;
+use super::descriptors::HIDDescriptor;
+use super::descriptors::HIDSubordinateDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::Recipient;
+use super::descriptors::RequestType;
+use super::descriptors::StandardRequest;
+use super::descriptors::TransferDirection;
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::OptionalCell;
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Longest report descriptor the device can have.
+pub const MAX_REPORT_DESCRIPTOR_LEN: usize = 128;
+
+/// Longest report that can be sent or received.
+pub const MAX_REPORT_LEN: usize = 64;
+
+/// Buffer for the report descriptor.
+pub static mut REPORT_DESCRIPTOR_BUFFER: [u8; MAX_REPORT_DESCRIPTOR_LEN] =
+    [0; MAX_REPORT_DESCRIPTOR_LEN];
+
+/// Identifying number for the endpoint when sending input reports to the
+/// host.
+const ENDPOINT_IN_NUM: usize = 1;
+
+const N_ENDPOINTS: usize = 1;
+
+/// The HID descriptor follows the configuration and interface descriptors in
+/// the configuration descriptor buffer.
+const HID_DESCRIPTOR_OFFSET: usize = 18;
+const HID_DESCRIPTOR_LEN: usize = 9;
+
+/// Class specific control requests.
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Kinds of reports, as numbered in GET_REPORT and SET_REPORT requests.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReportType {
+    Input = 1,
+    Output = 2,
+    Feature = 3,
+}
+
+impl ReportType {
+    fn from_u8(typ: u8) -> Option<ReportType> {
+        match typ {
+            1 => Some(ReportType::Input),
+            2 => Some(ReportType::Output),
+            3 => Some(ReportType::Feature),
+            _ => None,
+        }
+    }
+}
+
+/// Users of the HID device implement this to hear about reports.
+pub trait Client {
+    /// An input report passed to `send_report` has been sent to the host.
+    fn report_sent(&self, buffer: &'static mut [u8], result: ReturnCode);
+
+    /// The host has sent an output or feature report. The first byte is the
+    /// report ID if the report descriptor uses them.
+    fn report_received(&self, report_type: ReportType, report: &[u8]);
+}
+
+/// States of the Control Endpoint related to HID.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum CtrlState {
+    /// No ongoing ctrl transaction.
+    Idle,
+    /// The host is sending a report with SET_REPORT, of which `received`
+    /// bytes have arrived.
+    SetReport {
+        report_type: ReportType,
+        length: usize,
+        received: usize,
+    },
+}
+
+/// USB HID device with a report descriptor set at run time.
+pub struct UsbHid<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    client: OptionalCell<&'a dyn Client>,
+
+    report_descriptor: TakeCell<'static, [u8]>,
+    report_descriptor_len: Cell<usize>,
+    /// Whether `enable` has been called, after which the descriptors can no
+    /// longer change.
+    enabled: Cell<bool>,
+
+    ctrl_state: Cell<CtrlState>,
+    /// The report being received with SET_REPORT.
+    received_report: Cell<[u8; MAX_REPORT_LEN]>,
+    /// The report to answer GET_REPORT for feature reports with.
+    feature_report: Cell<[u8; MAX_REPORT_LEN]>,
+    feature_report_len: Cell<usize>,
+
+    /// The input report waiting to be sent, and then being sent.
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    /// Whether the report in `tx_buffer` is in the endpoint buffer already.
+    tx_in_progress: Cell<bool>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbHid<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        report_descriptor: &'static mut [u8],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x03,    // HID
+            interface_subclass: 0x00, // No boot interface
+            interface_protocol: 0x00, // None
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[EndpointDescriptor {
+            endpoint_address: EndpointAddress::new_const(
+                ENDPOINT_IN_NUM,
+                TransferDirection::DeviceToHost,
+            ),
+            transfer_type: TransferType::Interrupt,
+            max_packet_size: MAX_REPORT_LEN as u16,
+            interval: 1,
+        }]];
+
+        // The report descriptor length is filled in by `enable`.
+        let hid_descriptor = HIDDescriptor {
+            hid_class: 0x0111,
+            country_code: HIDCountryCode::NotSupported,
+            sub_descriptors: &[HIDSubordinateDescriptor {
+                typ: DescriptorType::Report,
+                len: 0,
+            }],
+        };
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                Some(&hid_descriptor),
+                None, // No CDC descriptor array
+            );
+
+        UsbHid {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // The HID descriptor is answered by `ctrl_setup`
+                None, // And so is the report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default()],
+            client: OptionalCell::empty(),
+            report_descriptor: TakeCell::new(report_descriptor),
+            report_descriptor_len: Cell::new(0),
+            enabled: Cell::new(false),
+            ctrl_state: Cell::new(CtrlState::Idle),
+            received_report: Cell::new([0; MAX_REPORT_LEN]),
+            feature_report: Cell::new([0; MAX_REPORT_LEN]),
+            feature_report_len: Cell::new(0),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_in_progress: Cell::new(false),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a dyn Client) {
+        self.client.set(client);
+    }
+
+    /// Sets the report descriptor of the device.
+    ///
+    /// Fails with EALREADY once the device is enabled and ESIZE if the
+    /// descriptor does not fit the buffer.
+    pub fn set_report_descriptor(&self, descriptor: &[u8]) -> ReturnCode {
+        if self.enabled.get() {
+            return ReturnCode::EALREADY;
+        }
+        self.report_descriptor.map_or(ReturnCode::FAIL, |buffer| {
+            if descriptor.len() > buffer.len() {
+                return ReturnCode::ESIZE;
+            }
+            buffer[..descriptor.len()].copy_from_slice(descriptor);
+            self.report_descriptor_len.set(descriptor.len());
+            ReturnCode::SUCCESS
+        })
+    }
+
+    /// Whether the device has been enabled, and with it its report
+    /// descriptor fixed.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    /// Sets the feature report the host gets with GET_REPORT. Fails with
+    /// ESIZE if it is longer than `MAX_REPORT_LEN`.
+    pub fn set_feature_report(&self, report: &[u8]) -> ReturnCode {
+        if report.len() > MAX_REPORT_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let mut feature_report = [0; MAX_REPORT_LEN];
+        feature_report[..report.len()].copy_from_slice(report);
+        self.feature_report.set(feature_report);
+        self.feature_report_len.set(report.len());
+        ReturnCode::SUCCESS
+    }
+
+    /// Sends the first `len` bytes of `buffer` as an input report the next
+    /// time the host polls the device. `report_sent` is called once it has
+    /// been sent.
+    pub fn send_report(
+        &self,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.tx_buffer.is_some() {
+            (ReturnCode::EBUSY, Some(buffer))
+        } else if len == 0 || len > cmp::min(buffer.len(), MAX_REPORT_LEN) {
+            (ReturnCode::ESIZE, Some(buffer))
+        } else if !self.enabled.get() {
+            (ReturnCode::EOFF, Some(buffer))
+        } else {
+            self.tx_buffer.replace(buffer);
+            self.tx_len.set(len);
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+            (ReturnCode::SUCCESS, None)
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    #[inline]
+    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
+        &self.buffers[i - 1].buf
+    }
+
+    /// The HID descriptor, which is both part of the configuration
+    /// descriptor and can be requested on its own.
+    fn hid_descriptor(&self) -> [u8; HID_DESCRIPTOR_LEN] {
+        let len = self.report_descriptor_len.get() as u16;
+        [
+            HID_DESCRIPTOR_LEN as u8,
+            DescriptorType::HID as u8,
+            0x11, // HID 1.11
+            0x01,
+            HIDCountryCode::NotSupported as u8,
+            1, // One report descriptor
+            DescriptorType::Report as u8,
+            len as u8,
+            (len >> 8) as u8,
+        ]
+    }
+
+    /// Answers GET_DESCRIPTOR for the HID and report descriptors.
+    fn get_descriptor(
+        &'a self,
+        endpoint: usize,
+        descriptor_type: DescriptorType,
+        requested_length: u16,
+    ) -> Option<hil::usb::CtrlSetupResult> {
+        match descriptor_type {
+            DescriptorType::HID => Some(self.client_ctrl.ctrl_setup_in_data(
+                endpoint,
+                &self.hid_descriptor(),
+                requested_length,
+            )),
+            DescriptorType::Report => self.report_descriptor.map(|descriptor| {
+                self.client_ctrl.ctrl_setup_in_data(
+                    endpoint,
+                    &descriptor[..self.report_descriptor_len.get()],
+                    requested_length,
+                )
+            }),
+            _ => None,
+        }
+    }
+
+    /// Handles the class requests of HID, or returns `None` to leave the
+    /// request to `ClientCtrl`.
+    fn class_request(
+        &'a self,
+        endpoint: usize,
+        request_code: u8,
+        value: u16,
+        length: u16,
+    ) -> Option<hil::usb::CtrlSetupResult> {
+        let report_type = ReportType::from_u8((value >> 8) as u8);
+        match request_code {
+            GET_REPORT => match report_type {
+                Some(ReportType::Feature) => {
+                    let report = self.feature_report.get();
+                    Some(self.client_ctrl.ctrl_setup_in_data(
+                        endpoint,
+                        &report[..self.feature_report_len.get()],
+                        length,
+                    ))
+                }
+                _ => Some(hil::usb::CtrlSetupResult::ErrGeneric),
+            },
+            // No idle rate, and always the report protocol
+            GET_IDLE => Some(self.client_ctrl.ctrl_setup_in_data(endpoint, &[0], length)),
+            GET_PROTOCOL => Some(self.client_ctrl.ctrl_setup_in_data(endpoint, &[1], length)),
+            SET_REPORT => match report_type {
+                Some(report_type @ ReportType::Output)
+                | Some(report_type @ ReportType::Feature)
+                    if (length as usize) <= MAX_REPORT_LEN =>
+                {
+                    self.ctrl_state.set(CtrlState::SetReport {
+                        report_type,
+                        length: length as usize,
+                        received: 0,
+                    });
+                    None
+                }
+                _ => Some(hil::usb::CtrlSetupResult::ErrGeneric),
+            },
+            // SET_IDLE and SET_PROTOCOL are accepted and ignored
+            _ => None,
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for UsbHid<'a, U> {
+    fn enable(&'a self) {
+        // The report descriptor is fixed from here on, so put its length
+        // into the configuration descriptor.
+        self.enabled.set(true);
+        self.client_ctrl
+            .update_other_descriptor(HID_DESCRIPTOR_OFFSET, &self.hid_descriptor());
+
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffer for input reports.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
+        self.controller()
+            .endpoint_in_enable(TransferType::Interrupt, ENDPOINT_IN_NUM);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.ctrl_state.set(CtrlState::Idle);
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// The HID and report descriptors and the HID class requests are handled
+    /// here, everything else by `ClientCtrl`.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        self.ctrl_state.set(CtrlState::Idle);
+        let result =
+            descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf).and_then(|setup_data| {
+                match setup_data.request_type.recipient() {
+                    Recipient::Interface => match setup_data.request_type.request_type() {
+                        RequestType::Standard => match setup_data.get_standard_request() {
+                            Some(StandardRequest::GetDescriptor {
+                                descriptor_type,
+                                requested_length,
+                                ..
+                            }) => self.get_descriptor(endpoint, descriptor_type, requested_length),
+                            _ => None,
+                        },
+                        RequestType::Class => self.class_request(
+                            endpoint,
+                            setup_data.request_code,
+                            setup_data.value,
+                            setup_data.length,
+                        ),
+                        _ => None,
+                    },
+                    _ => None,
+                }
+            });
+
+        result.unwrap_or_else(|| self.client_ctrl.ctrl_setup(endpoint))
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        self.client_ctrl.ctrl_in(endpoint)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        if let CtrlState::SetReport {
+            report_type,
+            length,
+            received,
+        } = self.ctrl_state.get()
+        {
+            // Collect the report from the control packets.
+            let mut report = self.received_report.get();
+            let packet = &self.client_ctrl.ctrl_buffer.buf;
+            let count = cmp::min(packet_bytes as usize, length - received);
+            for (r, p) in report[received..received + count]
+                .iter_mut()
+                .zip(packet.iter())
+            {
+                *r = p.get();
+            }
+            self.received_report.set(report);
+            self.ctrl_state.set(CtrlState::SetReport {
+                report_type,
+                length,
+                received: received + count,
+            });
+        }
+
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        if let CtrlState::SetReport {
+            report_type,
+            received,
+            ..
+        } = self.ctrl_state.get()
+        {
+            let report = self.received_report.get();
+            self.client
+                .map(|client| client.report_received(report_type, &report[..received]));
+        }
+        self.ctrl_state.set(CtrlState::Idle);
+
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle an Interrupt IN transaction.
+    ///
+    /// The host polls for input reports, and we send one if `send_report`
+    /// has given us one.
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        match transfer_type {
+            TransferType::Interrupt => {
+                if self.tx_in_progress.get() {
+                    return hil::usb::InResult::Delay;
+                }
+                self.tx_buffer.map_or(hil::usb::InResult::Delay, |tx_buf| {
+                    let len = self.tx_len.get();
+                    let packet = self.buffer(endpoint);
+                    for (p, b) in packet.iter().zip(tx_buf[..len].iter()) {
+                        p.set(*b);
+                    }
+                    self.tx_in_progress.set(true);
+                    hil::usb::InResult::Packet(len)
+                })
+            }
+            TransferType::Control | TransferType::Isochronous | TransferType::Bulk => {
+                // Nothing to do for HID.
+                hil::usb::InResult::Delay
+            }
+        }
+    }
+
+    /// Handle a Bulk/Interrupt OUT transaction
+    fn packet_out(
+        &'a self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        // Output reports come in over the control endpoint.
+        hil::usb::OutResult::Ok
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        if self.tx_in_progress.get() {
+            self.tx_in_progress.set(false);
+            self.tx_buffer.take().map(|tx_buf| {
+                self.client
+                    .map(move |client| client.report_sent(tx_buf, ReturnCode::SUCCESS));
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{leak, SimUsb};
+    use core::cell::RefCell;
+    use kernel::hil::usb::{Client as _, UsbController};
+    use std::vec::Vec;
+
+    const DESCRIPTOR: [u8; 6] = [0x06, 0x00, 0xff, 0x09, 0x01, 0xa1];
+
+    type TestHid = UsbHid<'static, SimUsb<'static>>;
+
+    #[derive(Default)]
+    struct TestClient {
+        sent: RefCell<Vec<ReturnCode>>,
+        received: RefCell<Vec<(ReportType, Vec<u8>)>>,
+    }
+
+    impl Client for TestClient {
+        fn report_sent(&self, _buffer: &'static mut [u8], result: ReturnCode) {
+            self.sent.borrow_mut().push(result);
+        }
+
+        fn report_received(&self, report_type: ReportType, report: &[u8]) {
+            self.received
+                .borrow_mut()
+                .push((report_type, report.to_vec()));
+        }
+    }
+
+    fn setup() -> (
+        &'static SimUsb<'static>,
+        &'static TestHid,
+        &'static TestClient,
+    ) {
+        let usb = leak(SimUsb::new());
+        let hid = leak(UsbHid::new(
+            &*usb,
+            64,
+            0x6667,
+            0xabcf,
+            &["Tock", "HID", "0"],
+            leak([0; MAX_REPORT_DESCRIPTOR_LEN]),
+        ));
+        let client = leak(TestClient::default());
+        usb.set_client(hid);
+        hid.set_client(client);
+        (usb, hid, client)
+    }
+
+    // A class request to the interface for a report of `report_type`
+    fn class_request(request_type: u8, request: u8, report_type: ReportType, len: u16) -> [u8; 8] {
+        let len = len.to_le_bytes();
+        [
+            request_type,
+            request,
+            0,
+            report_type as u8,
+            0,
+            0,
+            len[0],
+            len[1],
+        ]
+    }
+
+    // GET_DESCRIPTOR of the descriptor of `typ` from `request_type`
+    fn get_descriptor(request_type: u8, typ: DescriptorType) -> [u8; 8] {
+        [request_type, 0x06, 0, typ as u8, 0, 0, 0xff, 0]
+    }
+
+    #[test]
+    fn report_descriptor_is_fixed_once_enabled() {
+        let (usb, hid, _client) = setup();
+        assert_eq!(
+            hid.set_report_descriptor(&[0; MAX_REPORT_DESCRIPTOR_LEN + 1]),
+            ReturnCode::ESIZE
+        );
+        assert_eq!(hid.set_report_descriptor(&DESCRIPTOR), ReturnCode::SUCCESS);
+        assert!(!hid.is_enabled());
+        hid.enable();
+        hid.attach();
+        assert!(usb.attached.get());
+        assert_eq!(hid.set_report_descriptor(&[0]), ReturnCode::EALREADY);
+
+        assert_eq!(
+            usb.control_in(get_descriptor(0x81, DescriptorType::Report))
+                .ok(),
+            Some(DESCRIPTOR.to_vec())
+        );
+        let hid_descriptor = usb
+            .control_in(get_descriptor(0x81, DescriptorType::HID))
+            .unwrap();
+        assert_eq!(
+            hid_descriptor,
+            [9, DescriptorType::HID as u8, 0x11, 0x01, 0, 1, 0x22, 6, 0]
+        );
+
+        // The configuration descriptor has the HID descriptor too
+        let configuration = usb
+            .control_in(get_descriptor(0x80, DescriptorType::Configuration))
+            .unwrap();
+        assert_eq!(
+            configuration[HID_DESCRIPTOR_OFFSET..HID_DESCRIPTOR_OFFSET + HID_DESCRIPTOR_LEN],
+            hid_descriptor[..]
+        );
+    }
+
+    #[test]
+    fn input_reports_are_sent_when_polled() {
+        let (usb, hid, client) = setup();
+        let (result, buffer) = hid.send_report(leak([1, 2, 3]), 3);
+        assert_eq!(result, ReturnCode::EOFF);
+
+        hid.enable();
+        let buffer = buffer.unwrap();
+        assert_eq!(hid.send_report(buffer, 0).0, ReturnCode::ESIZE);
+        assert_eq!(
+            hid.send_report(leak([0; MAX_REPORT_LEN + 1]), MAX_REPORT_LEN + 1)
+                .0,
+            ReturnCode::ESIZE
+        );
+        assert_eq!(usb.poll_in(ENDPOINT_IN_NUM), None);
+
+        assert_eq!(
+            hid.send_report(leak([1, 2, 3]), 2),
+            (ReturnCode::SUCCESS, None)
+        );
+        assert_eq!(usb.resumed_in.get(), 1);
+        assert_eq!(hid.send_report(leak([4]), 1).0, ReturnCode::EBUSY);
+
+        assert_eq!(usb.poll_in(ENDPOINT_IN_NUM), Some(std::vec![1, 2]));
+        assert_eq!(*client.sent.borrow(), [ReturnCode::SUCCESS]);
+        assert_eq!(usb.poll_in(ENDPOINT_IN_NUM), None);
+
+        // The next report can be sent
+        assert_eq!(hid.send_report(leak([4]), 1).0, ReturnCode::SUCCESS);
+        assert_eq!(usb.poll_in(ENDPOINT_IN_NUM), Some(std::vec![4]));
+    }
+
+    #[test]
+    fn output_and_feature_reports_are_received() {
+        let (usb, hid, client) = setup();
+        hid.enable();
+
+        let report: Vec<u8> = (0..10).collect();
+        usb.control_out(
+            class_request(0x21, SET_REPORT, ReportType::Output, 10),
+            &report,
+            8,
+        )
+        .unwrap();
+        usb.control_out(
+            class_request(0x21, SET_REPORT, ReportType::Feature, 2),
+            &[7, 7],
+            8,
+        )
+        .unwrap();
+        assert_eq!(
+            *client.received.borrow(),
+            [
+                (ReportType::Output, report),
+                (ReportType::Feature, std::vec![7, 7])
+            ]
+        );
+
+        // Input reports cannot be set, and reports must fit the buffer
+        assert!(usb
+            .control_out(
+                class_request(0x21, SET_REPORT, ReportType::Input, 1),
+                &[1],
+                8
+            )
+            .is_err());
+        assert!(usb
+            .control_out(
+                class_request(0x21, SET_REPORT, ReportType::Output, 65),
+                &[0; 65],
+                8
+            )
+            .is_err());
+        assert_eq!(client.received.borrow().len(), 2);
+    }
+
+    #[test]
+    fn feature_report_is_returned() {
+        let (usb, hid, _client) = setup();
+        hid.enable();
+        assert_eq!(
+            hid.set_feature_report(&[0; MAX_REPORT_LEN + 1]),
+            ReturnCode::ESIZE
+        );
+        assert_eq!(hid.set_feature_report(&[9, 8, 7]), ReturnCode::SUCCESS);
+
+        assert_eq!(
+            usb.control_in(class_request(0xa1, GET_REPORT, ReportType::Feature, 64))
+                .ok(),
+            Some(std::vec![9, 8, 7])
+        );
+        assert_eq!(
+            usb.control_in(class_request(0xa1, GET_REPORT, ReportType::Feature, 2))
+                .ok(),
+            Some(std::vec![9, 8])
+        );
+        assert!(usb
+            .control_in(class_request(0xa1, GET_REPORT, ReportType::Input, 64))
+            .is_err());
+
+        assert_eq!(
+            usb.control_in(class_request(0xa1, GET_IDLE, ReportType::Input, 1))
+                .ok(),
+            Some(std::vec![0])
+        );
+        assert_eq!(
+            usb.control_in(class_request(0xa1, GET_PROTOCOL, ReportType::Input, 1))
+                .ok(),
+            Some(std::vec![1])
+        );
+    }
+}
@@ -0,0 +1,416 @@
+//! USB HID system call interface
+//!
+//! This capsule lets processes use a `hid::UsbHid` device: give it a report
+//! descriptor, send input reports, set the feature report and receive the
+//! output and feature reports the host sends.
+//!
+//! ## Instantiation
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let hid_driver = static_init!(
+//!     capsules::usb::hid_user::UsbHidDriver<'static, sam4l::usbc::Usbc<'static>>,
+//!     capsules::usb::hid_user::UsbHidDriver::new(
+//!         hid,
+//!         &mut capsules::usb::hid_user::BUFFER,
+//!         board_kernel.create_grant(&grant_cap)));
+//! hid.set_client(hid_driver);
+//! ```
+//!
+//! The system call interface is documented in doc/syscalls/20007_usb_hid.md.
+
+use core::cmp;
+
+use super::hid::{Client, ReportType, UsbHid, MAX_REPORT_LEN};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+use crate::driver;
+pub const DRIVER_NUM: usize = driver::NUM::UsbHid as usize;
+
+/// Buffer for the input report being sent.
+pub static mut BUFFER: [u8; MAX_REPORT_LEN] = [0; MAX_REPORT_LEN];
+
+#[derive(Default)]
+pub struct App {
+    report: Option<AppSlice<Shared, u8>>,
+    receive_buffer: Option<AppSlice<Shared, u8>>,
+    report_descriptor: Option<AppSlice<Shared, u8>>,
+    sent_callback: Option<Callback>,
+    received_callback: Option<Callback>,
+    /// Length of the input report waiting to be sent.
+    waiting: Option<usize>,
+}
+
+/// The device and the buffer input reports are sent from, apart from the
+/// process bookkeeping of `UsbHidDriver`.
+struct Reports<'a, U: hil::usb::UsbController<'a>> {
+    hid: &'a UsbHid<'a, U>,
+    buffer: TakeCell<'static, [u8]>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Reports<'a, U> {
+    /// Checks an input report of `len` bytes from `report` before it is
+    /// queued.
+    fn check(len: usize, report: Option<&[u8]>) -> ReturnCode {
+        if len == 0 || len > MAX_REPORT_LEN {
+            ReturnCode::ESIZE
+        } else if report.map_or(true, |report| len > report.len()) {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Sends the first `len` bytes of `report` as an input report.
+    fn send(&self, report: Option<&[u8]>, len: usize) -> ReturnCode {
+        let result = Reports::<U>::check(len, report);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        match (report, self.buffer.take()) {
+            (Some(report), Some(buffer)) => {
+                let len = cmp::min(len, buffer.len());
+                buffer[..len].copy_from_slice(&report[..len]);
+                let (result, buffer) = self.hid.send_report(buffer, len);
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                result
+            }
+            (_, buffer) => {
+                // A report is being sent
+                buffer.map(|buffer| self.buffer.replace(buffer));
+                ReturnCode::EBUSY
+            }
+        }
+    }
+
+    /// Sets the feature report to the first `len` bytes of `report`.
+    fn set_feature(&self, report: Option<&[u8]>, len: usize) -> ReturnCode {
+        report.map_or(ReturnCode::EINVAL, |report| {
+            if len > report.len() {
+                ReturnCode::EINVAL
+            } else {
+                self.hid.set_feature_report(&report[..len])
+            }
+        })
+    }
+
+    /// Enables the device with the report descriptor `descriptor`, or the
+    /// one set by the board, and attaches it to the bus.
+    fn enable_and_attach(&self, descriptor: Option<&[u8]>) -> ReturnCode {
+        if self.hid.is_enabled() {
+            return ReturnCode::EALREADY;
+        }
+        let result = descriptor.map_or(ReturnCode::SUCCESS, |descriptor| {
+            self.hid.set_report_descriptor(descriptor)
+        });
+        if result == ReturnCode::SUCCESS {
+            hil::usb::Client::enable(self.hid);
+            hil::usb::Client::attach(self.hid);
+        }
+        result
+    }
+}
+
+pub struct UsbHidDriver<'a, U: hil::usb::UsbController<'a>> {
+    reports: Reports<'a, U>,
+    apps: Grant<App>,
+    serving_app: OptionalCell<AppId>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> UsbHidDriver<'a, U> {
+    pub fn new(hid: &'a UsbHid<'a, U>, buffer: &'static mut [u8], apps: Grant<App>) -> Self {
+        UsbHidDriver {
+            reports: Reports {
+                hid: hid,
+                buffer: TakeCell::new(buffer),
+            },
+            apps: apps,
+            serving_app: OptionalCell::empty(),
+        }
+    }
+
+    fn serve_waiting_apps(&self) {
+        if self.serving_app.is_some() {
+            // A report is being sent
+            return;
+        }
+
+        // Find a waiting app and send its report
+        let mut found = false;
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                if let Some(len) = app.waiting {
+                    let report = app.report.as_ref().map(|report| report.as_ref());
+                    let result = self.reports.send(report, len);
+                    if result == ReturnCode::SUCCESS {
+                        self.serving_app.set(app.appid());
+                        found = true;
+                    } else {
+                        // The app's request failed
+                        if let Some(mut callback) = app.sent_callback {
+                            callback.schedule(From::from(result), 0, 0);
+                        }
+                        app.waiting = None;
+                    }
+                }
+            });
+            if found {
+                break;
+            }
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Client for UsbHidDriver<'a, U> {
+    fn report_sent(&self, buffer: &'static mut [u8], result: ReturnCode) {
+        self.reports.buffer.replace(buffer);
+        self.serving_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.waiting = None;
+                if let Some(mut callback) = app.sent_callback {
+                    callback.schedule(From::from(result), 0, 0);
+                }
+            });
+        });
+        self.serve_waiting_apps();
+    }
+
+    fn report_received(&self, report_type: ReportType, report: &[u8]) {
+        // Every app listening gets the report
+        for app in self.apps.iter() {
+            app.enter(|app, _| {
+                let callback = app.received_callback;
+                if let (Some(buffer), Some(mut callback)) = (app.receive_buffer.as_mut(), callback)
+                {
+                    let len = cmp::min(report.len(), buffer.len());
+                    buffer.as_mut()[..len].copy_from_slice(&report[..len]);
+                    callback.schedule(report_type as usize, len, 0);
+                }
+            });
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> Driver for UsbHidDriver<'a, U> {
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| match allow_num {
+                // Report to send
+                0 => {
+                    app.report = slice;
+                    ReturnCode::SUCCESS
+                }
+                // Buffer for reports from the host
+                1 => {
+                    app.receive_buffer = slice;
+                    ReturnCode::SUCCESS
+                }
+                // Report descriptor
+                2 => {
+                    app.report_descriptor = slice;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| match subscribe_num {
+                // Set callback for sent reports
+                0 => {
+                    app.sent_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                // Set callback for received reports
+                1 => {
+                    app.received_callback = callback;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::ENOSUPPORT,
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    fn command(&self, command_num: usize, len: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            // This driver is present
+            0 => ReturnCode::SUCCESS,
+
+            // Enable the device and attach to the bus
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    let descriptor = app
+                        .report_descriptor
+                        .as_ref()
+                        .map(|descriptor| descriptor.as_ref());
+                    self.reports.enable_and_attach(descriptor)
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            // Send an input report
+            2 => {
+                let result = self
+                    .apps
+                    .enter(appid, |app, _| {
+                        if app.waiting.is_some() {
+                            // Each app may send only one report at a time
+                            return ReturnCode::EBUSY;
+                        }
+                        let report = app.report.as_ref().map(|report| report.as_ref());
+                        let result = Reports::<U>::check(len, report);
+                        if result == ReturnCode::SUCCESS {
+                            app.waiting = Some(len);
+                        }
+                        result
+                    })
+                    .unwrap_or_else(|err| err.into());
+
+                if result == ReturnCode::SUCCESS {
+                    self.serve_waiting_apps();
+                }
+                result
+            }
+
+            // Set the feature report
+            3 => self
+                .apps
+                .enter(appid, |app, _| {
+                    let report = app.report.as_ref().map(|report| report.as_ref());
+                    self.reports.set_feature(report, len)
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{leak, SimUsb};
+    use crate::usb::hid::MAX_REPORT_DESCRIPTOR_LEN;
+    use kernel::hil::usb::UsbController;
+
+    type TestReports = Reports<'static, SimUsb<'static>>;
+
+    fn setup() -> (
+        &'static SimUsb<'static>,
+        &'static UsbHid<'static, SimUsb<'static>>,
+        TestReports,
+    ) {
+        let usb = leak(SimUsb::new());
+        let hid = leak(UsbHid::new(
+            &*usb,
+            64,
+            0x6667,
+            0xabcf,
+            &["Tock", "HID", "0"],
+            leak([0; MAX_REPORT_DESCRIPTOR_LEN]),
+        ));
+        usb.set_client(hid);
+        let reports = Reports {
+            hid: hid,
+            buffer: TakeCell::new(leak([0; MAX_REPORT_LEN])),
+        };
+        (usb, hid, reports)
+    }
+
+    #[test]
+    fn input_reports_are_checked() {
+        let report = [0; MAX_REPORT_LEN + 1];
+        assert_eq!(TestReports::check(4, Some(&report)), ReturnCode::SUCCESS);
+        assert_eq!(TestReports::check(0, Some(&report)), ReturnCode::ESIZE);
+        assert_eq!(
+            TestReports::check(MAX_REPORT_LEN + 1, Some(&report)),
+            ReturnCode::ESIZE
+        );
+        // No report, or a report shorter than the length
+        assert_eq!(TestReports::check(4, None), ReturnCode::EINVAL);
+        assert_eq!(
+            TestReports::check(4, Some(&report[..3])),
+            ReturnCode::EINVAL
+        );
+    }
+
+    #[test]
+    fn enable_sets_report_descriptor_once() {
+        let (usb, hid, reports) = setup();
+        assert_eq!(
+            reports.enable_and_attach(Some(&[0; MAX_REPORT_DESCRIPTOR_LEN + 1])),
+            ReturnCode::ESIZE
+        );
+        assert!(!hid.is_enabled());
+        assert!(!usb.attached.get());
+
+        assert_eq!(
+            reports.enable_and_attach(Some(&[0x06, 0x00, 0xff])),
+            ReturnCode::SUCCESS
+        );
+        assert!(hid.is_enabled());
+        assert!(usb.attached.get());
+        assert_eq!(
+            usb.control_in([0x81, 0x06, 0, 0x22, 0, 0, 0xff, 0]).ok(),
+            Some(std::vec![0x06, 0x00, 0xff])
+        );
+        assert_eq!(reports.enable_and_attach(None), ReturnCode::EALREADY);
+    }
+
+    #[test]
+    fn input_reports_share_the_buffer() {
+        let (usb, _hid, reports) = setup();
+        let report = [1, 2, 3, 4];
+        // The device must be enabled first
+        assert_eq!(reports.send(Some(&report), 4), ReturnCode::EOFF);
+        assert!(reports.buffer.is_some());
+
+        reports.enable_and_attach(None);
+        assert_eq!(reports.send(None, 4), ReturnCode::EINVAL);
+        assert_eq!(reports.send(Some(&report), 3), ReturnCode::SUCCESS);
+        assert_eq!(reports.send(Some(&report), 1), ReturnCode::EBUSY);
+        assert_eq!(usb.poll_in(1), Some(std::vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn feature_report_is_set() {
+        let (usb, _hid, reports) = setup();
+        let report = [0; MAX_REPORT_LEN + 1];
+        assert_eq!(reports.set_feature(None, 1), ReturnCode::EINVAL);
+        assert_eq!(
+            reports.set_feature(Some(&report[..2]), 3),
+            ReturnCode::EINVAL
+        );
+        assert_eq!(
+            reports.set_feature(Some(&report), MAX_REPORT_LEN + 1),
+            ReturnCode::ESIZE
+        );
+
+        reports.enable_and_attach(None);
+        assert_eq!(
+            reports.set_feature(Some(&[5, 6, 7]), 2),
+            ReturnCode::SUCCESS
+        );
+        assert_eq!(
+            usb.control_in([0xa1, 0x01, 0, 3, 0, 0, 64, 0]).ok(),
+            Some(std::vec![5, 6])
+        );
+    }
+}
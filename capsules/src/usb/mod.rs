@@ -1,5 +1,9 @@
 pub mod cdc;
 pub mod descriptors;
+pub mod hid;
+pub mod hid_user;
+pub mod msc;
+pub mod scsi;
 pub mod usb_user;
 pub mod usbc_client;
 pub mod usbc_client_ctrl;
@@ -0,0 +1,862 @@
+//! Mass Storage Class Device for USB
+//!
+//! This capsule makes a region of nonvolatile storage show up as a drive on
+//! the USB host, for example to let a PC read a log partition. It implements
+//! the bulk-only transport with the subset of the SCSI transparent command
+//! set in `scsi` that hosts need for a simple block device.
+//!
+//! The region is exposed as 512 byte blocks, and the storage is accessed one
+//! block at a time. While the storage is busy, the capsule NAKs the host.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let msc = static_init!(
+//!     capsules::usb::msc::MassStorage<'static, sam4l::usbc::Usbc<'static>>,
+//!     capsules::usb::msc::MassStorage::new(
+//!         &sam4l::usbc::USBC,
+//!         capsules::usb::usbc_client::MAX_CTRL_PACKET_SIZE_SAM4L,
+//!         0x6667,
+//!         0xabce,
+//!         strings,
+//!         nv_to_page,
+//!         0x60000,
+//!         0x20000,
+//!         &mut capsules::usb::msc::BUFFER,
+//!     )
+//! );
+//! nv_to_page.set_client(msc);
+//! sam4l::usbc::USBC.set_client(msc);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+
+use super::descriptors;
+use super::descriptors::Buffer64;
+use super::descriptors::EndpointAddress;
+use super::descriptors::EndpointDescriptor;
+use super::descriptors::InterfaceDescriptor;
+use super::descriptors::RequestType;
+use super::descriptors::TransferDirection;
+use super::scsi;
+use super::scsi::{Command, CommandBlockWrapper, Sense, Status};
+use super::usbc_client_ctrl::ClientCtrl;
+
+use kernel::common::cells::TakeCell;
+use kernel::common::cells::VolatileCell;
+use kernel::hil;
+use kernel::hil::nonvolatile_storage::{NonvolatileStorage, NonvolatileStorageClient};
+use kernel::hil::usb::TransferType;
+use kernel::ReturnCode;
+
+/// Size of the blocks the drive is made of.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Buffer for one block of the drive.
+pub static mut BUFFER: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
+
+/// Identifying number for the endpoint when transferring data from us to the
+/// host.
+const ENDPOINT_IN_NUM: usize = 1;
+/// Identifying number for the endpoint when transferring data from the host to
+/// us.
+const ENDPOINT_OUT_NUM: usize = 2;
+
+const N_ENDPOINTS: usize = 2;
+
+const PACKET_SIZE: usize = 64;
+
+/// Class specific control requests of the bulk-only transport.
+const GET_MAX_LUN: u8 = 0xfe;
+const BULK_ONLY_RESET: u8 = 0xff;
+
+static LANGUAGES: &'static [u16; 1] = &[
+    0x0409, // English (United States)
+];
+
+/// Where we are in the bulk-only transport.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    /// Waiting for the host to send a command.
+    Cbw,
+    /// Sending a response of the given length, which has been written to the
+    /// IN endpoint buffer.
+    Response(usize),
+    /// Sending an empty packet to end a data stage early.
+    ZeroLengthPacket,
+    /// Reading the block at `lba` from the storage.
+    ReadBusy { lba: u32, blocks: usize },
+    /// Sending the block buffer to the host, of which `offset` bytes have
+    /// already been sent.
+    ReadData {
+        lba: u32,
+        blocks: usize,
+        offset: usize,
+    },
+    /// Filling the block buffer with data from the host.
+    WriteData {
+        lba: u32,
+        blocks: usize,
+        offset: usize,
+    },
+    /// Writing the block buffer to the block at `lba`.
+    WriteBusy { lba: u32, blocks: usize },
+    /// Dropping the rest of the data stage after a failure.
+    Discard,
+    /// Sending the status of the command.
+    Csw,
+}
+
+/// USB mass storage device over a region of nonvolatile storage.
+pub struct MassStorage<'a, U: 'a> {
+    /// Helper USB client library for handling many USB operations.
+    client_ctrl: ClientCtrl<'a, 'static, U>,
+
+    /// 64 byte buffers for each endpoint.
+    buffers: [Buffer64; N_ENDPOINTS],
+
+    storage: &'a dyn NonvolatileStorage<'static>,
+    /// Address of the first block in the storage.
+    start_address: usize,
+    block_count: usize,
+    block: TakeCell<'static, [u8]>,
+
+    state: Cell<State>,
+    /// Tag of the current command, which the status must repeat.
+    tag: Cell<u32>,
+    /// Number of bytes of the data stage not transferred yet.
+    residue: Cell<usize>,
+    status: Cell<Status>,
+    /// Error of the last failed command, for REQUEST SENSE.
+    sense: Cell<Sense>,
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> MassStorage<'a, U> {
+    pub fn new(
+        controller: &'a U,
+        max_ctrl_packet_size: u8,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str; 3],
+        storage: &'a dyn NonvolatileStorage<'static>,
+        start_address: usize,
+        length: usize,
+        buffer: &'static mut [u8],
+    ) -> Self {
+        let interfaces: &mut [InterfaceDescriptor] = &mut [InterfaceDescriptor {
+            interface_number: 0,
+            interface_class: 0x08,    // Mass storage
+            interface_subclass: 0x06, // SCSI transparent command set
+            interface_protocol: 0x50, // Bulk-only transport
+            ..InterfaceDescriptor::default()
+        }];
+
+        let endpoints: &[&[EndpointDescriptor]] = &[&[
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_IN_NUM,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: PACKET_SIZE as u16,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new_const(
+                    ENDPOINT_OUT_NUM,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: PACKET_SIZE as u16,
+                interval: 0,
+            },
+        ]];
+
+        let (device_descriptor_buffer, other_descriptor_buffer) =
+            descriptors::create_descriptor_buffers(
+                descriptors::DeviceDescriptor {
+                    vendor_id: vendor_id,
+                    product_id: product_id,
+                    manufacturer_string: 1,
+                    product_string: 2,
+                    serial_number_string: 3,
+                    max_packet_size_ep0: max_ctrl_packet_size,
+                    ..descriptors::DeviceDescriptor::default()
+                },
+                descriptors::ConfigurationDescriptor {
+                    ..descriptors::ConfigurationDescriptor::default()
+                },
+                interfaces,
+                endpoints,
+                None, // No HID descriptor
+                None, // No CDC descriptor array
+            );
+
+        MassStorage {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor_buffer,
+                other_descriptor_buffer,
+                None, // No HID descriptor
+                None, // No report descriptor
+                LANGUAGES,
+                strings,
+            ),
+            buffers: [Buffer64::default(), Buffer64::default()],
+            storage: storage,
+            start_address: start_address,
+            block_count: length / BLOCK_SIZE,
+            block: TakeCell::new(buffer),
+            state: Cell::new(State::Cbw),
+            tag: Cell::new(0),
+            residue: Cell::new(0),
+            status: Cell::new(Status::Passed),
+            sense: Cell::new(Sense::NO_SENSE),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a U {
+        self.client_ctrl.controller()
+    }
+
+    #[inline]
+    fn buffer(&'a self, i: usize) -> &'a [VolatileCell<u8>; 64] {
+        &self.buffers[i - 1].buf
+    }
+
+    /// Whether the current state has something to send to the host.
+    fn sending(&self) -> bool {
+        match self.state.get() {
+            State::Response(_) | State::ZeroLengthPacket | State::ReadData { .. } | State::Csw => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The storage address of the block at `lba`, unless it doesn't fit in
+    /// a `usize`.
+    fn block_address(&self, lba: u32) -> Option<usize> {
+        (lba as usize)
+            .checked_mul(BLOCK_SIZE)
+            .and_then(|offset| self.start_address.checked_add(offset))
+    }
+
+    /// Ends the command with `sense`, skipping whatever is left of the data
+    /// stage.
+    fn fail(&'a self, cbw_data_in: bool, sense: Sense) {
+        self.sense.set(sense);
+        self.status.set(Status::Failed);
+        self.skip_data(cbw_data_in);
+    }
+
+    /// Moves on to the status stage once the rest of the data stage, if any,
+    /// has been skipped.
+    fn skip_data(&'a self, cbw_data_in: bool) {
+        if self.residue.get() == 0 {
+            self.state.set(State::Csw);
+        } else if cbw_data_in {
+            self.state.set(State::ZeroLengthPacket);
+        } else {
+            self.state.set(State::Discard);
+        }
+    }
+
+    /// Sends `data`, cut to what both the command and the host allow.
+    fn respond(&'a self, cbw: &CommandBlockWrapper, data: &[u8], allocation_length: usize) {
+        if cbw.data_length > 0 && !cbw.data_in {
+            self.status.set(Status::PhaseError);
+            self.skip_data(false);
+            return;
+        }
+        let len = cmp::min(data.len(), cmp::min(allocation_length, cbw.data_length));
+        if len == 0 {
+            self.skip_data(true);
+            return;
+        }
+        for (b, d) in self.buffer(ENDPOINT_IN_NUM).iter().zip(data[..len].iter()) {
+            b.set(*d);
+        }
+        self.state.set(State::Response(len));
+    }
+
+    /// Checks a READ or WRITE command and returns whether the data stage
+    /// should start.
+    fn check_transfer(
+        &'a self,
+        cbw: &CommandBlockWrapper,
+        data_in: bool,
+        lba: u32,
+        blocks: usize,
+    ) -> bool {
+        let in_range = (lba as usize)
+            .checked_add(blocks)
+            .map_or(false, |end| end <= self.block_count);
+        if !in_range {
+            self.fail(cbw.data_in, Sense::LBA_OUT_OF_RANGE);
+            false
+        } else if cbw.data_length != blocks * BLOCK_SIZE || (blocks > 0 && cbw.data_in != data_in) {
+            self.fail(cbw.data_in, Sense::INVALID_FIELD_IN_CDB);
+            false
+        } else if blocks == 0 {
+            self.state.set(State::Csw);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Starts reading the next block for a READ command.
+    fn read_block(&'a self, lba: u32, blocks: usize) {
+        let res = match (self.block_address(lba), self.block.take()) {
+            (Some(address), Some(buffer)) => self.storage.read(buffer, address, BLOCK_SIZE),
+            (None, buffer) => {
+                buffer.map(|buffer| self.block.replace(buffer));
+                ReturnCode::EINVAL
+            }
+            (_, None) => ReturnCode::EBUSY,
+        };
+        if res == ReturnCode::SUCCESS {
+            self.state.set(State::ReadBusy { lba, blocks });
+        } else {
+            self.fail(true, Sense::UNRECOVERED_READ_ERROR);
+        }
+    }
+
+    /// Handles a command from the host and sets up its data stage.
+    fn handle_command(&'a self, cbw: CommandBlockWrapper) {
+        self.tag.set(cbw.tag);
+        self.residue.set(cbw.data_length);
+        self.status.set(Status::Passed);
+
+        let mut data = [0; PACKET_SIZE];
+        match cbw.command {
+            Command::TestUnitReady
+            | Command::StartStopUnit
+            | Command::PreventAllowMediumRemoval
+            | Command::Verify10
+            | Command::SynchronizeCache10 => self.skip_data(cbw.data_in),
+            Command::RequestSense { allocation_length } => {
+                let len = scsi::write_sense(&mut data, self.sense.get());
+                self.sense.set(Sense::NO_SENSE);
+                self.respond(&cbw, &data[..len], allocation_length);
+            }
+            Command::Inquiry { allocation_length } => {
+                let len = scsi::write_inquiry(&mut data, "Tock", "Mass Storage", "1.0");
+                self.respond(&cbw, &data[..len], allocation_length);
+            }
+            Command::ModeSense6 { allocation_length } => {
+                let len = scsi::write_mode_sense6(&mut data, false);
+                self.respond(&cbw, &data[..len], allocation_length);
+            }
+            Command::ReadFormatCapacities { allocation_length } => {
+                let len = scsi::write_format_capacities(
+                    &mut data,
+                    self.block_count as u32,
+                    BLOCK_SIZE as u32,
+                );
+                self.respond(&cbw, &data[..len], allocation_length);
+            }
+            Command::ReadCapacity10 => {
+                let len = scsi::write_read_capacity(
+                    &mut data,
+                    self.block_count as u32,
+                    BLOCK_SIZE as u32,
+                );
+                self.respond(&cbw, &data[..len], len);
+            }
+            Command::Read10 { lba, blocks } => {
+                if self.check_transfer(&cbw, true, lba, blocks) {
+                    self.read_block(lba, blocks);
+                }
+            }
+            Command::Write10 { lba, blocks } => {
+                if self.check_transfer(&cbw, false, lba, blocks) {
+                    self.state.set(State::WriteData {
+                        lba,
+                        blocks,
+                        offset: 0,
+                    });
+                }
+            }
+            Command::Unsupported(_) => self.fail(cbw.data_in, Sense::INVALID_COMMAND),
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> hil::usb::Client<'a> for MassStorage<'a, U> {
+    fn enable(&'a self) {
+        // Set up the default control endpoint
+        self.client_ctrl.enable();
+
+        // Setup buffers for IN and OUT data transfer.
+        self.controller()
+            .endpoint_set_in_buffer(ENDPOINT_IN_NUM, self.buffer(ENDPOINT_IN_NUM));
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, ENDPOINT_IN_NUM);
+
+        self.controller()
+            .endpoint_set_out_buffer(ENDPOINT_OUT_NUM, self.buffer(ENDPOINT_OUT_NUM));
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, ENDPOINT_OUT_NUM);
+    }
+
+    fn attach(&'a self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&'a self) {
+        self.state.set(State::Cbw);
+    }
+
+    /// Handle a Control Setup transaction.
+    ///
+    /// The bulk-only transport has two class requests: GET MAX LUN, which we
+    /// answer ourselves, and the reset, which aborts the current command.
+    fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
+        let class_request = descriptors::SetupData::get(&self.client_ctrl.ctrl_buffer.buf)
+            .filter(|setup_data| match setup_data.request_type.request_type() {
+                RequestType::Class => true,
+                _ => false,
+            })
+            .map(|setup_data| (setup_data.request_code, setup_data.length));
+
+        match class_request {
+            Some((GET_MAX_LUN, length)) => {
+                // A single logical unit
+                self.client_ctrl.ctrl_setup_in_data(endpoint, &[0], length)
+            }
+            Some((BULK_ONLY_RESET, _)) => {
+                self.state.set(State::Cbw);
+                self.client_ctrl.ctrl_setup(endpoint)
+            }
+            _ => self.client_ctrl.ctrl_setup(endpoint),
+        }
+    }
+
+    /// Handle a Control In transaction
+    fn ctrl_in(&'a self, endpoint: usize) -> hil::usb::CtrlInResult {
+        self.client_ctrl.ctrl_in(endpoint)
+    }
+
+    /// Handle a Control Out transaction
+    fn ctrl_out(&'a self, endpoint: usize, packet_bytes: u32) -> hil::usb::CtrlOutResult {
+        self.client_ctrl.ctrl_out(endpoint, packet_bytes)
+    }
+
+    fn ctrl_status(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status(endpoint)
+    }
+
+    /// Handle the completion of a Control transfer
+    fn ctrl_status_complete(&'a self, endpoint: usize) {
+        self.client_ctrl.ctrl_status_complete(endpoint)
+    }
+
+    /// Handle a Bulk IN transaction.
+    ///
+    /// This sends one packet of the data stage or the status, and is called
+    /// again from `packet_transmitted` until there is nothing left to send.
+    fn packet_in(&'a self, transfer_type: TransferType, endpoint: usize) -> hil::usb::InResult {
+        if let TransferType::Control | TransferType::Isochronous | TransferType::Interrupt =
+            transfer_type
+        {
+            return hil::usb::InResult::Delay;
+        }
+        let packet = self.buffer(endpoint);
+        match self.state.get() {
+            State::Response(len) => {
+                // The response is already in the endpoint buffer
+                self.residue.set(self.residue.get() - len);
+                self.skip_data(true);
+                hil::usb::InResult::Packet(len)
+            }
+            State::ZeroLengthPacket => {
+                self.state.set(State::Csw);
+                hil::usb::InResult::Packet(0)
+            }
+            State::ReadData {
+                lba,
+                blocks,
+                offset,
+            } => {
+                let len = cmp::min(PACKET_SIZE, BLOCK_SIZE - offset);
+                let copied = self.block.map(|block| {
+                    for (p, b) in packet.iter().zip(block[offset..offset + len].iter()) {
+                        p.set(*b);
+                    }
+                });
+                if copied.is_none() {
+                    return hil::usb::InResult::Delay;
+                }
+                self.residue.set(self.residue.get() - len);
+                if offset + len < BLOCK_SIZE {
+                    self.state.set(State::ReadData {
+                        lba,
+                        blocks,
+                        offset: offset + len,
+                    });
+                } else if blocks > 1 {
+                    // The packet is already copied, so the buffer is free
+                    self.read_block(lba + 1, blocks - 1);
+                } else {
+                    self.state.set(State::Csw);
+                }
+                hil::usb::InResult::Packet(len)
+            }
+            State::Csw => {
+                let mut csw = [0; scsi::CSW_LEN];
+                scsi::write_csw(
+                    &mut csw,
+                    self.tag.get(),
+                    self.residue.get(),
+                    self.status.get(),
+                );
+                for (p, b) in packet.iter().zip(csw.iter()) {
+                    p.set(*b);
+                }
+                self.state.set(State::Cbw);
+                hil::usb::InResult::Packet(scsi::CSW_LEN)
+            }
+            _ => hil::usb::InResult::Delay,
+        }
+    }
+
+    /// Handle a Bulk OUT transaction
+    fn packet_out(
+        &'a self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> hil::usb::OutResult {
+        if let TransferType::Control | TransferType::Isochronous | TransferType::Interrupt =
+            transfer_type
+        {
+            return hil::usb::OutResult::Ok;
+        }
+        let packet = self.buffer(endpoint);
+        let packet_bytes = cmp::min(packet_bytes as usize, PACKET_SIZE);
+        match self.state.get() {
+            State::Cbw => {
+                let mut bytes = [0; PACKET_SIZE];
+                for (b, p) in bytes.iter_mut().zip(packet.iter()) {
+                    *b = p.get();
+                }
+                match CommandBlockWrapper::parse(&bytes[..packet_bytes]) {
+                    Some(cbw) => {
+                        self.handle_command(cbw);
+                        if self.sending() {
+                            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                        }
+                        hil::usb::OutResult::Ok
+                    }
+                    // Invalid commands stall the endpoint until the host
+                    // resets the transport.
+                    None => hil::usb::OutResult::Error,
+                }
+            }
+            State::WriteData {
+                lba,
+                blocks,
+                offset,
+            } => self
+                .block
+                .take()
+                .map_or(hil::usb::OutResult::Delay, |block| {
+                    let len = cmp::min(packet_bytes, BLOCK_SIZE - offset);
+                    for (b, p) in block[offset..offset + len].iter_mut().zip(packet.iter()) {
+                        *b = p.get();
+                    }
+                    self.residue.set(self.residue.get() - len);
+                    if offset + len < BLOCK_SIZE {
+                        self.block.replace(block);
+                        self.state.set(State::WriteData {
+                            lba,
+                            blocks,
+                            offset: offset + len,
+                        });
+                    } else {
+                        let res = match self.block_address(lba) {
+                            Some(address) => self.storage.write(block, address, BLOCK_SIZE),
+                            None => {
+                                self.block.replace(block);
+                                ReturnCode::EINVAL
+                            }
+                        };
+                        if res == ReturnCode::SUCCESS {
+                            self.state.set(State::WriteBusy { lba, blocks });
+                        } else {
+                            self.fail(false, Sense::WRITE_ERROR);
+                            if self.sending() {
+                                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                            }
+                        }
+                    }
+                    hil::usb::OutResult::Ok
+                }),
+            // Wait for the storage, `write_done` resumes the endpoint
+            State::WriteBusy { .. } => hil::usb::OutResult::Delay,
+            State::Discard => {
+                let residue = self.residue.get().saturating_sub(packet_bytes);
+                self.residue.set(residue);
+                if residue == 0 {
+                    self.state.set(State::Csw);
+                    self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+                }
+                hil::usb::OutResult::Ok
+            }
+            // The host should not send data now
+            _ => hil::usb::OutResult::Error,
+        }
+    }
+
+    fn packet_transmitted(&'a self, _endpoint: usize) {
+        if self.sending() {
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        }
+    }
+}
+
+impl<'a, U: hil::usb::UsbController<'a>> NonvolatileStorageClient<'static> for MassStorage<'a, U> {
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.block.replace(buffer);
+        if let State::ReadBusy { lba, blocks } = self.state.get() {
+            self.state.set(State::ReadData {
+                lba,
+                blocks,
+                offset: 0,
+            });
+            self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        self.block.replace(buffer);
+        if let State::WriteBusy { lba, blocks } = self.state.get() {
+            if blocks > 1 {
+                self.state.set(State::WriteData {
+                    lba: lba + 1,
+                    blocks: blocks - 1,
+                    offset: 0,
+                });
+                self.controller().endpoint_resume_out(ENDPOINT_OUT_NUM);
+            } else {
+                self.state.set(State::Csw);
+                self.controller().endpoint_resume_in(ENDPOINT_IN_NUM);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::nonvolatile_to_pages::NonvolatileToPages;
+    use crate::test_util::{leak, Medium, Page, SimFlash, SimUsb, WriteMode, PAGE_SIZE};
+    use kernel::hil::usb::{Client as _, OutResult, UsbController};
+    use std::vec::Vec;
+
+    const BLOCKS: usize = 8;
+
+    struct Host {
+        usb: &'static SimUsb<'static>,
+        flash: &'static SimFlash,
+        medium: &'static Medium,
+    }
+
+    impl Host {
+        /// A drive of `BLOCKS` blocks, over the start of a flash.
+        fn new() -> Host {
+            let medium = Medium::new(BLOCKS * BLOCK_SIZE / PAGE_SIZE);
+            let flash = SimFlash::new(medium, WriteMode::Replace);
+            let storage = leak(NonvolatileToPages::new(flash, leak(Page::default())));
+            flash.set_client(storage);
+            let usb = leak(SimUsb::new());
+            let msc = leak(MassStorage::new(
+                &*usb,
+                64,
+                0x6667,
+                0xabce,
+                &["Tock", "Mass Storage", "0"],
+                storage,
+                0,
+                BLOCKS * BLOCK_SIZE,
+                leak([0; BLOCK_SIZE]),
+            ));
+            storage.set_client(msc);
+            usb.set_client(msc);
+            msc.enable();
+            Host {
+                usb: usb,
+                flash: flash,
+                medium: medium,
+            }
+        }
+
+        /// Sends a CBW, and reports whether the drive took it.
+        fn send_cbw(&self, tag: u32, length: usize, data_in: bool, cdb: &[u8]) -> bool {
+            let mut packet = [0; scsi::CBW_LEN];
+            packet[0..4].copy_from_slice(b"USBC");
+            packet[4..8].copy_from_slice(&tag.to_le_bytes());
+            packet[8..12].copy_from_slice(&(length as u32).to_le_bytes());
+            packet[12] = if data_in { 0x80 } else { 0x00 };
+            packet[14] = cdb.len() as u8;
+            packet[15..15 + cdb.len()].copy_from_slice(cdb);
+            match self.usb.bulk_out(ENDPOINT_OUT_NUM, &packet) {
+                OutResult::Ok => true,
+                _ => false,
+            }
+        }
+
+        fn command(&self, tag: u32, length: usize, data_in: bool, cdb: &[u8]) {
+            assert!(self.send_cbw(tag, length, data_in, cdb));
+        }
+
+        /// Sends the data stage, waiting for the storage when the drive
+        /// delays a packet.
+        fn send(&self, data: &[u8]) {
+            for packet in data.chunks(PACKET_SIZE) {
+                loop {
+                    match self.usb.bulk_out(ENDPOINT_OUT_NUM, packet) {
+                        OutResult::Ok => break,
+                        OutResult::Delay => assert!(self.flash.step()),
+                        OutResult::Error => panic!("packet refused"),
+                    }
+                }
+            }
+        }
+
+        /// Receives packets until the status, which is returned as the tag,
+        /// residue and status, with the data before it.
+        fn receive(&self) -> (Vec<u8>, (u32, u32, u8)) {
+            let mut data = Vec::new();
+            loop {
+                match self.usb.bulk_in(ENDPOINT_IN_NUM) {
+                    Some(packet) if packet.len() == scsi::CSW_LEN && packet[..4] == *b"USBS" => {
+                        let word = |i: usize| {
+                            u32::from_le_bytes([
+                                packet[i],
+                                packet[i + 1],
+                                packet[i + 2],
+                                packet[i + 3],
+                            ])
+                        };
+                        return (data, (word(4), word(8), packet[12]));
+                    }
+                    Some(packet) => data.extend(packet),
+                    None => assert!(self.flash.step(), "the drive stopped"),
+                }
+            }
+        }
+
+        fn sense(&self) -> (u8, u8) {
+            self.command(99, 18, true, &[0x03, 0, 0, 0, 18, 0]);
+            let (data, status) = self.receive();
+            assert_eq!(status, (99, 0, 0));
+            (data[2], data[12])
+        }
+    }
+
+    fn read10(lba: u32, blocks: u16) -> [u8; 10] {
+        let lba = lba.to_be_bytes();
+        let blocks = blocks.to_be_bytes();
+        [
+            0x28, 0, lba[0], lba[1], lba[2], lba[3], 0, blocks[0], blocks[1], 0,
+        ]
+    }
+
+    fn write10(lba: u32, blocks: u16) -> [u8; 10] {
+        let mut cdb = read10(lba, blocks);
+        cdb[0] = 0x2a;
+        cdb
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        let host = Host::new();
+
+        // Packets that aren't a CBW stall the endpoint.
+        match host.usb.bulk_out(ENDPOINT_OUT_NUM, &[0; scsi::CBW_LEN]) {
+            OutResult::Error => {}
+            _ => panic!("not stalled"),
+        }
+        assert!(!host.send_cbw(1, 0, false, &[]));
+
+        host.command(2, 0, false, &[0x00, 0, 0, 0, 0, 0]);
+        assert_eq!(host.receive(), (Vec::new(), (2, 0, 0)));
+
+        // READ CAPACITY gives the last block and the block size.
+        host.command(3, 8, true, &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            host.receive(),
+            (std::vec![0, 0, 0, 7, 0, 0, 2, 0], (3, 0, 0))
+        );
+
+        host.command(4, 0, false, &[0xff, 0, 0, 0, 0, 0]);
+        assert_eq!(host.receive(), (Vec::new(), (4, 0, 1)));
+        assert_eq!(host.sense(), (0x05, 0x20));
+        assert_eq!(host.sense(), (0x00, 0x00));
+    }
+
+    #[test]
+    fn blocks_are_read_and_written() {
+        let host = Host::new();
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| (i / 3) as u8).collect();
+        host.command(5, 2 * BLOCK_SIZE, false, &write10(6, 2));
+        host.send(&data);
+        assert_eq!(host.receive(), (Vec::new(), (5, 0, 0)));
+        assert_eq!(host.medium.data.borrow()[6 * BLOCK_SIZE..], data[..]);
+
+        host.command(6, 3 * BLOCK_SIZE, true, &read10(5, 3));
+        let (read, status) = host.receive();
+        assert_eq!(status, (6, 0, 0));
+        assert_eq!(read[..BLOCK_SIZE], [0xff; BLOCK_SIZE][..]);
+        assert_eq!(read[BLOCK_SIZE..], data[..]);
+    }
+
+    #[test]
+    fn out_of_range_blocks_are_refused() {
+        let host = Host::new();
+        for &(lba, blocks) in [(7, 2), (8, 1), (u32::MAX, 1), (u32::MAX, 0xffff)].iter() {
+            let length = blocks as usize * BLOCK_SIZE;
+            host.command(7, length, true, &read10(lba, blocks));
+            // An empty packet ends the data stage early.
+            assert_eq!(host.receive(), (Vec::new(), (7, length as u32, 1)));
+            assert_eq!(host.sense(), (0x05, 0x21));
+        }
+        assert_eq!(host.flash.started.borrow().len(), 0);
+    }
+
+    #[test]
+    fn data_goes_the_way_of_the_command() {
+        let host = Host::new();
+
+        // A READ whose data stage goes to the device fails once the data
+        // sent is dropped.
+        host.command(8, BLOCK_SIZE, false, &read10(0, 1));
+        host.send(&[0x42; BLOCK_SIZE]);
+        assert_eq!(host.receive().1, (8, 0, 1));
+        assert_eq!(host.sense(), (0x05, 0x24));
+
+        // So does a WRITE whose data stage goes to the host.
+        host.command(9, BLOCK_SIZE, true, &write10(0, 1));
+        assert_eq!(host.receive(), (Vec::new(), (9, BLOCK_SIZE as u32, 1)));
+        assert_eq!(host.sense(), (0x05, 0x24));
+
+        // And a transfer of another length than the blocks.
+        host.command(10, 100, true, &read10(0, 1));
+        assert_eq!(host.receive(), (Vec::new(), (10, 100, 1)));
+        assert_eq!(host.sense(), (0x05, 0x24));
+
+        assert_eq!(host.flash.started.borrow().len(), 0);
+        assert_eq!(
+            host.medium.data.borrow()[..BLOCK_SIZE],
+            [0xff; BLOCK_SIZE][..]
+        );
+    }
+}
@@ -0,0 +1,335 @@
+//! SCSI commands for the USB mass storage class.
+//!
+//! This parses the command block wrappers of the bulk-only transport and the
+//! subset of the SCSI transparent command set that hosts use with a simple
+//! block device, and builds the responses and command status wrappers. It
+//! has no state and does no I/O, which is left to `msc::MassStorage`.
+
+/// Length of a command block wrapper (CBW).
+pub const CBW_LEN: usize = 31;
+/// Length of a command status wrapper (CSW).
+pub const CSW_LEN: usize = 13;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+
+/// A command from the host, as sent in a CBW.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CommandBlockWrapper {
+    pub tag: u32,
+    /// Number of bytes the host expects to transfer in the data stage.
+    pub data_length: usize,
+    /// Whether the data stage goes from the device to the host.
+    pub data_in: bool,
+    pub lun: u8,
+    pub command: Command,
+}
+
+impl CommandBlockWrapper {
+    /// Parses a CBW packet, returning `None` if it is not a valid CBW.
+    pub fn parse(packet: &[u8]) -> Option<CommandBlockWrapper> {
+        if packet.len() != CBW_LEN || get_u32_le(&packet[0..4]) != CBW_SIGNATURE {
+            return None;
+        }
+        let command_length = packet[14] as usize & 0x1f;
+        if command_length == 0 || command_length > 16 {
+            return None;
+        }
+        Some(CommandBlockWrapper {
+            tag: get_u32_le(&packet[4..8]),
+            data_length: get_u32_le(&packet[8..12]) as usize,
+            data_in: packet[12] & 0x80 != 0,
+            lun: packet[13] & 0x0f,
+            command: Command::parse(&packet[15..15 + command_length]),
+        })
+    }
+}
+
+/// The supported SCSI commands.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    TestUnitReady,
+    RequestSense {
+        allocation_length: usize,
+    },
+    Inquiry {
+        allocation_length: usize,
+    },
+    ModeSense6 {
+        allocation_length: usize,
+    },
+    StartStopUnit,
+    PreventAllowMediumRemoval,
+    ReadFormatCapacities {
+        allocation_length: usize,
+    },
+    ReadCapacity10,
+    Read10 {
+        lba: u32,
+        blocks: usize,
+    },
+    Write10 {
+        lba: u32,
+        blocks: usize,
+    },
+    Verify10,
+    SynchronizeCache10,
+    /// An unsupported command, or one with fields we don't support
+    Unsupported(u8),
+}
+
+impl Command {
+    /// Parses a command descriptor block.
+    pub fn parse(cdb: &[u8]) -> Command {
+        let opcode = cdb[0];
+        let byte = |i: usize| cdb.get(i).map_or(0, |b| *b);
+        let be16 = |i: usize| (byte(i) as usize) << 8 | byte(i + 1) as usize;
+        let lba = (byte(2) as u32) << 24
+            | (byte(3) as u32) << 16
+            | (byte(4) as u32) << 8
+            | byte(5) as u32;
+        match opcode {
+            0x00 => Command::TestUnitReady,
+            0x03 => Command::RequestSense {
+                allocation_length: byte(4) as usize,
+            },
+            // Vital product data pages are not supported
+            0x12 if byte(1) & 0x01 == 0 => Command::Inquiry {
+                allocation_length: be16(3),
+            },
+            0x1a => Command::ModeSense6 {
+                allocation_length: byte(4) as usize,
+            },
+            0x1b => Command::StartStopUnit,
+            0x1e => Command::PreventAllowMediumRemoval,
+            0x23 => Command::ReadFormatCapacities {
+                allocation_length: be16(7),
+            },
+            0x25 => Command::ReadCapacity10,
+            0x28 => Command::Read10 {
+                lba: lba,
+                blocks: be16(7),
+            },
+            0x2a => Command::Write10 {
+                lba: lba,
+                blocks: be16(7),
+            },
+            0x2f => Command::Verify10,
+            0x35 => Command::SynchronizeCache10,
+            _ => Command::Unsupported(opcode),
+        }
+    }
+
+    /// Whether the command has a data stage from the host to the device.
+    pub fn is_data_out(&self) -> bool {
+        match self {
+            Command::Write10 { blocks, .. } => *blocks > 0,
+            _ => false,
+        }
+    }
+}
+
+/// Result of a command, as reported in the CSW.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Status {
+    Passed = 0,
+    Failed = 1,
+    PhaseError = 2,
+}
+
+/// Writes a CSW into `buf` and returns its length.
+pub fn write_csw(buf: &mut [u8], tag: u32, residue: usize, status: Status) -> usize {
+    buf[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+    buf[4..8].copy_from_slice(&tag.to_le_bytes());
+    buf[8..12].copy_from_slice(&(residue as u32).to_le_bytes());
+    buf[12] = status as u8;
+    CSW_LEN
+}
+
+/// Sense key and additional sense code of the last failed command.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Sense {
+    pub key: u8,
+    pub asc: u8,
+}
+
+impl Sense {
+    pub const NO_SENSE: Sense = Sense {
+        key: 0x00,
+        asc: 0x00,
+    };
+    pub const UNRECOVERED_READ_ERROR: Sense = Sense {
+        key: 0x03,
+        asc: 0x11,
+    };
+    pub const WRITE_ERROR: Sense = Sense {
+        key: 0x03,
+        asc: 0x0c,
+    };
+    pub const INVALID_COMMAND: Sense = Sense {
+        key: 0x05,
+        asc: 0x20,
+    };
+    pub const LBA_OUT_OF_RANGE: Sense = Sense {
+        key: 0x05,
+        asc: 0x21,
+    };
+    pub const INVALID_FIELD_IN_CDB: Sense = Sense {
+        key: 0x05,
+        asc: 0x24,
+    };
+}
+
+/// Writes fixed format sense data and returns its length.
+pub fn write_sense(buf: &mut [u8], sense: Sense) -> usize {
+    let data = &mut buf[..18];
+    data.iter_mut().for_each(|b| *b = 0);
+    data[0] = 0x70; // Current error, fixed format
+    data[2] = sense.key;
+    data[7] = 10; // Additional sense length
+    data[12] = sense.asc;
+    data.len()
+}
+
+/// Writes standard inquiry data for a removable direct access block device
+/// and returns its length. The strings are padded with spaces or cut to 8,
+/// 16 and 4 bytes.
+pub fn write_inquiry(buf: &mut [u8], vendor: &str, product: &str, revision: &str) -> usize {
+    let data = &mut buf[..36];
+    data.iter_mut().for_each(|b| *b = b' ');
+    data[0] = 0x00; // Direct access block device
+    data[1] = 0x80; // Removable
+    data[2] = 0x04; // SPC-2
+    data[3] = 0x02; // Response data format
+    data[4] = 31; // Additional length
+    data[5] = 0;
+    data[6] = 0;
+    data[7] = 0;
+    for (field, s) in [(8..16, vendor), (16..32, product), (32..36, revision)].iter() {
+        for (b, c) in data[field.clone()].iter_mut().zip(s.bytes()) {
+            *b = c;
+        }
+    }
+    data.len()
+}
+
+/// Writes READ CAPACITY (10) data and returns its length.
+pub fn write_read_capacity(buf: &mut [u8], block_count: u32, block_size: u32) -> usize {
+    // The address of the last block
+    buf[0..4].copy_from_slice(&block_count.saturating_sub(1).to_be_bytes());
+    buf[4..8].copy_from_slice(&block_size.to_be_bytes());
+    8
+}
+
+/// Writes the capacity list of READ FORMAT CAPACITIES and returns its
+/// length.
+pub fn write_format_capacities(buf: &mut [u8], block_count: u32, block_size: u32) -> usize {
+    buf[0..4].copy_from_slice(&[0, 0, 0, 8]);
+    buf[4..8].copy_from_slice(&block_count.to_be_bytes());
+    // Formatted media, then the block size in 3 bytes
+    buf[8..12].copy_from_slice(&(0x0200_0000 | (block_size & 0x00ff_ffff)).to_be_bytes());
+    12
+}
+
+/// Writes a MODE SENSE (6) header without mode pages and returns its
+/// length.
+pub fn write_mode_sense6(buf: &mut [u8], write_protected: bool) -> usize {
+    buf[0] = 3; // Mode data length
+    buf[1] = 0; // Medium type
+    buf[2] = if write_protected { 0x80 } else { 0x00 };
+    buf[3] = 0; // Block descriptor length
+    4
+}
+
+fn get_u32_le(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cbw(tag: u32, length: u32, data_in: bool, cdb: &[u8]) -> [u8; CBW_LEN] {
+        let mut packet = [0; CBW_LEN];
+        packet[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        packet[4..8].copy_from_slice(&tag.to_le_bytes());
+        packet[8..12].copy_from_slice(&length.to_le_bytes());
+        packet[12] = if data_in { 0x80 } else { 0x00 };
+        packet[14] = cdb.len() as u8;
+        packet[15..15 + cdb.len()].copy_from_slice(cdb);
+        packet
+    }
+
+    #[test]
+    fn parse_read10() {
+        let packet = cbw(
+            0x1234,
+            4096,
+            true,
+            &[0x28, 0, 0x00, 0x01, 0x02, 0x03, 0, 0x00, 0x08, 0],
+        );
+        assert_eq!(
+            CommandBlockWrapper::parse(&packet),
+            Some(CommandBlockWrapper {
+                tag: 0x1234,
+                data_length: 4096,
+                data_in: true,
+                lun: 0,
+                command: Command::Read10 {
+                    lba: 0x0001_0203,
+                    blocks: 8
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(
+            Command::parse(&[0x12, 0, 0, 0, 36, 0]),
+            Command::Inquiry {
+                allocation_length: 36
+            }
+        );
+        // Vital product data
+        assert_eq!(
+            Command::parse(&[0x12, 1, 0x80, 0, 36, 0]),
+            Command::Unsupported(0x12)
+        );
+        assert_eq!(
+            Command::parse(&[0x2a, 0, 0, 0, 0, 9, 0, 0, 1, 0]),
+            Command::Write10 { lba: 9, blocks: 1 }
+        );
+        assert!(Command::parse(&[0x2a, 0, 0, 0, 0, 9, 0, 0, 1, 0]).is_data_out());
+        assert_eq!(Command::parse(&[0xa0]), Command::Unsupported(0xa0));
+    }
+
+    #[test]
+    fn reject_invalid_cbw() {
+        let mut packet = cbw(1, 0, false, &[0, 0, 0, 0, 0, 0]);
+        assert!(CommandBlockWrapper::parse(&packet[..30]).is_none());
+        packet[0] = 0;
+        assert!(CommandBlockWrapper::parse(&packet).is_none());
+    }
+
+    #[test]
+    fn responses() {
+        let mut buf = [0; 64];
+        assert_eq!(write_csw(&mut buf, 0x1234, 7, Status::Failed), CSW_LEN);
+        assert_eq!(
+            buf[..CSW_LEN],
+            [0x55, 0x53, 0x42, 0x53, 0x34, 0x12, 0, 0, 7, 0, 0, 0, 1]
+        );
+
+        assert_eq!(write_read_capacity(&mut buf, 128, 512), 8);
+        assert_eq!(buf[..8], [0, 0, 0, 127, 0, 0, 2, 0]);
+
+        assert_eq!(write_inquiry(&mut buf, "Tock", "Storage", "1"), 36);
+        assert_eq!(buf[1], 0x80);
+        assert_eq!(&buf[8..16], b"Tock    ");
+        assert_eq!(&buf[32..36], b"1   ");
+
+        assert_eq!(write_sense(&mut buf, Sense::LBA_OUT_OF_RANGE), 18);
+        assert_eq!((buf[0], buf[2], buf[12]), (0x70, 0x05, 0x21));
+    }
+}
@@ -184,6 +184,37 @@ impl<'a, 'b, U: hil::usb::UsbController<'a>> ClientCtrl<'a, 'b, U> {
         self.controller.attach();
     }
 
+    /// Overwrite part of the configuration descriptor buffer, starting at
+    /// `offset`. This is for class descriptors that are only known once the
+    /// device is about to attach.
+    pub fn update_other_descriptor(&self, offset: usize, data: &[u8]) {
+        for (b, d) in self.other_descriptor_buffer.buf[offset..]
+            .iter()
+            .zip(data.iter())
+        {
+            b.set(*d);
+        }
+    }
+
+    /// Answer a class or vendor Control In request with `data`, for clients
+    /// that handle such a request themselves instead of passing it to
+    /// `ctrl_setup`. At most `requested_length` bytes are sent.
+    pub fn ctrl_setup_in_data(
+        &'a self,
+        endpoint: usize,
+        data: &[u8],
+        requested_length: u16,
+    ) -> hil::usb::CtrlSetupResult {
+        let buf = self.descriptor_buf();
+        let len = min(data.len(), buf.len());
+        for (b, d) in buf.iter().zip(data[..len].iter()) {
+            b.set(*d);
+        }
+        let end = min(len, requested_length as usize);
+        self.state[endpoint].set(State::CtrlIn(0, end));
+        hil::usb::CtrlSetupResult::Ok
+    }
+
     /// Handle a Control Setup transaction
     pub fn ctrl_setup(&'a self, endpoint: usize) -> hil::usb::CtrlSetupResult {
         if endpoint != 0 {
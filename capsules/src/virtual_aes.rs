@@ -0,0 +1,799 @@
+//! Virtualize the AES128 interface to enable multiple users of an underlying
+//! AES engine.
+//!
+//! `MuxAES` queues the requests of its `VirtualAES128` users and runs them on
+//! the engine one at a time. Each user keeps its own key, IV and mode, which
+//! are loaded into the engine before each of its requests, so users can
+//! interleave their messages freely.
+//!
+//! To continue a message across `crypt()` calls, a user also keeps the
+//! chaining value of its message: the last ciphertext block for CBC and the
+//! next counter block for CTR. Every request is started on the engine as a
+//! new message from that value, which gives the same result as continuing
+//! it. Counters are incremented as a 128 bit big endian integer.
+//!
+//! A user's `set_client()` registers it with the mux, so every user must
+//! set a client before calling `crypt()`.
+//!
+//! If the engine refuses a request that was queued, the user's client gets
+//! the error and its untouched buffers in `crypt_done()`, from a deferred
+//! call.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # use kernel::static_init;
+//!
+//! let mux_aes = static_init!(
+//!     capsules::virtual_aes::MuxAES<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::virtual_aes::MuxAES::new(&sam4l::aes::AES, dynamic_deferred_caller)
+//! );
+//! sam4l::aes::AES.set_client(mux_aes);
+//! mux_aes.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(mux_aes)
+//!         .expect("no deferred call slot available for the AES mux"),
+//! );
+//!
+//! let aes_user = static_init!(
+//!     capsules::virtual_aes::VirtualAES128<'static, sam4l::aes::Aes<'static>>,
+//!     capsules::virtual_aes::VirtualAES128::new(mux_aes)
+//! );
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
+use kernel::common::{List, ListLink, ListNode};
+use kernel::hil::symmetric_encryption;
+use kernel::hil::symmetric_encryption::{
+    AES128Ctr, AES128, AES128CBC, AES128ECB, AES128_BLOCK_SIZE, AES128_KEY_SIZE,
+};
+use kernel::ReturnCode;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Mode {
+    Ecb,
+    Cbc,
+    Ctr,
+}
+
+/// Keeps the list of users of the AES engine and serializes their requests.
+/// After each completed request the list is checked to see if there is
+/// another user with a pending request.
+pub struct MuxAES<'a, A: AES128<'a>> {
+    aes: &'a A,
+    users: List<'a, VirtualAES128<'a, A>>,
+    inflight: OptionalCell<&'a VirtualAES128<'a, A>>,
+    enabled: Cell<usize>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
+}
+
+impl<'a, A: AES128<'a>> MuxAES<'a, A> {
+    pub const fn new(aes: &'a A, deferred_caller: &'a DynamicDeferredCall) -> MuxAES<'a, A> {
+        MuxAES {
+            aes: aes,
+            users: List::new(),
+            inflight: OptionalCell::empty(),
+            enabled: Cell::new(0),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    /// Initializes the handle of the deferred call that reports refused
+    /// requests.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// Loads the configuration of `user` into the engine and starts its
+    /// request.
+    fn start(
+        &self,
+        user: &'a VirtualAES128<'a, A>,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        let res = self.aes.set_key(&user.key.get());
+        if res != ReturnCode::SUCCESS {
+            return Some((res, source, dest));
+        }
+        if user.mode.get() != Mode::Ecb {
+            let res = self.aes.set_iv(&user.chain.get());
+            if res != ReturnCode::SUCCESS {
+                return Some((res, source, dest));
+            }
+        }
+        let encrypting = user.encrypting.get();
+        user.set_mode.map(|set_mode| set_mode(self.aes, encrypting));
+        self.aes.start_message();
+
+        // Decrypting CBC in place overwrites the last ciphertext block, which
+        // is the chaining value for the next request
+        if user.mode.get() == Mode::Cbc && !encrypting && stop_index > start_index {
+            let last = match source {
+                Some(ref source) => &source[source.len() - AES128_BLOCK_SIZE..],
+                None => &dest[stop_index - AES128_BLOCK_SIZE..stop_index],
+            };
+            let mut block = [0; AES128_BLOCK_SIZE];
+            block.copy_from_slice(last);
+            user.saved_block.set(block);
+        }
+        user.range.set((start_index, stop_index));
+
+        self.inflight.set(user);
+        let res = self.aes.crypt(source, dest, start_index, stop_index);
+        if res.is_some() {
+            self.inflight.clear();
+        }
+        res
+    }
+
+    /// Scan the list of users and find the first user that has a pending
+    /// request, then issue that request to the engine.
+    fn do_next_op(&self) {
+        while self.inflight.is_none() {
+            let mnode = self.users.iter().find(|node| node.pending.get());
+            match mnode {
+                None => break,
+                Some(node) => {
+                    node.pending.set(false);
+                    let (start_index, stop_index) = node.range.get();
+                    if let Some(dest) = node.dest.take() {
+                        let source = node.source.take();
+                        if let Some((res, source, dest)) =
+                            self.start(node, source, dest, start_index, stop_index)
+                        {
+                            // The user's crypt() has already returned, so
+                            // report the error from a deferred call.
+                            if let Some(source) = source {
+                                node.source.replace(source);
+                            }
+                            node.dest.replace(dest);
+                            node.refused.set(Some(res));
+                            self.handle.map(|handle| self.deferred_caller.set(*handle));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, A: AES128<'a>> symmetric_encryption::Client<'a> for MuxAES<'a, A> {
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8], result: ReturnCode) {
+        self.inflight.take().map(move |user| {
+            user.crypt_done(source, dest, result);
+        });
+        self.do_next_op();
+    }
+}
+
+impl<'a, A: AES128<'a>> DynamicDeferredCallClient for MuxAES<'a, A> {
+    /// Returns the refused requests to their users.
+    fn call(&self, _handle: DeferredCallHandle) {
+        for user in self.users.iter() {
+            if let Some(res) = user.refused.take() {
+                let source = user.source.take();
+                user.dest.take().map(|dest| {
+                    user.client
+                        .map(move |client| client.crypt_done(source, dest, res));
+                });
+            }
+        }
+    }
+}
+
+/// Keeps the state of one user of the AES engine. It implements the same
+/// modes as the underlying engine.
+pub struct VirtualAES128<'a, A: AES128<'a>> {
+    mux: &'a MuxAES<'a, A>,
+    next: ListLink<'a, VirtualAES128<'a, A>>,
+    client: OptionalCell<&'a dyn symmetric_encryption::Client<'a>>,
+
+    enabled: Cell<bool>,
+    key: Cell<[u8; AES128_KEY_SIZE]>,
+    iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+    /// The IV or counter to continue the current message with
+    chain: Cell<[u8; AES128_BLOCK_SIZE]>,
+    mode: Cell<Mode>,
+    set_mode: OptionalCell<fn(&A, bool)>,
+    encrypting: Cell<bool>,
+
+    pending: Cell<bool>,
+    /// The error for a queued request the engine refused, until it is reported
+    refused: Cell<Option<ReturnCode>>,
+    source: TakeCell<'a, [u8]>,
+    dest: TakeCell<'a, [u8]>,
+    range: Cell<(usize, usize)>,
+    saved_block: Cell<[u8; AES128_BLOCK_SIZE]>,
+}
+
+impl<'a, A: AES128<'a>> ListNode<'a, VirtualAES128<'a, A>> for VirtualAES128<'a, A> {
+    fn next(&'a self) -> &'a ListLink<'a, VirtualAES128<'a, A>> {
+        &self.next
+    }
+}
+
+impl<'a, A: AES128<'a>> VirtualAES128<'a, A> {
+    pub const fn new(mux: &'a MuxAES<'a, A>) -> VirtualAES128<'a, A> {
+        VirtualAES128 {
+            mux: mux,
+            next: ListLink::empty(),
+            client: OptionalCell::empty(),
+            enabled: Cell::new(false),
+            key: Cell::new([0; AES128_KEY_SIZE]),
+            iv: Cell::new([0; AES128_BLOCK_SIZE]),
+            chain: Cell::new([0; AES128_BLOCK_SIZE]),
+            mode: Cell::new(Mode::Ecb),
+            set_mode: OptionalCell::empty(),
+            encrypting: Cell::new(true),
+            pending: Cell::new(false),
+            refused: Cell::new(None),
+            source: TakeCell::empty(),
+            dest: TakeCell::empty(),
+            range: Cell::new((0, 0)),
+            saved_block: Cell::new([0; AES128_BLOCK_SIZE]),
+        }
+    }
+
+    fn busy(&self) -> bool {
+        self.pending.get()
+            || self.refused.get().is_some()
+            || self
+                .mux
+                .inflight
+                .map_or(false, |user| core::ptr::eq(*user, self))
+    }
+
+    fn configure_mode(&self, mode: Mode, set_mode: fn(&A, bool), encrypting: bool) {
+        self.mode.set(mode);
+        self.set_mode.set(set_mode);
+        self.encrypting.set(encrypting);
+    }
+
+    /// Updates the chaining value at the end of a successful request, then
+    /// passes the result to the client.
+    fn crypt_done(&self, source: Option<&'a mut [u8]>, dest: &'a mut [u8], result: ReturnCode) {
+        let (start_index, stop_index) = self.range.get();
+        let blocks = (stop_index - start_index) / AES128_BLOCK_SIZE;
+        if blocks > 0 && result == ReturnCode::SUCCESS {
+            match self.mode.get() {
+                Mode::Ecb => {}
+                Mode::Cbc => {
+                    if self.encrypting.get() {
+                        let mut block = [0; AES128_BLOCK_SIZE];
+                        block.copy_from_slice(&dest[stop_index - AES128_BLOCK_SIZE..stop_index]);
+                        self.chain.set(block);
+                    } else {
+                        self.chain.set(self.saved_block.get());
+                    }
+                }
+                Mode::Ctr => {
+                    let counter = u128::from_be_bytes(self.chain.get());
+                    self.chain
+                        .set(counter.wrapping_add(blocks as u128).to_be_bytes());
+                }
+            }
+        }
+        self.client
+            .map(move |client| client.crypt_done(source, dest, result));
+    }
+}
+
+impl<'a, A: AES128<'a>> AES128<'a> for VirtualAES128<'a, A> {
+    fn enable(&self) {
+        if !self.enabled.get() {
+            self.enabled.set(true);
+            self.mux.enabled.set(self.mux.enabled.get() + 1);
+        }
+        self.mux.aes.enable();
+    }
+
+    /// The engine is disabled once all of its users have disabled it.
+    fn disable(&self) {
+        if self.enabled.get() {
+            self.enabled.set(false);
+            self.mux.enabled.set(self.mux.enabled.get() - 1);
+            if self.mux.enabled.get() == 0 {
+                self.mux.aes.disable();
+            }
+        }
+    }
+
+    fn set_client(&'a self, client: &'a dyn symmetric_encryption::Client<'a>) {
+        if self.client.is_none() {
+            self.mux.users.push_head(self);
+        }
+        self.client.set(client);
+    }
+
+    fn set_key(&self, key: &[u8]) -> ReturnCode {
+        if key.len() != AES128_KEY_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_key = [0; AES128_KEY_SIZE];
+        new_key.copy_from_slice(key);
+        self.key.set(new_key);
+        ReturnCode::SUCCESS
+    }
+
+    fn set_iv(&self, iv: &[u8]) -> ReturnCode {
+        if iv.len() != AES128_BLOCK_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        let mut new_iv = [0; AES128_BLOCK_SIZE];
+        new_iv.copy_from_slice(iv);
+        self.iv.set(new_iv);
+        ReturnCode::SUCCESS
+    }
+
+    fn start_message(&self) {
+        if !self.busy() {
+            self.chain.set(self.iv.get());
+        }
+    }
+
+    fn crypt(
+        &'a self,
+        source: Option<&'a mut [u8]>,
+        dest: &'a mut [u8],
+        start_index: usize,
+        stop_index: usize,
+    ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+        if self.busy() {
+            return Some((ReturnCode::EBUSY, source, dest));
+        }
+        let valid = self.set_mode.is_some()
+            && start_index <= stop_index
+            && stop_index <= dest.len()
+            && (stop_index - start_index) % AES128_BLOCK_SIZE == 0
+            && source
+                .as_ref()
+                .map_or(true, |source| source.len() == stop_index - start_index);
+        if !valid {
+            return Some((ReturnCode::EINVAL, source, dest));
+        }
+
+        if self.mux.inflight.is_none() {
+            self.mux.start(self, source, dest, start_index, stop_index)
+        } else {
+            if let Some(source) = source {
+                self.source.replace(source);
+            }
+            self.dest.replace(dest);
+            self.range.set((start_index, stop_index));
+            self.pending.set(true);
+            None
+        }
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128Ctr> AES128Ctr for VirtualAES128<'a, A> {
+    fn set_mode_aes128ctr(&self, encrypting: bool) {
+        self.configure_mode(Mode::Ctr, A::set_mode_aes128ctr, encrypting);
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128CBC> AES128CBC for VirtualAES128<'a, A> {
+    fn set_mode_aes128cbc(&self, encrypting: bool) {
+        self.configure_mode(Mode::Cbc, A::set_mode_aes128cbc, encrypting);
+    }
+}
+
+impl<'a, A: AES128<'a> + AES128ECB> AES128ECB for VirtualAES128<'a, A> {
+    fn set_mode_aes128ecb(&self, encrypting: bool) {
+        self.configure_mode(Mode::Ecb, A::set_mode_aes128ecb, encrypting);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{deferred_caller, leak};
+    use core::cell::RefCell;
+    use std::vec::Vec;
+
+    /// A stand-in for the block cipher: invertible, and different for every
+    /// key, which is all the chaining needs.
+    fn encrypt_block(key: &[u8; AES128_BLOCK_SIZE], block: &mut [u8]) {
+        for (b, k) in block.iter_mut().zip(key.iter()) {
+            *b = (*b ^ *k).rotate_left(3);
+        }
+    }
+
+    fn decrypt_block(key: &[u8; AES128_BLOCK_SIZE], block: &mut [u8]) {
+        for (b, k) in block.iter_mut().zip(key.iter()) {
+            *b = b.rotate_right(3) ^ *k;
+        }
+    }
+
+    /// Runs a whole message through the cipher in `mode`.
+    fn reference(
+        mode: Mode,
+        encrypting: bool,
+        key: &[u8; AES128_BLOCK_SIZE],
+        iv: &[u8; AES128_BLOCK_SIZE],
+        data: &mut [u8],
+    ) {
+        let mut chain = *iv;
+        for block in data.chunks_mut(AES128_BLOCK_SIZE) {
+            match mode {
+                Mode::Ecb if encrypting => encrypt_block(key, block),
+                Mode::Ecb => decrypt_block(key, block),
+                Mode::Cbc if encrypting => {
+                    block
+                        .iter_mut()
+                        .zip(chain.iter())
+                        .for_each(|(b, c)| *b ^= *c);
+                    encrypt_block(key, block);
+                    chain.copy_from_slice(block);
+                }
+                Mode::Cbc => {
+                    let mut next = [0; AES128_BLOCK_SIZE];
+                    next.copy_from_slice(block);
+                    decrypt_block(key, block);
+                    block
+                        .iter_mut()
+                        .zip(chain.iter())
+                        .for_each(|(b, c)| *b ^= *c);
+                    chain = next;
+                }
+                Mode::Ctr => {
+                    let mut keystream = chain;
+                    encrypt_block(key, &mut keystream);
+                    block
+                        .iter_mut()
+                        .zip(keystream.iter())
+                        .for_each(|(b, k)| *b ^= *k);
+                    chain = (u128::from_be_bytes(chain) + 1).to_be_bytes();
+                }
+            }
+        }
+    }
+
+    /// An engine that computes a request when it is started, and returns it
+    /// when `step()` is called.
+    struct SimAes<'a> {
+        client: OptionalCell<&'a dyn symmetric_encryption::Client<'a>>,
+        key: Cell<[u8; AES128_BLOCK_SIZE]>,
+        iv: Cell<[u8; AES128_BLOCK_SIZE]>,
+        mode: Cell<Mode>,
+        encrypting: Cell<bool>,
+        source: TakeCell<'a, [u8]>,
+        dest: TakeCell<'a, [u8]>,
+        /// The error to refuse requests with
+        refuse: Cell<Option<ReturnCode>>,
+        started: Cell<usize>,
+    }
+
+    impl<'a> SimAes<'a> {
+        fn new() -> SimAes<'a> {
+            SimAes {
+                client: OptionalCell::empty(),
+                key: Cell::new([0; AES128_BLOCK_SIZE]),
+                iv: Cell::new([0; AES128_BLOCK_SIZE]),
+                mode: Cell::new(Mode::Ecb),
+                encrypting: Cell::new(true),
+                source: TakeCell::empty(),
+                dest: TakeCell::empty(),
+                refuse: Cell::new(None),
+                started: Cell::new(0),
+            }
+        }
+
+        /// Completes the request in progress. Returns false if there was none.
+        fn step(&self) -> bool {
+            let source = self.source.take();
+            self.dest.take().map_or(false, |dest| {
+                self.client
+                    .map(move |client| client.crypt_done(source, dest, ReturnCode::SUCCESS));
+                true
+            })
+        }
+    }
+
+    impl<'a> AES128<'a> for SimAes<'a> {
+        fn enable(&self) {}
+
+        fn disable(&self) {}
+
+        fn set_client(&'a self, client: &'a dyn symmetric_encryption::Client<'a>) {
+            self.client.set(client);
+        }
+
+        fn set_key(&self, key: &[u8]) -> ReturnCode {
+            let mut new_key = [0; AES128_KEY_SIZE];
+            new_key.copy_from_slice(key);
+            self.key.set(new_key);
+            ReturnCode::SUCCESS
+        }
+
+        fn set_iv(&self, iv: &[u8]) -> ReturnCode {
+            let mut new_iv = [0; AES128_BLOCK_SIZE];
+            new_iv.copy_from_slice(iv);
+            self.iv.set(new_iv);
+            ReturnCode::SUCCESS
+        }
+
+        fn start_message(&self) {}
+
+        fn crypt(
+            &'a self,
+            source: Option<&'a mut [u8]>,
+            dest: &'a mut [u8],
+            start_index: usize,
+            stop_index: usize,
+        ) -> Option<(ReturnCode, Option<&'a mut [u8]>, &'a mut [u8])> {
+            if self.dest.is_some() {
+                return Some((ReturnCode::EBUSY, source, dest));
+            }
+            if let Some(res) = self.refuse.get() {
+                return Some((res, source, dest));
+            }
+            if let Some(ref source) = source {
+                dest[start_index..stop_index].copy_from_slice(source);
+            }
+            reference(
+                self.mode.get(),
+                self.encrypting.get(),
+                &self.key.get(),
+                &self.iv.get(),
+                &mut dest[start_index..stop_index],
+            );
+            if let Some(source) = source {
+                self.source.replace(source);
+            }
+            self.dest.replace(dest);
+            self.started.set(self.started.get() + 1);
+            None
+        }
+    }
+
+    impl AES128Ctr for SimAes<'_> {
+        fn set_mode_aes128ctr(&self, encrypting: bool) {
+            self.mode.set(Mode::Ctr);
+            self.encrypting.set(encrypting);
+        }
+    }
+
+    impl AES128CBC for SimAes<'_> {
+        fn set_mode_aes128cbc(&self, encrypting: bool) {
+            self.mode.set(Mode::Cbc);
+            self.encrypting.set(encrypting);
+        }
+    }
+
+    impl AES128ECB for SimAes<'_> {
+        fn set_mode_aes128ecb(&self, encrypting: bool) {
+            self.mode.set(Mode::Ecb);
+            self.encrypting.set(encrypting);
+        }
+    }
+
+    type Engine = SimAes<'static>;
+
+    /// A user of the mux and the results returned to it.
+    struct User {
+        aes: &'static VirtualAES128<'static, Engine>,
+        results: RefCell<Vec<(Vec<u8>, ReturnCode)>>,
+    }
+
+    impl symmetric_encryption::Client<'static> for User {
+        fn crypt_done(
+            &'static self,
+            _source: Option<&'static mut [u8]>,
+            dest: &'static mut [u8],
+            result: ReturnCode,
+        ) {
+            self.results.borrow_mut().push((dest.to_vec(), result));
+        }
+    }
+
+    impl User {
+        fn new(
+            mux: &'static MuxAES<'static, Engine>,
+            key: u8,
+            iv: u8,
+            set_mode: fn(&VirtualAES128<'static, Engine>),
+        ) -> &'static User {
+            let user: &User = leak(User {
+                aes: leak(VirtualAES128::new(mux)),
+                results: RefCell::new(Vec::new()),
+            });
+            user.aes.set_client(user);
+            user.aes.set_key(&[key; AES128_KEY_SIZE]);
+            user.aes.set_iv(&[iv; AES128_BLOCK_SIZE]);
+            set_mode(user.aes);
+            user.aes.start_message();
+            user
+        }
+
+        /// Starts a request on a copy of `data`, in place.
+        fn crypt(&self, data: &[u8]) -> Option<ReturnCode> {
+            let dest = leak(data.to_vec()).as_mut_slice();
+            self.aes
+                .crypt(None, dest, 0, data.len())
+                .map(|(res, _, _)| res)
+        }
+
+        fn take_results(&self) -> Vec<(Vec<u8>, ReturnCode)> {
+            self.results.replace(Vec::new())
+        }
+    }
+
+    fn setup() -> (
+        &'static Engine,
+        &'static MuxAES<'static, Engine>,
+        DeferredCallHandle,
+    ) {
+        let engine: &Engine = leak(SimAes::new());
+        let deferred_caller = deferred_caller(1);
+        let mux: &MuxAES<'static, Engine> = leak(MuxAES::new(engine, deferred_caller));
+        engine.set_client(mux);
+        let handle = deferred_caller.register(mux).unwrap();
+        mux.initialize_callback_handle(handle);
+        (engine, mux, handle)
+    }
+
+    fn message(blocks: usize) -> Vec<u8> {
+        (0..blocks * AES128_BLOCK_SIZE)
+            .map(|i| (i * 13) as u8)
+            .collect()
+    }
+
+    fn expected(mode: Mode, encrypting: bool, key: u8, iv: u8, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        reference(
+            mode,
+            encrypting,
+            &[key; AES128_KEY_SIZE],
+            &[iv; AES128_BLOCK_SIZE],
+            &mut data,
+        );
+        data
+    }
+
+    fn cbc_encrypt(aes: &VirtualAES128<'static, Engine>) {
+        aes.set_mode_aes128cbc(true);
+    }
+
+    fn cbc_decrypt(aes: &VirtualAES128<'static, Engine>) {
+        aes.set_mode_aes128cbc(false);
+    }
+
+    fn ctr(aes: &VirtualAES128<'static, Engine>) {
+        aes.set_mode_aes128ctr(true);
+    }
+
+    fn ecb_encrypt(aes: &VirtualAES128<'static, Engine>) {
+        aes.set_mode_aes128ecb(true);
+    }
+
+    #[test]
+    fn requests_queue_behind_the_engine() {
+        let (engine, mux, _) = setup();
+        let a = User::new(mux, 1, 2, cbc_encrypt);
+        let b = User::new(mux, 3, 4, ecb_encrypt);
+        let data = message(2);
+
+        assert_eq!(a.crypt(&data), None);
+        assert_eq!(b.crypt(&data), None);
+        assert_eq!(engine.started.get(), 1);
+        // A user has one request at a time
+        assert_eq!(b.crypt(&data), Some(ReturnCode::EBUSY));
+
+        assert!(engine.step());
+        assert_eq!(engine.started.get(), 2);
+        assert_eq!(
+            a.take_results(),
+            [(expected(Mode::Cbc, true, 1, 2, &data), ReturnCode::SUCCESS)]
+        );
+        assert!(b.take_results().is_empty());
+
+        assert!(engine.step());
+        assert_eq!(
+            b.take_results(),
+            [(expected(Mode::Ecb, true, 3, 4, &data), ReturnCode::SUCCESS)]
+        );
+        assert!(!engine.step());
+    }
+
+    /// Splits a message of `a` in two requests, interleaved with requests of
+    /// another user, and checks that the result is the same as running the
+    /// whole message at once.
+    fn chains_across_requests(mode: Mode, set_mode: fn(&VirtualAES128<'static, Engine>)) {
+        let (engine, mux, _) = setup();
+        let a = User::new(mux, 5, 6, set_mode);
+        let b = User::new(mux, 7, 8, ctr);
+        let data = message(4);
+
+        for half in data.chunks(2 * AES128_BLOCK_SIZE) {
+            assert_eq!(b.crypt(&message(1)), None);
+            assert_eq!(a.crypt(half), None);
+            while engine.step() {}
+        }
+        let output: Vec<u8> = a
+            .take_results()
+            .into_iter()
+            .flat_map(|(half, res)| {
+                assert_eq!(res, ReturnCode::SUCCESS);
+                half
+            })
+            .collect();
+        assert_eq!(output, expected(mode, true, 5, 6, &data));
+        // The other user's message chains separately
+        let b_results = b.take_results();
+        assert_eq!(b_results[0].0, expected(Mode::Ctr, true, 7, 8, &message(1)));
+        assert_ne!(b_results[0].0, b_results[1].0);
+    }
+
+    #[test]
+    fn cbc_encryption_chains_across_requests() {
+        chains_across_requests(Mode::Cbc, cbc_encrypt);
+    }
+
+    #[test]
+    fn ctr_chains_across_requests() {
+        chains_across_requests(Mode::Ctr, ctr);
+    }
+
+    #[test]
+    fn cbc_decryption_chains_across_requests() {
+        let (engine, mux, _) = setup();
+        let a = User::new(mux, 5, 6, cbc_decrypt);
+        let b = User::new(mux, 7, 8, cbc_encrypt);
+        let data = message(4);
+        let ciphertext = expected(Mode::Cbc, true, 5, 6, &data);
+
+        for half in ciphertext.chunks(2 * AES128_BLOCK_SIZE) {
+            assert_eq!(a.crypt(half), None);
+            assert_eq!(b.crypt(&message(1)), None);
+            while engine.step() {}
+        }
+        let output: Vec<u8> = a
+            .take_results()
+            .into_iter()
+            .flat_map(|(half, _)| half)
+            .collect();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn refused_requests_are_reported_later() {
+        let (engine, mux, handle) = setup();
+        let a = User::new(mux, 1, 2, ctr);
+        let b = User::new(mux, 3, 4, ctr);
+        let data = message(2);
+
+        assert_eq!(a.crypt(&data), None);
+        assert_eq!(b.crypt(&data), None);
+        engine.refuse.set(Some(ReturnCode::FAIL));
+        assert!(engine.step());
+        assert_eq!(a.take_results().len(), 1);
+        // Not from within the completion of the other user's request
+        assert!(b.take_results().is_empty());
+        assert_eq!(b.crypt(&data), Some(ReturnCode::EBUSY));
+
+        mux.call(handle);
+        assert_eq!(b.take_results(), [(data.clone(), ReturnCode::FAIL)]);
+
+        // The counter did not advance
+        engine.refuse.set(None);
+        assert_eq!(b.crypt(&data), None);
+        assert!(engine.step());
+        assert_eq!(
+            b.take_results(),
+            [(expected(Mode::Ctr, true, 3, 4, &data), ReturnCode::SUCCESS)]
+        );
+    }
+}
@@ -8,6 +8,17 @@
 //! must use a `FlashUser` instance to contain the per-user state for the
 //! virtualization.
 //!
+//! Each `FlashUser` is given a range of pages, and reads, writes and erases of
+//! pages outside it fail with EINVAL, so that a buggy client can't change the
+//! pages of another. Page numbers are those of the underlying flash.
+//!
+//! A user can queue up to `QUEUE_LEN` operations, which run in order, and the
+//! mux serves the users with queued operations in turn. Erases are slow, so
+//! when a user queues several erases in a row they run back to back, up to
+//! `QUEUE_LEN` of them, before the next user is served. Operations the flash
+//! refuses to start complete with `FlashError` from a deferred call, so
+//! clients are never called back from within their own calls to the mux.
+//!
 //! Usage
 //! -----
 //!
@@ -17,21 +28,38 @@
 //! // Create the mux.
 //! let mux_flash = static_init!(
 //!     capsules::virtual_flash::MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
-//!     capsules::virtual_flash::MuxFlash::new(&sam4l::flashcalw::FLASH_CONTROLLER));
+//!     capsules::virtual_flash::MuxFlash::new(
+//!         &sam4l::flashcalw::FLASH_CONTROLLER,
+//!         dynamic_deferred_caller,
+//!     )
+//! );
+//! mux_flash.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(mux_flash)
+//!         .expect("no deferred call slot available for flash mux"),
+//! );
 //! hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, mux_flash);
 //!
-//! // Everything that then uses the virtualized flash must use one of these.
+//! // Everything that then uses the virtualized flash must use one of these,
+//! // here for the 64 pages from page 0x300.
 //! let virtual_flash = static_init!(
 //!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
-//!     capsules::virtual_flash::FlashUser::new(mux_flash));
+//!     capsules::virtual_flash::FlashUser::new(mux_flash, 0x300, 64));
 //! ```
 
 use core::cell::Cell;
+use core::ptr;
 use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
 use kernel::common::{List, ListLink, ListNode};
 use kernel::hil;
 use kernel::ReturnCode;
 
+/// Number of operations each user can queue, at most 32.
+pub const QUEUE_LEN: usize = 4;
+
 /// Handle keeping a list of active users of flash hardware and serialize their
 /// requests. After each completed request the list is checked to see if there
 /// is another flash user with an outstanding read, write, or erase request.
@@ -39,10 +67,21 @@ pub struct MuxFlash<'a, F: hil::flash::Flash + 'static> {
     flash: &'a F,
     users: List<'a, FlashUser<'a, F>>,
     inflight: OptionalCell<&'a FlashUser<'a, F>>,
+    /// The user served last, so that users take turns.
+    last: Cell<Option<&'a FlashUser<'a, F>>>,
+    /// Erases run back to back for the user served last.
+    erases_in_a_row: Cell<usize>,
+    /// The operation of the inflight user that the flash refused, which
+    /// fails from a deferred call.
+    refused: Cell<Option<Op>>,
+    refused_buffer: TakeCell<'static, F::Page>,
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
 }
 
 impl<F: hil::flash::Flash> hil::flash::Client<F> for MuxFlash<'_, F> {
     fn read_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.erases_in_a_row.set(0);
         self.inflight.take().map(move |user| {
             user.read_complete(pagebuffer, error);
         });
@@ -50,6 +89,7 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for MuxFlash<'_, F> {
     }
 
     fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.erases_in_a_row.set(0);
         self.inflight.take().map(move |user| {
             user.write_complete(pagebuffer, error);
         });
@@ -57,6 +97,7 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for MuxFlash<'_, F> {
     }
 
     fn erase_complete(&self, error: hil::flash::Error) {
+        self.erases_in_a_row.set(self.erases_in_a_row.get() + 1);
         self.inflight.take().map(move |user| {
             user.erase_complete(error);
         });
@@ -65,89 +106,215 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for MuxFlash<'_, F> {
 }
 
 impl<'a, F: hil::flash::Flash> MuxFlash<'a, F> {
-    pub const fn new(flash: &'a F) -> MuxFlash<'a, F> {
+    pub const fn new(flash: &'a F, deferred_caller: &'a DynamicDeferredCall) -> MuxFlash<'a, F> {
         MuxFlash {
             flash: flash,
             users: List::new(),
             inflight: OptionalCell::empty(),
+            last: Cell::new(None),
+            erases_in_a_row: Cell::new(0),
+            refused: Cell::new(None),
+            refused_buffer: TakeCell::empty(),
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
+        }
+    }
+
+    /// Initializes a callback handle for deferred callbacks.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.replace(handle);
+    }
+
+    /// The user whose operation runs next: the user served last if it has
+    /// more erases to batch, or else the next user after it with a queued
+    /// operation.
+    fn next_user(&self) -> Option<&'a FlashUser<'a, F>> {
+        let last = self.last.get();
+        if let Some(user) = last {
+            if let Some(Op::Erase(_)) = user.next_op() {
+                if self.erases_in_a_row.get() > 0 && self.erases_in_a_row.get() < QUEUE_LEN {
+                    return Some(user);
+                }
+            }
+        }
+        self.erases_in_a_row.set(0);
+
+        let mut after_last = false;
+        let mut first_waiting = None;
+        for user in self.users.iter() {
+            if user.next_op().is_some() {
+                if after_last {
+                    return Some(user);
+                }
+                first_waiting = first_waiting.or(Some(user));
+            }
+            if last.map_or(false, |last| ptr::eq(last, user)) {
+                after_last = true;
+            }
         }
+        first_waiting
     }
 
-    /// Scan the list of users and find the first user that has a pending
-    /// request, then issue that request to the flash hardware.
+    /// Issue the next queued request to the flash hardware, if the flash is
+    /// free. A request the hardware refuses stays inflight until it fails
+    /// from a deferred call.
     fn do_next_op(&self) {
-        if self.inflight.is_none() {
-            let mnode = self
-                .users
-                .iter()
-                .find(|node| node.operation.get() != Op::Idle);
-            mnode.map(|node| {
-                node.buffer.take().map_or_else(
-                    || {
-                        // Don't need a buffer for erase.
-                        match node.operation.get() {
-                            Op::Erase(page_number) => {
-                                self.flash.erase_page(page_number);
-                            }
-                            _ => {}
-                        };
-                    },
-                    |buf| {
-                        match node.operation.get() {
-                            Op::Write(page_number) => {
-                                if let Err((_, buf)) = self.flash.write_page(page_number, buf) {
-                                    node.buffer.replace(buf);
-                                }
-                            }
-                            Op::Read(page_number) => {
-                                if let Err((_, buf)) = self.flash.read_page(page_number, buf) {
-                                    node.buffer.replace(buf);
-                                }
-                            }
-                            Op::Erase(page_number) => {
-                                self.flash.erase_page(page_number);
-                            }
-                            Op::Idle => {} // Can't get here...
-                        }
-                    },
-                );
-                node.operation.set(Op::Idle);
-                self.inflight.set(node);
-            });
+        while self.inflight.is_none() {
+            let user = match self.next_user() {
+                Some(user) => user,
+                None => return,
+            };
+            let (op, buffer) = match user.dequeue() {
+                Some(next) => next,
+                None => return,
+            };
+            self.inflight.set(user);
+            self.last.set(Some(user));
+            let refused = match (op, buffer) {
+                (Op::Read(page_number), Some(buf)) => self
+                    .flash
+                    .read_page(page_number, buf)
+                    .map_err(|(_, buf)| self.refused_buffer.replace(buf))
+                    .is_err(),
+                (Op::Write(page_number), Some(buf)) => self
+                    .flash
+                    .write_page(page_number, buf)
+                    .map_err(|(_, buf)| self.refused_buffer.replace(buf))
+                    .is_err(),
+                (Op::Erase(page_number), _) => {
+                    self.flash.erase_page(page_number) != ReturnCode::SUCCESS
+                }
+                // Reads and writes are always queued with a buffer.
+                (_, None) => {
+                    self.inflight.clear();
+                    false
+                }
+            };
+            if refused {
+                self.refused.set(Some(op));
+                self.handle.map(|handle| self.deferred_caller.set(*handle));
+            }
         }
     }
 }
 
+impl<F: hil::flash::Flash> DynamicDeferredCallClient for MuxFlash<'_, F> {
+    fn call(&self, _handle: DeferredCallHandle) {
+        let error = hil::flash::Error::FlashError;
+        match self.refused.take() {
+            Some(Op::Read(_)) => self.refused_buffer.take().map(|buf| {
+                hil::flash::Client::read_complete(self, buf, error);
+            }),
+            Some(Op::Write(_)) => self.refused_buffer.take().map(|buf| {
+                hil::flash::Client::write_complete(self, buf, error);
+            }),
+            Some(Op::Erase(_)) => Some(hil::flash::Client::erase_complete(self, error)),
+            None => None,
+        };
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 enum Op {
-    Idle,
     Write(usize),
     Read(usize),
     Erase(usize),
 }
 
+impl Op {
+    fn page_number(&self) -> usize {
+        match *self {
+            Op::Write(page_number) | Op::Read(page_number) | Op::Erase(page_number) => page_number,
+        }
+    }
+}
+
+/// A queued operation, and the buffer of a read or write.
+struct Slot<F: hil::flash::Flash + 'static> {
+    op: Cell<Option<Op>>,
+    buffer: TakeCell<'static, F::Page>,
+}
+
+impl<F: hil::flash::Flash> Default for Slot<F> {
+    fn default() -> Self {
+        Slot {
+            op: Cell::new(None),
+            buffer: TakeCell::empty(),
+        }
+    }
+}
+
 /// Keep state for each flash user. All uses of the virtualized flash interface
 /// need to create one of these to be a user of the flash. The `new()` function
 /// handles most of the work, a user only has to pass in a reference to the
-/// MuxFlash object.
+/// MuxFlash object and the pages it may use.
 pub struct FlashUser<'a, F: hil::flash::Flash + 'static> {
     mux: &'a MuxFlash<'a, F>,
-    buffer: TakeCell<'static, F::Page>,
-    operation: Cell<Op>,
+    first_page: usize,
+    num_pages: usize,
+    /// Queued operations, from `head`.
+    queue: [Slot<F>; QUEUE_LEN],
+    head: Cell<usize>,
+    queued: Cell<usize>,
     next: ListLink<'a, FlashUser<'a, F>>,
     client: OptionalCell<&'a dyn hil::flash::Client<FlashUser<'a, F>>>,
 }
 
 impl<'a, F: hil::flash::Flash> FlashUser<'a, F> {
-    pub const fn new(mux: &'a MuxFlash<'a, F>) -> FlashUser<'a, F> {
+    /// A user of the `num_pages` pages from `first_page`.
+    pub fn new(mux: &'a MuxFlash<'a, F>, first_page: usize, num_pages: usize) -> FlashUser<'a, F> {
         FlashUser {
             mux: mux,
-            buffer: TakeCell::empty(),
-            operation: Cell::new(Op::Idle),
+            first_page: first_page,
+            num_pages: num_pages,
+            queue: Default::default(),
+            head: Cell::new(0),
+            queued: Cell::new(0),
             next: ListLink::empty(),
             client: OptionalCell::empty(),
         }
     }
+
+    /// Queues an operation, and starts it if the flash is free.
+    fn enqueue(
+        &self,
+        op: Op,
+        buf: Option<&'static mut F::Page>,
+    ) -> Result<(), (ReturnCode, Option<&'static mut F::Page>)> {
+        let page_number = op.page_number();
+        if page_number < self.first_page || page_number - self.first_page >= self.num_pages {
+            return Err((ReturnCode::EINVAL, buf));
+        }
+        if self.queued.get() == QUEUE_LEN {
+            return Err((ReturnCode::EBUSY, buf));
+        }
+        let slot = &self.queue[(self.head.get() + self.queued.get()) % QUEUE_LEN];
+        slot.op.set(Some(op));
+        buf.map(|buf| slot.buffer.replace(buf));
+        self.queued.set(self.queued.get() + 1);
+        self.mux.do_next_op();
+        Ok(())
+    }
+
+    /// The operation that runs next.
+    fn next_op(&self) -> Option<Op> {
+        if self.queued.get() == 0 {
+            None
+        } else {
+            self.queue[self.head.get()].op.get()
+        }
+    }
+
+    fn dequeue(&self) -> Option<(Op, Option<&'static mut F::Page>)> {
+        if self.queued.get() == 0 {
+            return None;
+        }
+        let head = self.head.get();
+        self.head.set((head + 1) % QUEUE_LEN);
+        self.queued.set(self.queued.get() - 1);
+        let slot = &self.queue[head];
+        slot.op.take().map(|op| (op, slot.buffer.take()))
+    }
 }
 
 impl<'a, F: hil::flash::Flash, C: hil::flash::Client<Self>> hil::flash::HasClient<'a, C>
@@ -193,10 +360,8 @@ impl<F: hil::flash::Flash> hil::flash::Flash for FlashUser<'_, F> {
         page_number: usize,
         buf: &'static mut Self::Page,
     ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
-        self.buffer.replace(buf);
-        self.operation.set(Op::Read(page_number));
-        self.mux.do_next_op();
-        Ok(())
+        self.enqueue(Op::Read(page_number), Some(buf))
+            .map_err(|(result, buf)| (result, buf.unwrap()))
     }
 
     fn write_page(
@@ -204,15 +369,203 @@ impl<F: hil::flash::Flash> hil::flash::Flash for FlashUser<'_, F> {
         page_number: usize,
         buf: &'static mut Self::Page,
     ) -> Result<(), (ReturnCode, &'static mut Self::Page)> {
-        self.buffer.replace(buf);
-        self.operation.set(Op::Write(page_number));
-        self.mux.do_next_op();
-        Ok(())
+        self.enqueue(Op::Write(page_number), Some(buf))
+            .map_err(|(result, buf)| (result, buf.unwrap()))
     }
 
     fn erase_page(&self, page_number: usize) -> ReturnCode {
-        self.operation.set(Op::Erase(page_number));
-        self.mux.do_next_op();
-        ReturnCode::SUCCESS
+        match self.enqueue(Op::Erase(page_number), None) {
+            Ok(()) => ReturnCode::SUCCESS,
+            Err((result, _)) => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{self, Medium, Op, Page, SimFlash, WriteMode};
+    use core::cell::RefCell;
+    use kernel::hil::flash::{Client, Flash, HasClient};
+    use std::vec::Vec;
+
+    /// Records whether each operation of a user succeeded.
+    struct TestClient {
+        results: RefCell<Vec<bool>>,
+        buffers: RefCell<Vec<&'static mut Page>>,
+    }
+
+    impl TestClient {
+        fn completed(&self, error: hil::flash::Error) {
+            let ok = error == hil::flash::Error::CommandComplete;
+            self.results.borrow_mut().push(ok);
+        }
+    }
+
+    impl Client<FlashUser<'static, SimFlash>> for TestClient {
+        fn read_complete(&self, buffer: &'static mut Page, error: hil::flash::Error) {
+            self.completed(error);
+            self.buffers.borrow_mut().push(buffer);
+        }
+
+        fn write_complete(&self, buffer: &'static mut Page, error: hil::flash::Error) {
+            self.completed(error);
+            self.buffers.borrow_mut().push(buffer);
+        }
+
+        fn erase_complete(&self, error: hil::flash::Error) {
+            self.completed(error);
+        }
+    }
+
+    fn page() -> &'static mut Page {
+        test_util::leak(Page::default())
+    }
+
+    fn setup() -> &'static MuxFlash<'static, SimFlash> {
+        let flash = SimFlash::new(Medium::new(16), WriteMode::Replace);
+        let deferred_caller = test_util::deferred_caller(1);
+        let mux = test_util::leak(MuxFlash::new(flash, deferred_caller));
+        mux.initialize_callback_handle(deferred_caller.register(mux).unwrap());
+        flash.set_client(mux);
+        mux
+    }
+
+    fn user(
+        mux: &'static MuxFlash<'static, SimFlash>,
+        first_page: usize,
+        num_pages: usize,
+    ) -> (&'static FlashUser<'static, SimFlash>, &'static TestClient) {
+        let user = test_util::leak(FlashUser::new(mux, first_page, num_pages));
+        let client = test_util::leak(TestClient {
+            results: RefCell::new(Vec::new()),
+            buffers: RefCell::new(Vec::new()),
+        });
+        user.set_client(client);
+        (user, client)
+    }
+
+    /// Completes operations, and the deferred calls reporting refused ones,
+    /// until there are none left.
+    fn run(mux: &MuxFlash<'static, SimFlash>) {
+        loop {
+            if mux.refused.get().is_some() {
+                mux.handle.map(|handle| mux.call(*handle));
+            } else if !mux.flash.step() {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn queue_and_region() {
+        let mux = setup();
+        let (user, client) = user(mux, 4, 2);
+
+        assert!(user.write_page(4, page()).is_ok());
+        assert!(user.read_page(5, page()).is_ok());
+        assert_eq!(user.erase_page(4), ReturnCode::SUCCESS);
+        assert_eq!(user.erase_page(5), ReturnCode::SUCCESS);
+        // The first operation runs, so there is room for one more.
+        assert!(user.read_page(4, page()).is_ok());
+        match user.read_page(4, page()) {
+            Err((ReturnCode::EBUSY, _)) => {}
+            _ => panic!("queue should be full"),
+        }
+        match user.write_page(6, page()) {
+            Err((ReturnCode::EINVAL, _)) => {}
+            _ => panic!("page 6 is outside the region"),
+        }
+        assert_eq!(user.erase_page(3), ReturnCode::EINVAL);
+
+        run(mux);
+        assert_eq!(
+            *mux.flash.started.borrow(),
+            [
+                Op::Write(4),
+                Op::Read(5),
+                Op::Erase(4),
+                Op::Erase(5),
+                Op::Read(4)
+            ]
+        );
+        assert_eq!(client.results.borrow().len(), 5);
+        assert_eq!(client.buffers.borrow().len(), 3);
+    }
+
+    #[test]
+    fn users_take_turns() {
+        let mux = setup();
+        let (a, _) = user(mux, 0, 4);
+        let (b, _) = user(mux, 4, 4);
+
+        assert!(a.write_page(0, page()).is_ok());
+        assert!(a.write_page(1, page()).is_ok());
+        assert!(a.write_page(2, page()).is_ok());
+        assert!(b.write_page(4, page()).is_ok());
+        assert!(b.write_page(5, page()).is_ok());
+
+        run(mux);
+        assert_eq!(
+            *mux.flash.started.borrow(),
+            [
+                Op::Write(0),
+                Op::Write(4),
+                Op::Write(1),
+                Op::Write(5),
+                Op::Write(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn erases_batched() {
+        let mux = setup();
+        let (a, _) = user(mux, 0, 8);
+        let (b, _) = user(mux, 8, 8);
+
+        assert_eq!(a.erase_page(0), ReturnCode::SUCCESS);
+        assert_eq!(a.erase_page(1), ReturnCode::SUCCESS);
+        assert_eq!(a.erase_page(2), ReturnCode::SUCCESS);
+        assert!(a.write_page(0, page()).is_ok());
+        assert!(b.read_page(8, page()).is_ok());
+        assert_eq!(b.erase_page(8), ReturnCode::SUCCESS);
+
+        run(mux);
+        assert_eq!(
+            *mux.flash.started.borrow(),
+            [
+                Op::Erase(0),
+                Op::Erase(1),
+                Op::Erase(2),
+                Op::Read(8),
+                Op::Write(0),
+                Op::Erase(8)
+            ]
+        );
+    }
+
+    #[test]
+    fn hardware_errors_reported() {
+        let mux = setup();
+        let (user, client) = user(mux, 0, 4);
+        mux.flash.broken_page.set(Some(1));
+
+        assert!(user.write_page(0, page()).is_ok());
+        assert!(user.write_page(1, page()).is_ok());
+        assert_eq!(user.erase_page(1), ReturnCode::SUCCESS);
+        assert_eq!(user.erase_page(2), ReturnCode::SUCCESS);
+
+        // Refused operations fail from a deferred call, not from the call
+        // that queued them.
+        while mux.flash.step() {}
+        assert_eq!(*client.results.borrow(), [true]);
+
+        run(mux);
+        assert_eq!(*client.results.borrow(), [true, false, false, true]);
+        assert_eq!(client.buffers.borrow().len(), 2);
+        assert_eq!(*mux.flash.started.borrow(), [Op::Write(0), Op::Erase(2)]);
     }
 }
@@ -1,4 +1,8 @@
 //! BLE driver.
+//!
+//! The BLE controller runs its own link layer and is reached over an
+//! internal SPI bus with HCI, so this driver only implements
+//! `BleAdvertisementDriver` and not `BleLinkLayerDriver`.
 
 use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
@@ -335,7 +335,11 @@ impl<'a> hil::symmetric_encryption::AES128<'a> for Aes<'a> {
             }
         }
         self.client.map(|client| {
-            client.crypt_done(self.source.take(), self.dest.take().unwrap());
+            client.crypt_done(
+                self.source.take(),
+                self.dest.take().unwrap(),
+                ReturnCode::SUCCESS,
+            );
         });
         None
     }
@@ -34,6 +34,7 @@
 //! * CRC - 3 bytes
 
 use core::cell::Cell;
+use core::cmp;
 use core::convert::TryFrom;
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
@@ -529,12 +530,24 @@ register_bitfields! [u32,
 static mut PAYLOAD: [u8; nrf5x::constants::RADIO_PAYLOAD_LENGTH] =
     [0x00; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
 
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
+const ADVERTISING_ACCESS_ADDRESS: u32 = 0x8e89bed6;
+// BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 4.1 Inter Frame Space
+const T_IFS_US: u32 = 150;
+
 pub struct Radio<'a> {
     registers: StaticRef<RadioRegisters>,
     tx_power: Cell<TxPower>,
     rx_client: OptionalCell<&'a dyn ble_advertising::RxClient>,
     tx_client: OptionalCell<&'a dyn ble_advertising::TxClient>,
+    exchange_client: OptionalCell<&'a dyn ble_advertising::ExchangeClient>,
     buffer: TakeCell<'static, [u8]>,
+    access_address: Cell<u32>,
+    crc_init: Cell<u32>,
+    // Whether an exchange is in progress, and whether the radio is receiving
+    // (rather than transmitting a response) in it
+    exchange: Cell<bool>,
+    exchange_receiving: Cell<bool>,
 }
 
 pub static mut RADIO: Radio = Radio::new();
@@ -546,7 +559,12 @@ impl<'a> Radio<'a> {
             tx_power: Cell::new(TxPower::ZerodBm),
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
+            exchange_client: OptionalCell::empty(),
             buffer: TakeCell::empty(),
+            access_address: Cell::new(ADVERTISING_ACCESS_ADDRESS),
+            crc_init: Cell::new(nrf5x::constants::RADIO_CRCINIT_BLE),
+            exchange: Cell::new(false),
+            exchange_receiving: Cell::new(false),
         }
     }
 
@@ -607,8 +625,11 @@ impl<'a> Radio<'a> {
 
         if regs.event_ready.is_set(Event::READY) {
             regs.event_ready.write(Event::READY::CLEAR);
-            regs.event_end.write(Event::READY::CLEAR);
-            regs.task_start.write(Task::ENABLE::SET);
+            // In an exchange the READY_START shortcut starts the radio
+            if !self.exchange.get() {
+                regs.event_end.write(Event::READY::CLEAR);
+                regs.task_start.write(Task::ENABLE::SET);
+            }
         }
 
         if regs.event_address.is_set(Event::READY) {
@@ -628,6 +649,12 @@ impl<'a> Radio<'a> {
                 ReturnCode::FAIL
             };
 
+            if self.exchange.get() {
+                self.exchange_packet_end(result == ReturnCode::SUCCESS);
+                self.enable_interrupts();
+                return;
+            }
+
             match regs.state.get() {
                 nrf5x::constants::RADIO_STATE_TXRU
                 | nrf5x::constants::RADIO_STATE_TXIDLE
@@ -684,6 +711,78 @@ impl<'a> Radio<'a> {
         regs.intenclr.set(0xffffffff);
     }
 
+    // Handles the end of a packet in an exchange. The shortcuts turn the
+    // radio around after each packet, so when a packet has been received the
+    // radio is already ramping up to transmit the response; it starts
+    // transmitting T_IFS after the received packet, by which time the
+    // response must be in the payload buffer.
+    fn exchange_packet_end(&self, crc_ok: bool) {
+        let regs = &*self.registers;
+        if !self.exchange_receiving.get() {
+            // The response was sent and the radio is ramping up to receive
+            self.exchange_receiving.set(true);
+            regs.shorts.write(
+                Shortcut::READY_START::SET
+                    + Shortcut::END_DISABLE::SET
+                    + Shortcut::DISABLED_TXEN::SET,
+            );
+            return;
+        }
+
+        let mut pdu = [0; nrf5x::constants::RADIO_PAYLOAD_LENGTH];
+        let response_len = unsafe {
+            let len = cmp::min(PAYLOAD[1] as usize + 2, PAYLOAD.len());
+            pdu[..len].copy_from_slice(&PAYLOAD[..len]);
+            self.exchange_client.map_or(0, |client| {
+                client.packet_received(&pdu[..len], crc_ok, &mut PAYLOAD)
+            })
+        };
+        if response_len > 0 {
+            self.exchange_receiving.set(false);
+            regs.shorts.write(
+                Shortcut::READY_START::SET
+                    + Shortcut::END_DISABLE::SET
+                    + Shortcut::DISABLED_RXEN::SET,
+            );
+        } else {
+            self.end_exchange();
+        }
+    }
+
+    fn end_exchange(&self) {
+        let regs = &*self.registers;
+        regs.shorts.set(0);
+        regs.task_disable.write(Task::ENABLE::SET);
+        self.radio_off();
+        self.exchange.set(false);
+        self.exchange_client
+            .map(|client| client.exchange_done(ReturnCode::SUCCESS));
+    }
+
+    fn start_exchange(&self, channel: RadioChannel, receiving: bool) {
+        let regs = &*self.registers;
+        self.ble_initialize(channel);
+        regs.tifs.write(InterFrameSpacing::TIFS.val(T_IFS_US));
+        self.exchange.set(true);
+        self.exchange_receiving.set(receiving);
+        if receiving {
+            regs.shorts.write(
+                Shortcut::READY_START::SET
+                    + Shortcut::END_DISABLE::SET
+                    + Shortcut::DISABLED_TXEN::SET,
+            );
+            self.rx();
+        } else {
+            regs.shorts.write(
+                Shortcut::READY_START::SET
+                    + Shortcut::END_DISABLE::SET
+                    + Shortcut::DISABLED_RXEN::SET,
+            );
+            self.tx();
+        }
+        self.enable_interrupts();
+    }
+
     fn replace_radio_buffer(&self, buf: &'static mut [u8]) -> &'static mut [u8] {
         // set payload
         for (i, c) in buf.as_ref().iter().enumerate() {
@@ -708,28 +807,40 @@ impl<'a> Radio<'a> {
         self.set_rx_address();
 
         self.ble_set_packet_config();
-        self.ble_set_advertising_access_address();
+        self.ble_set_access_address(channel);
 
-        self.ble_set_crc_config();
+        self.ble_set_crc_config(channel);
 
         self.set_dma_ptr();
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 3.1.1 CRC Generation
-    fn ble_set_crc_config(&self) {
+    // The advertising channels use a fixed CRC initial value, the data
+    // channels the one of the connection
+    fn ble_set_crc_config(&self, channel: RadioChannel) {
         let regs = &*self.registers;
         regs.crccnf
             .write(CrcConfiguration::LEN::THREE + CrcConfiguration::SKIPADDR::EXCLUDE);
-        regs.crcinit.set(nrf5x::constants::RADIO_CRCINIT_BLE);
+        if is_advertising_channel(channel) {
+            regs.crcinit.set(nrf5x::constants::RADIO_CRCINIT_BLE);
+        } else {
+            regs.crcinit.set(self.crc_init.get());
+        }
         regs.crcpoly.set(nrf5x::constants::RADIO_CRCPOLY_BLE);
     }
 
     // BLUETOOTH SPECIFICATION Version 4.2 [Vol 6, Part B], section 2.1.2 Access Address
-    // Set access address to 0x8E89BED6
-    fn ble_set_advertising_access_address(&self) {
+    // The advertising channels use the access address 0x8E89BED6, the data
+    // channels the one of the connection
+    fn ble_set_access_address(&self, channel: RadioChannel) {
         let regs = &*self.registers;
-        regs.prefix0.set(0x0000008e);
-        regs.base0.set(0x89bed600);
+        let access_address = if is_advertising_channel(channel) {
+            ADVERTISING_ACCESS_ADDRESS
+        } else {
+            self.access_address.get()
+        };
+        regs.prefix0.set(access_address >> 24);
+        regs.base0.set(access_address << 8);
     }
 
     // Packet configuration
@@ -822,6 +933,47 @@ impl<'a> ble_advertising::BleAdvertisementDriver<'a> for Radio<'a> {
     }
 }
 
+impl<'a> ble_advertising::BleLinkLayerDriver<'a> for Radio<'a> {
+    fn set_access_address(&self, access_address: u32, crc_init: u32) {
+        self.access_address.set(access_address);
+        self.crc_init.set(crc_init);
+    }
+
+    fn transmit_and_listen(&self, pdu: &[u8], channel: RadioChannel) -> ReturnCode {
+        if self.exchange.get() || self.buffer.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        unsafe {
+            let len = cmp::min(pdu.len(), PAYLOAD.len());
+            PAYLOAD[..len].copy_from_slice(&pdu[..len]);
+        }
+        self.start_exchange(channel, false);
+        ReturnCode::SUCCESS
+    }
+
+    fn listen(&self, channel: RadioChannel) -> ReturnCode {
+        if self.exchange.get() || self.buffer.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.start_exchange(channel, true);
+        ReturnCode::SUCCESS
+    }
+
+    fn stop_exchange(&self) {
+        if self.exchange.get() {
+            self.end_exchange();
+        }
+    }
+
+    fn set_exchange_client(&self, client: &'a dyn ble_advertising::ExchangeClient) {
+        self.exchange_client.set(client);
+    }
+}
+
+fn is_advertising_channel(channel: RadioChannel) -> bool {
+    channel.get_channel_index() >= 37
+}
+
 impl ble_advertising::BleConfig for Radio<'_> {
     // The BLE Advertising Driver validates that the `tx_power` is between -20 to 10 dBm but then
     // underlying chip must validate if the current `tx_power` is supported as well
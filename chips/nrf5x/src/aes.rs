@@ -229,8 +229,9 @@ impl<'a> AesECB<'a> {
                             *out = ks[i] ^ *inp;
                         }
 
-                        self.client
-                            .map(move |client| client.crypt_done(Some(slice), buf));
+                        self.client.map(move |client| {
+                            client.crypt_done(Some(slice), buf, ReturnCode::SUCCESS)
+                        });
                     });
                 });
             }
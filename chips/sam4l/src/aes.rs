@@ -396,7 +396,11 @@ impl<'a> Aes<'a> {
 
                 // Alert the client of the completion
                 self.client.map(|client| {
-                    client.crypt_done(self.source.take(), self.dest.take().unwrap());
+                    client.crypt_done(
+                        self.source.take(),
+                        self.dest.take().unwrap(),
+                        ReturnCode::SUCCESS,
+                    );
                 });
             }
         }
@@ -514,4 +518,10 @@ impl hil::symmetric_encryption::AES128CBC for Aes<'_> {
     }
 }
 
+impl hil::symmetric_encryption::AES128ECB for Aes<'_> {
+    fn set_mode_aes128ecb(&self, encrypting: bool) {
+        self.set_mode(encrypting, ConfidentialityMode::ECB);
+    }
+}
+
 pub static mut AES: Aes<'static> = Aes::new();
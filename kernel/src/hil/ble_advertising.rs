@@ -60,6 +60,43 @@ pub trait BleConfig {
     fn set_tx_power(&self, power: u8) -> ReturnCode;
 }
 
+/// Radio operations with a turnaround at the inter frame space (T_IFS,
+/// 150 us), which a link layer needs to answer advertising PDUs and to take
+/// part in connection events.
+///
+/// An exchange is a sequence of packets on one channel, alternating between
+/// receiving and transmitting. Each received packet is passed to
+/// `ExchangeClient::packet_received`, which writes the response PDU to be
+/// transmitted T_IFS after it. After a response is transmitted the radio
+/// listens for the next packet. The exchange ends when the client returns
+/// no response or when `stop_exchange` is called, and the client is then
+/// notified with `exchange_done`.
+pub trait BleLinkLayerDriver<'a> {
+    /// Sets the access address and CRC initial value used on the data
+    /// channels (0-36). The advertising channels always use the advertising
+    /// access address.
+    fn set_access_address(&self, access_address: u32, crc_init: u32);
+    /// Transmits `pdu` on `channel` and starts an exchange by listening for
+    /// a response T_IFS after it.
+    fn transmit_and_listen(&self, pdu: &[u8], channel: RadioChannel) -> ReturnCode;
+    /// Starts an exchange by listening on `channel`.
+    fn listen(&self, channel: RadioChannel) -> ReturnCode;
+    /// Ends the current exchange. `exchange_done` is called once the radio
+    /// is idle.
+    fn stop_exchange(&self);
+    fn set_exchange_client(&self, client: &'a dyn ExchangeClient);
+}
+
+pub trait ExchangeClient {
+    /// Called for each packet received in an exchange with the received PDU
+    /// (header and payload). Returns the length of the response PDU written
+    /// to `response`, or 0 to end the exchange. The response is transmitted
+    /// T_IFS after the received packet, so this must return quickly.
+    fn packet_received(&self, pdu: &[u8], crc_ok: bool, response: &mut [u8]) -> usize;
+    /// Called when the exchange has ended.
+    fn exchange_done(&self, result: ReturnCode);
+}
+
 pub trait RxClient {
     fn receive_event(&self, buf: &'static mut [u8], len: u8, result: ReturnCode);
 }
@@ -158,4 +195,51 @@ impl RadioChannel {
             RadioChannel::AdvertisingChannel39 => 39,
         }
     }
+
+    /// Returns the channel with the given channel index (0-39).
+    pub fn from_channel_index(index: u32) -> Option<RadioChannel> {
+        match index {
+            0 => Some(RadioChannel::DataChannel0),
+            1 => Some(RadioChannel::DataChannel1),
+            2 => Some(RadioChannel::DataChannel2),
+            3 => Some(RadioChannel::DataChannel3),
+            4 => Some(RadioChannel::DataChannel4),
+            5 => Some(RadioChannel::DataChannel5),
+            6 => Some(RadioChannel::DataChannel6),
+            7 => Some(RadioChannel::DataChannel7),
+            8 => Some(RadioChannel::DataChannel8),
+            9 => Some(RadioChannel::DataChannel9),
+            10 => Some(RadioChannel::DataChannel10),
+            11 => Some(RadioChannel::DataChannel11),
+            12 => Some(RadioChannel::DataChannel12),
+            13 => Some(RadioChannel::DataChannel13),
+            14 => Some(RadioChannel::DataChannel14),
+            15 => Some(RadioChannel::DataChannel15),
+            16 => Some(RadioChannel::DataChannel16),
+            17 => Some(RadioChannel::DataChannel17),
+            18 => Some(RadioChannel::DataChannel18),
+            19 => Some(RadioChannel::DataChannel19),
+            20 => Some(RadioChannel::DataChannel20),
+            21 => Some(RadioChannel::DataChannel21),
+            22 => Some(RadioChannel::DataChannel22),
+            23 => Some(RadioChannel::DataChannel23),
+            24 => Some(RadioChannel::DataChannel24),
+            25 => Some(RadioChannel::DataChannel25),
+            26 => Some(RadioChannel::DataChannel26),
+            27 => Some(RadioChannel::DataChannel27),
+            28 => Some(RadioChannel::DataChannel28),
+            29 => Some(RadioChannel::DataChannel29),
+            30 => Some(RadioChannel::DataChannel30),
+            31 => Some(RadioChannel::DataChannel31),
+            32 => Some(RadioChannel::DataChannel32),
+            33 => Some(RadioChannel::DataChannel33),
+            34 => Some(RadioChannel::DataChannel34),
+            35 => Some(RadioChannel::DataChannel35),
+            36 => Some(RadioChannel::DataChannel36),
+            37 => Some(RadioChannel::AdvertisingChannel37),
+            38 => Some(RadioChannel::AdvertisingChannel38),
+            39 => Some(RadioChannel::AdvertisingChannel39),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,95 @@
+//! Interface for storage devices made of numbered blocks, such as SD cards,
+//! flash chips and RAM disks.
+//!
+//! Reads and writes are of whole blocks, and are split-phase: a call that
+//! returns `Ok` completes with a callback to the client, which returns the
+//! buffer. Blocks can be written without erasing them first. Erasing a block
+//! tells the device its contents are no longer needed, which flash devices
+//! use to erase it ahead of the next write.
+//!
+//! A user of a block device might look like:
+//!
+//! ```rust
+//! use kernel::common::cells::TakeCell;
+//! use kernel::hil::block_storage::{BlockStorage, BlockStorageClient};
+//! use kernel::ReturnCode;
+//!
+//! pub struct BlockUser<'a> {
+//!     storage: &'a dyn BlockStorage<'a>,
+//!     buffer: TakeCell<'static, [u8]>,
+//! }
+//!
+//! impl<'a> BlockUser<'a> {
+//!     pub fn read_first_block(&self) -> ReturnCode {
+//!         self.buffer.take().map_or(ReturnCode::EBUSY, |buffer| {
+//!             match self.storage.read(0, buffer) {
+//!                 Ok(()) => ReturnCode::SUCCESS,
+//!                 Err((result, buffer)) => {
+//!                     self.buffer.replace(buffer);
+//!                     result
+//!                 }
+//!             }
+//!         })
+//!     }
+//! }
+//!
+//! impl BlockStorageClient for BlockUser<'_> {
+//!     fn read_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+//!         self.buffer.replace(buffer);
+//!     }
+//!     fn write_done(&self, buffer: &'static mut [u8], result: ReturnCode) {
+//!         self.buffer.replace(buffer);
+//!     }
+//!     fn erase_done(&self, result: ReturnCode) {}
+//! }
+//! ```
+
+use crate::returncode::ReturnCode;
+
+/// The layout of a block device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Geometry {
+    /// Size of a block in bytes.
+    pub block_size: usize,
+    /// Number of blocks.
+    pub blocks: u32,
+}
+
+pub trait BlockStorage<'a> {
+    fn set_client(&self, client: &'a dyn BlockStorageClient);
+
+    /// The layout of the device, or `None` if the device isn't ready, such as
+    /// an SD card that isn't initialized.
+    fn geometry(&self) -> Option<Geometry>;
+
+    /// Read block number `block` into `buffer`, which must be at least a
+    /// block long.
+    fn read(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Write the first block's worth of `buffer` to block number `block`.
+    fn write(
+        &self,
+        block: u32,
+        buffer: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8])>;
+
+    /// Erase block number `block`, after which its contents are undefined.
+    /// Returns ENOSUPPORT if the device doesn't erase blocks.
+    fn erase(&self, block: u32) -> ReturnCode;
+}
+
+/// Implement `BlockStorageClient` to receive callbacks from `BlockStorage`.
+pub trait BlockStorageClient {
+    /// A read finished. The buffer holds the block if `result` is SUCCESS.
+    fn read_done(&self, buffer: &'static mut [u8], result: ReturnCode);
+
+    /// A write finished.
+    fn write_done(&self, buffer: &'static mut [u8], result: ReturnCode);
+
+    /// An erase finished.
+    fn erase_done(&self, result: ReturnCode);
+}
@@ -70,3 +70,20 @@ pub trait HMACSha256 {
     /// The key used for the HMAC is passed to this function.
     fn set_mode_hmacsha256(&self, key: &[u8; 32]) -> Result<(), ReturnCode>;
 }
+
+pub trait Sha224 {
+    /// Call before `Digest::run()` to perform SHA-224. The digest fills the
+    /// first 28 bytes of the digest buffer.
+    fn set_mode_sha224(&self) -> Result<(), ReturnCode>;
+}
+
+pub trait Sha256 {
+    /// Call before `Digest::run()` to perform SHA-256
+    fn set_mode_sha256(&self) -> Result<(), ReturnCode>;
+}
+
+pub trait Sha512Trunc256 {
+    /// Call before `Digest::run()` to perform SHA-512/256, the SHA-512
+    /// variant with a 32 byte digest
+    fn set_mode_sha512_256(&self) -> Result<(), ReturnCode>;
+}
@@ -0,0 +1,46 @@
+//! Interface for keys held by the kernel.
+//!
+//! A key store keeps key material out of process memory. Processes and
+//! kernel capsules refer to a key with an opaque `KeyHandle`, and each key
+//! belongs either to the kernel or to one process. Only the capsules that
+//! program a crypto engine read the key itself, on behalf of its owner.
+
+use crate::callback::AppId;
+use crate::returncode::ReturnCode;
+
+/// Opaque reference to a key in a key store.
+///
+/// A handle is only meaningful to the key store that issued it, and gives
+/// no access on its own: the key store also checks who is asking.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct KeyHandle(usize);
+
+impl KeyHandle {
+    /// Creates a handle from the number a key store gave out, for example
+    /// one passed in by a process.
+    pub const fn new(id: usize) -> KeyHandle {
+        KeyHandle(id)
+    }
+
+    /// The number to give to processes for this handle.
+    pub fn id(&self) -> usize {
+        self.0
+    }
+}
+
+/// Read access to stored keys for kernel crypto capsules.
+pub trait KeyStore {
+    /// Copies the key referenced by `handle` into the start of `key` and
+    /// returns its length.
+    ///
+    /// `owner` is the process the key is used for, or `None` for keys of the
+    /// kernel. Fails with EINVAL if there is no such key or it belongs to
+    /// someone else, ESIZE if `key` is too short and EBUSY if the keys have
+    /// not been loaded yet.
+    fn read_key(
+        &self,
+        handle: KeyHandle,
+        owner: Option<AppId>,
+        key: &mut [u8],
+    ) -> Result<usize, ReturnCode>;
+}
@@ -0,0 +1,56 @@
+//! Interface for a persistent key-value store.
+//!
+//! Keys live in namespaces, so that different users of one store, such as
+//! processes, can't see or change each other's values. Values persist across
+//! device reboots.
+
+use crate::returncode::ReturnCode;
+
+/// Length of a namespace identifier in bytes.
+pub const NAMESPACE_LEN: usize = 8;
+
+/// Identifies the owner of a set of keys.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Namespace(pub [u8; NAMESPACE_LEN]);
+
+/// An interface for storing values by key.
+pub trait KVStore<'a> {
+    /// Set the client to call when operations complete.
+    fn set_client(&self, client: &'a dyn KVStoreClient);
+
+    /// Read the value of `key` into `value`. The key is copied, so it only
+    /// needs to live for the call.
+    fn get(
+        &self,
+        namespace: Namespace,
+        key: &[u8],
+        value: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)>;
+
+    /// Store the first `length` bytes of `value` as the value of `key`,
+    /// replacing any old value.
+    fn set(
+        &self,
+        namespace: Namespace,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ReturnCode, Option<&'static mut [u8]>)>;
+
+    /// Remove `key` and its value.
+    fn delete(&self, namespace: Namespace, key: &[u8]) -> ReturnCode;
+}
+
+/// Receive callbacks from `KVStore`.
+pub trait KVStoreClient {
+    /// A `get` has completed. On success `length` is the length of the value.
+    /// If the value is longer than the buffer, the buffer holds its start and
+    /// the error is ESIZE. ENOSUPPORT means the key has no value.
+    fn get_complete(&self, result: ReturnCode, value: &'static mut [u8], length: usize);
+
+    /// A `set` has completed and the value is persistent if it succeeded.
+    fn set_complete(&self, result: ReturnCode, value: &'static mut [u8]);
+
+    /// A `delete` has completed. ENOSUPPORT means the key had no value.
+    fn delete_complete(&self, result: ReturnCode);
+}
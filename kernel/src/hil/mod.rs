@@ -3,6 +3,7 @@
 pub mod adc;
 pub mod analog_comparator;
 pub mod ble_advertising;
+pub mod block_storage;
 pub mod crc;
 pub mod dac;
 pub mod digest;
@@ -12,6 +13,8 @@ pub mod flash;
 pub mod gpio;
 pub mod gpio_async;
 pub mod i2c;
+pub mod key_store;
+pub mod kv_store;
 pub mod led;
 pub mod log;
 pub mod nonvolatile_storage;
@@ -20,6 +23,7 @@ pub mod radio;
 pub mod rng;
 pub mod screen;
 pub mod sensors;
+pub mod signature;
 pub mod spi;
 pub mod symmetric_encryption;
 pub mod time;
@@ -0,0 +1,61 @@
+//! Interface for verifying public-key signatures
+
+use crate::common::leasable_buffer::LeasableBuffer;
+use crate::returncode::ReturnCode;
+
+/// Implement this trait and use `set_client()` in order to receive callbacks.
+pub trait Client<'a> {
+    /// This callback is called when a signature has been checked.
+    /// `result` is `Ok(true)` if the signature is valid, `Ok(false)` if it is
+    /// not, and an error if it could not be checked.
+    /// On error or success `message` and `signature` will contain references
+    /// to the original data supplied to `verify()`.
+    fn verification_done(
+        &'a self,
+        result: Result<bool, ReturnCode>,
+        message: &'static mut [u8],
+        signature: &'static mut [u8],
+    );
+}
+
+/// Checks signatures against a public key
+pub trait SignatureVerify<'a> {
+    /// Set the client instance which will receive `verification_done()`
+    /// callbacks.
+    fn set_client(&'a self, client: &'a dyn Client<'a>);
+
+    /// Check `signature` over `message` with the public key and algorithm set
+    /// by the last `set_mode*()` call.
+    ///
+    /// What `message` holds depends on the algorithm: Ed25519 signs the
+    /// message itself, while ECDSA signs a digest of it, so `message` is the
+    /// digest.
+    /// This doesn't return the result, instead the client needs to have set
+    /// a `verification_done` handler.
+    /// On error the return value will contain a return code and the original
+    /// data; ENOSUPPORT if no public key was set.
+    fn verify(
+        &'a self,
+        message: LeasableBuffer<'static, u8>,
+        signature: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])>;
+}
+
+pub trait Ed25519 {
+    /// Call before `SignatureVerify::verify()` to check Ed25519 signatures
+    /// (RFC 8032) made with the given 32 byte public key. Signatures are 64
+    /// bytes long.
+    ///
+    /// Returns EINVAL if the public key does not encode a point on the curve.
+    fn set_mode_ed25519(&self, public_key: &[u8; 32]) -> Result<(), ReturnCode>;
+}
+
+pub trait EcdsaP256 {
+    /// Call before `SignatureVerify::verify()` to check ECDSA signatures over
+    /// the NIST P-256 curve made with the given public key, the big endian
+    /// x and y coordinates of the public point. Signatures are the 32 byte
+    /// big endian r and s values, and messages are 32 byte digests.
+    ///
+    /// Returns EINVAL if the public key is not a point on the curve.
+    fn set_mode_ecdsa_p256(&self, public_key: &[u8; 64]) -> Result<(), ReturnCode>;
+}
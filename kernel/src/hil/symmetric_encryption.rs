@@ -7,7 +7,10 @@ use crate::returncode::ReturnCode;
 /// Implement this trait and use `set_client()` in order to receive callbacks from an `AES128`
 /// instance.
 pub trait Client<'a> {
-    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8]);
+    /// Returns the buffers of a request that `crypt()` accepted. `result` is `SUCCESS` if `dest`
+    /// holds the result of the encryption/decryption, or the error that stopped the request, in
+    /// which case the data is left untouched.
+    fn crypt_done(&'a self, source: Option<&'a mut [u8]>, dest: &'a mut [u8], result: ReturnCode);
 }
 
 /// The number of bytes used for AES block operations.  Keys and IVs must have this length,
@@ -51,8 +54,9 @@ pub trait AES128<'a> {
     /// provide the input, which will be overwritten.
     ///
     /// If `None` is returned, the client's `crypt_done` method will eventually
-    /// be called, and the portion of the data buffer between `start_index`
-    /// and `stop_index` will hold the result of the encryption/decryption.
+    /// be called, and unless it reports an error, the portion of the data
+    /// buffer between `start_index` and `stop_index` will hold the result of
+    /// the encryption/decryption.
     ///
     /// If `Some(result, source, dest)` is returned, `result` is the
     /// error condition and `source` and `dest` are the buffers that
@@ -135,3 +139,43 @@ pub trait AES128CCM<'a> {
         encrypting: bool,
     ) -> (ReturnCode, Option<&'static mut [u8]>);
 }
+
+pub trait GCMClient {
+    /// `res` is SUCCESS if the encryption/decryption process succeeded. This
+    /// does not mean that the message has been verified in the case of
+    /// decryption.
+    /// If we are encrypting: `tag_is_valid` is `true` iff `res` is SUCCESS.
+    /// If we are decrypting: `tag_is_valid` is `true` iff `res` is SUCCESS and the
+    /// authentication tag is valid.
+    fn crypt_done(&self, buf: &'static mut [u8], res: ReturnCode, tag_is_valid: bool);
+}
+
+pub const GCM_NONCE_LENGTH: usize = 12;
+pub const GCM_TAG_LENGTH: usize = 16;
+
+pub trait AES128GCM<'a> {
+    /// Set the client instance which will receive `crypt_done()` callbacks
+    fn set_client(&'a self, client: &'a dyn GCMClient);
+
+    /// Set the key to be used for GCM encryption
+    fn set_key(&self, key: &[u8]) -> ReturnCode;
+
+    /// Set the IV (length GCM_NONCE_LENGTH) to be used for GCM encryption
+    fn set_iv(&self, iv: &[u8]) -> ReturnCode;
+
+    /// Try to begin the encryption/decryption process.
+    ///
+    /// `buf[a_off..m_off]` is the additional authenticated data and
+    /// `buf[m_off..m_off + m_len]` the message, which is encrypted or
+    /// decrypted in place. The `GCM_TAG_LENGTH` bytes after the message
+    /// receive the tag when encrypting, and hold the tag to check when
+    /// decrypting.
+    fn crypt(
+        &self,
+        buf: &'static mut [u8],
+        a_off: usize,
+        m_off: usize,
+        m_len: usize,
+        encrypting: bool,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+}
@@ -81,6 +81,17 @@ impl KernelInfo {
             .process_map_or("unknown", app, |process| process.get_process_name())
     }
 
+    /// Get how many bytes of nonvolatile storage the process asks for in its
+    /// TBF header, or 0 if it doesn't.
+    pub fn nonvolatile_storage_size(
+        &self,
+        app: AppId,
+        _capability: &dyn ProcessManagementCapability,
+    ) -> usize {
+        self.kernel
+            .process_map_or(0, app, |process| process.get_nonvolatile_storage_size())
+    }
+
     /// Returns the number of syscalls the app has called.
     pub fn number_app_syscalls(
         &self,
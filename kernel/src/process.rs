@@ -360,6 +360,10 @@ pub trait ProcessType {
     /// writeable flash region.
     fn get_writeable_flash_region(&self, region_index: usize) -> (u32, u32);
 
+    /// How many bytes of nonvolatile storage the TBF header for this process
+    /// asks for, or 0 if it doesn't.
+    fn get_nonvolatile_storage_size(&self) -> usize;
+
     /// Debug function to update the kernel on where the stack starts for this
     /// process. Processes are not required to call this through the memop
     /// system call, but it aids in debugging the process.
@@ -1015,6 +1019,10 @@ impl<C: Chip> ProcessType for Process<'_, C> {
         self.header.get_writeable_flash_region(region_index)
     }
 
+    fn get_nonvolatile_storage_size(&self) -> usize {
+        self.header.get_nonvolatile_storage_size() as usize
+    }
+
     fn update_stack_start_pointer(&self, stack_pointer: *const u8) {
         if stack_pointer >= self.mem_start() && stack_pointer < self.mem_end() {
             self.debug.map(|debug| {
@@ -117,6 +117,7 @@ pub(crate) enum TbfHeaderTypes {
     TbfHeaderWriteableFlashRegions = 2,
     TbfHeaderPackageName = 3,
     TbfHeaderFixedAddresses = 5,
+    TbfHeaderNonvolatileStorage = 6,
 
     /// Some field in the header that we do not understand. Since the TLV format
     /// specifies the length of each section, if we get a field we do not
@@ -176,6 +177,16 @@ pub(crate) struct TbfHeaderV2FixedAddresses {
     start_process_flash: u32,
 }
 
+/// Nonvolatile storage the process asks for.
+///
+/// Capsules that give each process a region of nonvolatile storage use this
+/// as the size of the region.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TbfHeaderV2NonvolatileStorage {
+    /// Bytes of storage.
+    size: u32,
+}
+
 // Conversion functions from slices to the various TBF fields.
 
 impl core::convert::TryFrom<&[u8]> for TbfHeaderV2Base {
@@ -221,6 +232,7 @@ impl core::convert::TryFrom<u16> for TbfHeaderTypes {
             2 => Ok(TbfHeaderTypes::TbfHeaderWriteableFlashRegions),
             3 => Ok(TbfHeaderTypes::TbfHeaderPackageName),
             5 => Ok(TbfHeaderTypes::TbfHeaderFixedAddresses),
+            6 => Ok(TbfHeaderTypes::TbfHeaderNonvolatileStorage),
             _ => Ok(TbfHeaderTypes::Unknown),
         }
     }
@@ -308,6 +320,20 @@ impl core::convert::TryFrom<&[u8]> for TbfHeaderV2FixedAddresses {
     }
 }
 
+impl core::convert::TryFrom<&[u8]> for TbfHeaderV2NonvolatileStorage {
+    type Error = TbfParseError;
+
+    fn try_from(b: &[u8]) -> Result<TbfHeaderV2NonvolatileStorage, Self::Error> {
+        Ok(TbfHeaderV2NonvolatileStorage {
+            size: u32::from_le_bytes(
+                b.get(0..4)
+                    .ok_or(TbfParseError::InternalError)?
+                    .try_into()?,
+            ),
+        })
+    }
+}
+
 /// Single header that can contain all parts of a v2 header.
 ///
 /// Note, this struct limits the number of writeable regions an app can have to
@@ -320,6 +346,7 @@ pub(crate) struct TbfHeaderV2 {
     package_name: Option<&'static str>,
     writeable_regions: Option<[Option<TbfHeaderV2WriteableFlashRegion>; 4]>,
     fixed_addresses: Option<TbfHeaderV2FixedAddresses>,
+    nonvolatile_storage: Option<TbfHeaderV2NonvolatileStorage>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -446,6 +473,15 @@ impl TbfHeader {
             start => Some(start),
         }
     }
+
+    /// Get the number of bytes of nonvolatile storage the process asks for,
+    /// or 0 if it doesn't say.
+    pub(crate) fn get_nonvolatile_storage_size(&self) -> u32 {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.nonvolatile_storage.map_or(0, |nv| nv.size),
+            _ => 0,
+        }
+    }
 }
 
 /// Parse the TBF header length and the entire length of the TBF binary.
@@ -563,6 +599,7 @@ pub(crate) fn parse_tbf_header(
                     Default::default();
                 let mut app_name_str = "";
                 let mut fixed_address_pointer: Option<TbfHeaderV2FixedAddresses> = None;
+                let mut nonvolatile_storage_pointer: Option<TbfHeaderV2NonvolatileStorage> = None;
 
                 // Iterate the remainder of the header looking for TLV entries.
                 while remaining.len() > 0 {
@@ -643,6 +680,15 @@ pub(crate) fn parse_tbf_header(
                             }
                         }
 
+                        TbfHeaderTypes::TbfHeaderNonvolatileStorage => {
+                            let entry_len = 4;
+                            if tlv_header.length as usize == entry_len {
+                                nonvolatile_storage_pointer = Some(remaining.try_into()?);
+                            } else {
+                                return Err(TbfParseError::BadTlvEntry(tlv_header.tipe as usize));
+                            }
+                        }
+
                         _ => {}
                     }
 
@@ -660,6 +706,7 @@ pub(crate) fn parse_tbf_header(
                     package_name: Some(app_name_str),
                     writeable_regions: Some(wfr_pointer),
                     fixed_addresses: fixed_address_pointer,
+                    nonvolatile_storage: nonvolatile_storage_pointer,
                 };
 
                 Ok(TbfHeader::TbfHeaderV2(tbf_header))
@@ -0,0 +1,141 @@
+#!/usr/bin/env python3
+
+'''
+Convert the packet capture stream of `capsules::net::pcap::PcapCapture` into
+a pcap file that can be opened with Wireshark or tcpdump.
+
+The stream is read from a serial port (requires pyserial) or, with --file,
+from a file or FIFO such as a previously saved log. Output from the console
+that is interleaved with the capture is ignored. Since a pcap file has a
+single link type, only records of the selected link type are written.
+
+Usage: tock-pcap.py [options] OUTPUT
+
+Examples:
+  tock-pcap.py -p /dev/ttyUSB0 capture.pcap
+  tock-pcap.py --link-type ipv6 --file log.bin capture.pcap
+'''
+
+import argparse
+import struct
+import sys
+
+RECORD_MAGIC = b'\xd4\xc3\xb2\xa1'
+RECORD_HEADER_LEN = 24
+RECORD_TRAILER_LEN = 2
+# Records are at most one IPv6 packet.
+MAX_PACKET_LEN = 1280
+
+LINK_TYPES = {
+    '802154': 230,  # LINKTYPE_IEEE802_15_4_NOFCS
+    'ipv6': 229,  # LINKTYPE_IPV6
+}
+
+
+def pcap_file_header(link_type):
+    return struct.pack('<IHHiIII', 0xa1b2c3d4, 2, 4, 0, 0, 65535, link_type)
+
+
+class RecordParser:
+    '''Finds capture records in a byte stream.'''
+
+    def __init__(self):
+        self.buf = bytearray()
+        self.skipped = 0
+        self.corrupt = 0
+
+    def feed(self, data):
+        '''Adds data to the stream and returns the complete records in it as
+        (link type, direction, pcap record) tuples.'''
+        self.buf += data
+        records = []
+        while True:
+            start = self.buf.find(RECORD_MAGIC)
+            if start < 0:
+                # Keep a possible partial magic number.
+                keep = len(RECORD_MAGIC) - 1
+                self.skipped += max(0, len(self.buf) - keep)
+                del self.buf[:-keep]
+                return records
+            self.skipped += start
+            del self.buf[:start]
+            if len(self.buf) < RECORD_HEADER_LEN:
+                return records
+
+            link_type, direction = struct.unpack_from('<HB', self.buf, 4)
+            incl_len, orig_len = struct.unpack_from('<II', self.buf, 16)
+            if incl_len > MAX_PACKET_LEN or incl_len > orig_len:
+                self.resync()
+                continue
+            end = RECORD_HEADER_LEN + incl_len + RECORD_TRAILER_LEN
+            if len(self.buf) < end:
+                return records
+
+            body = self.buf[4:end - RECORD_TRAILER_LEN]
+            (checksum,) = struct.unpack_from('<H', self.buf, end - RECORD_TRAILER_LEN)
+            if sum(body) & 0xffff != checksum:
+                self.resync()
+                continue
+            records.append((link_type, direction, bytes(self.buf[8:end - RECORD_TRAILER_LEN])))
+            del self.buf[:end]
+
+    def resync(self):
+        # Not a valid record: skip the magic number and search again.
+        self.corrupt += 1
+        self.skipped += len(RECORD_MAGIC)
+        del self.buf[:len(RECORD_MAGIC)]
+
+
+def open_input(args):
+    if args.file:
+        return open(args.file, 'rb')
+    try:
+        import serial
+    except ImportError:
+        sys.exit('pyserial is required to read from a serial port; use --file otherwise')
+    return serial.Serial(args.port, args.baud, timeout=0.1)
+
+
+def main():
+    parser = argparse.ArgumentParser(
+        description='Write the packet capture streamed by a Tock board to a pcap file.')
+    parser.add_argument('output', help='pcap file to write')
+    parser.add_argument('-p', '--port', default='/dev/ttyUSB0',
+                        help='serial port of the board (default: /dev/ttyUSB0)')
+    parser.add_argument('-b', '--baud', type=int, default=115200,
+                        help='baud rate (default: 115200)')
+    parser.add_argument('-f', '--file', help='read the stream from a file instead')
+    parser.add_argument('-l', '--link-type', choices=sorted(LINK_TYPES), default='802154',
+                        help='link type to capture (default: 802154)')
+    parser.add_argument('-d', '--direction', choices=['rx', 'tx', 'both'], default='both',
+                        help='direction of packets to capture (default: both)')
+    args = parser.parse_args()
+
+    link_type = LINK_TYPES[args.link_type]
+    directions = {'rx': (0,), 'tx': (1,), 'both': (0, 1)}[args.direction]
+    record_parser = RecordParser()
+    count = 0
+
+    with open_input(args) as stream, open(args.output, 'wb') as out:
+        out.write(pcap_file_header(link_type))
+        try:
+            while True:
+                data = stream.read(4096)
+                if not data:
+                    if args.file:
+                        break
+                    continue
+                for (rec_link_type, direction, record) in record_parser.feed(data):
+                    if rec_link_type == link_type and direction in directions:
+                        out.write(record)
+                        out.flush()
+                        count += 1
+        except KeyboardInterrupt:
+            pass
+
+    print('{} packets written to {} ({} bytes skipped, {} corrupt records)'.format(
+        count, args.output, record_parser.skipped, record_parser.corrupt), file=sys.stderr)
+
+
+if __name__ == '__main__':
+    main()